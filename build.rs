@@ -0,0 +1,11 @@
+// Generates the gRPC service in proto/ for `--features grpc`, which also brings in
+// tonic-build and a vendored protoc; other builds need nothing from here.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/position_service.proto");
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::compile_protos("proto/position_service.proto")?;
+    }
+    Ok(())
+}
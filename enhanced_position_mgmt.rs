@@ -3,6 +3,8 @@
 use std::collections::HashMap;
 use chrono::NaiveDate;
 
+mod position_math;
+
 #[derive(Debug, Clone)]
 enum Side {
     Buy,
@@ -48,55 +50,14 @@ impl TradePosition {
         }
     }
 
+    // Sells past flat go short; buys against a short cover it first, then go long.
+    // Realized P&L is not tracked by this engine
     fn update_position(&mut self, trade: &Trade) {
-        match trade.side {
-            Side::Buy => {
-                // Fixed average price calculation
-                if self.quantity > 0 {
-                    let old_value = self.average_price * self.quantity as f64;
-                    let new_value = trade.price * trade.quantity as f64;
-                    self.quantity += trade.quantity;
-                    self.average_price = (old_value + new_value) / self.quantity as f64;
-                } else {
-                    // First buy or switching from short to long
-                    self.quantity += trade.quantity;
-                    self.average_price = trade.price;
-                }
-            },
-            Side::Sell => {
-                self.quantity -= trade.quantity;
-                if self.quantity == 0 {
-                    self.average_price = 0.0;
-                }
-                // Note: For sells, we don't change average price of remaining position
-            }
-        }
-    }
-
-    fn cancel_trade(&mut self, trade: &Trade) {
-        match trade.side {
-            Side::Buy => {
-                if self.quantity > trade.quantity {
-                    // Recalculate average price after removing this buy
-                    let total_value = self.average_price * self.quantity as f64;
-                    let trade_value = trade.price * trade.quantity as f64;
-                    self.quantity -= trade.quantity;
-                    if self.quantity > 0 {
-                        self.average_price = (total_value - trade_value) / self.quantity as f64;
-                    } else {
-                        self.average_price = 0.0;
-                    }
-                } else {
-                    self.quantity -= trade.quantity;
-                    if self.quantity == 0 {
-                        self.average_price = 0.0;
-                    }
-                }
-            },
-            Side::Sell => {
-                self.quantity += trade.quantity;
-            }
-        }
+        let fill_quantity = match trade.side {
+            Side::Buy => trade.quantity,
+            Side::Sell => -trade.quantity,
+        };
+        position_math::apply_fill(&mut self.quantity, &mut self.average_price, fill_quantity, trade.price);
     }
 }
 
@@ -126,15 +87,24 @@ impl TradeRepository {
 
     fn amend_trade(&mut self, trade_id: i32, new_quantity: i32, new_price: f64) {
         if let Some(trade) = self.trades.get_mut(&trade_id) {
-            let instrument = trade.instrument.clone();
-            // Cancel old trade effect
-            self.positions.get_mut(&instrument).unwrap().cancel_trade(trade);
-            // Update trade
             trade.quantity = new_quantity;
             trade.price = new_price;
-            // Apply new trade effect
-            self.positions.get_mut(&instrument).unwrap().update_position(trade);
+            let instrument = trade.instrument.clone();
+            self.rebuild_position(&instrument);
+        }
+    }
+
+    // Replay the instrument's remaining trades by date. Taking a trade back out
+    // arithmetically only works while the position stays long, so amends and
+    // cancels go through here.
+    fn rebuild_position(&mut self, instrument: &str) {
+        let mut trades: Vec<&Trade> = self.trades.values().filter(|trade| trade.instrument == instrument).collect();
+        trades.sort_by_key(|trade| (trade.trade_date, trade.trade_id));
+        let mut position = TradePosition::new(instrument.to_string());
+        for trade in trades {
+            position.update_position(trade);
         }
+        self.positions.insert(instrument.to_string(), position);
     }
 
     // NEW: Amend trade based on date
@@ -164,7 +134,7 @@ impl TradeRepository {
 
     fn cancel_trade(&mut self, trade_id: i32) {
         if let Some(trade) = self.trades.remove(&trade_id) {
-            self.positions.get_mut(&trade.instrument).unwrap().cancel_trade(&trade);
+            self.rebuild_position(&trade.instrument);
         }
     }
 
@@ -285,4 +255,58 @@ fn main() {
     for (date, position) in history {
         println!("{}: {} shares @ ${:.2}", date, position.quantity, position.average_price);
     }
+
+    // Short positions: open from flat, add, partially cover, then flip long
+    println!("\n=== Short Positions ===");
+    let mut shorts = TradeRepository::new();
+    let steps = [
+        (10, 1, 100, 50.0, Side::Sell, "flat -> short"),
+        (11, 2, 50, 56.0, Side::Sell, "add to short"),
+        (12, 3, 60, 48.0, Side::Buy, "partial cover"),
+        (13, 4, 140, 45.0, Side::Buy, "short -> long"),
+        (14, 5, 80, 47.0, Side::Sell, "long -> short"),
+    ];
+    for (trade_id, day, quantity, price, side, label) in steps {
+        println!("{:?} {} @ ${:.2} ({})", side, quantity, price, label);
+        shorts.add_trade(Trade::new(trade_id, NaiveDate::from_ymd_opt(2022, 2, day).unwrap(), "TSLA".to_string(), quantity, price, side));
+        let position = shorts.get_position("TSLA").unwrap();
+        println!("  TSLA: {} shares @ ${:.2}", position.quantity, position.average_price);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2022, 2, day).unwrap()
+    }
+
+    #[test]
+    fn cancelling_the_flip_restores_the_short() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "TSLA".to_string(), 100, 50.0, Side::Sell));
+        repo.add_trade(Trade::new(2, day(2), "TSLA".to_string(), 150, 40.0, Side::Buy));
+        let position = repo.get_position("TSLA").unwrap();
+        assert_eq!((position.quantity, position.average_price), (50, 40.0));
+
+        repo.cancel_trade(2);
+        let position = repo.get_position("TSLA").unwrap();
+        assert_eq!((position.quantity, position.average_price), (-100, 50.0));
+    }
+
+    #[test]
+    fn amending_a_cover_reprices_what_is_left_short() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "TSLA".to_string(), 100, 50.0, Side::Sell));
+        repo.add_trade(Trade::new(2, day(2), "TSLA".to_string(), 50, 56.0, Side::Sell));
+        repo.add_trade(Trade::new(3, day(3), "TSLA".to_string(), 60, 48.0, Side::Buy));
+
+        repo.amend_trade(1, 100, 44.0);
+        let position = repo.get_position("TSLA").unwrap();
+        assert_eq!((position.quantity, position.average_price), (-90, 48.0));
+        repo.amend_trade(3, 200, 48.0);
+        let position = repo.get_position("TSLA").unwrap();
+        assert_eq!((position.quantity, position.average_price), (50, 48.0));
+    }
 }
@@ -1,696 +1,18213 @@
-use std::collections::HashMap;
-use chrono::NaiveDate;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, SecondsFormat, Utc, Weekday};
 
+pub mod position_math;
+
+// Account used for trades booked without one
+pub const DEFAULT_ACCOUNT: &str = "DEFAULT";
+// Book and custodian of accounts without one, and strategy or counterparty of trades without one
+pub const UNASSIGNED: &str = "Unassigned";
+// Settlement cycle of instruments not in the instrument master
+pub const DEFAULT_SETTLEMENT_DAYS: u32 = 2;
+
+#[derive(Debug, Clone)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+// Lifecycle of a trade: New until booked, optionally held PendingApproval on the
+// way, then Active, Amended by any later change to its terms, and finally
+// Cancelled. A held trade that is turned down is Rejected. Cancelled and Rejected
+// are terminal.
 #[derive(Debug, Clone)]
-enum TradeStatus {
+pub enum TradeStatus {
+    New,
+    PendingApproval,
     Active,
     Cancelled,
     Amended,
+    Rejected,
+}
+
+impl TradeStatus {
+    pub fn can_become(&self, next: &TradeStatus) -> bool {
+        matches!((self, next),
+            (TradeStatus::New, TradeStatus::PendingApproval | TradeStatus::Active | TradeStatus::Rejected)
+                | (TradeStatus::PendingApproval, TradeStatus::Active | TradeStatus::Rejected)
+                | (TradeStatus::Active | TradeStatus::Amended, TradeStatus::Amended | TradeStatus::Cancelled))
+    }
 }
 
 #[derive(Debug, Clone)]
-enum TradeType {
+pub enum TradeType {
     Market,
     Limit,
     Stop,
 }
 
+// Errors returned by the repositories' mutating operations
 #[derive(Debug, Clone)]
-struct TradeFilter {
-    instrument: Option<String>,
-    side: Option<Side>,
-    trade_type: Option<TradeType>,
-    status: Option<TradeStatus>,
-    date_from: Option<NaiveDate>,
-    date_to: Option<NaiveDate>,
-    min_quantity: Option<i32>,
-    max_quantity: Option<i32>,
-    min_price: Option<f64>,
-    max_price: Option<f64>,
+pub enum PositionError {
+    TradeNotFound(TradeId),
+    NoTradeOnDate { instrument: String, date: NaiveDate },
+    // More than one active trade matched; amend by trade id instead
+    AmbiguousMatch { instrument: String, date: NaiveDate, trade_ids: Vec<TradeId> },
+    InstrumentNotFound(String),
+    DuplicateTradeId(TradeId),
+    TradeCancelled(TradeId),
+    InvalidQuantity(i32),
+    InvalidPrice { instrument: String, price: Price },
+    // A correction must be booked under a new trade id
+    InvalidCorrection(TradeId),
+    PositionMissing { instrument: String, side: Side },
+    SanityBlocked { trade_id: TradeId, rules: Vec<String> },
+    // A trade or order refused by the risk limits
+    LimitBreached { instrument: String, breaches: Vec<LimitBreach> },
+    MissingJustification,
+    QueryCancelled,
+    QueryTimedOut,
+    // Feed or exported record that could not be read
+    InvalidRecord(String),
+    // Trade source that failed to connect or poll
+    Source(String),
+    // A maintenance job would have changed this instrument's position
+    BaselineMismatch(String),
+    // The trade journal could not be written
+    Journal(String),
+    // The trade storage backend failed
+    Storage(String),
+    // A position snapshot could not be written or read
+    Snapshot(String),
+    // The API key was missing, unknown, expired or revoked
+    Unauthenticated(String),
+    // The API key is valid but its role or accounts do not cover the request
+    Forbidden { key_id: String, reason: String },
+    // A status change the trade lifecycle does not allow
+    InvalidTransition { trade_id: TradeId, from: TradeStatus, to: TradeStatus },
+    // A trade that breaks its instrument's lot or tick size, or an unregistered
+    // instrument while registration is required
+    InstrumentRule { instrument: String, reason: String },
+    OrderNotFound(OrderId),
+    // An order that is malformed, or a change the order's state does not allow
+    OrderRejected(String),
 }
 
-impl TradeFilter {
-    fn new() -> Self {
-        TradeFilter {
-            instrument: None,
-            side: None,
-            trade_type: None,
-            status: None,
-            date_from: None,
-            date_to: None,
-            min_quantity: None,
-            max_quantity: None,
-            min_price: None,
-            max_price: None,
+impl std::fmt::Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PositionError::TradeNotFound(trade_id) => write!(f, "No trade found with id {}", trade_id),
+            PositionError::NoTradeOnDate { instrument, date } => write!(f, "No trade found for {} on {}", instrument, date),
+            PositionError::AmbiguousMatch { instrument, date, trade_ids } => {
+                let ids: Vec<String> = trade_ids.iter().map(|trade_id| trade_id.to_string()).collect();
+                write!(f, "{} trades found for {} on {} ({}); amend by trade id", trade_ids.len(), instrument, date, ids.join(", "))
+            },
+            PositionError::InstrumentNotFound(instrument) => write!(f, "No trades for instrument {}", instrument),
+            PositionError::DuplicateTradeId(trade_id) => write!(f, "Trade {} already exists", trade_id),
+            PositionError::TradeCancelled(trade_id) => write!(f, "Trade {} is already cancelled", trade_id),
+            PositionError::InvalidQuantity(quantity) => write!(f, "Invalid quantity {}", quantity),
+            PositionError::InvalidPrice { instrument, price } => write!(f, "Invalid price {} for {}", price, instrument),
+            PositionError::InvalidCorrection(trade_id) => write!(f, "Correction must have a new trade id, got {}", trade_id),
+            PositionError::PositionMissing { instrument, side } => {
+                let direction = match side {
+                    Side::Buy => "long",
+                    Side::Sell => "short",
+                };
+                write!(f, "{} has no {} position", instrument, direction)
+            },
+            PositionError::SanityBlocked { trade_id, rules } => write!(f, "Trade {} blocked by sanity checks: {}", trade_id, rules.join("; ")),
+            PositionError::LimitBreached { instrument, breaches } => {
+                let breaches: Vec<String> = breaches.iter().map(|breach| breach.to_string()).collect();
+                write!(f, "{} blocked by risk limits: {}", instrument, breaches.join("; "))
+            },
+            PositionError::MissingJustification => write!(f, "An override needs a justification"),
+            PositionError::QueryCancelled => write!(f, "Query cancelled"),
+            PositionError::QueryTimedOut => write!(f, "Query timed out"),
+            PositionError::InvalidRecord(reason) => write!(f, "Invalid record: {}", reason),
+            PositionError::Source(reason) => write!(f, "Source error: {}", reason),
+            PositionError::BaselineMismatch(instrument) => write!(f, "Position of {} no longer matches its baseline", instrument),
+            PositionError::Journal(reason) => write!(f, "Journal write failed: {}", reason),
+            PositionError::Storage(reason) => write!(f, "Trade storage failed: {}", reason),
+            PositionError::Snapshot(reason) => write!(f, "Position snapshot failed: {}", reason),
+            PositionError::Unauthenticated(reason) => write!(f, "Not authenticated: {}", reason),
+            PositionError::Forbidden { key_id, reason } => write!(f, "Key {} is not allowed to {}", key_id, reason),
+            PositionError::OrderNotFound(order_id) => write!(f, "No order found with id {}", order_id),
+            PositionError::InvalidTransition { trade_id, from, to } => write!(f, "Trade {} cannot move from {:?} to {:?}", trade_id, from, to),
+            PositionError::InstrumentRule { instrument, reason } => write!(f, "Trade in {} rejected: {}", instrument, reason),
+            PositionError::OrderRejected(reason) => write!(f, "Order rejected: {}", reason),
         }
     }
+}
 
-    fn instrument(mut self, instrument: String) -> Self {
-        self.instrument = Some(instrument);
-        self
-    }
+impl std::error::Error for PositionError {}
 
-    fn side(mut self, side: Side) -> Self {
-        self.side = Some(side);
-        self
-    }
+pub const DECIMAL_PLACES: u32 = 9;
+pub const DECIMAL_SCALE: i128 = 1_000_000_000;
 
-    fn date_range(mut self, from: NaiveDate, to: NaiveDate) -> Self {
-        self.date_from = Some(from);
-        self.date_to = Some(to);
-        self
-    }
+// Fixed-point number with nine decimal places. Prices and money use it so that
+// P&L stays exact over long trade histories instead of drifting like f64 sums.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Decimal(i128);
 
-    fn quantity_range(mut self, min: i32, max: i32) -> Self {
-        self.min_quantity = Some(min);
-        self.max_quantity = Some(max);
-        self
-    }
+pub type Price = Decimal;
+pub type Money = Decimal;
 
-    fn price_range(mut self, min: f64, max: f64) -> Self {
-        self.min_price = Some(min);
-        self.max_price = Some(max);
-        self
-    }
+#[derive(Debug, Clone, Copy)]
+pub enum RoundingMode {
+    // Banker's rounding, the default for money
+    HalfEven,
+    HalfUp,
+    // Towards zero
+    Down,
 }
 
-#[derive(Debug, Clone)]
-struct Trade {
-    trade_id: i32,
-    trade_date: NaiveDate,
-    instrument: String,
-    quantity: i32,
-    price: f64,
-    side: Side,
-    trade_type: TradeType,
-    status: TradeStatus,
+// Divide with the remainder resolved by the rounding mode
+pub fn divide_rounded(numerator: i128, denominator: i128, mode: RoundingMode) -> i128 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder == 0 {
+        return quotient;
+    }
+    let away = if (numerator < 0) != (denominator < 0) { -1 } else { 1 };
+    let twice = (remainder.abs() * 2).cmp(&denominator.abs());
+    let round_away = match mode {
+        RoundingMode::Down => false,
+        RoundingMode::HalfUp => twice != std::cmp::Ordering::Less,
+        RoundingMode::HalfEven => match twice {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => quotient % 2 != 0,
+            std::cmp::Ordering::Less => false,
+        },
+    };
+    if round_away { quotient + away } else { quotient }
 }
 
-impl Trade {
-    fn new(trade_id: i32, trade_date: NaiveDate, instrument: String, quantity: i32, price: f64, side: Side) -> Trade {
-        Trade {
-            trade_id,
-            trade_date,
-            instrument,
-            quantity,
-            price,
-            side,
-            trade_type: TradeType::Market,
-            status: TradeStatus::Active,
-        }
-    }
+impl Decimal {
+    pub const ZERO: Decimal = Decimal(0);
 
-    fn new_with_type(trade_id: i32, trade_date: NaiveDate, instrument: String, quantity: i32, price: f64, side: Side, trade_type: TradeType) -> Trade {
-        Trade {
-            trade_id,
-            trade_date,
-            instrument,
-            quantity,
-            price,
-            side,
-            trade_type,
-            status: TradeStatus::Active,
+    // NaN and infinities have no decimal value and are refused, as are values too
+    // large for the nine places
+    pub fn from_f64(value: f64) -> Result<Decimal, String> {
+        if !value.is_finite() {
+            return Err(format!("{} is not a finite number", value));
         }
+        // Go through the shortest decimal representation so literals like 0.1 stay exact
+        Decimal::parse(&value.to_string())
     }
 
-    fn matches_filter(&self, filter: &TradeFilter) -> bool {
-        if let Some(ref instr) = filter.instrument {
-            if &self.instrument != instr { return false; }
+    pub fn parse(text: &str) -> Result<Decimal, String> {
+        let text = text.trim();
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if (whole.is_empty() && fraction.is_empty()) || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(format!("Invalid decimal '{}'", text));
         }
-        if let Some(ref side) = filter.side {
-            if !matches!((&self.side, side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell)) { return false; }
-        }
-        if let Some(ref status) = filter.status {
-            if !matches!((&self.status, status), (TradeStatus::Active, TradeStatus::Active) | (TradeStatus::Cancelled, TradeStatus::Cancelled) | (TradeStatus::Amended, TradeStatus::Amended)) { return false; }
-        }
-        if let Some(date_from) = filter.date_from {
-            if self.trade_date < date_from { return false; }
-        }
-        if let Some(date_to) = filter.date_to {
-            if self.trade_date > date_to { return false; }
-        }
-        if let Some(min_qty) = filter.min_quantity {
-            if self.quantity < min_qty { return false; }
-        }
-        if let Some(max_qty) = filter.max_quantity {
-            if self.quantity > max_qty { return false; }
+
+        let mut raw: i128 = 0;
+        for c in whole.chars() {
+            raw = raw.checked_mul(10).and_then(|raw| raw.checked_add((c as u8 - b'0') as i128)).ok_or(format!("Decimal '{}' is too large", text))?;
         }
-        if let Some(min_price) = filter.min_price {
-            if self.price < min_price { return false; }
+        raw = raw.checked_mul(DECIMAL_SCALE).ok_or(format!("Decimal '{}' is too large", text))?;
+        let mut scale = DECIMAL_SCALE;
+        for c in fraction.chars().take(DECIMAL_PLACES as usize) {
+            scale /= 10;
+            raw += (c as u8 - b'0') as i128 * scale;
         }
-        if let Some(max_price) = filter.max_price {
-            if self.price > max_price { return false; }
+        // Digits past the ninth place are rounded half-even: the first one decides,
+        // any non-zero digit after it breaks a tie
+        let mut rest = fraction.chars().skip(DECIMAL_PLACES as usize);
+        if let Some(first) = rest.next() {
+            let sticky = rest.any(|c| c != '0') as i128;
+            raw = divide_rounded(raw * 100 + (first as u8 - b'0') as i128 * 10 + sticky, 100, RoundingMode::HalfEven);
         }
-        true
+        Ok(Decimal(if negative { -raw } else { raw }))
     }
-}
 
-#[derive(Debug, Clone)]
-struct TradePosition {
-    instrument: String,
-    quantity: i32,
-    average_price: f64,
-    realized_pnl: f64,  // P&L from closed positions
-    total_cost: f64,    // Total amount invested
-}
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / DECIMAL_SCALE as f64
+    }
 
-impl TradePosition {
-    fn new(instrument: String) -> TradePosition {
-        TradePosition {
-            instrument,
-            quantity: 0,
-            average_price: 0.0,
-            realized_pnl: 0.0,
-            total_cost: 0.0,
+    pub fn round(self, decimals: u32, mode: RoundingMode) -> Decimal {
+        if decimals >= DECIMAL_PLACES {
+            return self;
         }
+        let unit = 10i128.pow(DECIMAL_PLACES - decimals);
+        Decimal(divide_rounded(self.0, unit, mode) * unit)
     }
 
-    // Calculate unrealized P&L based on current market price
-    fn unrealized_pnl(&self, current_price: f64) -> f64 {
-        if self.quantity == 0 {
-            0.0
-        } else {
-            (current_price - self.average_price) * self.quantity as f64
-        }
+    pub fn abs(self) -> Decimal {
+        Decimal(self.0.abs())
     }
 
-    // Calculate total P&L (realized + unrealized)
-    fn total_pnl(&self, current_price: f64) -> f64 {
-        self.realized_pnl + self.unrealized_pnl(current_price)
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
     }
 
-    // Get current market value of position
-    fn market_value(&self, current_price: f64) -> f64 {
-        self.quantity as f64 * current_price
+    // None for a zero divisor, where `/` panics
+    pub fn checked_div(self, other: Decimal) -> Option<Decimal> {
+        (!other.is_zero()).then(|| self / other)
     }
 
-    fn update_position(&mut self, trade: &Trade) {
-        match trade.side {
-            Side::Buy => {
-                if self.quantity >= 0 {
-                    // Adding to long position or starting new long position
-                    if self.quantity > 0 {
-                        let old_value = self.average_price * self.quantity as f64;
-                        let new_value = trade.price * trade.quantity as f64;
-                        self.total_cost += new_value;
-                        self.quantity += trade.quantity;
-                        self.average_price = (old_value + new_value) / self.quantity as f64;
-                    } else {
-                        // First buy or switching from flat to long
-                        self.quantity += trade.quantity;
-                        self.average_price = trade.price;
-                        self.total_cost += trade.price * trade.quantity as f64;
-                    }
-                } else {
-                    // Covering short position
-                    let covered_quantity = std::cmp::min(trade.quantity, -self.quantity);
-                    let remaining_buy = trade.quantity - covered_quantity;
-                    
-                    // Realize P&L on covered portion
-                    self.realized_pnl += (self.average_price - trade.price) * covered_quantity as f64;
-                    self.quantity += covered_quantity;
-                    
-                    if remaining_buy > 0 && self.quantity >= 0 {
-                        // After covering short, now going long
-                        self.quantity += remaining_buy;
-                        self.average_price = trade.price;
-                        self.total_cost = trade.price * remaining_buy as f64;
-                    }
-                }
-            },
-            Side::Sell => {
-                if self.quantity > 0 {
-                    // Selling from long position
-                    let sold_quantity = std::cmp::min(trade.quantity, self.quantity);
-                    let remaining_sell = trade.quantity - sold_quantity;
-                    
-                    // Realize P&L on sold portion
-                    self.realized_pnl += (trade.price - self.average_price) * sold_quantity as f64;
-                    self.quantity -= sold_quantity;
-                    
-                    if self.quantity == 0 {
-                        self.average_price = 0.0;
-                        self.total_cost = 0.0;
-                    }
-                    
-                    if remaining_sell > 0 {
-                        // After selling all long, now going short
-                        self.quantity -= remaining_sell;
-                        self.average_price = trade.price;
-                    }
-                } else {
-                    // Adding to short position or starting new short position
-                    if self.quantity < 0 {
-                        let old_value = self.average_price * (-self.quantity) as f64;
-                        let new_value = trade.price * trade.quantity as f64;
-                        self.quantity -= trade.quantity;
-                        self.average_price = (old_value + new_value) / (-self.quantity) as f64;
-                    } else {
-                        // Starting new short position
-                        self.quantity -= trade.quantity;
-                        self.average_price = trade.price;
-                    }
-                }
-            }
-        }
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
     }
 
-    fn cancel_trade(&mut self, trade: &Trade) {
-        match trade.side {
-            Side::Buy => {
-                if self.quantity > trade.quantity {
-                    // Recalculate average price after removing this buy
-                    let total_value = self.average_price * self.quantity as f64;
-                    let trade_value = trade.price * trade.quantity as f64;
-                    self.quantity -= trade.quantity;
-                    if self.quantity > 0 {
-                        self.average_price = (total_value - trade_value) / self.quantity as f64;
-                    } else {
-                        self.average_price = 0.0;
-                    }
-                } else {
-                    self.quantity -= trade.quantity;
-                    if self.quantity == 0 {
-                        self.average_price = 0.0;
-                    }
-                }
-            },
-            Side::Sell => {
-                self.quantity += trade.quantity;
-            }
-        }
+    // Whether the value is a whole number of steps; any value is for a zero step
+    pub fn is_multiple_of(self, step: Decimal) -> bool {
+        step.is_zero() || self.0 % step.0 == 0
     }
 }
 
-#[derive(Debug)]
-struct TradeRepository {
-    trades: HashMap<i32, Trade>,
-    // Market data for P&L calculations
-    positions: HashMap<String, TradePosition>,
-    market_prices: HashMap<String, f64>,
+// For literals and computed values known to be finite. NaN or infinity panics, like
+// an out-of-range integer cast would; input from outside goes through from_f64 or
+// parse, and so do ratios of outside values such as FX rates.
+impl From<f64> for Decimal {
+    fn from(value: f64) -> Decimal {
+        Decimal::from_f64(value).unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
-impl TradeRepository {
-    fn new() -> TradeRepository {
-        TradeRepository {
-            trades: HashMap::new(),
-            positions: HashMap::new(),
-            market_prices: HashMap::new(),
-        }
+impl From<i32> for Decimal {
+    fn from(value: i32) -> Decimal {
+        Decimal(value as i128 * DECIMAL_SCALE)
     }
+}
 
-    fn add_trade(&mut self, trade: Trade) {
-        let instrument = trade.instrument.clone();
-        self.trades.insert(trade.trade_id, trade.clone());
-        
-        if !self.positions.contains_key(&instrument) {
-            self.positions.insert(instrument.clone(), TradePosition::new(instrument.clone()));
-        }
-        self.positions.get_mut(&instrument).unwrap().update_position(&trade);
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Decimal {
+        Decimal(value as i128 * DECIMAL_SCALE)
     }
+}
 
-    fn amend_trade(&mut self, trade_id: i32, new_quantity: i32, new_price: f64) {
-        if let Some(trade) = self.trades.get_mut(&trade_id) {
-            let instrument = trade.instrument.clone();
-            // Cancel old trade effect
-            self.positions.get_mut(&instrument).unwrap().cancel_trade(trade);
-            // Update trade
-            trade.quantity = new_quantity;
-            trade.price = new_price;
-            // Apply new trade effect
-            self.positions.get_mut(&instrument).unwrap().update_position(trade);
-        }
+impl std::ops::Add for Decimal {
+    type Output = Decimal;
+    fn add(self, other: Decimal) -> Decimal {
+        Decimal(self.0 + other.0)
     }
+}
 
-    // NEW: Amend trade based on date
-    fn amend_trade_by_date(&mut self, instrument: &str, trade_date: NaiveDate, new_quantity: i32, new_price: f64) -> Result<(), String> {
-        // Find trade by instrument and date
-        let trade_id = self.trades
-            .iter()
-            .find(|(_, trade)| trade.instrument == instrument && trade.trade_date == trade_date)
-            .map(|(id, _)| *id);
+impl std::ops::Sub for Decimal {
+    type Output = Decimal;
+    fn sub(self, other: Decimal) -> Decimal {
+        Decimal(self.0 - other.0)
+    }
+}
 
-        match trade_id {
-            Some(id) => {
-                self.amend_trade(id, new_quantity, new_price);
-                Ok(())
-            },
-            None => Err(format!("No trade found for {} on {}", instrument, trade_date))
-        }
+impl std::ops::Neg for Decimal {
+    type Output = Decimal;
+    fn neg(self) -> Decimal {
+        Decimal(-self.0)
     }
+}
 
-    // NEW: Find trades by date range
-    fn find_trades_by_date(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<&Trade> {
-        self.trades
-            .values()
-            .filter(|trade| trade.trade_date >= start_date && trade.trade_date <= end_date)
-            .collect()
+impl std::ops::AddAssign for Decimal {
+    fn add_assign(&mut self, other: Decimal) {
+        self.0 += other.0;
     }
+}
 
-    fn cancel_trade(&mut self, trade_id: i32) {
-        if let Some(mut trade) = self.trades.get_mut(&trade_id) {
-            trade.status = TradeStatus::Cancelled;
-            self.positions.get_mut(&trade.instrument).unwrap().cancel_trade(&trade);
-        }
+impl std::ops::SubAssign for Decimal {
+    fn sub_assign(&mut self, other: Decimal) {
+        self.0 -= other.0;
     }
+}
 
-    // Update market price for P&L calculations
-    fn update_market_price(&mut self, instrument: &str, price: f64) {
-        self.market_prices.insert(instrument.to_string(), price);
+// Price times a share count is exact
+impl std::ops::Mul<i32> for Decimal {
+    type Output = Decimal;
+    fn mul(self, quantity: i32) -> Decimal {
+        Decimal(self.0 * quantity as i128)
     }
+}
 
-    // Get current market price
-    fn get_market_price(&self, instrument: &str) -> Option<f64> {
-        self.market_prices.get(instrument).copied()
+impl std::ops::Mul<i64> for Decimal {
+    type Output = Decimal;
+    fn mul(self, quantity: i64) -> Decimal {
+        Decimal(self.0 * quantity as i128)
     }
+}
 
-    // Advanced trade filtering
-    fn filter_trades(&self, filter: &TradeFilter) -> Vec<&Trade> {
-        self.trades
-            .values()
-            .filter(|trade| trade.matches_filter(filter))
-            .collect()
+impl std::ops::Mul for Decimal {
+    type Output = Decimal;
+    fn mul(self, other: Decimal) -> Decimal {
+        Decimal(divide_rounded(self.0 * other.0, DECIMAL_SCALE, RoundingMode::HalfEven))
     }
+}
 
-    // Get trades by multiple criteria
-    fn get_trades_by_criteria(&self, instruments: Vec<String>, side: Option<Side>, date_range: Option<(NaiveDate, NaiveDate)>) -> Vec<&Trade> {
-        self.trades
-            .values()
-            .filter(|trade| {
-                // Check instrument
-                if !instruments.is_empty() && !instruments.contains(&trade.instrument) {
-                    return false;
-                }
-                // Check side
-                if let Some(ref filter_side) = side {
-                    if !matches!((&trade.side, filter_side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell)) {
-                        return false;
-                    }
-                }
-                // Check date range
-                if let Some((start, end)) = date_range {
-                    if trade.trade_date < start || trade.trade_date > end {
-                        return false;
-                    }
-                }
-                true
-            })
-            .collect()
+// Ratios such as FX rates and weights are converted to nine places first
+impl std::ops::Mul<f64> for Decimal {
+    type Output = Decimal;
+    fn mul(self, factor: f64) -> Decimal {
+        self * Decimal::from(factor)
     }
+}
 
-    // Calculate portfolio P&L
-    fn calculate_portfolio_pnl(&self) -> (f64, f64, f64) {
-        let mut total_realized = 0.0;
-        let mut total_unrealized = 0.0;
-        let mut total_market_value = 0.0;
+impl std::ops::Div<i32> for Decimal {
+    type Output = Decimal;
+    fn div(self, quantity: i32) -> Decimal {
+        Decimal(divide_rounded(self.0, quantity as i128, RoundingMode::HalfEven))
+    }
+}
 
-        for (instrument, position) in &self.positions {
-            total_realized += position.realized_pnl;
-            
-            if let Some(market_price) = self.get_market_price(instrument) {
-                total_unrealized += position.unrealized_pnl(market_price);
-                total_market_value += position.market_value(market_price);
-            }
-        }
+// Panics on a zero divisor like integer division; see checked_div
+impl std::ops::Div for Decimal {
+    type Output = Decimal;
+    // The dividend is scaled up by DECIMAL_SCALE so the quotient keeps its nine places
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, other: Decimal) -> Decimal {
+        Decimal(divide_rounded(self.0 * DECIMAL_SCALE, other.0, RoundingMode::HalfEven))
+    }
+}
 
-        (total_realized, total_unrealized, total_market_value)
+impl std::iter::Sum for Decimal {
+    fn sum<I: Iterator<Item = Decimal>>(iter: I) -> Decimal {
+        iter.fold(Decimal::ZERO, |total, value| total + value)
     }
+}
 
-    // Get top gainers/losers
-    fn get_top_performers(&self, limit: usize, sort_by_unrealized: bool) -> Vec<(String, f64, f64)> {
-        let mut performers: Vec<(String, f64, f64)> = self.positions
-            .iter()
-            .filter_map(|(instrument, position)| {
-                if let Some(market_price) = self.get_market_price(instrument) {
-                    let unrealized = position.unrealized_pnl(market_price);
-                    let realized = position.realized_pnl;
-                    Some((instrument.clone(), realized, unrealized))
-                } else {
-                    None
-                }
-            })
-            .collect();
+impl position_math::PositionNumber for Decimal {
+    fn zero() -> Decimal {
+        Decimal::ZERO
+    }
 
-        if sort_by_unrealized {
-            performers.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
-        } else {
-            performers.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-        }
+    fn times(self, quantity: i32) -> Decimal {
+        self * quantity
+    }
 
-        performers.into_iter().take(limit).collect()
+    fn per(self, quantity: i32) -> Decimal {
+        self / quantity
     }
+}
 
-    fn get_position(&self, instrument: &str) -> Option<&TradePosition> {
-        self.positions.get(instrument)
+// Honours width, alignment and precision like f64 does: {:>10.2}
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let decimals = f.precision().map(|precision| precision as u32);
+        let value = decimals.map_or(*self, |decimals| self.round(decimals, RoundingMode::HalfEven));
+        let whole = value.0.abs() / DECIMAL_SCALE;
+        let fraction = format!("{:09}", value.0.abs() % DECIMAL_SCALE);
+        let fraction = match decimals {
+            Some(decimals) if decimals as usize <= fraction.len() => fraction[..decimals as usize].to_string(),
+            Some(decimals) => format!("{:0<width$}", fraction, width = decimals as usize),
+            None => fraction.trim_end_matches('0').to_string(),
+        };
+        let digits = if fraction.is_empty() { whole.to_string() } else { format!("{}.{}", whole, fraction) };
+        f.pad_integral(value.0 >= 0, "", &digits)
     }
+}
 
-    fn get_all_positions(&self) -> &HashMap<String, TradePosition> {
-        &self.positions
+// Debug output reads like the number rather than the raw scaled integer
+impl std::fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+// How an instrument's prices and money amounts are rounded when booked
+#[derive(Debug, Clone, Copy)]
+pub struct RoundingPolicy {
+    pub price_decimals: u32,
+    pub money_decimals: u32,
+    pub mode: RoundingMode,
+}
+
+impl RoundingPolicy {
+    pub fn new(price_decimals: u32, money_decimals: u32, mode: RoundingMode) -> RoundingPolicy {
+        RoundingPolicy { price_decimals, money_decimals, mode }
     }
 
-    // NEW: Build position map as of a specific date
-    fn build_position_map_as_of_date(&self, as_of_date: NaiveDate) -> HashMap<String, TradePosition> {
-        let mut positions_map: HashMap<String, TradePosition> = HashMap::new();
-        
-        // Get all trades up to and including the specified date
-        let mut relevant_trades: Vec<&Trade> = self.trades
-            .values()
-            .filter(|trade| trade.trade_date <= as_of_date)
-            .collect();
-        
-        // Sort trades by date to ensure proper chronological processing
-        relevant_trades.sort_by(|a, b| a.trade_date.cmp(&b.trade_date));
-        
-        // Process trades chronologically to build positions
-        for trade in relevant_trades {
-            if !positions_map.contains_key(&trade.instrument) {
-                positions_map.insert(trade.instrument.clone(), TradePosition::new(trade.instrument.clone()));
-            }
-            positions_map.get_mut(&trade.instrument).unwrap().update_position(trade);
-        }
-        
-        positions_map
+    pub fn round_price(&self, price: Price) -> Price {
+        price.round(self.price_decimals, self.mode)
     }
 
-    // NEW: Get position history for an instrument over date range
-    fn get_position_history(&self, instrument: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<(NaiveDate, TradePosition)> {
-        let mut history = Vec::new();
-        let mut current_date = start_date;
-        
-        while current_date <= end_date {
-            let position_map = self.build_position_map_as_of_date(current_date);
-            if let Some(position) = position_map.get(instrument) {
-                history.push((current_date, position.clone()));
-            } else {
-                history.push((current_date, TradePosition::new(instrument.to_string())));
-            }
-            current_date = current_date.succ_opt().unwrap_or(current_date);
+    pub fn round_money(&self, amount: Money) -> Money {
+        amount.round(self.money_decimals, self.mode)
+    }
+}
+
+impl Default for RoundingPolicy {
+    fn default() -> RoundingPolicy {
+        RoundingPolicy::new(6, 2, RoundingMode::HalfEven)
+    }
+}
+
+// What a scheduled fee is charged on
+#[derive(Debug, Clone, Copy)]
+pub enum FeeBasis {
+    PerShare(Money),
+    PerTrade(Money),
+    // Basis points of notional
+    Bps(f64),
+}
+
+impl FeeBasis {
+    pub fn amount(&self, trade: &Trade) -> Money {
+        match self {
+            FeeBasis::PerShare(rate) => *rate * trade.quantity,
+            FeeBasis::PerTrade(amount) => *amount,
+            FeeBasis::Bps(bps) => trade.notional() * (bps / 10_000.0),
         }
-        
-        history
     }
+}
 
-    // NEW: Print position summary with P&L as of date
-    fn print_position_summary_as_of(&self, as_of_date: NaiveDate) {
-        println!("\n=== Position Summary as of {} ===", as_of_date);
+#[derive(Debug, Clone, Copy)]
+pub enum FeeType {
+    Commission,
+    ExchangeFee,
+    Tax,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeeRule {
+    pub fee_type: FeeType,
+    pub basis: FeeBasis,
+    // Only charged on this side, e.g. a sell-side transaction levy; None for both
+    pub side: Option<Side>,
+}
+
+// Fees the repository charges automatically when trades are booked or amended.
+// Each fee type the schedule has rules for is replaced by the sum of those rules;
+// fee types without rules keep whatever the trade was booked with.
+#[derive(Debug, Clone)]
+pub struct FeeSchedule {
+    pub rules: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        FeeSchedule { rules: Vec::new() }
+    }
+
+    pub fn rule(mut self, fee_type: FeeType, basis: FeeBasis, side: Option<Side>) -> Self {
+        self.rules.push(FeeRule { fee_type, basis, side });
+        self
+    }
+
+    pub fn commission(self, basis: FeeBasis) -> Self {
+        self.rule(FeeType::Commission, basis, None)
+    }
+
+    pub fn exchange_fee(self, basis: FeeBasis) -> Self {
+        self.rule(FeeType::ExchangeFee, basis, None)
+    }
+
+    pub fn tax(self, basis: FeeBasis) -> Self {
+        self.rule(FeeType::Tax, basis, None)
+    }
+
+    pub fn tax_on(self, side: Side, basis: FeeBasis) -> Self {
+        self.rule(FeeType::Tax, basis, Some(side))
+    }
+
+    pub fn has_rules(&self, fee_type: FeeType) -> bool {
+        self.rules.iter().any(|rule| std::mem::discriminant(&rule.fee_type) == std::mem::discriminant(&fee_type))
+    }
+
+    pub fn apply(&self, trade: &mut Trade) {
+        for fee_type in [FeeType::Commission, FeeType::ExchangeFee, FeeType::Tax] {
+            let rules: Vec<&FeeRule> = self.rules
+                .iter()
+                .filter(|rule| std::mem::discriminant(&rule.fee_type) == std::mem::discriminant(&fee_type))
+                .collect();
+            if rules.is_empty() {
+                continue;
+            }
+            let amount: Money = rules
+                .iter()
+                .filter(|rule| rule.side.as_ref().is_none_or(|side| matches!((side, &trade.side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell))))
+                .map(|rule| rule.basis.amount(trade))
+                .sum();
+            match fee_type {
+                FeeType::Commission => trade.commission = amount,
+                FeeType::ExchangeFee => trade.exchange_fee = amount,
+                FeeType::Tax => trade.tax = amount,
+            }
+        }
+    }
+}
+
+impl Default for FeeSchedule {
+    fn default() -> FeeSchedule {
+        FeeSchedule::new()
+    }
+}
+
+// A tax charged on trades in a market, such as UK stamp duty or a financial transaction tax
+#[derive(Debug, Clone)]
+pub struct TransactionTax {
+    pub name: String,
+    pub basis: FeeBasis,
+    // Most are charged on purchases only
+    pub side: Option<Side>,
+}
+
+// Taxes of one market: transaction taxes charged when trades in its instruments are
+// booked, and the withholding rate deducted from the dividends they pay
+#[derive(Debug, Clone)]
+pub struct TaxMarket {
+    pub name: String,
+    pub transaction_taxes: Vec<TransactionTax>,
+    pub dividend_withholding: f64,
+}
+
+impl TaxMarket {
+    pub fn new(name: &str) -> Self {
+        TaxMarket {
+            name: name.to_string(),
+            transaction_taxes: Vec::new(),
+            dividend_withholding: 0.0,
+        }
+    }
+
+    pub fn transaction_tax(mut self, name: &str, side: Option<Side>, basis: FeeBasis) -> Self {
+        self.transaction_taxes.push(TransactionTax { name: name.to_string(), basis, side });
+        self
+    }
+
+    pub fn dividend_withholding(mut self, rate: f64) -> Self {
+        self.dividend_withholding = rate;
+        self
+    }
+
+    // Each transaction tax the trade is liable to, by name
+    pub fn charges(&self, trade: &Trade) -> Vec<(String, Money)> {
+        self.transaction_taxes
+            .iter()
+            .filter(|tax| tax.side.as_ref().is_none_or(|side| matches!((side, &trade.side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell))))
+            .map(|tax| (tax.name.clone(), tax.basis.amount(trade)))
+            .collect()
+    }
+}
+
+// A dividend credited to one account, net of the market's withholding
+#[derive(Debug, Clone)]
+pub struct DividendPayment {
+    pub account_id: String,
+    pub instrument: String,
+    pub ex_date: NaiveDate,
+    pub pay_date: NaiveDate,
+    // Shares held at the close before the ex-date; short holders pay the dividend
+    pub quantity: i32,
+    pub gross: Money,
+    pub withheld: Money,
+    pub net: Money,
+}
+
+// How a cash dividend from a corporate action is reported. Either way it is paid
+// into the holders' cash ledger on the pay date.
+#[derive(Debug, Clone, Copy)]
+pub enum DividendCredit {
+    // Also added to the instrument's realized P&L on the ex-date
+    RealizedPnl,
+    // Cash only
+    Cash,
+}
+
+#[derive(Debug, Clone)]
+pub enum CorporateActionKind {
+    // `new_shares` for every `old_shares`. Restating history rewrites the earlier
+    // trades at split-adjusted terms; otherwise holders are booked the bonus shares.
+    Split { new_shares: i32, old_shares: i32, restate_history: bool },
+    CashDividend { amount_per_share: Money, pay_date: NaiveDate, credit: DividendCredit },
+    // The instrument trades under the new symbol from the effective date
+    SymbolChange { new_symbol: String },
+}
+
+// An action taking effect at the open of `effective_date`, the ex-date for dividends
+#[derive(Debug, Clone)]
+pub struct CorporateAction {
+    pub instrument: String,
+    pub effective_date: NaiveDate,
+    pub kind: CorporateActionKind,
+}
+
+impl CorporateAction {
+    pub fn split(instrument: &str, effective_date: NaiveDate, new_shares: i32, old_shares: i32, restate_history: bool) -> CorporateAction {
+        CorporateAction {
+            instrument: instrument.to_string(),
+            effective_date,
+            kind: CorporateActionKind::Split { new_shares, old_shares, restate_history },
+        }
+    }
+
+    pub fn cash_dividend(instrument: &str, ex_date: NaiveDate, pay_date: NaiveDate, amount_per_share: impl Into<Money>, credit: DividendCredit) -> CorporateAction {
+        CorporateAction {
+            instrument: instrument.to_string(),
+            effective_date: ex_date,
+            kind: CorporateActionKind::CashDividend { amount_per_share: amount_per_share.into(), pay_date, credit },
+        }
+    }
+
+    pub fn symbol_change(instrument: &str, effective_date: NaiveDate, new_symbol: &str) -> CorporateAction {
+        CorporateAction {
+            instrument: instrument.to_string(),
+            effective_date,
+            kind: CorporateActionKind::SymbolChange { new_symbol: new_symbol.to_string() },
+        }
+    }
+}
+
+// What applying a corporate action changed: trades rewritten in place, trades
+// booked for it (bonus shares, dividend cash) and dividends paid
+#[derive(Debug, Clone)]
+pub struct CorporateActionResult {
+    pub action: CorporateAction,
+    pub restated_trades: Vec<TradeId>,
+    pub booked_trades: Vec<TradeId>,
+    pub dividends: Vec<DividendPayment>,
+}
+
+// Commissions, fees and taxes of one instrument, kept apart from each other
+#[derive(Debug, Clone)]
+pub struct FeeTaxRow {
+    pub instrument: String,
+    pub market: Option<String>,
+    pub commission: Money,
+    pub exchange_fees: Money,
+    // By tax name; tax not explained by the market's current rules is under "Other"
+    pub transaction_taxes: BTreeMap<String, Money>,
+    pub dividends_gross: Money,
+    pub dividends_withheld: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeeTaxReport {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub rows: Vec<FeeTaxRow>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeFilter {
+    pub instrument: Option<String>,
+    pub side: Option<Side>,
+    pub trade_type: Option<TradeType>,
+    pub status: Option<TradeStatus>,
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
+    pub min_quantity: Option<i32>,
+    pub max_quantity: Option<i32>,
+    pub min_price: Option<Price>,
+    pub max_price: Option<Price>,
+}
+
+impl TradeFilter {
+    pub fn new() -> Self {
+        TradeFilter {
+            instrument: None,
+            side: None,
+            trade_type: None,
+            status: None,
+            date_from: None,
+            date_to: None,
+            min_quantity: None,
+            max_quantity: None,
+            min_price: None,
+            max_price: None,
+        }
+    }
+
+    pub fn instrument(mut self, instrument: String) -> Self {
+        self.instrument = Some(instrument);
+        self
+    }
+
+    pub fn side(mut self, side: Side) -> Self {
+        self.side = Some(side);
+        self
+    }
+
+    pub fn trade_type(mut self, trade_type: TradeType) -> Self {
+        self.trade_type = Some(trade_type);
+        self
+    }
+
+    pub fn date_range(mut self, from: NaiveDate, to: NaiveDate) -> Self {
+        self.date_from = Some(from);
+        self.date_to = Some(to);
+        self
+    }
+
+    pub fn quantity_range(mut self, min: i32, max: i32) -> Self {
+        self.min_quantity = Some(min);
+        self.max_quantity = Some(max);
+        self
+    }
+
+    pub fn price_range(mut self, min: impl Into<Price>, max: impl Into<Price>) -> Self {
+        self.min_price = Some(min.into());
+        self.max_price = Some(max.into());
+        self
+    }
+}
+
+impl Default for TradeFilter {
+    fn default() -> TradeFilter {
+        TradeFilter::new()
+    }
+}
+
+// Trade identifier shared by every id scheme. Sequential and snowflake ids are
+// integers; UUIDs use all 128 bits. Ids that fit in an i64 print as integers and
+// anything larger as a hyphenated UUID.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TradeId(i128);
+
+// Largest integer a JSON number holds exactly; bigger ids are written as strings
+pub const JSON_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+impl TradeId {
+    pub fn from_uuid(bits: u128) -> TradeId {
+        TradeId(bits as i128)
+    }
+
+    pub fn as_i64(self) -> Option<i64> {
+        i64::try_from(self.0).ok()
+    }
+
+    pub fn parse(text: &str) -> Result<TradeId, String> {
+        let text = text.trim();
+        if let Ok(id) = text.parse::<i64>() {
+            return Ok(TradeId::from(id));
+        }
+        let hex: String = text.chars().filter(|c| *c != '-').collect();
+        if text.len() == 36 && hex.len() == 32 {
+            if let Ok(bits) = u128::from_str_radix(&hex, 16) {
+                return Ok(TradeId::from_uuid(bits));
+            }
+        }
+        Err(format!("Invalid trade id {}", text))
+    }
+
+    pub fn to_json(self) -> String {
+        match self.as_i64() {
+            Some(id) if id.abs() <= JSON_SAFE_INTEGER => id.to_string(),
+            _ => json_string(&self.to_string()),
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<TradeId> {
+        match value {
+            JsonValue::String(text) => TradeId::parse(text).ok(),
+            value => value.as_i64().map(TradeId::from),
+        }
+    }
+
+    // Spread ids over a fixed number of shards
+    pub fn shard(self, shards: usize) -> usize {
+        self.0.rem_euclid(shards as i128) as usize
+    }
+}
+
+impl From<i32> for TradeId {
+    fn from(id: i32) -> TradeId {
+        TradeId(id as i128)
+    }
+}
+
+impl From<i64> for TradeId {
+    fn from(id: i64) -> TradeId {
+        TradeId(id as i128)
+    }
+}
+
+impl std::fmt::Display for TradeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.as_i64() {
+            Some(id) => write!(f, "{}", id),
+            None => {
+                let hex = format!("{:032x}", self.0 as u128);
+                write!(f, "{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+            },
+        }
+    }
+}
+
+// Integer ids are stored as integers and UUIDs as text, so the trade_id column is untyped
+#[cfg(feature = "sqlite")]
+impl rusqlite::ToSql for TradeId {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(match self.as_i64() {
+            Some(id) => rusqlite::types::ToSqlOutput::from(id),
+            None => rusqlite::types::ToSqlOutput::from(self.to_string()),
+        })
+    }
+}
+
+impl std::fmt::Debug for TradeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Source of new trade ids. A repository without one numbers trades after the
+// highest integer id it holds.
+pub trait IdGenerator: std::fmt::Debug + Send {
+    fn next_id(&mut self) -> TradeId;
+}
+
+pub type SharedIdGenerator = Arc<Mutex<dyn IdGenerator>>;
+
+#[derive(Debug, Clone)]
+pub struct SequentialIds {
+    pub next: i64,
+}
+
+impl SequentialIds {
+    pub fn starting_at(first: i64) -> SequentialIds {
+        SequentialIds { next: first }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&mut self) -> TradeId {
+        self.next += 1;
+        TradeId::from(self.next - 1)
+    }
+}
+
+// Twitter-style snowflake: 41 bits of milliseconds since the epoch, 10 bits of
+// worker id and a 12-bit sequence within the millisecond. Ids from one worker
+// are strictly increasing even if the wall clock steps back.
+#[derive(Debug, Clone)]
+pub struct SnowflakeIds {
+    pub epoch: DateTime<Utc>,
+    pub worker_id: u16,
+    pub last_millis: i64,
+    pub sequence: u16,
+}
+
+pub const SNOWFLAKE_WORKERS: u16 = 1 << 10;
+pub const SNOWFLAKE_SEQUENCE: u16 = 1 << 12;
+
+impl SnowflakeIds {
+    pub fn new(worker_id: u16, epoch: DateTime<Utc>) -> SnowflakeIds {
+        assert!(worker_id < SNOWFLAKE_WORKERS, "snowflake worker id {} is over 10 bits", worker_id);
+        SnowflakeIds {
+            epoch,
+            worker_id,
+            last_millis: -1,
+            sequence: 0,
+        }
+    }
+
+    pub fn id_at(&mut self, now: DateTime<Utc>) -> TradeId {
+        let millis = (now - self.epoch).num_milliseconds().max(self.last_millis);
+        if millis == self.last_millis {
+            self.sequence += 1;
+            // Sequence used up: borrow the next millisecond
+            if self.sequence == SNOWFLAKE_SEQUENCE {
+                self.last_millis += 1;
+                self.sequence = 0;
+            }
+        } else {
+            self.last_millis = millis;
+            self.sequence = 0;
+        }
+        TradeId::from(self.last_millis << 22 | (self.worker_id as i64) << 12 | self.sequence as i64)
+    }
+
+    // Worker and creation time encoded in an id from this generator
+    pub fn decode(&self, id: TradeId) -> Option<(u16, DateTime<Utc>)> {
+        let id = id.as_i64()?;
+        Some((((id >> 12) & (SNOWFLAKE_WORKERS as i64 - 1)) as u16, self.epoch + chrono::Duration::milliseconds(id >> 22)))
+    }
+}
+
+impl IdGenerator for SnowflakeIds {
+    fn next_id(&mut self) -> TradeId {
+        self.id_at(Utc::now())
+    }
+}
+
+// RFC 9562 UUIDv7: 48 bits of Unix milliseconds, then a 12-bit counter (seeded
+// randomly each millisecond, so ids from one generator stay ordered) and 62 random bits
+#[derive(Debug, Clone)]
+pub struct Uuid7Ids {
+    pub last_millis: i64,
+    pub counter: u16,
+}
+
+impl Uuid7Ids {
+    pub fn new() -> Uuid7Ids {
+        Uuid7Ids { last_millis: -1, counter: 0 }
+    }
+
+    pub fn id_at(&mut self, now: DateTime<Utc>) -> TradeId {
+        let millis = now.timestamp_millis().max(self.last_millis);
+        if millis == self.last_millis && self.counter < 0xfff {
+            self.counter += 1;
+        } else {
+            self.last_millis = if millis == self.last_millis { millis + 1 } else { millis };
+            // Leave room to count up within the millisecond
+            self.counter = (random_u64() & 0x7ff) as u16;
+        }
+        let bits = (self.last_millis as u128 & 0xffff_ffff_ffff) << 80
+            | 0x7 << 76
+            | (self.counter as u128) << 64
+            | 0b10 << 62
+            | (random_u64() as u128 & 0x3fff_ffff_ffff_ffff);
+        TradeId::from_uuid(bits)
+    }
+}
+
+impl Default for Uuid7Ids {
+    fn default() -> Uuid7Ids {
+        Uuid7Ids::new()
+    }
+}
+
+impl IdGenerator for Uuid7Ids {
+    fn next_id(&mut self) -> TradeId {
+        self.id_at(Utc::now())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Trade {
+    pub trade_id: TradeId,
+    pub trade_date: NaiveDate,
+    // Execution time; midnight UTC of the trade date when only the date is known
+    pub executed_at: DateTime<Utc>,
+    pub instrument: String,
+    pub quantity: i32,
+    pub price: Price,
+    pub side: Side,
+    pub trade_type: TradeType,
+    pub status: TradeStatus,
+    pub account_id: String,
+    pub strategy: Option<String>,
+    pub counterparty: Option<String>,
+    pub broker: Option<String>,
+    pub commission: Money,
+    // Exchange/clearing fees and transaction taxes. With the commission they are
+    // part of the cost basis when opening and reduce realized P&L when closing.
+    pub exchange_fee: Money,
+    pub tax: Money,
+    // Price when the order reached the market, used to measure slippage
+    pub arrival_price: Option<Price>,
+    // Cancel/rebook links: the trade this one corrects, and the trade that corrected it
+    pub replaces: Option<TradeId>,
+    pub replaced_by: Option<TradeId>,
+    // Ingested trades only: when the source stamped the record and when it reached
+    // us. The two clocks may disagree, so neither is assumed to be the later one.
+    pub source_time: Option<DateTime<Utc>>,
+    pub received_at: Option<DateTime<Utc>>,
+    // Trades booked from execution reports: the parent order and the report's id
+    pub order_id: Option<OrderId>,
+    pub exec_id: Option<String>,
+    // Value of one point of price per unit, stamped from the instrument master at booking
+    pub multiplier: Decimal,
+}
+
+// Order trades are folded into positions in: execution time, then trade id
+pub type ChronologicalKey = (DateTime<Utc>, TradeId);
+
+impl Trade {
+    pub fn new(trade_id: impl Into<TradeId>, trade_date: NaiveDate, instrument: String, quantity: i32, price: impl Into<Price>, side: Side) -> Trade {
+        Trade {
+            trade_id: trade_id.into(),
+            trade_date,
+            executed_at: start_of_day(trade_date),
+            instrument,
+            quantity,
+            price: price.into(),
+            side,
+            trade_type: TradeType::Market,
+            status: TradeStatus::New,
+            account_id: DEFAULT_ACCOUNT.to_string(),
+            strategy: None,
+            counterparty: None,
+            broker: None,
+            commission: Decimal::ZERO,
+            exchange_fee: Decimal::ZERO,
+            tax: Decimal::ZERO,
+            arrival_price: None,
+            replaces: None,
+            replaced_by: None,
+            source_time: None,
+            received_at: None,
+            order_id: None,
+            exec_id: None,
+            multiplier: Decimal::from(1),
+        }
+    }
+
+    pub fn new_with_type(trade_id: impl Into<TradeId>, trade_date: NaiveDate, instrument: String, quantity: i32, price: impl Into<Price>, side: Side, trade_type: TradeType) -> Trade {
+        Trade {
+            trade_id: trade_id.into(),
+            trade_date,
+            executed_at: start_of_day(trade_date),
+            instrument,
+            quantity,
+            price: price.into(),
+            side,
+            trade_type,
+            status: TradeStatus::New,
+            account_id: DEFAULT_ACCOUNT.to_string(),
+            strategy: None,
+            counterparty: None,
+            broker: None,
+            commission: Decimal::ZERO,
+            exchange_fee: Decimal::ZERO,
+            tax: Decimal::ZERO,
+            arrival_price: None,
+            replaces: None,
+            replaced_by: None,
+            source_time: None,
+            received_at: None,
+            order_id: None,
+            exec_id: None,
+            multiplier: Decimal::from(1),
+        }
+    }
+
+    // Also moves the trade date to the execution's UTC date
+    pub fn with_execution_time(mut self, executed_at: DateTime<Utc>) -> Trade {
+        self.trade_date = executed_at.date_naive();
+        self.executed_at = executed_at;
+        self
+    }
+
+    pub fn with_source_time(mut self, source_time: DateTime<Utc>) -> Trade {
+        self.source_time = Some(source_time);
+        self
+    }
+
+    // When the trade happened in the given clock domain. Trades that were not
+    // ingested fall back to their execution time in both domains.
+    pub fn timestamp(&self, domain: TimestampDomain) -> DateTime<Utc> {
+        let source_time = self.source_time.unwrap_or(self.executed_at);
+        match domain {
+            TimestampDomain::Source => source_time,
+            TimestampDomain::Receive => self.received_at.unwrap_or(source_time),
+        }
+    }
+
+    pub fn with_account(mut self, account_id: &str) -> Trade {
+        self.account_id = account_id.to_string();
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: &str) -> Trade {
+        self.strategy = Some(strategy.to_string());
+        self
+    }
+
+    pub fn with_counterparty(mut self, counterparty: &str) -> Trade {
+        self.counterparty = Some(counterparty.to_string());
+        self
+    }
+
+    pub fn with_execution(mut self, order_id: OrderId, exec_id: &str) -> Trade {
+        self.order_id = Some(order_id);
+        self.exec_id = Some(exec_id.to_string());
+        self
+    }
+
+    pub fn with_broker(mut self, broker: &str, commission: impl Into<Money>) -> Trade {
+        self.broker = Some(broker.to_string());
+        self.commission = commission.into();
+        self
+    }
+
+    pub fn with_arrival_price(mut self, arrival_price: impl Into<Price>) -> Trade {
+        self.arrival_price = Some(arrival_price.into());
+        self
+    }
+
+    pub fn with_fees(mut self, exchange_fee: impl Into<Money>, tax: impl Into<Money>) -> Trade {
+        self.exchange_fee = exchange_fee.into();
+        self.tax = tax.into();
+        self
+    }
+
+    pub fn notional(&self) -> Money {
+        self.price * self.quantity * self.multiplier
+    }
+
+    pub fn total_fees(&self) -> Money {
+        self.commission + self.exchange_fee + self.tax
+    }
+
+    // Price per unit after fees: buys cost more, sells receive less. Fees are money,
+    // so they are spread over the points of every contract
+    pub fn net_price(&self) -> Price {
+        let Some(fee_per_unit) = self.total_fees().checked_div(self.multiplier * self.quantity) else {
+            return self.price;
+        };
+        match self.side {
+            Side::Buy => self.price + fee_per_unit,
+            Side::Sell => self.price - fee_per_unit,
+        }
+    }
+
+    // Order in which trades happened: execution time, then trade_id for ties
+    pub fn chronological_key(&self) -> ChronologicalKey {
+        (self.executed_at, self.trade_id)
+    }
+
+    // Quantity with sign: positive for buys, negative for sells
+    pub fn signed_quantity(&self) -> i32 {
+        match self.side {
+            Side::Buy => self.quantity,
+            Side::Sell => -self.quantity,
+        }
+    }
+
+    pub fn matches_filter(&self, filter: &TradeFilter) -> bool {
+        if let Some(ref instr) = filter.instrument {
+            if &self.instrument != instr { return false; }
+        }
+        if let Some(ref side) = filter.side {
+            if !matches!((&self.side, side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell)) { return false; }
+        }
+        if let Some(ref trade_type) = filter.trade_type {
+            if trade_type_name(&self.trade_type) != trade_type_name(trade_type) { return false; }
+        }
+        if let Some(ref status) = filter.status {
+            if trade_status_name(&self.status) != trade_status_name(status) { return false; }
+        }
+        if let Some(date_from) = filter.date_from {
+            if self.trade_date < date_from { return false; }
+        }
+        if let Some(date_to) = filter.date_to {
+            if self.trade_date > date_to { return false; }
+        }
+        if let Some(min_qty) = filter.min_quantity {
+            if self.quantity < min_qty { return false; }
+        }
+        if let Some(max_qty) = filter.max_quantity {
+            if self.quantity > max_qty { return false; }
+        }
+        if let Some(min_price) = filter.min_price {
+            if self.price < min_price { return false; }
+        }
+        if let Some(max_price) = filter.max_price {
+            if self.price > max_price { return false; }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TradePosition {
+    pub instrument: String,
+    pub quantity: i32,
+    pub average_price: Price,
+    pub realized_pnl: Money,  // P&L from closed positions
+    pub total_cost: Money,    // Total amount invested
+    // Contract multiplier of the trades folded in; prices are per unit, money is not
+    pub multiplier: Decimal,
+}
+
+impl TradePosition {
+    pub fn new(instrument: String) -> TradePosition {
+        TradePosition {
+            instrument,
+            quantity: 0,
+            average_price: Decimal::ZERO,
+            realized_pnl: Decimal::ZERO,
+            total_cost: Decimal::ZERO,
+            multiplier: Decimal::from(1),
+        }
+    }
+
+    // Calculate unrealized P&L based on current market price
+    pub fn unrealized_pnl(&self, current_price: Price) -> Money {
+        if self.quantity == 0 {
+            Decimal::ZERO
+        } else {
+            (current_price - self.average_price) * self.quantity * self.multiplier
+        }
+    }
+
+    // Calculate total P&L (realized + unrealized)
+    pub fn total_pnl(&self, current_price: Price) -> Money {
+        self.realized_pnl + self.unrealized_pnl(current_price)
+    }
+
+    // Get current market value of position
+    pub fn market_value(&self, current_price: Price) -> Money {
+        current_price * self.quantity * self.multiplier
+    }
+
+    // Daily futures settlement: the P&L since the last mark is realized and the
+    // position carried forward at the settlement price. Returns the variation margin.
+    pub fn settle_at(&mut self, price: Price) -> Money {
+        let margin = self.unrealized_pnl(price);
+        self.realized_pnl += margin;
+        if self.quantity != 0 {
+            self.average_price = price;
+        }
+        if self.quantity > 0 {
+            self.total_cost = price * self.quantity * self.multiplier;
+        }
+        margin
+    }
+
+    // Fees are folded in through the trade's net price. Crossing through flat is
+    // handled by position_math; total_cost tracks the long side only
+    pub fn update_position(&mut self, trade: &Trade) {
+        let price = trade.net_price();
+        let before = self.quantity;
+        self.multiplier = trade.multiplier;
+        self.realized_pnl += position_math::apply_fill(&mut self.quantity, &mut self.average_price, trade.signed_quantity(), price) * self.multiplier;
+        match trade.side {
+            Side::Buy if before >= 0 => self.total_cost += price * trade.quantity * self.multiplier,
+            Side::Buy if self.quantity > 0 => self.total_cost = price * self.quantity * self.multiplier,
+            Side::Sell if before > 0 && self.quantity <= 0 => self.total_cost = Decimal::ZERO,
+            _ => {}
+        }
+    }
+}
+
+// Shared flag a caller can set from another thread to stop a running query
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    pub cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// Cancellation token plus optional deadline, checked periodically by heavy queries
+#[derive(Debug, Clone)]
+pub struct QueryControl {
+    pub token: CancellationToken,
+    pub deadline: Option<Instant>,
+}
+
+impl QueryControl {
+    pub fn new(token: CancellationToken) -> QueryControl {
+        QueryControl {
+            token,
+            deadline: None,
+        }
+    }
+
+    // Control that never cancels, for the plain query methods
+    pub fn unlimited() -> QueryControl {
+        QueryControl::new(CancellationToken::new())
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> QueryControl {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    pub fn check(&self) -> Result<(), PositionError> {
+        if self.token.is_cancelled() {
+            return Err(PositionError::QueryCancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(PositionError::QueryTimedOut);
+        }
+        Ok(())
+    }
+}
+
+// Quantity is unsigned (the side gives direction); prices may be zero, e.g. an
+// option settling worthless, but not negative
+pub fn validate_terms(instrument: &str, quantity: i32, price: Price) -> Result<(), PositionError> {
+    if quantity <= 0 {
+        return Err(PositionError::InvalidQuantity(quantity));
+    }
+    if price.is_negative() {
+        return Err(PositionError::InvalidPrice { instrument: instrument.to_string(), price });
+    }
+    Ok(())
+}
+
+// Booking rules of one instrument applied to a trade: the contract multiplier is
+// stamped and the price rounded, scheduled fees and market taxes are charged, then
+// every fee is rounded to the policy
+pub fn apply_booking_rules_with(policy: RoundingPolicy, schedule: Option<&FeeSchedule>, market: Option<&TaxMarket>, multiplier: Decimal, trade: &mut Trade) {
+    trade.multiplier = multiplier;
+    trade.price = policy.round_price(trade.price);
+    if let Some(schedule) = schedule {
+        schedule.apply(trade);
+    }
+    // Market transaction taxes, unless the instrument's schedule sets tax itself
+    if let Some(market) = market {
+        if !market.transaction_taxes.is_empty() && !schedule.is_some_and(|schedule| schedule.has_rules(FeeType::Tax)) {
+            trade.tax = market.charges(trade).into_iter().map(|(_, amount)| amount).sum();
+        }
+    }
+    trade.commission = policy.round_money(trade.commission);
+    trade.exchange_fee = policy.round_money(trade.exchange_fee);
+    trade.tax = policy.round_money(trade.tax);
+}
+
+// Order used by every trade listing and export: instrument, then trade date, then trade id
+pub fn sort_trades_for_report(trades: &mut [&Trade]) {
+    trades.sort_by(|a, b| {
+        a.instrument.cmp(&b.instrument)
+            .then(a.trade_date.cmp(&b.trade_date))
+            .then(a.trade_id.cmp(&b.trade_id))
+    });
+}
+
+// Trades per compressed block of a cold segment; a lookup decodes one block
+pub const COLD_BLOCK_TRADES: usize = 1024;
+
+pub fn put_varint(out: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Zigzag, so small negative numbers stay short too
+pub fn put_signed(out: &mut Vec<u8>, value: i128) {
+    put_varint(out, ((value << 1) ^ (value >> 127)) as u128);
+}
+
+pub fn put_time(out: &mut Vec<u8>, time: DateTime<Utc>, since: DateTime<Utc>) {
+    put_signed(out, (time.timestamp() - since.timestamp()) as i128);
+    put_varint(out, time.timestamp_subsec_nanos() as u128);
+}
+
+pub struct ColdReader<'a> {
+    pub bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl ColdReader<'_> {
+    pub fn varint(&mut self) -> u128 {
+        let mut value = 0u128;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes[self.position];
+            self.position += 1;
+            value |= ((byte & 0x7f) as u128) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn signed(&mut self) -> i128 {
+        let value = self.varint();
+        (value >> 1) as i128 ^ -((value & 1) as i128)
+    }
+
+    pub fn time(&mut self, since: DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = since.timestamp() + self.signed() as i64;
+        DateTime::from_timestamp(seconds, self.varint() as u32).expect("cold block holds valid times")
+    }
+}
+
+// Up to COLD_BLOCK_TRADES sealed trades packed into bytes. Strings are indexes into
+// the block's string table; ids, dates and amounts are zigzag varints, ids and
+// dates as the difference from the trade before and execution times from the
+// start of the trade date. The trades are decoded on first access and kept until
+// one of them changes or the store releases them.
+#[derive(Debug)]
+pub struct ColdBlock {
+    pub first_id: TradeId,
+    pub last_id: TradeId,
+    pub strings: Arc<[String]>,
+    pub bytes: Arc<[u8]>,
+    pub decoded: std::sync::OnceLock<Box<[Trade]>>,
+}
+
+// Clones share the encoded trades but not the decoded copy
+impl Clone for ColdBlock {
+    fn clone(&self) -> ColdBlock {
+        ColdBlock {
+            first_id: self.first_id,
+            last_id: self.last_id,
+            strings: self.strings.clone(),
+            bytes: self.bytes.clone(),
+            decoded: std::sync::OnceLock::new(),
+        }
+    }
+}
+
+impl ColdBlock {
+    // `trades` are sorted by trade id and not empty
+    pub fn encode(trades: &[Trade]) -> ColdBlock {
+        let mut strings: Vec<String> = Vec::new();
+        let mut string_ids: HashMap<String, usize> = HashMap::new();
+        let mut bytes = Vec::new();
+        let mut previous: Option<&Trade> = None;
+        for trade in trades {
+            let mut put_string = |bytes: &mut Vec<u8>, text: &str| {
+                let id = match string_ids.get(text) {
+                    Some(id) => *id,
+                    None => {
+                        strings.push(text.to_string());
+                        string_ids.insert(text.to_string(), strings.len() - 1);
+                        strings.len() - 1
+                    },
+                };
+                put_varint(bytes, id as u128);
+            };
+            let (previous_id, previous_day) = previous.map_or((0, 0), |previous| (previous.trade_id.0, previous.trade_date.num_days_from_ce()));
+            put_signed(&mut bytes, trade.trade_id.0 - previous_id);
+            put_signed(&mut bytes, (trade.trade_date.num_days_from_ce() - previous_day) as i128);
+            put_time(&mut bytes, trade.executed_at, start_of_day(trade.trade_date));
+            put_string(&mut bytes, &trade.instrument);
+            put_signed(&mut bytes, trade.quantity as i128);
+            put_signed(&mut bytes, trade.price.0);
+            let side = match trade.side { Side::Buy => 0, Side::Sell => 1 };
+            let trade_type = match trade.trade_type { TradeType::Market => 0, TradeType::Limit => 1, TradeType::Stop => 2 };
+            let status = match trade.status {
+                TradeStatus::New => 0,
+                TradeStatus::PendingApproval => 1,
+                TradeStatus::Active => 2,
+                TradeStatus::Cancelled => 3,
+                TradeStatus::Amended => 4,
+                TradeStatus::Rejected => 5,
+            };
+            bytes.push(side | trade_type << 1 | status << 3);
+            put_string(&mut bytes, &trade.account_id);
+            for amount in [trade.commission, trade.exchange_fee, trade.tax, trade.multiplier] {
+                put_signed(&mut bytes, amount.0);
+            }
+            // Which optional fields follow, one bit each in field order
+            let present = [
+                trade.strategy.is_some(), trade.counterparty.is_some(), trade.broker.is_some(), trade.arrival_price.is_some(),
+                trade.replaces.is_some(), trade.replaced_by.is_some(), trade.source_time.is_some(), trade.received_at.is_some(),
+                trade.order_id.is_some(), trade.exec_id.is_some(),
+            ];
+            put_varint(&mut bytes, present.iter().rev().fold(0, |mask, set| mask << 1 | *set as u128));
+            for text in [&trade.strategy, &trade.counterparty, &trade.broker].into_iter().flatten() {
+                put_string(&mut bytes, text);
+            }
+            if let Some(price) = trade.arrival_price {
+                put_signed(&mut bytes, price.0);
+            }
+            for trade_id in [trade.replaces, trade.replaced_by].into_iter().flatten() {
+                put_signed(&mut bytes, trade_id.0);
+            }
+            for time in [trade.source_time, trade.received_at].into_iter().flatten() {
+                put_time(&mut bytes, time, trade.executed_at);
+            }
+            if let Some(order_id) = trade.order_id {
+                put_varint(&mut bytes, order_id as u128);
+            }
+            if let Some(exec_id) = &trade.exec_id {
+                put_string(&mut bytes, exec_id);
+            }
+            previous = Some(trade);
+        }
+        ColdBlock {
+            first_id: trades[0].trade_id,
+            last_id: trades[trades.len() - 1].trade_id,
+            strings: strings.into(),
+            bytes: bytes.into(),
+            decoded: std::sync::OnceLock::new(),
+        }
+    }
+
+    pub fn decode(&self) -> Box<[Trade]> {
+        let mut reader = ColdReader { bytes: &self.bytes, position: 0 };
+        let mut trades: Vec<Trade> = Vec::new();
+        while reader.position < self.bytes.len() {
+            let (previous_id, previous_day) = trades.last().map_or((0, 0), |previous| (previous.trade_id.0, previous.trade_date.num_days_from_ce()));
+            let trade_id = TradeId(previous_id + reader.signed());
+            let trade_date = NaiveDate::from_num_days_from_ce_opt(previous_day + reader.signed() as i32).expect("cold block holds valid dates");
+            let executed_at = reader.time(start_of_day(trade_date));
+            let instrument = self.strings[reader.varint() as usize].clone();
+            let quantity = reader.signed() as i32;
+            let price = Decimal(reader.signed());
+            let flags = reader.bytes[reader.position];
+            reader.position += 1;
+            let side = if flags & 1 == 0 { Side::Buy } else { Side::Sell };
+            let trade_type = match flags >> 1 & 3 {
+                0 => TradeType::Market,
+                1 => TradeType::Limit,
+                _ => TradeType::Stop,
+            };
+            let status = match flags >> 3 {
+                0 => TradeStatus::New,
+                1 => TradeStatus::PendingApproval,
+                2 => TradeStatus::Active,
+                3 => TradeStatus::Cancelled,
+                4 => TradeStatus::Amended,
+                _ => TradeStatus::Rejected,
+            };
+            let account_id = self.strings[reader.varint() as usize].clone();
+            let [commission, exchange_fee, tax, multiplier] = [(); 4].map(|_| Decimal(reader.signed()));
+            let present = reader.varint();
+            let has = |bit: u32| present >> bit & 1 == 1;
+            let text = |bit: u32, reader: &mut ColdReader| has(bit).then(|| self.strings[reader.varint() as usize].clone());
+            let strategy = text(0, &mut reader);
+            let counterparty = text(1, &mut reader);
+            let broker = text(2, &mut reader);
+            let arrival_price = has(3).then(|| Decimal(reader.signed()));
+            let replaces = has(4).then(|| TradeId(reader.signed()));
+            let replaced_by = has(5).then(|| TradeId(reader.signed()));
+            let source_time = has(6).then(|| reader.time(executed_at));
+            let received_at = has(7).then(|| reader.time(executed_at));
+            let order_id = has(8).then(|| reader.varint() as OrderId);
+            let exec_id = text(9, &mut reader);
+            trades.push(Trade {
+                trade_id,
+                trade_date,
+                executed_at,
+                instrument,
+                quantity,
+                price,
+                side,
+                trade_type,
+                status,
+                account_id,
+                strategy,
+                counterparty,
+                broker,
+                commission,
+                exchange_fee,
+                tax,
+                arrival_price,
+                replaces,
+                replaced_by,
+                source_time,
+                received_at,
+                order_id,
+                exec_id,
+                multiplier,
+            });
+        }
+        trades.into()
+    }
+
+    pub fn trades(&self) -> &[Trade] {
+        self.decoded.get_or_init(|| self.decode())
+    }
+}
+
+// Sealed run of older trades, sorted by trade_id and compressed in blocks, never
+// modified after sealing. There is no hash table: a lookup finds the block by id
+// range and searches it.
+#[derive(Debug, Clone)]
+pub struct ColdSegment {
+    pub blocks: Vec<ColdBlock>,
+    pub len: usize,
+    pub first_date: NaiveDate,
+    pub last_date: NaiveDate,
+}
+
+impl ColdSegment {
+    // None when there is nothing to seal
+    pub fn seal(mut trades: Vec<Trade>) -> Option<ColdSegment> {
+        trades.sort_by_key(|trade| trade.trade_id);
+        Some(ColdSegment {
+            first_date: trades.iter().map(|trade| trade.trade_date).min()?,
+            last_date: trades.iter().map(|trade| trade.trade_date).max()?,
+            len: trades.len(),
+            blocks: trades.chunks(COLD_BLOCK_TRADES).map(ColdBlock::encode).collect(),
+        })
+    }
+
+    pub fn get(&self, trade_id: TradeId) -> Option<&Trade> {
+        let index = self.blocks.partition_point(|block| block.last_id < trade_id);
+        let block = self.blocks.get(index).filter(|block| block.first_id <= trade_id)?;
+        let trades = block.trades();
+        trades
+            .binary_search_by_key(&trade_id, |trade| trade.trade_id)
+            .ok()
+            .map(|index| &trades[index])
+    }
+
+    pub fn trades(&self) -> impl Iterator<Item = &Trade> + '_ {
+        self.blocks.iter().flat_map(|block| block.trades().iter())
+    }
+
+    pub fn encoded_bytes(&self) -> usize {
+        self.blocks.iter().map(|block| block.bytes.len() + block.strings.iter().map(String::len).sum::<usize>()).sum()
+    }
+
+    // Drop the decoded copies; the next access decodes again
+    pub fn release_decoded(&mut self) {
+        for block in &mut self.blocks {
+            block.decoded.take();
+        }
+    }
+
+    // Drop the decoded copy of the block holding the trade, if any
+    pub fn release_block_of(&mut self, trade_id: TradeId) {
+        let index = self.blocks.partition_point(|block| block.last_id < trade_id);
+        if let Some(block) = self.blocks.get_mut(index).filter(|block| block.first_id <= trade_id) {
+            block.decoded.take();
+        }
+    }
+}
+
+// Trade storage split into a hot, mutable map of recent trades and immutable,
+// compressed cold segments of older ones. Lookups and scans cover both tiers;
+// changing a cold trade copies it back into the hot tier and hides the cold copy.
+// Both tiers are ordered by trade id, so scans visit trades in the same order on
+// every run. Cold trades decoded by reads stay decoded until a trade in their block
+// is changed, the cold tier is rewritten or release_decoded is called.
+#[derive(Debug, Clone)]
+pub struct TradeStore {
+    pub hot: BTreeMap<TradeId, Trade>,
+    pub cold: Vec<ColdSegment>,
+    // Cold trade ids that now live in the hot tier
+    pub superseded: HashSet<TradeId>,
+    // Secondary indexes over both tiers: chronological keys by instrument and trade
+    // date, trade ids by trade date alone, and by status
+    pub by_instrument: HashMap<String, BTreeMap<NaiveDate, BTreeSet<ChronologicalKey>>>,
+    pub by_date: BTreeMap<NaiveDate, BTreeSet<TradeId>>,
+    pub by_status: BTreeMap<&'static str, BTreeSet<TradeId>>,
+}
+
+// Access path chosen for a filtered trade query
+#[derive(Debug, Clone, Copy)]
+pub enum QueryPlan {
+    FullScan,
+    InstrumentIndex,
+    DateIndex,
+    StatusIndex,
+}
+
+impl TradeStore {
+    pub fn new() -> TradeStore {
+        TradeStore {
+            hot: BTreeMap::new(),
+            cold: Vec::new(),
+            superseded: HashSet::new(),
+            by_instrument: HashMap::new(),
+            by_date: BTreeMap::new(),
+            by_status: BTreeMap::new(),
+        }
+    }
+
+    // Store trades of one instrument that are known not to be stored yet, looking
+    // up each index bucket once per run of trades instead of once per trade
+    pub fn insert_new(&mut self, instrument: &str, trades: &[Trade]) {
+        let days = match self.by_instrument.get_mut(instrument) {
+            Some(days) => days,
+            None => self.by_instrument.entry(instrument.to_string()).or_default(),
+        };
+        for run in trades.chunk_by(|a, b| a.trade_date == b.trade_date) {
+            let date = run[0].trade_date;
+            days.entry(date).or_default().extend(run.iter().map(|trade| trade.chronological_key()));
+            self.by_date.entry(date).or_default().extend(run.iter().map(|trade| trade.trade_id));
+        }
+        for run in trades.chunk_by(|a, b| trade_status_name(&a.status) == trade_status_name(&b.status)) {
+            self.by_status.entry(trade_status_name(&run[0].status)).or_default().extend(run.iter().map(|trade| trade.trade_id));
+        }
+        self.hot.extend(trades.iter().map(|trade| (trade.trade_id, trade.clone())));
+    }
+
+    pub fn index(&mut self, trade: &Trade) {
+        self.by_instrument.entry(trade.instrument.clone()).or_default()
+            .entry(trade.trade_date).or_default()
+            .insert(trade.chronological_key());
+        self.by_date.entry(trade.trade_date).or_default().insert(trade.trade_id);
+        self.by_status.entry(trade_status_name(&trade.status)).or_default().insert(trade.trade_id);
+    }
+
+    pub fn unindex(&mut self, trade: &Trade) {
+        if let Some(days) = self.by_instrument.get_mut(&trade.instrument) {
+            if let Some(keys) = days.get_mut(&trade.trade_date) {
+                keys.remove(&trade.chronological_key());
+                if keys.is_empty() {
+                    days.remove(&trade.trade_date);
+                }
+            }
+            if days.is_empty() {
+                self.by_instrument.remove(&trade.instrument);
+            }
+        }
+        if let Some(ids) = self.by_date.get_mut(&trade.trade_date) {
+            ids.remove(&trade.trade_id);
+            if ids.is_empty() {
+                self.by_date.remove(&trade.trade_date);
+            }
+        }
+        if let Some(ids) = self.by_status.get_mut(trade_status_name(&trade.status)) {
+            ids.remove(&trade.trade_id);
+        }
+    }
+
+    // Pick the index expected to yield the fewest candidates for the filter, with
+    // that estimate. Range estimates walk the index's per-date buckets, not the trades.
+    pub fn plan(&self, filter: &TradeFilter) -> (QueryPlan, usize) {
+        let from = filter.date_from.unwrap_or(NaiveDate::MIN);
+        let to = filter.date_to.unwrap_or(NaiveDate::MAX);
+        let dated = filter.date_from.is_some() || filter.date_to.is_some();
+        let mut best = (QueryPlan::FullScan, self.len());
+        if from > to {
+            return (QueryPlan::DateIndex, 0);
+        }
+        if let Some(instrument) = &filter.instrument {
+            let estimate = self.by_instrument.get(instrument).map_or(0, |days| days.range(from..=to).map(|(_, ids)| ids.len()).sum());
+            if estimate < best.1 {
+                best = (QueryPlan::InstrumentIndex, estimate);
+            }
+        }
+        if let Some(status) = &filter.status {
+            let estimate = self.by_status.get(trade_status_name(status)).map_or(0, BTreeSet::len);
+            if estimate < best.1 {
+                best = (QueryPlan::StatusIndex, estimate);
+            }
+        }
+        if dated {
+            // Stop counting once the range can no longer beat the best estimate
+            let mut estimate = 0;
+            for (_, ids) in self.by_date.range(from..=to) {
+                estimate += ids.len();
+                if estimate >= best.1 {
+                    break;
+                }
+            }
+            if estimate < best.1 {
+                best = (QueryPlan::DateIndex, estimate);
+            }
+        }
+        best
+    }
+
+    // Trades matching the filter, read through the planned index, in no particular order
+    pub fn query(&self, filter: &TradeFilter) -> (QueryPlan, Vec<&Trade>) {
+        let (plan, _) = self.plan(filter);
+        let from = filter.date_from.unwrap_or(NaiveDate::MIN);
+        let to = filter.date_to.unwrap_or(NaiveDate::MAX);
+        let lookup = |ids: &mut dyn Iterator<Item = &TradeId>| -> Vec<&Trade> {
+            ids.filter_map(|trade_id| self.get(trade_id)).filter(|trade| trade.matches_filter(filter)).collect()
+        };
+        let trades = match plan {
+            QueryPlan::FullScan => self.values().filter(|trade| trade.matches_filter(filter)).collect(),
+            QueryPlan::InstrumentIndex => self.instrument_values_between(filter.instrument.as_deref().unwrap_or_default(), from, to)
+                .filter(|trade| trade.matches_filter(filter))
+                .collect(),
+            QueryPlan::DateIndex if from > to => Vec::new(),
+            QueryPlan::DateIndex => lookup(&mut self.by_date.range(from..=to).flat_map(|(_, ids)| ids.iter())),
+            QueryPlan::StatusIndex => {
+                let status = filter.status.as_ref().map_or("", trade_status_name);
+                lookup(&mut self.by_status.get(status).into_iter().flatten())
+            },
+        };
+        (plan, trades)
+    }
+
+    pub fn cold_get(&self, trade_id: TradeId) -> Option<&Trade> {
+        if self.superseded.contains(&trade_id) {
+            return None;
+        }
+        self.cold.iter().find_map(|segment| segment.get(trade_id))
+    }
+
+    pub fn insert(&mut self, trade_id: TradeId, trade: Trade) -> Option<Trade> {
+        if let Some(previous) = self.get(&trade_id).cloned() {
+            self.unindex(&previous);
+        }
+        self.index(&trade);
+        let previous = self.hot.insert(trade_id, trade);
+        let previous = match previous {
+            Some(previous) => Some(previous),
+            None => {
+                let cold = self.cold_get(trade_id).cloned();
+                if cold.is_some() {
+                    self.superseded.insert(trade_id);
+                    self.release_block_of(trade_id);
+                }
+                cold
+            }
+        };
+        previous
+    }
+
+    pub fn get(&self, trade_id: &TradeId) -> Option<&Trade> {
+        self.hot.get(trade_id).or_else(|| self.cold_get(*trade_id))
+    }
+
+    // Promotes a cold trade to the hot tier so it can be changed. The instrument, trade
+    // date, execution time and status are indexed, so changes to them go through insert.
+    pub fn get_mut(&mut self, trade_id: &TradeId) -> Option<&mut Trade> {
+        if !self.hot.contains_key(trade_id) {
+            let cold = self.cold_get(*trade_id)?.clone();
+            self.superseded.insert(*trade_id);
+            self.release_block_of(*trade_id);
+            self.hot.insert(*trade_id, cold);
+        }
+        self.hot.get_mut(trade_id)
+    }
+
+    pub fn contains_key(&self, trade_id: &TradeId) -> bool {
+        self.get(trade_id).is_some()
+    }
+
+    pub fn release_decoded(&mut self) {
+        for segment in &mut self.cold {
+            segment.release_decoded();
+        }
+    }
+
+    // A promoted trade is read from the hot tier from now on, so its block's decoded
+    // copy is dropped; other blocks keep theirs
+    pub fn release_block_of(&mut self, trade_id: TradeId) {
+        for segment in &mut self.cold {
+            segment.release_block_of(trade_id);
+        }
+    }
+
+    // Size of the cold tier as stored, before decoding
+    pub fn cold_bytes(&self) -> usize {
+        self.cold.iter().map(ColdSegment::encoded_bytes).sum()
+    }
+
+    pub fn is_hot(&self, trade_id: &TradeId) -> bool {
+        self.hot.contains_key(trade_id)
+    }
+
+    // Put a trade back as a rolled-back transaction found it: gone if it was not
+    // stored, otherwise the earlier copy in the tier that held it
+    pub fn restore(&mut self, trade_id: TradeId, before: Option<(Trade, bool)>) {
+        if let Some(current) = self.get(&trade_id).cloned() {
+            self.unindex(&current);
+        }
+        match before {
+            Some((trade, true)) => {
+                self.index(&trade);
+                self.hot.insert(trade_id, trade);
+            },
+            // The cold copy was never changed; it only needs unhiding
+            Some((trade, false)) => {
+                self.hot.remove(&trade_id);
+                self.superseded.remove(&trade_id);
+                self.index(&trade);
+            },
+            None => {
+                self.hot.remove(&trade_id);
+            },
+        }
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Trade> + '_ {
+        self.hot.values().chain(
+            self.cold
+                .iter()
+                .flat_map(ColdSegment::trades)
+                .filter(|trade| !self.superseded.contains(&trade.trade_id)),
+        )
+    }
+
+    // Trades dated within the range; cold segments outside it are skipped entirely
+    pub fn values_between(&self, start_date: NaiveDate, end_date: NaiveDate) -> impl Iterator<Item = &Trade> + '_ {
+        self.hot.values()
+            .chain(
+                self.cold
+                    .iter()
+                    .filter(move |segment| segment.first_date <= end_date && segment.last_date >= start_date)
+                    .flat_map(ColdSegment::trades)
+                    .filter(|trade| !self.superseded.contains(&trade.trade_id)),
+            )
+            .filter(move |trade| trade.trade_date >= start_date && trade.trade_date <= end_date)
+    }
+
+    // An instrument's trades dated within the range, by date then trade id,
+    // without scanning other instruments
+    pub fn instrument_values_between(&self, instrument: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Iterator<Item = &Trade> + '_ {
+        let ids = self.by_instrument.get(instrument).filter(|_| start_date <= end_date);
+        ids.into_iter()
+            .flat_map(move |days| days.range(start_date..=end_date))
+            .flat_map(|(_, keys)| keys.iter())
+            .filter_map(|(_, trade_id)| self.get(trade_id))
+    }
+
+    // Trades in an instrument dated `from_date` or later, in chronological order within
+    // each date, leaving out those on `from_date` up to and including `after`
+    pub fn instrument_values_from(&self, instrument: &str, from_date: NaiveDate, after: Option<ChronologicalKey>) -> impl Iterator<Item = &Trade> + '_ {
+        self.by_instrument.get(instrument).into_iter()
+            .flat_map(move |days| days.range(from_date..))
+            .flat_map(move |(date, keys)| match after.filter(|_| *date == from_date) {
+                Some(after) => keys.range((std::ops::Bound::Excluded(after), std::ops::Bound::Unbounded)),
+                None => keys.range(..),
+            })
+            .filter_map(|(_, trade_id)| self.get(trade_id))
+    }
+
+    pub fn instrument_values(&self, instrument: &str) -> impl Iterator<Item = &Trade> + '_ {
+        self.instrument_values_between(instrument, NaiveDate::MIN, NaiveDate::MAX)
+    }
+
+    // Chronological key of the latest active trade in an instrument dated before `date`
+    pub fn latest_active_before(&self, instrument: &str, date: NaiveDate) -> Option<ChronologicalKey> {
+        self.by_instrument.get(instrument)?.range(..date).rev().find_map(|(_, keys)| {
+            keys.iter().rev()
+                .find(|(_, trade_id)| self.get(trade_id).is_some_and(|trade| !matches!(trade.status, TradeStatus::Cancelled)))
+                .copied()
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&TradeId, &Trade)> + '_ {
+        self.values().map(|trade| (&trade.trade_id, trade))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &TradeId> + '_ {
+        self.values().map(|trade| &trade.trade_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.cold.iter().map(|segment| segment.len).sum::<usize>() - self.superseded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Move every hot trade dated before the cutoff into a new cold segment.
+    // Returns the number of trades sealed.
+    pub fn seal_before(&mut self, cutoff: NaiveDate) -> usize {
+        let sealed_ids: Vec<TradeId> = self.hot
+            .values()
+            .filter(|trade| trade.trade_date < cutoff)
+            .map(|trade| trade.trade_id)
+            .collect();
+        if sealed_ids.is_empty() {
+            return 0;
+        }
+
+        self.release_decoded();
+        let mut sealed: Vec<Trade> = sealed_ids.iter().filter_map(|trade_id| self.hot.remove(trade_id)).collect();
+        sealed.sort_by_key(|trade| trade.trade_id);
+
+        // Hot copies of cold trades replace the older cold copy in the new segment
+        self.cold = std::mem::take(&mut self.cold)
+            .into_iter()
+            .filter_map(|segment| {
+                if !sealed.iter().any(|trade| segment.get(trade.trade_id).is_some()) {
+                    return Some(segment);
+                }
+                let kept: Vec<Trade> = segment.trades()
+                    .filter(|trade| sealed.binary_search_by_key(&trade.trade_id, |sealed| sealed.trade_id).is_err())
+                    .cloned()
+                    .collect();
+                ColdSegment::seal(kept)
+            })
+            .collect();
+        for trade in &sealed {
+            self.superseded.remove(&trade.trade_id);
+        }
+
+        let count = sealed.len();
+        self.cold.extend(ColdSegment::seal(sealed));
+        self.release_decoded();
+        count
+    }
+
+    // Drop trades from both tiers. Cold segments holding any of them are rewritten
+    // without them. Returns the number of segments rewritten.
+    pub fn purge(&mut self, trade_ids: &HashSet<TradeId>) -> usize {
+        let purged: Vec<Trade> = trade_ids.iter().filter_map(|trade_id| self.get(trade_id).cloned()).collect();
+        for trade in &purged {
+            self.unindex(trade);
+        }
+        self.hot.retain(|trade_id, _| !trade_ids.contains(trade_id));
+        let mut rewritten = 0;
+        self.cold = std::mem::take(&mut self.cold)
+            .into_iter()
+            .filter_map(|segment| {
+                if !segment.trades().any(|trade| trade_ids.contains(&trade.trade_id)) {
+                    return Some(segment);
+                }
+                rewritten += 1;
+                ColdSegment::seal(segment.trades().filter(|trade| !trade_ids.contains(&trade.trade_id)).cloned().collect())
+            })
+            .collect();
+        self.superseded.retain(|trade_id| !trade_ids.contains(trade_id));
+        self.release_decoded();
+        rewritten
+    }
+}
+
+impl Default for TradeStore {
+    fn default() -> TradeStore {
+        TradeStore::new()
+    }
+}
+
+// How closing trades pick the open quantity they close against
+#[derive(Debug, Clone, Copy)]
+pub enum CostBasisMethod {
+    AverageCost,
+    Fifo,
+    Lifo,
+    // Highest cost first: the most expensive long lot, or the cheapest short lot
+    Hifo,
+    // Lots named for the closing trade via select_lots, then FIFO for the rest
+    SpecificLot,
+}
+
+// Open quantity from a single trade; negative quantity for short lots
+#[derive(Debug, Clone)]
+pub struct Lot {
+    pub trade_id: TradeId,
+    pub open_date: NaiveDate,
+    pub quantity: i32,
+    pub price: Price,
+}
+
+// Quantity of one lot closed by one trade, with the P&L it realized. Prices are
+// net of the opening and closing trades' fees.
+#[derive(Debug, Clone)]
+pub struct LotClosure {
+    pub lot_trade_id: TradeId,
+    pub open_date: NaiveDate,
+    pub open_price: Price,
+    pub closing_trade_id: TradeId,
+    pub close_date: NaiveDate,
+    pub close_price: Price,
+    // Signed like the lot: negative when a short lot was covered
+    pub quantity: i32,
+    pub realized_pnl: Money,
+}
+
+impl LotClosure {
+    pub fn holding_days(&self) -> i64 {
+        (self.close_date - self.open_date).num_days()
+    }
+}
+
+// Position kept as open lots under a cost-basis method
+#[derive(Debug, Clone)]
+pub struct LotPosition {
+    pub instrument: String,
+    pub method: CostBasisMethod,
+    pub lots: Vec<Lot>,
+    pub realized_pnl: Money,
+    pub closures: Vec<LotClosure>,
+    // As on TradePosition: lot prices are per unit, money is not
+    pub multiplier: Decimal,
+}
+
+impl LotPosition {
+    pub fn new(instrument: String, method: CostBasisMethod) -> LotPosition {
+        LotPosition {
+            instrument,
+            method,
+            lots: Vec::new(),
+            realized_pnl: Decimal::ZERO,
+            closures: Vec::new(),
+            multiplier: Decimal::from(1),
+        }
+    }
+
+    pub fn quantity(&self) -> i32 {
+        self.lots.iter().map(|lot| lot.quantity).sum()
+    }
+
+    // Open lots at their prices, per unit of the multiplier
+    pub fn lot_points(&self) -> Price {
+        self.lots.iter().map(|lot| lot.price * lot.quantity).sum()
+    }
+
+    pub fn cost_basis(&self) -> Money {
+        self.lot_points() * self.multiplier
+    }
+
+    pub fn average_price(&self) -> Price {
+        let quantity = self.quantity();
+        if quantity == 0 { Decimal::ZERO } else { self.lot_points() / quantity }
+    }
+
+    pub fn unrealized_pnl(&self, current_price: Price) -> Money {
+        current_price * self.quantity() * self.multiplier - self.cost_basis()
+    }
+
+    // `selection` lists the lots a SpecificLot closing trade should consume, in order
+    pub fn apply(&mut self, trade: &Trade, selection: &[TradeId]) {
+        let price = trade.net_price();
+        let mut remaining = trade.signed_quantity();
+        self.multiplier = trade.multiplier;
+
+        // Close against open lots on the other side, in the method's order
+        while remaining != 0 && self.lots.first().is_some_and(|lot| lot.quantity.signum() != remaining.signum()) {
+            let index = match self.method {
+                CostBasisMethod::Lifo => self.lots.len() - 1,
+                CostBasisMethod::Fifo | CostBasisMethod::AverageCost => 0,
+                CostBasisMethod::Hifo => (0..self.lots.len())
+                    .min_by_key(|&i| -self.lots[i].price * self.lots[i].quantity.signum())
+                    .unwrap(),
+                CostBasisMethod::SpecificLot => selection
+                    .iter()
+                    .find_map(|trade_id| self.lots.iter().position(|lot| lot.trade_id == *trade_id))
+                    .unwrap_or(0),
+            };
+            let lot = &mut self.lots[index];
+            let closed = remaining.abs().min(lot.quantity.abs()) * lot.quantity.signum();
+            let realized_pnl = (price - lot.price) * closed * self.multiplier;
+            self.realized_pnl += realized_pnl;
+            self.closures.push(LotClosure {
+                lot_trade_id: lot.trade_id,
+                open_date: lot.open_date,
+                open_price: lot.price,
+                closing_trade_id: trade.trade_id,
+                close_date: trade.trade_date,
+                close_price: price,
+                quantity: closed,
+                realized_pnl,
+            });
+            lot.quantity -= closed;
+            remaining += closed;
+            if lot.quantity == 0 {
+                self.lots.remove(index);
+            }
+        }
+
+        if remaining != 0 {
+            match (self.method, self.lots.first_mut()) {
+                // Average cost keeps a single lot at the weighted average price
+                (CostBasisMethod::AverageCost, Some(lot)) => {
+                    let quantity = lot.quantity + remaining;
+                    lot.price = (lot.price * lot.quantity + price * remaining) / quantity;
+                    lot.quantity = quantity;
+                },
+                _ => self.lots.push(Lot {
+                    trade_id: trade.trade_id,
+                    open_date: trade.trade_date,
+                    quantity: remaining,
+                    price,
+                }),
+            }
+        }
+    }
+}
+
+// P&L of one instrument under one cost-basis method, and its difference from the
+// first method in the comparison
+#[derive(Debug, Clone)]
+pub struct CostBasisResult {
+    pub method: CostBasisMethod,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub realized_difference: Money,
+    pub unrealized_difference: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct CostBasisComparison {
+    pub instrument: String,
+    pub quantity: i32,
+    pub market_price: Price,
+    pub results: Vec<CostBasisResult>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AssetClass {
+    Equity,
+    Future,
+    Option,
+    Fx,
+    Bond,
+    Crypto,
+}
+
+// Instrument master record. The multiplier is the money value of one point of
+// price per unit held, e.g. 50 for an E-mini S&P 500 future; prices stay quoted
+// per point, while market values and P&L are scaled by it.
+#[derive(Debug, Clone)]
+pub struct Instrument {
+    pub symbol: String,
+    pub asset_class: AssetClass,
+    pub currency: String,
+    pub multiplier: Decimal,
+    // Smallest price increment and smallest tradable quantity
+    pub tick_size: Price,
+    pub lot_size: i32,
+    // Business days from trade date to settlement
+    pub settlement_days: u32,
+}
+
+impl Instrument {
+    pub fn new(symbol: &str, asset_class: AssetClass, currency: &str) -> Instrument {
+        Instrument {
+            symbol: symbol.to_string(),
+            asset_class,
+            currency: currency.to_string(),
+            multiplier: Decimal::from(1),
+            tick_size: Decimal::ZERO,
+            lot_size: 1,
+            settlement_days: DEFAULT_SETTLEMENT_DAYS,
+        }
+    }
+
+    pub fn multiplier(mut self, multiplier: impl Into<Decimal>) -> Self {
+        self.multiplier = multiplier.into();
+        self
+    }
+
+    pub fn tick_size(mut self, tick_size: impl Into<Price>) -> Self {
+        self.tick_size = tick_size.into();
+        self
+    }
+
+    pub fn lot_size(mut self, lot_size: i32) -> Self {
+        self.lot_size = lot_size;
+        self
+    }
+
+    pub fn settlement_days(mut self, settlement_days: u32) -> Self {
+        self.settlement_days = settlement_days;
+        self
+    }
+
+    pub fn check(&self, quantity: i32, price: Price) -> Result<(), String> {
+        if self.lot_size > 1 && quantity % self.lot_size != 0 {
+            return Err(format!("quantity {} is not a multiple of the lot size {}", quantity, self.lot_size));
+        }
+        if !price.is_multiple_of(self.tick_size) {
+            return Err(format!("price {} is not on the {} tick", price, self.tick_size));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ContractKind {
+    Future,
+    Option,
+}
+
+// What to do with a position when its contract expires
+#[derive(Debug, Clone)]
+pub enum RollRule {
+    // Close the position at settlement and stay flat
+    Close,
+    // Close at settlement and re-open the same quantity in the next contract
+    RollInto(String),
+    // Options: close at intrinsic value and, when in the money, take or make
+    // delivery of the underlying at its settlement price
+    Exercise,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OptionRight {
+    Call,
+    Put,
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionTerms {
+    pub right: OptionRight,
+    pub strike: f64,
+    // Shares of the underlying per contract
+    pub multiplier: f64,
+}
+
+impl OptionTerms {
+    pub fn intrinsic_value(&self, spot: Price) -> Price {
+        let strike = Decimal::from(self.strike);
+        match self.right {
+            OptionRight::Call => (spot - strike).max(Decimal::ZERO),
+            OptionRight::Put => (strike - spot).max(Decimal::ZERO),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DerivativeContract {
+    pub instrument: String,
+    // Symbol root shared by all expiries of the same future, e.g. "ES";
+    // for options this is the underlying instrument
+    pub root: String,
+    pub kind: ContractKind,
+    pub expiry_date: NaiveDate,
+    pub roll_rule: RollRule,
+    pub option_terms: Option<OptionTerms>,
+}
+
+impl DerivativeContract {
+    pub fn new(instrument: String, root: String, kind: ContractKind, expiry_date: NaiveDate, roll_rule: RollRule) -> DerivativeContract {
+        DerivativeContract {
+            instrument,
+            root,
+            kind,
+            expiry_date,
+            roll_rule,
+            option_terms: None,
+        }
+    }
+
+    pub fn with_option_terms(mut self, right: OptionRight, strike: f64, multiplier: f64) -> Self {
+        self.option_terms = Some(OptionTerms { right, strike, multiplier });
+        self
+    }
+}
+
+// Black-Scholes sensitivities of one option on one share of the underlying
+#[derive(Debug, Clone, Copy)]
+pub struct OptionGreeks {
+    pub delta: f64,
+    pub gamma: f64,
+    // Per one volatility point
+    pub vega: f64,
+    // Per calendar day
+    pub theta: f64,
+}
+
+pub fn norm_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+// Abramowitz-Stegun 7.1.26 approximation of erf, accurate to about 1e-7
+pub fn norm_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * z);
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - poly * (-z * z).exp();
+    if x >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+pub fn option_greeks(right: OptionRight, spot: f64, strike: f64, volatility: f64, rate: f64, years: f64) -> OptionGreeks {
+    if years <= 0.0 || volatility <= 0.0 {
+        // At or past expiry only the intrinsic delta is left
+        let delta = match right {
+            OptionRight::Call if spot > strike => 1.0,
+            OptionRight::Put if spot < strike => -1.0,
+            _ => 0.0,
+        };
+        return OptionGreeks { delta, gamma: 0.0, vega: 0.0, theta: 0.0 };
+    }
+
+    let sqrt_t = years.sqrt();
+    let d1 = ((spot / strike).ln() + (rate + 0.5 * volatility * volatility) * years) / (volatility * sqrt_t);
+    let d2 = d1 - volatility * sqrt_t;
+    let discount = (-rate * years).exp();
+    let decay = -spot * norm_pdf(d1) * volatility / (2.0 * sqrt_t);
+
+    let (delta, theta) = match right {
+        OptionRight::Call => (norm_cdf(d1), decay - rate * strike * discount * norm_cdf(d2)),
+        OptionRight::Put => (norm_cdf(d1) - 1.0, decay + rate * strike * discount * norm_cdf(-d2)),
+    };
+
+    OptionGreeks {
+        delta,
+        gamma: norm_pdf(d1) / (spot * volatility * sqrt_t),
+        vega: spot * norm_pdf(d1) * sqrt_t / 100.0,
+        theta: theta / 365.0,
+    }
+}
+
+// Lifecycle actions recorded in the repository's event log
+#[derive(Debug, Clone)]
+pub enum LifecycleEvent {
+    // Original trade cancelled and replaced by a correction in one step
+    Rebooked {
+        date: NaiveDate,
+        original_trade_id: TradeId,
+        corrected_trade_id: TradeId,
+    },
+    Expired {
+        date: NaiveDate,
+        instrument: String,
+        quantity: i32,
+        settlement_price: Price,
+        realized_pnl: Money,
+        closing_trade_id: TradeId,
+    },
+    // In-the-money option delivered into the underlying; quantity is signed
+    Exercised {
+        date: NaiveDate,
+        instrument: String,
+        underlying: String,
+        quantity: i32,
+        price: Price,
+        opening_trade_id: TradeId,
+    },
+    Rolled {
+        date: NaiveDate,
+        from_instrument: String,
+        to_instrument: String,
+        quantity: i32,
+        price: Price,
+        opening_trade_id: TradeId,
+    },
+    // Expiry or roll that could not be carried out
+    Skipped {
+        date: NaiveDate,
+        instrument: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for LifecycleEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LifecycleEvent::Rebooked { date, original_trade_id, corrected_trade_id } =>
+                write!(f, "{} trade {} rebooked as trade {}", date, original_trade_id, corrected_trade_id),
+            LifecycleEvent::Expired { date, instrument, quantity, settlement_price, realized_pnl, closing_trade_id } =>
+                write!(f, "{} {} expired: {} closed at {} by trade {}, realized {}", date, instrument, quantity, settlement_price, closing_trade_id, realized_pnl),
+            LifecycleEvent::Rolled { date, from_instrument, to_instrument, quantity, price, opening_trade_id } =>
+                write!(f, "{} {} rolled into {}: {} opened at {} by trade {}", date, from_instrument, to_instrument, quantity, price, opening_trade_id),
+            LifecycleEvent::Exercised { date, instrument, underlying, quantity, price, opening_trade_id } =>
+                write!(f, "{} {} exercised: {} {} delivered at {} by trade {}", date, instrument, quantity, underlying, price, opening_trade_id),
+            LifecycleEvent::Skipped { date, instrument, reason } => write!(f, "{} {} skipped: {}", date, instrument, reason),
+        }
+    }
+}
+
+// Exchange days on which futures settle and variation margin is called
+#[derive(Debug, Clone)]
+pub struct SettlementCalendar {
+    pub name: String,
+    pub holidays: BTreeSet<NaiveDate>,
+}
+
+impl SettlementCalendar {
+    pub fn new(name: &str) -> SettlementCalendar {
+        SettlementCalendar {
+            name: name.to_string(),
+            holidays: BTreeSet::new(),
+        }
+    }
+
+    pub fn holiday(mut self, date: NaiveDate) -> Self {
+        self.holidays.insert(date);
+        self
+    }
+
+    pub fn is_settlement_day(&self, date: NaiveDate) -> bool {
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&date)
+    }
+
+    // Settlement days from `start_date` through `end_date`
+    pub fn settlement_days(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<NaiveDate> {
+        start_date.iter_days().take_while(|date| *date <= end_date).filter(|date| self.is_settlement_day(*date)).collect()
+    }
+}
+
+// One day's variation margin on a futures position: its move from the previous
+// settlement, or from the day's trade prices, to today's settlement price
+#[derive(Debug, Clone)]
+pub struct VariationMargin {
+    pub date: NaiveDate,
+    pub instrument: String,
+    pub currency: String,
+    pub quantity: i32,
+    pub previous_price: Price,
+    pub settlement_price: Price,
+    // Received (positive) or paid
+    pub amount: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct VariationMarginReport {
+    pub date: NaiveDate,
+    pub calls: Vec<VariationMargin>,
+    // Open futures positions left unsettled, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+impl VariationMarginReport {
+    // Net margin received per currency
+    pub fn totals(&self) -> BTreeMap<String, Money> {
+        let mut totals: BTreeMap<String, Money> = BTreeMap::new();
+        for call in &self.calls {
+            *totals.entry(call.currency.clone()).or_insert(Decimal::ZERO) += call.amount;
+        }
+        totals
+    }
+}
+
+// Margin a position needs, before and after it is put on
+#[derive(Debug, Clone)]
+pub enum MarginRule {
+    // Reg-T style fractions of the position's absolute market value
+    Percentage { initial: f64, maintenance: f64 },
+    // Flat amounts per contract held, as exchanges set for futures
+    PerContract { initial: Money, maintenance: Money },
+}
+
+impl MarginRule {
+    // (initial, maintenance) for a position of the quantity and market value
+    pub fn requirement(&self, quantity: i32, market_value: Money) -> (Money, Money) {
+        match self {
+            MarginRule::Percentage { initial, maintenance } => (market_value.abs() * *initial, market_value.abs() * *maintenance),
+            MarginRule::PerContract { initial, maintenance } => (*initial * quantity.abs(), *maintenance * quantity.abs()),
+        }
+    }
+}
+
+// The rule for an instrument is its own, else its asset class's, else the default
+#[derive(Debug, Clone)]
+pub struct MarginRules {
+    pub instruments: HashMap<String, MarginRule>,
+    pub asset_classes: Vec<(AssetClass, MarginRule)>,
+    pub default: MarginRule,
+}
+
+impl MarginRules {
+    // 50% initial and 25% maintenance on everything
+    pub fn reg_t() -> Self {
+        MarginRules {
+            instruments: HashMap::new(),
+            asset_classes: Vec::new(),
+            default: MarginRule::Percentage { initial: 0.50, maintenance: 0.25 },
+        }
+    }
+
+    pub fn instrument(mut self, instrument: &str, rule: MarginRule) -> Self {
+        self.instruments.insert(instrument.to_string(), rule);
+        self
+    }
+
+    pub fn asset_class(mut self, asset_class: AssetClass, rule: MarginRule) -> Self {
+        self.asset_classes.retain(|(class, _)| *class != asset_class);
+        self.asset_classes.push((asset_class, rule));
+        self
+    }
+
+    pub fn rule_for(&self, instrument: &str, asset_class: Option<AssetClass>) -> &MarginRule {
+        self.instruments
+            .get(instrument)
+            .or_else(|| self.asset_classes.iter().find(|(class, _)| Some(*class) == asset_class).map(|(_, rule)| rule))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MarginLine {
+    pub instrument: String,
+    pub quantity: i32,
+    pub market_value: Money,
+    pub initial: Money,
+    pub maintenance: Money,
+}
+
+// An account's margin in the firm currency. Equity is the account's portfolio
+// equity, where futures count for their P&L rather than their notional. Per-contract
+// amounts are in the instrument's currency.
+#[derive(Debug, Clone)]
+pub struct AccountMargin {
+    pub account_id: String,
+    pub equity: Money,
+    pub lines: Vec<MarginLine>,
+}
+
+impl AccountMargin {
+    pub fn initial(&self) -> Money {
+        self.lines.iter().map(|line| line.initial).sum()
+    }
+
+    pub fn maintenance(&self) -> Money {
+        self.lines.iter().map(|line| line.maintenance).sum()
+    }
+
+    // Equity above the maintenance requirement; negative when margin is called
+    pub fn excess(&self) -> Money {
+        self.equity - self.maintenance()
+    }
+
+    // Amount to deposit to get back to the maintenance requirement
+    pub fn margin_call(&self) -> Option<Money> {
+        let excess = self.excess();
+        (excess < Decimal::ZERO).then(|| -excess)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MarginReport {
+    pub as_of_date: NaiveDate,
+    pub accounts: Vec<AccountMargin>,
+}
+
+impl MarginReport {
+    pub fn calls(&self) -> Vec<&AccountMargin> {
+        self.accounts.iter().filter(|account| account.margin_call().is_some()).collect()
+    }
+}
+
+pub fn print_margin_report(report: &MarginReport) {
+    println!("\n=== Margin as of {} ===", report.as_of_date);
+    println!("{:<12} {:>16} {:>16} {:>16} {:>16}", "Account", "Equity", "Initial", "Maintenance", "Excess");
+    for account in &report.accounts {
+        println!("{:<12} {:>16.2} {:>16.2} {:>16.2} {:>16.2}", account.account_id, account.equity, account.initial(), account.maintenance(), account.excess());
+    }
+    for account in report.calls() {
+        println!("MARGIN CALL {}: {:.2}", account.account_id, account.margin_call().unwrap_or_default());
+        for line in &account.lines {
+            println!("  {:<10} {:>10} {:>16.2} maintenance {:>14.2}", line.instrument, line.quantity, line.market_value, line.maintenance);
+        }
+    }
+}
+
+// One day of a continuous futures series stitched from individual contracts
+#[derive(Debug, Clone)]
+pub struct ContinuousPoint {
+    pub date: NaiveDate,
+    pub contract: String,
+    pub raw_price: Price,
+    // Price shifted by the roll gaps of all later rolls (back-adjusted)
+    pub adjusted_price: Price,
+}
+
+// Two positions traded together as a long/short pair
+#[derive(Debug, Clone)]
+pub struct PairDefinition {
+    pub name: String,
+    pub long_leg: String,
+    pub short_leg: String,
+    // Short-leg shares per long-leg share when the pair was linked
+    pub target_hedge_ratio: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct PairReport {
+    pub name: String,
+    pub long_leg_pnl: Money,
+    pub short_leg_pnl: Money,
+    pub combined_pnl: Money,
+    // Long-leg price minus hedge ratio times short-leg price
+    pub spread: Price,
+    pub current_hedge_ratio: f64,
+    pub hedge_ratio_drift: f64,
+}
+
+// End-of-day FX rates, quoted as the value of one unit of each currency in the firm currency
+#[derive(Debug, Clone)]
+pub struct FxRates {
+    pub rates: HashMap<String, f64>,
+}
+
+impl FxRates {
+    pub fn new(firm_currency: &str) -> FxRates {
+        FxRates {
+            rates: HashMap::from([(firm_currency.to_string(), 1.0)]),
+        }
+    }
+
+    pub fn rate(mut self, currency: &str, rate: f64) -> Self {
+        self.rates.insert(currency.to_string(), rate);
+        self
+    }
+
+    pub fn convert(&self, amount: Money, from: &str, to: &str) -> Result<Money, String> {
+        let from_rate = self.rates.get(from).ok_or(format!("No FX rate for {}", from))?;
+        let to_rate = self.rates.get(to).ok_or(format!("No FX rate for {}", to))?;
+        let ratio = Decimal::from_f64(from_rate / to_rate).map_err(|e| format!("No usable FX rate from {} to {}: {}", from, to, e))?;
+        Ok(amount * ratio)
+    }
+}
+
+// What to do when the FX rate store has no rate on the requested date
+#[derive(Debug, Clone, Copy)]
+pub enum FxFallback {
+    // Use the latest rate before the date
+    PreviousBusinessDay,
+    // Interpolate linearly between the surrounding rates
+    Interpolate,
+    // Refuse the conversion
+    Fail,
+}
+
+// Dated values of one metric, one series per instrument (or currency): closes,
+// ADV, betas, borrow rates. value_on gives the last known value on or before a date.
+#[derive(Debug, Clone)]
+pub struct TimeSeriesStore<T> {
+    pub series: HashMap<String, BTreeMap<NaiveDate, T>>,
+}
+
+impl<T: Copy> TimeSeriesStore<T> {
+    pub fn new() -> TimeSeriesStore<T> {
+        TimeSeriesStore { series: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, name: &str, date: NaiveDate, value: T) {
+        self.series.entry(name.to_string()).or_default().insert(date, value);
+    }
+
+    pub fn extend(&mut self, name: &str, points: impl IntoIterator<Item = (NaiveDate, T)>) {
+        self.series.entry(name.to_string()).or_default().extend(points);
+    }
+
+    // Value recorded on exactly this date
+    pub fn get(&self, name: &str, date: NaiveDate) -> Option<T> {
+        self.series.get(name)?.get(&date).copied()
+    }
+
+    pub fn value_on(&self, name: &str, date: NaiveDate) -> Option<T> {
+        self.series.get(name)?.range(..=date).next_back().map(|(_, value)| *value)
+    }
+
+    pub fn latest(&self, name: &str) -> Option<(NaiveDate, T)> {
+        self.series.get(name)?.iter().next_back().map(|(date, value)| (*date, *value))
+    }
+
+    // Points between the dates inclusive, oldest first
+    pub fn range(&self, name: &str, start_date: NaiveDate, end_date: NaiveDate) -> impl Iterator<Item = (NaiveDate, T)> + '_ {
+        self.series.get(name).into_iter().flat_map(move |points| points.range(start_date..=end_date)).map(|(date, value)| (*date, *value))
+    }
+
+    pub fn series(&self, name: &str) -> Option<&BTreeMap<NaiveDate, T>> {
+        self.series.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> + '_ {
+        self.series.keys()
+    }
+
+    // Drop every point between the dates inclusive, across all series
+    pub fn remove_range(&mut self, start_date: NaiveDate, end_date: NaiveDate) {
+        for points in self.series.values_mut() {
+            points.retain(|date, _| *date < start_date || *date > end_date);
+        }
+        self.series.retain(|_, points| !points.is_empty());
+    }
+
+    // Move a series to a new name, e.g. after a symbol change
+    pub fn rename(&mut self, from: &str, to: &str) {
+        rekey(&mut self.series, from, to);
+    }
+
+    // Bulk load from CSV lines of three columns, date (YYYY-MM-DD), name and value,
+    // e.g. "date,instrument,adv". A header line is skipped. Returns the number of
+    // points imported; nothing is imported if any line is bad.
+    pub fn import_csv(&mut self, csv: &str, columns: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<usize, String> {
+        let value_column = columns.rsplit(',').next().unwrap_or("value");
+        let mut points = Vec::new();
+        for (line_number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || (line_number == 0 && line.starts_with("date")) {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 3 {
+                return Err(format!("Line {}: expected {}", line_number + 1, columns));
+            }
+            let date = NaiveDate::parse_from_str(fields[0], "%Y-%m-%d")
+                .map_err(|e| format!("Line {}: invalid date {}: {}", line_number + 1, fields[0], e))?;
+            let value = parse(fields[2]).map_err(|e| format!("Line {}: invalid {} {}: {}", line_number + 1, value_column, fields[2], e))?;
+            points.push((fields[1], date, value));
+        }
+        for (name, date, value) in &points {
+            self.insert(name, *date, *value);
+        }
+        Ok(points.len())
+    }
+}
+
+impl<T: Copy> Default for TimeSeriesStore<T> {
+    fn default() -> TimeSeriesStore<T> {
+        TimeSeriesStore::new()
+    }
+}
+
+// Dated history of FX rates, quoted like FxRates in the firm currency
+#[derive(Debug, Clone)]
+pub struct FxRateStore {
+    pub firm_currency: String,
+    pub rates: TimeSeriesStore<f64>,
+    pub fallback: FxFallback,
+}
+
+impl FxRateStore {
+    pub fn new(firm_currency: &str, fallback: FxFallback) -> FxRateStore {
+        FxRateStore {
+            firm_currency: firm_currency.to_string(),
+            rates: TimeSeriesStore::new(),
+            fallback,
+        }
+    }
+
+    pub fn set_rate(&mut self, currency: &str, date: NaiveDate, rate: f64) {
+        self.rates.insert(currency, date, rate);
+    }
+
+    pub fn rate_on(&self, currency: &str, date: NaiveDate) -> Result<f64, String> {
+        if currency == self.firm_currency {
+            return Ok(1.0);
+        }
+        let history = self.rates.series(currency).ok_or(format!("No FX rates for {}", currency))?;
+        if let Some(rate) = history.get(&date) {
+            return Ok(*rate);
+        }
+
+        let previous = history.range(..date).next_back();
+        let next = history.range(date..).next();
+        match (self.fallback, previous, next) {
+            (FxFallback::PreviousBusinessDay, Some((_, rate)), _) => Ok(*rate),
+            (FxFallback::Interpolate, Some((prev_date, prev_rate)), Some((next_date, next_rate))) => {
+                let span = (*next_date - *prev_date).num_days() as f64;
+                let elapsed = (date - *prev_date).num_days() as f64;
+                Ok(prev_rate + (next_rate - prev_rate) * elapsed / span)
+            },
+            _ => Err(format!("No FX rate for {} on {}", currency, date)),
+        }
+    }
+
+    // Rates for every known currency on a date. Currencies without a usable rate are
+    // left out, so converting them fails rather than silently using a stale rate.
+    pub fn rates_on(&self, date: NaiveDate) -> FxRates {
+        let mut rates = FxRates::new(&self.firm_currency);
+        for currency in self.rates.names() {
+            if let Ok(rate) = self.rate_on(currency, date) {
+                rates = rates.rate(currency, rate);
+            }
+        }
+        rates
+    }
+
+    // Import rates from CSV lines of `date,currency,rate` (dates as YYYY-MM-DD).
+    // A header line is skipped. Returns the number of rates imported.
+    pub fn import_csv(&mut self, csv: &str) -> Result<usize, String> {
+        let positive = |text: &str| text.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate > 0.0).ok_or("not a positive number".to_string());
+        self.rates.import_csv(csv, "date,currency,rate", positive)
+    }
+}
+
+pub fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN).and_utc()
+}
+
+// A change to a position that comes from no trade, kept per date and replayed
+// after that day's trades whenever the position is rebuilt
+#[derive(Debug, Clone, Copy)]
+pub enum PositionAdjustment {
+    // Futures daily settlement at this price
+    Settlement(Price),
+    // Income such as a dividend, added to realized P&L
+    RealizedCredit(Money),
+}
+
+// Daily position mark once a day is adjusted: trades executed before the next
+// midnight are out of order and rebuild the position through the adjustment
+pub fn adjusted_mark(date: NaiveDate) -> (DateTime<Utc>, TradeId) {
+    (start_of_day(date.succ_opt().unwrap_or(date)), TradeId(i128::MIN))
+}
+
+// Apply the adjustments dated before `before` to a replayed position, each leaving
+// its day's entry in the daily table. Returns the date of the last one applied.
+pub fn adjust_before(position: &mut TradePosition, days: &mut BTreeMap<NaiveDate, TradePosition>,
+                 adjustments: &mut std::iter::Peekable<impl Iterator<Item = (NaiveDate, PositionAdjustment)>>, before: NaiveDate) -> Option<NaiveDate> {
+    let mut applied = None;
+    while let Some((date, adjustment)) = adjustments.next_if(|(date, _)| *date < before) {
+        match adjustment {
+            PositionAdjustment::Settlement(price) => {
+                position.settle_at(price);
+            },
+            PositionAdjustment::RealizedCredit(amount) => position.realized_pnl += amount,
+        }
+        days.insert(date, position.clone());
+        applied = Some(date);
+    }
+    applied
+}
+
+// Trades folded into an instrument between two checkpoints, and how many of the
+// latest folds can be taken back out without a replay
+pub const FOLD_CHECKPOINT_INTERVAL: usize = 64;
+pub const FOLD_UNDO_DEPTH: usize = 64;
+
+// Position of an instrument as its trades are folded in, kept so that amending or
+// cancelling a trade replays only the trades after the checkpoint before it rather
+// than its whole day, and taking back the latest trades needs no replay at all
+#[derive(Debug, Clone, Default)]
+pub struct FoldCheckpoints {
+    // Running position after every FOLD_CHECKPOINT_INTERVAL-th trade, with its trade date
+    pub points: BTreeMap<ChronologicalKey, (NaiveDate, TradePosition)>,
+    pub since_last: usize,
+    // The latest folds, newest last
+    pub undo: VecDeque<FoldStep>,
+}
+
+// What folding one trade changed, to put back if it is cancelled or amended
+#[derive(Debug, Clone)]
+pub struct FoldStep {
+    pub key: ChronologicalKey,
+    pub trade_date: NaiveDate,
+    pub before: TradePosition,
+    pub previous_mark: Option<ChronologicalKey>,
+    // The trade was the first on its date, so its day had no entry before it
+    pub opened_day: bool,
+}
+
+impl FoldCheckpoints {
+    pub fn record(&mut self, trade: &Trade, position: &TradePosition) {
+        self.since_last += 1;
+        if self.since_last >= FOLD_CHECKPOINT_INTERVAL {
+            self.points.insert(trade.chronological_key(), (trade.trade_date, position.clone()));
+            self.since_last = 0;
+        }
+    }
+
+    pub fn push_undo(&mut self, step: FoldStep) {
+        if self.undo.len() == FOLD_UNDO_DEPTH {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(step);
+    }
+}
+
+// Fold trades, already in chronological order, into a running position: each day's
+// entry is written once the day is done, adjustments are applied between days and
+// after the last trade, and the checkpoints follow along. `open` is the day the
+// position already holds trades for without an entry written. Returns the mark.
+pub fn fold_into_days<'a, I>(position: &mut TradePosition, days: &mut BTreeMap<NaiveDate, TradePosition>, checkpoints: &mut FoldCheckpoints,
+                         adjustments: &mut std::iter::Peekable<impl Iterator<Item = (NaiveDate, PositionAdjustment)>>,
+                         mut open: Option<NaiveDate>, mut mark: Option<ChronologicalKey>, trades: I) -> Option<ChronologicalKey>
+where
+    I: IntoIterator<Item = &'a Trade>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let trades = trades.into_iter();
+    let undo_from = trades.len().saturating_sub(FOLD_UNDO_DEPTH);
+    for (count, trade) in trades.enumerate() {
+        if let Some(date) = open.filter(|date| *date != trade.trade_date) {
+            days.insert(date, position.clone());
+            open = None;
+        }
+        if let Some(date) = adjust_before(position, days, adjustments, trade.trade_date) {
+            mark = mark.max(Some(adjusted_mark(date)));
+        }
+        if count >= undo_from {
+            checkpoints.push_undo(FoldStep {
+                key: trade.chronological_key(),
+                trade_date: trade.trade_date,
+                before: position.clone(),
+                previous_mark: mark,
+                opened_day: open.is_none() && !days.contains_key(&trade.trade_date),
+            });
+        }
+        position.update_position(trade);
+        checkpoints.record(trade, position);
+        open = Some(trade.trade_date);
+        mark = Some(trade.chronological_key());
+    }
+    if let Some(date) = open {
+        days.insert(date, position.clone());
+    }
+    if let Some(date) = adjust_before(position, days, adjustments, NaiveDate::MAX) {
+        mark = mark.max(Some(adjusted_mark(date)));
+    }
+    mark
+}
+
+// Move an instrument's entry to its new symbol
+pub fn rekey<V>(map: &mut HashMap<String, V>, from: &str, to: &str) {
+    if let Some(value) = map.remove(from) {
+        map.insert(to.to_string(), value);
+    }
+}
+
+pub fn flatten_adjustments(adjustments: &BTreeMap<NaiveDate, Vec<PositionAdjustment>>) -> impl Iterator<Item = (NaiveDate, PositionAdjustment)> + '_ {
+    adjustments.iter().flat_map(|(date, list)| list.iter().map(move |adjustment| (*date, *adjustment)))
+}
+
+pub fn previous_business_day(date: NaiveDate) -> NaiveDate {
+    let mut previous = date.pred_opt().unwrap_or(date);
+    while matches!(previous.weekday(), Weekday::Sat | Weekday::Sun) {
+        previous = previous.pred_opt().unwrap_or(previous);
+    }
+    previous
+}
+
+pub fn next_business_day(date: NaiveDate) -> NaiveDate {
+    let mut next = date.succ_opt().unwrap_or(date);
+    while matches!(next.weekday(), Weekday::Sat | Weekday::Sun) {
+        next = next.succ_opt().unwrap_or(next);
+    }
+    next
+}
+
+// An exchange's trading hours in its local time, at a fixed offset from UTC.
+// Sessions open and close on the same local day; weekends and holidays have none,
+// and half days close early.
+#[derive(Debug, Clone)]
+pub struct SessionCalendar {
+    pub name: String,
+    pub utc_offset_minutes: i32,
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+    pub holidays: BTreeSet<NaiveDate>,
+    pub half_days: BTreeMap<NaiveDate, NaiveTime>,
+}
+
+impl SessionCalendar {
+    pub fn new(name: &str, utc_offset_minutes: i32, open: NaiveTime, close: NaiveTime) -> Self {
+        SessionCalendar {
+            name: name.to_string(),
+            utc_offset_minutes,
+            open,
+            close,
+            holidays: BTreeSet::new(),
+            half_days: BTreeMap::new(),
+        }
+    }
+
+    pub fn holiday(mut self, date: NaiveDate) -> Self {
+        self.holidays.insert(date);
+        self
+    }
+
+    pub fn half_day(mut self, date: NaiveDate, close: NaiveTime) -> Self {
+        self.half_days.insert(date, close);
+        self
+    }
+
+    pub fn is_trading_day(&self, date: NaiveDate) -> bool {
+        !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&date)
+    }
+
+    pub fn to_utc(&self, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+        (date.and_time(time) - chrono::Duration::minutes(self.utc_offset_minutes as i64)).and_utc()
+    }
+
+    // Open and close of the session on a local date, if the exchange trades that day
+    pub fn session_on(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.is_trading_day(date) {
+            return None;
+        }
+        let close = self.half_days.get(&date).copied().unwrap_or(self.close);
+        Some((self.to_utc(date, self.open), self.to_utc(date, close)))
+    }
+
+    // Close of the latest session on or before a local date, looking back at most a month
+    pub fn last_close_on_or_before(&self, date: NaiveDate) -> Option<DateTime<Utc>> {
+        (0..31).filter_map(|back| date.checked_sub_days(chrono::Days::new(back)))
+            .find_map(|day| self.session_on(day).map(|(_, close)| close))
+    }
+}
+
+// Outcome of the end-of-day close for one instrument
+#[derive(Debug, Clone)]
+pub enum EodCloseStatus {
+    Recorded,
+    // The exchange did not trade on the date
+    MarketClosed,
+    // The close has not been reached yet
+    SessionOpen,
+    NoMark,
+    // The book behind the mark was captured outside the day's session
+    StaleMark { captured_at: DateTime<Utc> },
+}
+
+#[derive(Debug, Clone)]
+pub struct EodClose {
+    pub instrument: String,
+    pub cutoff: Option<DateTime<Utc>>,
+    pub price: Option<Price>,
+    pub status: EodCloseStatus,
+}
+
+// Account P&L in the account's base currency and translated into the firm currency
+#[derive(Debug, Clone)]
+pub struct AccountCurrencyReport {
+    pub account_id: String,
+    pub base_currency: String,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub market_value: Money,
+    pub firm_realized_pnl: Money,
+    pub firm_unrealized_pnl: Money,
+    pub firm_market_value: Money,
+    // Change in the firm-currency value of the account's net assets caused only by
+    // the move from the opening to the closing rate of its base currency
+    pub translation_difference: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct FirmCurrencyReport {
+    pub firm_currency: String,
+    pub accounts: Vec<AccountCurrencyReport>,
+    pub total_realized_pnl: Money,
+    pub total_unrealized_pnl: Money,
+    pub total_market_value: Money,
+    pub total_translation_difference: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct Counterparty {
+    pub id: String,
+    pub name: String,
+    pub parent_id: Option<String>,
+}
+
+impl Counterparty {
+    pub fn new(id: &str, name: &str, parent_id: Option<&str>) -> Counterparty {
+        Counterparty {
+            id: id.to_string(),
+            name: name.to_string(),
+            parent_id: parent_id.map(|parent| parent.to_string()),
+        }
+    }
+}
+
+// Trading activity and open exposure of a counterparty group
+#[derive(Debug, Clone)]
+pub struct CounterpartyExposure {
+    pub ultimate_parent: String,
+    pub counterparties: Vec<String>,
+    pub trade_count: usize,
+    pub trade_volume: Money,
+    // Sum of each counterparty's absolute open market value per instrument
+    pub gross_exposure: Money,
+    // Open market value after netting per instrument across the whole group
+    pub net_exposure: Money,
+}
+
+// Calendar bucket trade flows are aggregated into; weeks start on Monday
+#[derive(Debug, Clone, Copy)]
+pub enum FlowPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl FlowPeriod {
+    pub fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            FlowPeriod::Day => date,
+            FlowPeriod::Week => date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64),
+            FlowPeriod::Month => date.with_day(1).unwrap(),
+        }
+    }
+}
+
+// What one account traded with one counterparty in one instrument during a period.
+// Bought and sold are from the account's side.
+#[derive(Debug, Clone)]
+pub struct FlowLink {
+    pub period_start: NaiveDate,
+    pub account_id: String,
+    pub counterparty: String,
+    pub instrument: String,
+    pub trade_count: usize,
+    pub bought_quantity: i32,
+    pub sold_quantity: i32,
+    pub bought: Money,
+    pub sold: Money,
+}
+
+impl FlowLink {
+    pub fn gross(&self) -> Money {
+        self.bought + self.sold
+    }
+
+    pub fn net(&self) -> Money {
+        self.bought - self.sold
+    }
+}
+
+// Account -> (quantity, notional) traded in one period and instrument, signed by side
+pub type AccountFlows<'a> = BTreeMap<&'a str, (i32, Money)>;
+
+// Period and instrument in which some accounts bought while others sold, so part
+// of the flow could have been crossed internally instead of going to the street
+#[derive(Debug, Clone)]
+pub struct CrossingOpportunity {
+    pub period_start: NaiveDate,
+    pub instrument: String,
+    pub buyers: Vec<String>,
+    pub sellers: Vec<String>,
+    pub crossable_quantity: i32,
+    // At the average price of the street flow on both sides
+    pub crossable_notional: Money,
+}
+
+// Two accounts of the firm on opposite sides of the same instrument at the same
+// execution time and price, so the firm as a whole did not change its position
+#[derive(Debug, Clone)]
+pub struct InternalCross {
+    pub instrument: String,
+    pub executed_at: DateTime<Utc>,
+    pub price: Price,
+    pub quantity: i32,
+    pub buy_trade_id: TradeId,
+    pub sell_trade_id: TradeId,
+    pub buyer: String,
+    pub seller: String,
+    // Accounts in different books, which compliance reviews more closely
+    pub cross_book: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowReport {
+    pub period: FlowPeriod,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub links: Vec<FlowLink>,
+}
+
+impl FlowReport {
+    // Nodes and weighted edges for a sankey diagram: account -> instrument ->
+    // counterparty per period, weighted by gross notional with the net alongside
+    pub fn sankey_json(&self) -> String {
+        let mut nodes: BTreeSet<(&str, &str)> = BTreeSet::new();
+        // (period, source, target) -> (gross, net)
+        let mut edges: BTreeMap<(NaiveDate, String, String), (Money, Money)> = BTreeMap::new();
+        for link in &self.links {
+            let account = format!("account:{}", link.account_id);
+            let instrument = format!("instrument:{}", link.instrument);
+            let counterparty = format!("counterparty:{}", link.counterparty);
+            nodes.insert(("account", &link.account_id));
+            nodes.insert(("instrument", &link.instrument));
+            nodes.insert(("counterparty", &link.counterparty));
+            for (source, target) in [(account, instrument.clone()), (instrument, counterparty)] {
+                let (gross, net) = edges.entry((link.period_start, source, target)).or_insert((Decimal::ZERO, Decimal::ZERO));
+                *gross += link.gross();
+                *net += link.net();
+            }
+        }
+
+        let nodes: Vec<String> = nodes
+            .iter()
+            .map(|(kind, name)| format!("{{\"id\":{},\"kind\":\"{}\",\"label\":{}}}", json_string(&format!("{}:{}", kind, name)), kind, json_string(name)))
+            .collect();
+        let edges: Vec<String> = edges
+            .iter()
+            .map(|((period_start, source, target), (gross, net))| {
+                format!("{{\"period\":\"{}\",\"source\":{},\"target\":{},\"value\":{},\"net\":{}}}",
+                        period_start, json_string(source), json_string(target), gross, net)
+            })
+            .collect();
+        format!("{{\"nodes\":[{}],\"links\":[{}]}}", nodes.join(","), edges.join(","))
+    }
+
+    // Per period and instrument, the quantity bought by some accounts and sold by
+    // others in the same bucket
+    pub fn crossing_opportunities(&self) -> Vec<CrossingOpportunity> {
+        let mut flows: BTreeMap<(NaiveDate, &str), AccountFlows> = BTreeMap::new();
+        for link in &self.links {
+            let (quantity, notional) = flows.entry((link.period_start, &link.instrument)).or_default()
+                .entry(&link.account_id).or_insert((0, Decimal::ZERO));
+            *quantity += link.bought_quantity - link.sold_quantity;
+            *notional += link.net();
+        }
+
+        flows
+            .into_iter()
+            .filter_map(|((period_start, instrument), accounts)| {
+                let buyers: Vec<&(i32, Money)> = accounts.values().filter(|(quantity, _)| *quantity > 0).collect();
+                let sellers: Vec<&(i32, Money)> = accounts.values().filter(|(quantity, _)| *quantity < 0).collect();
+                let bought: i32 = buyers.iter().map(|(quantity, _)| quantity).sum();
+                let sold: i32 = sellers.iter().map(|(quantity, _)| -quantity).sum();
+                let crossable_quantity = bought.min(sold);
+                if crossable_quantity == 0 {
+                    return None;
+                }
+                let bought_notional: Money = buyers.iter().map(|(_, notional)| *notional).sum();
+                let sold_notional: Money = sellers.iter().map(|(_, notional)| -*notional).sum();
+                let average_price = (bought_notional + sold_notional) / (bought + sold);
+                Some(CrossingOpportunity {
+                    period_start,
+                    instrument: instrument.to_string(),
+                    buyers: accounts.iter().filter(|(_, (quantity, _))| *quantity > 0).map(|(account, _)| account.to_string()).collect(),
+                    sellers: accounts.iter().filter(|(_, (quantity, _))| *quantity < 0).map(|(account, _)| account.to_string()).collect(),
+                    crossable_quantity,
+                    crossable_notional: average_price * crossable_quantity,
+                })
+            })
+            .collect()
+    }
+}
+
+// Securities and cash one trade moves at settlement, seen from the account's custodian
+#[derive(Debug, Clone)]
+pub struct SettlementMovement {
+    pub settlement_date: NaiveDate,
+    pub custodian: String,
+    pub account_id: String,
+    pub trade_id: TradeId,
+    pub instrument: String,
+    // Units received (positive) or delivered
+    pub quantity: i32,
+    pub currency: String,
+    // Cash received (positive) or paid, fees included
+    pub cash: Money,
+}
+
+// Nostro whose projected balance goes below zero within the ladder
+#[derive(Debug, Clone)]
+pub struct FundingNeed {
+    pub custodian: String,
+    pub currency: String,
+    // Day of the lowest cumulative balance, and how far below zero it is
+    pub date: NaiveDate,
+    pub shortfall: Money,
+}
+
+// Unsettled trades projected onto the business days after `as_of_date`: cash per
+// nostro (custodian, currency) and securities per depot (custodian, instrument)
+#[derive(Debug, Clone)]
+pub struct SettlementLadder {
+    pub as_of_date: NaiveDate,
+    pub dates: Vec<NaiveDate>,
+    pub movements: Vec<SettlementMovement>,
+}
+
+impl SettlementLadder {
+    pub fn column(&self, date: NaiveDate) -> usize {
+        self.dates.iter().position(|day| *day == date).unwrap_or(0)
+    }
+
+    // Net cash due on each ladder date per (custodian, currency)
+    pub fn cash_ladder(&self) -> BTreeMap<(String, String), Vec<Money>> {
+        let mut ladder: BTreeMap<(String, String), Vec<Money>> = BTreeMap::new();
+        for movement in &self.movements {
+            let row = ladder
+                .entry((movement.custodian.clone(), movement.currency.clone()))
+                .or_insert_with(|| vec![Decimal::ZERO; self.dates.len()]);
+            row[self.column(movement.settlement_date)] += movement.cash;
+        }
+        ladder
+    }
+
+    // Net units due on each ladder date per (custodian, instrument)
+    pub fn security_ladder(&self) -> BTreeMap<(String, String), Vec<i32>> {
+        let mut ladder: BTreeMap<(String, String), Vec<i32>> = BTreeMap::new();
+        for movement in &self.movements {
+            let row = ladder
+                .entry((movement.custodian.clone(), movement.instrument.clone()))
+                .or_insert_with(|| vec![0; self.dates.len()]);
+            row[self.column(movement.settlement_date)] += movement.quantity;
+        }
+        ladder
+    }
+
+    // Running cash total per nostro; the cash that has to be in place before each day
+    pub fn cumulative_cash(&self) -> BTreeMap<(String, String), Vec<Money>> {
+        let mut ladder = self.cash_ladder();
+        for row in ladder.values_mut() {
+            let mut running = Decimal::ZERO;
+            for amount in row.iter_mut() {
+                running += *amount;
+                *amount = running;
+            }
+        }
+        ladder
+    }
+
+    pub fn funding_needs(&self) -> Vec<FundingNeed> {
+        self.cumulative_cash()
+            .into_iter()
+            .filter_map(|((custodian, currency), row)| {
+                let (column, lowest) = row.iter().enumerate().min_by_key(|(_, balance)| **balance)?;
+                (*lowest < Decimal::ZERO).then(|| FundingNeed { custodian, currency, date: self.dates[column], shortfall: -*lowest })
+            })
+            .collect()
+    }
+}
+
+// Trade confirmation received from a counterparty, with the side from our perspective
+#[derive(Debug, Clone)]
+pub struct Confirmation {
+    pub confirm_id: String,
+    pub counterparty: String,
+    pub trade_date: NaiveDate,
+    pub instrument: String,
+    pub quantity: i32,
+    pub price: Price,
+    pub side: Side,
+    pub received_date: NaiveDate,
+}
+
+// How far a confirmation's economics may differ from our trade and still match
+#[derive(Debug, Clone)]
+pub struct MatchTolerance {
+    pub price: Price,
+    pub quantity: i32,
+    pub trade_date_days: i64,
+}
+
+// Confirmation paired with one of our trades but outside tolerance
+#[derive(Debug, Clone)]
+pub struct ConfirmationMismatch {
+    pub trade_id: TradeId,
+    pub confirm_id: String,
+    pub differences: Vec<String>,
+    pub age_days: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfirmationMatchReport {
+    pub matched: Vec<(TradeId, String)>,
+    pub mismatched: Vec<ConfirmationMismatch>,
+    // (trade id, age in days since trade date) for trades with no confirmation
+    pub unmatched_trades: Vec<(TradeId, i64)>,
+    // (confirm id, age in days since received) for confirmations with no trade
+    pub unmatched_confirmations: Vec<(String, i64)>,
+}
+
+// Feed record that could not be ingested, kept for ops to inspect and replay
+#[derive(Debug, Clone)]
+pub struct QuarantinedRecord {
+    pub source: String,
+    pub record: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IngestSummary {
+    pub source: String,
+    pub polled: usize,
+    pub ingested: usize,
+    pub quarantined: usize,
+    // Sequenced feeds only: records that arrived after a higher sequence number,
+    // and gaps declared during this run
+    pub out_of_order: usize,
+    // Resent records that were skipped: repeated sequence numbers, or trades
+    // already booked with the same terms
+    pub duplicates: usize,
+    pub gaps_opened: usize,
+    // Times the feed dropped or refused the connection and was connected again
+    pub reconnects: usize,
+}
+
+// Sequence number bookkeeping for one feed source, kept across ingest runs
+#[derive(Debug, Clone, Default)]
+pub struct SequenceState {
+    // None until the first sequenced record; feeds may start at any number
+    pub next_expected: Option<u64>,
+    pub highest_seen: Option<u64>,
+    // Lowest sequence number booked or buffered; nothing below it has been seen
+    pub lowest_seen: Option<u64>,
+    // Inclusive ranges given up on once the reorder window filled; a range
+    // shrinks if its records turn up later
+    pub open_gaps: Vec<(u64, u64)>,
+    pub out_of_order: usize,
+    pub duplicates: usize,
+}
+
+// Unresolved sequence gap, surfaced as an alert
+#[derive(Debug, Clone)]
+pub struct SequenceGap {
+    pub source: String,
+    pub first: u64,
+    pub last: u64,
+}
+
+// Which clock orders ingested trades and prices in as-of queries: the source's
+// own timestamps, or the time each record reached us
+#[derive(Debug, Clone, Copy)]
+pub enum TimestampDomain {
+    Source,
+    Receive,
+}
+
+// Samples kept per source for latency metrics; older ones are dropped
+pub const LATENCY_WINDOW: usize = 10_000;
+
+// A price as a source published it
+#[derive(Debug, Clone)]
+pub struct PriceTick {
+    pub source: String,
+    pub price: Price,
+    pub source_time: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl PriceTick {
+    pub fn timestamp(&self, domain: TimestampDomain) -> DateTime<Utc> {
+        match domain {
+            TimestampDomain::Source => self.source_time,
+            TimestampDomain::Receive => self.received_at,
+        }
+    }
+}
+
+// Receive time minus source time over a source's recent records. A negative
+// latency means the source's clock is ahead of ours.
+#[derive(Debug, Clone)]
+pub struct SourceLatency {
+    pub source: String,
+    pub samples: usize,
+    pub min_ms: i64,
+    pub median_ms: i64,
+    pub p95_ms: i64,
+    pub max_ms: i64,
+    // Records stamped later than we received them
+    pub ahead_of_receiver: usize,
+}
+
+// Position and P&L of one instrument as of a time in one clock domain
+#[derive(Debug, Clone)]
+pub struct AsOfPnlRow {
+    pub instrument: String,
+    pub quantity: i32,
+    pub average_price: Price,
+    pub mark: Option<Price>,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct BrokerCostReport {
+    pub broker: String,
+    pub trade_count: usize,
+    pub notional: Money,
+    pub total_commissions: Money,
+    pub commission_bps: f64,
+    // Cost versus the benchmark price; positive means we traded worse than the benchmark
+    pub slippage: Money,
+    pub slippage_bps: f64,
+    // Trades without an arrival price or close on the trade date are left out of slippage
+    pub trades_without_benchmark: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SanityAction {
+    // Book the trade and record the breach
+    Flag,
+    // Refuse the booking unless it is overridden with a justification
+    Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BookLevel {
+    pub price: Price,
+    pub size: i64,
+}
+
+// L1 (one level per side) or L2 snapshot of an instrument's order book, best
+// price first on each side
+#[derive(Debug, Clone)]
+pub struct BookSnapshot {
+    pub instrument: String,
+    pub captured_at: DateTime<Utc>,
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+}
+
+impl BookSnapshot {
+    pub fn new(instrument: &str, captured_at: DateTime<Utc>) -> Self {
+        BookSnapshot {
+            instrument: instrument.to_string(),
+            captured_at,
+            bids: Vec::new(),
+            asks: Vec::new(),
+        }
+    }
+
+    pub fn bid(mut self, price: impl Into<Price>, size: i64) -> Self {
+        self.bids.push(BookLevel { price: price.into(), size });
+        self
+    }
+
+    pub fn ask(mut self, price: impl Into<Price>, size: i64) -> Self {
+        self.asks.push(BookLevel { price: price.into(), size });
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bids.iter().chain(&self.asks).any(|level| level.size <= 0 || level.price.is_negative() || level.price.is_zero()) {
+            return Err(format!("{} book has a level with no size or price", self.instrument));
+        }
+        if self.bids.windows(2).any(|pair| pair[1].price >= pair[0].price) || self.asks.windows(2).any(|pair| pair[1].price <= pair[0].price) {
+            return Err(format!("{} book levels are not in price order", self.instrument));
+        }
+        if let (Some(bid), Some(ask)) = (self.bids.first(), self.asks.first()) {
+            if bid.price >= ask.price {
+                return Err(format!("{} book is crossed: bid {} >= ask {}", self.instrument, bid.price, ask.price));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mid(&self) -> Option<Price> {
+        Some((self.bids.first()?.price + self.asks.first()?.price) / 2)
+    }
+
+    // Top-of-book mid weighted towards the side with less size, where the next trade is likelier
+    pub fn microprice(&self) -> Option<Price> {
+        self.depth_weighted(1, 1.0)
+    }
+
+    // Microprice over the first `levels` levels; each level deeper counts `decay`
+    // times the one above it
+    pub fn depth_weighted(&self, levels: usize, decay: f64) -> Option<Price> {
+        let side = |book: &[BookLevel]| -> Option<(Price, Decimal)> {
+            let mut weight = 1.0;
+            let mut notional = Decimal::ZERO;
+            let mut depth = Decimal::ZERO;
+            for level in book.iter().take(levels.max(1)) {
+                // A NaN or infinite decay leaves no usable weight
+                let size = Decimal::from_f64(level.size as f64 * weight).ok()?;
+                notional += level.price * size;
+                depth += size;
+                weight *= decay;
+            }
+            notional.checked_div(depth).map(|price| (price, depth))
+        };
+        let (bid_price, bid_depth) = side(&self.bids)?;
+        let (ask_price, ask_depth) = side(&self.asks)?;
+        (bid_price * ask_depth + ask_price * bid_depth).checked_div(bid_depth + ask_depth)
+    }
+}
+
+// How unrealized P&L marks are taken for an instrument
+#[derive(Debug, Clone, Copy)]
+pub enum MarkMethod {
+    // Prices set with update_market_price; book snapshots are stored but not used
+    LastTrade,
+    Mid,
+    Microprice,
+    DepthWeighted { levels: usize, decay: f64 },
+}
+
+// Manual mark that takes precedence over feed prices until it expires. When several
+// are active for an instrument the highest priority wins, then the latest set.
+#[derive(Debug, Clone)]
+pub struct MarkOverride {
+    pub instrument: String,
+    pub price: Price,
+    pub owner: String,
+    pub reason: String,
+    pub priority: u32,
+    pub set_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl MarkOverride {
+    pub fn new(instrument: &str, price: impl Into<Price>, owner: &str, expires_at: DateTime<Utc>) -> Self {
+        MarkOverride {
+            instrument: instrument.to_string(),
+            price: price.into(),
+            owner: owner.to_string(),
+            reason: String::new(),
+            priority: 0,
+            set_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    // Valid until the end of the current UTC day
+    pub fn for_today(instrument: &str, price: impl Into<Price>, owner: &str) -> Self {
+        let expires_at = (Utc::now().date_naive() + chrono::Duration::days(1)).and_hms_opt(0, 0, 0).unwrap().and_utc();
+        MarkOverride::new(instrument, price, owner, expires_at)
+    }
+
+    pub fn priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = reason.to_string();
+        self
+    }
+
+    pub fn is_active(&self, at: DateTime<Utc>) -> bool {
+        at < self.expires_at
+    }
+}
+
+// Fat-finger limits for an instrument
+#[derive(Debug, Clone)]
+pub struct SanityBand {
+    // Largest allowed distance from the last mark, as a fraction of the mark
+    pub max_price_deviation: f64,
+    // Largest allowed quantity as a fraction of average daily volume
+    pub max_adv_fraction: f64,
+    pub action: SanityAction,
+}
+
+#[derive(Debug, Clone)]
+pub struct SanityBreach {
+    pub trade_id: TradeId,
+    pub instrument: String,
+    pub rule: String,
+    pub value: f64,
+    pub limit: f64,
+}
+
+impl std::fmt::Display for SanityBreach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "trade {} in {}: {} ({:.2} against a limit of {:.2})", self.trade_id, self.instrument, self.rule, self.value, self.limit)
+    }
+}
+
+// Booking that went through despite sanity breaches
+#[derive(Debug, Clone)]
+pub struct SanityOverride {
+    pub trade_id: TradeId,
+    pub breaches: Vec<SanityBreach>,
+    pub justification: String,
+    pub approved_by: String,
+}
+
+// Pre-trade limits checked before a trade is booked or an order accepted. Limits
+// left unset are not checked. Exposures are valued like a RiskSnapshot: at the
+// mark, or at average cost without one.
+#[derive(Debug, Clone)]
+pub struct RiskLimits {
+    // instrument -> largest position, long or short, in units
+    pub max_position: HashMap<String, i32>,
+    pub max_order_notional: Option<Money>,
+    pub max_gross_exposure: Option<Money>,
+    // Compared with the absolute net exposure, so it caps net short books as well
+    pub max_net_exposure: Option<Money>,
+    pub restricted: BTreeSet<String>,
+    pub action: SanityAction,
+}
+
+impl RiskLimits {
+    pub fn new(action: SanityAction) -> Self {
+        RiskLimits {
+            max_position: HashMap::new(),
+            max_order_notional: None,
+            max_gross_exposure: None,
+            max_net_exposure: None,
+            restricted: BTreeSet::new(),
+            action,
+        }
+    }
+
+    pub fn max_position(mut self, instrument: &str, quantity: i32) -> Self {
+        self.max_position.insert(instrument.to_string(), quantity);
+        self
+    }
+
+    pub fn max_order_notional(mut self, notional: impl Into<Money>) -> Self {
+        self.max_order_notional = Some(notional.into());
+        self
+    }
+
+    pub fn max_gross_exposure(mut self, exposure: impl Into<Money>) -> Self {
+        self.max_gross_exposure = Some(exposure.into());
+        self
+    }
+
+    pub fn max_net_exposure(mut self, exposure: impl Into<Money>) -> Self {
+        self.max_net_exposure = Some(exposure.into());
+        self
+    }
+
+    pub fn restrict(mut self, instrument: &str) -> Self {
+        self.restricted.insert(instrument.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitRule {
+    Restricted,
+    MaxPosition,
+    MaxOrderNotional,
+    MaxGrossExposure,
+    MaxNetExposure,
+}
+
+// A trade or order that would break a risk limit. value is what it would take the
+// book to and limit the configured maximum: units for position and restricted
+// breaches, money for the rest.
+#[derive(Debug, Clone)]
+pub struct LimitBreach {
+    pub instrument: String,
+    pub rule: LimitRule,
+    pub value: Decimal,
+    pub limit: Decimal,
+}
+
+impl std::fmt::Display for LimitBreach {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.rule {
+            LimitRule::Restricted => write!(f, "{} is restricted", self.instrument),
+            LimitRule::MaxPosition => write!(f, "position in {} of {} over {}", self.instrument, self.value, self.limit),
+            LimitRule::MaxOrderNotional => write!(f, "notional {} over {}", self.value, self.limit),
+            LimitRule::MaxGrossExposure => write!(f, "gross exposure {} over {}", self.value, self.limit),
+            LimitRule::MaxNetExposure => write!(f, "net exposure {} over {}", self.value, self.limit),
+        }
+    }
+}
+
+// One move of a trade through its lifecycle
+#[derive(Debug, Clone)]
+pub struct StatusTransition {
+    pub trade_id: TradeId,
+    pub from: TradeStatus,
+    pub to: TradeStatus,
+    pub actor: String,
+    pub recorded_at: DateTime<Utc>,
+    // Why a held trade was rejected
+    pub reason: Option<String>,
+}
+
+// Limits applied when generating rebalance trades
+#[derive(Debug, Clone)]
+pub struct RebalanceConstraints {
+    // Largest traded notional for the day as a fraction of portfolio value,
+    // including trades already booked that day
+    pub max_turnover: Option<f64>,
+    pub max_trade_notional: Option<Money>,
+    pub instrument_max_trade_notional: HashMap<String, Money>,
+    pub do_not_trade: HashSet<String>,
+}
+
+impl RebalanceConstraints {
+    pub fn new() -> Self {
+        RebalanceConstraints {
+            max_turnover: None,
+            max_trade_notional: None,
+            instrument_max_trade_notional: HashMap::new(),
+            do_not_trade: HashSet::new(),
+        }
+    }
+
+    pub fn max_turnover(mut self, fraction: f64) -> Self {
+        self.max_turnover = Some(fraction);
+        self
+    }
+
+    pub fn max_trade_notional(mut self, notional: impl Into<Money>) -> Self {
+        self.max_trade_notional = Some(notional.into());
+        self
+    }
+
+    pub fn max_trade_notional_for(mut self, instrument: &str, notional: impl Into<Money>) -> Self {
+        self.instrument_max_trade_notional.insert(instrument.to_string(), notional.into());
+        self
+    }
+
+    pub fn do_not_trade(mut self, instrument: &str) -> Self {
+        self.do_not_trade.insert(instrument.to_string());
+        self
+    }
+
+    pub fn trade_notional_limit(&self, instrument: &str) -> Option<Money> {
+        self.instrument_max_trade_notional.get(instrument).copied().or(self.max_trade_notional)
+    }
+}
+
+impl Default for RebalanceConstraints {
+    fn default() -> RebalanceConstraints {
+        RebalanceConstraints::new()
+    }
+}
+
+// Own traded volume against market ADV for one instrument and day
+#[derive(Debug, Clone)]
+pub struct ParticipationRow {
+    pub instrument: String,
+    pub date: NaiveDate,
+    pub own_volume: i64,
+    pub adv: Option<f64>,
+    pub participation: Option<f64>,
+    pub exceeds_limit: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParticipationReport {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub limit: Option<f64>,
+    pub rows: Vec<ParticipationRow>,
+}
+
+// Target a rebalance could not reach, and why
+#[derive(Debug, Clone)]
+pub struct UnmetTarget {
+    pub instrument: String,
+    pub current_quantity: i32,
+    pub target_quantity: i32,
+    pub planned_quantity: i32,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RebalancePlan {
+    pub trade_date: NaiveDate,
+    pub portfolio_value: Money,
+    // Proposed trades, not booked
+    pub trades: Vec<Trade>,
+    pub turnover: Money,
+    pub unmet: Vec<UnmetTarget>,
+}
+
+// One instrument in the optimizer export. The weight bounds carry the rebalance
+// constraints: unpriced and do-not-trade instruments are pinned at their current
+// weight, notional caps become a band around it, and otherwise the optimizer may
+// hold anything from the current short (if any) up to the whole portfolio.
+#[derive(Debug, Clone)]
+pub struct OptimizerRow {
+    pub instrument: String,
+    pub quantity: i32,
+    pub price: Option<Price>,
+    pub weight: f64,
+    pub beta: Option<f64>,
+    pub sector: String,
+    pub min_weight: f64,
+    pub max_weight: f64,
+}
+
+// Positions, prices, betas and constraints laid out for an external optimizer
+#[derive(Debug, Clone)]
+pub struct OptimizerExport {
+    pub as_of: NaiveDate,
+    pub portfolio_value: Money,
+    pub max_turnover: Option<f64>,
+    pub rows: Vec<OptimizerRow>,
+}
+
+impl OptimizerExport {
+    pub fn sectors(&self) -> BTreeSet<&str> {
+        self.rows.iter().map(|row| row.sector.as_str()).collect()
+    }
+
+    // One row per instrument with sector membership as 0/1 columns, so the numeric
+    // part loads straight into a matrix. Unknown prices and betas are left empty.
+    pub fn exposures_csv(&self) -> String {
+        let sectors = self.sectors();
+        let mut csv = "instrument,quantity,price,market_value,weight,beta,min_weight,max_weight".to_string();
+        for sector in &sectors {
+            csv.push_str(&format!(",sector:{}", sector));
+        }
+        csv.push('\n');
+        for row in &self.rows {
+            let price = row.price.map_or(String::new(), |price| price.to_string());
+            let market_value = row.price.map_or(String::new(), |price| (price * row.quantity).to_string());
+            let beta = row.beta.map_or(String::new(), |beta| beta.to_string());
+            csv.push_str(&format!("{},{},{},{},{:.6},{},{:.6},{:.6}",
+                                  row.instrument, row.quantity, price, market_value, row.weight, beta, row.min_weight, row.max_weight));
+            for sector in &sectors {
+                csv.push_str(if *sector == row.sector { ",1" } else { ",0" });
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    // Portfolio-wide scalars as name,value rows
+    pub fn constraints_csv(&self) -> String {
+        let max_turnover = self.max_turnover.map_or(String::new(), |fraction| fraction.to_string());
+        format!("name,value\nas_of,{}\nportfolio_value,{}\nmax_turnover,{}\n", self.as_of, self.portfolio_value, max_turnover)
+    }
+}
+
+// P&L for one instrument measured from the prior close instead of cost
+#[derive(Debug, Clone)]
+pub struct IntradayPnlRow {
+    pub instrument: String,
+    pub opening_quantity: i32,
+    // Prior close, or the average cost when no close was recorded
+    pub opening_price: Price,
+    pub closing_quantity: i32,
+    pub mark: Option<Price>,
+    pub realized_since_open: Money,
+    pub unrealized_since_open: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct IntradayPnlReport {
+    pub date: NaiveDate,
+    pub rows: Vec<IntradayPnlRow>,
+    pub total_realized: Money,
+    pub total_unrealized: Money,
+}
+
+// Attribute positions can be grouped by in summaries
+#[derive(Debug, Clone, Copy)]
+pub enum Dimension {
+    Account,
+    Sector,
+    Currency,
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionSummaryRow {
+    // Account, sector or currency; empty when the summary is not grouped
+    pub group: String,
+    pub instrument: String,
+    pub quantity: i32,
+    pub average_price: Price,
+    pub market_price: Price,
+    pub market_value: Money,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionSummaryTotals {
+    pub group: String,
+    pub market_value: Money,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+}
+
+impl PositionSummaryTotals {
+    pub fn new(group: &str) -> PositionSummaryTotals {
+        PositionSummaryTotals {
+            group: group.to_string(),
+            market_value: Decimal::ZERO,
+            realized_pnl: Decimal::ZERO,
+            unrealized_pnl: Decimal::ZERO,
+        }
+    }
+
+    pub fn add(&mut self, row: &PositionSummaryRow) {
+        self.market_value += row.market_value;
+        self.realized_pnl += row.realized_pnl;
+        self.unrealized_pnl += row.unrealized_pnl;
+    }
+
+    pub fn total_pnl(&self) -> Money {
+        self.realized_pnl + self.unrealized_pnl
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionSummary {
+    pub as_of_date: NaiveDate,
+    pub group_by: Option<Dimension>,
+    // Sorted by group, then instrument
+    pub rows: Vec<PositionSummaryRow>,
+    // One entry per group, in row order; empty when not grouped
+    pub group_totals: Vec<PositionSummaryTotals>,
+    pub totals: PositionSummaryTotals,
+}
+
+// One tile of a P&L contribution treemap. Weight is the node's share of the
+// portfolio's gross market value and pnl_share its share of the portfolio's total
+// P&L, so a losing tile has a negative share; a parent is the sum of its children.
+#[derive(Debug, Clone)]
+pub struct ContributionNode {
+    pub name: String,
+    pub market_value: Money,
+    pub total_pnl: Money,
+    pub weight: f64,
+    pub pnl_share: f64,
+    pub children: Vec<ContributionNode>,
+}
+
+impl ContributionNode {
+    // Nested {name, value, weight, pnl, pnl_share, children} objects, the hierarchy
+    // format treemap and sunburst charts read; leaves have no children key
+    pub fn to_json(&self) -> String {
+        let children = if self.children.is_empty() {
+            String::new()
+        } else {
+            let children: Vec<String> = self.children.iter().map(|child| child.to_json()).collect();
+            format!(",\"children\":[{}]", children.join(","))
+        };
+        format!("{{\"name\":{},\"value\":{},\"weight\":{:.6},\"pnl\":{},\"pnl_share\":{:.6}{}}}",
+                json_string(&self.name), self.market_value, self.weight, self.total_pnl, self.pnl_share, children)
+    }
+}
+
+// Key positions can be grouped by in an aggregation tree. Tag groups by the value
+// an instrument was tagged with under that name.
+#[derive(Debug, Clone)]
+pub enum GroupBy {
+    AssetClass,
+    Sector,
+    Currency,
+    Book,
+    Account,
+    Instrument,
+    Tag(String),
+}
+
+// One node of an aggregation tree; every node is the sum of its children. Weight is
+// the node's share of the total gross market value.
+#[derive(Debug, Clone)]
+pub struct AggregateNode {
+    // None for the root
+    pub group_by: Option<GroupBy>,
+    pub name: String,
+    pub market_value: Money,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub weight: f64,
+    pub children: Vec<AggregateNode>,
+}
+
+impl AggregateNode {
+    pub fn total_pnl(&self) -> Money {
+        self.realized_pnl + self.unrealized_pnl
+    }
+
+    // Follow child names down the tree, e.g. ["Equity", "AAPL"]
+    pub fn drill_down(&self, path: &[&str]) -> Option<&AggregateNode> {
+        match path.split_first() {
+            None => Some(self),
+            Some((name, rest)) => self.children.iter().find(|child| child.name == *name)?.drill_down(rest),
+        }
+    }
+}
+
+// Positions labelled with one name per grouping key: (labels, market value, realized, unrealized)
+pub type LabelledPosition = (Vec<String>, Money, Money, Money);
+
+// Build the nodes for keys[depth..] over the positions sharing the labels above them
+pub fn aggregate_nodes(positions: &[&LabelledPosition], keys: &[GroupBy], depth: usize, gross: Money) -> Vec<AggregateNode> {
+    let Some(group_by) = keys.get(depth) else { return Vec::new() };
+    let mut groups: BTreeMap<&str, Vec<&LabelledPosition>> = BTreeMap::new();
+    for position in positions {
+        groups.entry(position.0[depth].as_str()).or_default().push(position);
+    }
+    groups
+        .into_iter()
+        .map(|(name, members)| {
+            let long_short: Money = members.iter().map(|(_, market_value, _, _)| market_value.abs()).sum();
+            AggregateNode {
+                group_by: Some(group_by.clone()),
+                name: name.to_string(),
+                market_value: members.iter().map(|(_, market_value, _, _)| *market_value).sum(),
+                realized_pnl: members.iter().map(|(_, _, realized, _)| *realized).sum(),
+                unrealized_pnl: members.iter().map(|(_, _, _, unrealized)| *unrealized).sum(),
+                weight: if gross.is_zero() { 0.0 } else { long_short.to_f64() / gross.to_f64() },
+                children: aggregate_nodes(&members, keys, depth + 1, gross),
+            }
+        })
+        .collect()
+}
+
+pub fn print_aggregate_node(node: &AggregateNode, depth: usize) {
+    let level = node.group_by.as_ref().map_or("Total".to_string(), |group_by| match group_by {
+        GroupBy::Tag(name) => format!("Tag {}", name),
+        other => format!("{:?}", other),
+    });
+    println!("{}{} {}: Value ${:.2} ({:.1}%) | Realized ${:.2} | Unrealized ${:.2} | P&L ${:.2}",
+             "  ".repeat(depth), level, node.name, node.market_value, node.weight * 100.0, node.realized_pnl, node.unrealized_pnl, node.total_pnl());
+    for child in &node.children {
+        print_aggregate_node(child, depth + 1);
+    }
+}
+
+// Long and short market value of one group of positions; short is a magnitude
+#[derive(Debug, Clone)]
+pub struct ExposureLine {
+    pub name: String,
+    pub long_value: Money,
+    pub short_value: Money,
+}
+
+impl ExposureLine {
+    pub fn gross(&self) -> Money {
+        self.long_value + self.short_value
+    }
+
+    pub fn net(&self) -> Money {
+        self.long_value - self.short_value
+    }
+}
+
+// Exposure of the book as of a date in the firm currency. Long and short values come
+// from each account's own positions; concentration looks at instruments netted
+// across accounts. NAV is the net value of everything held, cash included.
+#[derive(Debug, Clone)]
+pub struct ExposureReport {
+    pub as_of_date: NaiveDate,
+    pub nav: Money,
+    pub total: ExposureLine,
+    // Largest instruments by absolute net value, with their signed value
+    pub top_positions: Vec<(String, Money)>,
+    // Sum of squared shares of gross exposure across instruments: 1 for a single
+    // position, 1/n for n equal ones
+    pub herfindahl: f64,
+    // Empty unless the report was grouped
+    pub groups: Vec<ExposureLine>,
+}
+
+impl ExposureReport {
+    pub fn share_of_nav(&self, value: Money) -> f64 {
+        if self.nav.is_zero() { 0.0 } else { value.to_f64() / self.nav.to_f64() }
+    }
+
+    // Share of NAV held in the top positions, by absolute value
+    pub fn top_concentration(&self) -> f64 {
+        self.share_of_nav(self.top_positions.iter().map(|(_, value)| value.abs()).sum())
+    }
+
+    // Number of equal positions with the same Herfindahl index
+    pub fn effective_positions(&self) -> f64 {
+        if self.herfindahl > 0.0 { 1.0 / self.herfindahl } else { 0.0 }
+    }
+}
+
+pub fn print_exposure_report(report: &ExposureReport) {
+    println!("\n=== Exposure as of {} ===", report.as_of_date);
+    println!("NAV {:.2} | Long {:.2} | Short {:.2} | Gross {:.2} ({:.0}% of NAV) | Net {:.2} ({:.0}% of NAV)",
+             report.nav, report.total.long_value, report.total.short_value, report.total.gross(), report.share_of_nav(report.total.gross()) * 100.0,
+             report.total.net(), report.share_of_nav(report.total.net()) * 100.0);
+    println!("Top {} positions: {:.1}% of NAV | Herfindahl {:.4} ({:.1} effective positions)",
+             report.top_positions.len(), report.top_concentration() * 100.0, report.herfindahl, report.effective_positions());
+    for (instrument, value) in &report.top_positions {
+        println!("  {:<10} {:>16.2} {:>7.1}%", instrument, value, report.share_of_nav(value.abs()) * 100.0);
+    }
+    for group in &report.groups {
+        println!("{:<14} long {:>16.2} short {:>16.2} net {:>16.2} gross {:>6.1}% of NAV", group.name, group.long_value, group.short_value, group.net(), report.share_of_nav(group.gross()) * 100.0);
+    }
+}
+
+// Portfolio sensitivities aggregated over every position on one underlying
+#[derive(Debug, Clone)]
+pub struct UnderlyingGreeks {
+    pub underlying: String,
+    pub spot: f64,
+    // Shares-equivalent delta, and the same in dollars
+    pub delta: f64,
+    pub dollar_delta: f64,
+    // Change in shares-equivalent delta per $1 move
+    pub gamma: f64,
+    // Change in dollar delta for a 1% move in the underlying
+    pub dollar_gamma: f64,
+    // Dollars per volatility point
+    pub vega: f64,
+    // Dollars per calendar day
+    pub theta: f64,
+}
+
+impl UnderlyingGreeks {
+    pub fn new(underlying: String, spot: f64) -> UnderlyingGreeks {
+        UnderlyingGreeks {
+            underlying,
+            spot,
+            delta: 0.0,
+            dollar_delta: 0.0,
+            gamma: 0.0,
+            dollar_gamma: 0.0,
+            vega: 0.0,
+            theta: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GreekReport {
+    pub as_of_date: NaiveDate,
+    pub rows: Vec<UnderlyingGreeks>,
+    pub total_dollar_delta: f64,
+    pub total_dollar_gamma: f64,
+    pub total_vega: f64,
+    pub total_theta: f64,
+    // Positions left out because a spot price or volatility was missing
+    pub missing: Vec<String>,
+}
+
+// Multi-leg structures recognized in option positions
+#[derive(Debug, Clone, Copy)]
+pub enum OptionStrategyKind {
+    // Long and short options of one right at two strikes
+    VerticalSpread,
+    // Call and put at the same strike, both long or both short
+    Straddle,
+    // Short calls against long shares of the underlying
+    CoveredCall,
+    // Whatever is left over once the structures are taken out
+    SingleOption,
+}
+
+// Option leg awaiting a structure: instrument, right, strike and the quantity not
+// yet paired
+pub type UnpairedLeg = (String, OptionRight, f64, i32);
+
+// One recognized structure. Legs are (instrument, signed quantity), in contracts for
+// options and shares for the stock leg of a covered call. P&L follows the
+// repository's valuation of each leg; greeks are in shares of the underlying and
+// dollars, as in the greek report.
+#[derive(Debug, Clone)]
+pub struct OptionStrategy {
+    pub kind: OptionStrategyKind,
+    pub underlying: String,
+    pub expiry_date: NaiveDate,
+    pub legs: Vec<(String, i32)>,
+    pub unrealized_pnl: Money,
+    pub delta: f64,
+    pub dollar_delta: f64,
+    pub gamma: f64,
+    pub vega: f64,
+    pub theta: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionStrategyReport {
+    pub as_of_date: NaiveDate,
+    pub strategies: Vec<OptionStrategy>,
+    // Legs valued without a mark, or left without greeks for want of a spot or volatility
+    pub missing: Vec<String>,
+}
+
+// Minimal JSON value, enough for the NDJSON exports and their replay
+#[derive(Debug, Clone)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(BTreeMap<String, JsonValue>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(fields) => fields.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_f64().filter(|value| value.fract() == 0.0).map(|value| value as i64)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, JsonValue::Null)
+    }
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn json_optional_string(value: &Option<String>) -> String {
+    value.as_deref().map_or("null".to_string(), json_string)
+}
+
+// Deepest nesting parse_json accepts; the parser recurses once per level, so a
+// body of a million '[' would otherwise overflow the stack of the thread reading it
+pub const JSON_MAX_DEPTH: usize = 128;
+
+pub fn parse_json(text: &str) -> Result<JsonValue, String> {
+    let mut parser = JsonParser { bytes: text.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return Err(format!("Unexpected trailing characters at {}", parser.pos));
+    }
+    Ok(value)
+}
+
+pub struct JsonParser<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+    // Arrays and objects open around the current position
+    pub depth: usize,
+}
+
+impl JsonParser<'_> {
+    pub fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    pub fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' at {}", byte as char, self.pos))
+        }
+    }
+
+    pub fn literal(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, String> {
+        if self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(format!("Invalid literal at {}", self.pos))
+        }
+    }
+
+    pub fn parse_value(&mut self) -> Result<JsonValue, String> {
+        if self.depth == JSON_MAX_DEPTH {
+            return Err(format!("Nested more than {} deep at {}", JSON_MAX_DEPTH, self.pos));
+        }
+        self.depth += 1;
+        let value = self.parse_element();
+        self.depth -= 1;
+        value
+    }
+
+    pub fn parse_element(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            None => Err("Unexpected end of input".to_string()),
+            Some(b'n') => self.literal("null", JsonValue::Null),
+            Some(b't') => self.literal("true", JsonValue::Bool(true)),
+            Some(b'f') => self.literal("false", JsonValue::Bool(false)),
+            Some(b'"') => self.parse_string().map(JsonValue::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Array(items));
+                        },
+                        _ => return Err(format!("Expected ',' or ']' at {}", self.pos)),
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = BTreeMap::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    fields.insert(key, self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Object(fields));
+                        },
+                        _ => return Err(format!("Expected ',' or '}}' at {}", self.pos)),
+                    }
+                }
+            },
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+                number.parse().map(JsonValue::Number).map_err(|_| format!("Invalid number at {}", start))
+            },
+        }
+    }
+
+    pub fn parse_string(&mut self) -> Result<String, String> {
+        if self.bytes.get(self.pos) != Some(&b'"') {
+            return Err(format!("Expected string at {}", self.pos));
+        }
+        self.pos += 1;
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            match self.bytes.get(self.pos) {
+                None => return Err("Unterminated string".to_string()),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return String::from_utf8(out).map_err(|_| "Invalid UTF-8 in string".to_string());
+                },
+                Some(b'\\') => {
+                    let escaped = *self.bytes.get(self.pos + 1).ok_or("Unterminated escape")?;
+                    self.pos += 2;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self.bytes.get(self.pos..self.pos + 4).ok_or("Truncated \\u escape")?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex).unwrap_or(""), 16).map_err(|_| "Invalid \\u escape")?;
+                            self.pos += 4;
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        },
+                        other => return Err(format!("Invalid escape '\\{}'", other as char)),
+                    };
+                    let mut buffer = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                },
+                Some(&byte) => {
+                    out.push(byte);
+                    self.pos += 1;
+                },
+            }
+        }
+    }
+}
+
+pub fn side_name(side: &Side) -> &'static str {
+    match side {
+        Side::Buy => "buy",
+        Side::Sell => "sell",
+    }
+}
+
+pub fn trade_type_name(trade_type: &TradeType) -> &'static str {
+    match trade_type {
+        TradeType::Market => "market",
+        TradeType::Limit => "limit",
+        TradeType::Stop => "stop",
+    }
+}
+
+pub fn trade_status_name(status: &TradeStatus) -> &'static str {
+    match status {
+        TradeStatus::New => "new",
+        TradeStatus::PendingApproval => "pending_approval",
+        TradeStatus::Active => "active",
+        TradeStatus::Cancelled => "cancelled",
+        TradeStatus::Amended => "amended",
+        TradeStatus::Rejected => "rejected",
+    }
+}
+
+pub fn trade_to_json(trade: &Trade) -> String {
+    let optional_number = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    format!(
+        "{{\"trade_id\":{},\"trade_date\":\"{}\",\"executed_at\":\"{}\",\"instrument\":{},\"quantity\":{},\"price\":{},\"side\":\"{}\",\"trade_type\":\"{}\",\"status\":\"{}\",\"account_id\":{},\"strategy\":{},\"counterparty\":{},\"broker\":{},\"commission\":{},\"exchange_fee\":{},\"tax\":{},\"arrival_price\":{},\"replaces\":{},\"replaced_by\":{},\"source_time\":{},\"received_at\":{},\"order_id\":{},\"exec_id\":{},\"multiplier\":{}}}",
+        trade.trade_id.to_json(),
+        trade.trade_date,
+        trade.executed_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        json_string(&trade.instrument),
+        trade.quantity,
+        trade.price,
+        side_name(&trade.side),
+        trade_type_name(&trade.trade_type),
+        trade_status_name(&trade.status),
+        json_string(&trade.account_id),
+        json_optional_string(&trade.strategy),
+        json_optional_string(&trade.counterparty),
+        json_optional_string(&trade.broker),
+        trade.commission,
+        trade.exchange_fee,
+        trade.tax,
+        optional_number(trade.arrival_price.map(|price| price.to_string())),
+        optional_number(trade.replaces.map(TradeId::to_json)),
+        optional_number(trade.replaced_by.map(TradeId::to_json)),
+        json_optional_string(&trade.source_time.map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))),
+        json_optional_string(&trade.received_at.map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))),
+        optional_number(trade.order_id.map(|order_id| order_id.to_string())),
+        json_optional_string(&trade.exec_id),
+        trade.multiplier,
+    )
+}
+
+pub fn trade_from_json(value: &JsonValue) -> Result<Trade, String> {
+    let field = |name: &str| value.get(name).ok_or(format!("Trade is missing '{}'", name));
+    let text = |name: &str| field(name)?.as_str().map(str::to_string).ok_or(format!("Trade field '{}' is not a string", name));
+    let number = |name: &str| {
+        let value = field(name)?.as_f64().ok_or(format!("Trade field '{}' is not a number", name))?;
+        Decimal::from_f64(value).map_err(|e| format!("Invalid {}: {}", name, e))
+    };
+    let integer = |name: &str| {
+        let value = field(name)?.as_i64().ok_or(format!("Trade field '{}' is not an integer", name))?;
+        i32::try_from(value).map_err(|_| format!("Trade field '{}' is out of range: {}", name, value))
+    };
+    let id = |name: &str| TradeId::from_json(field(name)?).ok_or(format!("Trade field '{}' is not a trade id", name));
+    let optional = |name: &str| value.get(name).filter(|value| !value.is_null());
+
+    let trade_date = NaiveDate::parse_from_str(&text("trade_date")?, "%Y-%m-%d").map_err(|_| "Invalid trade_date".to_string())?;
+    let side = match text("side")?.as_str() {
+        "buy" => Side::Buy,
+        "sell" => Side::Sell,
+        other => return Err(format!("Invalid side {}", other)),
+    };
+    let trade_type = match text("trade_type")?.as_str() {
+        "market" => TradeType::Market,
+        "limit" => TradeType::Limit,
+        "stop" => TradeType::Stop,
+        other => return Err(format!("Invalid trade_type {}", other)),
+    };
+    let status = match text("status")?.as_str() {
+        "new" => TradeStatus::New,
+        "pending_approval" => TradeStatus::PendingApproval,
+        "active" => TradeStatus::Active,
+        "cancelled" => TradeStatus::Cancelled,
+        "amended" => TradeStatus::Amended,
+        "rejected" => TradeStatus::Rejected,
+        other => return Err(format!("Invalid status {}", other)),
+    };
+
+    let mut trade = Trade::new_with_type(id("trade_id")?, trade_date, text("instrument")?, integer("quantity")?, number("price")?, side, trade_type);
+    trade.status = status;
+    // Records from before execution times were exported carry only the date
+    if let Some(executed_at) = optional("executed_at").and_then(JsonValue::as_str) {
+        trade.executed_at = DateTime::parse_from_rfc3339(executed_at).map_err(|e| format!("Invalid executed_at: {}", e))?.with_timezone(&Utc);
+    }
+    trade.account_id = text("account_id")?;
+    trade.strategy = optional("strategy").and_then(JsonValue::as_str).map(str::to_string);
+    trade.counterparty = optional("counterparty").and_then(JsonValue::as_str).map(str::to_string);
+    trade.broker = optional("broker").and_then(JsonValue::as_str).map(str::to_string);
+    trade.commission = number("commission")?;
+    // Fee fields were added after the first export format; older records have none
+    let decimal = |name: &str| -> Result<Option<Decimal>, String> {
+        optional(name).and_then(JsonValue::as_f64)
+            .map(|value| Decimal::from_f64(value).map_err(|e| format!("Invalid {}: {}", name, e)))
+            .transpose()
+    };
+    trade.exchange_fee = decimal("exchange_fee")?.unwrap_or(Decimal::ZERO);
+    trade.tax = decimal("tax")?.unwrap_or(Decimal::ZERO);
+    trade.arrival_price = decimal("arrival_price")?;
+    trade.replaces = optional("replaces").and_then(TradeId::from_json);
+    trade.replaced_by = optional("replaced_by").and_then(TradeId::from_json);
+    let timestamp = |name: &str| -> Result<Option<DateTime<Utc>>, String> {
+        optional(name).and_then(JsonValue::as_str)
+            .map(|time| DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Utc)).map_err(|e| format!("Invalid {}: {}", name, e)))
+            .transpose()
+    };
+    trade.source_time = timestamp("source_time")?;
+    trade.received_at = timestamp("received_at")?;
+    trade.order_id = optional("order_id").and_then(JsonValue::as_i64).map(|order_id| order_id as OrderId);
+    trade.exec_id = optional("exec_id").and_then(JsonValue::as_str).map(str::to_string);
+    trade.multiplier = decimal("multiplier")?.unwrap_or(Decimal::from(1));
+    Ok(trade)
+}
+
+// Version of the audit NDJSON layout; bump when fields change meaning
+pub const AUDIT_SCHEMA_VERSION: i64 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuditAction {
+    Add,
+    Amend,
+    Cancel,
+}
+
+impl AuditAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditAction::Add => "add",
+            AuditAction::Amend => "amend",
+            AuditAction::Cancel => "cancel",
+        }
+    }
+}
+
+// Changes to a booked trade; fields left unset keep their current value
+#[derive(Debug, Clone, Default)]
+pub struct AmendRequest {
+    pub quantity: Option<i32>,
+    pub price: Option<Price>,
+    pub side: Option<Side>,
+    pub instrument: Option<String>,
+    // Moves the execution time to the same time of day on the new date
+    pub trade_date: Option<NaiveDate>,
+}
+
+impl AmendRequest {
+    pub fn new() -> Self {
+        AmendRequest::default()
+    }
+
+    pub fn quantity(mut self, quantity: i32) -> Self {
+        self.quantity = Some(quantity);
+        self
+    }
+
+    pub fn price(mut self, price: impl Into<Price>) -> Self {
+        self.price = Some(price.into());
+        self
+    }
+
+    pub fn side(mut self, side: Side) -> Self {
+        self.side = Some(side);
+        self
+    }
+
+    pub fn instrument(mut self, instrument: &str) -> Self {
+        self.instrument = Some(instrument.to_string());
+        self
+    }
+
+    pub fn trade_date(mut self, trade_date: NaiveDate) -> Self {
+        self.trade_date = Some(trade_date);
+        self
+    }
+}
+
+// One amendment of a trade, read back from the audit log
+#[derive(Debug, Clone)]
+pub struct Amendment {
+    pub sequence: u64,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: String,
+    // (field, before, after) for every field that changed
+    pub changes: Vec<(&'static str, String, String)>,
+}
+
+// Economic fields that differ between two versions of a trade
+pub fn trade_changes(before: &Trade, after: &Trade) -> Vec<(&'static str, String, String)> {
+    let fields = [
+        ("instrument", before.instrument.clone(), after.instrument.clone()),
+        ("trade_date", before.trade_date.to_string(), after.trade_date.to_string()),
+        ("side", format!("{:?}", before.side), format!("{:?}", after.side)),
+        ("quantity", before.quantity.to_string(), after.quantity.to_string()),
+        ("price", before.price.to_string(), after.price.to_string()),
+        ("fees", before.total_fees().to_string(), after.total_fees().to_string()),
+    ];
+    fields.into_iter().filter(|(_, before, after)| before != after).collect()
+}
+
+// One change to a trade, with the trade as it was before and after
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: String,
+    pub action: AuditAction,
+    pub trade_id: TradeId,
+    pub before: Option<Trade>,
+    pub after: Option<Trade>,
+}
+
+impl AuditEntry {
+    pub fn to_json(&self) -> String {
+        let trade = |trade: &Option<Trade>| trade.as_ref().map_or("null".to_string(), trade_to_json);
+        format!(
+            "{{\"schema_version\":{},\"sequence\":{},\"recorded_at\":\"{}\",\"actor\":{},\"action\":\"{}\",\"trade_id\":{},\"before\":{},\"after\":{}}}",
+            AUDIT_SCHEMA_VERSION,
+            self.sequence,
+            self.recorded_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            json_string(&self.actor),
+            self.action.name(),
+            self.trade_id.to_json(),
+            trade(&self.before),
+            trade(&self.after),
+        )
+    }
+
+    pub fn from_json(line: &str) -> Result<AuditEntry, String> {
+        let value = parse_json(line)?;
+        let version = value.get("schema_version").and_then(JsonValue::as_i64).ok_or("Missing schema_version")?;
+        if version > AUDIT_SCHEMA_VERSION {
+            return Err(format!("Unsupported audit schema version {}", version));
+        }
+        let text = |name: &str| value.get(name).and_then(JsonValue::as_str).ok_or(format!("Missing '{}'", name));
+        let trade = |name: &str| match value.get(name) {
+            Some(trade) if !trade.is_null() => trade_from_json(trade).map(Some),
+            _ => Ok(None),
+        };
+
+        Ok(AuditEntry {
+            sequence: value.get("sequence").and_then(JsonValue::as_i64).ok_or("Missing sequence")? as u64,
+            recorded_at: DateTime::parse_from_rfc3339(text("recorded_at")?).map_err(|e| format!("Invalid recorded_at: {}", e))?.with_timezone(&Utc),
+            actor: text("actor")?.to_string(),
+            action: match text("action")? {
+                "add" => AuditAction::Add,
+                "amend" => AuditAction::Amend,
+                "cancel" => AuditAction::Cancel,
+                other => return Err(format!("Unknown action {}", other)),
+            },
+            trade_id: value.get("trade_id").and_then(TradeId::from_json).ok_or("Missing trade_id")?,
+            before: trade("before")?,
+            after: trade("after")?,
+        })
+    }
+}
+
+pub fn parse_audit_ndjson(ndjson: &str) -> Result<Vec<AuditEntry>, PositionError> {
+    ndjson
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| AuditEntry::from_json(line).map_err(|e| PositionError::InvalidRecord(format!("line {}: {}", number + 1, e))))
+        .collect()
+}
+
+// One change to the trade book. Added and Amended carry the trade exactly as it
+// was stored, fees and rounding included, so a replay never re-derives anything.
+#[derive(Debug, Clone)]
+pub enum TradeEvent {
+    Added(Trade),
+    Amended(Trade),
+    Cancelled { trade_id: TradeId },
+    // Round trips swapped for synthetic trades by compress_before
+    Compressed(Vec<Compression>),
+}
+
+impl TradeEvent {
+    // The single trade the event is about; None for compressions
+    pub fn trade_id(&self) -> Option<TradeId> {
+        match self {
+            TradeEvent::Added(trade) | TradeEvent::Amended(trade) => Some(trade.trade_id),
+            TradeEvent::Cancelled { trade_id } => Some(*trade_id),
+            TradeEvent::Compressed(_) => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        match self {
+            TradeEvent::Added(trade) => format!("{{\"event\":\"added\",\"trade\":{}}}", trade_to_json(trade)),
+            TradeEvent::Amended(trade) => format!("{{\"event\":\"amended\",\"trade\":{}}}", trade_to_json(trade)),
+            TradeEvent::Cancelled { trade_id } => format!("{{\"event\":\"cancelled\",\"trade_id\":{}}}", trade_id.to_json()),
+            TradeEvent::Compressed(compressions) => {
+                let compressions: Vec<String> = compressions.iter().map(Compression::to_json).collect();
+                format!("{{\"event\":\"compressed\",\"compressions\":[{}]}}", compressions.join(","))
+            },
+        }
+    }
+
+    pub fn from_json(line: &str) -> Result<TradeEvent, String> {
+        let value = parse_json(line)?;
+        let trade = || value.get("trade").ok_or("Missing 'trade'".to_string()).and_then(trade_from_json);
+        match value.get("event").and_then(JsonValue::as_str) {
+            Some("added") => Ok(TradeEvent::Added(trade()?)),
+            Some("amended") => Ok(TradeEvent::Amended(trade()?)),
+            Some("cancelled") => Ok(TradeEvent::Cancelled {
+                trade_id: value.get("trade_id").and_then(TradeId::from_json).ok_or("Missing trade_id")?,
+            }),
+            Some("compressed") => match value.get("compressions") {
+                Some(JsonValue::Array(compressions)) => Ok(TradeEvent::Compressed(compressions.iter().map(Compression::from_json).collect::<Result<_, _>>()?)),
+                _ => Err("Missing 'compressions'".to_string()),
+            },
+            Some(other) => Err(format!("Unknown event {}", other)),
+            None => Err("Missing 'event'".to_string()),
+        }
+    }
+}
+
+// Closed round trips of one account and strategy in an instrument, replaced by a
+// synthetic buy and sell of one unit whose price difference is their realized P&L
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub instrument: String,
+    pub account_id: String,
+    pub strategy: Option<String>,
+    // Date of the last round trip; the synthetic trades are booked then
+    pub compressed_through: NaiveDate,
+    pub trade_ids: Vec<TradeId>,
+    pub realized_pnl: Money,
+    // Empty when the round trips realized nothing
+    pub synthetic_trades: Vec<Trade>,
+}
+
+impl Compression {
+    pub fn to_json(&self) -> String {
+        let trade_ids: Vec<String> = self.trade_ids.iter().map(|trade_id| trade_id.to_json()).collect();
+        let synthetic: Vec<String> = self.synthetic_trades.iter().map(trade_to_json).collect();
+        format!(
+            "{{\"instrument\":{},\"account_id\":{},\"strategy\":{},\"compressed_through\":\"{}\",\"trade_ids\":[{}],\"realized_pnl\":\"{}\",\"synthetic_trades\":[{}]}}",
+            json_string(&self.instrument),
+            json_string(&self.account_id),
+            json_optional_string(&self.strategy),
+            self.compressed_through,
+            trade_ids.join(","),
+            self.realized_pnl,
+            synthetic.join(","),
+        )
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Compression, String> {
+        let text = |name: &str| value.get(name).and_then(JsonValue::as_str).ok_or(format!("Compression is missing '{}'", name));
+        let array = |name: &str| match value.get(name) {
+            Some(JsonValue::Array(items)) => Ok(items),
+            _ => Err(format!("Compression is missing '{}'", name)),
+        };
+        Ok(Compression {
+            instrument: text("instrument")?.to_string(),
+            account_id: text("account_id")?.to_string(),
+            strategy: value.get("strategy").and_then(JsonValue::as_str).map(str::to_string),
+            compressed_through: NaiveDate::parse_from_str(text("compressed_through")?, "%Y-%m-%d").map_err(|_| "Invalid compressed_through".to_string())?,
+            trade_ids: array("trade_ids")?.iter()
+                .map(|trade_id| TradeId::from_json(trade_id).ok_or("Invalid trade id".to_string()))
+                .collect::<Result<_, _>>()?,
+            realized_pnl: Decimal::parse(text("realized_pnl")?)?,
+            synthetic_trades: array("synthetic_trades")?.iter().map(trade_from_json).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+// Rewrites a repository so a real book can be shared for bug reports and benchmarks.
+// Instruments, accounts, strategies, counterparties and brokers get pseudonyms that
+// stay the same across the whole copy; prices are scaled by one factor, quantities
+// by an integer one and money by both. Trade ids, dates, times, sides, statuses and
+// the order of journal events are kept, so the replayed copy has the same shape.
+// Cash instruments keep their names and unit price.
+#[derive(Debug, Clone)]
+pub struct Anonymizer {
+    pub price_scale: Decimal,
+    pub quantity_scale: i32,
+    // (kind, original name) -> pseudonym, numbered in the order names are first seen
+    pub names: BTreeMap<(&'static str, String), String>,
+}
+
+impl Anonymizer {
+    pub fn new(price_scale: impl Into<Decimal>, quantity_scale: i32) -> Anonymizer {
+        Anonymizer {
+            price_scale: price_scale.into(),
+            quantity_scale,
+            names: BTreeMap::new(),
+        }
+    }
+
+    pub fn pseudonym(&mut self, kind: &'static str, name: &str) -> String {
+        if kind == "INST" && is_cash_instrument(name) {
+            return name.to_string();
+        }
+        let next = self.names.keys().filter(|(named, _)| *named == kind).count() + 1;
+        self.names.entry((kind, name.to_string())).or_insert_with(|| format!("{}{:04}", kind, next)).clone()
+    }
+
+    // The real name behind a pseudonym, for mapping a shared report back
+    pub fn original_name(&self, pseudonym: &str) -> Option<&str> {
+        self.names.iter().find(|(_, named)| named.as_str() == pseudonym).map(|((_, name), _)| name.as_str())
+    }
+
+    pub fn price(&self, instrument: &str, price: Price) -> Price {
+        if is_cash_instrument(instrument) {
+            price
+        } else {
+            price * self.price_scale
+        }
+    }
+
+    pub fn money(&self, amount: Money) -> Money {
+        amount * self.price_scale * self.quantity_scale
+    }
+
+    pub fn anonymize_trade(&mut self, trade: &Trade) -> Trade {
+        let mut copy = trade.clone();
+        copy.instrument = self.pseudonym("INST", &trade.instrument);
+        copy.account_id = self.pseudonym("ACCT", &trade.account_id);
+        copy.strategy = trade.strategy.as_deref().map(|strategy| self.pseudonym("STRAT", strategy));
+        copy.counterparty = trade.counterparty.as_deref().map(|counterparty| self.pseudonym("CPTY", counterparty));
+        copy.broker = trade.broker.as_deref().map(|broker| self.pseudonym("BRKR", broker));
+        copy.quantity = trade.quantity * self.quantity_scale;
+        copy.price = self.price(&trade.instrument, trade.price);
+        copy.arrival_price = trade.arrival_price.map(|price| self.price(&trade.instrument, price));
+        copy.commission = self.money(trade.commission);
+        copy.exchange_fee = self.money(trade.exchange_fee);
+        copy.tax = self.money(trade.tax);
+        copy
+    }
+
+    pub fn anonymize_event(&mut self, event: &TradeEvent) -> TradeEvent {
+        match event {
+            TradeEvent::Added(trade) => TradeEvent::Added(self.anonymize_trade(trade)),
+            TradeEvent::Amended(trade) => TradeEvent::Amended(self.anonymize_trade(trade)),
+            TradeEvent::Cancelled { trade_id } => TradeEvent::Cancelled { trade_id: *trade_id },
+            TradeEvent::Compressed(compressions) => TradeEvent::Compressed(compressions
+                .iter()
+                .map(|compression| Compression {
+                    instrument: self.pseudonym("INST", &compression.instrument),
+                    account_id: self.pseudonym("ACCT", &compression.account_id),
+                    strategy: compression.strategy.as_deref().map(|strategy| self.pseudonym("STRAT", strategy)),
+                    compressed_through: compression.compressed_through,
+                    trade_ids: compression.trade_ids.clone(),
+                    realized_pnl: self.money(compression.realized_pnl),
+                    synthetic_trades: compression.synthetic_trades.iter().map(|trade| self.anonymize_trade(trade)).collect(),
+                })
+                .collect()),
+        }
+    }
+
+    // Replay the anonymized journal into a fresh repository, with the instrument
+    // master and market prices carried over under the same mapping. Other reference
+    // data (books, calendars, limits) is not copied.
+    pub fn anonymize(&mut self, repo: &TradeRepository) -> Result<TradeRepository, PositionError> {
+        let events: Vec<TradeEvent> = repo.journal.iter().map(|event| self.anonymize_event(event)).collect();
+        let mut copy = TradeRepository::replay(&events)?;
+        let mut instruments: Vec<&Instrument> = repo.instruments.values().collect();
+        instruments.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        for record in instruments {
+            let mut masked = record.clone();
+            masked.symbol = self.pseudonym("INST", &record.symbol);
+            masked.tick_size = self.price(&record.symbol, record.tick_size);
+            masked.lot_size = record.lot_size * self.quantity_scale;
+            copy.register_instrument(masked);
+        }
+        let mut prices: Vec<(&String, &Price)> = repo.market_prices.iter().collect();
+        prices.sort();
+        for (instrument, price) in prices {
+            let name = self.pseudonym("INST", instrument);
+            copy.update_market_price(&name, self.price(instrument, *price))?;
+        }
+        Ok(copy)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionReport {
+    pub cutoff: NaiveDate,
+    pub compressions: Vec<Compression>,
+    pub trades_removed: usize,
+    pub trades_added: usize,
+    // Instruments with round trips before the cutoff that could not be compressed, and why
+    pub skipped: Vec<(String, String)>,
+}
+
+pub fn print_compression_report(report: &CompressionReport) {
+    println!("\n=== Compression before {} ===", report.cutoff);
+    for compression in &report.compressions {
+        println!("{} {}/{}: {} trades through {} -> {} synthetic, realized ${:.2}",
+                 compression.instrument, compression.account_id, compression.strategy.as_deref().unwrap_or(UNASSIGNED),
+                 compression.trade_ids.len(), compression.compressed_through, compression.synthetic_trades.len(), compression.realized_pnl);
+    }
+    for (instrument, reason) in &report.skipped {
+        println!("{} not compressed: {}", instrument, reason);
+    }
+    println!("Trades removed: {}, synthetic trades added: {}", report.trades_removed, report.trades_added);
+}
+
+// Append-only NDJSON file of trade events. Each append is flushed and synced
+// before the change it records is applied, so the file is never behind the book.
+#[derive(Debug, Clone)]
+pub struct TradeJournal {
+    pub path: String,
+    pub file: Arc<Mutex<std::fs::File>>,
+}
+
+impl TradeJournal {
+    pub fn open(path: &str) -> std::io::Result<TradeJournal> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(TradeJournal {
+            path: path.to_string(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn append(&self, events: &[TradeEvent]) -> std::io::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut lines = String::new();
+        for event in events {
+            lines.push_str(&event.to_json());
+            lines.push('\n');
+        }
+        let mut file = self.file.lock().unwrap();
+        file.write_all(lines.as_bytes())?;
+        file.flush()?;
+        file.sync_data()
+    }
+
+    // Events in the file in order. A last line cut short by a crash mid-write is
+    // dropped; a bad line anywhere else is an error.
+    pub fn load(path: &str) -> Result<Vec<TradeEvent>, PositionError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| PositionError::Journal(e.to_string()))?;
+        let torn_tail = !contents.is_empty() && !contents.ends_with('\n');
+        let lines: Vec<&str> = contents.lines().collect();
+        let mut events = Vec::new();
+        for (number, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match TradeEvent::from_json(line) {
+                Ok(event) => events.push(event),
+                Err(_) if torn_tail && number + 1 == lines.len() => break,
+                Err(e) => return Err(PositionError::InvalidRecord(format!("{} line {}: {}", path, number + 1, e))),
+            }
+        }
+        Ok(events)
+    }
+}
+
+// Durable home for trades. A repository with storage attached writes every add,
+// amend and cancel through to it, and can be rebuilt from it after a restart.
+pub trait TradeStorage: std::fmt::Debug + Send {
+    fn insert(&mut self, trade: &Trade) -> Result<(), PositionError>;
+    // Replace the stored trade with the same id
+    fn amend(&mut self, trade: &Trade) -> Result<(), PositionError>;
+    fn cancel(&mut self, trade_id: TradeId) -> Result<(), PositionError>;
+    // Delete trades outright; only compression does this
+    fn remove(&mut self, trade_ids: &[TradeId]) -> Result<(), PositionError>;
+    // Matching trades, cancelled ones included, in trade id order
+    fn query(&self, filter: &TradeFilter) -> Result<Vec<Trade>, PositionError>;
+
+    // A committed batch of changes; backends that can should apply it atomically
+    fn apply(&mut self, events: &[TradeEvent]) -> Result<(), PositionError> {
+        for event in events {
+            match event {
+                TradeEvent::Added(trade) => self.insert(trade)?,
+                TradeEvent::Amended(trade) => self.amend(trade)?,
+                TradeEvent::Cancelled { trade_id } => self.cancel(*trade_id)?,
+                TradeEvent::Compressed(compressions) => {
+                    for compression in compressions {
+                        self.remove(&compression.trade_ids)?;
+                        for trade in &compression.synthetic_trades {
+                            self.insert(trade)?;
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+pub type SharedTradeStorage = Arc<Mutex<dyn TradeStorage>>;
+
+// A backend that panicked mid-write leaves its lock poisoned; report that as a
+// storage failure rather than panicking every repository that shares it
+pub fn lock_storage(storage: &SharedTradeStorage) -> Result<std::sync::MutexGuard<'_, dyn TradeStorage + 'static>, PositionError> {
+    storage.lock().map_err(|_| PositionError::Storage("a previous write panicked".to_string()))
+}
+
+// Storage kept in memory, for tests and for running without a database
+#[derive(Debug, Default)]
+pub struct MemoryTradeStore {
+    pub trades: BTreeMap<TradeId, Trade>,
+}
+
+impl TradeStorage for MemoryTradeStore {
+    fn insert(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        if self.trades.contains_key(&trade.trade_id) {
+            return Err(PositionError::DuplicateTradeId(trade.trade_id));
+        }
+        self.trades.insert(trade.trade_id, trade.clone());
+        Ok(())
+    }
+
+    fn amend(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        let stored = self.trades.get_mut(&trade.trade_id).ok_or(PositionError::TradeNotFound(trade.trade_id))?;
+        *stored = trade.clone();
+        Ok(())
+    }
+
+    fn cancel(&mut self, trade_id: TradeId) -> Result<(), PositionError> {
+        let stored = self.trades.get_mut(&trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        stored.status = TradeStatus::Cancelled;
+        Ok(())
+    }
+
+    fn remove(&mut self, trade_ids: &[TradeId]) -> Result<(), PositionError> {
+        for trade_id in trade_ids {
+            self.trades.remove(trade_id).ok_or(PositionError::TradeNotFound(*trade_id))?;
+        }
+        Ok(())
+    }
+
+    fn query(&self, filter: &TradeFilter) -> Result<Vec<Trade>, PositionError> {
+        Ok(self.trades.values().filter(|trade| trade.matches_filter(filter)).cloned().collect())
+    }
+}
+
+// SQLite-backed storage (`--features sqlite`). Each trade is one row of the
+// `trades` table: typed columns for back-office SQL (amounts as REAL) and the
+// exact record as JSON in `record`, which is what the repository reads back.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteTradeStore {
+    pub connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteTradeStore {
+    pub fn open(path: &str) -> Result<SqliteTradeStore, PositionError> {
+        SqliteTradeStore::with_connection(rusqlite::Connection::open(path).map_err(|e| PositionError::Storage(e.to_string()))?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteTradeStore, PositionError> {
+        SqliteTradeStore::with_connection(rusqlite::Connection::open_in_memory().map_err(|e| PositionError::Storage(e.to_string()))?)
+    }
+
+    pub fn with_connection(connection: rusqlite::Connection) -> Result<SqliteTradeStore, PositionError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS trades (
+                trade_id NOT NULL PRIMARY KEY,
+                trade_date TEXT NOT NULL,
+                executed_at TEXT NOT NULL,
+                instrument TEXT NOT NULL,
+                quantity INTEGER NOT NULL,
+                price REAL NOT NULL,
+                side TEXT NOT NULL,
+                trade_type TEXT NOT NULL,
+                status TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                strategy TEXT,
+                counterparty TEXT,
+                broker TEXT,
+                commission REAL NOT NULL,
+                exchange_fee REAL NOT NULL,
+                tax REAL NOT NULL,
+                record TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS trades_instrument_date ON trades (instrument, trade_date);
+            CREATE INDEX IF NOT EXISTS trades_account ON trades (account_id);",
+        ).map_err(|e| PositionError::Storage(e.to_string()))?;
+        Ok(SqliteTradeStore { connection })
+    }
+
+    pub fn write(connection: &rusqlite::Connection, trade: &Trade, replace: bool) -> Result<usize, rusqlite::Error> {
+        let verb = if replace { "REPLACE" } else { "INSERT" };
+        connection.execute(
+            &format!("{} INTO trades (trade_id, trade_date, executed_at, instrument, quantity, price, side, trade_type, status,
+                account_id, strategy, counterparty, broker, commission, exchange_fee, tax, record)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)", verb),
+            rusqlite::params![
+                trade.trade_id,
+                trade.trade_date.to_string(),
+                trade.executed_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                trade.instrument,
+                trade.quantity,
+                trade.price.to_f64(),
+                side_name(&trade.side),
+                trade_type_name(&trade.trade_type),
+                trade_status_name(&trade.status),
+                trade.account_id,
+                trade.strategy,
+                trade.counterparty,
+                trade.broker,
+                trade.commission.to_f64(),
+                trade.exchange_fee.to_f64(),
+                trade.tax.to_f64(),
+                trade_to_json(trade),
+            ],
+        )
+    }
+
+    pub fn exists(connection: &rusqlite::Connection, trade_id: TradeId) -> Result<bool, rusqlite::Error> {
+        connection.query_row("SELECT COUNT(*) FROM trades WHERE trade_id = ?1", [trade_id], |row| row.get::<_, i64>(0)).map(|count| count > 0)
+    }
+
+    pub fn apply_to(connection: &rusqlite::Connection, event: &TradeEvent) -> Result<(), PositionError> {
+        let storage_error = |e: rusqlite::Error| PositionError::Storage(e.to_string());
+        match event {
+            TradeEvent::Added(trade) => {
+                if SqliteTradeStore::exists(connection, trade.trade_id).map_err(storage_error)? {
+                    return Err(PositionError::DuplicateTradeId(trade.trade_id));
+                }
+                SqliteTradeStore::write(connection, trade, false).map_err(storage_error)?;
+            },
+            TradeEvent::Amended(trade) => {
+                if !SqliteTradeStore::exists(connection, trade.trade_id).map_err(storage_error)? {
+                    return Err(PositionError::TradeNotFound(trade.trade_id));
+                }
+                SqliteTradeStore::write(connection, trade, true).map_err(storage_error)?;
+            },
+            TradeEvent::Cancelled { trade_id } => {
+                let record: String = connection
+                    .query_row("SELECT record FROM trades WHERE trade_id = ?1", [trade_id], |row| row.get(0))
+                    .map_err(|_| PositionError::TradeNotFound(*trade_id))?;
+                let mut trade = parse_json(&record).and_then(|value| trade_from_json(&value)).map_err(PositionError::Storage)?;
+                trade.status = TradeStatus::Cancelled;
+                SqliteTradeStore::write(connection, &trade, true).map_err(storage_error)?;
+            },
+            TradeEvent::Compressed(compressions) => {
+                for compression in compressions {
+                    for trade_id in &compression.trade_ids {
+                        if connection.execute("DELETE FROM trades WHERE trade_id = ?1", [trade_id]).map_err(storage_error)? == 0 {
+                            return Err(PositionError::TradeNotFound(*trade_id));
+                        }
+                    }
+                    for trade in &compression.synthetic_trades {
+                        SqliteTradeStore::apply_to(connection, &TradeEvent::Added(trade.clone()))?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl TradeStorage for SqliteTradeStore {
+    fn insert(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        SqliteTradeStore::apply_to(&self.connection, &TradeEvent::Added(trade.clone()))
+    }
+
+    fn amend(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        SqliteTradeStore::apply_to(&self.connection, &TradeEvent::Amended(trade.clone()))
+    }
+
+    fn cancel(&mut self, trade_id: TradeId) -> Result<(), PositionError> {
+        SqliteTradeStore::apply_to(&self.connection, &TradeEvent::Cancelled { trade_id })
+    }
+
+    fn remove(&mut self, trade_ids: &[TradeId]) -> Result<(), PositionError> {
+        let storage_error = |e: rusqlite::Error| PositionError::Storage(e.to_string());
+        let transaction = self.connection.transaction().map_err(storage_error)?;
+        for trade_id in trade_ids {
+            if transaction.execute("DELETE FROM trades WHERE trade_id = ?1", [trade_id]).map_err(storage_error)? == 0 {
+                return Err(PositionError::TradeNotFound(*trade_id));
+            }
+        }
+        transaction.commit().map_err(storage_error)
+    }
+
+    // The SQL narrows the rows; the filter is re-checked on the exact records
+    fn query(&self, filter: &TradeFilter) -> Result<Vec<Trade>, PositionError> {
+        use rusqlite::types::Value;
+        let storage_error = |e: rusqlite::Error| PositionError::Storage(e.to_string());
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut params: Vec<Value> = Vec::new();
+        if let Some(instrument) = &filter.instrument {
+            conditions.push("instrument = ?");
+            params.push(Value::Text(instrument.clone()));
+        }
+        if let Some(side) = &filter.side {
+            conditions.push("side = ?");
+            params.push(Value::Text(side_name(side).to_string()));
+        }
+        if let Some(status) = &filter.status {
+            conditions.push("status = ?");
+            params.push(Value::Text(trade_status_name(status).to_string()));
+        }
+        if let Some(date_from) = filter.date_from {
+            conditions.push("trade_date >= ?");
+            params.push(Value::Text(date_from.to_string()));
+        }
+        if let Some(date_to) = filter.date_to {
+            conditions.push("trade_date <= ?");
+            params.push(Value::Text(date_to.to_string()));
+        }
+        if let Some(min_quantity) = filter.min_quantity {
+            conditions.push("quantity >= ?");
+            params.push(Value::Integer(min_quantity as i64));
+        }
+        if let Some(max_quantity) = filter.max_quantity {
+            conditions.push("quantity <= ?");
+            params.push(Value::Integer(max_quantity as i64));
+        }
+
+        let mut sql = "SELECT record FROM trades".to_string();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY trade_id");
+
+        let mut statement = self.connection.prepare(&sql).map_err(storage_error)?;
+        let records = statement
+            .query_map(rusqlite::params_from_iter(params), |row| row.get::<_, String>(0))
+            .map_err(storage_error)?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(storage_error)?;
+        let mut trades = Vec::new();
+        for record in records {
+            let trade = parse_json(&record).and_then(|value| trade_from_json(&value)).map_err(PositionError::Storage)?;
+            if trade.matches_filter(filter) {
+                trades.push(trade);
+            }
+        }
+        Ok(trades)
+    }
+
+    // All of a transaction's changes commit together or not at all
+    fn apply(&mut self, events: &[TradeEvent]) -> Result<(), PositionError> {
+        let storage_error = |e: rusqlite::Error| PositionError::Storage(e.to_string());
+        let transaction = self.connection.transaction().map_err(storage_error)?;
+        for event in events {
+            SqliteTradeStore::apply_to(&transaction, event)?;
+        }
+        transaction.commit().map_err(storage_error)
+    }
+}
+
+pub fn position_to_json(position: &TradePosition) -> String {
+    // Amounts are written as strings so they read back exactly
+    format!(
+        "{{\"instrument\":{},\"quantity\":{},\"average_price\":\"{}\",\"realized_pnl\":\"{}\",\"total_cost\":\"{}\",\"multiplier\":\"{}\"}}",
+        json_string(&position.instrument),
+        position.quantity,
+        position.average_price,
+        position.realized_pnl,
+        position.total_cost,
+        position.multiplier,
+    )
+}
+
+pub fn position_from_json(value: &JsonValue) -> Result<TradePosition, String> {
+    let text = |name: &str| value.get(name).and_then(JsonValue::as_str).ok_or(format!("Position is missing '{}'", name));
+    let amount = |name: &str| Decimal::parse(text(name)?);
+    Ok(TradePosition {
+        instrument: text("instrument")?.to_string(),
+        quantity: value.get("quantity").and_then(JsonValue::as_i64).ok_or("Position is missing 'quantity'")? as i32,
+        average_price: amount("average_price")?,
+        realized_pnl: amount("realized_pnl")?,
+        total_cost: amount("total_cost")?,
+        // Snapshots from before the instrument master have no multiplier
+        multiplier: value.get("multiplier").map_or(Ok(Decimal::from(1)), |_| amount("multiplier"))?,
+    })
+}
+
+// End-of-day position snapshots on disk, one `positions-YYYY-MM-DD.json` file per
+// date. Positions as of any date are the nearest earlier snapshot plus the trades
+// dated after it, so the full history never has to be replayed.
+#[derive(Debug, Clone)]
+pub struct PositionSnapshotStore {
+    pub directory: String,
+    // Minimum calendar days between snapshots taken at end of day
+    pub interval_days: i64,
+    // Snapshot dates present in the directory
+    pub dates: BTreeSet<NaiveDate>,
+}
+
+impl PositionSnapshotStore {
+    // Open a snapshot directory, creating it if needed and indexing what it holds
+    pub fn open(directory: &str) -> std::io::Result<PositionSnapshotStore> {
+        std::fs::create_dir_all(directory)?;
+        let mut dates = BTreeSet::new();
+        for entry in std::fs::read_dir(directory)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let date = name.strip_prefix("positions-").and_then(|rest| rest.strip_suffix(".json"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            if let Some(date) = date {
+                dates.insert(date);
+            }
+        }
+        Ok(PositionSnapshotStore {
+            directory: directory.to_string(),
+            interval_days: 1,
+            dates,
+        })
+    }
+
+    pub fn interval_days(mut self, days: i64) -> Self {
+        self.interval_days = days.max(1);
+        self
+    }
+
+    pub fn path(&self, date: NaiveDate) -> String {
+        std::path::Path::new(&self.directory).join(format!("positions-{}.json", date)).to_string_lossy().into_owned()
+    }
+
+    // Whether a snapshot is due at the end of `date`
+    pub fn due(&self, date: NaiveDate) -> bool {
+        self.dates.range(..=date).next_back().is_none_or(|last| (date - *last).num_days() >= self.interval_days)
+    }
+
+    // Written to a temporary file and renamed, so a crash never leaves half a snapshot
+    pub fn write(&mut self, date: NaiveDate, positions: &BTreeMap<String, TradePosition>) -> std::io::Result<()> {
+        let body: Vec<String> = positions.values().map(position_to_json).collect();
+        let contents = format!("{{\"as_of_date\":\"{}\",\"positions\":[{}]}}\n", date, body.join(","));
+        let path = self.path(date);
+        let staging = format!("{}.tmp", path);
+        let mut file = std::fs::File::create(&staging)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(&staging, &path)?;
+        self.dates.insert(date);
+        Ok(())
+    }
+
+    pub fn read(&self, date: NaiveDate) -> Result<BTreeMap<String, TradePosition>, PositionError> {
+        let path = self.path(date);
+        let contents = std::fs::read_to_string(&path).map_err(|e| PositionError::Snapshot(format!("{}: {}", path, e)))?;
+        let value = parse_json(&contents).map_err(|e| PositionError::Snapshot(format!("{}: {}", path, e)))?;
+        let positions = match value.get("positions") {
+            Some(JsonValue::Array(positions)) => positions,
+            _ => return Err(PositionError::Snapshot(format!("{}: missing positions", path))),
+        };
+        positions.iter()
+            .map(|position| position_from_json(position).map(|position| (position.instrument.clone(), position)))
+            .collect::<Result<_, _>>()
+            .map_err(|e| PositionError::Snapshot(format!("{}: {}", path, e)))
+    }
+
+    // Drop snapshots taken on or after `date`; a change to a trade dated then makes them stale.
+    // A file that cannot be removed is still forgotten by this store.
+    pub fn invalidate_from(&mut self, date: NaiveDate) {
+        for stale in self.dates.split_off(&date) {
+            let _ = std::fs::remove_file(self.path(stale));
+        }
+    }
+}
+
+// One-day 99% normal quantile used by the snapshot VaR
+pub const VAR_99_Z: f64 = 2.326;
+
+// Normal quantile for a probability, by bisection on norm_cdf
+pub fn norm_quantile(probability: f64) -> f64 {
+    let (mut low, mut high) = (-10.0, 10.0);
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if norm_cdf(mid) < probability {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+// Confidence levels (e.g. 0.99) and how many daily returns of closing prices
+// the one-day VaR is measured over
+#[derive(Debug, Clone)]
+pub struct VarConfig {
+    pub confidence_levels: Vec<f64>,
+    pub lookback_days: usize,
+}
+
+// One-day losses at a confidence level, as positive amounts in the firm currency
+#[derive(Debug, Clone)]
+pub struct VarFigures {
+    pub confidence: f64,
+    pub parametric_var: Money,
+    pub parametric_es: Money,
+    pub historical_var: Money,
+    pub historical_es: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct VarLine {
+    pub name: String,
+    pub market_value: Money,
+    // Historical scenarios the figures rest on
+    pub scenarios: usize,
+    pub figures: Vec<VarFigures>,
+}
+
+// VaR and expected shortfall per instrument and for the whole book. Each scenario
+// applies one historical day's returns to today's positions, so the portfolio
+// figures reflect the correlation between instruments.
+#[derive(Debug, Clone)]
+pub struct VarReport {
+    pub as_of_date: NaiveDate,
+    pub instruments: Vec<VarLine>,
+    pub portfolio: VarLine,
+    // Open positions without enough close history, left out
+    pub missing_history: Vec<String>,
+}
+
+// Parametric figures assume normal P&L with the scenarios' standard deviation and
+// zero mean; historical figures read the losses off the scenarios themselves,
+// ES being the mean loss beyond the VaR
+pub fn var_line(name: &str, market_value: Money, scenario_pnl: &[f64], confidence_levels: &[f64]) -> VarLine {
+    let n = scenario_pnl.len();
+    let mean = scenario_pnl.iter().sum::<f64>() / n.max(1) as f64;
+    let deviation = (scenario_pnl.iter().map(|pnl| (pnl - mean).powi(2)).sum::<f64>() / (n.max(2) - 1) as f64).sqrt();
+    let mut losses: Vec<f64> = scenario_pnl.iter().map(|pnl| -pnl).collect();
+    losses.sort_by(|a, b| b.partial_cmp(a).unwrap());
+
+    let figures = confidence_levels
+        .iter()
+        .map(|confidence| {
+            let z = norm_quantile(*confidence);
+            let tail = (((1.0 - confidence) * n as f64).ceil() as usize).clamp(1, n.max(1));
+            let (historical_var, historical_es) = match losses.get(tail - 1) {
+                Some(var) => (*var, losses[..tail].iter().sum::<f64>() / tail as f64),
+                None => (0.0, 0.0),
+            };
+            VarFigures {
+                confidence: *confidence,
+                parametric_var: Decimal::from(z * deviation),
+                parametric_es: Decimal::from(deviation * norm_pdf(z) / (1.0 - confidence)),
+                historical_var: Decimal::from(historical_var.max(0.0)),
+                historical_es: Decimal::from(historical_es.max(0.0)),
+            }
+        })
+        .collect();
+    VarLine { name: name.to_string(), market_value, scenarios: n, figures }
+}
+
+pub fn print_var_report(report: &VarReport) {
+    println!("\n=== Value at Risk as of {} ===", report.as_of_date);
+    println!("{:<10} {:>14} {:>6} {:>6} {:>14} {:>14} {:>14} {:>14}", "Name", "Market Value", "Days", "Conf", "Param VaR", "Param ES", "Hist VaR", "Hist ES");
+    for line in report.instruments.iter().chain(std::iter::once(&report.portfolio)) {
+        for (i, figures) in line.figures.iter().enumerate() {
+            let (name, value, days) = if i == 0 {
+                (line.name.clone(), format!("{:.2}", line.market_value), line.scenarios.to_string())
+            } else {
+                (String::new(), String::new(), String::new())
+            };
+            println!("{:<10} {:>14} {:>6} {:>5.1}% {:>14.2} {:>14.2} {:>14.2} {:>14.2}",
+                     name, value, days, figures.confidence * 100.0, figures.parametric_var, figures.parametric_es, figures.historical_var, figures.historical_es);
+        }
+    }
+    if !report.missing_history.is_empty() {
+        println!("Left out for lack of close history: {}", report.missing_history.join(", "));
+    }
+}
+
+// One move in a stress scenario. Price moves are fractions, e.g. -0.10 for -10%.
+#[derive(Debug, Clone)]
+pub enum Shock {
+    Instrument { instrument: String, change: f64 },
+    AssetClass { asset_class: AssetClass, change: f64 },
+    Sector { sector: String, change: f64 },
+    // Parallel shift in basis points, moving each instrument with a modified
+    // duration by -duration * shift
+    Rates { basis_points: f64 },
+    // Move of a currency against the firm currency
+    Currency { currency: String, change: f64 },
+}
+
+// Named set of shocks. Every shock matching an instrument applies, compounding,
+// so "equities -10%" with "AAPL +5%" takes AAPL to 0.90 * 1.05 of its price.
+#[derive(Debug, Clone)]
+pub struct StressScenario {
+    pub name: String,
+    pub shocks: Vec<Shock>,
+}
+
+impl StressScenario {
+    pub fn new(name: &str) -> Self {
+        StressScenario { name: name.to_string(), shocks: Vec::new() }
+    }
+
+    pub fn instrument(mut self, instrument: &str, change: f64) -> Self {
+        self.shocks.push(Shock::Instrument { instrument: instrument.to_string(), change });
+        self
+    }
+
+    pub fn asset_class(mut self, asset_class: AssetClass, change: f64) -> Self {
+        self.shocks.push(Shock::AssetClass { asset_class, change });
+        self
+    }
+
+    pub fn sector(mut self, sector: &str, change: f64) -> Self {
+        self.shocks.push(Shock::Sector { sector: sector.to_string(), change });
+        self
+    }
+
+    pub fn rates(mut self, basis_points: f64) -> Self {
+        self.shocks.push(Shock::Rates { basis_points });
+        self
+    }
+
+    pub fn currency(mut self, currency: &str, change: f64) -> Self {
+        self.shocks.push(Shock::Currency { currency: currency.to_string(), change });
+        self
+    }
+}
+
+// A position's value in the firm currency before and after a scenario
+#[derive(Debug, Clone)]
+pub struct StressLine {
+    pub instrument: String,
+    pub quantity: i32,
+    pub market_value: Money,
+    pub stressed_value: Money,
+}
+
+impl StressLine {
+    pub fn pnl(&self) -> Money {
+        self.stressed_value - self.market_value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StressResult {
+    pub scenario: String,
+    pub lines: Vec<StressLine>,
+}
+
+impl StressResult {
+    pub fn pnl(&self) -> Money {
+        self.lines.iter().map(StressLine::pnl).sum()
+    }
+
+    // Positions ordered by how much they lose
+    pub fn worst(&self, count: usize) -> Vec<&StressLine> {
+        let mut lines: Vec<&StressLine> = self.lines.iter().collect();
+        lines.sort_by_key(|line| line.pnl());
+        lines.truncate(count);
+        lines
+    }
+}
+
+// Hypothetical P&L of the book as of a date under each scenario. Positions are
+// valued at the date's close, or the market price or average cost without one.
+#[derive(Debug, Clone)]
+pub struct StressReport {
+    pub as_of_date: NaiveDate,
+    pub results: Vec<StressResult>,
+}
+
+impl StressReport {
+    // One row per scenario and position, for the overnight risk pack
+    pub fn to_csv(&self) -> String {
+        let mut csv = "as_of,scenario,instrument,quantity,market_value,stressed_value,pnl\n".to_string();
+        for result in &self.results {
+            for line in &result.lines {
+                csv.push_str(&format!("{},{},{},{},{},{},{}\n", self.as_of_date, result.scenario, line.instrument, line.quantity,
+                                      line.market_value, line.stressed_value, line.pnl()));
+            }
+        }
+        csv
+    }
+}
+
+pub fn print_stress_report(report: &StressReport, contributors: usize) {
+    println!("\n=== Stress Test as of {} ===", report.as_of_date);
+    for result in &report.results {
+        println!("{:<32} {:>14.2}", result.scenario, result.pnl());
+        for line in result.worst(contributors) {
+            println!("  {:<10} {:>10} {:>14.2} -> {:>14.2} {:>14.2}", line.instrument, line.quantity, line.market_value, line.stressed_value, line.pnl());
+        }
+    }
+}
+
+// Risk of the book at one instant. Instruments without a market price are valued at
+// average cost. VaR is the sum of each position's one-day 99% parametric VaR from its
+// implied volatility: an upper bound that ignores diversification.
+#[derive(Debug, Clone)]
+pub struct RiskSnapshot {
+    pub taken_at: DateTime<Utc>,
+    // instrument -> (quantity, market value)
+    pub positions: BTreeMap<String, (i32, Money)>,
+    pub long_exposure: Money,
+    pub short_exposure: Money,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub var_99: Money,
+    // Open positions left out of the VaR for lack of a volatility
+    pub without_volatility: Vec<String>,
+}
+
+impl RiskSnapshot {
+    pub fn net_exposure(&self) -> Money {
+        self.long_exposure - self.short_exposure
+    }
+
+    pub fn gross_exposure(&self) -> Money {
+        self.long_exposure + self.short_exposure
+    }
+
+    // Amounts are written as strings so they read back exactly
+    pub fn to_json(&self) -> String {
+        let positions: Vec<String> = self.positions
+            .iter()
+            .map(|(instrument, (quantity, market_value))| format!("{}:{{\"quantity\":{},\"market_value\":\"{}\"}}", json_string(instrument), quantity, market_value))
+            .collect();
+        let without_volatility: Vec<String> = self.without_volatility.iter().map(|instrument| json_string(instrument)).collect();
+        format!(
+            "{{\"taken_at\":\"{}\",\"positions\":{{{}}},\"long_exposure\":\"{}\",\"short_exposure\":\"{}\",\"realized_pnl\":\"{}\",\"unrealized_pnl\":\"{}\",\"var_99\":\"{}\",\"without_volatility\":[{}]}}",
+            self.taken_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            positions.join(","),
+            self.long_exposure,
+            self.short_exposure,
+            self.realized_pnl,
+            self.unrealized_pnl,
+            self.var_99,
+            without_volatility.join(","),
+        )
+    }
+
+    pub fn from_json(line: &str) -> Result<RiskSnapshot, String> {
+        let value = parse_json(line)?;
+        let text = |name: &str| value.get(name).and_then(JsonValue::as_str).ok_or(format!("Snapshot is missing '{}'", name));
+        let amount = |name: &str| Decimal::parse(text(name)?);
+        let mut positions = BTreeMap::new();
+        if let Some(JsonValue::Object(entries)) = value.get("positions") {
+            for (instrument, entry) in entries {
+                let quantity = entry.get("quantity").and_then(JsonValue::as_i64).ok_or(format!("Position {} is missing 'quantity'", instrument))?;
+                let market_value = entry.get("market_value").and_then(JsonValue::as_str).ok_or(format!("Position {} is missing 'market_value'", instrument))?;
+                positions.insert(instrument.clone(), (quantity as i32, Decimal::parse(market_value)?));
+            }
+        }
+        let without_volatility = match value.get("without_volatility") {
+            Some(JsonValue::Array(items)) => items.iter().filter_map(JsonValue::as_str).map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        Ok(RiskSnapshot {
+            taken_at: DateTime::parse_from_rfc3339(text("taken_at")?).map_err(|e| format!("Invalid taken_at: {}", e))?.with_timezone(&Utc),
+            positions,
+            long_exposure: amount("long_exposure")?,
+            short_exposure: amount("short_exposure")?,
+            realized_pnl: amount("realized_pnl")?,
+            unrealized_pnl: amount("unrealized_pnl")?,
+            var_99: amount("var_99")?,
+            without_volatility,
+        })
+    }
+}
+
+// Most recent risk snapshots, oldest dropped first once the capacity is reached.
+// With a file attached every snapshot is also appended to it as an NDJSON line.
+#[derive(Debug)]
+pub struct RiskSnapshotHistory {
+    pub capacity: usize,
+    pub snapshots: VecDeque<RiskSnapshot>,
+    pub file: Option<std::fs::File>,
+    pub persist_failures: usize,
+}
+
+impl RiskSnapshotHistory {
+    pub fn new(capacity: usize) -> RiskSnapshotHistory {
+        RiskSnapshotHistory {
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            file: None,
+            persist_failures: 0,
+        }
+    }
+
+    pub fn persist_to(mut self, path: &str) -> std::io::Result<Self> {
+        self.file = Some(std::fs::OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(self)
+    }
+
+    // A snapshot that cannot be written is still kept in memory
+    pub fn push(&mut self, snapshot: RiskSnapshot) {
+        if let Some(file) = &mut self.file {
+            let line = format!("{}\n", snapshot.to_json());
+            if file.write_all(line.as_bytes()).and_then(|_| file.flush()).is_err() {
+                self.persist_failures += 1;
+            }
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<&RiskSnapshot> {
+        self.snapshots.iter().filter(|snapshot| snapshot.taken_at >= from && snapshot.taken_at <= to).collect()
+    }
+
+    // Snapshots persisted to a file, for analysis after the fact
+    pub fn load(path: &str) -> Result<Vec<RiskSnapshot>, PositionError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| PositionError::Snapshot(e.to_string()))?;
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| RiskSnapshot::from_json(line).map_err(|e| PositionError::InvalidRecord(format!("{} line {}: {}", path, number + 1, e))))
+            .collect()
+    }
+}
+
+// Background thread snapshotting a shared repository's risk at a fixed interval
+// until stopped. Readers of the history never wait on the repository lock.
+#[derive(Debug)]
+pub struct RiskSnapshotter {
+    pub history: Arc<Mutex<RiskSnapshotHistory>>,
+    pub token: CancellationToken,
+    pub worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl RiskSnapshotter {
+    pub fn start(repo: Arc<RwLock<TradeRepository>>, interval: Duration, history: RiskSnapshotHistory) -> RiskSnapshotter {
+        let history = Arc::new(Mutex::new(history));
+        let token = CancellationToken::new();
+        let worker = {
+            let history = Arc::clone(&history);
+            let token = token.clone();
+            std::thread::spawn(move || {
+                // Sleep in short steps so a stop request is seen promptly
+                let step = interval.min(Duration::from_millis(50));
+                let mut next = Instant::now() + interval;
+                while !token.is_cancelled() {
+                    let now = Instant::now();
+                    if now < next {
+                        std::thread::sleep(step.min(next - now));
+                        continue;
+                    }
+                    let snapshot = repo.read().unwrap().risk_snapshot(Utc::now());
+                    history.lock().unwrap().push(snapshot);
+                    next += interval;
+                }
+            })
+        };
+        RiskSnapshotter { history, token, worker: Some(worker) }
+    }
+
+    pub fn snapshots(&self) -> Vec<RiskSnapshot> {
+        self.history.lock().unwrap().snapshots.iter().cloned().collect()
+    }
+
+    // Stop the thread and hand back what it collected
+    pub fn stop(mut self) -> RiskSnapshotHistory {
+        self.shutdown();
+        let history = std::mem::replace(&mut *self.history.lock().unwrap(), RiskSnapshotHistory::new(1));
+        history
+    }
+
+    pub fn shutdown(&mut self) {
+        self.token.cancel();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for RiskSnapshotter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Fields a custom report can show, filter, group and sort on; one row per account and instrument
+pub const REPORT_FIELDS: [&str; 14] = [
+    "account", "book", "instrument", "sector", "currency", "side", "quantity", "average_price",
+    "market_price", "market_value", "exposure", "realized_pnl", "unrealized_pnl", "total_pnl",
+];
+// Fields that get subtotals per group
+pub const REPORT_SUMMED_FIELDS: [&str; 5] = ["market_value", "exposure", "realized_pnl", "unrealized_pnl", "total_pnl"];
+
+#[derive(Debug, Clone)]
+pub enum ReportValue {
+    Text(String),
+    Number(Decimal),
+}
+
+impl ReportValue {
+    pub fn compare(&self, other: &ReportValue) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (ReportValue::Text(a), ReportValue::Text(b)) => Some(a.cmp(b)),
+            (ReportValue::Number(a), ReportValue::Number(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    pub fn render(&self, field: &str) -> String {
+        match self {
+            ReportValue::Text(text) => text.clone(),
+            ReportValue::Number(number) if field == "quantity" => number.to_string(),
+            ReportValue::Number(number) => format!("{:.2}", number),
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<ReportValue, String> {
+        match value {
+            JsonValue::String(text) => Ok(ReportValue::Text(text.clone())),
+            JsonValue::Number(number) => Ok(ReportValue::Number(Decimal::from_f64(*number)?)),
+            _ => Err("Filter value must be a string or a number".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportFilter {
+    pub field: String,
+    pub op: FilterOp,
+    pub value: ReportValue,
+}
+
+impl ReportFilter {
+    // Comparing text with a number never matches
+    pub fn matches(&self, row: &HashMap<&str, ReportValue>) -> bool {
+        use std::cmp::Ordering;
+        let Some(ordering) = row.get(self.field.as_str()).and_then(|value| value.compare(&self.value)) else {
+            return false;
+        };
+        match self.op {
+            FilterOp::Eq => ordering == Ordering::Equal,
+            FilterOp::Ne => ordering != Ordering::Equal,
+            FilterOp::Lt => ordering == Ordering::Less,
+            FilterOp::Le => ordering != Ordering::Greater,
+            FilterOp::Gt => ordering == Ordering::Greater,
+            FilterOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+// A recurring report defined as data, e.g.
+// {"name": "Short positions over $1M by sector",
+//  "columns": ["instrument", "account", "quantity", "market_value"],
+//  "filters": [{"field": "side", "op": "=", "value": "short"},
+//              {"field": "exposure", "op": ">", "value": 1000000}],
+//  "group_by": "sector", "sort": [{"field": "exposure", "descending": true}],
+//  "format": "table"}
+// Filters are combined with AND; format defaults to table.
+#[derive(Debug, Clone)]
+pub struct ReportDefinition {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub filters: Vec<ReportFilter>,
+    pub group_by: Option<String>,
+    // Field and whether to sort descending, applied in order
+    pub sort: Vec<(String, bool)>,
+    pub format: ReportFormat,
+}
+
+impl ReportDefinition {
+    pub fn from_json(text: &str) -> Result<ReportDefinition, String> {
+        ReportDefinition::from_json_value(&parse_json(text)?)
+    }
+
+    pub fn from_json_value(value: &JsonValue) -> Result<ReportDefinition, String> {
+        let field_name = |value: Option<&JsonValue>, context: &str| -> Result<String, String> {
+            let name = value.and_then(JsonValue::as_str).ok_or(format!("{} must be a field name", context))?;
+            if !REPORT_FIELDS.contains(&name) {
+                return Err(format!("Unknown field '{}' in {}", name, context));
+            }
+            Ok(name.to_string())
+        };
+        let array = |key: &str| match value.get(key) {
+            Some(JsonValue::Array(items)) => Ok(items.clone()),
+            None | Some(JsonValue::Null) => Ok(Vec::new()),
+            Some(_) => Err(format!("'{}' must be an array", key)),
+        };
+
+        let name = value.get("name").and_then(JsonValue::as_str).ok_or("Report needs a name")?.to_string();
+        let columns = array("columns")?
+            .iter()
+            .map(|column| field_name(Some(column), "columns"))
+            .collect::<Result<Vec<String>, String>>()?;
+        if columns.is_empty() {
+            return Err("Report needs at least one column".to_string());
+        }
+        let filters = array("filters")?
+            .iter()
+            .map(|filter| {
+                let op = match filter.get("op").and_then(JsonValue::as_str) {
+                    Some("=") => FilterOp::Eq,
+                    Some("!=") => FilterOp::Ne,
+                    Some("<") => FilterOp::Lt,
+                    Some("<=") => FilterOp::Le,
+                    Some(">") => FilterOp::Gt,
+                    Some(">=") => FilterOp::Ge,
+                    other => return Err(format!("Unknown filter op {:?}", other)),
+                };
+                Ok(ReportFilter {
+                    field: field_name(filter.get("field"), "filters")?,
+                    op,
+                    value: ReportValue::from_json(filter.get("value").ok_or("Filter needs a value")?)?,
+                })
+            })
+            .collect::<Result<Vec<ReportFilter>, String>>()?;
+        let group_by = match value.get("group_by") {
+            None | Some(JsonValue::Null) => None,
+            group_by => Some(field_name(group_by, "group_by")?),
+        };
+        let sort = array("sort")?
+            .iter()
+            .map(|key| Ok((field_name(key.get("field"), "sort")?, matches!(key.get("descending"), Some(JsonValue::Bool(true))))))
+            .collect::<Result<Vec<(String, bool)>, String>>()?;
+        let format = match value.get("format").and_then(JsonValue::as_str) {
+            None | Some("table") => ReportFormat::Table,
+            Some("csv") => ReportFormat::Csv,
+            Some("json") => ReportFormat::Json,
+            Some(other) => return Err(format!("Unknown format {}", other)),
+        };
+
+        Ok(ReportDefinition { name, columns, filters, group_by, sort, format })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportGroup {
+    // Value of the group_by field; None when the report is not grouped
+    pub key: Option<String>,
+    pub rows: Vec<Vec<ReportValue>>,
+    // Per column, for the summed fields only
+    pub subtotals: Vec<Option<Decimal>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CustomReport {
+    pub name: String,
+    pub as_of_date: NaiveDate,
+    pub columns: Vec<String>,
+    pub group_by: Option<String>,
+    pub groups: Vec<ReportGroup>,
+    pub format: ReportFormat,
+}
+
+impl CustomReport {
+    pub fn render(&self) -> String {
+        match self.format {
+            ReportFormat::Table => self.render_table(),
+            ReportFormat::Csv => self.render_csv(),
+            ReportFormat::Json => self.render_json(),
+        }
+    }
+
+    pub fn render_table(&self) -> String {
+        let cells: Vec<Vec<String>> = self.groups
+            .iter()
+            .flat_map(|group| group.rows.iter())
+            .map(|row| row.iter().zip(&self.columns).map(|(value, field)| value.render(field)).collect())
+            .collect();
+        let widths: Vec<usize> = self.columns
+            .iter()
+            .enumerate()
+            .map(|(column, name)| cells.iter().map(|row| row[column].len()).chain([name.len()]).max().unwrap_or(0))
+            .collect();
+        let line = |values: &[String]| values.iter().zip(&widths).map(|(value, width)| format!("{:<width$}", value, width = width)).collect::<Vec<_>>().join(" | ").trim_end().to_string();
+
+        let mut out = format!("=== {} as of {} ===\n{}\n", self.name, self.as_of_date, line(&self.columns));
+        let mut cells = cells.into_iter();
+        for group in &self.groups {
+            if let Some(key) = &group.key {
+                out.push_str(&format!("[{}]\n", key));
+            }
+            for row in cells.by_ref().take(group.rows.len()) {
+                out.push_str(&line(&row));
+                out.push('\n');
+            }
+            if group.key.is_some() {
+                let subtotals: Vec<String> = group.subtotals.iter().map(|total| total.map_or(String::new(), |total| format!("{:.2}", total))).collect();
+                out.push_str(&format!("{} (subtotal)\n", line(&subtotals)));
+            }
+        }
+        out
+    }
+
+    pub fn render_csv(&self) -> String {
+        let mut header: Vec<String> = self.group_by.iter().cloned().collect();
+        header.extend(self.columns.iter().cloned());
+        let mut out = header.join(",") + "\n";
+        for group in &self.groups {
+            for row in &group.rows {
+                let mut fields: Vec<String> = group.key.iter().cloned().collect();
+                fields.extend(row.iter().zip(&self.columns).map(|(value, field)| value.render(field)));
+                out.push_str(&fields.join(","));
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    pub fn render_json(&self) -> String {
+        let value = |value: &ReportValue, field: &str| match value {
+            ReportValue::Text(text) => json_string(text),
+            ReportValue::Number(_) => value.render(field),
+        };
+        let groups: Vec<String> = self.groups
+            .iter()
+            .map(|group| {
+                let rows: Vec<String> = group.rows
+                    .iter()
+                    .map(|row| {
+                        let fields: Vec<String> = row.iter().zip(&self.columns).map(|(cell, field)| format!("{}:{}", json_string(field), value(cell, field))).collect();
+                        format!("{{{}}}", fields.join(","))
+                    })
+                    .collect();
+                let subtotals: Vec<String> = group.subtotals
+                    .iter()
+                    .zip(&self.columns)
+                    .filter_map(|(total, field)| total.map(|total| format!("{}:{:.2}", json_string(field), total)))
+                    .collect();
+                format!("{{\"group\":{},\"rows\":[{}],\"subtotals\":{{{}}}}}", json_optional_string(&group.key), rows.join(","), subtotals.join(","))
+            })
+            .collect();
+        format!("{{\"name\":{},\"as_of_date\":\"{}\",\"groups\":[{}]}}", json_string(&self.name), self.as_of_date, groups.join(","))
+    }
+}
+
+// How long personal and dead data is kept. Ages are in whole years before the
+// as-of date of the retention run; None keeps the data forever.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    // Actors recorded in the audit log
+    pub trader_identity_years: Option<u32>,
+    pub cancelled_trade_years: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn new() -> Self {
+        RetentionPolicy {
+            trader_identity_years: None,
+            cancelled_trade_years: None,
+        }
+    }
+
+    pub fn purge_trader_identities_after(mut self, years: u32) -> Self {
+        self.trader_identity_years = Some(years);
+        self
+    }
+
+    pub fn purge_cancelled_trades_after(mut self, years: u32) -> Self {
+        self.cancelled_trade_years = Some(years);
+        self
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionReport {
+    pub as_of_date: NaiveDate,
+    pub cancelled_trades_purged: usize,
+    pub segments_rewritten: usize,
+    pub audit_entries_purged: usize,
+    pub identities_redacted: usize,
+}
+
+pub fn years_before(date: NaiveDate, years: u32) -> NaiveDate {
+    date.checked_sub_months(chrono::Months::new(years * 12)).unwrap_or(NaiveDate::MIN)
+}
+
+// Cash is held as one instrument per currency, in whole currency units at a price of 1
+pub fn cash_instrument(currency: &str) -> String {
+    format!("CASH.{}", currency)
+}
+
+pub fn is_cash_instrument(instrument: &str) -> bool {
+    instrument.starts_with("CASH.")
+}
+
+#[derive(Debug, Clone)]
+pub enum CashMovementKind {
+    // Trades in a cash instrument, booked through book_cash or as a sweep's cash leg
+    Deposit,
+    Withdrawal,
+    // Consideration of a buy (debit) or sell (credit), and the fees charged on it
+    Trade(TradeId),
+    Fees(TradeId),
+    // Net of withholding, on the pay date
+    Dividend(String),
+    // Called on the firm's futures position, so it belongs to no account
+    VariationMargin(String),
+    // Credit (or debit) interest paid by the broker on the account's cash
+    Interest,
+}
+
+impl std::fmt::Display for CashMovementKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CashMovementKind::Deposit => write!(f, "deposit"),
+            CashMovementKind::Withdrawal => write!(f, "withdrawal"),
+            CashMovementKind::Trade(trade_id) => write!(f, "trade {}", trade_id),
+            CashMovementKind::Fees(trade_id) => write!(f, "fees on trade {}", trade_id),
+            CashMovementKind::Dividend(instrument) => write!(f, "{} dividend", instrument),
+            CashMovementKind::VariationMargin(instrument) => write!(f, "{} variation margin", instrument),
+            CashMovementKind::Interest => write!(f, "interest"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterestPayment {
+    pub account_id: String,
+    pub currency: String,
+    pub date: NaiveDate,
+    pub amount: Money,
+}
+
+// One line of a broker's cash activity statement. The reference is the trade id of
+// trade and fee lines and the instrument of dividend lines, when the broker gives one.
+#[derive(Debug, Clone)]
+pub struct BrokerCashActivity {
+    pub activity_id: String,
+    pub account_id: String,
+    pub currency: String,
+    pub date: NaiveDate,
+    pub kind: String,
+    pub amount: Money,
+    pub reference: Option<String>,
+}
+
+// How far a broker cash line may differ from our ledger and still match. Brokers
+// book trade cash on settlement, so the date window usually spans the cycle.
+#[derive(Debug, Clone)]
+pub struct CashMatchTolerance {
+    pub amount: Money,
+    pub days: i64,
+}
+
+// Broker line paired with one of our movements but with a different amount
+#[derive(Debug, Clone)]
+pub struct CashBreak {
+    pub activity_id: String,
+    pub movement: CashMovement,
+    pub broker_amount: Money,
+    pub age_days: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CashReconciliation {
+    pub account_id: String,
+    pub as_of_date: NaiveDate,
+    pub matched: Vec<(String, CashMovement)>,
+    pub breaks: Vec<CashBreak>,
+    // Ledger movements the broker does not show, aged since their date
+    pub unmatched_movements: Vec<(CashMovement, i64)>,
+    // Broker lines we have no movement for, aged since their date
+    pub unmatched_activity: Vec<(BrokerCashActivity, i64)>,
+    // Ledger balance less broker balance per currency, over everything up to the as-of date
+    pub balance_differences: BTreeMap<String, Money>,
+}
+
+// Aging buckets of unreconciled cash items, by days outstanding
+pub const CASH_AGING_BUCKETS: [(&str, i64); 4] = [("0-2 days", 2), ("3-5 days", 5), ("6-30 days", 30), ("over 30 days", i64::MAX)];
+
+impl CashReconciliation {
+    // Count and absolute amount of open items (breaks and unmatched on either side) per bucket
+    pub fn aging(&self) -> Vec<(&'static str, usize, Money)> {
+        let mut buckets: Vec<(&'static str, usize, Money)> = CASH_AGING_BUCKETS.iter().map(|(label, _)| (*label, 0, Decimal::ZERO)).collect();
+        let items = self.breaks.iter().map(|item| (item.age_days, (item.movement.amount - item.broker_amount).abs()))
+            .chain(self.unmatched_movements.iter().map(|(movement, age_days)| (*age_days, movement.amount.abs())))
+            .chain(self.unmatched_activity.iter().map(|(activity, age_days)| (*age_days, activity.amount.abs())));
+        for (age_days, amount) in items {
+            let bucket = CASH_AGING_BUCKETS.iter().position(|(_, limit)| age_days <= *limit).unwrap_or(CASH_AGING_BUCKETS.len() - 1);
+            buckets[bucket].1 += 1;
+            buckets[bucket].2 += amount;
+        }
+        buckets
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CashMovement {
+    pub date: NaiveDate,
+    pub account_id: Option<String>,
+    pub kind: CashMovementKind,
+    pub amount: Money,
+}
+
+// Cash in one currency: every movement in date order and the resulting balance
+#[derive(Debug, Clone)]
+pub struct CashAccount {
+    pub currency: String,
+    pub balance: Money,
+    pub movements: Vec<CashMovement>,
+}
+
+impl CashAccount {
+    pub fn new(currency: &str) -> CashAccount {
+        CashAccount { currency: currency.to_string(), balance: Decimal::ZERO, movements: Vec::new() }
+    }
+
+    pub fn debits(&self) -> Money {
+        self.movements.iter().filter(|movement| movement.amount.is_negative()).map(|movement| movement.amount).sum()
+    }
+
+    pub fn credits(&self) -> Money {
+        self.movements.iter().filter(|movement| !movement.amount.is_negative()).map(|movement| movement.amount).sum()
+    }
+}
+
+// Cash plus the market value of positions per currency, and their total in the
+// firm currency
+#[derive(Debug, Clone)]
+pub struct PortfolioEquity {
+    pub as_of_date: NaiveDate,
+    pub firm_currency: String,
+    pub cash: BTreeMap<String, Money>,
+    pub market_value: BTreeMap<String, Money>,
+    pub total: Money,
+}
+
+// End-of-day sweep of an account's idle cash into a money-market fund. Cash above
+// the target balance is invested; a shortfall is redeemed from the fund.
+#[derive(Debug, Clone)]
+pub struct SweepRule {
+    pub account_id: String,
+    pub currency: String,
+    // Fund units are bought and sold at a stable NAV of 1
+    pub mmf_instrument: String,
+    pub target_balance: i32,
+    // Accrued daily on the units held at each day's close
+    pub annual_yield: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SweepEvent {
+    pub date: NaiveDate,
+    pub account_id: String,
+    pub mmf_instrument: String,
+    // Positive into the fund, negative redeemed from it
+    pub amount: i32,
+    pub cash_trade_id: TradeId,
+    pub fund_trade_id: TradeId,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IncomeSource {
+    SweepInterest,
+    Dividend,
+}
+
+#[derive(Debug, Clone)]
+pub struct IncomeRow {
+    pub account_id: String,
+    pub instrument: String,
+    pub source: IncomeSource,
+    // Units held: averaged over the period for sweeps, at the ex-date for dividends
+    pub average_balance: Money,
+    // Net of withholding
+    pub amount: Money,
+    pub withheld: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct IncomeReport {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub rows: Vec<IncomeRow>,
+    pub total: Money,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RollupLevel {
+    Firm,
+    Book,
+    Account,
+    Strategy,
+    Instrument,
+}
+
+// One node of the firm -> book -> account -> strategy -> instrument P&L tree.
+// Amounts are in the firm currency and every node is the sum of its children.
+#[derive(Debug, Clone)]
+pub struct PnlNode {
+    pub level: RollupLevel,
+    pub name: String,
+    // Instrument nodes only
+    pub quantity: Option<i32>,
+    pub realized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub long_exposure: Money,
+    pub short_exposure: Money,
+    pub children: Vec<PnlNode>,
+}
+
+impl PnlNode {
+    pub fn rollup(level: RollupLevel, name: &str, children: Vec<PnlNode>) -> PnlNode {
+        PnlNode {
+            level,
+            name: name.to_string(),
+            quantity: None,
+            realized_pnl: children.iter().map(|child| child.realized_pnl).sum(),
+            unrealized_pnl: children.iter().map(|child| child.unrealized_pnl).sum(),
+            long_exposure: children.iter().map(|child| child.long_exposure).sum(),
+            short_exposure: children.iter().map(|child| child.short_exposure).sum(),
+            children,
+        }
+    }
+
+    pub fn total_pnl(&self) -> Money {
+        self.realized_pnl + self.unrealized_pnl
+    }
+
+    pub fn net_exposure(&self) -> Money {
+        self.long_exposure - self.short_exposure
+    }
+
+    pub fn gross_exposure(&self) -> Money {
+        self.long_exposure + self.short_exposure
+    }
+
+    // Follow child names down the tree, e.g. ["Equities", "ACC-EQ1", "momentum"]
+    pub fn drill_down(&self, path: &[&str]) -> Option<&PnlNode> {
+        match path.split_first() {
+            None => Some(self),
+            Some((name, rest)) => self.children.iter().find(|child| child.name == *name)?.drill_down(rest),
+        }
+    }
+}
+
+pub fn print_pnl_node(node: &PnlNode, depth: usize) {
+    println!("{}{:?} {}: P&L ${:.2} (realized ${:.2}, unrealized ${:.2}) | net ${:.2}, gross ${:.2}{}",
+        "  ".repeat(depth),
+        node.level,
+        node.name,
+        node.total_pnl(),
+        node.realized_pnl,
+        node.unrealized_pnl,
+        node.net_exposure(),
+        node.gross_exposure(),
+        node.quantity.map_or(String::new(), |quantity| format!(" | {} shares", quantity)),
+    );
+    for child in &node.children {
+        print_pnl_node(child, depth + 1);
+    }
+}
+
+// Day-over-day P&L change of one instrument split into its sources, in the firm
+// currency. previous_pnl is realized plus unrealized at the prior close as reported
+// that day, i.e. before the amendments booked on the explained date.
+#[derive(Debug, Clone, Default)]
+pub struct PnlExplainLine {
+    pub instrument: String,
+    pub previous_pnl: Money,
+    pub pnl: Money,
+    // Today's trades marked from their price to today's close
+    pub new_trades: Money,
+    // Positions held at the prior close marked to today's close
+    pub price_move: Money,
+    pub fees: Money,
+    // Effect of amending or cancelling earlier trades, at the prior close
+    pub amendments: Money,
+    // Prior P&L revalued at today's FX rate
+    pub fx: Money,
+    pub unexplained: Money,
+}
+
+impl PnlExplainLine {
+    pub fn change(&self) -> Money {
+        self.pnl - self.previous_pnl
+    }
+
+    pub fn add(&mut self, other: &PnlExplainLine) {
+        self.previous_pnl += other.previous_pnl;
+        self.pnl += other.pnl;
+        self.new_trades += other.new_trades;
+        self.price_move += other.price_move;
+        self.fees += other.fees;
+        self.amendments += other.amendments;
+        self.fx += other.fx;
+        self.unexplained += other.unexplained;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PnlExplain {
+    pub date: NaiveDate,
+    pub lines: Vec<PnlExplainLine>,
+}
+
+impl PnlExplain {
+    pub fn total(&self) -> PnlExplainLine {
+        let mut total = PnlExplainLine { instrument: "Total".to_string(), ..PnlExplainLine::default() };
+        for line in &self.lines {
+            total.add(line);
+        }
+        total
+    }
+}
+
+pub fn print_pnl_explain(explain: &PnlExplain) {
+    println!("\n=== P&L Explain for {} ===", explain.date);
+    println!("{:<10} {:>12} {:>12} {:>12} {:>12} {:>10} {:>12} {:>10} {:>12}",
+             "Instrument", "Change", "New Trades", "Price Move", "Fees", "FX", "Amendments", "Other", "P&L");
+    for line in explain.lines.iter().chain(std::iter::once(&explain.total())) {
+        println!("{:<10} {:>12.2} {:>12.2} {:>12.2} {:>12.2} {:>10.2} {:>12.2} {:>10.2} {:>12.2}",
+                 line.instrument, line.change(), line.new_trades, line.price_move, line.fees, line.fx, line.amendments, line.unexplained, line.pnl);
+    }
+}
+
+// An instrument's position at each level of the firm -> book -> account hierarchy
+#[derive(Debug, Clone)]
+pub struct PositionBreakdown {
+    pub instrument: String,
+    pub as_of_date: NaiveDate,
+    pub firm: TradePosition,
+    pub books: BTreeMap<String, TradePosition>,
+    pub accounts: BTreeMap<String, TradePosition>,
+}
+
+// P&L impact of recomputing an instrument after an instrument master correction.
+// Firm-currency figures are None when there is no FX rate for the currency.
+#[derive(Debug, Clone)]
+pub struct Restatement {
+    pub instrument: String,
+    pub as_of_date: NaiveDate,
+    pub previous_currency: String,
+    pub currency: String,
+    // Trades whose price or fees changed under the corrected booking rules
+    pub trades_restated: Vec<TradeId>,
+    pub days_restated: usize,
+    pub previous_quantity: i32,
+    pub quantity: i32,
+    pub previous_realized_pnl: Money,
+    pub realized_pnl: Money,
+    pub previous_unrealized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub previous_firm_pnl: Option<Money>,
+    pub firm_pnl: Option<Money>,
+}
+
+impl Restatement {
+    pub fn firm_pnl_impact(&self) -> Option<Money> {
+        Some(self.firm_pnl? - self.previous_firm_pnl?)
+    }
+}
+
+pub fn print_restatement(restatement: &Restatement) {
+    let firm = |pnl: Option<Money>| pnl.map_or("n/a".to_string(), |pnl| format!("{:.2}", pnl));
+    println!("\n=== Restatement of {} as of {} ===", restatement.instrument, restatement.as_of_date);
+    println!("Currency: {} -> {}", restatement.previous_currency, restatement.currency);
+    println!("Trades restated: {:?}, daily snapshots restated: {}", restatement.trades_restated, restatement.days_restated);
+    println!("Quantity: {} -> {}", restatement.previous_quantity, restatement.quantity);
+    println!("Realized P&L: {:.2} -> {:.2}", restatement.previous_realized_pnl, restatement.realized_pnl);
+    println!("Unrealized P&L: {:.2} -> {:.2}", restatement.previous_unrealized_pnl, restatement.unrealized_pnl);
+    println!("Firm P&L: {} -> {} (impact {})", firm(restatement.previous_firm_pnl), firm(restatement.firm_pnl), firm(restatement.firm_pnl_impact()));
+}
+
+// One day's position and P&L for one instrument before and after a restatement,
+// with unrealized P&L at that day's close
+#[derive(Debug, Clone)]
+pub struct RestatedDay {
+    pub date: NaiveDate,
+    pub instrument: String,
+    pub previous_quantity: i32,
+    pub quantity: i32,
+    pub previous_realized_pnl: Money,
+    pub realized_pnl: Money,
+    pub previous_unrealized_pnl: Money,
+    pub unrealized_pnl: Money,
+}
+
+impl RestatedDay {
+    pub fn previous_pnl(&self) -> Money {
+        self.previous_realized_pnl + self.previous_unrealized_pnl
+    }
+
+    pub fn pnl(&self) -> Money {
+        self.realized_pnl + self.unrealized_pnl
+    }
+
+    pub fn pnl_delta(&self) -> Money {
+        self.pnl() - self.previous_pnl()
+    }
+}
+
+// Recomputation of every daily snapshot in a date range after a methodology or data
+// fix. Only days whose quantity or P&L changed are kept; the prior values stay on
+// each row so the report can be signed off.
+#[derive(Debug, Clone)]
+pub struct RangeRestatement {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub reason: String,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: String,
+    // Trades in the range whose price or fees changed under the current booking rules
+    pub trades_restated: Vec<TradeId>,
+    pub days: Vec<RestatedDay>,
+}
+
+impl RangeRestatement {
+    pub fn total_pnl_delta(&self) -> Money {
+        self.days.iter().map(|day| day.pnl_delta()).sum()
+    }
+
+    // Net P&L change per date across instruments
+    pub fn delta_by_date(&self) -> BTreeMap<NaiveDate, Money> {
+        let mut deltas = BTreeMap::new();
+        for day in &self.days {
+            *deltas.entry(day.date).or_insert(Decimal::ZERO) += day.pnl_delta();
+        }
+        deltas
+    }
+}
+
+pub fn print_range_restatement(restatement: &RangeRestatement) {
+    println!("\n=== Restatement {} to {} ===", restatement.start_date, restatement.end_date);
+    println!("Reason: {} (by {} at {})", restatement.reason, restatement.actor, restatement.recorded_at.format("%Y-%m-%d %H:%M:%S"));
+    println!("Trades restated: {:?}", restatement.trades_restated);
+    println!("{:<12} {:<10} {:>10} {:>10} {:>14} {:>14} {:>12}", "Date", "Instrument", "Prev Qty", "Qty", "Prev P&L", "P&L", "Delta");
+    for day in &restatement.days {
+        println!(
+            "{:<12} {:<10} {:>10} {:>10} {:>14.2} {:>14.2} {:>12.2}",
+            day.date, day.instrument, day.previous_quantity, day.quantity, day.previous_pnl(), day.pnl(), day.pnl_delta()
+        );
+    }
+    for (date, delta) in restatement.delta_by_date() {
+        println!("{}: net change {:.2}", date, delta);
+    }
+    println!("Total P&L change: {:.2} over {} instrument-days", restatement.total_pnl_delta(), restatement.days.len());
+}
+
+// What a P&L subscription watches: one instrument or every position, unrealized
+// P&L levels to report crossings of, and a relative change since the last
+// notification (0.1 for 10%) that is reported on its own
+#[derive(Debug, Clone)]
+pub struct PnlWatch {
+    pub instrument: Option<String>,
+    pub thresholds: Vec<Money>,
+    pub change_fraction: Option<f64>,
+}
+
+impl PnlWatch {
+    pub fn all() -> Self {
+        PnlWatch { instrument: None, thresholds: Vec::new(), change_fraction: None }
+    }
+
+    pub fn instrument(instrument: &str) -> Self {
+        PnlWatch { instrument: Some(instrument.to_string()), ..PnlWatch::all() }
+    }
+
+    pub fn threshold(mut self, level: impl Into<Money>) -> Self {
+        self.thresholds.push(level.into());
+        self
+    }
+
+    pub fn change_fraction(mut self, fraction: f64) -> Self {
+        self.change_fraction = Some(fraction);
+        self
+    }
+
+    pub fn covers(&self, instrument: &str) -> bool {
+        self.instrument.as_deref().is_none_or(|watched| watched == instrument)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PnlTrigger {
+    Crossed { threshold: Money, upward: bool },
+    Changed { fraction: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct PnlNotification {
+    pub instrument: String,
+    pub previous_unrealized_pnl: Money,
+    pub unrealized_pnl: Money,
+    pub trigger: PnlTrigger,
+    pub raised_at: DateTime<Utc>,
+}
+
+// A subscriber's channel with the unrealized P&L it last saw per instrument, and the
+// level changes are measured from (reset each time a change is reported)
+#[derive(Debug, Clone)]
+pub struct PnlSubscription {
+    pub watch: PnlWatch,
+    pub sender: std::sync::mpsc::Sender<PnlNotification>,
+    pub last_seen: HashMap<String, Money>,
+    pub change_base: HashMap<String, Money>,
+}
+
+impl PnlSubscription {
+    pub fn triggers(&mut self, instrument: &str, unrealized: Money) -> Vec<(Money, PnlTrigger)> {
+        let previous = self.last_seen.insert(instrument.to_string(), unrealized).unwrap_or(Decimal::ZERO);
+        let mut triggers: Vec<(Money, PnlTrigger)> = self.watch.thresholds
+            .iter()
+            .filter(|threshold| (previous < **threshold) != (unrealized < **threshold))
+            .map(|threshold| (previous, PnlTrigger::Crossed { threshold: *threshold, upward: unrealized > previous }))
+            .collect();
+        if let Some(fraction) = self.watch.change_fraction {
+            let base = *self.change_base.entry(instrument.to_string()).or_insert(previous);
+            let change = if base.is_zero() {
+                if unrealized.is_zero() { 0.0 } else { f64::INFINITY }
+            } else {
+                ((unrealized - base) / base.abs()).to_f64()
+            };
+            if change.abs() > fraction {
+                self.change_base.insert(instrument.to_string(), unrealized);
+                triggers.push((base, PnlTrigger::Changed { fraction: change }));
+            }
+        }
+        triggers
+    }
+}
+
+// Positions a stream follows: the firm's, or one account's, in every instrument or one
+#[derive(Debug, Clone, Default)]
+pub struct StreamFilter {
+    pub instrument: Option<String>,
+    pub account_id: Option<String>,
+}
+
+impl StreamFilter {
+    pub fn new() -> Self {
+        StreamFilter::default()
+    }
+
+    pub fn instrument(mut self, instrument: &str) -> Self {
+        self.instrument = Some(instrument.to_string());
+        self
+    }
+
+    pub fn account(mut self, account_id: &str) -> Self {
+        self.account_id = Some(account_id.to_string());
+        self
+    }
+
+    pub fn covers(&self, instrument: &str) -> bool {
+        self.instrument.as_deref().is_none_or(|followed| followed == instrument)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PositionUpdate {
+    // Quantity changed by a trade, amendment or cancellation
+    Position { account_id: Option<String>, instrument: String, previous_quantity: i32, quantity: i32, average_price: Price },
+    // Realized or unrealized P&L moved, on a trade or a new price
+    Pnl { account_id: Option<String>, instrument: String, price: Price, realized_pnl: Money, unrealized_pnl: Money },
+}
+
+impl PositionUpdate {
+    pub fn instrument(&self) -> &str {
+        match self {
+            PositionUpdate::Position { instrument, .. } | PositionUpdate::Pnl { instrument, .. } => instrument,
+        }
+    }
+}
+
+// Updates a stream can hold unread. A subscriber that falls further behind has its
+// stream ended rather than the backlog kept.
+pub const POSITION_STREAM_BUFFER: usize = 4096;
+
+// A stream's channel with the quantity and P&L it last sent per instrument, so
+// only changes go out
+#[derive(Debug, Clone)]
+pub struct PositionStream {
+    pub filter: StreamFilter,
+    pub sender: std::sync::mpsc::SyncSender<PositionUpdate>,
+    pub last_quantity: HashMap<String, i32>,
+    pub last_pnl: HashMap<String, (Money, Money)>,
+}
+
+impl PositionStream {
+    pub fn updates(&mut self, position: &TradePosition, price: Option<Price>) -> Vec<PositionUpdate> {
+        let instrument = &position.instrument;
+        let account_id = self.filter.account_id.clone();
+        let mut updates = Vec::new();
+        let previous_quantity = self.last_quantity.insert(instrument.clone(), position.quantity).unwrap_or(0);
+        if previous_quantity != position.quantity {
+            updates.push(PositionUpdate::Position {
+                account_id: account_id.clone(),
+                instrument: instrument.clone(),
+                previous_quantity,
+                quantity: position.quantity,
+                average_price: position.average_price,
+            });
+        }
+        // Without a price there is no unrealized P&L to report yet
+        if let Some(price) = price {
+            let pnl = (position.realized_pnl, position.unrealized_pnl(price));
+            if self.last_pnl.insert(instrument.clone(), pnl) != Some(pnl) {
+                updates.push(PositionUpdate::Pnl { account_id, instrument: instrument.clone(), price, realized_pnl: pnl.0, unrealized_pnl: pnl.1 });
+            }
+        }
+        updates
+    }
+}
+
+// Outcome of add_trades_batch for each trade, in the order the trades were given
+#[derive(Debug, Clone)]
+pub struct BatchReport {
+    pub results: Vec<(TradeId, Result<(), PositionError>)>,
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeRepository {
+    pub trades: TradeStore,
+    // Market data for P&L calculations
+    pub positions: BTreeMap<String, TradePosition>,
+    pub market_prices: HashMap<String, Price>,
+    // Latest order book per instrument and how marks are derived from it
+    pub book_snapshots: HashMap<String, BookSnapshot>,
+    pub mark_methods: HashMap<String, MarkMethod>,
+    pub default_mark_method: MarkMethod,
+    // Manual marks per instrument; feed prices keep updating underneath them
+    pub mark_overrides: HashMap<String, Vec<MarkOverride>>,
+    // End-of-day position per instrument, keyed by the dates the instrument traded or settled
+    pub daily_positions: BTreeMap<String, BTreeMap<NaiveDate, TradePosition>>,
+    // Chronological key of the latest trade folded into each instrument's daily positions
+    pub daily_position_marks: HashMap<String, (DateTime<Utc>, TradeId)>,
+    pub fold_checkpoints: HashMap<String, FoldCheckpoints>,
+    // Futures/options with an expiry date, keyed by instrument
+    pub contracts: HashMap<String, DerivativeContract>,
+    // Futures settlements and income credits per instrument and date, replayed on rebuilds
+    pub position_adjustments: HashMap<String, BTreeMap<NaiveDate, Vec<PositionAdjustment>>>,
+    pub margin_rules: MarginRules,
+    // Variation margin called on each settlement date
+    pub variation_margin: BTreeMap<NaiveDate, VariationMarginReport>,
+    // Calendar each future settles on; weekdays for those without one
+    pub settlement_calendars: HashMap<String, SettlementCalendar>,
+    pub instruments: HashMap<String, Instrument>,
+    // Refuse trades in symbols without an instrument record
+    pub require_registered_instruments: bool,
+    pub event_log: Vec<LifecycleEvent>,
+    // Daily closing prices per instrument
+    pub close_prices: TimeSeriesStore<Price>,
+    // Closes as they stood before being overwritten, kept until a restatement covers
+    // their date so it can show the P&L they produced
+    pub superseded_closes: TimeSeriesStore<Price>,
+    pub restatements: Vec<RangeRestatement>,
+    // Channels notified when unrealized P&L crosses a level or moves by a fraction
+    pub pnl_subscriptions: Vec<PnlSubscription>,
+    // Channels sent every position and P&L change of the positions they follow
+    pub position_streams: Vec<PositionStream>,
+    pub pairs: HashMap<String, PairDefinition>,
+    pub firm_currency: String,
+    // Base currency per account and quote currency per instrument; both default to the firm currency
+    pub account_currencies: HashMap<String, String>,
+    pub account_books: HashMap<String, String>,
+    // Custodian holding each account's securities and cash
+    pub account_custodians: HashMap<String, String>,
+    pub instrument_currencies: HashMap<String, String>,
+    // Currency each instrument's figures were last computed in, to restate from
+    pub booked_currencies: HashMap<String, String>,
+    pub instrument_sectors: HashMap<String, String>,
+    // Free-form labels per instrument, e.g. "region" -> "EMEA", for aggregation
+    pub instrument_tags: HashMap<String, BTreeMap<String, String>>,
+    // Exchange sessions by name and the session each instrument trades in. Instruments
+    // without one are treated as trading the whole UTC day, every day.
+    pub session_calendars: HashMap<String, SessionCalendar>,
+    pub instrument_sessions: HashMap<String, String>,
+    pub fx_rates: FxRateStore,
+    pub counterparties: HashMap<String, Counterparty>,
+    pub confirmations: Vec<Confirmation>,
+    pub quarantine: Vec<QuarantinedRecord>,
+    pub sequence_states: HashMap<String, SequenceState>,
+    // Ingested prices per instrument in arrival order, and recent latency samples
+    // (milliseconds) per source
+    pub price_ticks: HashMap<String, Vec<PriceTick>>,
+    pub source_latencies: BTreeMap<String, VecDeque<i64>>,
+    // Records buffered per source while waiting for a missing sequence number
+    pub reorder_window: usize,
+    // Reconnect attempts allowed per ingest run before the feed's error is returned
+    pub feed_reconnect_limit: usize,
+    pub average_daily_volumes: HashMap<String, f64>,
+    // Imported ADV by date; takes precedence over the flat figure above
+    pub adv_history: TimeSeriesStore<f64>,
+    // Per-instrument sanity bands, falling back to the default band when set
+    pub sanity_bands: HashMap<String, SanityBand>,
+    pub default_sanity_band: Option<SanityBand>,
+    pub sanity_flags: Vec<SanityBreach>,
+    pub sanity_overrides: Vec<SanityOverride>,
+    pub risk_limits: Option<RiskLimits>,
+    // Trades booked under flagging limits, with what they broke
+    pub limit_flags: Vec<(TradeId, LimitBreach)>,
+    // Trades held for approval, and the rejected ones, kept out of the book
+    pub held_trades: BTreeMap<TradeId, Trade>,
+    pub status_log: Vec<StatusTransition>,
+    // Internal crosses flagged for compliance review, and whether crossed quantity is
+    // left out of counterparty exposure and broker costs
+    pub internal_cross_flags: Vec<InternalCross>,
+    pub net_internal_crosses: bool,
+    // Applied to heavy queries started through query_control
+    pub default_query_timeout: Option<Duration>,
+    pub implied_volatilities: HashMap<String, f64>,
+    // Modified durations, in years, of rate-sensitive instruments for rate shocks
+    pub durations: HashMap<String, f64>,
+    // Market betas, exported to portfolio optimizers, and stock borrow rates (annual
+    // fractions) for short positions
+    pub betas: TimeSeriesStore<f64>,
+    pub borrow_rates: TimeSeriesStore<f64>,
+    // Index or ETF levels performance is compared against, by benchmark name
+    pub benchmark_prices: TimeSeriesStore<Price>,
+    // Quantity left on each order per its latest execution report
+    pub order_leaves: HashMap<OrderId, i32>,
+    pub risk_free_rate: f64,
+    pub audit_log: Vec<AuditEntry>,
+    // Recorded as the actor of every audit entry
+    pub actor: String,
+    // Applied to prices and commissions when trades are booked
+    pub rounding_policies: HashMap<String, RoundingPolicy>,
+    pub default_rounding_policy: RoundingPolicy,
+    pub fee_schedules: HashMap<String, FeeSchedule>,
+    pub default_fee_schedule: Option<FeeSchedule>,
+    // Tax markets by name and the market each instrument is taxed in
+    pub tax_markets: HashMap<String, TaxMarket>,
+    pub instrument_markets: HashMap<String, String>,
+    pub dividends: Vec<DividendPayment>,
+    pub interest_payments: Vec<InterestPayment>,
+    // Imported broker cash statements, reconciled against the cash ledger
+    pub broker_cash_activity: Vec<BrokerCashActivity>,
+    pub corporate_actions: Vec<CorporateActionResult>,
+    // Old symbol to current symbol; trades still booked under an old ticker are moved
+    pub renamed_symbols: HashMap<String, String>,
+    // Tax-lot method per instrument, and the lots named by SpecificLot closing trades
+    pub cost_basis_methods: HashMap<String, CostBasisMethod>,
+    pub default_cost_basis_method: CostBasisMethod,
+    pub lot_selections: HashMap<TradeId, Vec<TradeId>>,
+    pub sweep_rules: Vec<SweepRule>,
+    // Round trips compressed away, kept to trace old trade ids to their synthetic trades
+    pub compressions: Vec<Compression>,
+    // Every add, amend and cancel in order, mirrored to the journal file when one is attached
+    pub journal: Vec<TradeEvent>,
+    pub journal_file: Option<TradeJournal>,
+    pub storage: Option<SharedTradeStorage>,
+    // Journaled events the storage failed to apply, oldest first
+    pub storage_backlog: Vec<TradeEvent>,
+    // Trade id scheme; None numbers trades after the highest integer id
+    pub id_generator: Option<SharedIdGenerator>,
+    pub position_snapshots: Option<PositionSnapshotStore>,
+    // One per open transaction, innermost last. Events inside a transaction reach
+    // the file only when the outermost one commits.
+    pub savepoints: Vec<Savepoint>,
+}
+
+impl TradeRepository {
+    pub fn new() -> TradeRepository {
+        TradeRepository {
+            trades: TradeStore::new(),
+            positions: BTreeMap::new(),
+            market_prices: HashMap::new(),
+            book_snapshots: HashMap::new(),
+            mark_methods: HashMap::new(),
+            mark_overrides: HashMap::new(),
+            default_mark_method: MarkMethod::LastTrade,
+            daily_positions: BTreeMap::new(),
+            daily_position_marks: HashMap::new(),
+            fold_checkpoints: HashMap::new(),
+            contracts: HashMap::new(),
+            position_adjustments: HashMap::new(),
+            margin_rules: MarginRules::reg_t(),
+            variation_margin: BTreeMap::new(),
+            settlement_calendars: HashMap::new(),
+            instruments: HashMap::new(),
+            require_registered_instruments: false,
+            event_log: Vec::new(),
+            close_prices: TimeSeriesStore::new(),
+            superseded_closes: TimeSeriesStore::new(),
+            restatements: Vec::new(),
+            pnl_subscriptions: Vec::new(),
+            position_streams: Vec::new(),
+            pairs: HashMap::new(),
+            firm_currency: "USD".to_string(),
+            account_currencies: HashMap::new(),
+            account_books: HashMap::new(),
+            account_custodians: HashMap::new(),
+            instrument_currencies: HashMap::new(),
+            booked_currencies: HashMap::new(),
+            instrument_sectors: HashMap::new(),
+            instrument_tags: HashMap::new(),
+            session_calendars: HashMap::new(),
+            instrument_sessions: HashMap::new(),
+            fx_rates: FxRateStore::new("USD", FxFallback::PreviousBusinessDay),
+            counterparties: HashMap::new(),
+            confirmations: Vec::new(),
+            quarantine: Vec::new(),
+            sequence_states: HashMap::new(),
+            price_ticks: HashMap::new(),
+            source_latencies: BTreeMap::new(),
+            reorder_window: 100,
+            feed_reconnect_limit: 5,
+            average_daily_volumes: HashMap::new(),
+            adv_history: TimeSeriesStore::new(),
+            sanity_bands: HashMap::new(),
+            default_sanity_band: None,
+            sanity_flags: Vec::new(),
+            sanity_overrides: Vec::new(),
+            risk_limits: None,
+            limit_flags: Vec::new(),
+            held_trades: BTreeMap::new(),
+            status_log: Vec::new(),
+            internal_cross_flags: Vec::new(),
+            net_internal_crosses: false,
+            default_query_timeout: None,
+            implied_volatilities: HashMap::new(),
+            durations: HashMap::new(),
+            betas: TimeSeriesStore::new(),
+            borrow_rates: TimeSeriesStore::new(),
+            benchmark_prices: TimeSeriesStore::new(),
+            order_leaves: HashMap::new(),
+            risk_free_rate: 0.0,
+            audit_log: Vec::new(),
+            actor: "system".to_string(),
+            rounding_policies: HashMap::new(),
+            default_rounding_policy: RoundingPolicy::default(),
+            fee_schedules: HashMap::new(),
+            default_fee_schedule: None,
+            tax_markets: HashMap::new(),
+            instrument_markets: HashMap::new(),
+            dividends: Vec::new(),
+            interest_payments: Vec::new(),
+            broker_cash_activity: Vec::new(),
+            corporate_actions: Vec::new(),
+            renamed_symbols: HashMap::new(),
+            cost_basis_methods: HashMap::new(),
+            default_cost_basis_method: CostBasisMethod::Fifo,
+            lot_selections: HashMap::new(),
+            sweep_rules: Vec::new(),
+            compressions: Vec::new(),
+            journal: Vec::new(),
+            journal_file: None,
+            storage: None,
+            storage_backlog: Vec::new(),
+            id_generator: None,
+            position_snapshots: None,
+            savepoints: Vec::new(),
+        }
+    }
+
+    pub fn set_rounding_policy(&mut self, instrument: &str, policy: RoundingPolicy) {
+        self.rounding_policies.insert(instrument.to_string(), policy);
+    }
+
+    pub fn set_default_rounding_policy(&mut self, policy: RoundingPolicy) {
+        self.default_rounding_policy = policy;
+    }
+
+    pub fn rounding_policy(&self, instrument: &str) -> RoundingPolicy {
+        self.rounding_policies.get(instrument).copied().unwrap_or(self.default_rounding_policy)
+    }
+
+    pub fn set_fee_schedule(&mut self, instrument: &str, schedule: FeeSchedule) {
+        self.fee_schedules.insert(instrument.to_string(), schedule);
+    }
+
+    pub fn set_default_fee_schedule(&mut self, schedule: FeeSchedule) {
+        self.default_fee_schedule = Some(schedule);
+    }
+
+    // Cash movements and sweeps are free unless the instrument has its own schedule
+    pub fn fee_schedule(&self, instrument: &str) -> Option<&FeeSchedule> {
+        let exempt = is_cash_instrument(instrument) || self.sweep_rules.iter().any(|rule| rule.mmf_instrument == instrument);
+        match self.fee_schedules.get(instrument) {
+            Some(schedule) => Some(schedule),
+            None if exempt => None,
+            None => self.default_fee_schedule.as_ref(),
+        }
+    }
+
+    pub fn add_tax_market(&mut self, market: TaxMarket) {
+        self.tax_markets.insert(market.name.clone(), market);
+    }
+
+    pub fn set_instrument_market(&mut self, instrument: &str, market: &str) {
+        self.instrument_markets.insert(instrument.to_string(), market.to_string());
+    }
+
+    pub fn tax_market(&self, instrument: &str) -> Option<&TaxMarket> {
+        self.tax_markets.get(self.instrument_markets.get(instrument)?)
+    }
+
+    // Scheduled fees and market taxes, then rounding of the price and every fee to the instrument's policy
+    pub fn apply_booking_rules(&self, trade: &mut Trade) {
+        let instrument = trade.instrument.as_str();
+        let (policy, schedule, market) = (self.rounding_policy(instrument), self.fee_schedule(instrument), self.tax_market(instrument));
+        apply_booking_rules_with(policy, schedule, market, self.contract_multiplier(instrument), trade);
+    }
+
+    pub fn add_trade(&mut self, mut trade: Trade) -> Result<(), PositionError> {
+        if self.trades.contains_key(&trade.trade_id) {
+            return Err(PositionError::DuplicateTradeId(trade.trade_id));
+        }
+        if let Some(renamed) = self.renamed_symbols.get(&trade.instrument) {
+            trade.instrument = renamed.clone();
+        }
+        validate_terms(&trade.instrument, trade.quantity, trade.price)?;
+        self.apply_booking_rules(&mut trade);
+        self.check_instrument(&trade.instrument, trade.quantity, trade.price)?;
+        let breaches = self.check_risk_limits(&trade.instrument, &trade.side, trade.quantity, trade.price);
+        if !breaches.is_empty() && self.limits_block() {
+            return Err(PositionError::LimitBreached { instrument: trade.instrument, breaches });
+        }
+        let trade_id = trade.trade_id;
+        self.book_trade(trade)?;
+        self.limit_flags.extend(breaches.into_iter().map(|breach| (trade_id, breach)));
+        Ok(())
+    }
+
+    // Book many trades in one pass. Each trade is checked like add_trade, with risk
+    // limits counting the trades accepted ahead of it, and rejected on its own. The
+    // accepted ones are grouped by instrument, so booking rules are
+    // looked up, the position updated and the daily table extended once per
+    // instrument, and journaled together. Only a failure to journal the batch fails
+    // the call, and then nothing is booked.
+    pub fn add_trades_batch(&mut self, trades: Vec<Trade>) -> Result<BatchReport, PositionError> {
+        let mut results = Vec::with_capacity(trades.len());
+        let mut batch_ids = HashSet::with_capacity(trades.len());
+        let mut by_instrument: BTreeMap<String, Vec<Trade>> = BTreeMap::new();
+        let mut pending: BTreeMap<String, (i32, Price)> = BTreeMap::new();
+        let mut flagged = Vec::new();
+        for mut trade in trades {
+            if let Some(renamed) = self.renamed_symbols.get(&trade.instrument) {
+                trade.instrument = renamed.clone();
+            }
+            let trade_id = trade.trade_id;
+            let checked = if self.trades.contains_key(&trade_id) || !batch_ids.insert(trade_id) {
+                Err(PositionError::DuplicateTradeId(trade_id))
+            } else {
+                validate_terms(&trade.instrument, trade.quantity, trade.price)
+            };
+            let checked = checked
+                .and_then(|_| self.check_instrument(&trade.instrument, trade.quantity, self.rounding_policy(&trade.instrument).round_price(trade.price)))
+                .and_then(|_| self.booking_transition(&trade).map(|_| ()))
+                .and_then(|_| {
+                    let breaches = self.risk_limit_breaches(&trade.instrument, &trade.side, trade.quantity, trade.price, &pending);
+                    if !breaches.is_empty() && self.limits_block() {
+                        return Err(PositionError::LimitBreached { instrument: trade.instrument.clone(), breaches });
+                    }
+                    flagged.extend(breaches.into_iter().map(|breach| (trade_id, breach)));
+                    Ok(())
+                });
+            if checked.is_ok() {
+                let signed = match trade.side {
+                    Side::Buy => trade.quantity,
+                    Side::Sell => -trade.quantity,
+                };
+                let entry = pending.entry(trade.instrument.clone()).or_insert((0, trade.price));
+                *entry = (entry.0 + signed, trade.price);
+                match by_instrument.get_mut(&trade.instrument) {
+                    Some(group) => group.push(trade),
+                    None => {
+                        by_instrument.insert(trade.instrument.clone(), vec![trade]);
+                    }
+                }
+            }
+            results.push((trade_id, checked));
+        }
+        for (instrument, trades) in by_instrument.iter_mut() {
+            let (policy, schedule, market) = (self.rounding_policy(instrument), self.fee_schedule(instrument), self.tax_market(instrument));
+            let multiplier = self.contract_multiplier(instrument);
+            for trade in trades.iter_mut() {
+                apply_booking_rules_with(policy, schedule, market, multiplier, trade);
+            }
+            trades.sort_by_key(|trade| trade.chronological_key());
+        }
+        let booked_from: Vec<(TradeId, TradeStatus)> = by_instrument.values_mut().flatten()
+            .map(|trade| (trade.trade_id, std::mem::replace(&mut trade.status, TradeStatus::Active)))
+            .collect();
+
+        let events: Vec<TradeEvent> = by_instrument.values().flatten().cloned().map(TradeEvent::Added).collect();
+        if self.savepoints.is_empty() {
+            self.persist(&events)?;
+        }
+        self.journal.extend(events);
+        self.limit_flags.extend(flagged);
+        for (trade_id, from) in booked_from {
+            if !matches!(from, TradeStatus::Active) {
+                self.log_transition(trade_id, from, TradeStatus::Active, None);
+            }
+        }
+
+        let recorded_at = Utc::now();
+        let booked_instruments: Vec<String> = by_instrument.keys().cloned().collect();
+        for (instrument, trades) in by_instrument {
+            if !self.booked_currencies.contains_key(&instrument) {
+                let currency = self.instrument_currency(&instrument).to_string();
+                self.booked_currencies.insert(instrument.clone(), currency);
+            }
+            for trade in &trades {
+                self.save_for_rollback(trade.trade_id, &[&instrument]);
+            }
+            let position = self.positions.entry(instrument.clone()).or_insert_with(|| TradePosition::new(instrument.clone()));
+            for trade in &trades {
+                position.update_position(trade);
+            }
+            self.trades.insert_new(&instrument, &trades);
+
+            // Trades after everything already folded in extend the daily table, one
+            // entry per date; anything earlier rebuilds it from the first date touched
+            let first = &trades[0];
+            let in_order = self.daily_position_marks.get(&instrument).is_none_or(|mark| first.chronological_key() > *mark);
+            if in_order {
+                if let Some(snapshots) = &mut self.position_snapshots {
+                    snapshots.invalidate_from(first.trade_date);
+                }
+                let days = self.daily_positions.entry(instrument.clone()).or_default();
+                let checkpoints = self.fold_checkpoints.entry(instrument.clone()).or_default();
+                let mut position = days.values().next_back().cloned().unwrap_or_else(|| TradePosition::new(instrument.clone()));
+                let mark = self.daily_position_marks.get(&instrument).copied();
+                if let Some(mark) = fold_into_days(&mut position, days, checkpoints, &mut std::iter::empty().peekable(), None, mark, &trades) {
+                    self.daily_position_marks.insert(instrument.clone(), mark);
+                }
+            } else {
+                self.rebuild_position(&instrument, first.trade_date);
+            }
+
+            let first_sequence = self.audit_log.last().map_or(0, |entry| entry.sequence) + 1;
+            self.audit_log.reserve(trades.len());
+            for (trade, sequence) in trades.into_iter().zip(first_sequence..) {
+                self.audit_log.push(AuditEntry {
+                    sequence,
+                    recorded_at,
+                    actor: self.actor.clone(),
+                    action: AuditAction::Add,
+                    trade_id: trade.trade_id,
+                    before: None,
+                    after: Some(trade),
+                });
+            }
+        }
+        // Subscribers hear about each instrument once, with the whole batch folded in
+        for instrument in &booked_instruments {
+            self.notify_pnl(instrument);
+        }
+
+        let accepted = results.iter().filter(|(_, result)| result.is_ok()).count();
+        Ok(BatchReport {
+            rejected: results.len() - accepted,
+            accepted,
+            results,
+        })
+    }
+
+    // Store a validated trade and fold it into the positions. New and approved trades
+    // become Active; trades replayed from a journal or storage are already Active.
+    pub fn book_trade(&mut self, mut trade: Trade) -> Result<(), PositionError> {
+        let booked_from = self.booking_transition(&trade)?;
+        trade.status = TradeStatus::Active;
+        self.record_event(TradeEvent::Added(trade.clone()))?;
+        if let Some(from) = booked_from {
+            self.log_transition(trade.trade_id, from, TradeStatus::Active, None);
+        }
+        let instrument = trade.instrument.clone();
+        if !self.booked_currencies.contains_key(&instrument) {
+            let currency = self.instrument_currency(&instrument).to_string();
+            self.booked_currencies.insert(instrument.clone(), currency);
+        }
+        self.save_for_rollback(trade.trade_id, &[&instrument]);
+        self.trades.insert(trade.trade_id, trade.clone());
+        
+        if !self.positions.contains_key(&instrument) {
+            self.positions.insert(instrument.clone(), TradePosition::new(instrument.clone()));
+        }
+        self.positions.get_mut(&instrument).unwrap().update_position(&trade);
+        self.record_daily_position(&trade);
+        self.record_audit(AuditAction::Add, trade.trade_id, None, Some(trade));
+        self.notify_pnl(&instrument);
+        Ok(())
+    }
+
+    pub fn attach_journal(&mut self, journal: TradeJournal) {
+        self.journal_file = Some(journal);
+    }
+
+    // Write trades through to the storage from now on. Trades already in the
+    // repository are not copied; attach storage to an empty repository or one loaded from it.
+    pub fn attach_storage(&mut self, storage: SharedTradeStorage) {
+        self.storage = Some(storage);
+    }
+
+    // Rebuild a repository from storage and keep writing to it
+    pub fn load_from_storage(storage: SharedTradeStorage) -> Result<TradeRepository, PositionError> {
+        let mut trades = lock_storage(&storage)?.query(&TradeFilter::new())?;
+        trades.sort_by_key(|trade| trade.chronological_key());
+        let mut repo = TradeRepository::new();
+        for trade in trades {
+            let cancelled = matches!(trade.status, TradeStatus::Cancelled);
+            let trade_id = trade.trade_id;
+            repo.apply_event(&TradeEvent::Added(Trade { status: TradeStatus::Active, ..trade }))?;
+            if cancelled {
+                repo.apply_event(&TradeEvent::Cancelled { trade_id })?;
+            }
+        }
+        repo.attach_storage(storage);
+        Ok(repo)
+    }
+
+    pub fn record_event(&mut self, event: TradeEvent) -> Result<(), PositionError> {
+        if self.savepoints.is_empty() {
+            self.persist(std::slice::from_ref(&event))?;
+        }
+        self.journal.push(event);
+        Ok(())
+    }
+
+    // Committed events go to the journal file, then to storage. Once an event is in
+    // the journal it is committed: if the storage then fails, the event is kept in
+    // the storage backlog and retried, in order, ahead of the next write. Without a
+    // journal a storage failure fails the change.
+    pub fn persist(&mut self, events: &[TradeEvent]) -> Result<(), PositionError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        if let Some(journal) = &self.journal_file {
+            journal.append(events).map_err(|e| PositionError::Journal(format!("{}: {}", journal.path, e)))?;
+        }
+        match &self.storage {
+            Some(_) if self.journal_file.is_some() => {
+                self.storage_backlog.extend(events.iter().cloned());
+                // The journal has the events; a failure here only leaves storage behind
+                let _ = self.flush_storage();
+            },
+            Some(storage) => lock_storage(storage)?.apply(events)?,
+            None => {},
+        }
+        Ok(())
+    }
+
+    // Retry the events the storage has not applied yet
+    pub fn flush_storage(&mut self) -> Result<(), PositionError> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        if !self.storage_backlog.is_empty() {
+            lock_storage(storage)?.apply(&self.storage_backlog)?;
+            self.storage_backlog.clear();
+        }
+        Ok(())
+    }
+
+    // Rebuild a repository from a journal. Trades are restored exactly as recorded,
+    // so the same events always give the same trades and positions.
+    pub fn replay(events: &[TradeEvent]) -> Result<TradeRepository, PositionError> {
+        let mut repo = TradeRepository::new();
+        for event in events {
+            repo.apply_event(event)?;
+        }
+        Ok(repo)
+    }
+
+    pub fn apply_event(&mut self, event: &TradeEvent) -> Result<(), PositionError> {
+        match event {
+            TradeEvent::Added(trade) => {
+                if self.trades.contains_key(&trade.trade_id) {
+                    return Err(PositionError::DuplicateTradeId(trade.trade_id));
+                }
+                validate_terms(&trade.instrument, trade.quantity, trade.price)?;
+                self.book_trade(trade.clone())
+            },
+            TradeEvent::Amended(trade) => {
+                let current = self.trades.get(&trade.trade_id).ok_or(PositionError::TradeNotFound(trade.trade_id))?;
+                let moves_position = current.quantity != trade.quantity || current.price != trade.price || current.total_fees() != trade.total_fees()
+                    || current.instrument != trade.instrument || current.trade_date != trade.trade_date
+                    || !matches!((&current.side, &trade.side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell));
+                if !moves_position {
+                    // Cancelled trades still get their rebook links
+                    return self.update_trade_details(trade.clone());
+                }
+                if matches!(current.status, TradeStatus::Cancelled) {
+                    return Err(PositionError::TradeCancelled(trade.trade_id));
+                }
+                self.replace_trade(trade.clone())
+            },
+            TradeEvent::Cancelled { trade_id } => self.cancel_trade(*trade_id),
+            TradeEvent::Compressed(compressions) => {
+                self.record_event(event.clone())?;
+                self.apply_compressions(compressions)
+            },
+        }
+    }
+
+    pub fn set_actor(&mut self, actor: &str) {
+        self.actor = actor.to_string();
+    }
+
+    pub fn record_audit(&mut self, action: AuditAction, trade_id: TradeId, before: Option<Trade>, after: Option<Trade>) {
+        self.audit_log.push(AuditEntry {
+            sequence: self.audit_log.last().map_or(1, |entry| entry.sequence + 1),
+            recorded_at: Utc::now(),
+            actor: self.actor.clone(),
+            action,
+            trade_id,
+            before,
+            after,
+        });
+    }
+
+    // Fold a new trade into the daily position table. Trades executed after the last
+    // one folded in only touch their day; earlier ones rebuild the table and the live
+    // position from their day onwards.
+    pub fn record_daily_position(&mut self, trade: &Trade) {
+        if let Some(snapshots) = &mut self.position_snapshots {
+            snapshots.invalidate_from(trade.trade_date);
+        }
+        let in_order = self.daily_position_marks.get(&trade.instrument).is_none_or(|mark| trade.chronological_key() > *mark);
+        if !in_order {
+            self.rebuild_position_from(&trade.instrument.clone(), trade.trade_date, trade.chronological_key());
+            return;
+        }
+
+        let days = self.daily_positions.entry(trade.instrument.clone()).or_default();
+        let checkpoints = self.fold_checkpoints.entry(trade.instrument.clone()).or_default();
+        let mut position = days.values().next_back().cloned()
+            .unwrap_or_else(|| TradePosition::new(trade.instrument.clone()));
+        let mark = self.daily_position_marks.get(&trade.instrument).copied();
+        fold_into_days(&mut position, days, checkpoints, &mut std::iter::empty().peekable(), None, mark, [trade]);
+        self.daily_position_marks.insert(trade.instrument.clone(), trade.chronological_key());
+    }
+
+    // Rebuild the daily position table for an instrument from `from_date` onwards by
+    // replaying its active trades on top of the position carried from the prior day.
+    // Trades on the same day are applied in execution-time order, then trade_id.
+    pub fn refresh_daily_positions(&mut self, instrument: &str, from_date: NaiveDate) {
+        self.refresh_daily_positions_from(instrument, from_date, (start_of_day(from_date), TradeId(i128::MIN)));
+    }
+
+    // Rebuild the daily position table for an instrument from the trade at `from`,
+    // dated `from_date`. The replay starts at the last checkpoint before it on the
+    // same day, or else at the position carried from the prior day.
+    pub fn refresh_daily_positions_from(&mut self, instrument: &str, from_date: NaiveDate, from: ChronologicalKey) {
+        if let Some(snapshots) = &mut self.position_snapshots {
+            snapshots.invalidate_from(from_date);
+        }
+        let days = self.daily_positions.entry(instrument.to_string()).or_default();
+        let checkpoints = self.fold_checkpoints.entry(instrument.to_string()).or_default();
+        checkpoints.points.split_off(&from);
+        checkpoints.since_last = 0;
+        checkpoints.undo.clear();
+        days.split_off(&from_date);
+
+        let adjusted = self.position_adjustments.get(instrument);
+        let adjusted_before = adjusted.and_then(|dates| dates.range(..from_date).next_back()).map(|(date, _)| adjusted_mark(*date));
+        let checkpoint = checkpoints.points.last_key_value().filter(|(_, (date, _))| *date == from_date);
+        let (mut position, after, open) = match checkpoint {
+            Some((key, (_, position))) => (position.clone(), Some(*key), Some(from_date)),
+            None => {
+                checkpoints.points.retain(|_, (date, _)| *date < from_date);
+                let position = days.values().next_back().cloned().unwrap_or_else(|| TradePosition::new(instrument.to_string()));
+                (position, self.trades.latest_active_before(instrument, from_date), None)
+            },
+        };
+        let mut replay: Vec<&Trade> = self.trades
+            .instrument_values_from(instrument, from_date, open.and(after))
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        replay.sort_by_key(|trade| trade.chronological_key());
+
+        let mut adjustments = adjusted.into_iter().flat_map(flatten_adjustments).filter(|(date, _)| *date >= from_date).peekable();
+        let mark = fold_into_days(&mut position, days, checkpoints, &mut adjustments, open, after.max(adjusted_before), replay);
+        match mark {
+            Some(mark) => self.daily_position_marks.insert(instrument.to_string(), mark),
+            None => self.daily_position_marks.remove(instrument),
+        };
+    }
+
+    // Rebuild an instrument's position by replaying its remaining active trades in
+    // chronological order. Undoing a trade arithmetically only works for the most
+    // recent one, so cancels and amends rebuild the daily table from the changed
+    // trade and take the live position from its last day.
+    pub fn rebuild_position(&mut self, instrument: &str, from_date: NaiveDate) {
+        self.rebuild_position_from(instrument, from_date, (start_of_day(from_date), TradeId(i128::MIN)));
+    }
+
+    pub fn rebuild_position_from(&mut self, instrument: &str, from_date: NaiveDate, from: ChronologicalKey) {
+        self.refresh_daily_positions_from(instrument, from_date, from);
+        let position = self.daily_positions.get(instrument)
+            .and_then(|days| days.values().next_back().cloned())
+            .unwrap_or_else(|| TradePosition::new(instrument.to_string()));
+        self.positions.insert(instrument.to_string(), position);
+        self.publish_position_updates(instrument);
+    }
+
+    // Take the latest trade folded into an instrument back out without a replay: the
+    // live position, its day's entry and the mark return to what they were before it.
+    // False if the trade is not the latest one folded in or its fold was not kept.
+    pub fn unfold_latest(&mut self, trade: &Trade) -> bool {
+        let key = trade.chronological_key();
+        if self.daily_position_marks.get(&trade.instrument) != Some(&key) {
+            return false;
+        }
+        let Some(checkpoints) = self.fold_checkpoints.get_mut(&trade.instrument) else {
+            return false;
+        };
+        if checkpoints.undo.back().is_none_or(|step| step.key != key || step.trade_date != trade.trade_date) {
+            return false;
+        }
+        let Some(latest) = checkpoints.undo.pop_back() else {
+            return false;
+        };
+        checkpoints.points.remove(&key);
+        checkpoints.since_last = checkpoints.since_last.saturating_sub(1);
+        if let Some(snapshots) = &mut self.position_snapshots {
+            snapshots.invalidate_from(trade.trade_date);
+        }
+        let days = self.daily_positions.entry(trade.instrument.clone()).or_default();
+        if latest.opened_day {
+            days.remove(&trade.trade_date);
+        } else {
+            days.insert(trade.trade_date, latest.before.clone());
+        }
+        match latest.previous_mark {
+            Some(mark) => self.daily_position_marks.insert(trade.instrument.clone(), mark),
+            None => self.daily_position_marks.remove(&trade.instrument),
+        };
+        self.positions.insert(trade.instrument.clone(), latest.before);
+        true
+    }
+
+    pub fn amend_trade(&mut self, trade_id: impl Into<TradeId>, new_quantity: i32, new_price: impl Into<Price>) -> Result<(), PositionError> {
+        self.amend(trade_id, AmendRequest::new().quantity(new_quantity).price(new_price))
+    }
+
+    // Apply an amendment to an active trade. Scheduled fees follow the new terms.
+    pub fn amend(&mut self, trade_id: impl Into<TradeId>, request: AmendRequest) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let existing = self.trades.get(&trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        if matches!(existing.status, TradeStatus::Cancelled) {
+            return Err(PositionError::TradeCancelled(trade_id));
+        }
+        let mut amended = existing.clone();
+        if let Some(quantity) = request.quantity {
+            amended.quantity = quantity;
+        }
+        if let Some(price) = request.price {
+            amended.price = price;
+        }
+        if let Some(side) = request.side {
+            amended.side = side;
+        }
+        if let Some(instrument) = request.instrument {
+            amended.instrument = instrument;
+        }
+        if let Some(trade_date) = request.trade_date {
+            amended.trade_date = trade_date;
+            amended.executed_at = trade_date.and_time(existing.executed_at.time()).and_utc();
+        }
+        validate_terms(&amended.instrument, amended.quantity, amended.price)?;
+        self.apply_booking_rules(&mut amended);
+        self.check_instrument(&amended.instrument, amended.quantity, amended.price)?;
+        self.replace_trade(amended)
+    }
+
+    // Swap a stored trade for its amended version, moving the position with it.
+    // A trade moved to another instrument rebuilds both positions.
+    pub fn replace_trade(&mut self, amended: Trade) -> Result<(), PositionError> {
+        let trade_id = amended.trade_id;
+        let (before, amended) = self.store_amended(amended)?;
+        let same_key = before.chronological_key() == amended.chronological_key() && before.trade_date == amended.trade_date;
+        if before.instrument == amended.instrument && same_key && self.unfold_latest(&before) {
+            // The latest trade is folded back in with its new terms
+            self.positions.get_mut(&amended.instrument).unwrap().update_position(&amended);
+            self.record_daily_position(&amended);
+            self.publish_position_updates(&amended.instrument);
+        } else if before.instrument == amended.instrument {
+            let (from_date, from) = (before.trade_date.min(amended.trade_date), before.chronological_key().min(amended.chronological_key()));
+            self.rebuild_position_from(&amended.instrument, from_date, from);
+        } else {
+            self.rebuild_position_from(&before.instrument, before.trade_date, before.chronological_key());
+            self.rebuild_position_from(&amended.instrument, amended.trade_date, amended.chronological_key());
+        }
+        self.record_audit(AuditAction::Amend, trade_id, Some(before), Some(amended));
+        Ok(())
+    }
+
+    // Journal and store an amended trade, leaving its position alone; the stored
+    // trade before and after
+    fn store_amended(&mut self, mut amended: Trade) -> Result<(Trade, Trade), PositionError> {
+        let trade_id = amended.trade_id;
+        let before = self.trades.get(&trade_id).cloned().ok_or(PositionError::TradeNotFound(trade_id))?;
+        self.check_transition(trade_id, &before.status, &TradeStatus::Amended)?;
+        amended.status = TradeStatus::Amended;
+        self.record_event(TradeEvent::Amended(amended.clone()))?;
+        self.log_transition(trade_id, before.status.clone(), TradeStatus::Amended, None);
+        self.save_for_rollback(trade_id, &[&before.instrument, &amended.instrument]);
+        if before.instrument != amended.instrument && !self.booked_currencies.contains_key(&amended.instrument) {
+            let currency = self.instrument_currency(&amended.instrument).to_string();
+            self.booked_currencies.insert(amended.instrument.clone(), currency);
+        }
+        self.trades.insert(trade_id, amended.clone());
+        Ok((before, amended))
+    }
+
+    // Replace many trades at once, as a corporate action restating history does:
+    // each instrument they touch is replayed once, from the earliest change, rather
+    // than once per trade
+    pub fn replace_trades(&mut self, amended: Vec<Trade>) -> Result<(), PositionError> {
+        let mut replay_from: BTreeMap<String, (NaiveDate, ChronologicalKey)> = BTreeMap::new();
+        for trade in amended {
+            let trade_id = trade.trade_id;
+            let (before, after) = self.store_amended(trade)?;
+            for changed in [&before, &after] {
+                let from = (changed.trade_date, changed.chronological_key());
+                replay_from
+                    .entry(changed.instrument.clone())
+                    .and_modify(|earliest| *earliest = (earliest.0.min(from.0), earliest.1.min(from.1)))
+                    .or_insert(from);
+            }
+            self.record_audit(AuditAction::Amend, trade_id, Some(before), Some(after));
+        }
+        for (instrument, (from_date, from)) in replay_from {
+            self.rebuild_position_from(&instrument, from_date, from);
+        }
+        Ok(())
+    }
+
+    // NEW: Amend trade based on date. Only an active trade that is the sole match is amended.
+    pub fn amend_trade_by_date(&mut self, instrument: &str, trade_date: NaiveDate, new_quantity: i32, new_price: impl Into<Price>) -> Result<(), PositionError> {
+        // Find trades by instrument and date
+        let candidates: Vec<&Trade> = self.trades
+            .instrument_values_between(instrument, trade_date, trade_date)
+            .collect();
+        let mut active: Vec<TradeId> = candidates
+            .iter()
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .map(|trade| trade.trade_id)
+            .collect();
+        active.sort();
+
+        match (active.as_slice(), candidates.first()) {
+            ([trade_id], _) => self.amend_trade(*trade_id, new_quantity, new_price),
+            ([], Some(cancelled)) => Err(PositionError::TradeCancelled(cancelled.trade_id)),
+            ([], None) if !self.positions.contains_key(instrument) => Err(PositionError::InstrumentNotFound(instrument.to_string())),
+            ([], None) => Err(PositionError::NoTradeOnDate { instrument: instrument.to_string(), date: trade_date }),
+            _ => Err(PositionError::AmbiguousMatch { instrument: instrument.to_string(), date: trade_date, trade_ids: active }),
+        }
+    }
+
+    // Amendments of a trade in order: who changed which fields, from what to what.
+    // Read from the audit log, so retention purges apply to it too.
+    pub fn amendment_history(&self, trade_id: impl Into<TradeId>) -> Vec<Amendment> {
+        let trade_id = trade_id.into();
+        self.audit_log
+            .iter()
+            .filter(|entry| entry.trade_id == trade_id && matches!(entry.action, AuditAction::Amend))
+            .filter_map(|entry| {
+                let changes = trade_changes(entry.before.as_ref()?, entry.after.as_ref()?);
+                Some(Amendment {
+                    sequence: entry.sequence,
+                    recorded_at: entry.recorded_at,
+                    actor: entry.actor.clone(),
+                    changes,
+                })
+            })
+            .collect()
+    }
+
+    pub fn print_amendment_history(&self, trade_id: impl Into<TradeId>) {
+        let trade_id = trade_id.into();
+        let history = self.amendment_history(trade_id);
+        println!("Trade {}: {} amendment(s)", trade_id, history.len());
+        for amendment in history {
+            let changes: Vec<String> = amendment.changes.iter().map(|(field, before, after)| format!("{} {} -> {}", field, before, after)).collect();
+            println!("  #{} at {} by {}: {}", amendment.sequence, amendment.recorded_at.format("%Y-%m-%d %H:%M:%S"), amendment.actor, changes.join(", "));
+        }
+    }
+
+    // NEW: Find trades by date range
+    pub fn find_trades_by_date(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<&Trade> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values_between(start_date, end_date)
+            .collect();
+        sort_trades_for_report(&mut trades);
+        trades
+    }
+
+    pub fn cancel_trade(&mut self, trade_id: impl Into<TradeId>) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let before = self.trades.get(&trade_id).cloned().ok_or(PositionError::TradeNotFound(trade_id))?;
+        if matches!(before.status, TradeStatus::Cancelled) {
+            return Err(PositionError::TradeCancelled(trade_id));
+        }
+        self.check_transition(trade_id, &before.status, &TradeStatus::Cancelled)?;
+        self.record_event(TradeEvent::Cancelled { trade_id })?;
+        self.log_transition(trade_id, before.status.clone(), TradeStatus::Cancelled, None);
+        let after = Trade { status: TradeStatus::Cancelled, ..before.clone() };
+        self.save_for_rollback(trade_id, &[&after.instrument]);
+        self.trades.insert(trade_id, after.clone());
+        if self.unfold_latest(&after) {
+            self.publish_position_updates(&after.instrument);
+        } else {
+            self.rebuild_position_from(&after.instrument, after.trade_date, after.chronological_key());
+        }
+        self.record_audit(AuditAction::Cancel, trade_id, Some(before), Some(after));
+        Ok(())
+    }
+
+    // Apply a group of changes atomically. If the closure returns an error the
+    // repository is restored to its state before the transaction started, so
+    // trades, positions, the daily position table and prices never end up half-updated.
+    // A panic inside the closure is not caught and skips the rollback: the repository
+    // is left mid-transaction, with nothing persisted from then on, and should be
+    // dropped, as a lock around it would be poisoned.
+    pub fn transaction<T, F>(&mut self, changes: F) -> Result<T, PositionError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T, PositionError>,
+    {
+        let first_event = self.journal.len();
+        let savepoint = self.savepoint();
+        self.savepoints.push(savepoint);
+        let mut result = changes(&mut Transaction { repo: self });
+        let savepoint = self.savepoints.pop().expect("savepoint of the open transaction");
+        if result.is_ok() && self.savepoints.is_empty() {
+            let committed = self.journal.get(first_event..).unwrap_or_default().to_vec();
+            if let Err(e) = self.persist(&committed) {
+                result = Err(e);
+            }
+        }
+        if result.is_err() {
+            self.roll_back(savepoint);
+        }
+        result
+    }
+
+    // Exchange the state a savepoint saves piecemeal with another repository's
+    pub fn swap_tracked_state(&mut self, other: &mut TradeRepository) {
+        std::mem::swap(&mut self.trades, &mut other.trades);
+        std::mem::swap(&mut self.positions, &mut other.positions);
+        std::mem::swap(&mut self.daily_positions, &mut other.daily_positions);
+        std::mem::swap(&mut self.daily_position_marks, &mut other.daily_position_marks);
+        std::mem::swap(&mut self.fold_checkpoints, &mut other.fold_checkpoints);
+        std::mem::swap(&mut self.audit_log, &mut other.audit_log);
+        std::mem::swap(&mut self.journal, &mut other.journal);
+        std::mem::swap(&mut self.status_log, &mut other.status_log);
+        std::mem::swap(&mut self.event_log, &mut other.event_log);
+        std::mem::swap(&mut self.savepoints, &mut other.savepoints);
+    }
+
+    pub fn savepoint(&mut self) -> Savepoint {
+        let mut tracked = TradeRepository::new();
+        self.swap_tracked_state(&mut tracked);
+        let rest = Box::new(self.clone());
+        self.swap_tracked_state(&mut tracked);
+        Savepoint {
+            rest,
+            trades: HashMap::new(),
+            instruments: HashMap::new(),
+            audit_len: self.audit_log.len(),
+            journal_len: self.journal.len(),
+            status_log_len: self.status_log.len(),
+            event_log_len: self.event_log.len(),
+        }
+    }
+
+    // Called before a trade is stored or replaced: every open savepoint that has not
+    // seen the trade or the instruments yet saves them as they are now
+    pub fn save_for_rollback(&mut self, trade_id: TradeId, instruments: &[&str]) {
+        if self.savepoints.is_empty() {
+            return;
+        }
+        let before = self.trades.get(&trade_id).map(|trade| (trade.clone(), self.trades.is_hot(&trade_id)));
+        for savepoint in &mut self.savepoints {
+            savepoint.trades.entry(trade_id).or_insert_with(|| before.clone());
+            for instrument in instruments {
+                if !savepoint.instruments.contains_key(*instrument) {
+                    savepoint.instruments.insert(instrument.to_string(), SavedInstrument {
+                        position: self.positions.get(*instrument).cloned(),
+                        days: self.daily_positions.get(*instrument).cloned(),
+                        mark: self.daily_position_marks.get(*instrument).copied(),
+                        checkpoints: self.fold_checkpoints.get(*instrument).cloned(),
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn roll_back(&mut self, savepoint: Savepoint) {
+        let mut current = std::mem::replace(self, *savepoint.rest);
+        self.swap_tracked_state(&mut current);
+        for (trade_id, before) in savepoint.trades {
+            self.trades.restore(trade_id, before);
+        }
+        for (instrument, saved) in savepoint.instruments {
+            match saved.position {
+                Some(position) => self.positions.insert(instrument.clone(), position),
+                None => self.positions.remove(&instrument),
+            };
+            match saved.days {
+                Some(days) => self.daily_positions.insert(instrument.clone(), days),
+                None => self.daily_positions.remove(&instrument),
+            };
+            match saved.mark {
+                Some(mark) => self.daily_position_marks.insert(instrument.clone(), mark),
+                None => self.daily_position_marks.remove(&instrument),
+            };
+            match saved.checkpoints {
+                Some(checkpoints) => self.fold_checkpoints.insert(instrument, checkpoints),
+                None => self.fold_checkpoints.remove(&instrument),
+            };
+        }
+        self.audit_log.truncate(savepoint.audit_len);
+        self.journal.truncate(savepoint.journal_len);
+        self.status_log.truncate(savepoint.status_log_len);
+        self.event_log.truncate(savepoint.event_log_len);
+    }
+
+    // Change fields of a trade that don't move its position, such as rebook links
+    pub fn update_trade_details(&mut self, after: Trade) -> Result<(), PositionError> {
+        let trade_id = after.trade_id;
+        let before = self.trades.get(&trade_id).cloned().ok_or(PositionError::TradeNotFound(trade_id))?;
+        let status_changed = trade_status_name(&before.status) != trade_status_name(&after.status);
+        if status_changed {
+            self.check_transition(trade_id, &before.status, &after.status)?;
+        }
+        self.record_event(TradeEvent::Amended(after.clone()))?;
+        if status_changed {
+            self.log_transition(trade_id, before.status.clone(), after.status.clone(), None);
+        }
+        self.save_for_rollback(trade_id, &[&before.instrument, &after.instrument]);
+        let reindex = status_changed || before.instrument != after.instrument || before.trade_date != after.trade_date
+            || before.chronological_key() != after.chronological_key();
+        match self.trades.get_mut(&trade_id) {
+            Some(trade) if !reindex => *trade = after.clone(),
+            _ => {
+                self.trades.insert(trade_id, after.clone());
+            },
+        }
+        self.record_audit(AuditAction::Amend, trade_id, Some(before), Some(after));
+        Ok(())
+    }
+
+    // Standard ops correction: cancel a trade and book its replacement atomically,
+    // linking the two trades and logging the pair as a single event
+    pub fn cancel_and_rebook(&mut self, trade_id: impl Into<TradeId>, corrected: Trade) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        if corrected.trade_id == trade_id {
+            return Err(PositionError::InvalidCorrection(trade_id));
+        }
+        let corrected_trade_id = corrected.trade_id;
+        let date = corrected.trade_date;
+
+        self.transaction(|tx| {
+            tx.cancel(trade_id)?;
+            tx.add_trade(Trade { replaces: Some(trade_id), ..corrected })
+        })?;
+
+        if let Some(original) = self.trades.get(&trade_id) {
+            let relinked = Trade { replaced_by: Some(corrected_trade_id), ..original.clone() };
+            self.update_trade_details(relinked)?;
+        }
+        self.event_log.push(LifecycleEvent::Rebooked {
+            date,
+            original_trade_id: trade_id,
+            corrected_trade_id,
+        });
+        Ok(())
+    }
+
+    pub fn set_average_daily_volume(&mut self, instrument: &str, volume: f64) {
+        self.average_daily_volumes.insert(instrument.to_string(), volume);
+    }
+
+    pub fn set_average_daily_volume_on(&mut self, instrument: &str, date: NaiveDate, volume: f64) {
+        self.adv_history.insert(instrument, date, volume);
+    }
+
+    // ADV history as CSV rows of date,instrument,adv
+    pub fn import_adv_csv(&mut self, csv: &str) -> Result<usize, PositionError> {
+        let positive = |text: &str| text.parse::<f64>().ok().filter(|volume| volume.is_finite() && *volume > 0.0).ok_or("not a positive number".to_string());
+        self.adv_history.import_csv(csv, "date,instrument,adv", positive).map_err(PositionError::InvalidRecord)
+    }
+
+    // Latest imported ADV on or before the date, else the flat ADV
+    pub fn average_daily_volume_on(&self, instrument: &str, date: NaiveDate) -> Option<f64> {
+        self.adv_history.value_on(instrument, date)
+            .or_else(|| self.average_daily_volumes.get(instrument).copied())
+            .filter(|volume| *volume > 0.0)
+    }
+
+    // Our own traded volume per instrument and day, both sides counted
+    pub fn own_daily_volume(&self) -> BTreeMap<(String, NaiveDate), i64> {
+        let mut volumes = BTreeMap::new();
+        for trade in self.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            *volumes.entry((trade.instrument.clone(), trade.trade_date)).or_insert(0) += trade.quantity as i64;
+        }
+        volumes
+    }
+
+    // Own volume as a share of ADV for every instrument and day we traded in the
+    // range. Days without an ADV are listed with no rate rather than dropped.
+    pub fn participation_report(&self, start_date: NaiveDate, end_date: NaiveDate, limit: Option<f64>) -> ParticipationReport {
+        let rows: Vec<ParticipationRow> = self.own_daily_volume()
+            .into_iter()
+            .filter(|((_, date), _)| *date >= start_date && *date <= end_date)
+            .map(|((instrument, date), own_volume)| {
+                let adv = self.average_daily_volume_on(&instrument, date);
+                let participation = adv.map(|adv| own_volume as f64 / adv);
+                ParticipationRow {
+                    exceeds_limit: matches!((participation, limit), (Some(rate), Some(limit)) if rate > limit),
+                    instrument,
+                    date,
+                    own_volume,
+                    adv,
+                    participation,
+                }
+            })
+            .collect();
+
+        ParticipationReport {
+            start_date,
+            end_date,
+            limit,
+            rows,
+        }
+    }
+
+    pub fn print_participation_report(&self, start_date: NaiveDate, end_date: NaiveDate, limit: Option<f64>) {
+        let report = self.participation_report(start_date, end_date, limit);
+        println!("Participation {} to {}{}", report.start_date, report.end_date,
+                 report.limit.map_or(String::new(), |limit| format!(" (limit {:.1}% of ADV)", limit * 100.0)));
+        println!("{:<10} {:<12} {:>12} {:>14} {:>14}", "Instrument", "Date", "Own Volume", "ADV", "Participation");
+        for row in &report.rows {
+            let adv = row.adv.map_or("n/a".to_string(), |adv| format!("{:.0}", adv));
+            let participation = row.participation.map_or("n/a".to_string(), |rate| format!("{:.2}%", rate * 100.0));
+            println!("{:<10} {:<12} {:>12} {:>14} {:>14}{}", row.instrument, row.date, row.own_volume, adv, participation,
+                     if row.exceeds_limit { "  over limit" } else { "" });
+        }
+    }
+
+    pub fn set_sanity_band(&mut self, instrument: &str, band: SanityBand) {
+        self.sanity_bands.insert(instrument.to_string(), band);
+    }
+
+    pub fn sanity_band(&self, instrument: &str) -> Option<&SanityBand> {
+        self.sanity_bands.get(instrument).or(self.default_sanity_band.as_ref())
+    }
+
+    // Compare a trade with its instrument's sanity band. Checks that need a mark or
+    // an ADV are skipped when that data is missing.
+    pub fn check_trade_sanity(&self, trade: &Trade) -> Vec<SanityBreach> {
+        let mut breaches = Vec::new();
+        let Some(band) = self.sanity_band(&trade.instrument) else { return breaches };
+
+        if let Some(mark) = self.get_market_price(&trade.instrument).filter(|mark| !mark.is_zero()) {
+            let deviation = ((trade.price - mark).abs() / mark).to_f64();
+            if deviation > band.max_price_deviation {
+                breaches.push(SanityBreach {
+                    trade_id: trade.trade_id,
+                    instrument: trade.instrument.clone(),
+                    rule: format!("price {} vs mark {}", trade.price, mark),
+                    value: deviation,
+                    limit: band.max_price_deviation,
+                });
+            }
+        }
+        if let Some(adv) = self.average_daily_volume_on(&trade.instrument, trade.trade_date) {
+            let adv_fraction = trade.quantity as f64 / adv;
+            if adv_fraction > band.max_adv_fraction {
+                breaches.push(SanityBreach {
+                    trade_id: trade.trade_id,
+                    instrument: trade.instrument.clone(),
+                    rule: format!("quantity {} vs ADV {}", trade.quantity, adv),
+                    value: adv_fraction,
+                    limit: band.max_adv_fraction,
+                });
+            }
+        }
+        breaches
+    }
+
+    // Book a trade after sanity checks. Breaches of a flagging band are booked and
+    // recorded; breaches of a blocking band are refused.
+    pub fn add_trade_checked(&mut self, trade: Trade) -> Result<Vec<SanityBreach>, PositionError> {
+        let breaches = self.check_trade_sanity(&trade);
+        let blocking = self.sanity_band(&trade.instrument).is_some_and(|band| matches!(band.action, SanityAction::Block));
+        if !breaches.is_empty() && blocking {
+            let rules: Vec<String> = breaches.iter().map(|breach| breach.rule.clone()).collect();
+            return Err(PositionError::SanityBlocked { trade_id: trade.trade_id, rules });
+        }
+
+        self.add_trade(trade)?;
+        self.sanity_flags.extend(breaches.iter().cloned());
+        Ok(breaches)
+    }
+
+    // Book a trade regardless of sanity breaches, recording who approved it and why
+    pub fn add_trade_with_override(&mut self, trade: Trade, justification: &str, approved_by: &str) -> Result<(), PositionError> {
+        if justification.trim().is_empty() {
+            return Err(PositionError::MissingJustification);
+        }
+        let breaches = self.check_trade_sanity(&trade);
+        let trade_id = trade.trade_id;
+        self.add_trade(trade)?;
+        if !breaches.is_empty() {
+            self.sanity_overrides.push(SanityOverride {
+                trade_id,
+                breaches,
+                justification: justification.to_string(),
+                approved_by: approved_by.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn set_risk_limits(&mut self, limits: RiskLimits) {
+        self.risk_limits = Some(limits);
+    }
+
+    pub fn limits_block(&self) -> bool {
+        self.risk_limits.as_ref().is_some_and(|limits| matches!(limits.action, SanityAction::Block))
+    }
+
+    // Limits a trade or order would break if it were booked now
+    pub fn check_risk_limits(&self, instrument: &str, side: &Side, quantity: i32, price: Price) -> Vec<LimitBreach> {
+        self.risk_limit_breaches(instrument, side, quantity, price, &BTreeMap::new())
+    }
+
+    // pending holds the signed quantity and last price of trades accepted but not
+    // yet booked, e.g. earlier in the same batch
+    pub fn risk_limit_breaches(&self, instrument: &str, side: &Side, quantity: i32, price: Price, pending: &BTreeMap<String, (i32, Price)>) -> Vec<LimitBreach> {
+        let mut breaches = Vec::new();
+        let Some(limits) = &self.risk_limits else { return breaches };
+        let breach = |rule: LimitRule, value: Decimal, limit: Decimal| LimitBreach { instrument: instrument.to_string(), rule, value, limit };
+
+        if limits.restricted.contains(instrument) {
+            breaches.push(breach(LimitRule::Restricted, Decimal::from(quantity), Decimal::ZERO));
+        }
+        let notional = price * quantity * self.contract_multiplier(instrument);
+        if let Some(limit) = limits.max_order_notional.filter(|limit| notional > *limit) {
+            breaches.push(breach(LimitRule::MaxOrderNotional, notional, limit));
+        }
+        let signed = match side {
+            Side::Buy => quantity,
+            Side::Sell => -quantity,
+        };
+        let held = |name: &str| self.positions.get(name).map_or(0, |position| position.quantity) + pending.get(name).map_or(0, |(quantity, _)| *quantity);
+        let after = held(instrument) + signed;
+        if let Some(&limit) = limits.max_position.get(instrument).filter(|limit| after.abs() > **limit) {
+            breaches.push(breach(LimitRule::MaxPosition, Decimal::from(after.abs()), Decimal::from(limit)));
+        }
+
+        if limits.max_gross_exposure.is_some() || limits.max_net_exposure.is_some() {
+            let mut names: BTreeSet<&str> = self.positions.keys().map(String::as_str).collect();
+            names.extend(pending.keys().map(String::as_str));
+            names.insert(instrument);
+            let (mut gross, mut net) = (Decimal::ZERO, Decimal::ZERO);
+            for name in names {
+                let (quantity, fallback) = if name == instrument {
+                    (after, Some(price))
+                } else {
+                    let cost = self.positions.get(name).map(|position| position.average_price).or(pending.get(name).map(|(_, price)| *price));
+                    (held(name), cost)
+                };
+                if quantity == 0 {
+                    continue;
+                }
+                let value = self.get_market_price(name).or(fallback).unwrap_or(Decimal::ZERO) * quantity * self.contract_multiplier(name);
+                gross += value.abs();
+                net += value;
+            }
+            if let Some(limit) = limits.max_gross_exposure.filter(|limit| gross > *limit) {
+                breaches.push(breach(LimitRule::MaxGrossExposure, gross, limit));
+            }
+            if let Some(limit) = limits.max_net_exposure.filter(|limit| net.abs() > *limit) {
+                breaches.push(breach(LimitRule::MaxNetExposure, net, limit));
+            }
+        }
+        breaches
+    }
+
+    pub fn print_limit_flags(&self) {
+        let Some(limits) = &self.risk_limits else {
+            println!("No risk limits set");
+            return;
+        };
+        println!("Risk limits ({:?}): {} restricted, {} position limits", limits.action, limits.restricted.len(), limits.max_position.len());
+        for (trade_id, breach) in &self.limit_flags {
+            println!("  Trade {}: {}", trade_id, breach);
+        }
+    }
+
+    pub fn check_transition(&self, trade_id: TradeId, from: &TradeStatus, to: &TradeStatus) -> Result<(), PositionError> {
+        if !from.can_become(to) {
+            return Err(PositionError::InvalidTransition { trade_id, from: from.clone(), to: to.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn log_transition(&mut self, trade_id: TradeId, from: TradeStatus, to: TradeStatus, reason: Option<&str>) {
+        self.status_log.push(StatusTransition {
+            trade_id,
+            from,
+            to,
+            actor: self.actor.clone(),
+            recorded_at: Utc::now(),
+            reason: reason.map(str::to_string),
+        });
+    }
+
+    // Status a trade is booked from, or None for one restored already Active
+    pub fn booking_transition(&self, trade: &Trade) -> Result<Option<TradeStatus>, PositionError> {
+        if matches!(trade.status, TradeStatus::Active) {
+            return Ok(None);
+        }
+        self.check_transition(trade.trade_id, &trade.status, &TradeStatus::Active)?;
+        Ok(Some(trade.status.clone()))
+    }
+
+    // Hold a new trade out of the book until it is approved or rejected
+    pub fn submit_for_approval(&mut self, mut trade: Trade) -> Result<(), PositionError> {
+        let trade_id = trade.trade_id;
+        if self.trades.contains_key(&trade_id) || self.held_trades.contains_key(&trade_id) {
+            return Err(PositionError::DuplicateTradeId(trade_id));
+        }
+        validate_terms(&trade.instrument, trade.quantity, trade.price)?;
+        self.check_instrument(&trade.instrument, trade.quantity, trade.price)?;
+        self.check_transition(trade_id, &trade.status, &TradeStatus::PendingApproval)?;
+        self.log_transition(trade_id, trade.status.clone(), TradeStatus::PendingApproval, None);
+        trade.status = TradeStatus::PendingApproval;
+        self.held_trades.insert(trade_id, trade);
+        Ok(())
+    }
+
+    // Book a held trade, as the approver
+    pub fn approve_trade(&mut self, trade_id: impl Into<TradeId>, approved_by: &str) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let trade = self.held_trades.get(&trade_id).cloned().ok_or(PositionError::TradeNotFound(trade_id))?;
+        self.check_transition(trade_id, &trade.status, &TradeStatus::Active)?;
+        let actor = std::mem::replace(&mut self.actor, approved_by.to_string());
+        let booked = self.add_trade(trade);
+        self.actor = actor;
+        booked?;
+        self.held_trades.remove(&trade_id);
+        Ok(())
+    }
+
+    pub fn reject_trade(&mut self, trade_id: impl Into<TradeId>, rejected_by: &str, reason: &str) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let from = self.held_trades.get(&trade_id).map(|trade| trade.status.clone()).ok_or(PositionError::TradeNotFound(trade_id))?;
+        self.check_transition(trade_id, &from, &TradeStatus::Rejected)?;
+        self.held_trades.get_mut(&trade_id).unwrap().status = TradeStatus::Rejected;
+        let actor = std::mem::replace(&mut self.actor, rejected_by.to_string());
+        self.log_transition(trade_id, from, TradeStatus::Rejected, Some(reason));
+        self.actor = actor;
+        Ok(())
+    }
+
+    pub fn held_trade(&self, trade_id: impl Into<TradeId>) -> Option<&Trade> {
+        self.held_trades.get(&trade_id.into())
+    }
+
+    pub fn status_history(&self, trade_id: impl Into<TradeId>) -> Vec<&StatusTransition> {
+        let trade_id = trade_id.into();
+        self.status_log.iter().filter(|transition| transition.trade_id == trade_id).collect()
+    }
+
+    pub fn print_status_history(&self, trade_id: impl Into<TradeId>) {
+        let trade_id = trade_id.into();
+        println!("Status history of trade {}", trade_id);
+        for transition in self.status_history(trade_id) {
+            let reason = transition.reason.as_ref().map_or(String::new(), |reason| format!(" ({})", reason));
+            println!("  {} {:<15} -> {:<15} by {}{}", transition.recorded_at.format("%Y-%m-%d %H:%M:%S"), format!("{:?}", transition.from), format!("{:?}", transition.to), transition.actor, reason);
+        }
+    }
+
+    pub fn set_mark_method(&mut self, instrument: &str, method: MarkMethod) {
+        self.mark_methods.insert(instrument.to_string(), method);
+    }
+
+    pub fn set_default_mark_method(&mut self, method: MarkMethod) {
+        self.default_mark_method = method;
+    }
+
+    pub fn mark_method(&self, instrument: &str) -> MarkMethod {
+        self.mark_methods.get(instrument).copied().unwrap_or(self.default_mark_method)
+    }
+
+    // Mark implied by the latest book under the instrument's method; None for
+    // last-trade marking or when the book lacks a side
+    pub fn book_mark(&self, instrument: &str) -> Option<Price> {
+        let book = self.book_snapshots.get(instrument)?;
+        let mark = match self.mark_method(instrument) {
+            MarkMethod::LastTrade => None,
+            MarkMethod::Mid => book.mid(),
+            MarkMethod::Microprice => book.microprice(),
+            MarkMethod::DepthWeighted { levels, decay } => book.depth_weighted(levels, decay),
+        };
+        mark.map(|mark| self.rounding_policy(instrument).round_price(mark))
+    }
+
+    // Store a book snapshot and, unless the instrument is marked at last trade,
+    // re-mark it from the book. Returns the mark applied. Snapshots older than the
+    // stored one are ignored.
+    pub fn ingest_book_snapshot(&mut self, snapshot: BookSnapshot) -> Result<Option<Price>, PositionError> {
+        snapshot.validate().map_err(PositionError::InvalidRecord)?;
+        if self.book_snapshots.get(&snapshot.instrument).is_some_and(|stored| stored.captured_at > snapshot.captured_at) {
+            return Ok(None);
+        }
+        let instrument = snapshot.instrument.clone();
+        self.book_snapshots.insert(instrument.clone(), snapshot);
+        let mark = self.book_mark(&instrument);
+        if let Some(mark) = mark {
+            self.update_market_price(&instrument, mark)?;
+        }
+        Ok(mark)
+    }
+
+    // Update market price for P&L calculations
+    pub fn update_market_price(&mut self, instrument: &str, price: impl Into<Price>) -> Result<(), PositionError> {
+        let price = price.into();
+        if price.is_negative() || price.is_zero() {
+            return Err(PositionError::InvalidPrice { instrument: instrument.to_string(), price });
+        }
+        self.market_prices.insert(instrument.to_string(), price);
+        self.notify_pnl(instrument);
+        Ok(())
+    }
+
+    // Receive a notification whenever a watched position's unrealized P&L crosses one
+    // of the watch's levels or moves by more than its change fraction, checked as
+    // prices and trades arrive. Dropping the receiver ends the subscription.
+    pub fn subscribe_pnl(&mut self, watch: PnlWatch) -> std::sync::mpsc::Receiver<PnlNotification> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut subscription = PnlSubscription { watch, sender, last_seen: HashMap::new(), change_base: HashMap::new() };
+        for (instrument, position) in &self.positions {
+            if let (true, Some(price)) = (subscription.watch.covers(instrument), self.get_market_price(instrument)) {
+                subscription.last_seen.insert(instrument.clone(), position.unrealized_pnl(price));
+            }
+        }
+        self.pnl_subscriptions.push(subscription);
+        receiver
+    }
+
+    pub fn notify_pnl(&mut self, instrument: &str) {
+        self.publish_position_updates(instrument);
+        if self.pnl_subscriptions.is_empty() {
+            return;
+        }
+        let (Some(position), Some(price)) = (self.positions.get(instrument), self.get_market_price(instrument)) else {
+            return;
+        };
+        let unrealized = position.unrealized_pnl(price);
+        let raised_at = Utc::now();
+        self.pnl_subscriptions.retain_mut(|subscription| {
+            if !subscription.watch.covers(instrument) {
+                return true;
+            }
+            subscription.triggers(instrument, unrealized).into_iter().all(|(previous, trigger)| {
+                subscription.sender.send(PnlNotification {
+                    instrument: instrument.to_string(),
+                    previous_unrealized_pnl: previous,
+                    unrealized_pnl: unrealized,
+                    trigger,
+                    raised_at,
+                }).is_ok()
+            })
+        });
+    }
+
+    // Stream position deltas and P&L updates for the positions the filter follows as
+    // trades, amendments, cancellations and prices arrive, starting with where each
+    // stands now. Dropping the receiver ends the stream, and so does falling more
+    // than POSITION_STREAM_BUFFER updates behind.
+    pub fn subscribe_positions(&mut self, filter: StreamFilter) -> std::sync::mpsc::Receiver<PositionUpdate> {
+        let mut positions = match &filter.account_id {
+            Some(account_id) => self.build_account_positions(account_id),
+            None => self.positions.clone(),
+        };
+        positions.retain(|instrument, _| filter.covers(instrument));
+        // Room for the opening position and P&L of everything followed, at least
+        let (sender, receiver) = std::sync::mpsc::sync_channel(POSITION_STREAM_BUFFER.max(2 * positions.len()));
+        let mut stream = PositionStream { filter, sender, last_quantity: HashMap::new(), last_pnl: HashMap::new() };
+        for position in positions.values() {
+            for update in stream.updates(position, self.get_market_price(&position.instrument)) {
+                let _ = stream.sender.try_send(update);
+            }
+        }
+        self.position_streams.push(stream);
+        receiver
+    }
+
+    pub fn publish_position_updates(&mut self, instrument: &str) {
+        if self.position_streams.is_empty() {
+            return;
+        }
+        let price = self.get_market_price(instrument);
+        let firm = self.positions.get(instrument).cloned().unwrap_or_else(|| TradePosition::new(instrument.to_string()));
+        // Account positions are rebuilt from the account's trades, once per account
+        let mut accounts: HashMap<String, TradePosition> = HashMap::new();
+        let mut streams = std::mem::take(&mut self.position_streams);
+        streams.retain_mut(|stream| {
+            if !stream.filter.covers(instrument) {
+                return true;
+            }
+            let position = match &stream.filter.account_id {
+                Some(account_id) => &*accounts.entry(account_id.clone()).or_insert_with(|| {
+                    self.build_positions_where(NaiveDate::MAX, |trade| trade.account_id == *account_id && trade.instrument == instrument)
+                        .remove(instrument)
+                        .unwrap_or_else(|| TradePosition::new(instrument.to_string()))
+                }),
+                None => &firm,
+            };
+            stream.updates(position, price).into_iter().all(|update| stream.sender.try_send(update).is_ok())
+        });
+        self.position_streams = streams;
+    }
+
+    // Get current market price
+    pub fn get_market_price(&self, instrument: &str) -> Option<Price> {
+        if is_cash_instrument(instrument) {
+            return Some(Decimal::from(1));
+        }
+        if let Some(mark_override) = self.active_mark_override(instrument, Utc::now()) {
+            return Some(mark_override.price);
+        }
+        self.market_prices.get(instrument).copied()
+    }
+
+    // Mark an instrument by hand until the override expires; the feed price is used
+    // again afterwards. An owner's earlier override of the instrument is replaced.
+    pub fn override_mark(&mut self, mark_override: MarkOverride) -> Result<(), PositionError> {
+        let instrument = mark_override.instrument.clone();
+        if mark_override.price.is_negative() || mark_override.price.is_zero() {
+            return Err(PositionError::InvalidPrice { instrument, price: mark_override.price });
+        }
+        if !mark_override.is_active(Utc::now()) {
+            return Err(PositionError::InvalidRecord(format!("mark override for {} expired at {}", instrument, mark_override.expires_at)));
+        }
+        let overrides = self.mark_overrides.entry(instrument.clone()).or_default();
+        overrides.retain(|existing| existing.owner != mark_override.owner);
+        overrides.push(mark_override);
+        self.notify_pnl(&instrument);
+        Ok(())
+    }
+
+    pub fn clear_mark_override(&mut self, instrument: &str, owner: &str) {
+        if let Some(overrides) = self.mark_overrides.get_mut(instrument) {
+            overrides.retain(|existing| existing.owner != owner);
+            if overrides.is_empty() {
+                self.mark_overrides.remove(instrument);
+            }
+        }
+        self.notify_pnl(instrument);
+    }
+
+    pub fn active_mark_override(&self, instrument: &str, at: DateTime<Utc>) -> Option<&MarkOverride> {
+        self.mark_overrides
+            .get(instrument)?
+            .iter()
+            .filter(|mark_override| mark_override.is_active(at))
+            .max_by_key(|mark_override| (mark_override.priority, mark_override.set_at))
+    }
+
+    // Drop overrides that expired by the time given, returning them so the revert
+    // to the feed price can be logged
+    pub fn expire_mark_overrides(&mut self, at: DateTime<Utc>) -> Vec<MarkOverride> {
+        let mut expired = Vec::new();
+        for overrides in self.mark_overrides.values_mut() {
+            let (active, lapsed): (Vec<MarkOverride>, Vec<MarkOverride>) = overrides.drain(..).partition(|mark_override| mark_override.is_active(at));
+            *overrides = active;
+            expired.extend(lapsed);
+        }
+        self.mark_overrides.retain(|_, overrides| !overrides.is_empty());
+        let instruments: BTreeSet<String> = expired.iter().map(|mark_override| mark_override.instrument.clone()).collect();
+        for instrument in instruments {
+            self.notify_pnl(&instrument);
+        }
+        expired
+    }
+
+    // Overrides active at the time given, by instrument and then priority, with the
+    // one in effect first
+    pub fn mark_override_report(&self, at: DateTime<Utc>) -> Vec<&MarkOverride> {
+        let mut active: Vec<&MarkOverride> = self.mark_overrides
+            .values()
+            .flatten()
+            .filter(|mark_override| mark_override.is_active(at))
+            .collect();
+        active.sort_by(|a, b| a.instrument.cmp(&b.instrument).then_with(|| (b.priority, b.set_at).cmp(&(a.priority, a.set_at))));
+        active
+    }
+
+    pub fn print_mark_overrides(&self, at: DateTime<Utc>) {
+        println!("\n=== Mark Overrides at {} ===", at.format("%Y-%m-%d %H:%M"));
+        println!("{:<8} {:>10} {:>10} {:>8} {:<12} {:<17} Reason", "Symbol", "Override", "Feed", "Priority", "Owner", "Expires");
+        for mark_override in self.mark_override_report(at) {
+            let in_effect = self.active_mark_override(&mark_override.instrument, at).is_some_and(|active| std::ptr::eq(active, mark_override));
+            let feed = self.market_prices.get(&mark_override.instrument).map_or("n/a".to_string(), |price| format!("{:.2}", price));
+            println!("{:<8} {:>10.2} {:>10} {:>8} {:<12} {:<17} {}{}", mark_override.instrument, mark_override.price, feed, mark_override.priority,
+                     mark_override.owner, mark_override.expires_at.format("%Y-%m-%d %H:%M"), mark_override.reason, if in_effect { "" } else { " (superseded)" });
+        }
+    }
+
+    pub fn register_contract(&mut self, contract: DerivativeContract) {
+        self.contracts.insert(contract.instrument.clone(), contract);
+    }
+
+    // Register an option contract together with its instrument record: quoted in
+    // the underlying's currency, with the shares per contract as its multiplier
+    pub fn register_option(&mut self, contract: DerivativeContract) -> Result<(), PositionError> {
+        let terms = contract.option_terms.as_ref().ok_or_else(|| PositionError::InstrumentRule {
+            instrument: contract.instrument.clone(),
+            reason: "option contract without option terms".to_string(),
+        })?;
+        let currency = self.instrument_currency(&contract.root).to_string();
+        let instrument = Instrument::new(&contract.instrument, AssetClass::Option, &currency).multiplier(terms.multiplier);
+        self.register_instrument(instrument);
+        self.register_contract(contract);
+        Ok(())
+    }
+
+    // Add or replace an instrument record; its currency becomes the instrument's
+    // quote currency. Trades already booked keep the multiplier they were booked with.
+    pub fn register_instrument(&mut self, instrument: Instrument) {
+        self.instrument_currencies.insert(instrument.symbol.clone(), instrument.currency.clone());
+        self.instruments.insert(instrument.symbol.clone(), instrument);
+    }
+
+    pub fn instrument(&self, symbol: &str) -> Option<&Instrument> {
+        self.instruments.get(symbol)
+    }
+
+    pub fn require_registered_instruments(&mut self, required: bool) {
+        self.require_registered_instruments = required;
+    }
+
+    pub fn contract_multiplier(&self, instrument: &str) -> Decimal {
+        self.instruments.get(instrument).map_or(Decimal::from(1), |record| record.multiplier)
+    }
+
+    pub fn check_instrument(&self, instrument: &str, quantity: i32, price: Price) -> Result<(), PositionError> {
+        let rule = |reason: String| PositionError::InstrumentRule { instrument: instrument.to_string(), reason };
+        match self.instruments.get(instrument) {
+            Some(record) => record.check(quantity, price).map_err(rule),
+            None if self.require_registered_instruments => Err(rule("not in the instrument master".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_id_generator(&mut self, generator: SharedIdGenerator) {
+        self.id_generator = Some(generator);
+    }
+
+    pub fn next_trade_id(&self) -> TradeId {
+        match &self.id_generator {
+            Some(generator) => generator.lock().unwrap().next_id(),
+            None => TradeId::from(self.trades.keys().filter_map(|id| id.as_i64()).max().map_or(1, |id| id + 1)),
+        }
+    }
+
+    // Id for the next of several trades built before any of them is booked
+    pub fn following_trade_id(&self, issued: TradeId) -> TradeId {
+        match &self.id_generator {
+            Some(generator) => generator.lock().unwrap().next_id(),
+            None => TradeId(issued.0 + 1),
+        }
+    }
+
+    // Deposit (positive) or withdraw (negative) cash; returns the booked trade id
+    pub fn book_cash(&mut self, account_id: &str, currency: &str, amount: i32, date: NaiveDate) -> Result<TradeId, PositionError> {
+        let side = if amount >= 0 { Side::Buy } else { Side::Sell };
+        let trade_id = self.next_trade_id();
+        self.add_trade(Trade::new(trade_id, date, cash_instrument(currency), amount.abs(), 1.0, side).with_account(account_id))?;
+        Ok(trade_id)
+    }
+
+    pub fn cash_balance(&self, account_id: &str, currency: &str, as_of_date: NaiveDate) -> i32 {
+        self.build_account_positions_as_of(account_id, as_of_date)
+            .get(&cash_instrument(currency))
+            .map_or(0, |position| position.quantity)
+    }
+
+    pub fn deposit_cash(&mut self, account_id: &str, currency: &str, amount: i32, date: NaiveDate) -> Result<TradeId, PositionError> {
+        if amount <= 0 {
+            return Err(PositionError::InvalidQuantity(amount));
+        }
+        self.book_cash(account_id, currency, amount, date)
+    }
+
+    pub fn withdraw_cash(&mut self, account_id: &str, currency: &str, amount: i32, date: NaiveDate) -> Result<TradeId, PositionError> {
+        if amount <= 0 {
+            return Err(PositionError::InvalidQuantity(amount));
+        }
+        self.book_cash(account_id, currency, -amount, date)
+    }
+
+    // Cash per currency from everything dated on or before the date: deposits and
+    // withdrawals, trade consideration and fees, dividends, interest and, for the whole firm
+    // only, variation margin. Futures trades move no consideration, and sweep fund
+    // trades settle through the cash leg booked with them.
+    pub fn cash_ledger(&self, account_id: Option<&str>, as_of_date: NaiveDate) -> BTreeMap<String, CashAccount> {
+        let mut movements: Vec<(String, CashMovement)> = Vec::new();
+        let mut trades: Vec<&Trade> = self.trades
+            .values_between(NaiveDate::MIN, as_of_date)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .filter(|trade| account_id.is_none_or(|account_id| trade.account_id == account_id))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+        for trade in trades {
+            let movement = |kind: CashMovementKind, amount: Money| CashMovement { date: trade.trade_date, account_id: Some(trade.account_id.clone()), kind, amount };
+            if let Some(currency) = trade.instrument.strip_prefix("CASH.") {
+                let kind = if matches!(trade.side, Side::Buy) { CashMovementKind::Deposit } else { CashMovementKind::Withdrawal };
+                movements.push((currency.to_string(), movement(kind, trade.price * trade.signed_quantity())));
+                continue;
+            }
+            if self.sweep_rules.iter().any(|rule| rule.account_id == trade.account_id && rule.mmf_instrument == trade.instrument) {
+                continue;
+            }
+            let currency = self.instrument_currency(&trade.instrument).to_string();
+            if !self.is_future(&trade.instrument) && !trade.price.is_zero() {
+                let consideration = trade.notional();
+                let amount = if matches!(trade.side, Side::Buy) { -consideration } else { consideration };
+                movements.push((currency.clone(), movement(CashMovementKind::Trade(trade.trade_id), amount)));
+            }
+            if !trade.total_fees().is_zero() {
+                movements.push((currency, movement(CashMovementKind::Fees(trade.trade_id), -trade.total_fees())));
+            }
+        }
+        for payment in self.dividends.iter().filter(|payment| payment.pay_date <= as_of_date) {
+            if account_id.is_none_or(|account_id| payment.account_id == account_id) {
+                movements.push((self.instrument_currency(&payment.instrument).to_string(), CashMovement {
+                    date: payment.pay_date,
+                    account_id: Some(payment.account_id.clone()),
+                    kind: CashMovementKind::Dividend(payment.instrument.clone()),
+                    amount: payment.net,
+                }));
+            }
+        }
+        for payment in self.interest_payments.iter().filter(|payment| payment.date <= as_of_date) {
+            if account_id.is_none_or(|account_id| payment.account_id == account_id) {
+                movements.push((payment.currency.clone(), CashMovement {
+                    date: payment.date,
+                    account_id: Some(payment.account_id.clone()),
+                    kind: CashMovementKind::Interest,
+                    amount: payment.amount,
+                }));
+            }
+        }
+        if account_id.is_none() {
+            for call in self.variation_margin.range(..=as_of_date).flat_map(|(_, report)| &report.calls) {
+                movements.push((call.currency.clone(), CashMovement {
+                    date: call.date,
+                    account_id: None,
+                    kind: CashMovementKind::VariationMargin(call.instrument.clone()),
+                    amount: call.amount,
+                }));
+            }
+        }
+
+        movements.sort_by_key(|(_, movement)| movement.date);
+        let mut accounts: BTreeMap<String, CashAccount> = BTreeMap::new();
+        for (currency, movement) in movements {
+            let account = accounts.entry(currency.clone()).or_insert_with(|| CashAccount::new(&currency));
+            account.balance += movement.amount;
+            account.movements.push(movement);
+        }
+        accounts
+    }
+
+    // Cash an account can still spend: its balance less what its buys waiting for
+    // approval will cost once booked
+    pub fn buying_power(&self, account_id: &str, currency: &str, as_of_date: NaiveDate) -> Money {
+        let balance = self.cash_ledger(Some(account_id), as_of_date).get(currency).map_or(Decimal::ZERO, |account| account.balance);
+        let reserved: Money = self.held_trades
+            .values()
+            .filter(|trade| matches!(trade.status, TradeStatus::PendingApproval) && matches!(trade.side, Side::Buy))
+            .filter(|trade| trade.account_id == account_id && self.instrument_currency(&trade.instrument) == currency)
+            .map(|trade| trade.notional() + trade.total_fees())
+            .sum();
+        balance - reserved
+    }
+
+    // Total equity as cash plus the market value of positions at the date's close,
+    // or their cost without one. Futures are worth their P&L since the last
+    // settlement, as the rest has been paid in variation margin.
+    pub fn portfolio_equity(&self, account_id: Option<&str>, as_of_date: NaiveDate) -> Result<PortfolioEquity, String> {
+        let mut equity = PortfolioEquity {
+            as_of_date,
+            firm_currency: self.firm_currency.clone(),
+            cash: self.cash_ledger(account_id, as_of_date).into_iter().map(|(currency, account)| (currency, account.balance)).collect(),
+            market_value: BTreeMap::new(),
+            total: Decimal::ZERO,
+        };
+        let positions = match account_id {
+            Some(account_id) => self.build_account_positions_as_of(account_id, as_of_date),
+            None => self.build_position_map_as_of_date(as_of_date),
+        };
+        for (instrument, position) in positions.iter().filter(|(instrument, position)| position.quantity != 0 && !is_cash_instrument(instrument)) {
+            let price = self.valuation_price(instrument, as_of_date, position);
+            let value = if self.is_future(instrument) { position.unrealized_pnl(price) } else { position.market_value(price) };
+            *equity.market_value.entry(self.instrument_currency(instrument).to_string()).or_insert(Decimal::ZERO) += value;
+        }
+        for (currency, amount) in equity.cash.iter().chain(&equity.market_value) {
+            equity.total += *amount * self.fx_rates.rate_on(currency, as_of_date)?;
+        }
+        Ok(equity)
+    }
+
+    pub fn print_cash_ledger(&self, account_id: Option<&str>, as_of_date: NaiveDate) {
+        for account in self.cash_ledger(account_id, as_of_date).values() {
+            println!("{} cash: {:.2} (credits {:.2}, debits {:.2})", account.currency, account.balance, account.credits(), account.debits());
+            for movement in &account.movements {
+                println!("  {} {:<8} {}: {:.2}", movement.date, movement.account_id.as_deref().unwrap_or("-"), movement.kind, movement.amount);
+            }
+        }
+    }
+
+    // Interest the broker paid on (or charged to) the account's cash
+    pub fn record_interest(&mut self, account_id: &str, currency: &str, date: NaiveDate, amount: impl Into<Money>) {
+        self.interest_payments.push(InterestPayment {
+            account_id: account_id.to_string(),
+            currency: currency.to_string(),
+            date,
+            amount: amount.into(),
+        });
+    }
+
+    // Broker cash statement as CSV lines of
+    // `activity_id,date,account,currency,type,amount[,reference]`, where type is one of
+    // deposit, withdrawal, trade, fee, dividend or interest. A header line is skipped;
+    // nothing is imported if any line is bad.
+    pub fn import_broker_cash_csv(&mut self, csv: &str) -> Result<usize, PositionError> {
+        let mut activity = Vec::new();
+        for (line_number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || (line_number == 0 && line.starts_with("activity_id")) {
+                continue;
+            }
+
+            let invalid = |reason: String| PositionError::InvalidRecord(format!("line {}: {}", line_number + 1, reason));
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 6 && fields.len() != 7 {
+                return Err(invalid("expected activity_id,date,account,currency,type,amount[,reference]".to_string()));
+            }
+            let date = NaiveDate::parse_from_str(fields[1], "%Y-%m-%d").map_err(|e| invalid(format!("invalid date {}: {}", fields[1], e)))?;
+            let kind = fields[4].to_lowercase();
+            if !["deposit", "withdrawal", "trade", "fee", "dividend", "interest"].contains(&kind.as_str()) {
+                return Err(invalid(format!("unknown type {}", fields[4])));
+            }
+            let amount = Decimal::parse(fields[5]).map_err(|e| invalid(format!("invalid amount {}: {}", fields[5], e)))?;
+            activity.push(BrokerCashActivity {
+                activity_id: fields[0].to_string(),
+                account_id: fields[2].to_string(),
+                currency: fields[3].to_string(),
+                date,
+                kind,
+                amount,
+                reference: fields.get(6).filter(|reference| !reference.is_empty()).map(|reference| reference.to_string()),
+            });
+        }
+        let imported = activity.len();
+        self.broker_cash_activity.extend(activity);
+        Ok(imported)
+    }
+
+    // Pair the broker's cash lines for an account with the account's cash ledger, like
+    // confirmation matching: each line is compared with unclaimed movements of the
+    // same type and currency within the date window (and the same trade or instrument
+    // when the line has a reference). The closest one within the amount tolerance is
+    // matched, otherwise the closest one is a break. Everything left over is unmatched,
+    // aged against `as_of_date`.
+    pub fn reconcile_cash(&self, account_id: &str, as_of_date: NaiveDate, tolerance: &CashMatchTolerance) -> CashReconciliation {
+        let ledger = self.cash_ledger(Some(account_id), as_of_date);
+        let movements: Vec<(&str, &CashMovement)> = ledger
+            .iter()
+            .flat_map(|(currency, account)| account.movements.iter().map(move |movement| (currency.as_str(), movement)))
+            .collect();
+        let mut activity: Vec<&BrokerCashActivity> = self.broker_cash_activity
+            .iter()
+            .filter(|line| line.account_id == account_id && line.date <= as_of_date)
+            .collect();
+        activity.sort_by(|a, b| a.date.cmp(&b.date).then(a.activity_id.cmp(&b.activity_id)));
+
+        let mut report = CashReconciliation {
+            account_id: account_id.to_string(),
+            as_of_date,
+            matched: Vec::new(),
+            breaks: Vec::new(),
+            unmatched_movements: Vec::new(),
+            unmatched_activity: Vec::new(),
+            balance_differences: BTreeMap::new(),
+        };
+        let mut claimed = vec![false; movements.len()];
+
+        for line in activity {
+            let same_key = |movement: &CashMovement| {
+                let (kind, reference) = match &movement.kind {
+                    CashMovementKind::Deposit => ("deposit", None),
+                    CashMovementKind::Withdrawal => ("withdrawal", None),
+                    CashMovementKind::Trade(trade_id) => ("trade", Some(trade_id.to_string())),
+                    CashMovementKind::Fees(trade_id) => ("fee", Some(trade_id.to_string())),
+                    CashMovementKind::Dividend(instrument) => ("dividend", Some(instrument.clone())),
+                    CashMovementKind::Interest => ("interest", None),
+                    CashMovementKind::VariationMargin(_) => ("variation_margin", None),
+                };
+                kind == line.kind
+                    && (movement.date - line.date).num_days().abs() <= tolerance.days
+                    && line.reference.as_ref().is_none_or(|expected| reference.as_ref() == Some(expected))
+            };
+            let distance = |movement: &CashMovement| ((movement.amount - line.amount).abs(), (movement.date - line.date).num_days().abs());
+
+            let mut candidates: Vec<usize> = (0..movements.len())
+                .filter(|index| !claimed[*index] && movements[*index].0 == line.currency && same_key(movements[*index].1))
+                .collect();
+            candidates.sort_by_key(|index| distance(movements[*index].1));
+            match candidates.first() {
+                Some(&index) => {
+                    claimed[index] = true;
+                    let movement = movements[index].1;
+                    if (movement.amount - line.amount).abs() <= tolerance.amount {
+                        report.matched.push((line.activity_id.clone(), movement.clone()));
+                    } else {
+                        report.breaks.push(CashBreak {
+                            activity_id: line.activity_id.clone(),
+                            movement: movement.clone(),
+                            broker_amount: line.amount,
+                            age_days: (as_of_date - movement.date.min(line.date)).num_days(),
+                        });
+                    }
+                },
+                None => report.unmatched_activity.push((line.clone(), (as_of_date - line.date).num_days())),
+            }
+        }
+
+        report.unmatched_movements = movements
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !claimed[*index])
+            .map(|(_, (_, movement))| ((*movement).clone(), (as_of_date - movement.date).num_days()))
+            .collect();
+        for (currency, account) in &ledger {
+            report.balance_differences.insert(currency.clone(), account.balance);
+        }
+        for line in self.broker_cash_activity.iter().filter(|line| line.account_id == account_id && line.date <= as_of_date) {
+            *report.balance_differences.entry(line.currency.clone()).or_insert(Decimal::ZERO) -= line.amount;
+        }
+        report
+    }
+
+    pub fn print_cash_reconciliation(&self, account_id: &str, as_of_date: NaiveDate, tolerance: &CashMatchTolerance) {
+        let report = self.reconcile_cash(account_id, as_of_date, tolerance);
+        println!("Cash reconciliation of {} as of {}: {} matched", report.account_id, report.as_of_date, report.matched.len());
+        for item in &report.breaks {
+            println!("  Break {} vs {} on {}: ours {:.2}, broker {:.2} ({} days)",
+                     item.activity_id, item.movement.kind, item.movement.date, item.movement.amount, item.broker_amount, item.age_days);
+        }
+        for (movement, age_days) in &report.unmatched_movements {
+            println!("  Not on statement: {} {} {:.2} ({} days)", movement.date, movement.kind, movement.amount, age_days);
+        }
+        for (line, age_days) in &report.unmatched_activity {
+            println!("  Not in ledger: {} {} {} {} {:.2} ({} days)", line.activity_id, line.date, line.kind, line.currency, line.amount, age_days);
+        }
+        for (bucket, count, amount) in report.aging() {
+            println!("  {:<13} {:>3} items {:>12.2}", bucket, count, amount);
+        }
+        for (currency, difference) in &report.balance_differences {
+            println!("  {} balance difference: {:.2}", currency, difference);
+        }
+    }
+
+    pub fn add_sweep_rule(&mut self, rule: SweepRule) -> Result<(), PositionError> {
+        self.update_market_price(&rule.mmf_instrument, 1.0)?;
+        self.sweep_rules.push(rule);
+        Ok(())
+    }
+
+    // End-of-day sweeps for the date: each rule moves its account's cash back to the
+    // target balance through the fund, redeeming no more units than the account holds
+    pub fn run_sweeps(&mut self, date: NaiveDate) -> Result<Vec<SweepEvent>, PositionError> {
+        let mut events = Vec::new();
+        for rule in self.sweep_rules.clone() {
+            let positions = self.build_account_positions_as_of(&rule.account_id, date);
+            let cash = positions.get(&cash_instrument(&rule.currency)).map_or(0, |position| position.quantity);
+            let units = positions.get(&rule.mmf_instrument).map_or(0, |position| position.quantity);
+            let amount = (cash - rule.target_balance).max(-units);
+            if amount == 0 {
+                continue;
+            }
+
+            let (cash_side, fund_side) = if amount > 0 { (Side::Sell, Side::Buy) } else { (Side::Buy, Side::Sell) };
+            let cash_trade_id = self.next_trade_id();
+            self.add_trade(Trade::new(cash_trade_id, date, cash_instrument(&rule.currency), amount.abs(), 1.0, cash_side).with_account(&rule.account_id))?;
+            let fund_trade_id = self.next_trade_id();
+            self.add_trade(Trade::new(fund_trade_id, date, rule.mmf_instrument.clone(), amount.abs(), 1.0, fund_side).with_account(&rule.account_id))?;
+            events.push(SweepEvent {
+                date,
+                account_id: rule.account_id.clone(),
+                mmf_instrument: rule.mmf_instrument.clone(),
+                amount,
+                cash_trade_id,
+                fund_trade_id,
+            });
+        }
+        Ok(events)
+    }
+
+    // Income earned over the date range, inclusive. Sweep interest accrues each
+    // calendar day on the fund units held at that day's close.
+    pub fn income_report(&self, start_date: NaiveDate, end_date: NaiveDate) -> IncomeReport {
+        let mut rows = Vec::new();
+        for rule in &self.sweep_rules {
+            let mut trades: Vec<&Trade> = self.trades
+                .values()
+                .filter(|trade| trade.account_id == rule.account_id && trade.instrument == rule.mmf_instrument)
+                .filter(|trade| trade.trade_date <= end_date && !matches!(trade.status, TradeStatus::Cancelled))
+                .collect();
+            trades.sort_by_key(|trade| trade.chronological_key());
+
+            let mut trades = trades.into_iter().peekable();
+            let mut units: i64 = 0;
+            let mut unit_days: i64 = 0;
+            let mut days: i64 = 0;
+            let mut date = start_date;
+            while date <= end_date {
+                while let Some(trade) = trades.next_if(|trade| trade.trade_date <= date) {
+                    units += trade.signed_quantity() as i64;
+                }
+                unit_days += units;
+                days += 1;
+                match date.succ_opt() {
+                    Some(next_date) => date = next_date,
+                    None => break,
+                }
+            }
+            if unit_days == 0 {
+                continue;
+            }
+
+            let policy = self.rounding_policy(&rule.mmf_instrument);
+            rows.push(IncomeRow {
+                account_id: rule.account_id.clone(),
+                instrument: rule.mmf_instrument.clone(),
+                source: IncomeSource::SweepInterest,
+                average_balance: policy.round_money(Decimal::from(unit_days) / Decimal::from(days)),
+                amount: policy.round_money(Decimal::from(unit_days) * (rule.annual_yield / 365.0)),
+                withheld: Decimal::ZERO,
+            });
+        }
+        for payment in self.dividends.iter().filter(|payment| payment.pay_date >= start_date && payment.pay_date <= end_date) {
+            rows.push(IncomeRow {
+                account_id: payment.account_id.clone(),
+                instrument: payment.instrument.clone(),
+                source: IncomeSource::Dividend,
+                average_balance: Decimal::from(payment.quantity),
+                amount: payment.net,
+                withheld: payment.withheld,
+            });
+        }
+
+        IncomeReport {
+            start_date,
+            end_date,
+            total: rows.iter().map(|row| row.amount).sum(),
+            rows,
+        }
+    }
+
+    // Credit a cash dividend to every account holding the instrument at the close
+    // before its ex-date. Long holders suffer the market's withholding; short
+    // holders pay the gross amount.
+    pub fn record_dividend(&mut self, instrument: &str, ex_date: NaiveDate, pay_date: NaiveDate, amount_per_share: impl Into<Money>) -> Result<Vec<DividendPayment>, PositionError> {
+        let amount_per_share = amount_per_share.into();
+        if amount_per_share.is_negative() || amount_per_share.is_zero() {
+            return Err(PositionError::InvalidPrice { instrument: instrument.to_string(), price: amount_per_share });
+        }
+        let record_date = ex_date.pred_opt().unwrap_or(ex_date);
+        let mut holdings: BTreeMap<String, i32> = BTreeMap::new();
+        for trade in self.trades.instrument_values_between(instrument, NaiveDate::MIN, record_date) {
+            if !matches!(trade.status, TradeStatus::Cancelled) {
+                *holdings.entry(trade.account_id.clone()).or_default() += trade.signed_quantity();
+            }
+        }
+        let withholding = self.tax_market(instrument).map_or(0.0, |market| market.dividend_withholding);
+        let policy = self.rounding_policy(instrument);
+
+        let mut payments = Vec::new();
+        for (account_id, quantity) in holdings.into_iter().filter(|(_, quantity)| *quantity != 0) {
+            let gross = policy.round_money(amount_per_share * quantity);
+            let withheld = if quantity > 0 { policy.round_money(gross * withholding) } else { Decimal::ZERO };
+            payments.push(DividendPayment {
+                account_id,
+                instrument: instrument.to_string(),
+                ex_date,
+                pay_date,
+                quantity,
+                gross,
+                withheld,
+                net: gross - withheld,
+            });
+        }
+        self.dividends.extend(payments.iter().cloned());
+        Ok(payments)
+    }
+
+    // Apply a split, cash dividend or ticker change to the book. Each is checked
+    // before anything is changed, and kept with what it changed. The action is one
+    // transaction: if any restated trade is refused the book is left as it was.
+    pub fn apply_corporate_action(&mut self, action: CorporateAction) -> Result<CorporateActionResult, PositionError> {
+        self.transaction(|tx| tx.repo.apply_corporate_action_changes(action))
+    }
+
+    fn apply_corporate_action_changes(&mut self, action: CorporateAction) -> Result<CorporateActionResult, PositionError> {
+        let instrument = action.instrument.clone();
+        let date = action.effective_date;
+        let rule = |reason: &str| PositionError::InstrumentRule { instrument: instrument.clone(), reason: reason.to_string() };
+        let mut result = CorporateActionResult { action: action.clone(), restated_trades: Vec::new(), booked_trades: Vec::new(), dividends: Vec::new() };
+
+        match &action.kind {
+            CorporateActionKind::Split { new_shares, old_shares, restate_history } => {
+                let (new_shares, old_shares) = (*new_shares, *old_shares);
+                if new_shares <= 0 || old_shares <= 0 || new_shares == old_shares {
+                    return Err(rule("split ratio must be two different positive share counts"));
+                }
+                let policy = self.rounding_policy(&instrument);
+                let adjust_price = |price: Price| policy.round_price(price * old_shares / new_shares);
+                if *restate_history {
+                    let restated: Vec<Trade> = self.trades
+                        .instrument_values_between(&instrument, NaiveDate::MIN, date.pred_opt().unwrap_or(date))
+                        .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+                        .cloned()
+                        .collect();
+                    if restated.iter().any(|trade| trade.quantity as i64 * new_shares as i64 % old_shares as i64 != 0) {
+                        return Err(rule("split leaves a trade with a fractional quantity"));
+                    }
+                    let restated: Vec<Trade> = restated.into_iter().map(|trade| Trade {
+                        quantity: (trade.quantity as i64 * new_shares as i64 / old_shares as i64) as i32,
+                        price: adjust_price(trade.price),
+                        arrival_price: trade.arrival_price.map(adjust_price),
+                        ..trade
+                    }).collect();
+                    result.restated_trades = restated.iter().map(|trade| trade.trade_id).collect();
+                    self.replace_trades(restated)?;
+                } else {
+                    // Bonus shares at no cost: the holding grows and its average price falls
+                    if new_shares < old_shares {
+                        return Err(rule("reverse splits must restate history"));
+                    }
+                    let mut holdings: BTreeMap<String, i32> = BTreeMap::new();
+                    for trade in self.trades.instrument_values_between(&instrument, NaiveDate::MIN, date.pred_opt().unwrap_or(date)) {
+                        if !matches!(trade.status, TradeStatus::Cancelled) {
+                            *holdings.entry(trade.account_id.clone()).or_default() += trade.signed_quantity();
+                        }
+                    }
+                    holdings.retain(|_, quantity| *quantity != 0);
+                    if holdings.values().any(|quantity| *quantity as i64 * (new_shares - old_shares) as i64 % old_shares as i64 != 0) {
+                        return Err(rule("split leaves a holding with a fractional quantity"));
+                    }
+                    for (account_id, quantity) in holdings {
+                        let bonus = (quantity.abs() as i64 * (new_shares - old_shares) as i64 / old_shares as i64) as i32;
+                        let side = if quantity > 0 { Side::Buy } else { Side::Sell };
+                        let trade_id = self.next_trade_id();
+                        let mut trade = Trade::new(trade_id, date, instrument.clone(), bonus, 0.0, side).with_account(&account_id);
+                        trade.multiplier = self.contract_multiplier(&instrument);
+                        self.book_trade(trade)?;
+                        result.booked_trades.push(trade_id);
+                    }
+                }
+                let closes: Vec<(NaiveDate, Price)> = self.close_prices.range(&instrument, NaiveDate::MIN, date)
+                    .filter(|(close_date, _)| *close_date < date)
+                    .map(|(close_date, price)| (close_date, adjust_price(price)))
+                    .collect();
+                self.close_prices.extend(&instrument, closes);
+                // The live price is taken to be the last pre-split quote
+                if let Some(price) = self.market_prices.get(&instrument).copied() {
+                    self.market_prices.insert(instrument.clone(), adjust_price(price));
+                }
+            },
+            CorporateActionKind::CashDividend { amount_per_share, pay_date, credit } => {
+                let payments = self.record_dividend(&instrument, date, *pay_date, *amount_per_share)?;
+                if matches!(credit, DividendCredit::RealizedPnl) {
+                    let amount: Money = payments.iter().map(|payment| payment.net).sum();
+                    self.position_adjustments.entry(instrument.clone()).or_default().entry(date).or_default().push(PositionAdjustment::RealizedCredit(amount));
+                    self.rebuild_position(&instrument, date);
+                }
+                result.dividends = payments;
+            },
+            CorporateActionKind::SymbolChange { new_symbol } => {
+                if *new_symbol == instrument {
+                    return Err(rule("new symbol is the current symbol"));
+                }
+                if self.trades.instrument_values(new_symbol).next().is_some() || self.positions.contains_key(new_symbol) {
+                    return Err(rule(&format!("{} is already traded", new_symbol)));
+                }
+                if self.contracts.contains_key(&instrument) {
+                    return Err(rule("derivative contracts are not renamed"));
+                }
+                // Reference data moves first, so the moved trades rebuild under it
+                rekey(&mut self.market_prices, &instrument, new_symbol);
+                self.close_prices.rename(&instrument, new_symbol);
+                self.superseded_closes.rename(&instrument, new_symbol);
+                rekey(&mut self.book_snapshots, &instrument, new_symbol);
+                rekey(&mut self.mark_methods, &instrument, new_symbol);
+                rekey(&mut self.mark_overrides, &instrument, new_symbol);
+                for mark_override in self.mark_overrides.get_mut(new_symbol).into_iter().flatten() {
+                    mark_override.instrument = new_symbol.to_string();
+                }
+                rekey(&mut self.price_ticks, &instrument, new_symbol);
+                rekey(&mut self.instrument_currencies, &instrument, new_symbol);
+                rekey(&mut self.booked_currencies, &instrument, new_symbol);
+                rekey(&mut self.instrument_sectors, &instrument, new_symbol);
+                rekey(&mut self.instrument_tags, &instrument, new_symbol);
+                rekey(&mut self.instrument_sessions, &instrument, new_symbol);
+                rekey(&mut self.instrument_markets, &instrument, new_symbol);
+                rekey(&mut self.settlement_calendars, &instrument, new_symbol);
+                rekey(&mut self.position_adjustments, &instrument, new_symbol);
+                rekey(&mut self.average_daily_volumes, &instrument, new_symbol);
+                self.adv_history.rename(&instrument, new_symbol);
+                rekey(&mut self.sanity_bands, &instrument, new_symbol);
+                rekey(&mut self.implied_volatilities, &instrument, new_symbol);
+                rekey(&mut self.durations, &instrument, new_symbol);
+                self.betas.rename(&instrument, new_symbol);
+                self.borrow_rates.rename(&instrument, new_symbol);
+                rekey(&mut self.rounding_policies, &instrument, new_symbol);
+                rekey(&mut self.fee_schedules, &instrument, new_symbol);
+                rekey(&mut self.cost_basis_methods, &instrument, new_symbol);
+                if let Some(mut record) = self.instruments.remove(&instrument) {
+                    record.symbol = new_symbol.clone();
+                    self.instruments.insert(new_symbol.clone(), record);
+                }
+
+                let mut moved: Vec<Trade> = self.trades.instrument_values(&instrument).cloned().collect();
+                moved.sort_by_key(|trade| trade.chronological_key());
+                result.restated_trades = moved.iter().map(|trade| trade.trade_id).collect();
+                let (cancelled, active): (Vec<Trade>, Vec<Trade>) = moved
+                    .into_iter()
+                    .map(|trade| Trade { instrument: new_symbol.clone(), ..trade })
+                    .partition(|trade| matches!(trade.status, TradeStatus::Cancelled));
+                for trade in cancelled {
+                    self.update_trade_details(trade)?;
+                }
+                self.replace_trades(active)?;
+                self.positions.remove(&instrument);
+                self.daily_positions.remove(&instrument);
+                self.daily_position_marks.remove(&instrument);
+                self.fold_checkpoints.remove(&instrument);
+
+                self.renamed_symbols.remove(new_symbol);
+                for current in self.renamed_symbols.values_mut().filter(|current| **current == instrument) {
+                    *current = new_symbol.clone();
+                }
+                self.renamed_symbols.insert(instrument.clone(), new_symbol.clone());
+            },
+        }
+        if let Some(snapshots) = &mut self.position_snapshots {
+            snapshots.invalidate_from(date);
+        }
+        self.corporate_actions.push(result.clone());
+        Ok(result)
+    }
+
+    pub fn print_corporate_actions(&self) {
+        for result in &self.corporate_actions {
+            let action = &result.action;
+            let description = match &action.kind {
+                CorporateActionKind::Split { new_shares, old_shares, restate_history } => {
+                    format!("{}-for-{} split{}", new_shares, old_shares, if *restate_history { ", history restated" } else { "" })
+                },
+                CorporateActionKind::CashDividend { amount_per_share, pay_date, credit } => {
+                    format!("dividend {} per share paid {} to {:?}", amount_per_share, pay_date, credit)
+                },
+                CorporateActionKind::SymbolChange { new_symbol } => format!("renamed to {}", new_symbol),
+            };
+            println!("{} {}: {}", action.effective_date, action.instrument, description);
+            if !result.restated_trades.is_empty() {
+                println!("  Restated trades: {:?}", result.restated_trades);
+            }
+            if !result.booked_trades.is_empty() {
+                println!("  Booked trades: {:?}", result.booked_trades);
+            }
+            for payment in &result.dividends {
+                println!("  {} {} shares: gross {:.2}, withheld {:.2}, net {:.2}", payment.account_id, payment.quantity, payment.gross, payment.withheld, payment.net);
+            }
+        }
+    }
+
+    // Commissions, exchange fees and each transaction tax per instrument for trades
+    // dated in the range, with the dividends paid in it and the tax withheld from them
+    pub fn fee_tax_report(&self, start_date: NaiveDate, end_date: NaiveDate) -> FeeTaxReport {
+        let mut rows: BTreeMap<String, FeeTaxRow> = BTreeMap::new();
+        let row = |rows: &mut BTreeMap<String, FeeTaxRow>, instrument: &str| -> FeeTaxRow {
+            rows.remove(instrument).unwrap_or_else(|| FeeTaxRow {
+                instrument: instrument.to_string(),
+                market: self.instrument_markets.get(instrument).cloned(),
+                commission: Decimal::ZERO,
+                exchange_fees: Decimal::ZERO,
+                transaction_taxes: BTreeMap::new(),
+                dividends_gross: Decimal::ZERO,
+                dividends_withheld: Decimal::ZERO,
+            })
+        };
+
+        let trades = self.trades.values_between(start_date, end_date).filter(|trade| !matches!(trade.status, TradeStatus::Cancelled));
+        for trade in trades {
+            let mut entry = row(&mut rows, &trade.instrument);
+            entry.commission += trade.commission;
+            entry.exchange_fees += trade.exchange_fee;
+            // Same precedence as booking: a schedule's own tax rules replace the market's
+            let policy = self.rounding_policy(&trade.instrument);
+            let scheduled_tax = self.fee_schedule(&trade.instrument).is_some_and(|schedule| schedule.has_rules(FeeType::Tax));
+            let charges = match self.tax_market(&trade.instrument) {
+                Some(market) if !scheduled_tax => market.charges(trade),
+                _ => Vec::new(),
+            };
+            let mut explained = Decimal::ZERO;
+            for (name, amount) in charges {
+                let amount = policy.round_money(amount);
+                explained += amount;
+                *entry.transaction_taxes.entry(name).or_insert(Decimal::ZERO) += amount;
+            }
+            if trade.tax != explained {
+                *entry.transaction_taxes.entry("Other".to_string()).or_insert(Decimal::ZERO) += trade.tax - explained;
+            }
+            rows.insert(trade.instrument.clone(), entry);
+        }
+        for payment in self.dividends.iter().filter(|payment| payment.pay_date >= start_date && payment.pay_date <= end_date) {
+            let mut entry = row(&mut rows, &payment.instrument);
+            entry.dividends_gross += payment.gross;
+            entry.dividends_withheld += payment.withheld;
+            rows.insert(payment.instrument.clone(), entry);
+        }
+
+        FeeTaxReport {
+            start_date,
+            end_date,
+            rows: rows.into_values().collect(),
+        }
+    }
+
+    pub fn print_fee_tax_report(&self, start_date: NaiveDate, end_date: NaiveDate) {
+        let report = self.fee_tax_report(start_date, end_date);
+        println!("\n=== Fees and Taxes {} to {} ===", report.start_date, report.end_date);
+        for row in &report.rows {
+            let taxes: Vec<String> = row.transaction_taxes.iter().map(|(name, amount)| format!("{} ${:.2}", name, amount)).collect();
+            println!("{} ({}): commission ${:.2}, exchange fees ${:.2}, taxes [{}], dividends ${:.2} gross / ${:.2} withheld",
+                     row.instrument, row.market.as_deref().unwrap_or("-"), row.commission, row.exchange_fees, taxes.join(", "), row.dividends_gross, row.dividends_withheld);
+        }
+    }
+
+    pub fn print_income_report(&self, start_date: NaiveDate, end_date: NaiveDate) {
+        let report = self.income_report(start_date, end_date);
+        println!("\n=== Income {} to {} ===", report.start_date, report.end_date);
+        for row in &report.rows {
+            let withheld = if row.withheld.is_zero() { String::new() } else { format!(", ${:.2} withheld", row.withheld) };
+            println!("{} {} {:?}: ${:.2} (average balance {:.2}{})", row.account_id, row.instrument, row.source, row.amount, row.average_balance, withheld);
+        }
+        println!("Total income: ${:.2}", report.total);
+    }
+
+    // Expire every contract whose expiry date is on or before `as_of_date`: open
+    // positions are closed at the settlement price (realizing P&L) and, if the
+    // roll rule says so, re-opened in the next contract. Options close at their
+    // intrinsic value against the underlying's settlement price (or its market
+    // price) and, under RollRule::Exercise, deliver into the underlying. Closing
+    // and opening trades are booked like any other trade and every action is logged.
+    pub fn process_expiries(&mut self, as_of_date: NaiveDate, settlement_prices: &HashMap<String, Price>) -> Vec<LifecycleEvent> {
+        let mut expiring: Vec<DerivativeContract> = self.contracts
+            .values()
+            .filter(|contract| contract.expiry_date <= as_of_date)
+            .filter(|contract| self.get_position(&contract.instrument).is_some_and(|position| position.quantity != 0))
+            .cloned()
+            .collect();
+        expiring.sort_by(|a, b| a.expiry_date.cmp(&b.expiry_date).then(a.instrument.cmp(&b.instrument)));
+
+        let mut events = Vec::new();
+        for contract in expiring {
+            let underlying_price = contract.option_terms.as_ref()
+                .and_then(|_| settlement_prices.get(&contract.root).copied().or_else(|| self.get_market_price(&contract.root)));
+            let settlement_price = match &contract.option_terms {
+                Some(terms) => underlying_price.map(|spot| terms.intrinsic_value(spot)),
+                None => settlement_prices.get(&contract.instrument).copied(),
+            };
+            let settlement_price = match settlement_price {
+                Some(price) => price,
+                None => {
+                    let reason = match &contract.option_terms {
+                        Some(_) => format!("No settlement price for underlying {}", contract.root),
+                        None => "No settlement price".to_string(),
+                    };
+                    events.push(LifecycleEvent::Skipped {
+                        date: contract.expiry_date,
+                        instrument: contract.instrument.clone(),
+                        reason,
+                    });
+                    continue;
+                }
+            };
+
+            let position = self.get_position(&contract.instrument).unwrap();
+            let quantity = position.quantity;
+            let realized_before = position.realized_pnl;
+            let closing_side = if quantity > 0 { Side::Sell } else { Side::Buy };
+            let closing_trade_id = self.next_trade_id();
+            if let Err(e) = self.add_trade(Trade::new(closing_trade_id, contract.expiry_date, contract.instrument.clone(), quantity.abs(), settlement_price, closing_side)) {
+                events.push(LifecycleEvent::Skipped {
+                    date: contract.expiry_date,
+                    instrument: contract.instrument.clone(),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+
+            events.push(LifecycleEvent::Expired {
+                date: contract.expiry_date,
+                instrument: contract.instrument.clone(),
+                quantity,
+                settlement_price,
+                realized_pnl: self.get_position(&contract.instrument).unwrap().realized_pnl - realized_before,
+                closing_trade_id,
+            });
+
+            if let (RollRule::Exercise, Some(terms), Some(spot)) = (&contract.roll_rule, &contract.option_terms, underlying_price) {
+                if settlement_price > Decimal::ZERO {
+                    // Long calls and short puts take delivery; long puts and short calls deliver
+                    let side = match (terms.right, quantity > 0) {
+                        (OptionRight::Call, true) | (OptionRight::Put, false) => Side::Buy,
+                        _ => Side::Sell,
+                    };
+                    let shares = (quantity.abs() as f64 * terms.multiplier).round() as i32;
+                    let opening_trade_id = self.next_trade_id();
+                    let delivery = Trade::new(opening_trade_id, contract.expiry_date, contract.root.clone(), shares, spot, side.clone());
+                    match self.add_trade(delivery) {
+                        Ok(_) => events.push(LifecycleEvent::Exercised {
+                            date: contract.expiry_date,
+                            instrument: contract.instrument.clone(),
+                            underlying: contract.root.clone(),
+                            quantity: if matches!(side, Side::Buy) { shares } else { -shares },
+                            price: spot,
+                            opening_trade_id,
+                        }),
+                        Err(e) => events.push(LifecycleEvent::Skipped {
+                            date: contract.expiry_date,
+                            instrument: contract.instrument.clone(),
+                            reason: e.to_string(),
+                        }),
+                    }
+                }
+            }
+
+            if let RollRule::RollInto(next_instrument) = &contract.roll_rule {
+                let roll_price = settlement_prices.get(next_instrument).copied()
+                    .or_else(|| self.get_market_price(next_instrument));
+                let opening_trade_id = self.next_trade_id();
+                let opened = roll_price.ok_or(format!("No price to roll into {}", next_instrument)).and_then(|price| {
+                    let opening_side = if quantity > 0 { Side::Buy } else { Side::Sell };
+                    self.add_trade(Trade::new(opening_trade_id, contract.expiry_date, next_instrument.clone(), quantity.abs(), price, opening_side))
+                        .map(|_| price)
+                        .map_err(|e| e.to_string())
+                });
+                match opened {
+                    Ok(price) => {
+                        events.push(LifecycleEvent::Rolled {
+                            date: contract.expiry_date,
+                            from_instrument: contract.instrument.clone(),
+                            to_instrument: next_instrument.clone(),
+                            quantity,
+                            price,
+                            opening_trade_id,
+                        });
+                    },
+                    Err(reason) => events.push(LifecycleEvent::Skipped {
+                        date: contract.expiry_date,
+                        instrument: contract.instrument.clone(),
+                        reason,
+                    }),
+                }
+            }
+        }
+
+        self.event_log.extend(events.iter().cloned());
+        events
+    }
+
+    pub fn set_settlement_calendar(&mut self, instrument: &str, calendar: SettlementCalendar) {
+        self.settlement_calendars.insert(instrument.to_string(), calendar);
+    }
+
+    pub fn is_future(&self, instrument: &str) -> bool {
+        self.instruments.get(instrument).is_some_and(|record| matches!(record.asset_class, AssetClass::Future))
+            || self.contracts.get(instrument).is_some_and(|contract| matches!(contract.kind, ContractKind::Future))
+    }
+
+    // Daily settlement of every open futures position: the variation margin against
+    // the settlement price is realized and the position carried at that price, so
+    // futures accrue no unrealized P&L from one day to the next. Positions without a
+    // price, on a day their calendar does not settle, or already settled on or after
+    // the date are skipped. Settlements are kept and replayed whenever the position
+    // is rebuilt, and trades booked for a settled day rebuild it.
+    pub fn settle_futures(&mut self, date: NaiveDate, settlement_prices: &HashMap<String, Price>) -> VariationMarginReport {
+        let instruments: Vec<String> = self.positions
+            .values()
+            .filter(|position| position.quantity != 0 && self.is_future(&position.instrument))
+            .map(|position| position.instrument.clone())
+            .collect();
+
+        let mut report = VariationMarginReport { date, calls: Vec::new(), skipped: Vec::new() };
+        for instrument in instruments {
+            let settles = match self.settlement_calendars.get(&instrument) {
+                Some(calendar) => calendar.is_settlement_day(date),
+                None => !matches!(date.weekday(), Weekday::Sat | Weekday::Sun),
+            };
+            if !settles {
+                report.skipped.push((instrument, "Not a settlement day".to_string()));
+                continue;
+            }
+            let last_settled = self.position_adjustments.get(&instrument).and_then(|dates| {
+                dates.iter().rev().find(|(_, list)| list.iter().any(|adjustment| matches!(adjustment, PositionAdjustment::Settlement(_)))).map(|(date, _)| *date)
+            });
+            if let Some(last) = last_settled.filter(|last| *last >= date) {
+                report.skipped.push((instrument, format!("Already settled on {}", last)));
+                continue;
+            }
+            let Some(settlement_price) = settlement_prices.get(&instrument).copied() else {
+                report.skipped.push((instrument, "No settlement price".to_string()));
+                continue;
+            };
+
+            let position = self.positions.get_mut(&instrument).unwrap();
+            let (quantity, previous_price) = (position.quantity, position.average_price);
+            let amount = position.settle_at(settlement_price);
+            self.daily_positions.entry(instrument.clone()).or_default().insert(date, position.clone());
+            if let Some(checkpoints) = self.fold_checkpoints.get_mut(&instrument) {
+                checkpoints.undo.clear();
+            }
+            self.position_adjustments.entry(instrument.clone()).or_default().entry(date).or_default().push(PositionAdjustment::Settlement(settlement_price));
+            let mark = self.daily_position_marks.entry(instrument.clone()).or_insert(adjusted_mark(date));
+            *mark = (*mark).max(adjusted_mark(date));
+            report.calls.push(VariationMargin {
+                date,
+                currency: self.instrument_currency(&instrument).to_string(),
+                instrument,
+                quantity,
+                previous_price,
+                settlement_price,
+                amount,
+            });
+        }
+        if let Some(snapshots) = &mut self.position_snapshots {
+            snapshots.invalidate_from(date);
+        }
+        self.variation_margin.insert(date, report.clone());
+        report
+    }
+
+    pub fn variation_margin_reports(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<&VariationMarginReport> {
+        self.variation_margin.range(start_date..=end_date).map(|(_, report)| report).collect()
+    }
+
+    pub fn print_variation_margin(&self, start_date: NaiveDate, end_date: NaiveDate) {
+        for report in self.variation_margin_reports(start_date, end_date) {
+            let totals: Vec<String> = report.totals().iter().map(|(currency, amount)| format!("{} {:.2}", currency, amount)).collect();
+            println!("Variation margin {}: [{}]", report.date, totals.join(", "));
+            for call in &report.calls {
+                println!("  {} {} @ {} -> {}: {} {:.2}", call.instrument, call.quantity, call.previous_price, call.settlement_price, call.currency, call.amount);
+            }
+            for (instrument, reason) in &report.skipped {
+                println!("  {} skipped: {}", instrument, reason);
+            }
+        }
+    }
+
+    pub fn record_close_price(&mut self, instrument: &str, date: NaiveDate, price: impl Into<Price>) {
+        let price = price.into();
+        if let Some(previous) = self.close_prices.get(instrument, date) {
+            if previous != price && self.superseded_closes.get(instrument, date).is_none() {
+                self.superseded_closes.insert(instrument, date, previous);
+            }
+        }
+        self.close_prices.insert(instrument, date, price);
+    }
+
+    // Last close on or before the date
+    pub fn close_price_on(&self, instrument: &str, date: NaiveDate) -> Option<Price> {
+        self.close_prices.value_on(instrument, date)
+    }
+
+    // Price a position is valued at on a date: the date's close, or the market price
+    // or average cost without one
+    pub fn valuation_price(&self, instrument: &str, date: NaiveDate, position: &TradePosition) -> Price {
+        self.close_price_on(instrument, date).or_else(|| self.get_market_price(instrument)).unwrap_or(position.average_price)
+    }
+
+    // Contracts of a symbol root ordered by expiry
+    pub fn contracts_for_root(&self, root: &str) -> Vec<&DerivativeContract> {
+        let mut contracts: Vec<&DerivativeContract> = self.contracts
+            .values()
+            .filter(|contract| contract.root == root)
+            .collect();
+        contracts.sort_by_key(|contract| contract.expiry_date);
+        contracts
+    }
+
+    // Stitch the closes of a root's contracts into one series. Each contract is used
+    // up to and including its expiry date; earlier segments are back-adjusted by the
+    // price gap at every later roll so the series has no jumps at rolls.
+    pub fn continuous_series(&self, root: &str) -> Vec<ContinuousPoint> {
+        let contracts = self.contracts_for_root(root);
+
+        // Adjustment for each contract = sum of the roll gaps after it
+        let mut adjustments = vec![Decimal::ZERO; contracts.len()];
+        for i in (0..contracts.len().saturating_sub(1)).rev() {
+            let roll_date = contracts[i].expiry_date;
+            let gap = match (self.close_price_on(&contracts[i + 1].instrument, roll_date), self.close_price_on(&contracts[i].instrument, roll_date)) {
+                (Some(next_price), Some(expiring_price)) => next_price - expiring_price,
+                _ => Decimal::ZERO,
+            };
+            adjustments[i] = adjustments[i + 1] + gap;
+        }
+
+        let mut series = Vec::new();
+        let mut window_start: Option<NaiveDate> = None;
+        for (contract, adjustment) in contracts.iter().zip(adjustments) {
+            if let Some(closes) = self.close_prices.series(&contract.instrument) {
+                for (date, price) in closes.range(..=contract.expiry_date) {
+                    if window_start.is_some_and(|start| *date <= start) {
+                        continue;
+                    }
+                    series.push(ContinuousPoint {
+                        date: *date,
+                        contract: contract.instrument.clone(),
+                        raw_price: *price,
+                        adjusted_price: *price + adjustment,
+                    });
+                }
+            }
+            window_start = Some(contract.expiry_date);
+        }
+
+        series
+    }
+
+    // Daily total P&L (realized + unrealized at that day's close) across all contracts
+    // of a root. Expiries realize at settlement and rolls re-open at the next contract's
+    // price, so the history runs through rolls without gaps.
+    pub fn root_pnl_history(&self, root: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<(NaiveDate, Money)> {
+        let contracts = self.contracts_for_root(root);
+        let histories: Vec<(&str, Vec<(NaiveDate, TradePosition)>)> = contracts
+            .iter()
+            .map(|contract| (contract.instrument.as_str(), self.get_position_history(&contract.instrument, start_date, end_date)))
+            .collect();
+
+        let mut pnl_history = Vec::new();
+        for day in 0..histories.first().map_or(0, |(_, history)| history.len()) {
+            let date = histories[0].1[day].0;
+            let total: Money = histories
+                .iter()
+                .map(|(instrument, history)| {
+                    let position = &history[day].1;
+                    let close = self.close_price_on(instrument, date).unwrap_or(position.average_price);
+                    position.total_pnl(close)
+                })
+                .sum();
+            pnl_history.push((date, total));
+        }
+
+        pnl_history
+    }
+
+    // Daily total P&L of one instrument over business days, valued at each day's close
+    pub fn instrument_pnl_history(&self, instrument: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<(NaiveDate, Money)> {
+        self.get_position_history(instrument, start_date, end_date)
+            .into_iter()
+            .filter(|(date, _)| !matches!(date.weekday(), Weekday::Sat | Weekday::Sun))
+            .map(|(date, position)| {
+                let close = self.close_price_on(instrument, date).unwrap_or(position.average_price);
+                (date, position.total_pnl(close))
+            })
+            .collect()
+    }
+
+    // Exception report for the morning check: unusual P&L moves on any instrument
+    // within the date range, largest first
+    pub fn pnl_exception_report(&self, start_date: NaiveDate, end_date: NaiveDate, config: &PnlAnomalyConfig) -> Vec<PnlAnomaly> {
+        let mut anomalies: Vec<PnlAnomaly> = self.daily_positions
+            .keys()
+            .flat_map(|instrument| pnl_anomalies(instrument, &self.instrument_pnl_history(instrument, start_date, end_date), config))
+            .collect();
+        anomalies.sort_by(|a, b| b.sigmas.abs().partial_cmp(&a.sigmas.abs()).unwrap().then(a.instrument.cmp(&b.instrument)));
+        anomalies
+    }
+
+    pub fn print_pnl_exception_report(&self, start_date: NaiveDate, end_date: NaiveDate, config: &PnlAnomalyConfig) {
+        let anomalies = self.pnl_exception_report(start_date, end_date, config);
+        println!("\n=== P&L Exceptions {} to {} (> {:.1} sigma) ===", start_date, end_date, config.threshold_sigmas);
+        if anomalies.is_empty() {
+            println!("No exceptions");
+        }
+        for anomaly in &anomalies {
+            println!("{} {}: P&L change ${:.2} is {:+.1} sigma (mean ${:.2}, std dev ${:.2})",
+                anomaly.date, anomaly.instrument, anomaly.pnl_change, anomaly.sigmas, anomaly.mean_change, anomaly.std_dev);
+        }
+    }
+
+    // Advanced trade filtering, through the most selective index for the filter
+    pub fn filter_trades(&self, filter: &TradeFilter) -> Vec<&Trade> {
+        let (_, mut trades) = self.trades.query(filter);
+        sort_trades_for_report(&mut trades);
+        trades
+    }
+
+    // Get trades by multiple criteria
+    pub fn get_trades_by_criteria(&self, instruments: Vec<String>, side: Option<Side>, date_range: Option<(NaiveDate, NaiveDate)>) -> Vec<&Trade> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| {
+                // Check instrument
+                if !instruments.is_empty() && !instruments.contains(&trade.instrument) {
+                    return false;
+                }
+                // Check side
+                if let Some(ref filter_side) = side {
+                    if !matches!((&trade.side, filter_side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell)) {
+                        return false;
+                    }
+                }
+                // Check date range
+                if let Some((start, end)) = date_range {
+                    if trade.trade_date < start || trade.trade_date > end {
+                        return false;
+                    }
+                }
+                true
+            })
+            .collect();
+        sort_trades_for_report(&mut trades);
+        trades
+    }
+
+    // Calculate portfolio P&L
+    pub fn calculate_portfolio_pnl(&self) -> (Money, Money, Money) {
+        let mut total_realized = Decimal::ZERO;
+        let mut total_unrealized = Decimal::ZERO;
+        let mut total_market_value = Decimal::ZERO;
+
+        for (instrument, position) in &self.positions {
+            total_realized += position.realized_pnl;
+            
+            if let Some(market_price) = self.get_market_price(instrument) {
+                total_unrealized += position.unrealized_pnl(market_price);
+                total_market_value += position.market_value(market_price);
+            }
+        }
+
+        (total_realized, total_unrealized, total_market_value)
+    }
+
+    // calculate_portfolio_pnl over one account's positions
+    pub fn calculate_account_pnl(&self, account_id: &str) -> (Money, Money, Money) {
+        let mut pnl = (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO);
+        for (instrument, position) in self.build_account_positions(account_id) {
+            pnl.0 += position.realized_pnl;
+            if let Some(market_price) = self.get_market_price(&instrument) {
+                pnl.1 += position.unrealized_pnl(market_price);
+                pnl.2 += position.market_value(market_price);
+            }
+        }
+        pnl
+    }
+
+    // Exposure, P&L, VaR and positions right now; see RiskSnapshot
+    pub fn risk_snapshot(&self, taken_at: DateTime<Utc>) -> RiskSnapshot {
+        let mut snapshot = RiskSnapshot {
+            taken_at,
+            positions: BTreeMap::new(),
+            long_exposure: Decimal::ZERO,
+            short_exposure: Decimal::ZERO,
+            realized_pnl: Decimal::ZERO,
+            unrealized_pnl: Decimal::ZERO,
+            var_99: Decimal::ZERO,
+            without_volatility: Vec::new(),
+        };
+        for (instrument, position) in &self.positions {
+            snapshot.realized_pnl += position.realized_pnl;
+            if position.quantity == 0 {
+                continue;
+            }
+            let price = self.get_market_price(instrument).unwrap_or(position.average_price);
+            let market_value = position.market_value(price);
+            snapshot.unrealized_pnl += position.unrealized_pnl(price);
+            if position.quantity > 0 {
+                snapshot.long_exposure += market_value;
+            } else {
+                snapshot.short_exposure -= market_value;
+            }
+            match self.implied_volatilities.get(instrument) {
+                Some(volatility) => snapshot.var_99 += market_value.abs() * (volatility * VAR_99_Z / 252f64.sqrt()),
+                None => snapshot.without_volatility.push(instrument.clone()),
+            }
+            snapshot.positions.insert(instrument.clone(), (position.quantity, market_value));
+        }
+        snapshot
+    }
+
+    // Get top gainers/losers
+    pub fn get_top_performers(&self, limit: usize, sort_by_unrealized: bool) -> Vec<(String, Money, Money)> {
+        let mut performers: Vec<(String, Money, Money)> = self.positions
+            .iter()
+            .filter_map(|(instrument, position)| {
+                if let Some(market_price) = self.get_market_price(instrument) {
+                    let unrealized = position.unrealized_pnl(market_price);
+                    let realized = position.realized_pnl;
+                    Some((instrument.clone(), realized, unrealized))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if sort_by_unrealized {
+            performers.sort_by_key(|performer| std::cmp::Reverse(performer.2));
+        } else {
+            performers.sort_by_key(|performer| std::cmp::Reverse(performer.1));
+        }
+
+        performers.into_iter().take(limit).collect()
+    }
+
+    pub fn get_position(&self, instrument: &str) -> Option<&TradePosition> {
+        self.positions.get(instrument)
+    }
+
+    pub fn get_all_positions(&self) -> &BTreeMap<String, TradePosition> {
+        &self.positions
+    }
+
+    // NEW: Build position map as of a specific date
+    pub fn build_position_map_as_of_date(&self, as_of_date: NaiveDate) -> BTreeMap<String, TradePosition> {
+        // Latest end-of-day position on or before the date for every instrument
+        self.daily_positions
+            .iter()
+            .filter_map(|(instrument, days)| {
+                days.range(..=as_of_date).next_back()
+                    .map(|(_, position)| (instrument.clone(), position.clone()))
+            })
+            .collect()
+    }
+
+    pub fn attach_position_snapshots(&mut self, snapshots: PositionSnapshotStore) {
+        self.position_snapshots = Some(snapshots);
+    }
+
+    // End-of-day job: persist the closing positions of `date` if the store's interval
+    // has passed since the last snapshot. Returns whether a snapshot was written.
+    pub fn snapshot_end_of_day(&mut self, date: NaiveDate) -> Result<bool, PositionError> {
+        let positions = self.build_position_map_as_of_date(date);
+        let snapshots = self.position_snapshots.as_mut().ok_or(PositionError::Snapshot("No snapshot store attached".to_string()))?;
+        if !snapshots.due(date) {
+            return Ok(false);
+        }
+        snapshots.write(date, &positions).map_err(|e| PositionError::Snapshot(e.to_string()))?;
+        Ok(true)
+    }
+
+    // Positions as of a date from the nearest snapshot on or before it plus the active
+    // trades dated after the snapshot. Without a usable snapshot every trade up to the
+    // date is replayed.
+    pub fn positions_from_snapshots(&self, as_of_date: NaiveDate) -> Result<BTreeMap<String, TradePosition>, PositionError> {
+        let mut base: Option<(NaiveDate, BTreeMap<String, TradePosition>)> = None;
+        if let Some(snapshots) = &self.position_snapshots {
+            // A rolled-back transaction can remember a snapshot that was since removed
+            for date in snapshots.dates.range(..=as_of_date).rev() {
+                if std::path::Path::new(&snapshots.path(*date)).exists() {
+                    base = Some((*date, snapshots.read(*date)?));
+                    break;
+                }
+            }
+        }
+        let (replay_from, mut positions) = match base {
+            Some((date, positions)) => (date.succ_opt().unwrap_or(NaiveDate::MAX), positions),
+            None => (NaiveDate::MIN, BTreeMap::new()),
+        };
+
+        let mut incremental: Vec<&Trade> = self.trades
+            .values_between(replay_from, as_of_date)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        incremental.sort_by_key(|trade| trade.chronological_key());
+        for trade in incremental {
+            positions.entry(trade.instrument.clone())
+                .or_insert_with(|| TradePosition::new(trade.instrument.clone()))
+                .update_position(trade);
+        }
+        Ok(positions)
+    }
+
+    // Positions as of an exact time: the previous day's closing positions plus the
+    // trades executed on the day up to and including `as_of`
+    pub fn build_position_map_as_of(&self, as_of: DateTime<Utc>) -> BTreeMap<String, TradePosition> {
+        let as_of_date = as_of.date_naive();
+        let mut positions: BTreeMap<String, TradePosition> = self.daily_positions
+            .iter()
+            .filter_map(|(instrument, days)| {
+                days.range(..as_of_date).next_back()
+                    .map(|(_, position)| (instrument.clone(), position.clone()))
+            })
+            .collect();
+
+        let mut intraday: Vec<&Trade> = self.trades
+            .values_between(as_of_date, as_of_date)
+            .filter(|trade| trade.executed_at <= as_of)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        intraday.sort_by_key(|trade| trade.chronological_key());
+        for trade in intraday {
+            positions.entry(trade.instrument.clone())
+                .or_insert_with(|| TradePosition::new(trade.instrument.clone()))
+                .update_position(trade);
+        }
+        positions
+    }
+
+    pub fn add_session_calendar(&mut self, calendar: SessionCalendar) {
+        self.session_calendars.insert(calendar.name.clone(), calendar);
+    }
+
+    pub fn set_instrument_session(&mut self, instrument: &str, calendar: &str) {
+        self.instrument_sessions.insert(instrument.to_string(), calendar.to_string());
+    }
+
+    pub fn session_calendar(&self, instrument: &str) -> Option<&SessionCalendar> {
+        self.session_calendars.get(self.instrument_sessions.get(instrument)?)
+    }
+
+    // The instrument's session on a date; the whole UTC day for instruments without a calendar
+    pub fn session_on(&self, instrument: &str, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        match self.session_calendar(instrument) {
+            Some(calendar) => calendar.session_on(date),
+            None => Some((start_of_day(date), start_of_day(date.succ_opt()?) - chrono::Duration::milliseconds(1))),
+        }
+    }
+
+    // Positions at each instrument's own close on a date. On a day its exchange is shut,
+    // an instrument is taken at its previous session close.
+    pub fn positions_at_close(&self, date: NaiveDate) -> BTreeMap<String, TradePosition> {
+        let cutoff_on = |instrument: &str| match self.session_calendar(instrument) {
+            Some(calendar) => calendar.last_close_on_or_before(date),
+            None => self.session_on(instrument, date).map(|(_, close)| close),
+        };
+        let mut cutoffs: BTreeMap<DateTime<Utc>, Vec<&String>> = BTreeMap::new();
+        for instrument in self.daily_positions.keys() {
+            if let Some(cutoff) = cutoff_on(instrument) {
+                cutoffs.entry(cutoff).or_default().push(instrument);
+            }
+        }
+        // One as-of build per distinct cut-off, keeping the instruments that close then
+        let mut positions = BTreeMap::new();
+        for (cutoff, instruments) in cutoffs {
+            let mut at_cutoff = self.build_position_map_as_of(cutoff);
+            for instrument in instruments {
+                if let Some(position) = at_cutoff.remove(instrument) {
+                    positions.insert(instrument.clone(), position);
+                }
+            }
+        }
+        positions
+    }
+
+    // Session-aware end of day: once an instrument's session on `date` has closed, its
+    // current mark becomes the day's close. A mark from a book captured outside the
+    // session is not recorded. Marks set directly carry no time and are trusted.
+    pub fn run_end_of_day(&mut self, date: NaiveDate, now: DateTime<Utc>) -> Vec<EodClose> {
+        let instruments: Vec<String> = self.positions.keys().filter(|instrument| !is_cash_instrument(instrument)).cloned().collect();
+        let mut closes = Vec::new();
+        for instrument in instruments {
+            let session = self.session_on(&instrument, date);
+            let price = self.get_market_price(&instrument);
+            let status = match session {
+                None => EodCloseStatus::MarketClosed,
+                Some((_, close)) if now < close => EodCloseStatus::SessionOpen,
+                Some((open, close)) => match (price, self.book_snapshots.get(&instrument)) {
+                    (None, _) => EodCloseStatus::NoMark,
+                    (Some(_), Some(book)) if !matches!(self.mark_method(&instrument), MarkMethod::LastTrade)
+                        && (book.captured_at < open || book.captured_at > close) => EodCloseStatus::StaleMark { captured_at: book.captured_at },
+                    (Some(price), _) => {
+                        self.record_close_price(&instrument, date, price);
+                        EodCloseStatus::Recorded
+                    },
+                },
+            };
+            closes.push(EodClose { instrument, cutoff: session.map(|(_, close)| close), price, status });
+        }
+        closes
+    }
+
+    // Control for a heavy query, using the repository's default timeout if one is set
+    pub fn query_control(&self, token: CancellationToken) -> QueryControl {
+        match self.default_query_timeout {
+            Some(timeout) => QueryControl::new(token).with_timeout(timeout),
+            None => QueryControl::new(token),
+        }
+    }
+
+    // Rebuild the whole daily position table from the trade history. The new table
+    // replaces the old one only if the rebuild finishes before being cancelled.
+    pub fn rebuild_daily_positions(&mut self, control: &QueryControl) -> Result<(), PositionError> {
+        let mut instruments: Vec<String> = self.trades.values().map(|trade| trade.instrument.clone()).collect();
+        instruments.sort();
+        instruments.dedup();
+
+        let mut rebuilt: BTreeMap<String, BTreeMap<NaiveDate, TradePosition>> = BTreeMap::new();
+        let mut marks = HashMap::new();
+        for instrument in instruments {
+            control.check()?;
+            let mut position = TradePosition::new(instrument.clone());
+            let days = rebuilt.entry(instrument.clone()).or_default();
+            let adjusted = self.position_adjustments.get(&instrument);
+            let mut adjustments = adjusted.into_iter().flat_map(flatten_adjustments).peekable();
+            for (count, trade) in self.instrument_trades_chronological(&instrument).into_iter().enumerate() {
+                if count % 1024 == 0 {
+                    control.check()?;
+                }
+                adjust_before(&mut position, days, &mut adjustments, trade.trade_date);
+                position.update_position(trade);
+                days.insert(trade.trade_date, position.clone());
+                marks.insert(instrument.clone(), trade.chronological_key());
+            }
+            adjust_before(&mut position, days, &mut adjustments, NaiveDate::MAX);
+            if let Some(date) = adjusted.and_then(|dates| dates.keys().next_back()) {
+                let mark = marks.entry(instrument.clone()).or_insert(adjusted_mark(*date));
+                *mark = (*mark).max(adjusted_mark(*date));
+            }
+        }
+
+        self.daily_positions = rebuilt;
+        self.daily_position_marks = marks;
+        self.fold_checkpoints.clear();
+        Ok(())
+    }
+
+    // Recompute an instrument after its master data (currency, fee schedule, rounding)
+    // was corrected: active trades are re-booked under the current rules, and the
+    // position and daily snapshots rebuilt from them in execution order. The summary
+    // values both states at the `as_of_date` close and FX rate.
+    // Recompute every daily snapshot between the dates inclusive after a methodology or
+    // data fix: trades in the range are re-booked under the current rules and each
+    // instrument's daily positions are rebuilt from the start date. Days are valued at
+    // their close, with corrected closes valued at the close they replaced for the
+    // prior figures. The restatement is kept on the repository and returned.
+    pub fn restate(&mut self, start_date: NaiveDate, end_date: NaiveDate, reason: &str) -> Result<RangeRestatement, PositionError> {
+        if end_date < start_date {
+            return Err(PositionError::InvalidRecord(format!("restatement ends {} before it starts {}", end_date, start_date)));
+        }
+        let previous_days = self.daily_positions.clone();
+
+        let mut trades_restated = Vec::new();
+        let mut in_range: Vec<Trade> = self.trades
+            .values()
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .filter(|trade| trade.trade_date >= start_date && trade.trade_date <= end_date)
+            .cloned()
+            .collect();
+        in_range.sort_by_key(|trade| trade.chronological_key());
+        for trade in in_range {
+            let mut rebooked = trade.clone();
+            self.apply_booking_rules(&mut rebooked);
+            if rebooked.price != trade.price || rebooked.total_fees() != trade.total_fees() {
+                trades_restated.push(trade.trade_id);
+                self.update_trade_details(rebooked)?;
+            }
+        }
+        let instruments: Vec<String> = self.daily_positions.keys().cloned().collect();
+        for instrument in &instruments {
+            self.refresh_daily_positions(instrument, start_date);
+        }
+
+        let position_on = |days: &BTreeMap<String, BTreeMap<NaiveDate, TradePosition>>, instrument: &str, date: NaiveDate| {
+            days.get(instrument)
+                .and_then(|days| days.range(..=date).next_back())
+                .map(|(_, position)| position.clone())
+                .unwrap_or_else(|| TradePosition::new(instrument.to_string()))
+        };
+        let mut days = Vec::new();
+        for instrument in &instruments {
+            let closes = self.close_prices.series(instrument);
+            for date in start_date.iter_days().take_while(|date| *date <= end_date) {
+                let (before, after) = (position_on(&previous_days, instrument, date), position_on(&self.daily_positions, instrument, date));
+                let close_date = closes.and_then(|closes| closes.range(..=date).next_back()).map(|(close_date, _)| *close_date);
+                let close = self.close_price_on(instrument, date);
+                let previous_close = close_date.and_then(|close_date| self.superseded_closes.get(instrument, close_date)).or(close);
+                let unrealized = |position: &TradePosition, close: Option<Price>| close.map_or(Decimal::ZERO, |close| position.unrealized_pnl(close));
+                let day = RestatedDay {
+                    date,
+                    instrument: instrument.clone(),
+                    previous_quantity: before.quantity,
+                    quantity: after.quantity,
+                    previous_realized_pnl: before.realized_pnl,
+                    realized_pnl: after.realized_pnl,
+                    previous_unrealized_pnl: unrealized(&before, previous_close),
+                    unrealized_pnl: unrealized(&after, close),
+                };
+                if day.previous_quantity != day.quantity || day.previous_realized_pnl != day.realized_pnl || day.previous_unrealized_pnl != day.unrealized_pnl {
+                    days.push(day);
+                }
+            }
+        }
+        days.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.instrument.cmp(&b.instrument)));
+        self.superseded_closes.remove_range(start_date, end_date);
+
+        let restatement = RangeRestatement {
+            start_date,
+            end_date,
+            reason: reason.to_string(),
+            recorded_at: Utc::now(),
+            actor: self.actor.clone(),
+            trades_restated,
+            days,
+        };
+        self.restatements.push(restatement.clone());
+        Ok(restatement)
+    }
+
+    pub fn recompute_instrument(&mut self, instrument: &str, as_of_date: NaiveDate) -> Result<Restatement, PositionError> {
+        if !self.positions.contains_key(instrument) {
+            return Err(PositionError::InstrumentNotFound(instrument.to_string()));
+        }
+        let previous_position = self.positions[instrument].clone();
+        let previous_days = self.daily_positions.get(instrument).cloned().unwrap_or_default();
+        let currency = self.instrument_currency(instrument).to_string();
+        let previous_currency = self.booked_currencies.get(instrument).cloned().unwrap_or_else(|| currency.clone());
+
+        let mut trades_restated = Vec::new();
+        for trade in self.instrument_trades_chronological(instrument).into_iter().cloned().collect::<Vec<Trade>>() {
+            let mut rebooked = trade.clone();
+            self.apply_booking_rules(&mut rebooked);
+            if rebooked.price != trade.price || rebooked.total_fees() != trade.total_fees() {
+                trades_restated.push(trade.trade_id);
+                self.update_trade_details(rebooked)?;
+            }
+        }
+
+        let mut position = TradePosition::new(instrument.to_string());
+        for trade in self.instrument_trades_chronological(instrument) {
+            position.update_position(trade);
+        }
+        self.positions.insert(instrument.to_string(), position.clone());
+        self.refresh_daily_positions(instrument, NaiveDate::MIN);
+        self.booked_currencies.insert(instrument.to_string(), currency.clone());
+
+        let days = self.daily_positions.get(instrument).cloned().unwrap_or_default();
+        let same = |a: &TradePosition, b: &TradePosition| a.quantity == b.quantity && a.average_price == b.average_price && a.realized_pnl == b.realized_pnl;
+        let dates: BTreeSet<&NaiveDate> = previous_days.keys().chain(days.keys()).collect();
+        let days_restated = dates
+            .into_iter()
+            .filter(|date| match (previous_days.get(date), days.get(date)) {
+                (Some(before), Some(after)) => !same(before, after),
+                _ => true,
+            })
+            .count();
+
+        let close = self.close_price_on(instrument, as_of_date).or(self.get_market_price(instrument));
+        let unrealized = |position: &TradePosition| close.map_or(Decimal::ZERO, |close| position.unrealized_pnl(close));
+        let firm = |position: &TradePosition, currency: &str| {
+            self.fx_rates.rate_on(currency, as_of_date).ok().map(|rate| (position.realized_pnl + unrealized(position)) * rate)
+        };
+
+        Ok(Restatement {
+            instrument: instrument.to_string(),
+            as_of_date,
+            trades_restated,
+            days_restated,
+            previous_quantity: previous_position.quantity,
+            quantity: position.quantity,
+            previous_realized_pnl: previous_position.realized_pnl,
+            realized_pnl: position.realized_pnl,
+            previous_unrealized_pnl: unrealized(&previous_position),
+            unrealized_pnl: unrealized(&position),
+            previous_firm_pnl: firm(&previous_position, &previous_currency),
+            firm_pnl: firm(&position, &currency),
+            previous_currency,
+            currency,
+        })
+    }
+
+    pub fn get_position_history_controlled(&self, instrument: &str, start_date: NaiveDate, end_date: NaiveDate, control: &QueryControl) -> Result<Vec<(NaiveDate, TradePosition)>, PositionError> {
+        let mut history = Vec::new();
+        let mut current_date = start_date;
+        while current_date <= end_date {
+            control.check()?;
+            let chunk_end = (current_date + chrono::Duration::days(365)).min(end_date);
+            history.extend(self.get_position_history(instrument, current_date, chunk_end));
+            match chunk_end.succ_opt() {
+                Some(next_date) => current_date = next_date,
+                None => break,
+            }
+        }
+        Ok(history)
+    }
+
+    // NEW: Get position history for an instrument over date range
+    pub fn get_position_history(&self, instrument: &str, start_date: NaiveDate, end_date: NaiveDate) -> Vec<(NaiveDate, TradePosition)> {
+        let mut history = Vec::new();
+        if start_date > end_date {
+            return history;
+        }
+
+        let no_days = BTreeMap::new();
+        let days = self.daily_positions.get(instrument).unwrap_or(&no_days);
+
+        // Carry the last known position forward across days without trades
+        let mut position = days.range(..start_date).next_back()
+            .map(|(_, position)| position.clone())
+            .unwrap_or_else(|| TradePosition::new(instrument.to_string()));
+        let mut changes = days.range(start_date..=end_date).peekable();
+        let mut current_date = start_date;
+        
+        while current_date <= end_date {
+            if let Some((_, changed)) = changes.next_if(|(date, _)| **date == current_date) {
+                position = changed.clone();
+            }
+            history.push((current_date, position.clone()));
+            match current_date.succ_opt() {
+                Some(next_date) => current_date = next_date,
+                None => break,
+            }
+        }
+        
+        history
+    }
+
+    // NEW: Print position summary with P&L as of date
+    pub fn print_position_summary_as_of(&self, as_of_date: NaiveDate) {
+        self.print_position_summary(as_of_date, None);
+    }
+
+    // Open positions as of a date with P&L at current market prices (average price
+    // when there is no mark), optionally grouped with subtotals per group
+    pub fn position_summary(&self, as_of_date: NaiveDate, group_by: Option<Dimension>) -> PositionSummary {
+        let mut rows: Vec<PositionSummaryRow> = self.grouped_positions(as_of_date, group_by)
+            .into_iter()
+            .filter(|(_, position)| position.quantity != 0)
+            .map(|(group, position)| {
+                let market_price = self.get_market_price(&position.instrument).unwrap_or(position.average_price);
+                PositionSummaryRow {
+                    group,
+                    instrument: position.instrument.clone(),
+                    quantity: position.quantity,
+                    average_price: position.average_price,
+                    market_price,
+                    market_value: position.market_value(market_price),
+                    realized_pnl: position.realized_pnl,
+                    unrealized_pnl: position.unrealized_pnl(market_price),
+                }
+            })
+            .collect();
+        rows.sort_by(|a, b| a.group.cmp(&b.group).then(a.instrument.cmp(&b.instrument)));
+
+        let mut group_totals: Vec<PositionSummaryTotals> = Vec::new();
+        if group_by.is_some() {
+            for row in &rows {
+                if group_totals.last().is_none_or(|totals| totals.group != row.group) {
+                    group_totals.push(PositionSummaryTotals::new(&row.group));
+                }
+                group_totals.last_mut().unwrap().add(row);
+            }
+        }
+        let mut totals = PositionSummaryTotals::new("Total");
+        for row in &rows {
+            totals.add(row);
+        }
+
+        PositionSummary {
+            as_of_date,
+            group_by,
+            rows,
+            group_totals,
+            totals,
+        }
+    }
+
+    // (group, position) pairs as of a date; account grouping rebuilds positions per
+    // account, the other dimensions label the firm-wide positions
+    pub fn grouped_positions(&self, as_of_date: NaiveDate, group_by: Option<Dimension>) -> Vec<(String, TradePosition)> {
+        match group_by {
+            Some(Dimension::Account) => self.account_ids()
+                .into_iter()
+                .flat_map(|account_id| {
+                    self.build_account_positions_as_of(&account_id, as_of_date)
+                        .into_values()
+                        .map(move |position| (account_id.clone(), position))
+                })
+                .collect(),
+            Some(Dimension::Sector) => self.build_position_map_as_of_date(as_of_date)
+                .into_values()
+                .map(|position| (self.instrument_sector(&position.instrument).to_string(), position))
+                .collect(),
+            Some(Dimension::Currency) => self.build_position_map_as_of_date(as_of_date)
+                .into_values()
+                .map(|position| (self.instrument_currency(&position.instrument).to_string(), position))
+                .collect(),
+            None => self.build_position_map_as_of_date(as_of_date)
+                .into_values()
+                .map(|position| (String::new(), position))
+                .collect(),
+        }
+    }
+
+    // Portfolio -> group -> instrument contribution tree as of a date, marked like
+    // position_summary. Closed positions stay in as zero-weight tiles while they
+    // carry realized P&L.
+    pub fn pnl_contribution(&self, as_of_date: NaiveDate, dimension: Dimension) -> ContributionNode {
+        let mut groups: BTreeMap<String, Vec<(String, Money, Money)>> = BTreeMap::new();
+        for (group, position) in self.grouped_positions(as_of_date, Some(dimension)) {
+            if position.quantity == 0 && position.realized_pnl.is_zero() {
+                continue;
+            }
+            let market_price = self.get_market_price(&position.instrument).unwrap_or(position.average_price);
+            let total_pnl = position.realized_pnl + position.unrealized_pnl(market_price);
+            groups.entry(group).or_default().push((position.instrument.clone(), position.market_value(market_price), total_pnl));
+        }
+
+        let gross: Money = groups.values().flatten().map(|(_, market_value, _)| market_value.abs()).sum();
+        let total_pnl: Money = groups.values().flatten().map(|(_, _, pnl)| *pnl).sum();
+        let share = |amount: Money, total: Money| if total.is_zero() { 0.0 } else { amount.to_f64() / total.to_f64() };
+        let node = |name: &str, market_value: Money, pnl: Money, children: Vec<ContributionNode>| ContributionNode {
+            name: name.to_string(),
+            market_value,
+            total_pnl: pnl,
+            weight: share(market_value.abs(), gross),
+            pnl_share: share(pnl, total_pnl),
+            children,
+        };
+
+        let children = groups
+            .iter()
+            .map(|(group, instruments)| {
+                let leaves: Vec<ContributionNode> = instruments
+                    .iter()
+                    .map(|(instrument, market_value, pnl)| node(instrument, *market_value, *pnl, Vec::new()))
+                    .collect();
+                let mut group_node = node(group, leaves.iter().map(|leaf| leaf.market_value).sum(), leaves.iter().map(|leaf| leaf.total_pnl).sum(), leaves);
+                // Longs and shorts in one group both take up room on the chart
+                group_node.weight = group_node.children.iter().map(|leaf| leaf.weight).sum();
+                group_node
+            })
+            .collect::<Vec<_>>();
+        let mut root = node("Portfolio", children.iter().map(|child| child.market_value).sum(), total_pnl, children);
+        root.weight = if gross.is_zero() { 0.0 } else { 1.0 };
+        root
+    }
+
+    // Current positions and P&L grouped level by level on the given keys, e.g.
+    // aggregate(&[GroupBy::AssetClass, GroupBy::Instrument])
+    pub fn aggregate(&self, group_by: &[GroupBy]) -> AggregateNode {
+        self.aggregate_as_of(NaiveDate::MAX, group_by)
+    }
+
+    // Positions are rebuilt per account, so realized P&L is each account's own even
+    // when the grouping does not separate accounts, and marked like position_summary.
+    // Flat positions with no realized P&L are left out.
+    pub fn aggregate_as_of(&self, as_of_date: NaiveDate, group_by: &[GroupBy]) -> AggregateNode {
+        let mut positions: Vec<LabelledPosition> = Vec::new();
+        for account_id in self.account_ids() {
+            for (instrument, position) in self.build_account_positions_as_of(&account_id, as_of_date) {
+                if position.quantity == 0 && position.realized_pnl.is_zero() {
+                    continue;
+                }
+                let labels = group_by.iter().map(|key| self.group_label(key, &account_id, &instrument)).collect();
+                let market_price = self.get_market_price(&instrument).unwrap_or(position.average_price);
+                positions.push((labels, position.market_value(market_price), position.realized_pnl, position.unrealized_pnl(market_price)));
+            }
+        }
+
+        let gross: Money = positions.iter().map(|(_, market_value, _, _)| market_value.abs()).sum();
+        let members: Vec<&LabelledPosition> = positions.iter().collect();
+        AggregateNode {
+            group_by: None,
+            name: "Total".to_string(),
+            market_value: positions.iter().map(|(_, market_value, _, _)| *market_value).sum(),
+            realized_pnl: positions.iter().map(|(_, _, realized, _)| *realized).sum(),
+            unrealized_pnl: positions.iter().map(|(_, _, _, unrealized)| *unrealized).sum(),
+            weight: if gross.is_zero() { 0.0 } else { 1.0 },
+            children: aggregate_nodes(&members, group_by, 0, gross),
+        }
+    }
+
+    // Name of the group a position falls in under one grouping key
+    pub fn group_label(&self, key: &GroupBy, account_id: &str, instrument: &str) -> String {
+        match key {
+            GroupBy::AssetClass => self.instruments.get(instrument).map_or(UNASSIGNED.to_string(), |record| format!("{:?}", record.asset_class)),
+            GroupBy::Sector => self.instrument_sector(instrument).to_string(),
+            GroupBy::Currency => self.instrument_currency(instrument).to_string(),
+            GroupBy::Book => self.account_book(account_id).to_string(),
+            GroupBy::Account => account_id.to_string(),
+            GroupBy::Instrument => instrument.to_string(),
+            GroupBy::Tag(tag) => self.instrument_tag(instrument, tag).to_string(),
+        }
+    }
+
+    // Gross, net and concentration of the positions held at the date, optionally
+    // grouped, e.g. exposure_report(date, 10, Some(GroupBy::Sector)). Positions are
+    // valued at the date's close, or the market price or average cost without one.
+    pub fn exposure_report(&self, as_of_date: NaiveDate, top_n: usize, group_by: Option<GroupBy>) -> Result<ExposureReport, String> {
+        let mut nav = Decimal::ZERO;
+        let mut total = ExposureLine { name: "Total".to_string(), long_value: Decimal::ZERO, short_value: Decimal::ZERO };
+        let mut by_instrument: BTreeMap<String, Money> = BTreeMap::new();
+        let mut groups: BTreeMap<String, ExposureLine> = BTreeMap::new();
+        for account_id in self.account_ids() {
+            for (instrument, position) in self.build_account_positions_as_of(&account_id, as_of_date) {
+                if position.quantity == 0 {
+                    continue;
+                }
+                let price = self.valuation_price(&instrument, as_of_date, &position);
+                let market_value = position.market_value(price) * self.fx_rates.rate_on(self.instrument_currency(&instrument), as_of_date)?;
+                nav += market_value;
+                if is_cash_instrument(&instrument) {
+                    continue;
+                }
+                *by_instrument.entry(instrument.clone()).or_default() += market_value;
+                let group = group_by.as_ref().map(|key| {
+                    let name = self.group_label(key, &account_id, &instrument);
+                    groups.entry(name.clone()).or_insert(ExposureLine { name, long_value: Decimal::ZERO, short_value: Decimal::ZERO })
+                });
+                for line in std::iter::once(&mut total).chain(group) {
+                    if market_value > Decimal::ZERO {
+                        line.long_value += market_value;
+                    } else {
+                        line.short_value -= market_value;
+                    }
+                }
+            }
+        }
+
+        let instrument_gross: f64 = by_instrument.values().map(|value| value.abs().to_f64()).sum();
+        let herfindahl = if instrument_gross > 0.0 {
+            by_instrument.values().map(|value| (value.abs().to_f64() / instrument_gross).powi(2)).sum()
+        } else {
+            0.0
+        };
+        let mut top_positions: Vec<(String, Money)> = by_instrument.into_iter().collect();
+        top_positions.sort_by_key(|(_, value)| std::cmp::Reverse(value.abs()));
+        top_positions.truncate(top_n);
+        Ok(ExposureReport {
+            as_of_date,
+            nav,
+            total,
+            top_positions,
+            herfindahl,
+            groups: groups.into_values().collect(),
+        })
+    }
+
+    pub fn set_margin_rules(&mut self, rules: MarginRules) {
+        self.margin_rules = rules;
+    }
+
+    // Initial and maintenance margin per account and position at the date, with the
+    // accounts whose equity has fallen below maintenance
+    pub fn margin_report(&self, as_of_date: NaiveDate) -> Result<MarginReport, String> {
+        let mut accounts = Vec::new();
+        for account_id in self.account_ids() {
+            let equity = self.portfolio_equity(Some(&account_id), as_of_date)?.total;
+            let mut lines = Vec::new();
+            for (instrument, position) in self.build_account_positions_as_of(&account_id, as_of_date) {
+                if position.quantity == 0 || is_cash_instrument(&instrument) {
+                    continue;
+                }
+                let rate = self.fx_rates.rate_on(self.instrument_currency(&instrument), as_of_date)?;
+                let local_value = position.market_value(self.valuation_price(&instrument, as_of_date, &position));
+                let market_value = local_value * rate;
+                let asset_class = self.instruments.get(&instrument).map(|record| record.asset_class);
+                let (initial, maintenance) = self.margin_rules.rule_for(&instrument, asset_class).requirement(position.quantity, local_value);
+                lines.push(MarginLine { instrument, quantity: position.quantity, market_value, initial: initial * rate, maintenance: maintenance * rate });
+            }
+            accounts.push(AccountMargin { account_id, equity, lines });
+        }
+        Ok(MarginReport { as_of_date, accounts })
+    }
+
+    // One-day VaR and expected shortfall of the positions held at the date, from the
+    // daily returns of each instrument's closes over the lookback. Positions are
+    // valued at the date's close and FX rate; a day an instrument has no return for
+    // contributes nothing for it.
+    pub fn value_at_risk(&self, as_of_date: NaiveDate, config: &VarConfig) -> Result<VarReport, String> {
+        let mut exposures = Vec::new();
+        let mut missing_history = Vec::new();
+        for (instrument, position) in self.build_position_map_as_of_date(as_of_date) {
+            if position.quantity == 0 || is_cash_instrument(&instrument) {
+                continue;
+            }
+            let closes: Vec<(NaiveDate, Price)> = self.close_prices.range(&instrument, NaiveDate::MIN, as_of_date).collect();
+            let closes = &closes[closes.len().saturating_sub(config.lookback_days + 1)..];
+            let (Some((_, close)), true) = (closes.last(), closes.len() > 1) else {
+                missing_history.push(instrument);
+                continue;
+            };
+            let rate = self.fx_rates.rate_on(self.instrument_currency(&instrument), as_of_date)?;
+            let market_value = position.market_value(*close) * rate;
+            // A zero close has no return to the next day; that day is left out
+            let returns: BTreeMap<NaiveDate, f64> = closes.windows(2)
+                .filter_map(|pair| Some((pair[1].0, pair[1].1.checked_div(pair[0].1)?.to_f64() - 1.0)))
+                .collect();
+            exposures.push((instrument, market_value, returns));
+        }
+
+        let mut scenario_dates: Vec<NaiveDate> = exposures.iter().flat_map(|(_, _, returns)| returns.keys().copied()).collect::<BTreeSet<NaiveDate>>().into_iter().collect();
+        scenario_dates = scenario_dates.split_off(scenario_dates.len().saturating_sub(config.lookback_days));
+        let mut portfolio_pnl = vec![0.0; scenario_dates.len()];
+        let mut instruments = Vec::new();
+        for (instrument, market_value, returns) in &exposures {
+            let pnl: Vec<f64> = scenario_dates.iter().map(|date| market_value.to_f64() * returns.get(date).copied().unwrap_or(0.0)).collect();
+            for (total, pnl) in portfolio_pnl.iter_mut().zip(&pnl) {
+                *total += pnl;
+            }
+            let own: Vec<f64> = scenario_dates.iter().zip(&pnl).filter(|(date, _)| returns.contains_key(date)).map(|(_, pnl)| *pnl).collect();
+            instruments.push(var_line(instrument, *market_value, &own, &config.confidence_levels));
+        }
+        let total_value = exposures.iter().map(|(_, market_value, _)| *market_value).sum();
+        Ok(VarReport {
+            as_of_date,
+            instruments,
+            portfolio: var_line("Portfolio", total_value, &portfolio_pnl, &config.confidence_levels),
+            missing_history,
+        })
+    }
+
+    // Revalue the book as of a date under each scenario, in the firm currency
+    pub fn stress_test(&self, as_of_date: NaiveDate, scenarios: &[StressScenario]) -> Result<StressReport, String> {
+        let mut positions = Vec::new();
+        for (instrument, position) in self.build_position_map_as_of_date(as_of_date) {
+            if position.quantity == 0 {
+                continue;
+            }
+            let price = self.valuation_price(&instrument, as_of_date, &position);
+            let currency = self.instrument_currency(&instrument).to_string();
+            let rate = self.fx_rates.rate_on(&currency, as_of_date)?;
+            positions.push((instrument, position, price, currency, rate));
+        }
+
+        let results = scenarios
+            .iter()
+            .map(|scenario| {
+                let lines = positions
+                    .iter()
+                    .map(|(instrument, position, price, currency, rate)| {
+                        let (price_factor, fx_factor) = self.shock_factors(instrument, currency, &scenario.shocks);
+                        StressLine {
+                            instrument: instrument.clone(),
+                            quantity: position.quantity,
+                            market_value: position.market_value(*price) * *rate,
+                            stressed_value: position.market_value(*price * price_factor) * (rate * fx_factor),
+                        }
+                    })
+                    .collect();
+                StressResult { scenario: scenario.name.clone(), lines }
+            })
+            .collect();
+        Ok(StressReport { as_of_date, results })
+    }
+
+    // Price and FX multipliers a scenario's shocks apply to one instrument
+    pub fn shock_factors(&self, instrument: &str, currency: &str, shocks: &[Shock]) -> (f64, f64) {
+        let asset_class = self.instruments.get(instrument).map(|record| record.asset_class);
+        let (mut price, mut fx) = (1.0, 1.0);
+        for shock in shocks {
+            match shock {
+                Shock::Instrument { instrument: shocked, change } if shocked == instrument => price *= 1.0 + change,
+                Shock::AssetClass { asset_class: shocked, change } if asset_class == Some(*shocked) => price *= 1.0 + change,
+                Shock::Sector { sector, change } if self.instrument_sector(instrument) == sector => price *= 1.0 + change,
+                Shock::Rates { basis_points } => {
+                    if let Some(duration) = self.durations.get(instrument) {
+                        price *= 1.0 - duration * basis_points / 10_000.0;
+                    }
+                },
+                Shock::Currency { currency: shocked, change } if shocked == currency => fx *= 1.0 + change,
+                _ => {}
+            }
+        }
+        (price, fx)
+    }
+
+    // Whole-firm P&L and exposure tree as of a date. Positions are rebuilt per account
+    // and strategy, marked at current prices (average price when unmarked) and
+    // converted at the date's FX rates; flat positions with no realized P&L are left out.
+    pub fn pnl_rollup(&self, as_of_date: NaiveDate) -> Result<PnlNode, String> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.trade_date <= as_of_date && !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+
+        let mut positions: BTreeMap<(String, String, String, String), TradePosition> = BTreeMap::new();
+        for trade in trades {
+            let strategy = trade.strategy.clone().unwrap_or_else(|| UNASSIGNED.to_string());
+            let key = (self.account_book(&trade.account_id).to_string(), trade.account_id.clone(), strategy, trade.instrument.clone());
+            positions.entry(key)
+                .or_insert_with(|| TradePosition::new(trade.instrument.clone()))
+                .update_position(trade);
+        }
+
+        let mut tree: BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<PnlNode>>>> = BTreeMap::new();
+        for ((book, account_id, strategy, instrument), position) in positions {
+            if position.quantity == 0 && position.realized_pnl.is_zero() {
+                continue;
+            }
+            let mark = self.get_market_price(&instrument).unwrap_or(position.average_price);
+            let rate = self.fx_rates.rate_on(self.instrument_currency(&instrument), as_of_date)?;
+            let market_value = position.market_value(mark) * rate;
+            tree.entry(book).or_default().entry(account_id).or_default().entry(strategy).or_default().push(PnlNode {
+                level: RollupLevel::Instrument,
+                name: instrument,
+                quantity: Some(position.quantity),
+                realized_pnl: position.realized_pnl * rate,
+                unrealized_pnl: position.unrealized_pnl(mark) * rate,
+                long_exposure: market_value.max(Decimal::ZERO),
+                short_exposure: (-market_value).max(Decimal::ZERO),
+                children: Vec::new(),
+            });
+        }
+
+        let books = tree
+            .into_iter()
+            .map(|(book, accounts)| {
+                let accounts = accounts
+                    .into_iter()
+                    .map(|(account_id, strategies)| {
+                        let strategies = strategies
+                            .into_iter()
+                            .map(|(strategy, instruments)| PnlNode::rollup(RollupLevel::Strategy, &strategy, instruments))
+                            .collect();
+                        PnlNode::rollup(RollupLevel::Account, &account_id, strategies)
+                    })
+                    .collect();
+                PnlNode::rollup(RollupLevel::Book, &book, accounts)
+            })
+            .collect();
+        Ok(PnlNode::rollup(RollupLevel::Firm, &self.firm_currency, books))
+    }
+
+    // Explain the day's P&L change per instrument: today's trades, the move from the
+    // prior close to today's close on the carried position, fees, amendments and
+    // cancels of earlier trades booked today, and FX on the prior P&L. Whatever the
+    // components don't cover (e.g. closes missing on one of the days) is unexplained.
+    pub fn pnl_explain(&self, date: NaiveDate) -> Result<PnlExplain, String> {
+        let prior_date = date - chrono::Duration::days(1);
+        // A trade's own P&L marked at a price, fees included
+        let standalone = |trade: &Trade, mark: Option<Price>| match (&trade.status, mark) {
+            (TradeStatus::Cancelled, _) | (_, None) => Decimal::ZERO,
+            (_, Some(mark)) => (mark - trade.price) * trade.signed_quantity() * trade.multiplier - trade.total_fees(),
+        };
+
+        let mut lines = Vec::new();
+        for (instrument, days) in &self.daily_positions {
+            let position_on = |date: NaiveDate| days.range(..=date).next_back().map(|(_, position)| position.clone());
+            let (previous, current) = (position_on(prior_date), position_on(date));
+            let todays: Vec<&Trade> = self.trades
+                .instrument_values(instrument)
+                .filter(|trade| trade.trade_date == date && !matches!(trade.status, TradeStatus::Cancelled))
+                .collect();
+            let (prior_close, close) = (self.close_price_on(instrument, prior_date), self.close_price_on(instrument, date));
+            let amendments: Money = self.audit_log
+                .iter()
+                .filter(|entry| entry.recorded_at.date_naive() == date && !matches!(entry.action, AuditAction::Add))
+                .filter_map(|entry| Some((entry.before.as_ref()?, entry.after.as_ref()?)))
+                .filter(|(before, _)| before.instrument == *instrument && before.trade_date < date)
+                .map(|(before, after)| standalone(after, prior_close) - standalone(before, prior_close))
+                .sum();
+            if previous.is_none() && current.is_none() && amendments.is_zero() {
+                continue;
+            }
+
+            let value = |position: &Option<TradePosition>, close: Option<Price>| position.as_ref().map_or(Decimal::ZERO, |position| {
+                position.realized_pnl + close.map_or(Decimal::ZERO, |close| position.unrealized_pnl(close))
+            });
+            let previous_local = value(&previous, prior_close) - amendments;
+            let current_local = value(&current, close);
+            let price_move = match (&previous, prior_close, close) {
+                (Some(previous), Some(prior_close), Some(close)) => previous.unrealized_pnl(close) - previous.unrealized_pnl(prior_close),
+                _ => Decimal::ZERO,
+            };
+            let fees: Money = todays.iter().map(|trade| -trade.total_fees()).sum();
+            let new_trades: Money = todays.iter().map(|trade| standalone(trade, close)).sum::<Money>() - fees;
+
+            let currency = self.instrument_currency(instrument);
+            let (prior_rate, rate) = (self.fx_rates.rate_on(currency, prior_date)?, self.fx_rates.rate_on(currency, date)?);
+            let line = PnlExplainLine {
+                instrument: instrument.clone(),
+                previous_pnl: previous_local * prior_rate,
+                pnl: current_local * rate,
+                new_trades: new_trades * rate,
+                price_move: price_move * rate,
+                fees: fees * rate,
+                amendments: amendments * rate,
+                fx: previous_local * (rate - prior_rate),
+                unexplained: (current_local - previous_local - new_trades - price_move - fees - amendments) * rate,
+            };
+            if !line.change().is_zero() || !line.amendments.is_zero() {
+                lines.push(line);
+            }
+        }
+        Ok(PnlExplain { date, lines })
+    }
+
+    pub fn print_pnl_rollup(&self, as_of_date: NaiveDate) {
+        println!("\n=== P&L Rollup as of {} ===", as_of_date);
+        match self.pnl_rollup(as_of_date) {
+            Ok(firm) => print_pnl_node(&firm, 0),
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+
+    // Every field a custom report can use, one row per account and instrument with
+    // an open position or realized P&L, marked like position_summary
+    pub fn report_rows(&self, as_of_date: NaiveDate) -> Vec<HashMap<&'static str, ReportValue>> {
+        let mut rows = Vec::new();
+        for account_id in self.account_ids() {
+            for (instrument, position) in self.build_account_positions_as_of(&account_id, as_of_date) {
+                if position.quantity == 0 && position.realized_pnl.is_zero() {
+                    continue;
+                }
+                let market_price = self.get_market_price(&instrument).unwrap_or(position.average_price);
+                let market_value = position.market_value(market_price);
+                let unrealized_pnl = position.unrealized_pnl(market_price);
+                let text = |value: &str| ReportValue::Text(value.to_string());
+                rows.push(HashMap::from([
+                    ("account", text(&account_id)),
+                    ("book", text(self.account_book(&account_id))),
+                    ("instrument", text(&instrument)),
+                    ("sector", text(self.instrument_sector(&instrument))),
+                    ("currency", text(self.instrument_currency(&instrument))),
+                    ("side", text(if position.quantity < 0 { "short" } else if position.quantity > 0 { "long" } else { "flat" })),
+                    ("quantity", ReportValue::Number(Decimal::from(position.quantity))),
+                    ("average_price", ReportValue::Number(position.average_price)),
+                    ("market_price", ReportValue::Number(market_price)),
+                    ("market_value", ReportValue::Number(market_value)),
+                    ("exposure", ReportValue::Number(market_value.abs())),
+                    ("realized_pnl", ReportValue::Number(position.realized_pnl)),
+                    ("unrealized_pnl", ReportValue::Number(unrealized_pnl)),
+                    ("total_pnl", ReportValue::Number(position.realized_pnl + unrealized_pnl)),
+                ]));
+            }
+        }
+        rows
+    }
+
+    pub fn run_report(&self, definition: &ReportDefinition, as_of_date: NaiveDate) -> CustomReport {
+        use std::cmp::Ordering;
+        let mut rows: Vec<HashMap<&str, ReportValue>> = self.report_rows(as_of_date)
+            .into_iter()
+            .filter(|row| definition.filters.iter().all(|filter| filter.matches(row)))
+            .collect();
+        let group_key = |row: &HashMap<&str, ReportValue>| definition.group_by.as_ref().map(|field| row[field.as_str()].render(field));
+        rows.sort_by(|a, b| {
+            let by_sort_keys = definition.sort.iter().fold(Ordering::Equal, |ordering, (field, descending)| {
+                ordering.then_with(|| {
+                    let ordering = a[field.as_str()].compare(&b[field.as_str()]).unwrap_or(Ordering::Equal);
+                    if *descending { ordering.reverse() } else { ordering }
+                })
+            });
+            group_key(a).cmp(&group_key(b)).then(by_sort_keys)
+        });
+
+        let mut groups: Vec<ReportGroup> = Vec::new();
+        for row in &rows {
+            let key = group_key(row);
+            if groups.last().is_none_or(|group| group.key != key) {
+                groups.push(ReportGroup {
+                    key,
+                    rows: Vec::new(),
+                    subtotals: definition.columns.iter().map(|field| REPORT_SUMMED_FIELDS.contains(&field.as_str()).then_some(Decimal::ZERO)).collect(),
+                });
+            }
+            let group = groups.last_mut().unwrap();
+            for (total, field) in group.subtotals.iter_mut().zip(&definition.columns) {
+                if let (Some(total), ReportValue::Number(value)) = (total.as_mut(), &row[field.as_str()]) {
+                    *total += *value;
+                }
+            }
+            group.rows.push(definition.columns.iter().map(|field| row[field.as_str()].clone()).collect());
+        }
+
+        CustomReport {
+            name: definition.name.clone(),
+            as_of_date,
+            columns: definition.columns.clone(),
+            group_by: definition.group_by.clone(),
+            groups,
+            format: definition.format,
+        }
+    }
+
+    pub fn print_position_summary(&self, as_of_date: NaiveDate, group_by: Option<Dimension>) {
+        let summary = self.position_summary(as_of_date, group_by);
+        match summary.group_by {
+            Some(dimension) => println!("\n=== Position Summary as of {} by {:?} ===", summary.as_of_date, dimension),
+            None => println!("\n=== Position Summary as of {} ===", summary.as_of_date),
+        }
+
+        let mut group_totals = summary.group_totals.iter().peekable();
+        for (index, row) in summary.rows.iter().enumerate() {
+            if summary.group_by.is_some() && (index == 0 || summary.rows[index - 1].group != row.group) {
+                println!("[{}]", row.group);
+            }
+            println!("{}: {} shares @ ${:.2} avg | Market: ${:.2} | Value: ${:.2} | Realized P&L: ${:.2} | Unrealized P&L: ${:.2}", 
+                row.instrument, 
+                row.quantity, 
+                row.average_price,
+                row.market_price,
+                row.market_value,
+                row.realized_pnl,
+                row.unrealized_pnl
+            );
+            let group_ends = summary.rows.get(index + 1).is_none_or(|next| next.group != row.group);
+            if let Some(totals) = group_totals.next_if(|totals| group_ends && totals.group == row.group) {
+                println!("  Subtotal: Value: ${:.2} | Realized P&L: ${:.2} | Unrealized P&L: ${:.2}", totals.market_value, totals.realized_pnl, totals.unrealized_pnl);
+            }
+        }
+        
+        println!("\n--- Portfolio Summary ---");
+        println!("Total Market Value: ${:.2}", summary.totals.market_value);
+        println!("Total Realized P&L: ${:.2}", summary.totals.realized_pnl);
+        println!("Total Unrealized P&L: ${:.2}", summary.totals.unrealized_pnl);
+        println!("Total P&L: ${:.2}", summary.totals.total_pnl());
+    }
+
+    // Link an open long position and an open short position as a pair. The
+    // current quantity ratio becomes the target hedge ratio.
+    pub fn link_pair(&mut self, name: &str, long_leg: &str, short_leg: &str) -> Result<(), PositionError> {
+        let long_quantity = self.get_position(long_leg).map_or(0, |position| position.quantity);
+        let short_quantity = self.get_position(short_leg).map_or(0, |position| position.quantity);
+        if long_quantity <= 0 {
+            return Err(PositionError::PositionMissing { instrument: long_leg.to_string(), side: Side::Buy });
+        }
+        if short_quantity >= 0 {
+            return Err(PositionError::PositionMissing { instrument: short_leg.to_string(), side: Side::Sell });
+        }
+
+        self.pairs.insert(name.to_string(), PairDefinition {
+            name: name.to_string(),
+            long_leg: long_leg.to_string(),
+            short_leg: short_leg.to_string(),
+            target_hedge_ratio: -short_quantity as f64 / long_quantity as f64,
+        });
+        Ok(())
+    }
+
+    pub fn pair_report(&self, name: &str) -> Option<PairReport> {
+        let pair = self.pairs.get(name)?;
+        let long_position = self.get_position(&pair.long_leg)?;
+        let short_position = self.get_position(&pair.short_leg)?;
+        // Legs without a mark are valued at their average price
+        let long_price = self.get_market_price(&pair.long_leg).unwrap_or(long_position.average_price);
+        let short_price = self.get_market_price(&pair.short_leg).unwrap_or(short_position.average_price);
+
+        let long_leg_pnl = long_position.total_pnl(long_price);
+        let short_leg_pnl = short_position.total_pnl(short_price);
+        let current_hedge_ratio = if long_position.quantity != 0 {
+            -short_position.quantity as f64 / long_position.quantity as f64
+        } else {
+            0.0
+        };
+
+        Some(PairReport {
+            name: pair.name.clone(),
+            long_leg_pnl,
+            short_leg_pnl,
+            combined_pnl: long_leg_pnl + short_leg_pnl,
+            spread: long_price - short_price * pair.target_hedge_ratio,
+            current_hedge_ratio,
+            hedge_ratio_drift: current_hedge_ratio - pair.target_hedge_ratio,
+        })
+    }
+
+    pub fn pair_reports(&self) -> Vec<PairReport> {
+        let mut names: Vec<&String> = self.pairs.keys().collect();
+        names.sort();
+        names.into_iter().filter_map(|name| self.pair_report(name)).collect()
+    }
+
+    pub fn print_pair_reports(&self) {
+        println!("\n=== Pair Trades ===");
+        for report in self.pair_reports() {
+            println!("{}: Long P&L: ${:.2} | Short P&L: ${:.2} | Combined: ${:.2} | Spread: {:.2} | Hedge Ratio: {:.3} (drift {:+.3})",
+                report.name,
+                report.long_leg_pnl,
+                report.short_leg_pnl,
+                report.combined_pnl,
+                report.spread,
+                report.current_hedge_ratio,
+                report.hedge_ratio_drift
+            );
+        }
+    }
+
+    pub fn set_account_book(&mut self, account_id: &str, book: &str) {
+        self.account_books.insert(account_id.to_string(), book.to_string());
+    }
+
+    pub fn account_book(&self, account_id: &str) -> &str {
+        self.account_books.get(account_id).map_or(UNASSIGNED, |book| book.as_str())
+    }
+
+    pub fn set_account_custodian(&mut self, account_id: &str, custodian: &str) {
+        self.account_custodians.insert(account_id.to_string(), custodian.to_string());
+    }
+
+    pub fn account_custodian(&self, account_id: &str) -> &str {
+        self.account_custodians.get(account_id).map_or(UNASSIGNED, |custodian| custodian.as_str())
+    }
+
+    pub fn set_account_currency(&mut self, account_id: &str, currency: &str) {
+        self.account_currencies.insert(account_id.to_string(), currency.to_string());
+    }
+
+    pub fn set_instrument_currency(&mut self, instrument: &str, currency: &str) {
+        self.instrument_currencies.insert(instrument.to_string(), currency.to_string());
+    }
+
+    pub fn set_instrument_sector(&mut self, instrument: &str, sector: &str) {
+        self.instrument_sectors.insert(instrument.to_string(), sector.to_string());
+    }
+
+    pub fn instrument_sector(&self, instrument: &str) -> &str {
+        self.instrument_sectors.get(instrument).map_or("Unclassified", |sector| sector.as_str())
+    }
+
+    pub fn set_instrument_tag(&mut self, instrument: &str, tag: &str, value: &str) {
+        self.instrument_tags.entry(instrument.to_string()).or_default().insert(tag.to_string(), value.to_string());
+    }
+
+    pub fn instrument_tag(&self, instrument: &str, tag: &str) -> &str {
+        self.instrument_tags.get(instrument).and_then(|tags| tags.get(tag)).map_or(UNASSIGNED, |value| value.as_str())
+    }
+
+    pub fn account_currency(&self, account_id: &str) -> &str {
+        self.account_currencies.get(account_id).unwrap_or(&self.firm_currency)
+    }
+
+    pub fn instrument_currency(&self, instrument: &str) -> &str {
+        self.instrument_currencies.get(instrument).unwrap_or(&self.firm_currency)
+    }
+
+    // Accounts that have booked at least one trade, sorted
+    pub fn account_ids(&self) -> Vec<String> {
+        let mut accounts: Vec<String> = self.trades.values().map(|trade| trade.account_id.clone()).collect();
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+
+    // Positions of a single account, rebuilt from its active trades
+    pub fn build_account_positions(&self, account_id: &str) -> BTreeMap<String, TradePosition> {
+        self.build_account_positions_as_of(account_id, NaiveDate::MAX)
+    }
+
+    pub fn build_account_positions_as_of(&self, account_id: &str, as_of_date: NaiveDate) -> BTreeMap<String, TradePosition> {
+        self.build_positions_where(as_of_date, |trade| trade.account_id == account_id)
+    }
+
+    // Positions of every account in the book, folded together as one book
+    pub fn build_book_positions_as_of(&self, book: &str, as_of_date: NaiveDate) -> BTreeMap<String, TradePosition> {
+        self.build_positions_where(as_of_date, |trade| self.account_book(&trade.account_id) == book)
+    }
+
+    // Accounts that have traded, grouped by book
+    pub fn books(&self) -> BTreeMap<String, Vec<String>> {
+        let mut books: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for account_id in self.account_ids() {
+            books.entry(self.account_book(&account_id).to_string()).or_default().push(account_id);
+        }
+        books
+    }
+
+    pub fn build_positions_where(&self, as_of_date: NaiveDate, include: impl Fn(&Trade) -> bool) -> BTreeMap<String, TradePosition> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.trade_date <= as_of_date && !matches!(trade.status, TradeStatus::Cancelled))
+            .filter(|trade| include(trade))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+
+        let mut positions: BTreeMap<String, TradePosition> = BTreeMap::new();
+        for trade in trades {
+            positions.entry(trade.instrument.clone())
+                .or_insert_with(|| TradePosition::new(trade.instrument.clone()))
+                .update_position(trade);
+        }
+        positions
+    }
+
+    // One instrument's position firm-wide, per book and per account. Each level folds
+    // its own trades, so one account selling to another within a book realizes P&L
+    // for the book while both accounts keep their open positions.
+    pub fn position_breakdown(&self, instrument: &str, as_of_date: NaiveDate) -> PositionBreakdown {
+        let mut breakdown = PositionBreakdown {
+            instrument: instrument.to_string(),
+            as_of_date,
+            firm: TradePosition::new(instrument.to_string()),
+            books: BTreeMap::new(),
+            accounts: BTreeMap::new(),
+        };
+        let mut trades: Vec<&Trade> = self.trades
+            .instrument_values_between(instrument, NaiveDate::MIN, as_of_date)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+        for trade in trades {
+            breakdown.firm.update_position(trade);
+            breakdown.books.entry(self.account_book(&trade.account_id).to_string())
+                .or_insert_with(|| TradePosition::new(instrument.to_string()))
+                .update_position(trade);
+            breakdown.accounts.entry(trade.account_id.clone())
+                .or_insert_with(|| TradePosition::new(instrument.to_string()))
+                .update_position(trade);
+        }
+        breakdown
+    }
+
+    pub fn print_position_breakdown(&self, instrument: &str, as_of_date: NaiveDate) {
+        let breakdown = self.position_breakdown(instrument, as_of_date);
+        let mark = self.get_market_price(instrument);
+        let line = |level: &str, name: &str, position: &TradePosition| {
+            let unrealized = mark.map_or("-".to_string(), |mark| format!("{:.2}", position.unrealized_pnl(mark)));
+            println!("  {:<8} {:<10} {:>6} @ {:>9.2}  realized {:>9.2}  unrealized {:>9}", level, name, position.quantity, position.average_price, position.realized_pnl, unrealized);
+        };
+        println!("{} as of {} (mark {:?})", breakdown.instrument, breakdown.as_of_date, mark);
+        line("Firm", &self.firm_currency, &breakdown.firm);
+        for (book, position) in &breakdown.books {
+            line("Book", book, position);
+        }
+        for (account_id, position) in &breakdown.accounts {
+            line("Account", account_id, position);
+        }
+    }
+
+    // Account-level P&L in the account's own base currency, with instrument amounts
+    // converted at the given EOD rates
+    pub fn account_currency_report(&self, account_id: &str, closing_rates: &FxRates, opening_rates: &FxRates) -> Result<AccountCurrencyReport, String> {
+        let base_currency = self.account_currency(account_id).to_string();
+        let mut realized_pnl = Decimal::ZERO;
+        let mut unrealized_pnl = Decimal::ZERO;
+        let mut market_value = Decimal::ZERO;
+
+        for (instrument, position) in self.build_account_positions(account_id) {
+            let currency = self.instrument_currency(&instrument);
+            let market_price = self.get_market_price(&instrument).unwrap_or(position.average_price);
+            realized_pnl += closing_rates.convert(position.realized_pnl, currency, &base_currency)?;
+            unrealized_pnl += closing_rates.convert(position.unrealized_pnl(market_price), currency, &base_currency)?;
+            market_value += closing_rates.convert(position.market_value(market_price), currency, &base_currency)?;
+        }
+
+        let firm_market_value = closing_rates.convert(market_value, &base_currency, &self.firm_currency)?;
+        Ok(AccountCurrencyReport {
+            account_id: account_id.to_string(),
+            base_currency: base_currency.clone(),
+            realized_pnl,
+            unrealized_pnl,
+            market_value,
+            firm_realized_pnl: closing_rates.convert(realized_pnl, &base_currency, &self.firm_currency)?,
+            firm_unrealized_pnl: closing_rates.convert(unrealized_pnl, &base_currency, &self.firm_currency)?,
+            firm_market_value,
+            translation_difference: firm_market_value - opening_rates.convert(market_value, &base_currency, &self.firm_currency)?,
+        })
+    }
+
+    // Consolidate every account into the firm currency at the closing EOD rates,
+    // reporting translation differences against the opening rates separately
+    pub fn firm_currency_report(&self, closing_rates: &FxRates, opening_rates: &FxRates) -> Result<FirmCurrencyReport, String> {
+        let accounts = self.account_ids()
+            .iter()
+            .map(|account_id| self.account_currency_report(account_id, closing_rates, opening_rates))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(FirmCurrencyReport {
+            firm_currency: self.firm_currency.clone(),
+            total_realized_pnl: accounts.iter().map(|account| account.firm_realized_pnl).sum(),
+            total_unrealized_pnl: accounts.iter().map(|account| account.firm_unrealized_pnl).sum(),
+            total_market_value: accounts.iter().map(|account| account.firm_market_value).sum(),
+            total_translation_difference: accounts.iter().map(|account| account.translation_difference).sum(),
+            accounts,
+        })
+    }
+
+    // Firm consolidation using the historical FX store: closing rates on the date and
+    // opening rates from the previous business day
+    pub fn firm_currency_report_as_of(&self, as_of_date: NaiveDate) -> Result<FirmCurrencyReport, String> {
+        let closing_rates = self.fx_rates.rates_on(as_of_date);
+        let opening_rates = self.fx_rates.rates_on(previous_business_day(as_of_date));
+        self.firm_currency_report(&closing_rates, &opening_rates)
+    }
+
+    // Daily total P&L of the whole book in the firm currency, valuing each day's
+    // positions at that day's close and FX rates
+    pub fn firm_pnl_history(&self, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, Money)>, String> {
+        self.firm_pnl_history_controlled(start_date, end_date, &QueryControl::unlimited())
+    }
+
+    pub fn firm_pnl_history_controlled(&self, start_date: NaiveDate, end_date: NaiveDate, control: &QueryControl) -> Result<Vec<(NaiveDate, Money)>, String> {
+        let mut history = Vec::new();
+        let mut current_date = start_date;
+
+        while current_date <= end_date {
+            control.check().map_err(|e| e.to_string())?;
+            let mut total = Decimal::ZERO;
+            for (instrument, position) in self.build_position_map_as_of_date(current_date) {
+                let close = self.close_price_on(&instrument, current_date).unwrap_or(position.average_price);
+                let rate = self.fx_rates.rate_on(self.instrument_currency(&instrument), current_date)?;
+                total += position.total_pnl(close) * rate;
+            }
+            history.push((current_date, total));
+            match current_date.succ_opt() {
+                Some(next_date) => current_date = next_date,
+                None => break,
+            }
+        }
+
+        Ok(history)
+    }
+
+    pub fn print_firm_currency_report(&self, closing_rates: &FxRates, opening_rates: &FxRates) {
+        match self.firm_currency_report(closing_rates, opening_rates) {
+            Ok(report) => {
+                println!("\n=== Firm Consolidation ({}) ===", report.firm_currency);
+                for account in &report.accounts {
+                    println!("{} ({}): Realized: {:.2} | Unrealized: {:.2} | Value: {:.2} -> {} Value: {:.2} | Translation: {:.2}",
+                        account.account_id,
+                        account.base_currency,
+                        account.realized_pnl,
+                        account.unrealized_pnl,
+                        account.market_value,
+                        report.firm_currency,
+                        account.firm_market_value,
+                        account.translation_difference
+                    );
+                }
+                println!("Total Realized P&L: {:.2}", report.total_realized_pnl);
+                println!("Total Unrealized P&L: {:.2}", report.total_unrealized_pnl);
+                println!("Total Market Value: {:.2}", report.total_market_value);
+                println!("Total Translation Difference: {:.2}", report.total_translation_difference);
+            },
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+
+    pub fn register_counterparty(&mut self, counterparty: Counterparty) {
+        self.counterparties.insert(counterparty.id.clone(), counterparty);
+    }
+
+    // Top of a counterparty's parent chain. Unknown counterparties are their own
+    // ultimate parent; a cycle in the hierarchy stops at the first repeated entity.
+    pub fn ultimate_parent(&self, counterparty_id: &str) -> String {
+        let mut current = counterparty_id.to_string();
+        let mut visited = vec![current.clone()];
+        while let Some(parent_id) = self.counterparties.get(&current).and_then(|counterparty| counterparty.parent_id.clone()) {
+            if visited.contains(&parent_id) {
+                break;
+            }
+            visited.push(parent_id.clone());
+            current = parent_id;
+        }
+        current
+    }
+
+    // Volume and open exposure of active trades, rolled up to each ultimate parent.
+    // With internal crosses netted, only the quantity not crossed counts.
+    pub fn counterparty_exposure_report(&self) -> Vec<CounterpartyExposure> {
+        // ultimate parent -> counterparty -> instrument -> net quantity
+        let mut groups: BTreeMap<String, BTreeMap<String, BTreeMap<String, i32>>> = BTreeMap::new();
+        let mut activity: HashMap<String, (usize, Money)> = HashMap::new();
+
+        let crossed = self.netted_cross_quantities();
+        for trade in self.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            let Some(counterparty) = &trade.counterparty else { continue };
+            let external = trade.quantity - crossed.get(&trade.trade_id).copied().unwrap_or(0);
+            if external == 0 {
+                continue;
+            }
+            let parent = self.ultimate_parent(counterparty);
+            *groups.entry(parent.clone()).or_default()
+                .entry(counterparty.clone()).or_default()
+                .entry(trade.instrument.clone()).or_insert(0) += if matches!(trade.side, Side::Buy) { external } else { -external };
+            let (count, volume) = activity.entry(parent).or_insert((0, Decimal::ZERO));
+            *count += 1;
+            *volume += trade.notional() * external / trade.quantity;
+        }
+
+        groups
+            .into_iter()
+            .map(|(parent, members)| {
+                let price_of = |instrument: &str| self.get_market_price(instrument)
+                    .or_else(|| self.get_position(instrument).map(|position| position.average_price))
+                    .unwrap_or(Decimal::ZERO);
+
+                let mut gross_exposure = Decimal::ZERO;
+                let mut netted: BTreeMap<&str, i32> = BTreeMap::new();
+                for holdings in members.values() {
+                    for (instrument, quantity) in holdings {
+                        gross_exposure += (price_of(instrument) * *quantity).abs();
+                        *netted.entry(instrument.as_str()).or_insert(0) += quantity;
+                    }
+                }
+                let net_exposure = netted
+                    .iter()
+                    .map(|(instrument, quantity)| (price_of(instrument) * *quantity).abs())
+                    .sum();
+                let (trade_count, trade_volume) = activity[&parent];
+
+                CounterpartyExposure {
+                    ultimate_parent: parent,
+                    counterparties: members.keys().cloned().collect(),
+                    trade_count,
+                    trade_volume,
+                    gross_exposure,
+                    net_exposure,
+                }
+            })
+            .collect()
+    }
+
+    pub fn print_counterparty_exposure_report(&self) {
+        println!("\n=== Counterparty Exposure by Ultimate Parent ===");
+        for exposure in self.counterparty_exposure_report() {
+            let name = self.counterparties.get(&exposure.ultimate_parent)
+                .map_or(exposure.ultimate_parent.clone(), |counterparty| counterparty.name.clone());
+            println!("{} ({}): {} trades | Volume: ${:.2} | Gross Exposure: ${:.2} | Net Exposure: ${:.2}",
+                name,
+                exposure.counterparties.join(", "),
+                exposure.trade_count,
+                exposure.trade_volume,
+                exposure.gross_exposure,
+                exposure.net_exposure
+            );
+        }
+    }
+
+    // Active trades between the dates aggregated by period, account, counterparty
+    // and instrument. Trades without a counterparty are grouped as Unassigned.
+    pub fn trade_flows(&self, start_date: NaiveDate, end_date: NaiveDate, period: FlowPeriod) -> FlowReport {
+        let mut links: BTreeMap<(NaiveDate, String, String, String), FlowLink> = BTreeMap::new();
+        for trade in self.trades.values_between(start_date, end_date).filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            let period_start = period.start_of(trade.trade_date);
+            let counterparty = trade.counterparty.clone().unwrap_or_else(|| UNASSIGNED.to_string());
+            let key = (period_start, trade.account_id.clone(), counterparty.clone(), trade.instrument.clone());
+            let link = links.entry(key).or_insert_with(|| FlowLink {
+                period_start,
+                account_id: trade.account_id.clone(),
+                counterparty,
+                instrument: trade.instrument.clone(),
+                trade_count: 0,
+                bought_quantity: 0,
+                sold_quantity: 0,
+                bought: Decimal::ZERO,
+                sold: Decimal::ZERO,
+            });
+            link.trade_count += 1;
+            match trade.side {
+                Side::Buy => {
+                    link.bought_quantity += trade.quantity;
+                    link.bought += trade.notional();
+                },
+                Side::Sell => {
+                    link.sold_quantity += trade.quantity;
+                    link.sold += trade.notional();
+                },
+            }
+        }
+        FlowReport { period, start_date, end_date, links: links.into_values().collect() }
+    }
+
+    pub fn print_trade_flows(&self, start_date: NaiveDate, end_date: NaiveDate, period: FlowPeriod) {
+        let report = self.trade_flows(start_date, end_date, period);
+        println!("Trade flows {} to {} by {:?}", report.start_date, report.end_date, report.period);
+        println!("{:<10} {:<10} {:<12} {:<6} {:>6} {:>12} {:>12} {:>12} {:>12}", "Period", "Account", "Cpty", "Instr", "Trades", "Bought", "Sold", "Gross", "Net");
+        for link in &report.links {
+            println!("{:<10} {:<10} {:<12} {:<6} {:>6} {:>12.2} {:>12.2} {:>12.2} {:>12.2}",
+                     link.period_start, link.account_id, link.counterparty, link.instrument, link.trade_count, link.bought, link.sold, link.gross(), link.net());
+        }
+        for opportunity in report.crossing_opportunities() {
+            println!("Could cross {} {} in period {} ({} buying, {} selling), about ${:.2}",
+                     opportunity.crossable_quantity, opportunity.instrument, opportunity.period_start,
+                     opportunity.buyers.join("/"), opportunity.sellers.join("/"), opportunity.crossable_notional);
+        }
+    }
+
+    // Active trades between the dates where one account bought and another sold the
+    // same instrument at the same execution time and price. At each time and price,
+    // buys and sells are paired in trade id order; a trade larger than its opposite
+    // can cross with several.
+    pub fn internal_crosses(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<InternalCross> {
+        // Buys and sells at one time and price, each with the quantity left to pair
+        type Sides<'a> = (Vec<(&'a Trade, i32)>, Vec<(&'a Trade, i32)>);
+        let mut candidates: BTreeMap<(&str, DateTime<Utc>, Price), Sides> = BTreeMap::new();
+        for trade in self.trades.values_between(start_date, end_date).filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            let (buys, sells) = candidates.entry((&trade.instrument, trade.executed_at, trade.price)).or_default();
+            match trade.side {
+                Side::Buy => buys.push((trade, trade.quantity)),
+                Side::Sell => sells.push((trade, trade.quantity)),
+            }
+        }
+
+        let mut crosses = Vec::new();
+        for ((instrument, executed_at, price), (mut buys, mut sells)) in candidates {
+            buys.sort_by_key(|(trade, _)| trade.trade_id);
+            sells.sort_by_key(|(trade, _)| trade.trade_id);
+            for (buy, buy_left) in buys.iter_mut() {
+                for (sell, sell_left) in sells.iter_mut() {
+                    if *buy_left == 0 {
+                        break;
+                    }
+                    if *sell_left == 0 || sell.account_id == buy.account_id {
+                        continue;
+                    }
+                    let quantity = (*buy_left).min(*sell_left);
+                    *buy_left -= quantity;
+                    *sell_left -= quantity;
+                    crosses.push(InternalCross {
+                        instrument: instrument.to_string(),
+                        executed_at,
+                        price,
+                        quantity,
+                        buy_trade_id: buy.trade_id,
+                        sell_trade_id: sell.trade_id,
+                        buyer: buy.account_id.clone(),
+                        seller: sell.account_id.clone(),
+                        cross_book: self.account_book(&buy.account_id) != self.account_book(&sell.account_id),
+                    });
+                }
+            }
+        }
+        crosses
+    }
+
+    // Add the internal crosses between the dates to the compliance review list,
+    // skipping pairs already on it, and return the newly flagged ones
+    pub fn flag_internal_crosses(&mut self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<InternalCross> {
+        let flagged: HashSet<(TradeId, TradeId)> = self.internal_cross_flags.iter().map(|cross| (cross.buy_trade_id, cross.sell_trade_id)).collect();
+        let new: Vec<InternalCross> = self.internal_crosses(start_date, end_date)
+            .into_iter()
+            .filter(|cross| !flagged.contains(&(cross.buy_trade_id, cross.sell_trade_id)))
+            .collect();
+        self.internal_cross_flags.extend(new.iter().cloned());
+        new
+    }
+
+    pub fn set_net_internal_crosses(&mut self, net: bool) {
+        self.net_internal_crosses = net;
+    }
+
+    // Quantity of each trade crossed internally, when crosses are netted out of external reporting
+    pub fn netted_cross_quantities(&self) -> HashMap<TradeId, i32> {
+        let mut crossed = HashMap::new();
+        if self.net_internal_crosses {
+            for cross in self.internal_crosses(NaiveDate::MIN, NaiveDate::MAX) {
+                *crossed.entry(cross.buy_trade_id).or_insert(0) += cross.quantity;
+                *crossed.entry(cross.sell_trade_id).or_insert(0) += cross.quantity;
+            }
+        }
+        crossed
+    }
+
+    pub fn print_internal_cross_flags(&self) {
+        println!("Internal crosses for compliance review: {}", self.internal_cross_flags.len());
+        for cross in &self.internal_cross_flags {
+            println!("  {} {} @ {:.2} at {}: {} (trade {}) bought from {} (trade {}){}",
+                     cross.quantity, cross.instrument, cross.price, cross.executed_at.format("%Y-%m-%d %H:%M:%S"),
+                     cross.buyer, cross.buy_trade_id, cross.seller, cross.sell_trade_id,
+                     if cross.cross_book { " [cross-book]" } else { "" });
+        }
+    }
+
+    // Trade date plus the instrument's settlement cycle in business days, skipping
+    // its exchange's holidays when it has a session calendar
+    pub fn settlement_date(&self, trade: &Trade) -> NaiveDate {
+        let cycle = self.instruments.get(&trade.instrument).map_or(DEFAULT_SETTLEMENT_DAYS, |record| record.settlement_days);
+        let calendar = self.session_calendar(&trade.instrument);
+        let mut date = trade.trade_date;
+        for _ in 0..cycle {
+            date = next_business_day(date);
+            while calendar.is_some_and(|calendar| !calendar.is_trading_day(date)) {
+                date = next_business_day(date);
+            }
+        }
+        date
+    }
+
+    // Movements of trades done by `as_of_date` that settle within the next `days`
+    // business days. Futures are margined rather than settled and are left out.
+    pub fn settlement_ladder(&self, as_of_date: NaiveDate, days: u32) -> SettlementLadder {
+        let mut dates = Vec::new();
+        let mut date = as_of_date;
+        for _ in 0..days {
+            date = next_business_day(date);
+            dates.push(date);
+        }
+        let through = dates.last().copied().unwrap_or(as_of_date);
+
+        let mut movements: Vec<SettlementMovement> = self.trades
+            .values()
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled) && trade.trade_date <= as_of_date)
+            .filter(|trade| !self.instruments.get(&trade.instrument).is_some_and(|record| matches!(record.asset_class, AssetClass::Future)))
+            .filter_map(|trade| {
+                let settlement_date = self.settlement_date(trade);
+                if settlement_date <= as_of_date || settlement_date > through {
+                    return None;
+                }
+                let (quantity, cash) = match trade.side {
+                    Side::Buy => (trade.quantity, -(trade.notional() + trade.total_fees())),
+                    Side::Sell => (-trade.quantity, trade.notional() - trade.total_fees()),
+                };
+                Some(SettlementMovement {
+                    settlement_date,
+                    custodian: self.account_custodian(&trade.account_id).to_string(),
+                    account_id: trade.account_id.clone(),
+                    trade_id: trade.trade_id,
+                    instrument: trade.instrument.clone(),
+                    quantity,
+                    currency: self.instrument_currency(&trade.instrument).to_string(),
+                    cash,
+                })
+            })
+            .collect();
+        movements.sort_by(|a, b| (a.settlement_date, &a.custodian, a.trade_id).cmp(&(b.settlement_date, &b.custodian, b.trade_id)));
+        SettlementLadder { as_of_date, dates, movements }
+    }
+
+    pub fn print_settlement_ladder(&self, as_of_date: NaiveDate, days: u32) {
+        let ladder = self.settlement_ladder(as_of_date, days);
+        println!("Settlement ladder as of {}, {} movement(s)", ladder.as_of_date, ladder.movements.len());
+        let header: Vec<String> = ladder.dates.iter().map(|date| format!("{:>12}", date.format("%a %m-%d"))).collect();
+        println!("{:<12} {:<8} {:<10} {}", "Custodian", "Ccy", "Cash", header.join(" "));
+        let cumulative = ladder.cumulative_cash();
+        for (key, row) in ladder.cash_ladder() {
+            let daily: Vec<String> = row.iter().map(|amount| format!("{:>12.2}", amount)).collect();
+            let running: Vec<String> = cumulative[&key].iter().map(|amount| format!("{:>12.2}", amount)).collect();
+            println!("{:<12} {:<8} {:<10} {}", key.0, key.1, "net", daily.join(" "));
+            println!("{:<12} {:<8} {:<10} {}", key.0, key.1, "cumulative", running.join(" "));
+        }
+        println!("{:<12} {:<8} {:<10} {}", "Custodian", "Instr", "Units", header.join(" "));
+        for ((custodian, instrument), row) in ladder.security_ladder() {
+            let daily: Vec<String> = row.iter().map(|quantity| format!("{:>12}", quantity)).collect();
+            println!("{:<12} {:<8} {:<10} {}", custodian, instrument, "net", daily.join(" "));
+        }
+        for need in ladder.funding_needs() {
+            println!("Fund {} {} nostro with {:.2} by {}", need.custodian, need.currency, need.shortfall, need.date);
+        }
+        let unassigned: BTreeSet<&str> = ladder.movements.iter().filter(|movement| movement.custodian == UNASSIGNED).map(|movement| movement.account_id.as_str()).collect();
+        if !unassigned.is_empty() {
+            println!("No custodian for account(s) {}", unassigned.into_iter().collect::<Vec<_>>().join(", "));
+        }
+    }
+
+    pub fn import_confirmations(&mut self, confirmations: Vec<Confirmation>) {
+        self.confirmations.extend(confirmations);
+    }
+
+    // Pair active counterparty trades with imported confirmations. A confirmation is
+    // compared with trades of the same counterparty, instrument and side: the closest
+    // one within tolerance is matched, otherwise the closest one is reported as a
+    // mismatch. Everything left over is unmatched, aged against `as_of_date`.
+    pub fn match_confirmations(&self, as_of_date: NaiveDate, tolerance: &MatchTolerance) -> ConfirmationMatchReport {
+        let mut candidates: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.counterparty.is_some() && !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        candidates.sort_by_key(|trade| trade.trade_id);
+
+        let mut confirmations: Vec<&Confirmation> = self.confirmations.iter().collect();
+        confirmations.sort_by(|a, b| a.confirm_id.cmp(&b.confirm_id));
+
+        let mut report = ConfirmationMatchReport {
+            matched: Vec::new(),
+            mismatched: Vec::new(),
+            unmatched_trades: Vec::new(),
+            unmatched_confirmations: Vec::new(),
+        };
+        let mut claimed: Vec<TradeId> = Vec::new();
+
+        for confirm in confirmations {
+            let differences_for = |trade: &Trade| {
+                let mut differences = Vec::new();
+                if (trade.price - confirm.price).abs() > tolerance.price {
+                    differences.push(format!("price {} vs {}", trade.price, confirm.price));
+                }
+                if (trade.quantity - confirm.quantity).abs() > tolerance.quantity {
+                    differences.push(format!("quantity {} vs {}", trade.quantity, confirm.quantity));
+                }
+                if (trade.trade_date - confirm.trade_date).num_days().abs() > tolerance.trade_date_days {
+                    differences.push(format!("trade date {} vs {}", trade.trade_date, confirm.trade_date));
+                }
+                differences
+            };
+            let distance = |trade: &Trade| {
+                (trade.price - confirm.price).abs().to_f64() / confirm.price.abs().to_f64().max(f64::EPSILON)
+                    + (trade.quantity - confirm.quantity).abs() as f64 / confirm.quantity.abs().max(1) as f64
+                    + (trade.trade_date - confirm.trade_date).num_days().abs() as f64
+            };
+
+            let mut same_key: Vec<&Trade> = candidates
+                .iter()
+                .copied()
+                .filter(|trade| !claimed.contains(&trade.trade_id))
+                .filter(|trade| trade.counterparty.as_deref() == Some(confirm.counterparty.as_str()))
+                .filter(|trade| trade.instrument == confirm.instrument)
+                .filter(|trade| matches!((&trade.side, &confirm.side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell)))
+                .collect();
+            same_key.sort_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap());
+
+            let within_tolerance = same_key.iter().find(|trade| differences_for(trade).is_empty());
+            match (within_tolerance, same_key.first()) {
+                (Some(trade), _) => {
+                    claimed.push(trade.trade_id);
+                    report.matched.push((trade.trade_id, confirm.confirm_id.clone()));
+                },
+                (None, Some(trade)) => {
+                    claimed.push(trade.trade_id);
+                    report.mismatched.push(ConfirmationMismatch {
+                        trade_id: trade.trade_id,
+                        confirm_id: confirm.confirm_id.clone(),
+                        differences: differences_for(trade),
+                        age_days: (as_of_date - trade.trade_date).num_days(),
+                    });
+                },
+                (None, None) => report.unmatched_confirmations.push((confirm.confirm_id.clone(), (as_of_date - confirm.received_date).num_days())),
+            }
+        }
+
+        report.unmatched_trades = candidates
+            .iter()
+            .filter(|trade| !claimed.contains(&trade.trade_id))
+            .map(|trade| (trade.trade_id, (as_of_date - trade.trade_date).num_days()))
+            .collect();
+        report
+    }
+
+    pub fn print_confirmation_report(&self, as_of_date: NaiveDate, tolerance: &MatchTolerance) {
+        let report = self.match_confirmations(as_of_date, tolerance);
+        println!("\n=== Confirmation Matching as of {} ===", as_of_date);
+        println!("Matched: {}", report.matched.len());
+        for (trade_id, confirm_id) in &report.matched {
+            println!("  Trade {} <-> {}", trade_id, confirm_id);
+        }
+        println!("Mismatched: {}", report.mismatched.len());
+        for mismatch in &report.mismatched {
+            println!("  Trade {} <-> {} ({} days): {}", mismatch.trade_id, mismatch.confirm_id, mismatch.age_days, mismatch.differences.join(", "));
+        }
+        println!("Unmatched trades: {}", report.unmatched_trades.len());
+        for (trade_id, age_days) in &report.unmatched_trades {
+            println!("  Trade {} ({} days)", trade_id, age_days);
+        }
+        println!("Unmatched confirmations: {}", report.unmatched_confirmations.len());
+        for (confirm_id, age_days) in &report.unmatched_confirmations {
+            println!("  {} ({} days)", confirm_id, age_days);
+        }
+    }
+
+    pub fn set_reorder_window(&mut self, records: usize) {
+        self.reorder_window = records;
+    }
+
+    pub fn set_feed_reconnect_limit(&mut self, attempts: usize) {
+        self.feed_reconnect_limit = attempts;
+    }
+
+    // Connect, retrying refused connections until the run's reconnect limit is used up
+    pub fn connect_feed<A: SourceAdapter>(&self, adapter: &mut A, summary: &mut IngestSummary) -> Result<(), PositionError> {
+        loop {
+            match adapter.connect() {
+                Ok(()) => return Ok(()),
+                Err(_) if summary.reconnects < self.feed_reconnect_limit => summary.reconnects += 1,
+                Err(e) => return Err(PositionError::Source(e)),
+            }
+        }
+    }
+
+    // Pull every available record from a feed adapter into the repository. Records
+    // that fail to map or validate are quarantined instead of stopping the feed.
+    // Records of sequenced feeds are booked in sequence order: early arrivals wait
+    // in a reorder buffer, and when the buffer outgrows the reorder window (or the
+    // feed is caught up) the missing range is declared a gap and booking moves on.
+    // A dropped connection is re-established and ingestion carries on; whatever the
+    // feed resends after reconnecting is skipped as a duplicate.
+    pub fn ingest_from<A: SourceAdapter>(&mut self, adapter: &mut A) -> Result<IngestSummary, PositionError> {
+        let mut summary = IngestSummary {
+            source: adapter.name().to_string(),
+            polled: 0,
+            ingested: 0,
+            quarantined: 0,
+            out_of_order: 0,
+            duplicates: 0,
+            gaps_opened: 0,
+            reconnects: 0,
+        };
+        self.connect_feed(adapter, &mut summary)?;
+        let mut pending: BTreeMap<u64, (A::Record, DateTime<Utc>)> = BTreeMap::new();
+
+        loop {
+            let records = match adapter.poll() {
+                Ok(records) => records,
+                Err(e) if summary.reconnects >= self.feed_reconnect_limit => return Err(PositionError::Source(e)),
+                Err(_) => {
+                    summary.reconnects += 1;
+                    self.connect_feed(adapter, &mut summary)?;
+                    continue;
+                },
+            };
+            if records.is_empty() {
+                break;
+            }
+            let received_at = Utc::now();
+
+            for record in records {
+                summary.polled += 1;
+                let Some(sequence) = adapter.sequence_number(&record) else {
+                    self.ingest_record(adapter, record, received_at, &mut summary);
+                    continue;
+                };
+
+                let state = self.sequence_states.entry(summary.source.clone()).or_default();
+                if state.highest_seen.is_some_and(|highest| sequence < highest) {
+                    state.out_of_order += 1;
+                    summary.out_of_order += 1;
+                }
+                state.highest_seen = state.highest_seen.max(Some(sequence));
+                let next_expected = *state.next_expected.get_or_insert(sequence);
+                let lowest_seen = *state.lowest_seen.get_or_insert(sequence);
+
+                if sequence < lowest_seen {
+                    // The feed started below its first arrival; what lies between
+                    // is a gap until it turns up
+                    if sequence + 1 < lowest_seen {
+                        state.open_gaps.push((sequence + 1, lowest_seen - 1));
+                    }
+                    state.lowest_seen = Some(sequence);
+                    self.ingest_record(adapter, record, received_at, &mut summary);
+                } else if sequence >= next_expected && !pending.contains_key(&sequence) {
+                    pending.insert(sequence, (record, received_at));
+                } else if let Some(index) = state.open_gaps.iter().position(|(first, last)| (*first..=*last).contains(&sequence)) {
+                    // Late arrival inside a declared gap
+                    let (first, last) = state.open_gaps.remove(index);
+                    if sequence < last {
+                        state.open_gaps.insert(index, (sequence + 1, last));
+                    }
+                    if sequence > first {
+                        state.open_gaps.insert(index, (first, sequence - 1));
+                    }
+                    self.ingest_record(adapter, record, received_at, &mut summary);
+                } else {
+                    state.duplicates += 1;
+                    summary.duplicates += 1;
+                }
+                self.release_pending(adapter, &mut pending, &mut summary, false);
+            }
+        }
+        self.release_pending(adapter, &mut pending, &mut summary, true);
+
+        Ok(summary)
+    }
+
+    // Book buffered records that are next in sequence. When the buffer is over the
+    // reorder window, or `flush` is set, skip ahead to the lowest buffered record
+    // and record the skipped range as a gap.
+    pub fn release_pending<A: SourceAdapter>(&mut self, adapter: &A, pending: &mut BTreeMap<u64, (A::Record, DateTime<Utc>)>, summary: &mut IngestSummary, flush: bool) {
+        while let Some((&sequence, _)) = pending.first_key_value() {
+            let state = self.sequence_states.entry(summary.source.clone()).or_default();
+            let next_expected = state.next_expected.unwrap_or(sequence);
+            if sequence != next_expected {
+                if !flush && pending.len() <= self.reorder_window {
+                    break;
+                }
+                state.open_gaps.push((next_expected, sequence - 1));
+                summary.gaps_opened += 1;
+            }
+            state.next_expected = Some(sequence + 1);
+            let (record, received_at) = pending.remove(&sequence).unwrap();
+            self.ingest_record(adapter, record, received_at, summary);
+        }
+    }
+
+    pub fn ingest_record<A: SourceAdapter>(&mut self, adapter: &A, record: A::Record, received_at: DateTime<Utc>, summary: &mut IngestSummary) {
+        let result = adapter.map_to_trade(&record)
+            .map_err(PositionError::InvalidRecord)
+            .and_then(|mut trade| {
+                if let Some(source_time) = adapter.source_timestamp(&record) {
+                    trade.source_time = Some(source_time);
+                }
+                self.validate_ingested_trade(&trade)?;
+                self.ingest_trade(&summary.source, trade, received_at)
+            });
+        match result {
+            Ok(()) => summary.ingested += 1,
+            Err(PositionError::DuplicateTradeId(trade_id)) if adapter.map_to_trade(&record).is_ok_and(|trade| self.is_resend(trade_id, trade)) => {
+                summary.duplicates += 1;
+            },
+            Err(e) => {
+                let reason = match e {
+                    PositionError::InvalidRecord(reason) => reason,
+                    e => e.to_string(),
+                };
+                self.quarantine.push(QuarantinedRecord {
+                    source: summary.source.clone(),
+                    record: format!("{:?}", record),
+                    reason,
+                });
+                summary.quarantined += 1;
+            }
+        }
+    }
+
+    // A record for a trade id already booked with the same terms, as feeds send
+    // again after reconnecting
+    pub fn is_resend(&self, trade_id: TradeId, mut trade: Trade) -> bool {
+        let Some(booked) = self.trades.get(&trade_id) else {
+            return false;
+        };
+        self.apply_booking_rules(&mut trade);
+        booked.instrument == trade.instrument && booked.trade_date == trade.trade_date && booked.account_id == trade.account_id
+            && matches!((&booked.side, &trade.side), (Side::Buy, Side::Buy) | (Side::Sell, Side::Sell))
+            && booked.quantity == trade.quantity && booked.price == trade.price
+    }
+
+    // Book a trade from a source, stamped with when it was received. Its source
+    // timestamp, when the source gave one, also feeds the source's latency metrics.
+    pub fn ingest_trade(&mut self, source: &str, mut trade: Trade, received_at: DateTime<Utc>) -> Result<(), PositionError> {
+        trade.received_at = Some(received_at);
+        let source_time = trade.source_time;
+        self.add_trade(trade)?;
+        if let Some(source_time) = source_time {
+            self.record_latency(source, source_time, received_at);
+        }
+        Ok(())
+    }
+
+    // Store a price from a source. The instrument is re-marked only when the price
+    // is the newest by source time, so a late or skewed arrival cannot replace a
+    // fresher price.
+    pub fn ingest_price(&mut self, source: &str, instrument: &str, price: impl Into<Price>, source_time: DateTime<Utc>, received_at: DateTime<Utc>) -> Result<(), PositionError> {
+        let price = price.into();
+        if price.is_negative() || price.is_zero() {
+            return Err(PositionError::InvalidPrice { instrument: instrument.to_string(), price });
+        }
+        let ticks = self.price_ticks.entry(instrument.to_string()).or_default();
+        let newest = ticks.iter().all(|tick| tick.source_time <= source_time);
+        ticks.push(PriceTick {
+            source: source.to_string(),
+            price,
+            source_time,
+            received_at,
+        });
+        self.record_latency(source, source_time, received_at);
+        if newest {
+            self.update_market_price(instrument, price)?;
+        }
+        Ok(())
+    }
+
+    // Ingest every quote waiting on the stream without blocking, so marks and
+    // unrealized P&L follow the feed. Returns the number of quotes applied.
+    pub fn apply_quotes(&mut self, stream: &QuoteStream) -> Result<usize, PositionError> {
+        let mut applied = 0;
+        for quote in stream.quotes.try_iter() {
+            self.ingest_price(&stream.name, &quote.instrument, quote.price, quote.source_time, Utc::now())?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    pub fn record_latency(&mut self, source: &str, source_time: DateTime<Utc>, received_at: DateTime<Utc>) {
+        let samples = self.source_latencies.entry(source.to_string()).or_default();
+        if samples.len() == LATENCY_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back((received_at - source_time).num_milliseconds());
+    }
+
+    pub fn latency_report(&self) -> Vec<SourceLatency> {
+        self.source_latencies
+            .iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(source, samples)| {
+                let mut sorted: Vec<i64> = samples.iter().copied().collect();
+                sorted.sort();
+                let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+                SourceLatency {
+                    source: source.clone(),
+                    samples: sorted.len(),
+                    min_ms: sorted[0],
+                    median_ms: percentile(0.5),
+                    p95_ms: percentile(0.95),
+                    max_ms: sorted[sorted.len() - 1],
+                    ahead_of_receiver: sorted.iter().filter(|latency| **latency < 0).count(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn print_latency_report(&self) {
+        println!("{:<12} {:>7} {:>9} {:>9} {:>9} {:>9} {:>7}", "Source", "Samples", "Min ms", "Median", "P95", "Max", "Ahead");
+        for row in self.latency_report() {
+            println!("{:<12} {:>7} {:>9} {:>9} {:>9} {:>9} {:>7}", row.source, row.samples, row.min_ms, row.median_ms, row.p95_ms, row.max_ms, row.ahead_of_receiver);
+        }
+    }
+
+    // Latest ingested price at or before `as_of` in the domain; instruments without
+    // ingested prices use their current mark
+    pub fn price_as_of(&self, instrument: &str, as_of: DateTime<Utc>, domain: TimestampDomain) -> Option<Price> {
+        let Some(ticks) = self.price_ticks.get(instrument) else {
+            return self.get_market_price(instrument);
+        };
+        ticks.iter()
+            .filter(|tick| tick.timestamp(domain) <= as_of)
+            .max_by_key(|tick| (tick.source_time, tick.received_at))
+            .map(|tick| tick.price)
+    }
+
+    // Source of the ingested price the instrument is marked at
+    pub fn mark_source(&self, instrument: &str) -> Option<&str> {
+        self.price_ticks.get(instrument)?
+            .iter()
+            .max_by_key(|tick| (tick.source_time, tick.received_at))
+            .map(|tick| tick.source.as_str())
+    }
+
+    // Positions and P&L from the trades and prices known at `as_of` in the domain:
+    // the source domain answers what had happened by then, the receive domain what
+    // we could have known by then
+    pub fn pnl_as_of(&self, as_of: DateTime<Utc>, domain: TimestampDomain) -> Vec<AsOfPnlRow> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.timestamp(domain) <= as_of)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        trades.sort_by_key(|trade| (trade.timestamp(domain), trade.trade_id));
+        let mut positions: BTreeMap<String, TradePosition> = BTreeMap::new();
+        for trade in trades {
+            positions.entry(trade.instrument.clone())
+                .or_insert_with(|| TradePosition::new(trade.instrument.clone()))
+                .update_position(trade);
+        }
+        positions
+            .into_values()
+            .map(|position| {
+                let mark = self.price_as_of(&position.instrument, as_of, domain);
+                AsOfPnlRow {
+                    unrealized_pnl: mark.map_or(Decimal::ZERO, |mark| position.unrealized_pnl(mark)),
+                    instrument: position.instrument,
+                    quantity: position.quantity,
+                    average_price: position.average_price,
+                    mark,
+                    realized_pnl: position.realized_pnl,
+                }
+            })
+            .collect()
+    }
+
+    pub fn print_pnl_as_of(&self, as_of: DateTime<Utc>, domain: TimestampDomain) {
+        println!("P&L as of {} by {} time:", as_of.format("%H:%M:%S%.3f"), match domain {
+            TimestampDomain::Source => "source",
+            TimestampDomain::Receive => "receive",
+        });
+        for row in self.pnl_as_of(as_of, domain) {
+            let mark = row.mark.map_or("-".to_string(), |mark| format!("{:.2}", mark));
+            println!("  {:<6} qty {:>5} avg {:>8.2} mark {:>8} realized {:>8.2} unrealized {:>8.2}",
+                     row.instrument, row.quantity, row.average_price, mark, row.realized_pnl, row.unrealized_pnl);
+        }
+    }
+
+    // Gaps not yet filled by late records, by source then sequence
+    pub fn sequence_gaps(&self) -> Vec<SequenceGap> {
+        let mut gaps: Vec<SequenceGap> = self.sequence_states
+            .iter()
+            .flat_map(|(source, state)| state.open_gaps.iter().map(|(first, last)| SequenceGap {
+                source: source.clone(),
+                first: *first,
+                last: *last,
+            }))
+            .collect();
+        gaps.sort_by(|a, b| a.source.cmp(&b.source).then(a.first.cmp(&b.first)));
+        gaps
+    }
+
+    pub fn print_sequence_alerts(&self) {
+        let mut sources: Vec<(&String, &SequenceState)> = self.sequence_states.iter().collect();
+        sources.sort_by(|a, b| a.0.cmp(b.0));
+        for (source, state) in sources {
+            println!("{}: next expected {:?}, {} out of order, {} duplicates", source, state.next_expected, state.out_of_order, state.duplicates);
+        }
+        for gap in self.sequence_gaps() {
+            if gap.first == gap.last {
+                println!("  ALERT {}: sequence {} missing", gap.source, gap.first);
+            } else {
+                println!("  ALERT {}: sequences {}-{} missing", gap.source, gap.first, gap.last);
+            }
+        }
+    }
+
+    // Feeds are held to a stricter standard than manual bookings: no zero prices
+    pub fn validate_ingested_trade(&self, trade: &Trade) -> Result<(), PositionError> {
+        if trade.price.is_zero() {
+            return Err(PositionError::InvalidPrice { instrument: trade.instrument.clone(), price: trade.price });
+        }
+        Ok(())
+    }
+
+    // Daily equity: starting capital plus total book P&L in the firm currency
+    pub fn equity_curve(&self, starting_equity: impl Into<Money>, start_date: NaiveDate, end_date: NaiveDate) -> Result<Vec<(NaiveDate, Money)>, String> {
+        let starting_equity = starting_equity.into();
+        Ok(self.firm_pnl_history(start_date, end_date)?
+            .into_iter()
+            .map(|(date, pnl)| (date, starting_equity + pnl))
+            .collect())
+    }
+
+    // Statistics of the trades matching the filter, exact or from a sample and sketches
+    // with error bounds. Both modes read each matching trade once; the approximate
+    // one keeps only the sample and sketches in memory.
+    pub fn trade_statistics(&self, filter: &TradeFilter, mode: AnalyticsMode) -> TradeStatistics {
+        let trades = self.trades.values().filter(|trade| trade.matches_filter(filter));
+        match mode {
+            AnalyticsMode::Exact => {
+                let trades: Vec<&Trade> = trades.collect();
+                let instruments: HashSet<&str> = trades.iter().map(|trade| trade.instrument.as_str()).collect();
+                let accounts: HashSet<&str> = trades.iter().map(|trade| trade.account_id.as_str()).collect();
+                TradeStatistics {
+                    mode,
+                    trades: trades.len(),
+                    sampled: trades.len(),
+                    total_quantity: Estimate::exact(trades.iter().map(|trade| trade.quantity as f64).sum()),
+                    total_notional: Estimate::exact(trades.iter().map(|trade| trade.notional()).sum::<Money>().to_f64()),
+                    distinct_instruments: Estimate::exact(instruments.len() as f64),
+                    distinct_accounts: Estimate::exact(accounts.len() as f64),
+                    quantity_quantiles: sample_quantiles(trades.iter().map(|trade| trade.quantity as f64).collect()),
+                    price_quantiles: sample_quantiles(trades.iter().map(|trade| trade.price.to_f64()).collect()),
+                    quantile_rank_error: 0.0,
+                }
+            },
+            AnalyticsMode::Approximate { sample_size, seed } => {
+                let mut reservoir = Reservoir::new(sample_size, seed);
+                let (mut instruments, mut accounts) = (DistinctSketch::new(), DistinctSketch::new());
+                for trade in trades {
+                    instruments.insert(&trade.instrument);
+                    accounts.insert(&trade.account_id);
+                    reservoir.offer(trade);
+                }
+                let population = reservoir.seen as usize;
+                let sample = reservoir.items;
+                let quantities: Vec<f64> = sample.iter().map(|trade| trade.quantity as f64).collect();
+                let notionals: Vec<f64> = sample.iter().map(|trade| trade.notional().to_f64()).collect();
+                let complete = sample.len() >= population;
+                TradeStatistics {
+                    mode,
+                    trades: population,
+                    sampled: sample.len(),
+                    total_quantity: estimate_total(&quantities, population),
+                    total_notional: estimate_total(&notionals, population),
+                    distinct_instruments: instruments.estimate(),
+                    distinct_accounts: accounts.estimate(),
+                    quantity_quantiles: sample_quantiles(quantities),
+                    price_quantiles: sample_quantiles(sample.iter().map(|trade| trade.price.to_f64()).collect()),
+                    // Dvoretzky-Kiefer-Wolfowitz bound on the sample's distribution
+                    quantile_rank_error: if complete { 0.0 } else { ((2.0f64 / 0.05).ln() / (2.0 * sample.len().max(1) as f64)).sqrt() },
+                }
+            },
+        }
+    }
+
+    // Performance of the whole book: the equity curve plus deposits and withdrawals
+    // booked from the start date on, in the firm currency, measured against the
+    // risk-free rate. starting_equity is the capital held before those flows.
+    pub fn performance(&self, starting_equity: impl Into<Money>, start_date: NaiveDate, end_date: NaiveDate) -> Result<PerformanceReport, String> {
+        let mut flows: BTreeMap<NaiveDate, Money> = BTreeMap::new();
+        for (currency, account) in self.cash_ledger(None, end_date) {
+            for movement in account.movements.iter().filter(|movement| movement.date >= start_date) {
+                if matches!(movement.kind, CashMovementKind::Deposit | CashMovementKind::Withdrawal) {
+                    *flows.entry(movement.date).or_insert(Decimal::ZERO) += movement.amount * self.fx_rates.rate_on(&currency, movement.date)?;
+                }
+            }
+        }
+        let mut invested = Decimal::ZERO;
+        let equity: Vec<(NaiveDate, Money)> = self.equity_curve(starting_equity, start_date, end_date)?
+            .into_iter()
+            .map(|(date, value)| {
+                invested += flows.get(&date).copied().unwrap_or(Decimal::ZERO);
+                (date, value + invested)
+            })
+            .collect();
+        performance_report(&equity, &flows, self.risk_free_rate).ok_or_else(|| format!("No equity between {} and {}", start_date, end_date))
+    }
+
+    // Commissions and slippage of active trades per broker. Slippage is measured
+    // against the trade's arrival price, or the close on the trade date if none.
+    // With internal crosses netted, crossed quantity is left out pro rata.
+    pub fn broker_cost_report(&self) -> Vec<BrokerCostReport> {
+        let mut reports: BTreeMap<String, BrokerCostReport> = BTreeMap::new();
+        let mut benchmarked_notional: HashMap<String, Money> = HashMap::new();
+
+        let crossed = self.netted_cross_quantities();
+        for trade in self.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            let Some(broker) = &trade.broker else { continue };
+            let external = trade.quantity - crossed.get(&trade.trade_id).copied().unwrap_or(0);
+            if external == 0 {
+                continue;
+            }
+            let report = reports.entry(broker.clone()).or_insert_with(|| BrokerCostReport {
+                broker: broker.clone(),
+                trade_count: 0,
+                notional: Decimal::ZERO,
+                total_commissions: Decimal::ZERO,
+                commission_bps: 0.0,
+                slippage: Decimal::ZERO,
+                slippage_bps: 0.0,
+                trades_without_benchmark: 0,
+            });
+            report.trade_count += 1;
+            report.notional += trade.notional() * external / trade.quantity;
+            report.total_commissions += trade.commission * external / trade.quantity;
+
+            let benchmark = trade.arrival_price.or_else(|| {
+                self.close_prices.get(&trade.instrument, trade.trade_date)
+            });
+            match benchmark {
+                Some(benchmark) => {
+                    let price_difference = match trade.side {
+                        Side::Buy => trade.price - benchmark,
+                        Side::Sell => benchmark - trade.price,
+                    };
+                    report.slippage += price_difference * external * trade.multiplier;
+                    *benchmarked_notional.entry(broker.clone()).or_default() += benchmark * external * trade.multiplier;
+                },
+                None => report.trades_without_benchmark += 1,
+            }
+        }
+
+        reports
+            .into_values()
+            .map(|mut report| {
+                if report.notional > Decimal::ZERO {
+                    report.commission_bps = (report.total_commissions / report.notional).to_f64() * 10_000.0;
+                }
+                let benchmarked = benchmarked_notional.get(&report.broker).copied().unwrap_or_default();
+                if benchmarked > Decimal::ZERO {
+                    report.slippage_bps = (report.slippage / benchmarked).to_f64() * 10_000.0;
+                }
+                report
+            })
+            .collect()
+    }
+
+    pub fn print_broker_cost_report(&self) {
+        println!("\n=== Trade Cost Analysis by Broker ===");
+        for report in self.broker_cost_report() {
+            println!("{}: {} trades | Notional: ${:.2} | Commissions: ${:.2} ({:.2} bps) | Slippage: ${:.2} ({:.2} bps) | No benchmark: {}",
+                report.broker,
+                report.trade_count,
+                report.notional,
+                report.total_commissions,
+                report.commission_bps,
+                report.slippage,
+                report.slippage_bps,
+                report.trades_without_benchmark
+            );
+        }
+    }
+
+    // Active trades of an instrument in booking order (date, then trade id)
+    pub fn instrument_trades_chronological(&self, instrument: &str) -> Vec<&Trade> {
+        let mut trades: Vec<&Trade> = self.trades
+            .instrument_values(instrument)
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+        trades
+    }
+
+    // Replay each instrument's trade history under every given cost-basis method and
+    // report realized/unrealized P&L side by side, with differences from the first method
+    pub fn compare_cost_basis(&self, methods: &[CostBasisMethod]) -> Vec<CostBasisComparison> {
+        let mut instruments: Vec<&String> = self.positions.keys().collect();
+        instruments.sort();
+
+        instruments
+            .into_iter()
+            .map(|instrument| {
+                let lot_positions: Vec<LotPosition> = methods
+                    .iter()
+                    .map(|method| self.replay_lots(instrument, *method))
+                    .collect();
+
+                let quantity = lot_positions.first().map_or(0, |position| position.quantity());
+                let market_price = self.get_market_price(instrument)
+                    .unwrap_or_else(|| lot_positions.first().map_or(Decimal::ZERO, |position| position.average_price()));
+                let baseline = lot_positions.first().map(|position| (position.realized_pnl, position.unrealized_pnl(market_price)));
+
+                CostBasisComparison {
+                    instrument: instrument.clone(),
+                    quantity,
+                    market_price,
+                    results: lot_positions
+                        .iter()
+                        .map(|position| {
+                            let unrealized_pnl = position.unrealized_pnl(market_price);
+                            let (baseline_realized, baseline_unrealized) = baseline.unwrap();
+                            CostBasisResult {
+                                method: position.method,
+                                realized_pnl: position.realized_pnl,
+                                unrealized_pnl,
+                                realized_difference: position.realized_pnl - baseline_realized,
+                                unrealized_difference: unrealized_pnl - baseline_unrealized,
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_cost_basis_method(&mut self, instrument: &str, method: CostBasisMethod) {
+        self.cost_basis_methods.insert(instrument.to_string(), method);
+    }
+
+    pub fn set_default_cost_basis_method(&mut self, method: CostBasisMethod) {
+        self.default_cost_basis_method = method;
+    }
+
+    pub fn cost_basis_method(&self, instrument: &str) -> CostBasisMethod {
+        self.cost_basis_methods.get(instrument).copied().unwrap_or(self.default_cost_basis_method)
+    }
+
+    // Name the lots (by opening trade id) a closing trade consumes under SpecificLot.
+    // The closing trade may be booked before or after the selection is made.
+    pub fn select_lots(&mut self, closing_trade_id: impl Into<TradeId>, lot_trade_ids: &[TradeId]) -> Result<(), PositionError> {
+        let closing_trade_id = closing_trade_id.into();
+        for trade_id in lot_trade_ids {
+            if !self.trades.contains_key(trade_id) {
+                return Err(PositionError::TradeNotFound(*trade_id));
+            }
+        }
+        self.lot_selections.insert(closing_trade_id, lot_trade_ids.to_vec());
+        Ok(())
+    }
+
+    // Lots are rebuilt from the active trade history, so amends and cancels are
+    // reflected without adjusting lots in place
+    pub fn replay_lots(&self, instrument: &str, method: CostBasisMethod) -> LotPosition {
+        let mut position = LotPosition::new(instrument.to_string(), method);
+        for trade in self.instrument_trades_chronological(instrument) {
+            let selection = self.lot_selections.get(&trade.trade_id).map_or(&[][..], Vec::as_slice);
+            position.apply(trade, selection);
+        }
+        position
+    }
+
+    // Open and closed lots of an instrument under its configured method
+    pub fn tax_lots(&self, instrument: &str) -> Option<LotPosition> {
+        self.positions.contains_key(instrument).then(|| self.replay_lots(instrument, self.cost_basis_method(instrument)))
+    }
+
+    // Every lot closure with a close date in the range, across all instruments,
+    // ordered by close date, instrument and closing trade
+    pub fn realized_lots(&self, start_date: NaiveDate, end_date: NaiveDate) -> Vec<(String, LotClosure)> {
+        let mut closures: Vec<(String, LotClosure)> = self.positions
+            .keys()
+            .filter_map(|instrument| self.tax_lots(instrument))
+            .flat_map(|position| {
+                let instrument = position.instrument.clone();
+                position.closures.into_iter().map(move |closure| (instrument.clone(), closure))
+            })
+            .filter(|(_, closure)| closure.close_date >= start_date && closure.close_date <= end_date)
+            .collect();
+        closures.sort_by(|a, b| a.1.close_date.cmp(&b.1.close_date).then(a.0.cmp(&b.0)).then(a.1.closing_trade_id.cmp(&b.1.closing_trade_id)));
+        closures
+    }
+
+    pub fn print_tax_lots(&self, instrument: &str) {
+        let Some(position) = self.tax_lots(instrument) else {
+            println!("No position in {}", instrument);
+            return;
+        };
+        println!("{} lots ({:?}): {} open, realized ${:.2}", instrument, position.method, position.quantity(), position.realized_pnl);
+        for lot in &position.lots {
+            println!("  Open lot #{} from {}: {} @ ${:.2}", lot.trade_id, lot.open_date, lot.quantity, lot.price);
+        }
+        for closure in &position.closures {
+            println!("  Lot #{} ({} @ ${:.2}) closed by #{} on {}: {} @ ${:.2}, realized ${:.2}, held {} days",
+                closure.lot_trade_id,
+                closure.open_date,
+                closure.open_price,
+                closure.closing_trade_id,
+                closure.close_date,
+                closure.quantity,
+                closure.close_price,
+                closure.realized_pnl,
+                closure.holding_days()
+            );
+        }
+    }
+
+    pub fn print_cost_basis_comparison(&self, methods: &[CostBasisMethod]) {
+        println!("\n=== Cost Basis Comparison ===");
+        for comparison in self.compare_cost_basis(methods) {
+            println!("{} ({} @ ${:.2}):", comparison.instrument, comparison.quantity, comparison.market_price);
+            for result in &comparison.results {
+                println!("  {:?}: Realized: ${:.2} ({:+.2}) | Unrealized: ${:.2} ({:+.2})",
+                    result.method,
+                    result.realized_pnl,
+                    result.realized_difference,
+                    result.unrealized_pnl,
+                    result.unrealized_difference
+                );
+            }
+        }
+    }
+
+    // Seal trades older than `hot_days` before `as_of_date` into a cold segment,
+    // keeping only recent trades in the mutable tier
+    pub fn tier_storage(&mut self, as_of_date: NaiveDate, hot_days: i64) -> usize {
+        self.trades.seal_before(as_of_date - chrono::Duration::days(hot_days))
+    }
+
+    // Give back the memory of cold trades decoded by reads, e.g. after a large
+    // report; they are decoded again when next read
+    pub fn release_decoded_trades(&mut self) {
+        self.trades.release_decoded();
+    }
+
+    // Maintenance job applying a retention policy. Cancelled trades past their
+    // retention are removed from the store (rewriting cold segments) together with
+    // their audit entries; older audit entries lose their actor. The end-of-day
+    // position table is snapshotted first and rebuilt after the purge: if any
+    // instrument's latest position differs from the baseline, everything is rolled back.
+    pub fn run_retention(&mut self, policy: &RetentionPolicy, as_of_date: NaiveDate) -> Result<RetentionReport, PositionError> {
+        let mut report = RetentionReport {
+            as_of_date,
+            cancelled_trades_purged: 0,
+            segments_rewritten: 0,
+            audit_entries_purged: 0,
+            identities_redacted: 0,
+        };
+
+        let purged: HashSet<TradeId> = match policy.cancelled_trade_years {
+            Some(years) => {
+                let cutoff = years_before(as_of_date, years);
+                self.trades
+                    .values()
+                    .filter(|trade| matches!(trade.status, TradeStatus::Cancelled) && trade.trade_date < cutoff)
+                    .map(|trade| trade.trade_id)
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+
+        let baseline = self.clone();
+        if !purged.is_empty() {
+            report.cancelled_trades_purged = purged.len();
+            report.segments_rewritten = self.trades.purge(&purged);
+            let entries = self.audit_log.len();
+            self.audit_log.retain(|entry| !purged.contains(&entry.trade_id));
+            // The journal file keeps the full history; only the in-memory copy is trimmed
+            self.journal.retain(|event| event.trade_id().is_none_or(|trade_id| !purged.contains(&trade_id)));
+            report.audit_entries_purged = entries - self.audit_log.len();
+
+            self.rebuild_daily_positions(&QueryControl::unlimited())?;
+            let changed = baseline.daily_positions.iter().find(|(instrument, days)| {
+                let before = days.values().next_back();
+                let after = self.daily_positions.get(*instrument).and_then(|days| days.values().next_back());
+                match (before, after) {
+                    (Some(before), Some(after)) => before.quantity != after.quantity || before.average_price != after.average_price || before.realized_pnl != after.realized_pnl,
+                    (Some(before), None) => before.quantity != 0 || !before.realized_pnl.is_zero(),
+                    _ => false,
+                }
+            }).map(|(instrument, _)| instrument.clone());
+            if let Some(instrument) = changed {
+                *self = baseline;
+                return Err(PositionError::BaselineMismatch(instrument));
+            }
+        }
+
+        if let Some(years) = policy.trader_identity_years {
+            let cutoff = years_before(as_of_date, years);
+            for entry in self.audit_log.iter_mut().filter(|entry| entry.recorded_at.date_naive() < cutoff && entry.actor != "redacted") {
+                entry.actor = "redacted".to_string();
+                report.identities_redacted += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Replace each instrument's closed round trips dated before `cutoff` with synthetic
+    // opening-balance trades. An instrument is compressed up to the last point before
+    // the cutoff where it, every account and every strategy in it were all flat, and only
+    // if the realized P&L of those levels still adds up when replayed on its own. The
+    // removed trades' audit entries stay, and `compressions` links them to the synthetic ones.
+    pub fn compress_before(&mut self, cutoff: NaiveDate) -> Result<CompressionReport, PositionError> {
+        let mut instruments: Vec<String> = self.positions.keys().cloned().collect();
+        instruments.sort();
+        let mut next_trade_id = self.next_trade_id();
+        let mut compressions = Vec::new();
+        let mut skipped = Vec::new();
+
+        for instrument in instruments {
+            let trades = self.instrument_trades_chronological(&instrument);
+            // The cut must fall strictly before the next trade's execution time
+            let mut instrument_quantity = 0;
+            let mut group_quantities: BTreeMap<(&str, Option<&str>), i32> = BTreeMap::new();
+            let mut flat_through = None;
+            for (index, trade) in trades.iter().enumerate().take_while(|(_, trade)| trade.trade_date < cutoff) {
+                instrument_quantity += trade.signed_quantity();
+                *group_quantities.entry((trade.account_id.as_str(), trade.strategy.as_deref())).or_default() += trade.signed_quantity();
+                let separated = trades.get(index + 1).is_none_or(|next| next.executed_at > trade.executed_at);
+                if instrument_quantity == 0 && separated && group_quantities.values().all(|quantity| *quantity == 0) {
+                    flat_through = Some(index);
+                }
+            }
+            let Some(last) = flat_through else {
+                continue;
+            };
+            let round_trips = &trades[..=last];
+
+            let replay = |trades: &mut dyn Iterator<Item = &&Trade>| {
+                let mut position = TradePosition::new(instrument.clone());
+                for trade in trades {
+                    position.update_position(trade);
+                }
+                position.realized_pnl
+            };
+            let mut groups: BTreeMap<(String, Option<String>), Vec<&Trade>> = BTreeMap::new();
+            for trade in round_trips {
+                groups.entry((trade.account_id.clone(), trade.strategy.clone())).or_default().push(trade);
+            }
+            if round_trips.len() <= groups.len() * 2 {
+                continue;
+            }
+            let group_pnl: BTreeMap<&(String, Option<String>), Money> = groups.iter()
+                .map(|(key, trades)| (key, replay(&mut trades.iter())))
+                .collect();
+            let instrument_pnl = replay(&mut round_trips.iter());
+            let accounts_add_up = groups.keys().map(|(account_id, _)| account_id).collect::<BTreeSet<_>>().into_iter().all(|account_id| {
+                let account_pnl = replay(&mut round_trips.iter().filter(|trade| &trade.account_id == account_id));
+                account_pnl == group_pnl.iter().filter(|((account, _), _)| account == account_id).map(|(_, pnl)| *pnl).sum()
+            });
+            if instrument_pnl != group_pnl.values().copied().sum() || !accounts_add_up {
+                skipped.push((instrument.clone(), "realized P&L by account and strategy does not add up to the instrument's".to_string()));
+                continue;
+            }
+
+            let closing = round_trips[last];
+            for ((account_id, strategy), trades) in &groups {
+                let realized_pnl = group_pnl[&(account_id.clone(), strategy.clone())];
+                let mut synthetic_trades = Vec::new();
+                if !realized_pnl.is_zero() {
+                    // Both prices stay positive whatever the sign of the P&L
+                    let per_unit = realized_pnl / closing.multiplier;
+                    let buy_price = if per_unit.is_negative() { Decimal::from(1) - per_unit } else { Decimal::from(1) };
+                    for (side, price) in [(Side::Buy, buy_price), (Side::Sell, buy_price + per_unit)] {
+                        // Booked straight into the store with the compression
+                        let mut trade = Trade { status: TradeStatus::Active, ..Trade::new(next_trade_id, closing.trade_date, instrument.clone(), 1, price, side).with_account(account_id) };
+                        trade.multiplier = closing.multiplier;
+                        trade.executed_at = closing.executed_at;
+                        trade.strategy = strategy.clone();
+                        synthetic_trades.push(trade);
+                        next_trade_id = self.following_trade_id(next_trade_id);
+                    }
+                }
+                compressions.push(Compression {
+                    instrument: instrument.clone(),
+                    account_id: account_id.clone(),
+                    strategy: strategy.clone(),
+                    compressed_through: closing.trade_date,
+                    trade_ids: trades.iter().map(|trade| trade.trade_id).collect(),
+                    realized_pnl,
+                    synthetic_trades,
+                });
+            }
+        }
+
+        if !compressions.is_empty() {
+            self.record_event(TradeEvent::Compressed(compressions.clone()))?;
+            self.apply_compressions(&compressions)?;
+        }
+        Ok(CompressionReport {
+            cutoff,
+            trades_removed: compressions.iter().map(|compression| compression.trade_ids.len()).sum(),
+            trades_added: compressions.iter().map(|compression| compression.synthetic_trades.len()).sum(),
+            compressions,
+            skipped,
+        })
+    }
+
+    // Swap compressed trades for their synthetic ones and rebuild the instruments
+    // from the first compressed date
+    pub fn apply_compressions(&mut self, compressions: &[Compression]) -> Result<(), PositionError> {
+        let mut removed = HashSet::new();
+        let mut rebuild_from: BTreeMap<String, NaiveDate> = BTreeMap::new();
+        for compression in compressions {
+            for trade_id in &compression.trade_ids {
+                let trade = self.trades.get(trade_id).ok_or(PositionError::TradeNotFound(*trade_id))?;
+                let from = rebuild_from.entry(trade.instrument.clone()).or_insert(trade.trade_date);
+                *from = (*from).min(trade.trade_date);
+                removed.insert(*trade_id);
+            }
+        }
+        self.trades.purge(&removed);
+        for trade in compressions.iter().flat_map(|compression| &compression.synthetic_trades) {
+            if self.trades.contains_key(&trade.trade_id) {
+                return Err(PositionError::DuplicateTradeId(trade.trade_id));
+            }
+            self.trades.insert(trade.trade_id, trade.clone());
+            self.record_audit(AuditAction::Add, trade.trade_id, None, Some(trade.clone()));
+        }
+        for (instrument, from_date) in rebuild_from {
+            self.rebuild_position(&instrument, from_date);
+        }
+        self.compressions.extend(compressions.iter().cloned());
+        Ok(())
+    }
+
+    // The compression a removed trade went into
+    pub fn compression_of(&self, trade_id: impl Into<TradeId>) -> Option<&Compression> {
+        let trade_id = trade_id.into();
+        self.compressions.iter().find(|compression| compression.trade_ids.contains(&trade_id))
+    }
+
+    pub fn set_implied_volatility(&mut self, instrument: &str, volatility: f64) {
+        self.implied_volatilities.insert(instrument.to_string(), volatility);
+    }
+
+    pub fn set_duration(&mut self, instrument: &str, modified_duration: f64) {
+        self.durations.insert(instrument.to_string(), modified_duration);
+    }
+
+    // A beta without a date holds until a dated one replaces it
+    pub fn set_beta(&mut self, instrument: &str, beta: f64) {
+        self.betas.insert(instrument, NaiveDate::MIN, beta);
+    }
+
+    pub fn set_beta_on(&mut self, instrument: &str, date: NaiveDate, beta: f64) {
+        self.betas.insert(instrument, date, beta);
+    }
+
+    pub fn set_borrow_rate_on(&mut self, instrument: &str, date: NaiveDate, rate: f64) {
+        self.borrow_rates.insert(instrument, date, rate);
+    }
+
+    pub fn borrow_rate_on(&self, instrument: &str, date: NaiveDate) -> Option<f64> {
+        self.borrow_rates.value_on(instrument, date)
+    }
+
+    // Borrow rates as CSV rows of date,instrument,rate. A zero rate is a general
+    // collateral name; a negative one is refused.
+    pub fn import_borrow_rates_csv(&mut self, csv: &str) -> Result<usize, PositionError> {
+        let rate = |text: &str| text.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate >= 0.0).ok_or("not a rate of zero or more".to_string());
+        self.borrow_rates.import_csv(csv, "date,instrument,rate", rate)
+            .map_err(PositionError::InvalidRecord)
+    }
+
+    pub fn set_benchmark_price(&mut self, benchmark: &str, date: NaiveDate, price: impl Into<Price>) {
+        self.benchmark_prices.insert(benchmark, date, price.into());
+    }
+
+    // Benchmark levels as CSV rows of date,benchmark,price
+    pub fn import_benchmark_csv(&mut self, csv: &str) -> Result<usize, PositionError> {
+        self.benchmark_prices.import_csv(csv, "date,benchmark,price", Decimal::parse)
+            .map_err(PositionError::InvalidRecord)
+    }
+
+    // Book performance against a registered benchmark over the same days. The
+    // benchmark's return for a day runs from its last level on or before the
+    // previous return date.
+    pub fn benchmark_comparison(&self, benchmark: &str, starting_equity: impl Into<Money>, start_date: NaiveDate, end_date: NaiveDate) -> Result<BenchmarkComparison, String> {
+        let performance = self.performance(starting_equity, start_date, end_date)?;
+        let level = |date: NaiveDate| self.benchmark_prices.value_on(benchmark, date).map(|price| price.to_f64());
+        let mut pairs = Vec::with_capacity(performance.daily_returns.len());
+        let mut previous_date = performance.start_date;
+        for (date, portfolio_return) in &performance.daily_returns {
+            let (Some(previous), Some(current)) = (level(previous_date), level(*date)) else {
+                return Err(format!("No {} level on or before {}", benchmark, previous_date.min(*date)));
+            };
+            pairs.push((*portfolio_return, current / previous - 1.0));
+            previous_date = *date;
+        }
+        Ok(benchmark_comparison(benchmark, &performance, &pairs, self.risk_free_rate))
+    }
+
+    pub fn set_risk_free_rate(&mut self, rate: f64) {
+        self.risk_free_rate = rate;
+    }
+
+    // Close on the date if recorded, otherwise the latest market price; as f64 for
+    // the option model
+    pub fn spot_price_on(&self, instrument: &str, date: NaiveDate) -> Option<f64> {
+        self.close_price_on(instrument, date).or_else(|| self.get_market_price(instrument)).map(Decimal::to_f64)
+    }
+
+    // Delta, gamma, vega and theta by underlying. Options contribute their
+    // Black-Scholes greeks, futures their root and everything else counts as
+    // one share of delta on itself, so the rows show how far from delta-neutral
+    // each underlying is.
+    pub fn greek_report(&self, as_of_date: NaiveDate) -> GreekReport {
+        let mut rows: BTreeMap<String, UnderlyingGreeks> = BTreeMap::new();
+        let mut missing = Vec::new();
+
+        for (instrument, position) in self.build_position_map_as_of_date(as_of_date) {
+            if position.quantity == 0 {
+                continue;
+            }
+            let quantity = position.quantity as f64;
+            let contract = self.contracts.get(&instrument);
+
+            match contract.and_then(|contract| contract.option_terms.as_ref().map(|terms| (contract, terms))) {
+                Some((contract, terms)) => {
+                    let (Some(spot), Some(&volatility)) = (self.spot_price_on(&contract.root, as_of_date), self.implied_volatilities.get(&instrument)) else {
+                        missing.push(instrument);
+                        continue;
+                    };
+                    let years = (contract.expiry_date - as_of_date).num_days() as f64 / 365.0;
+                    let greeks = option_greeks(terms.right, spot, terms.strike, volatility, self.risk_free_rate, years);
+                    let shares = quantity * terms.multiplier;
+
+                    let row = rows.entry(contract.root.clone()).or_insert_with(|| UnderlyingGreeks::new(contract.root.clone(), spot));
+                    row.delta += shares * greeks.delta;
+                    row.dollar_delta += shares * greeks.delta * spot;
+                    row.gamma += shares * greeks.gamma;
+                    row.dollar_gamma += shares * greeks.gamma * spot * spot / 100.0;
+                    row.vega += shares * greeks.vega;
+                    row.theta += shares * greeks.theta;
+                }
+                None => {
+                    let underlying = contract.map(|contract| contract.root.clone()).unwrap_or_else(|| instrument.clone());
+                    let Some(price) = self.spot_price_on(&instrument, as_of_date) else {
+                        missing.push(instrument);
+                        continue;
+                    };
+                    let spot = self.spot_price_on(&underlying, as_of_date).unwrap_or(price);
+                    let row = rows.entry(underlying.clone()).or_insert_with(|| UnderlyingGreeks::new(underlying, spot));
+                    row.delta += quantity;
+                    row.dollar_delta += quantity * price;
+                }
+            }
+        }
+
+        let rows: Vec<UnderlyingGreeks> = rows.into_values().collect();
+        GreekReport {
+            as_of_date,
+            total_dollar_delta: rows.iter().map(|row| row.dollar_delta).sum(),
+            total_dollar_gamma: rows.iter().map(|row| row.dollar_gamma).sum(),
+            total_vega: rows.iter().map(|row| row.vega).sum(),
+            total_theta: rows.iter().map(|row| row.theta).sum(),
+            rows,
+            missing,
+        }
+    }
+
+    // Print P&L together with the greeks by underlying
+    pub fn print_risk_report(&self, as_of_date: NaiveDate) {
+        let (realized, unrealized, market_value) = self.calculate_portfolio_pnl();
+        let report = self.greek_report(as_of_date);
+
+        println!("Risk Report as of {}", report.as_of_date);
+        println!("Market Value: ${:.2}  Realized: ${:.2}  Unrealized: ${:.2}", market_value, realized, unrealized);
+        println!("{:<10} {:>10} {:>12} {:>14} {:>10} {:>12} {:>10} {:>10}", "Underlying", "Spot", "Delta", "Dollar Delta", "Gamma", "Dollar Gamma", "Vega", "Theta");
+        for row in &report.rows {
+            println!("{:<10} {:>10.2} {:>12.2} {:>14.2} {:>10.4} {:>12.2} {:>10.2} {:>10.2}",
+                     row.underlying, row.spot, row.delta, row.dollar_delta, row.gamma, row.dollar_gamma, row.vega, row.theta);
+        }
+        println!("{:<10} {:>10} {:>12} {:>14.2} {:>10} {:>12.2} {:>10.2} {:>10.2}",
+                 "Total", "", "", report.total_dollar_delta, "", report.total_dollar_gamma, report.total_vega, report.total_theta);
+        if !report.missing.is_empty() {
+            println!("Missing price or volatility: {}", report.missing.join(", "));
+        }
+    }
+
+    // Group option positions into verticals, straddles and covered calls per
+    // underlying and expiry, in that order of preference, so P&L and risk can be read
+    // per structure. Pairing is greedy: each structure takes as many units as both
+    // legs allow, and what no structure absorbs is reported as single options.
+    pub fn option_strategy_report(&self, as_of_date: NaiveDate) -> OptionStrategyReport {
         let positions = self.build_position_map_as_of_date(as_of_date);
-        let mut total_market_value = 0.0;
-        let mut total_realized_pnl = 0.0;
-        let mut total_unrealized_pnl = 0.0;
+        // (underlying, expiry) -> legs
+        let mut chains: BTreeMap<(String, NaiveDate), Vec<UnpairedLeg>> = BTreeMap::new();
+        for (instrument, position) in &positions {
+            let Some((contract, terms)) = self.contracts.get(instrument).and_then(|contract| contract.option_terms.as_ref().map(|terms| (contract, terms))) else { continue };
+            if position.quantity != 0 {
+                chains.entry((contract.root.clone(), contract.expiry_date)).or_default().push((instrument.clone(), terms.right, terms.strike, position.quantity));
+            }
+        }
+        let mut shares: HashMap<String, i32> = positions.iter().map(|(instrument, position)| (instrument.clone(), position.quantity)).collect();
+
+        let mut missing = BTreeSet::new();
+        let mut strategies = Vec::new();
+        for ((underlying, expiry_date), mut legs) in chains {
+            legs.sort_by(|a, b| a.2.total_cmp(&b.2));
+            let mut found: Vec<(OptionStrategyKind, Vec<(String, i32)>)> = Vec::new();
+            let same_right = |a: OptionRight, b: OptionRight| matches!((a, b), (OptionRight::Call, OptionRight::Call) | (OptionRight::Put, OptionRight::Put));
+
+            // Same-strike call/put pairs first, then spreads, then calls written against shares
+            for i in 0..legs.len() {
+                for j in 0..legs.len() {
+                    let (a, b) = (legs[i].3, legs[j].3);
+                    if !matches!((legs[i].1, legs[j].1), (OptionRight::Call, OptionRight::Put)) || legs[i].2 != legs[j].2 || a.signum() * b.signum() != 1 {
+                        continue;
+                    }
+                    let units = a.abs().min(b.abs());
+                    found.push((OptionStrategyKind::Straddle, vec![(legs[i].0.clone(), units * a.signum()), (legs[j].0.clone(), units * b.signum())]));
+                    legs[i].3 -= units * a.signum();
+                    legs[j].3 -= units * b.signum();
+                }
+            }
+            for i in 0..legs.len() {
+                for j in i + 1..legs.len() {
+                    let (a, b) = (legs[i].3, legs[j].3);
+                    if !same_right(legs[i].1, legs[j].1) || a.signum() * b.signum() != -1 || legs[i].2 == legs[j].2 {
+                        continue;
+                    }
+                    let units = a.abs().min(b.abs());
+                    found.push((OptionStrategyKind::VerticalSpread, vec![(legs[i].0.clone(), units * a.signum()), (legs[j].0.clone(), units * b.signum())]));
+                    legs[i].3 -= units * a.signum();
+                    legs[j].3 -= units * b.signum();
+                }
+            }
+            for leg in legs.iter_mut().filter(|leg| matches!(leg.1, OptionRight::Call) && leg.3 < 0) {
+                let multiplier = self.contracts[&leg.0].option_terms.as_ref().map_or(1.0, |terms| terms.multiplier) as i32;
+                let held = shares.get(&underlying).copied().unwrap_or(0);
+                let units = (-leg.3).min(held / multiplier.max(1));
+                if units > 0 {
+                    found.push((OptionStrategyKind::CoveredCall, vec![(underlying.clone(), units * multiplier), (leg.0.clone(), -units)]));
+                    shares.insert(underlying.clone(), held - units * multiplier);
+                    leg.3 += units;
+                }
+            }
+            for leg in legs.iter().filter(|leg| leg.3 != 0) {
+                found.push((OptionStrategyKind::SingleOption, vec![(leg.0.clone(), leg.3)]));
+            }
+
+            let spot = self.spot_price_on(&underlying, as_of_date);
+            for (kind, strategy_legs) in found {
+                let mut strategy = OptionStrategy {
+                    kind,
+                    underlying: underlying.clone(),
+                    expiry_date,
+                    legs: strategy_legs.clone(),
+                    unrealized_pnl: Decimal::ZERO,
+                    delta: 0.0,
+                    dollar_delta: 0.0,
+                    gamma: 0.0,
+                    vega: 0.0,
+                    theta: 0.0,
+                };
+                for (instrument, quantity) in &strategy_legs {
+                    let average_price = positions[instrument].average_price;
+                    match self.close_price_on(instrument, as_of_date).or_else(|| self.get_market_price(instrument)) {
+                        Some(mark) => strategy.unrealized_pnl += (mark - average_price) * *quantity * positions[instrument].multiplier,
+                        None => {
+                            missing.insert(instrument.clone());
+                        },
+                    }
+                    let Some(terms) = self.contracts.get(instrument).and_then(|contract| contract.option_terms.as_ref()) else {
+                        // The stock leg: one share of delta each
+                        strategy.delta += *quantity as f64;
+                        strategy.dollar_delta += *quantity as f64 * spot.unwrap_or(0.0);
+                        continue;
+                    };
+                    let (Some(spot), Some(&volatility)) = (spot, self.implied_volatilities.get(instrument)) else {
+                        missing.insert(instrument.clone());
+                        continue;
+                    };
+                    let years = (expiry_date - as_of_date).num_days() as f64 / 365.0;
+                    let greeks = option_greeks(terms.right, spot, terms.strike, volatility, self.risk_free_rate, years);
+                    let leg_shares = *quantity as f64 * terms.multiplier;
+                    strategy.delta += leg_shares * greeks.delta;
+                    strategy.dollar_delta += leg_shares * greeks.delta * spot;
+                    strategy.gamma += leg_shares * greeks.gamma;
+                    strategy.vega += leg_shares * greeks.vega;
+                    strategy.theta += leg_shares * greeks.theta;
+                }
+                strategies.push(strategy);
+            }
+        }
+
+        OptionStrategyReport { as_of_date, strategies, missing: missing.into_iter().collect() }
+    }
+
+    pub fn print_option_strategies(&self, as_of_date: NaiveDate) {
+        let report = self.option_strategy_report(as_of_date);
+        println!("Option strategies as of {}", report.as_of_date);
+        println!("{:<15} {:<6} {:<10} {:>12} {:>10} {:>12} {:>8} {:>9} {:>9}  Legs", "Strategy", "Under", "Expiry", "Unrealized", "Delta", "Dollar Delta", "Gamma", "Vega", "Theta");
+        for strategy in &report.strategies {
+            let legs: Vec<String> = strategy.legs.iter().map(|(instrument, quantity)| format!("{:+} {}", quantity, instrument)).collect();
+            println!("{:<15} {:<6} {:<10} {:>12.2} {:>10.2} {:>12.2} {:>8.4} {:>9.2} {:>9.2}  {}",
+                     format!("{:?}", strategy.kind), strategy.underlying, strategy.expiry_date, strategy.unrealized_pnl,
+                     strategy.delta, strategy.dollar_delta, strategy.gamma, strategy.vega, strategy.theta, legs.join(", "));
+        }
+        if !report.missing.is_empty() {
+            println!("Missing mark or volatility: {}", report.missing.join(", "));
+        }
+    }
+
+    // Audit history as NDJSON, one schema-versioned entry per line
+    // Book one fill from an execution report as a trade linked to its order. The
+    // report's cumulative quantity must equal what the order has booked plus this
+    // fill, so a lost or repeated report is caught instead of booked.
+    pub fn book_execution(&mut self, report: ExecutionReport) -> Result<TradeId, PositionError> {
+        if report.last_qty <= 0 {
+            return Err(PositionError::InvalidQuantity(report.last_qty));
+        }
+        if report.leaves_qty < 0 {
+            return Err(PositionError::InvalidRecord(format!("execution {} has negative leaves_qty {}", report.exec_id, report.leaves_qty)));
+        }
+        let (_, trades) = self.trades.query(&TradeFilter::new().instrument(report.instrument.clone()));
+        let fills: Vec<&Trade> = trades.into_iter()
+            .filter(|trade| trade.order_id == Some(report.order_id) && !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        if let Some(trade) = fills.iter().find(|trade| trade.exec_id.as_deref() == Some(report.exec_id.as_str())) {
+            return Err(PositionError::InvalidRecord(format!("execution {} is already booked as trade {}", report.exec_id, trade.trade_id)));
+        }
+        if fills.iter().any(|trade| matches!((&trade.side, &report.side), (Side::Buy, Side::Sell) | (Side::Sell, Side::Buy))) {
+            return Err(PositionError::InvalidRecord(format!("execution {} is a {:?} but order {} was filled on the other side", report.exec_id, report.side, report.order_id)));
+        }
+        let booked: i32 = fills.iter().map(|trade| trade.quantity).sum();
+        if booked + report.last_qty != report.cum_qty {
+            return Err(PositionError::InvalidRecord(format!("execution {} reports cum_qty {} but order {} had {} filled before it", report.exec_id, report.cum_qty, report.order_id, booked)));
+        }
+
+        let trade_id = self.next_trade_id();
+        let mut trade = Trade::new_with_type(trade_id, report.transact_time.date_naive(), report.instrument.clone(), report.last_qty, report.last_price, report.side.clone(), report.order_type.clone())
+            .with_execution_time(report.transact_time)
+            .with_account(&report.account_id)
+            .with_execution(report.order_id, &report.exec_id);
+        if let Some(counterparty) = &report.counterparty {
+            trade = trade.with_counterparty(counterparty);
+        }
+        self.add_trade(trade)?;
+        self.order_leaves.insert(report.order_id, report.leaves_qty);
+        Ok(trade_id)
+    }
+
+    // Apply one inbound FIX ExecutionReport. Fills (ExecType F, or 1 and 2 from
+    // FIX 4.2 sessions) are booked like book_execution. A cancel (4 or H) or
+    // correction (5 or G) that names an earlier execution in ExecRefID (19) cancels
+    // that execution's trade, or amends it to the report's LastQty and LastPx;
+    // without ExecRefID they are order events and book nothing.
+    pub fn apply_fix_execution(&mut self, raw: &str) -> Result<FixOutcome, PositionError> {
+        let message = FixMessage::parse(raw)?;
+        if message.msg_type() != Some("8") {
+            return Err(PositionError::InvalidRecord(format!("FIX: expected an ExecutionReport (35=8), got 35={}", message.msg_type().unwrap_or_default())));
+        }
+        let exec_type = message.required(150, "ExecType")?.to_string();
+        let reference = message.get(19).map(|exec_ref_id| (exec_ref_id.to_string(), message.get(55).unwrap_or_default().to_string()));
+        match (exec_type.as_str(), reference) {
+            ("F" | "1" | "2", _) => Ok(FixOutcome::Booked(self.book_execution(message.to_execution_report()?)?)),
+            ("4" | "H", Some((exec_ref_id, instrument))) => {
+                let trade_id = self.execution_trade(&instrument, &exec_ref_id)?;
+                self.cancel_trade(trade_id)?;
+                Ok(FixOutcome::Cancelled(trade_id))
+            },
+            ("5" | "G", Some((exec_ref_id, instrument))) => {
+                let trade_id = self.execution_trade(&instrument, &exec_ref_id)?;
+                let report = message.to_execution_report()?;
+                self.amend_trade(trade_id, report.last_qty, report.last_price)?;
+                Ok(FixOutcome::Corrected(trade_id))
+            },
+            _ => Ok(FixOutcome::Ignored { exec_type }),
+        }
+    }
+
+    // Active trade booked for an execution id
+    pub fn execution_trade(&self, instrument: &str, exec_id: &str) -> Result<TradeId, PositionError> {
+        let (_, trades) = self.trades.query(&TradeFilter::new().instrument(instrument.to_string()));
+        trades.into_iter()
+            .find(|trade| trade.exec_id.as_deref() == Some(exec_id) && !matches!(trade.status, TradeStatus::Cancelled))
+            .map(|trade| trade.trade_id)
+            .ok_or_else(|| PositionError::InvalidRecord(format!("FIX: no active trade for execution {} in {}", exec_id, instrument)))
+    }
+
+    // Active fills of one order aggregated into a single execution
+    pub fn order_fills(&self, order_id: OrderId) -> Option<OrderFillView> {
+        self.order_fill_views().into_iter().find(|view| view.order_id == order_id)
+    }
+
+    pub fn order_fill_views(&self) -> Vec<OrderFillView> {
+        let mut by_order: BTreeMap<OrderId, Vec<&Trade>> = BTreeMap::new();
+        for trade in self.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+            if let Some(order_id) = trade.order_id {
+                by_order.entry(order_id).or_default().push(trade);
+            }
+        }
+        by_order.into_iter().map(|(order_id, mut fills)| {
+            fills.sort_by_key(|trade| trade.chronological_key());
+            let cum_qty: i32 = fills.iter().map(|trade| trade.quantity).sum();
+            let notional: Money = fills.iter().map(|trade| trade.price * trade.quantity).sum();
+            OrderFillView {
+                order_id,
+                instrument: fills[0].instrument.clone(),
+                side: fills[0].side.clone(),
+                account_id: fills[0].account_id.clone(),
+                cum_qty,
+                leaves_qty: self.order_leaves.get(&order_id).copied(),
+                average_price: notional / cum_qty,
+                first_fill: fills[0].executed_at,
+                last_fill: fills[fills.len() - 1].executed_at,
+                trade_ids: fills.iter().map(|trade| trade.trade_id).collect(),
+            }
+        }).collect()
+    }
+
+    pub fn print_order_fills(&self) {
+        println!("{:<5} {:<6} {:<5} {:<8} {:>5} {:>6} {:>6} {:>10}  Trades", "Order", "Instr", "Side", "Account", "Fills", "Cum", "Leaves", "Avg Px");
+        for view in self.order_fill_views() {
+            let trade_ids: Vec<String> = view.trade_ids.iter().map(|trade_id| trade_id.to_string()).collect();
+            println!("{:<5} {:<6} {:<5} {:<8} {:>5} {:>6} {:>6} {:>10.4}  {}",
+                     view.order_id, view.instrument, format!("{:?}", view.side), view.account_id, view.trade_ids.len(), view.cum_qty,
+                     view.leaves_qty.map_or("-".to_string(), |leaves| leaves.to_string()), view.average_price, trade_ids.join(", "));
+        }
+    }
+
+    pub fn export_audit_ndjson<W: Write>(&self, out: &mut W) -> std::io::Result<usize> {
+        for entry in &self.audit_log {
+            writeln!(out, "{}", entry.to_json())?;
+        }
+        Ok(self.audit_log.len())
+    }
+
+    // Rebuild trades by replaying an exported audit log. The original actors and
+    // timestamps are kept; the whole replay is rolled back if any line fails.
+    pub fn replay_audit_ndjson(&mut self, ndjson: &str) -> Result<usize, PositionError> {
+        let entries = parse_audit_ndjson(ndjson)?;
+        let actor = self.actor.clone();
+
+        let result = self.transaction(|tx| {
+            for entry in &entries {
+                tx.repo.apply_audit_entry(entry)?;
+            }
+            Ok(entries.len())
+        });
+
+        self.actor = actor;
+        result
+    }
+
+    // Apply one audit entry as its original actor, keeping its timestamp
+    pub fn apply_audit_entry(&mut self, entry: &AuditEntry) -> Result<(), PositionError> {
+        self.actor = entry.actor.clone();
+        let after = || entry.after.clone().ok_or(PositionError::InvalidRecord(format!("entry {} has no trade after the change", entry.sequence)));
+        match entry.action {
+            AuditAction::Add => self.add_trade(after()?)?,
+            AuditAction::Cancel => self.cancel_trade(entry.trade_id)?,
+            AuditAction::Amend => {
+                let after = after()?;
+                let current = self.trades.get(&entry.trade_id).cloned().ok_or(PositionError::TradeNotFound(entry.trade_id))?;
+                if current.quantity != after.quantity || current.price != after.price {
+                    self.amend_trade(entry.trade_id, after.quantity, after.price)?;
+                    self.save_for_rollback(entry.trade_id, &[&current.instrument, &after.instrument]);
+                    self.trades.insert(entry.trade_id, after);
+                } else {
+                    self.update_trade_details(after)?;
+                }
+            },
+        }
+        self.stamp_last_audit(entry.recorded_at);
+        Ok(())
+    }
+
+    pub fn stamp_last_audit(&mut self, recorded_at: DateTime<Utc>) {
+        if let Some(entry) = self.audit_log.last_mut() {
+            entry.recorded_at = recorded_at;
+        }
+    }
+
+    // Trades moving the portfolio towards target weights (fractions of current
+    // market value) within the constraints. Instruments held but missing from the
+    // targets are sold down to zero. Targets that cannot be fully reached are
+    // listed in the plan instead of being silently dropped.
+    pub fn generate_rebalance(&self, targets: &BTreeMap<String, f64>, trade_date: NaiveDate, constraints: &RebalanceConstraints) -> RebalancePlan {
+        let (_, _, portfolio_value) = self.calculate_portfolio_pnl();
+        let mut instruments: Vec<&String> = targets.keys().chain(self.positions.iter().filter(|(_, position)| position.quantity != 0).map(|(instrument, _)| instrument)).collect();
+        instruments.sort();
+        instruments.dedup();
+
+        let mut unmet = Vec::new();
+        // (instrument, price, current, target, planned change)
+        let mut wanted: Vec<(String, Price, i32, i32, i32)> = Vec::new();
+        for instrument in instruments {
+            let current = self.get_position(instrument).map_or(0, |position| position.quantity);
+            let weight = targets.get(instrument).copied().unwrap_or(0.0);
+            let Some(price) = self.get_market_price(instrument).filter(|price| *price > Decimal::ZERO) else {
+                unmet.push(UnmetTarget {
+                    instrument: instrument.clone(),
+                    current_quantity: current,
+                    target_quantity: current,
+                    planned_quantity: current,
+                    reason: "No market price".to_string(),
+                });
+                continue;
+            };
+            let target = (portfolio_value * weight / price).to_f64().trunc() as i32;
+            let mut change = target - current;
+            if change == 0 {
+                continue;
+            }
+
+            let mut reason = None;
+            if constraints.do_not_trade.contains(instrument) {
+                change = 0;
+                reason = Some("On do-not-trade list".to_string());
+            } else if let Some(limit) = constraints.trade_notional_limit(instrument) {
+                let max_quantity = (limit / price).to_f64().trunc() as i32;
+                if change.abs() > max_quantity {
+                    change = max_quantity * change.signum();
+                    reason = Some(format!("Trade notional capped at {:.2}", limit));
+                }
+            }
+            if let Some(reason) = reason {
+                unmet.push(UnmetTarget {
+                    instrument: instrument.clone(),
+                    current_quantity: current,
+                    target_quantity: target,
+                    planned_quantity: current + change,
+                    reason,
+                });
+            }
+            if change != 0 {
+                wanted.push((instrument.clone(), price, current, target, change));
+            }
+        }
+
+        // Scale every trade down by the same factor when the day's turnover budget is short
+        let wanted_turnover: Money = wanted.iter().map(|(_, price, _, _, change)| *price * change.abs()).sum();
+        if let Some(max_turnover) = constraints.max_turnover {
+            let traded_today: Money = self.trades
+                .values()
+                .filter(|trade| trade.trade_date == trade_date && !matches!(trade.status, TradeStatus::Cancelled))
+                .map(|trade| trade.notional().abs())
+                .sum();
+            let budget = (portfolio_value.abs() * max_turnover - traded_today).max(Decimal::ZERO);
+            if wanted_turnover > budget {
+                let scale = (budget / wanted_turnover).to_f64();
+                for (instrument, _, current, target, change) in wanted.iter_mut() {
+                    let scaled = (*change as f64 * scale).trunc() as i32;
+                    let reason = format!("Turnover limit {:.1}% of portfolio value", max_turnover * 100.0);
+                    match unmet.iter_mut().find(|unmet| &unmet.instrument == instrument) {
+                        Some(existing) => {
+                            existing.planned_quantity = *current + scaled;
+                            existing.reason = format!("{}; {}", existing.reason, reason);
+                        },
+                        None => unmet.push(UnmetTarget {
+                            instrument: instrument.clone(),
+                            current_quantity: *current,
+                            target_quantity: *target,
+                            planned_quantity: *current + scaled,
+                            reason,
+                        }),
+                    }
+                    *change = scaled;
+                }
+            }
+        }
+
+        let mut next_trade_id = self.next_trade_id();
+        let mut trades = Vec::new();
+        for (instrument, price, _, _, change) in wanted {
+            if change == 0 {
+                continue;
+            }
+            let side = if change > 0 { Side::Buy } else { Side::Sell };
+            trades.push(Trade::new(next_trade_id, trade_date, instrument, change.abs(), price, side));
+            next_trade_id = self.following_trade_id(next_trade_id);
+        }
+        unmet.sort_by(|a, b| a.instrument.cmp(&b.instrument));
+
+        RebalancePlan {
+            trade_date,
+            portfolio_value,
+            turnover: trades.iter().map(|trade| trade.notional()).sum(),
+            trades,
+            unmet,
+        }
+    }
+
+    // Current book in the optimizer layout, with the constraints translated to
+    // per-instrument weight bounds
+    pub fn optimizer_export(&self, as_of: NaiveDate, constraints: &RebalanceConstraints) -> OptimizerExport {
+        let (_, _, portfolio_value) = self.calculate_portfolio_pnl();
+        let rows = self.positions
+            .values()
+            .filter(|position| position.quantity != 0)
+            .map(|position| {
+                let instrument = position.instrument.clone();
+                let price = self.get_market_price(&instrument).filter(|price| *price > Decimal::ZERO);
+                let weight = price
+                    .and_then(|price| position.market_value(price).checked_div(portfolio_value))
+                    .map_or(0.0, Decimal::to_f64);
+                let band = constraints.trade_notional_limit(&instrument).and_then(|limit| limit.checked_div(portfolio_value.abs()));
+                let (min_weight, max_weight) = match band {
+                    _ if price.is_none() || constraints.do_not_trade.contains(&instrument) => (weight, weight),
+                    Some(band) => {
+                        let band = band.to_f64();
+                        ((weight - band).max(weight.min(0.0)), (weight + band).min(weight.max(1.0)))
+                    },
+                    None => (weight.min(0.0), weight.max(1.0)),
+                };
+                OptimizerRow {
+                    quantity: position.quantity,
+                    price,
+                    weight,
+                    beta: self.betas.value_on(&instrument, as_of),
+                    sector: self.instrument_sector(&instrument).to_string(),
+                    min_weight,
+                    max_weight,
+                    instrument,
+                }
+            })
+            .collect();
+        OptimizerExport { as_of, portfolio_value, max_turnover: constraints.max_turnover, rows }
+    }
+
+    // Turn optimizer output (instrument,weight rows) into rebalance trades. The
+    // output should list every instrument to keep; anything missing is sold down.
+    pub fn import_optimizer_weights(&self, csv: &str, trade_date: NaiveDate, constraints: &RebalanceConstraints) -> Result<RebalancePlan, PositionError> {
+        let mut targets = BTreeMap::new();
+        for (line_number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || (line_number == 0 && line.starts_with("instrument")) {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 2 || fields[0].is_empty() {
+                return Err(PositionError::InvalidRecord(format!("line {}: expected instrument,weight", line_number + 1)));
+            }
+            let weight: f64 = fields[1].parse().ok().filter(|weight: &f64| weight.is_finite())
+                .ok_or(PositionError::InvalidRecord(format!("line {}: invalid weight {}", line_number + 1, fields[1])))?;
+            if targets.insert(fields[0].to_string(), weight).is_some() {
+                return Err(PositionError::InvalidRecord(format!("line {}: {} listed twice", line_number + 1, fields[0])));
+            }
+        }
+        Ok(self.generate_rebalance(&targets, trade_date, constraints))
+    }
+
+    pub fn print_rebalance_plan(&self, plan: &RebalancePlan) {
+        println!("Rebalance for {} (portfolio value ${:.2}, turnover ${:.2})", plan.trade_date, plan.portfolio_value, plan.turnover);
+        for trade in &plan.trades {
+            println!("  {:?} {} {} @ ${:.2}", trade.side, trade.quantity, trade.instrument, trade.price);
+        }
+        for unmet in &plan.unmet {
+            println!("  Unmet {}: current {}, target {}, planned {} ({})",
+                     unmet.instrument, unmet.current_quantity, unmet.target_quantity, unmet.planned_quantity, unmet.reason);
+        }
+    }
+
+    // Day-trading view: the previous day's closing position is re-based at the
+    // prior close and the day's trades are replayed on top of it, giving P&L since
+    // the open. Works on copies, so stored positions and lots are not touched.
+    pub fn intraday_pnl(&self, date: NaiveDate) -> IntradayPnlReport {
+        let previous_day = date.pred_opt().unwrap_or(date);
+        let opening = self.build_position_map_as_of_date(previous_day);
+        let mut todays_trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.trade_date == date && !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        todays_trades.sort_by_key(|trade| trade.trade_id);
+
+        let instruments: BTreeSet<&String> = opening
+            .iter()
+            .filter(|(_, position)| position.quantity != 0)
+            .map(|(instrument, _)| instrument)
+            .chain(todays_trades.iter().map(|trade| &trade.instrument))
+            .collect();
+
+        let mut rows = Vec::new();
+        for instrument in instruments {
+            let opened = opening.get(instrument);
+            let opening_quantity = opened.map_or(0, |position| position.quantity);
+            let opening_price = self.close_price_on(instrument, previous_day)
+                .or(opened.map(|position| position.average_price))
+                .unwrap_or(Decimal::ZERO);
+
+            let mut position = TradePosition::new(instrument.clone());
+            if opening_quantity != 0 {
+                position.quantity = opening_quantity;
+                position.average_price = opening_price;
+                position.total_cost = opening_price * opening_quantity;
+            }
+            for trade in todays_trades.iter().filter(|trade| &trade.instrument == instrument) {
+                position.update_position(trade);
+            }
+
+            // The day's own close once recorded, otherwise the live mark
+            let mark = self.close_prices.get(instrument, date)
+                .or_else(|| self.get_market_price(instrument));
+            rows.push(IntradayPnlRow {
+                instrument: instrument.clone(),
+                opening_quantity,
+                opening_price,
+                closing_quantity: position.quantity,
+                mark,
+                realized_since_open: position.realized_pnl,
+                unrealized_since_open: mark.map_or(Decimal::ZERO, |mark| position.unrealized_pnl(mark)),
+            });
+        }
+
+        IntradayPnlReport {
+            date,
+            total_realized: rows.iter().map(|row| row.realized_since_open).sum(),
+            total_unrealized: rows.iter().map(|row| row.unrealized_since_open).sum(),
+            rows,
+        }
+    }
+
+    pub fn print_intraday_pnl(&self, date: NaiveDate) {
+        let report = self.intraday_pnl(date);
+        println!("P&L since open on {}", report.date);
+        println!("{:<10} {:>8} {:>10} {:>8} {:>10} {:>12} {:>12}", "Instrument", "Open", "Open Px", "Close", "Mark", "Realized", "Unrealized");
+        for row in &report.rows {
+            let mark = row.mark.map_or("n/a".to_string(), |mark| format!("{:.2}", mark));
+            println!("{:<10} {:>8} {:>10.2} {:>8} {:>10} {:>12.2} {:>12.2}",
+                     row.instrument, row.opening_quantity, row.opening_price, row.closing_quantity, mark, row.realized_since_open, row.unrealized_since_open);
+        }
+        println!("Total realized: ${:.2}  unrealized: ${:.2}", report.total_realized, report.total_unrealized);
+    }
+
+    // Print trade analysis
+    pub fn print_trade_analysis(&self, filter: &TradeFilter) {
+        let filtered_trades = self.filter_trades(filter);
+        
+        println!("\n=== Trade Analysis ===");
+        println!("Total trades matching filter: {}", filtered_trades.len());
+        
+        let mut total_volume = Decimal::ZERO;
+        let mut buy_volume = Decimal::ZERO;
+        let mut sell_volume = Decimal::ZERO;
+        let mut buy_count = 0;
+        let mut sell_count = 0;
+        
+        for trade in &filtered_trades {
+            let volume = trade.notional();
+            total_volume += volume;
+            
+            match trade.side {
+                Side::Buy => {
+                    buy_volume += volume;
+                    buy_count += 1;
+                },
+                Side::Sell => {
+                    sell_volume += volume;
+                    sell_count += 1;
+                }
+            }
+        }
         
-        for (instrument, position) in positions {
-            if position.quantity != 0 {
-                let market_price = self.get_market_price(&instrument).unwrap_or(position.average_price);
-                let market_value = position.market_value(market_price);
-                let unrealized_pnl = position.unrealized_pnl(market_price);
-                
-                total_market_value += market_value;
-                total_realized_pnl += position.realized_pnl;
-                total_unrealized_pnl += unrealized_pnl;
-                
-                println!("{}: {} shares @ ${:.2} avg | Market: ${:.2} | Value: ${:.2} | Realized P&L: ${:.2} | Unrealized P&L: ${:.2}", 
-                    instrument, 
-                    position.quantity, 
-                    position.average_price,
-                    market_price,
-                    market_value,
-                    position.realized_pnl,
-                    unrealized_pnl
-                );
+        println!("Buy trades: {} (Volume: ${:.2})", buy_count, buy_volume);
+        println!("Sell trades: {} (Volume: ${:.2})", sell_count, sell_volume);
+        println!("Total volume: ${:.2}", total_volume);
+        
+        if buy_count > 0 {
+            println!("Average buy volume: ${:.2}", buy_volume / buy_count);
+        }
+        if sell_count > 0 {
+            println!("Average sell volume: ${:.2}", sell_volume / sell_count);
+        }
+    }
+}
+
+impl Default for TradeRepository {
+    fn default() -> TradeRepository {
+        TradeRepository::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReplaySpeed {
+    AsFastAsPossible,
+    RealTime,
+    // Faster than real time by the factor, e.g. 10.0
+    Accelerated(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayStats {
+    pub events: usize,
+    // Span of the replayed history and the wall-clock time the replay took
+    pub history: Duration,
+    pub elapsed: Duration,
+}
+
+// Plays a recorded audit log back into a repository, pausing between events for
+// the gaps between their timestamps scaled by the speed. After each event the
+// callback sees the repository as a live consumer (dashboard, alert check) would
+// have seen it at that point in production.
+pub struct Replayer {
+    pub entries: Vec<AuditEntry>,
+    pub speed: ReplaySpeed,
+    // Longest single pause, so overnight gaps don't stall a demo
+    pub max_pause: Option<Duration>,
+}
+
+impl Replayer {
+    pub fn new(entries: Vec<AuditEntry>) -> Self {
+        Replayer {
+            entries,
+            speed: ReplaySpeed::AsFastAsPossible,
+            max_pause: None,
+        }
+    }
+
+    pub fn from_ndjson(ndjson: &str) -> Result<Self, PositionError> {
+        Ok(Replayer::new(parse_audit_ndjson(ndjson)?))
+    }
+
+    pub fn speed(mut self, speed: ReplaySpeed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn max_pause(mut self, pause: Duration) -> Self {
+        self.max_pause = Some(pause);
+        self
+    }
+
+    pub fn pause_between(&self, previous: &AuditEntry, next: &AuditEntry) -> Duration {
+        let gap = (next.recorded_at - previous.recorded_at).to_std().unwrap_or(Duration::ZERO);
+        let pause = match self.speed {
+            ReplaySpeed::AsFastAsPossible => Duration::ZERO,
+            ReplaySpeed::RealTime => gap,
+            ReplaySpeed::Accelerated(factor) if factor > 0.0 => gap.div_f64(factor),
+            ReplaySpeed::Accelerated(_) => Duration::ZERO,
+        };
+        self.max_pause.map_or(pause, |max_pause| pause.min(max_pause))
+    }
+
+    // Stops at the first event the repository rejects, leaving the events before it applied
+    pub fn run<F: FnMut(&TradeRepository, &AuditEntry)>(&self, repo: &mut TradeRepository, mut on_event: F) -> Result<ReplayStats, PositionError> {
+        let started = Instant::now();
+        let actor = repo.actor.clone();
+        let mut result = Ok(());
+        for (index, entry) in self.entries.iter().enumerate() {
+            if index > 0 {
+                let pause = self.pause_between(&self.entries[index - 1], entry);
+                if !pause.is_zero() {
+                    std::thread::sleep(pause);
+                }
+            }
+            result = repo.apply_audit_entry(entry);
+            if result.is_err() {
+                break;
+            }
+            on_event(repo, entry);
+        }
+        repo.actor = actor;
+        result?;
+
+        let history = match (self.entries.first(), self.entries.last()) {
+            (Some(first), Some(last)) => (last.recorded_at - first.recorded_at).to_std().unwrap_or(Duration::ZERO),
+            _ => Duration::ZERO,
+        };
+        Ok(ReplayStats {
+            events: self.entries.len(),
+            history,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+// What rolling back a transaction restores. Trades and the positions of their
+// instruments are saved one at a time as the transaction first changes them, so a
+// transaction costs what it touches rather than a copy of the whole book. The logs
+// only grow inside a transaction, so their lengths are enough; everything else is
+// a copy of the repository with those left out.
+#[derive(Debug, Clone)]
+pub struct Savepoint {
+    pub rest: Box<TradeRepository>,
+    // Each trade as it was and whether it was in the hot tier; None if it was not stored
+    pub trades: HashMap<TradeId, Option<(Trade, bool)>>,
+    pub instruments: HashMap<String, SavedInstrument>,
+    pub audit_len: usize,
+    pub journal_len: usize,
+    pub status_log_len: usize,
+    pub event_log_len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SavedInstrument {
+    pub position: Option<TradePosition>,
+    pub days: Option<BTreeMap<NaiveDate, TradePosition>>,
+    pub mark: Option<ChronologicalKey>,
+    pub checkpoints: Option<FoldCheckpoints>,
+}
+
+// Checked operations available inside TradeRepository::transaction. Unlike the
+// repository methods these fail on unknown or duplicate trades, aborting the transaction.
+pub struct Transaction<'a> {
+    pub repo: &'a mut TradeRepository,
+}
+
+impl Transaction<'_> {
+    pub fn add_trade(&mut self, trade: Trade) -> Result<(), PositionError> {
+        self.repo.add_trade(trade)
+    }
+
+    pub fn amend(&mut self, trade_id: impl Into<TradeId>, new_quantity: i32, new_price: impl Into<Price>) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        self.repo.amend_trade(trade_id, new_quantity, new_price)
+    }
+
+    pub fn cancel(&mut self, trade_id: impl Into<TradeId>) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        self.repo.cancel_trade(trade_id)
+    }
+
+    pub fn update_price(&mut self, instrument: &str, price: impl Into<Price>) -> Result<(), PositionError> {
+        self.repo.update_market_price(instrument, price)
+    }
+
+    // Positions reflect the changes made so far in this transaction
+    pub fn get_position(&self, instrument: &str) -> Option<&TradePosition> {
+        self.repo.get_position(instrument)
+    }
+
+    pub fn active_trade(&self, trade_id: impl Into<TradeId>) -> Result<&Trade, PositionError> {
+        let trade_id = trade_id.into();
+        match self.repo.trades.get(&trade_id) {
+            Some(trade) if !matches!(trade.status, TradeStatus::Cancelled) => Ok(trade),
+            Some(_) => Err(PositionError::TradeCancelled(trade_id)),
+            None => Err(PositionError::TradeNotFound(trade_id)),
+        }
+    }
+}
+
+// Extension point for trade feeds. To write an adapter:
+//   1. choose the feed's raw record type (it only needs Debug, for quarantine),
+//   2. open the connection or file in `connect`,
+//   3. return the next batch from `poll`, and an empty batch once caught up,
+//   4. turn a record into a Trade in `map_to_trade`, returning Err for bad records,
+//   5. for feeds with sequence numbers, return them from `sequence_number` so
+//      gaps and out-of-order arrivals are detected.
+// TradeRepository::ingest_from drives the adapter and quarantines failures. Each
+// adapter gets a test in the tests module that runs a sample feed through
+// verify_adapter.
+pub trait SourceAdapter {
+    type Record: std::fmt::Debug;
+
+    fn name(&self) -> &str;
+
+    fn connect(&mut self) -> Result<(), String>;
+
+    fn poll(&mut self) -> Result<Vec<Self::Record>, String>;
+
+    fn map_to_trade(&self, record: &Self::Record) -> Result<Trade, String>;
+
+    fn sequence_number(&self, _record: &Self::Record) -> Option<u64> {
+        None
+    }
+
+    // When the source stamped the record, if the feed carries it
+    fn source_timestamp(&self, _record: &Self::Record) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+// Example adapter reading `trade_id,date,instrument,side,quantity,price[,account]`
+// lines from CSV text, a batch of lines per poll. Sequenced feeds put the sequence
+// number in a leading `seq` column.
+pub struct CsvSourceAdapter {
+    pub name: String,
+    pub csv: String,
+    pub batch_size: usize,
+    pub lines: Vec<String>,
+    pub position: usize,
+    pub sequenced: bool,
+}
+
+impl CsvSourceAdapter {
+    pub fn new(name: &str, csv: &str, batch_size: usize) -> CsvSourceAdapter {
+        CsvSourceAdapter {
+            name: name.to_string(),
+            csv: csv.to_string(),
+            batch_size: batch_size.max(1),
+            lines: Vec::new(),
+            position: 0,
+            sequenced: false,
+        }
+    }
+
+    pub fn sequenced(mut self) -> Self {
+        self.sequenced = true;
+        self
+    }
+}
+
+impl SourceAdapter for CsvSourceAdapter {
+    type Record = String;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        self.lines = self.csv
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with("trade_id") && !line.starts_with("seq"))
+            .collect();
+        self.position = 0;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<String>, String> {
+        let end = (self.position + self.batch_size).min(self.lines.len());
+        let batch = self.lines[self.position..end].to_vec();
+        self.position = end;
+        Ok(batch)
+    }
+
+    fn map_to_trade(&self, record: &String) -> Result<Trade, String> {
+        let mut fields: Vec<&str> = record.split(',').map(|field| field.trim()).collect();
+        if self.sequenced && !fields.is_empty() {
+            fields.remove(0);
+        }
+        if fields.len() < 6 {
+            return Err(format!("Expected at least 6 fields, got {}", fields.len()));
+        }
+        let trade_id = TradeId::parse(fields[0])?;
+        let trade_date = NaiveDate::parse_from_str(fields[1], "%Y-%m-%d").map_err(|_| format!("Invalid date {}", fields[1]))?;
+        let side = match fields[3].to_uppercase().as_str() {
+            "BUY" => Side::Buy,
+            "SELL" => Side::Sell,
+            other => return Err(format!("Invalid side {}", other)),
+        };
+        let quantity: i32 = fields[4].parse().map_err(|_| format!("Invalid quantity {}", fields[4]))?;
+        let price = Decimal::parse(fields[5]).map_err(|_| format!("Invalid price {}", fields[5]))?;
+
+        let trade = Trade::new(trade_id, trade_date, fields[2].to_string(), quantity, price, side);
+        Ok(match fields.get(6) {
+            Some(account_id) if !account_id.is_empty() => trade.with_account(account_id),
+            _ => trade,
+        })
+    }
+
+    fn sequence_number(&self, record: &String) -> Option<u64> {
+        if !self.sequenced {
+            return None;
+        }
+        record.split(',').next()?.trim().parse().ok()
+    }
+}
+
+// Price of one instrument from a market data feed
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub instrument: String,
+    pub price: Price,
+    pub source_time: DateTime<Utc>,
+}
+
+// Extension point for market data. A feed hands out quotes for the instruments
+// subscribed to, a batch per call to `next_quotes`, and None once it has nothing
+// more to send. QuoteStream drives a feed on its own thread; the repository
+// applies what arrives with apply_quotes.
+pub trait MarketDataFeed: Send {
+    fn name(&self) -> &str;
+
+    fn subscribe(&mut self, instrument: &str);
+
+    fn unsubscribe(&mut self, instrument: &str);
+
+    fn next_quotes(&mut self) -> Option<Vec<Quote>>;
+}
+
+// Random-walk prices for demos and load tests. Every call moves each subscribed
+// instrument by a normal-ish step of `volatility` (a fraction of price) and
+// advances the feed's clock by one tick. The same seed gives the same prices.
+pub struct SimulatedFeed {
+    pub name: String,
+    pub prices: BTreeMap<String, f64>,
+    pub subscribed: BTreeSet<String>,
+    pub volatility: f64,
+    pub clock: DateTime<Utc>,
+    pub tick: chrono::Duration,
+    pub remaining: Option<usize>,
+    pub state: u64,
+}
+
+impl SimulatedFeed {
+    pub fn new(name: &str, seed: u64, volatility: f64, start: DateTime<Utc>, tick: chrono::Duration) -> SimulatedFeed {
+        SimulatedFeed {
+            name: name.to_string(),
+            prices: BTreeMap::new(),
+            subscribed: BTreeSet::new(),
+            volatility,
+            clock: start,
+            tick,
+            remaining: None,
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    // Where an instrument's walk starts; instruments without one start at 100
+    pub fn start_price(mut self, instrument: &str, price: f64) -> Self {
+        self.prices.insert(instrument.to_string(), price);
+        self
+    }
+
+    // Stop after this many batches
+    pub fn limit(mut self, batches: usize) -> Self {
+        self.remaining = Some(batches);
+        self
+    }
+
+    // Sum of uniforms in [-0.5, 0.5): mean 0, standard deviation 1 for twelve draws
+    pub fn standard_step(&mut self) -> f64 {
+        (0..12).map(|_| {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            (self.state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+        }).sum()
+    }
+}
+
+impl MarketDataFeed for SimulatedFeed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn subscribe(&mut self, instrument: &str) {
+        self.subscribed.insert(instrument.to_string());
+    }
+
+    fn unsubscribe(&mut self, instrument: &str) {
+        self.subscribed.remove(instrument);
+    }
+
+    fn next_quotes(&mut self) -> Option<Vec<Quote>> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            if *remaining == 0 {
+                return None;
+            }
+            *remaining -= 1;
+        }
+        self.clock += self.tick;
+        let mut quotes = Vec::new();
+        for instrument in self.subscribed.clone() {
+            let step = self.standard_step() * self.volatility;
+            let price = self.prices.entry(instrument.clone()).or_insert(100.0);
+            *price = (*price * (1.0 + step)).max(0.01);
+            quotes.push(Quote {
+                instrument,
+                price: Decimal::from(*price).round(2, RoundingMode::HalfEven),
+                source_time: self.clock,
+            });
+        }
+        Some(quotes)
+    }
+}
+
+// Replays recorded quotes from CSV lines of `time,instrument,price` (RFC 3339
+// times, in time order). Each batch is every quote sharing the next timestamp;
+// quotes for instruments not subscribed to are skipped.
+pub struct CsvReplayFeed {
+    pub name: String,
+    pub quotes: VecDeque<Quote>,
+    pub subscribed: BTreeSet<String>,
+}
+
+impl CsvReplayFeed {
+    pub fn new(name: &str, csv: &str) -> Result<CsvReplayFeed, String> {
+        let mut quotes = VecDeque::new();
+        for (line_number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || (line_number == 0 && line.starts_with("time")) {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            if fields.len() != 3 {
+                return Err(format!("Line {}: expected time,instrument,price", line_number + 1));
+            }
+            let source_time = DateTime::parse_from_rfc3339(fields[0])
+                .map_err(|e| format!("Line {}: invalid time {}: {}", line_number + 1, fields[0], e))?
+                .with_timezone(&Utc);
+            let price = Decimal::parse(fields[2]).map_err(|e| format!("Line {}: invalid price {}: {}", line_number + 1, fields[2], e))?;
+            quotes.push_back(Quote { instrument: fields[1].to_string(), price, source_time });
+        }
+        Ok(CsvReplayFeed { name: name.to_string(), quotes, subscribed: BTreeSet::new() })
+    }
+}
+
+impl MarketDataFeed for CsvReplayFeed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn subscribe(&mut self, instrument: &str) {
+        self.subscribed.insert(instrument.to_string());
+    }
+
+    fn unsubscribe(&mut self, instrument: &str) {
+        self.subscribed.remove(instrument);
+    }
+
+    fn next_quotes(&mut self) -> Option<Vec<Quote>> {
+        let time = self.quotes.front()?.source_time;
+        let mut batch = Vec::new();
+        while self.quotes.front().is_some_and(|quote| quote.source_time == time) {
+            let quote = self.quotes.pop_front().unwrap();
+            if self.subscribed.contains(&quote.instrument) {
+                batch.push(quote);
+            }
+        }
+        Some(batch)
+    }
+}
+
+// A feed running on its own thread, polled every interval, with its quotes arriving
+// on a channel. The stream ends when the feed runs out or the stream is stopped;
+// subscriptions can change while it runs.
+pub struct QuoteStream {
+    pub name: String,
+    pub feed: Arc<Mutex<dyn MarketDataFeed>>,
+    pub quotes: std::sync::mpsc::Receiver<Quote>,
+    pub token: CancellationToken,
+    pub worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl QuoteStream {
+    pub fn start<F: MarketDataFeed + 'static>(feed: F, interval: Duration) -> QuoteStream {
+        let name = feed.name().to_string();
+        let feed: Arc<Mutex<dyn MarketDataFeed>> = Arc::new(Mutex::new(feed));
+        let (sender, quotes) = std::sync::mpsc::channel();
+        let token = CancellationToken::new();
+        let worker = {
+            let feed = Arc::clone(&feed);
+            let token = token.clone();
+            std::thread::spawn(move || {
+                while !token.is_cancelled() {
+                    let Some(batch) = feed.lock().unwrap().next_quotes() else { break };
+                    // A dropped receiver means nobody is listening any more
+                    if batch.into_iter().any(|quote| sender.send(quote).is_err()) {
+                        break;
+                    }
+                    std::thread::sleep(interval);
+                }
+            })
+        };
+        QuoteStream { name, feed, quotes, token, worker: Some(worker) }
+    }
+
+    pub fn subscribe(&self, instrument: &str) {
+        self.feed.lock().unwrap().subscribe(instrument);
+    }
+
+    pub fn unsubscribe(&self, instrument: &str) {
+        self.feed.lock().unwrap().unsubscribe(instrument);
+    }
+
+    // Wait up to the timeout for the next quote; None on timeout or once the stream has ended
+    pub fn next_quote(&self, timeout: Duration) -> Option<Quote> {
+        self.quotes.recv_timeout(timeout).ok()
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    pub fn shutdown(&mut self) {
+        self.token.cancel();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for QuoteStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Fault injection for soak tests (`--features chaos`). Faults are drawn from a
+// seeded xorshift generator, so a failing run repeats with the same seed.
+#[cfg(feature = "chaos")]
+#[derive(Debug, Clone)]
+pub struct ChaosRng(u64);
+
+#[cfg(feature = "chaos")]
+impl ChaosRng {
+    pub fn new(seed: u64) -> ChaosRng {
+        ChaosRng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    // Uniform in 0..bound
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound.max(1) as u64) as usize
+    }
+}
+
+// Storage that fails a share of writes before they reach the wrapped storage;
+// a failed batch leaves the wrapped storage untouched
+#[cfg(feature = "chaos")]
+#[derive(Debug)]
+pub struct ChaosStorage {
+    pub inner: SharedTradeStorage,
+    pub rng: ChaosRng,
+    pub failure_rate: f64,
+    pub failures: usize,
+}
+
+#[cfg(feature = "chaos")]
+impl ChaosStorage {
+    pub fn new(inner: SharedTradeStorage, seed: u64, failure_rate: f64) -> ChaosStorage {
+        ChaosStorage {
+            inner,
+            rng: ChaosRng::new(seed),
+            failure_rate,
+            failures: 0,
+        }
+    }
+
+    pub fn inject(&mut self) -> Result<(), PositionError> {
+        if self.rng.chance(self.failure_rate) {
+            self.failures += 1;
+            return Err(PositionError::Storage("injected write failure".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "chaos")]
+impl TradeStorage for ChaosStorage {
+    fn insert(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        self.inject()?;
+        self.inner.lock().unwrap().insert(trade)
+    }
+
+    fn amend(&mut self, trade: &Trade) -> Result<(), PositionError> {
+        self.inject()?;
+        self.inner.lock().unwrap().amend(trade)
+    }
+
+    fn cancel(&mut self, trade_id: TradeId) -> Result<(), PositionError> {
+        self.inject()?;
+        self.inner.lock().unwrap().cancel(trade_id)
+    }
+
+    fn remove(&mut self, trade_ids: &[TradeId]) -> Result<(), PositionError> {
+        self.inject()?;
+        self.inner.lock().unwrap().remove(trade_ids)
+    }
+
+    fn query(&self, filter: &TradeFilter) -> Result<Vec<Trade>, PositionError> {
+        self.inner.lock().unwrap().query(filter)
+    }
+
+    fn apply(&mut self, events: &[TradeEvent]) -> Result<(), PositionError> {
+        self.inject()?;
+        self.inner.lock().unwrap().apply(events)
+    }
+}
+
+// Faults a ChaosAdapter has injected so far
+#[cfg(feature = "chaos")]
+#[derive(Debug, Clone, Default)]
+pub struct FeedFaults {
+    pub disconnects: usize,
+    pub refused_connects: usize,
+    pub duplicates: usize,
+    pub reordered: usize,
+}
+
+// Feed wrapper that drops the connection mid-poll (losing the batch), refuses
+// reconnects, resends records and swaps records within a batch. What comes back
+// after a reconnect is up to the wrapped adapter's `connect`.
+#[cfg(feature = "chaos")]
+pub struct ChaosAdapter<A: SourceAdapter> {
+    pub inner: A,
+    pub rng: ChaosRng,
+    pub disconnect_rate: f64,
+    pub refuse_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub faults: FeedFaults,
+}
+
+#[cfg(feature = "chaos")]
+impl<A: SourceAdapter> ChaosAdapter<A> {
+    pub fn new(inner: A, seed: u64) -> ChaosAdapter<A> {
+        ChaosAdapter {
+            inner,
+            rng: ChaosRng::new(seed),
+            disconnect_rate: 0.0,
+            refuse_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            faults: FeedFaults::default(),
+        }
+    }
+
+    // Chance per poll of dropping the connection, and per connect of refusing it
+    pub fn disconnects(mut self, disconnect_rate: f64, refuse_rate: f64) -> Self {
+        self.disconnect_rate = disconnect_rate;
+        self.refuse_rate = refuse_rate;
+        self
+    }
+
+    // Chance per record of sending it twice, and of swapping it with another
+    pub fn garbles(mut self, duplicate_rate: f64, reorder_rate: f64) -> Self {
+        self.duplicate_rate = duplicate_rate;
+        self.reorder_rate = reorder_rate;
+        self
+    }
+}
+
+#[cfg(feature = "chaos")]
+impl<A: SourceAdapter> SourceAdapter for ChaosAdapter<A>
+where
+    A::Record: Clone,
+{
+    type Record = A::Record;
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        if self.rng.chance(self.refuse_rate) {
+            self.faults.refused_connects += 1;
+            return Err("injected: connection refused".to_string());
+        }
+        self.inner.connect()
+    }
+
+    fn poll(&mut self) -> Result<Vec<A::Record>, String> {
+        let mut batch = self.inner.poll()?;
+        if !batch.is_empty() && self.rng.chance(self.disconnect_rate) {
+            self.faults.disconnects += 1;
+            return Err("injected: connection reset".to_string());
+        }
+        for index in (0..batch.len()).rev() {
+            if self.rng.chance(self.duplicate_rate) {
+                let position = index + self.rng.below(batch.len() - index) + 1;
+                batch.insert(position.min(batch.len()), batch[index].clone());
+                self.faults.duplicates += 1;
+            }
+        }
+        for index in 0..batch.len() {
+            if self.rng.chance(self.reorder_rate) {
+                let other = self.rng.below(batch.len());
+                batch.swap(index, other);
+                self.faults.reordered += 1;
+            }
+        }
+        Ok(batch)
+    }
+
+    fn map_to_trade(&self, record: &A::Record) -> Result<Trade, String> {
+        self.inner.map_to_trade(record)
+    }
+
+    fn sequence_number(&self, record: &A::Record) -> Option<u64> {
+        self.inner.sequence_number(record)
+    }
+
+    fn source_timestamp(&self, record: &A::Record) -> Option<DateTime<Utc>> {
+        self.inner.source_timestamp(record)
+    }
+}
+
+// Open position as seen by an external strategy or execution framework
+#[derive(Debug, Clone)]
+pub struct ProviderPosition {
+    pub instrument: String,
+    pub quantity: i32,
+    pub average_price: Price,
+    pub market_price: Option<Price>,
+}
+
+// Executed trade as seen by an external framework; sells carry a negative quantity
+#[derive(Debug, Clone)]
+pub struct Fill {
+    pub(crate) trade_id: TradeId,
+    pub trade_date: NaiveDate,
+    pub instrument: String,
+    pub quantity: i32,
+    pub price: Price,
+}
+
+// Portfolio state for strategy/execution frameworks, so they can work against any
+// position store rather than TradeRepository itself
+pub trait PositionProvider {
+    // All non-flat positions
+    fn positions(&self) -> Vec<ProviderPosition>;
+
+    // Active fills on or after the given date, oldest first
+    fn fills_since(&self, since: NaiveDate) -> Vec<Fill>;
+
+    // Account equity marked at current market prices
+    fn account_equity(&self) -> Money;
+}
+
+impl PositionProvider for TradeRepository {
+    fn positions(&self) -> Vec<ProviderPosition> {
+        let mut positions: Vec<ProviderPosition> = self.positions
+            .values()
+            .filter(|position| position.quantity != 0)
+            .map(|position| ProviderPosition {
+                instrument: position.instrument.clone(),
+                quantity: position.quantity,
+                average_price: position.average_price,
+                market_price: self.get_market_price(&position.instrument),
+            })
+            .collect();
+        positions.sort_by(|a, b| a.instrument.cmp(&b.instrument));
+        positions
+    }
+
+    fn fills_since(&self, since: NaiveDate) -> Vec<Fill> {
+        let mut trades: Vec<&Trade> = self.trades
+            .values()
+            .filter(|trade| trade.trade_date >= since && !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        trades.sort_by_key(|trade| trade.chronological_key());
+
+        trades
+            .into_iter()
+            .map(|trade| Fill {
+                trade_id: trade.trade_id,
+                trade_date: trade.trade_date,
+                instrument: trade.instrument.clone(),
+                quantity: trade.signed_quantity(),
+                price: trade.price,
+            })
+            .collect()
+    }
+
+    // Cash plus market value today. Without the FX rates to consolidate, equity is
+    // measured against zero starting capital: realized plus unrealized P&L.
+    fn account_equity(&self) -> Money {
+        match self.portfolio_equity(None, Utc::now().date_naive()) {
+            Ok(equity) => equity.total,
+            Err(_) => {
+                let (realized, unrealized, _) = self.calculate_portfolio_pnl();
+                realized + unrealized
+            },
+        }
+    }
+}
+
+// Example of framework code that only knows about the trait
+pub fn print_portfolio_state(provider: &dyn PositionProvider, since: NaiveDate) {
+    println!("Equity: ${:.2}", provider.account_equity());
+    for position in provider.positions() {
+        println!("  {}: {} @ ${:.2} (mark: {:?})", position.instrument, position.quantity, position.average_price, position.market_price);
+    }
+    for fill in provider.fills_since(since) {
+        println!("  Fill #{} {} {} {} @ ${:.2}", fill.trade_id, fill.trade_date, fill.instrument, fill.quantity, fill.price);
+    }
+}
+
+pub type OrderId = u64;
+
+// How long an order works before the unfilled rest is cancelled
+#[derive(Debug, Clone, Copy)]
+pub enum TimeInForce {
+    Day,
+    GoodTillCancel,
+    // Fill what is available now, cancel the rest
+    ImmediateOrCancel,
+    // Fill everything now or nothing
+    FillOrKill,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+}
+
+// Instruction to buy or sell, executed as one or more trades. The order type
+// reuses TradeType, and every execution is booked with it.
+#[derive(Debug, Clone)]
+pub struct Order {
+    // Assigned by the order manager on submission
+    pub order_id: OrderId,
+    pub instrument: String,
+    pub side: Side,
+    pub quantity: i32,
+    pub order_type: TradeType,
+    pub limit_price: Option<Price>,
+    pub stop_price: Option<Price>,
+    pub time_in_force: TimeInForce,
+    pub account_id: String,
+    pub status: OrderStatus,
+    // A stop order works as a market order once the market reaches its stop price
+    pub triggered: bool,
+    pub filled_quantity: i32,
+    pub filled_notional: Money,
+    pub executions: Vec<TradeId>,
+}
+
+impl Order {
+    pub fn new(instrument: &str, side: Side, quantity: i32, order_type: TradeType) -> Order {
+        Order {
+            order_id: 0,
+            instrument: instrument.to_string(),
+            side,
+            quantity,
+            order_type,
+            limit_price: None,
+            stop_price: None,
+            time_in_force: TimeInForce::Day,
+            account_id: DEFAULT_ACCOUNT.to_string(),
+            status: OrderStatus::New,
+            triggered: false,
+            filled_quantity: 0,
+            filled_notional: Decimal::ZERO,
+            executions: Vec::new(),
+        }
+    }
+
+    pub fn market(instrument: &str, side: Side, quantity: i32) -> Order {
+        Order::new(instrument, side, quantity, TradeType::Market)
+    }
+
+    pub fn limit(instrument: &str, side: Side, quantity: i32, limit_price: impl Into<Price>) -> Order {
+        Order { limit_price: Some(limit_price.into()), ..Order::new(instrument, side, quantity, TradeType::Limit) }
+    }
+
+    pub fn stop(instrument: &str, side: Side, quantity: i32, stop_price: impl Into<Price>) -> Order {
+        Order { stop_price: Some(stop_price.into()), ..Order::new(instrument, side, quantity, TradeType::Stop) }
+    }
+
+    pub fn time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn with_account(mut self, account_id: &str) -> Self {
+        self.account_id = account_id.to_string();
+        self
+    }
+
+    pub fn remaining(&self) -> i32 {
+        self.quantity - self.filled_quantity
+    }
+
+    pub fn is_working(&self) -> bool {
+        matches!(self.status, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+
+    pub fn average_fill_price(&self) -> Option<Price> {
+        (self.filled_quantity > 0).then(|| self.filled_notional / self.filled_quantity)
+    }
+
+    // Whether the order may trade at `price` right now
+    pub fn accepts(&self, price: Price) -> bool {
+        match (&self.order_type, self.limit_price) {
+            (TradeType::Limit, Some(limit)) => match self.side {
+                Side::Buy => price <= limit,
+                Side::Sell => price >= limit,
+            },
+            _ => true,
+        }
+    }
+}
+
+// Report of one fill of an order, as a broker or venue sends it. cum_qty and
+// leaves_qty are the order's filled and unfilled quantity after this fill.
+#[derive(Debug, Clone)]
+pub struct ExecutionReport {
+    pub order_id: OrderId,
+    pub exec_id: String,
+    pub instrument: String,
+    pub side: Side,
+    pub order_type: TradeType,
+    pub account_id: String,
+    pub counterparty: Option<String>,
+    pub last_qty: i32,
+    pub last_price: Price,
+    pub cum_qty: i32,
+    pub leaves_qty: i32,
+    pub transact_time: DateTime<Utc>,
+}
+
+impl ExecutionReport {
+    // Report for a fill about to be applied to the order
+    pub fn for_fill(order: &Order, last_qty: i32, last_price: Price, transact_time: DateTime<Utc>) -> ExecutionReport {
+        ExecutionReport {
+            order_id: order.order_id,
+            exec_id: format!("{}-{}", order.order_id, order.executions.len() + 1),
+            instrument: order.instrument.clone(),
+            side: order.side.clone(),
+            order_type: order.order_type.clone(),
+            account_id: order.account_id.clone(),
+            counterparty: None,
+            last_qty,
+            last_price,
+            cum_qty: order.filled_quantity + last_qty,
+            leaves_qty: order.remaining() - last_qty,
+            transact_time,
+        }
+    }
+}
+
+// FIX field separator. Messages copied from logs often use '|' instead, which
+// FixMessage::parse accepts as well.
+pub const FIX_SOH: char = '\u{1}';
+
+// One FIX tag=value message. Fields are kept in wire order; BodyLength (9) and
+// CheckSum (10) are worked out on encoding and checked on parsing.
+#[derive(Debug, Clone)]
+pub struct FixMessage {
+    pub fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &str) -> Self {
+        FixMessage { fields: vec![(8, "FIX.4.4".to_string()), (35, msg_type.to_string())] }
+    }
+
+    pub fn field(mut self, tag: u32, value: impl std::fmt::Display) -> Self {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    // First value of the tag
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| *field == tag).map(|(_, value)| value.as_str())
+    }
+
+    pub fn msg_type(&self) -> Option<&str> {
+        self.get(35)
+    }
+
+    pub fn encode(&self) -> String {
+        let begin_string = self.get(8).unwrap_or("FIX.4.4");
+        let body: String = self.fields
+            .iter()
+            .filter(|(tag, _)| !(8..=10).contains(tag))
+            .map(|(tag, value)| format!("{}={}{}", tag, value, FIX_SOH))
+            .collect();
+        let head = format!("8={}{}9={}{}{}", begin_string, FIX_SOH, body.len(), FIX_SOH, body);
+        let checksum = head.bytes().map(u32::from).sum::<u32>() % 256;
+        format!("{}10={:03}{}", head, checksum, FIX_SOH)
+    }
+
+    pub fn parse(raw: &str) -> Result<FixMessage, PositionError> {
+        let invalid = |reason: String| PositionError::InvalidRecord(format!("FIX: {}", reason));
+        let wire = raw.trim().replace('|', &FIX_SOH.to_string());
+        let mut fields = Vec::new();
+        for field in wire.split(FIX_SOH).filter(|field| !field.is_empty()) {
+            let (tag, value) = field.split_once('=').ok_or_else(|| invalid(format!("field without a tag: {}", field)))?;
+            let tag: u32 = tag.parse().map_err(|_| invalid(format!("invalid tag {}", tag)))?;
+            fields.push((tag, value.to_string()));
+        }
+        let message = FixMessage { fields };
+        if message.fields.first().map(|(tag, _)| *tag) != Some(8) {
+            return Err(invalid("message does not start with BeginString (8)".to_string()));
+        }
+        message.msg_type().ok_or_else(|| invalid("no MsgType (35)".to_string()))?;
+
+        if let Some(declared) = message.get(9) {
+            let start = wire.find(&format!("{}9=", FIX_SOH)).and_then(|at| wire[at + 1..].find(FIX_SOH).map(|end| at + end + 2)).unwrap_or(0);
+            let end = wire.rfind(&format!("{}10=", FIX_SOH)).map_or(wire.len(), |at| at + 1);
+            if declared.parse::<usize>().ok() != Some(end.saturating_sub(start)) {
+                return Err(invalid(format!("BodyLength {} does not match a body of {} bytes", declared, end.saturating_sub(start))));
+            }
+        }
+        if let (Some(declared), Some(at)) = (message.get(10), wire.rfind(&format!("{}10=", FIX_SOH))) {
+            let checksum = wire[..=at].bytes().map(u32::from).sum::<u32>() % 256;
+            if declared.parse::<u32>().ok() != Some(checksum) {
+                return Err(invalid(format!("CheckSum {} does not match {:03}", declared, checksum)));
+            }
+        }
+        Ok(message)
+    }
+
+    pub fn required(&self, tag: u32, name: &str) -> Result<&str, PositionError> {
+        self.get(tag).ok_or_else(|| PositionError::InvalidRecord(format!("FIX: ExecutionReport without {} ({})", name, tag)))
+    }
+
+    // The fill an ExecutionReport (35=8) carries. Quantities are whole units and
+    // TransactTime is UTC, as YYYYMMDD-HH:MM:SS with optional milliseconds.
+    pub fn to_execution_report(&self) -> Result<ExecutionReport, PositionError> {
+        let invalid = |tag: u32, value: &str| PositionError::InvalidRecord(format!("FIX: invalid value {} for tag {}", value, tag));
+        let number = |tag: u32, name: &str| -> Result<i32, PositionError> {
+            let value = self.required(tag, name)?;
+            value.parse::<f64>()
+                .ok()
+                .filter(|quantity| quantity.fract() == 0.0 && (i32::MIN as f64..=i32::MAX as f64).contains(quantity))
+                .map(|quantity| quantity as i32)
+                .ok_or_else(|| invalid(tag, value))
+        };
+        let order_id = self.required(37, "OrderID")?;
+        let side = match self.required(54, "Side")? {
+            "1" => Side::Buy,
+            "2" | "5" | "6" => Side::Sell,
+            other => return Err(invalid(54, other)),
+        };
+        let order_type = match self.get(40).unwrap_or("1") {
+            "1" => TradeType::Market,
+            "2" => TradeType::Limit,
+            "3" => TradeType::Stop,
+            other => return Err(invalid(40, other)),
+        };
+        let last_price = self.required(31, "LastPx")?;
+        let transact_time = self.required(60, "TransactTime")?;
+        Ok(ExecutionReport {
+            order_id: order_id.parse().map_err(|_| invalid(37, order_id))?,
+            exec_id: self.required(17, "ExecID")?.to_string(),
+            instrument: self.required(55, "Symbol")?.to_string(),
+            side,
+            order_type,
+            account_id: self.get(1).unwrap_or(DEFAULT_ACCOUNT).to_string(),
+            counterparty: self.get(448).map(str::to_string),
+            last_qty: number(32, "LastQty")?,
+            last_price: Decimal::parse(last_price).map_err(|_| invalid(31, last_price))?,
+            cum_qty: number(14, "CumQty")?,
+            leaves_qty: number(151, "LeavesQty")?,
+            transact_time: chrono::NaiveDateTime::parse_from_str(transact_time, "%Y%m%d-%H:%M:%S%.f")
+                .map_err(|_| invalid(60, transact_time))?
+                .and_utc(),
+        })
+    }
+}
+
+// What an inbound ExecutionReport did to the book
+#[derive(Debug, Clone)]
+pub enum FixOutcome {
+    Booked(TradeId),
+    Cancelled(TradeId),
+    Corrected(TradeId),
+    // Order acknowledgements, rejects and status reports book nothing
+    Ignored { exec_type: String },
+}
+
+// Drop copy of the trade flow for middle-office systems. Each journaled event goes
+// out as a FIX 4.4 ExecutionReport under the session's comp ids and the next
+// MsgSeqNum: additions as fills (ExecType F), amendments as corrections (G) and
+// cancellations as busts (H), the last two referencing the report they replace.
+// Compressions are internal housekeeping and are not sent.
+#[derive(Debug, Clone)]
+pub struct FixDropCopy {
+    pub sender_comp_id: String,
+    pub target_comp_id: String,
+    pub next_seq_num: u64,
+    // trade -> ExecID of the last report sent for it
+    pub sent: HashMap<TradeId, String>,
+}
+
+impl FixDropCopy {
+    pub fn new(sender_comp_id: &str, target_comp_id: &str) -> Self {
+        FixDropCopy {
+            sender_comp_id: sender_comp_id.to_string(),
+            target_comp_id: target_comp_id.to_string(),
+            next_seq_num: 1,
+            sent: HashMap::new(),
+        }
+    }
+
+    // Carry on a session's numbering, e.g. after a restart
+    pub fn starting_at(mut self, seq_num: u64) -> Self {
+        self.next_seq_num = seq_num;
+        self
+    }
+
+    pub fn encode(&mut self, repo: &TradeRepository, event: &TradeEvent) -> Option<String> {
+        let (trade, exec_type) = match event {
+            TradeEvent::Added(trade) => (trade, "F"),
+            TradeEvent::Amended(trade) => (trade, "G"),
+            TradeEvent::Cancelled { trade_id } => (repo.trades.get(trade_id)?, "H"),
+            TradeEvent::Compressed(_) => return None,
+        };
+        let seq_num = self.next_seq_num;
+        self.next_seq_num += 1;
+        let exec_id = format!("{}-{}", self.sender_comp_id, seq_num);
+        let side = match trade.side {
+            Side::Buy => 1,
+            Side::Sell => 2,
+        };
+        let order_type = match trade.trade_type {
+            TradeType::Market => 1,
+            TradeType::Limit => 2,
+            TradeType::Stop => 3,
+        };
+        let mut message = FixMessage::new("8")
+            .field(49, &self.sender_comp_id)
+            .field(56, &self.target_comp_id)
+            .field(34, seq_num)
+            .field(52, Utc::now().format("%Y%m%d-%H:%M:%S%.3f"))
+            .field(37, trade.order_id.map_or(trade.trade_id.to_string(), |order_id| order_id.to_string()))
+            .field(17, &exec_id);
+        if exec_type != "F" {
+            let exec_ref_id = self.sent.get(&trade.trade_id).cloned().or(trade.exec_id.clone()).unwrap_or(trade.trade_id.to_string());
+            message = message.field(19, exec_ref_id);
+        }
+        message = message
+            .field(150, exec_type)
+            .field(39, 2)
+            .field(1, &trade.account_id)
+            .field(55, &trade.instrument)
+            .field(54, side)
+            .field(40, order_type)
+            .field(32, trade.quantity)
+            .field(31, trade.price)
+            .field(14, trade.quantity)
+            .field(151, 0)
+            .field(6, trade.price)
+            .field(75, trade.trade_date.format("%Y%m%d"))
+            .field(60, trade.executed_at.format("%Y%m%d-%H:%M:%S%.3f"));
+        if !trade.commission.is_zero() {
+            message = message.field(12, trade.commission);
+        }
+        if let Some(counterparty) = &trade.counterparty {
+            message = message.field(448, counterparty);
+        }
+        self.sent.insert(trade.trade_id, exec_id);
+        Some(message.encode())
+    }
+
+    pub fn encode_all(&mut self, repo: &TradeRepository, events: &[TradeEvent]) -> Vec<String> {
+        events.iter().filter_map(|event| self.encode(repo, event)).collect()
+    }
+}
+
+// An order's fills seen as one execution
+#[derive(Debug, Clone)]
+pub struct OrderFillView {
+    pub order_id: OrderId,
+    pub instrument: String,
+    pub side: Side,
+    pub account_id: String,
+    pub cum_qty: i32,
+    // Per the order's latest execution report; None when its fills were booked without one
+    pub leaves_qty: Option<i32>,
+    pub average_price: Price,
+    pub first_fill: DateTime<Utc>,
+    pub last_fill: DateTime<Utc>,
+    pub trade_ids: Vec<TradeId>,
+}
+
+// Working orders and their executions. Orders trade against the repository's
+// market: its book snapshot for the instrument when there is one, each level up to
+// its displayed size (the snapshot itself is not depleted), otherwise the market
+// price for any size. Every execution is booked in the repository as a trade.
+#[derive(Debug, Clone)]
+pub struct OrderManager {
+    pub orders: BTreeMap<OrderId, Order>,
+    pub next_order_id: OrderId,
+    // Date executions are booked on; end_of_day moves it on
+    pub trading_date: NaiveDate,
+}
+
+impl OrderManager {
+    pub fn new(trading_date: NaiveDate) -> OrderManager {
+        OrderManager {
+            orders: BTreeMap::new(),
+            next_order_id: 1,
+            trading_date,
+        }
+    }
+
+    pub fn get_order(&self, order_id: OrderId) -> Option<&Order> {
+        self.orders.get(&order_id)
+    }
+
+    pub fn working_orders(&self, instrument: &str) -> Vec<&Order> {
+        self.orders.values().filter(|order| order.instrument == instrument && order.is_working()).collect()
+    }
+
+    // Accept an order and execute what the market allows straight away
+    pub fn submit(&mut self, repo: &mut TradeRepository, mut order: Order) -> Result<OrderId, PositionError> {
+        if order.quantity <= 0 {
+            return Err(PositionError::InvalidQuantity(order.quantity));
+        }
+        let missing = match order.order_type {
+            TradeType::Limit if order.limit_price.is_none() => Some("a limit order needs a limit price"),
+            TradeType::Stop if order.stop_price.is_none() => Some("a stop order needs a stop price"),
+            _ => None,
+        };
+        if let Some(reason) = missing {
+            return Err(PositionError::OrderRejected(reason.to_string()));
+        }
+        if matches!(order.order_type, TradeType::Market) && repo.get_market_price(&order.instrument).is_none() && !repo.book_snapshots.contains_key(&order.instrument) {
+            return Err(PositionError::OrderRejected(format!("no market for {}", order.instrument)));
+        }
+        // Flagged breaches are recorded when the executions are booked. An order
+        // with no price to go on is checked against everything but notional.
+        let price = order.limit_price
+            .or(order.stop_price)
+            .or_else(|| repo.get_market_price(&order.instrument))
+            .or_else(|| repo.book_snapshots.get(&order.instrument).and_then(|book| book.mid()))
+            .unwrap_or(Decimal::ZERO);
+        let breaches = repo.check_risk_limits(&order.instrument, &order.side, order.quantity, price);
+        if !breaches.is_empty() && repo.limits_block() {
+            return Err(PositionError::LimitBreached { instrument: order.instrument, breaches });
+        }
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        order.order_id = order_id;
+        order.status = OrderStatus::New;
+        self.orders.insert(order_id, order);
+        self.work(repo, order_id)?;
+        Ok(order_id)
+    }
+
+    // Re-work the instrument's resting orders after its market moved
+    pub fn on_market_update(&mut self, repo: &mut TradeRepository, instrument: &str) -> Result<Vec<TradeId>, PositionError> {
+        let working: Vec<OrderId> = self.working_orders(instrument).iter().map(|order| order.order_id).collect();
+        let mut executions = Vec::new();
+        for order_id in working {
+            executions.extend(self.work(repo, order_id)?);
+        }
+        Ok(executions)
+    }
+
+    // Book an execution reported from outside, e.g. by a broker
+    pub fn fill(&mut self, repo: &mut TradeRepository, order_id: OrderId, quantity: i32, price: impl Into<Price>) -> Result<TradeId, PositionError> {
+        let order = self.orders.get(&order_id).ok_or(PositionError::OrderNotFound(order_id))?;
+        if !order.is_working() {
+            return Err(PositionError::OrderRejected(format!("order {} is {:?}", order_id, order.status)));
+        }
+        if quantity <= 0 || quantity > order.remaining() {
+            return Err(PositionError::InvalidQuantity(quantity));
+        }
+        self.execute(repo, order_id, quantity, price.into())
+    }
+
+    pub fn cancel(&mut self, order_id: OrderId) -> Result<(), PositionError> {
+        let order = self.orders.get_mut(&order_id).ok_or(PositionError::OrderNotFound(order_id))?;
+        if !order.is_working() {
+            return Err(PositionError::OrderRejected(format!("order {} is {:?}", order_id, order.status)));
+        }
+        order.status = OrderStatus::Cancelled;
+        Ok(())
+    }
+
+    // Cancel what is left of day orders and start booking on the next date
+    pub fn end_of_day(&mut self, next_trading_date: NaiveDate) -> Vec<OrderId> {
+        let mut expired = Vec::new();
+        for order in self.orders.values_mut().filter(|order| order.is_working() && matches!(order.time_in_force, TimeInForce::Day)) {
+            order.status = OrderStatus::Cancelled;
+            expired.push(order.order_id);
+        }
+        self.trading_date = next_trading_date;
+        expired
+    }
+
+    // Price levels the order could trade at now, best first, with the size each offers
+    pub fn available_liquidity(&self, repo: &TradeRepository, order: &Order) -> Vec<(Price, Option<i64>)> {
+        let levels: Vec<(Price, Option<i64>)> = match repo.book_snapshots.get(&order.instrument) {
+            Some(book) => {
+                let side = match order.side {
+                    Side::Buy => &book.asks,
+                    Side::Sell => &book.bids,
+                };
+                side.iter().map(|level| (level.price, Some(level.size))).collect()
+            },
+            None => repo.get_market_price(&order.instrument).map(|price| (price, None)).into_iter().collect(),
+        };
+        levels.into_iter().take_while(|(price, _)| order.accepts(*price)).collect()
+    }
+
+    pub fn work(&mut self, repo: &mut TradeRepository, order_id: OrderId) -> Result<Vec<TradeId>, PositionError> {
+        let mut order = self.orders[&order_id].clone();
+        if matches!(order.order_type, TradeType::Stop) && !order.triggered {
+            let (Some(stop), Some(price)) = (order.stop_price, repo.get_market_price(&order.instrument)) else {
+                return self.expire_if_immediate(order_id).map(|_| Vec::new());
+            };
+            order.triggered = match order.side {
+                Side::Buy => price >= stop,
+                Side::Sell => price <= stop,
+            };
+            self.orders.get_mut(&order_id).unwrap().triggered = order.triggered;
+            if !order.triggered {
+                return self.expire_if_immediate(order_id).map(|_| Vec::new());
+            }
+        }
+
+        let levels = self.available_liquidity(repo, &order);
+        if matches!(order.time_in_force, TimeInForce::FillOrKill) {
+            let available: i64 = levels.iter().map(|(_, size)| size.unwrap_or(i64::MAX)).fold(0, i64::saturating_add);
+            if available < order.remaining() as i64 {
+                self.orders.get_mut(&order_id).unwrap().status = OrderStatus::Cancelled;
+                return Ok(Vec::new());
+            }
+        }
+        let mut executions = Vec::new();
+        let mut remaining = order.remaining();
+        for (price, size) in levels {
+            if remaining == 0 {
+                break;
+            }
+            let quantity = size.map_or(remaining, |size| size.min(remaining as i64) as i32);
+            executions.push(self.execute(repo, order_id, quantity, price)?);
+            remaining -= quantity;
+        }
+        self.expire_if_immediate(order_id)?;
+        Ok(executions)
+    }
+
+    pub fn expire_if_immediate(&mut self, order_id: OrderId) -> Result<(), PositionError> {
+        let order = self.orders.get_mut(&order_id).ok_or(PositionError::OrderNotFound(order_id))?;
+        if order.is_working() && matches!(order.time_in_force, TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill) {
+            order.status = OrderStatus::Cancelled;
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, repo: &mut TradeRepository, order_id: OrderId, quantity: i32, price: Price) -> Result<TradeId, PositionError> {
+        let report = ExecutionReport::for_fill(&self.orders[&order_id], quantity, price, start_of_day(self.trading_date));
+        let trade_id = repo.book_execution(report)?;
+        let order = self.orders.get_mut(&order_id).unwrap();
+        order.filled_quantity += quantity;
+        order.filled_notional += price * quantity;
+        order.executions.push(trade_id);
+        order.status = if order.remaining() == 0 { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+        Ok(trade_id)
+    }
+
+    pub fn print_orders(&self) {
+        println!("{:<5} {:<6} {:<5} {:<7} {:>6} {:>6} {:>9} {:<17} Status", "Order", "Instr", "Side", "Type", "Qty", "Filled", "Avg Px", "TIF");
+        for order in self.orders.values() {
+            let average = order.average_fill_price().map_or("-".to_string(), |price| format!("{:.2}", price));
+            println!("{:<5} {:<6} {:<5} {:<7} {:>6} {:>6} {:>9} {:<17} {:?}",
+                     order.order_id, order.instrument, format!("{:?}", order.side), format!("{:?}", order.order_type),
+                     order.quantity, order.filled_quantity, average, format!("{:?}", order.time_in_force), order.status);
+        }
+    }
+}
+
+// One match between an incoming order and a resting one
+#[derive(Debug, Clone)]
+pub struct MatchFill {
+    pub instrument: String,
+    pub price: Price,
+    pub quantity: i32,
+    pub buy_order: OrderId,
+    pub sell_order: OrderId,
+    // Side of the incoming order, which took the liquidity
+    pub aggressor: Side,
+    // Trades booked for each side; None for an account the engine does not book
+    pub buy_trade: Option<TradeId>,
+    pub sell_trade: Option<TradeId>,
+}
+
+// Resting limit orders of one instrument: price -> order ids in arrival order
+#[derive(Debug, Clone, Default)]
+pub struct MatchingBook {
+    pub bids: BTreeMap<Price, VecDeque<OrderId>>,
+    pub asks: BTreeMap<Price, VecDeque<OrderId>>,
+}
+
+// Price-time priority matching engine for simulation. An incoming order trades
+// against the best opposite prices while it crosses, oldest resting order first
+// at each price, and always at the resting order's price. Limit orders rest with
+// what is left, market orders never rest and stop orders are not accepted. Both
+// sides of a match are booked in the repository as trades under their orders'
+// accounts, each with the other account as counterparty, and the match price
+// becomes the instrument's market price.
+#[derive(Debug, Clone)]
+pub struct MatchingEngine {
+    pub orders: BTreeMap<OrderId, Order>,
+    pub books: HashMap<String, MatchingBook>,
+    pub next_order_id: OrderId,
+    // Date matches are booked on; end_of_day moves it on
+    pub trading_date: NaiveDate,
+    // Accounts whose side of a match is booked; None books every account
+    pub booked_accounts: Option<BTreeSet<String>>,
+    pub fills: Vec<MatchFill>,
+}
+
+impl MatchingEngine {
+    pub fn new(trading_date: NaiveDate) -> MatchingEngine {
+        MatchingEngine {
+            orders: BTreeMap::new(),
+            books: HashMap::new(),
+            next_order_id: 1,
+            trading_date,
+            booked_accounts: None,
+            fills: Vec::new(),
+        }
+    }
+
+    // Book only these accounts' executions, e.g. the strategy under test, and
+    // treat every other account as simulated market participants
+    pub fn book_only(mut self, accounts: &[&str]) -> Self {
+        self.booked_accounts = Some(accounts.iter().map(|account| account.to_string()).collect());
+        self
+    }
+
+    pub fn get_order(&self, order_id: OrderId) -> Option<&Order> {
+        self.orders.get(&order_id)
+    }
+
+    pub fn fills_for(&self, order_id: OrderId) -> Vec<&MatchFill> {
+        self.fills.iter().filter(|fill| fill.buy_order == order_id || fill.sell_order == order_id).collect()
+    }
+
+    pub fn best_bid(&self, instrument: &str) -> Option<Price> {
+        self.books.get(instrument)?.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self, instrument: &str) -> Option<Price> {
+        self.books.get(instrument)?.asks.keys().next().copied()
+    }
+
+    // Match an order against the book and rest what a limit order has left
+    pub fn submit(&mut self, repo: &mut TradeRepository, mut order: Order) -> Result<OrderId, PositionError> {
+        if order.quantity <= 0 {
+            return Err(PositionError::InvalidQuantity(order.quantity));
+        }
+        let rejection = match order.order_type {
+            TradeType::Limit if order.limit_price.is_none() => Some("a limit order needs a limit price".to_string()),
+            TradeType::Limit | TradeType::Market => None,
+            _ => Some(format!("the matching engine does not accept {:?} orders", order.order_type)),
+        };
+        if let Some(reason) = rejection {
+            return Err(PositionError::OrderRejected(reason));
+        }
+        let opposite = match order.side {
+            Side::Buy => self.best_ask(&order.instrument),
+            Side::Sell => self.best_bid(&order.instrument),
+        };
+        if matches!(order.order_type, TradeType::Market) && opposite.is_none() {
+            return Err(PositionError::OrderRejected(format!("no liquidity for a market order in {}", order.instrument)));
+        }
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        order.order_id = order_id;
+        order.status = OrderStatus::New;
+        self.orders.insert(order_id, order);
+        self.match_order(repo, order_id)?;
+        Ok(order_id)
+    }
+
+    pub fn cancel(&mut self, order_id: OrderId) -> Result<(), PositionError> {
+        let order = self.orders.get(&order_id).ok_or(PositionError::OrderNotFound(order_id))?;
+        if !order.is_working() {
+            return Err(PositionError::OrderRejected(format!("order {} is {:?}", order_id, order.status)));
+        }
+        self.remove_resting(order_id);
+        self.orders.get_mut(&order_id).unwrap().status = OrderStatus::Cancelled;
+        Ok(())
+    }
+
+    // Pull day orders from the books and start booking on the next date
+    pub fn end_of_day(&mut self, next_trading_date: NaiveDate) -> Vec<OrderId> {
+        let expired: Vec<OrderId> = self.orders.values()
+            .filter(|order| order.is_working() && matches!(order.time_in_force, TimeInForce::Day))
+            .map(|order| order.order_id)
+            .collect();
+        for order_id in &expired {
+            self.remove_resting(*order_id);
+            self.orders.get_mut(order_id).unwrap().status = OrderStatus::Cancelled;
+        }
+        self.trading_date = next_trading_date;
+        expired
+    }
+
+    // Resting size per price, in the form the repository marks from
+    pub fn book_snapshot(&self, instrument: &str, captured_at: DateTime<Utc>) -> BookSnapshot {
+        let mut snapshot = BookSnapshot::new(instrument, captured_at);
+        if let Some(book) = self.books.get(instrument) {
+            let size = |queue: &VecDeque<OrderId>| queue.iter().map(|order_id| self.orders[order_id].remaining() as i64).sum::<i64>();
+            for (price, queue) in book.bids.iter().rev() {
+                snapshot = snapshot.bid(*price, size(queue));
+            }
+            for (price, queue) in &book.asks {
+                snapshot = snapshot.ask(*price, size(queue));
+            }
+        }
+        snapshot
+    }
+
+    // Best resting order the incoming side would trade with, and its price
+    pub fn best_resting(&self, instrument: &str, side: &Side) -> Option<(Price, OrderId)> {
+        let book = self.books.get(instrument)?;
+        let (price, queue) = match side {
+            Side::Buy => book.asks.iter().next()?,
+            Side::Sell => book.bids.iter().next_back()?,
+        };
+        Some((*price, *queue.front()?))
+    }
+
+    pub fn remove_resting(&mut self, order_id: OrderId) {
+        let order = &self.orders[&order_id];
+        let (Some(book), Some(price)) = (self.books.get_mut(&order.instrument), order.limit_price) else { return };
+        let levels = match order.side {
+            Side::Buy => &mut book.bids,
+            Side::Sell => &mut book.asks,
+        };
+        if let Some(queue) = levels.get_mut(&price) {
+            queue.retain(|resting| *resting != order_id);
+            if queue.is_empty() {
+                levels.remove(&price);
+            }
+        }
+    }
+
+    pub fn match_order(&mut self, repo: &mut TradeRepository, order_id: OrderId) -> Result<(), PositionError> {
+        let order = self.orders[&order_id].clone();
+        if matches!(order.time_in_force, TimeInForce::FillOrKill) {
+            let crossing: i32 = self.books.get(&order.instrument).map_or(0, |book| {
+                let levels = match order.side {
+                    Side::Buy => &book.asks,
+                    Side::Sell => &book.bids,
+                };
+                levels.iter()
+                    .filter(|(price, _)| order.accepts(**price))
+                    .flat_map(|(_, queue)| queue)
+                    .map(|resting| self.orders[resting].remaining())
+                    .sum()
+            });
+            if crossing < order.quantity {
+                self.orders.get_mut(&order_id).unwrap().status = OrderStatus::Cancelled;
+                return Ok(());
+            }
+        }
+
+        while self.orders[&order_id].remaining() > 0 {
+            let Some((price, resting)) = self.best_resting(&order.instrument, &order.side) else { break };
+            if !order.accepts(price) {
+                break;
+            }
+            let quantity = self.orders[&order_id].remaining().min(self.orders[&resting].remaining());
+            self.book_match(repo, order_id, resting, price, quantity)?;
+            if self.orders[&resting].remaining() == 0 {
+                self.remove_resting(resting);
+            }
+        }
+
+        let order = &self.orders[&order_id];
+        if order.remaining() == 0 {
+            return Ok(());
+        }
+        let rests = matches!(order.order_type, TradeType::Limit) && matches!(order.time_in_force, TimeInForce::Day | TimeInForce::GoodTillCancel);
+        if !rests {
+            self.orders.get_mut(&order_id).unwrap().status = OrderStatus::Cancelled;
+            return Ok(());
+        }
+        let book = self.books.entry(order.instrument.clone()).or_default();
+        let levels = match order.side {
+            Side::Buy => &mut book.bids,
+            Side::Sell => &mut book.asks,
+        };
+        levels.entry(order.limit_price.unwrap()).or_default().push_back(order_id);
+        Ok(())
+    }
+
+    pub fn book_match(&mut self, repo: &mut TradeRepository, incoming: OrderId, resting: OrderId, price: Price, quantity: i32) -> Result<(), PositionError> {
+        let aggressor = self.orders[&incoming].side.clone();
+        let (buy_order, sell_order) = match aggressor {
+            Side::Buy => (incoming, resting),
+            Side::Sell => (resting, incoming),
+        };
+        let buy_trade = self.execute(repo, buy_order, sell_order, quantity, price)?;
+        let sell_trade = self.execute(repo, sell_order, buy_order, quantity, price)?;
+        let instrument = self.orders[&incoming].instrument.clone();
+        repo.update_market_price(&instrument, price)?;
+        self.fills.push(MatchFill { instrument, price, quantity, buy_order, sell_order, aggressor, buy_trade, sell_trade });
+        Ok(())
+    }
+
+    // Fill one side of a match, booking it unless its account is not booked
+    pub fn execute(&mut self, repo: &mut TradeRepository, order_id: OrderId, contra_id: OrderId, quantity: i32, price: Price) -> Result<Option<TradeId>, PositionError> {
+        let order = &self.orders[&order_id];
+        let booked = self.booked_accounts.as_ref().is_none_or(|accounts| accounts.contains(&order.account_id));
+        let trade_id = if booked {
+            let report = ExecutionReport { counterparty: Some(self.orders[&contra_id].account_id.clone()), ..ExecutionReport::for_fill(order, quantity, price, start_of_day(self.trading_date)) };
+            Some(repo.book_execution(report)?)
+        } else {
+            None
+        };
+        let order = self.orders.get_mut(&order_id).unwrap();
+        order.filled_quantity += quantity;
+        order.filled_notional += price * quantity;
+        order.status = if order.remaining() == 0 { OrderStatus::Filled } else { OrderStatus::PartiallyFilled };
+        order.executions.extend(trade_id);
+        Ok(trade_id)
+    }
+
+    pub fn print_book(&self, instrument: &str) {
+        let snapshot = self.book_snapshot(instrument, Utc::now());
+        println!("{} book", instrument);
+        println!("{:>8} {:>10} {:>8}", "Bid", "Price", "Ask");
+        for level in snapshot.asks.iter().rev() {
+            println!("{:>19.2} {:>8}", level.price, level.size);
+        }
+        for level in &snapshot.bids {
+            println!("{:>8} {:>10.2}", level.size, level.price);
+        }
+    }
+
+    pub fn print_fills(&self) {
+        println!("{:<6} {:>6} {:>9} {:>5} {:>5} {:<9} {:>9} {:>10}", "Instr", "Qty", "Price", "Buy", "Sell", "Aggressor", "Buy trade", "Sell trade");
+        for fill in &self.fills {
+            let trade = |trade_id: Option<TradeId>| trade_id.map_or("-".to_string(), |trade_id| trade_id.to_string());
+            println!("{:<6} {:>6} {:>9.2} {:>5} {:>5} {:<9} {:>9} {:>10}",
+                     fill.instrument, fill.quantity, fill.price, fill.buy_order, fill.sell_order,
+                     format!("{:?}", fill.aggressor), trade(fill.buy_trade), trade(fill.sell_trade));
+        }
+    }
+}
+
+// Drawdown levels to alert on, as fractions of peak equity (e.g. 0.05 for 5%)
+#[derive(Debug, Clone)]
+pub struct DrawdownAlertConfig {
+    pub thresholds: Vec<f64>,
+    // A threshold that has fired stays quiet for this many days
+    pub cooldown_days: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DrawdownAlert {
+    pub date: NaiveDate,
+    pub threshold: f64,
+    pub drawdown: f64,
+    pub peak_date: NaiveDate,
+    pub peak_equity: Money,
+    pub equity: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct DrawdownPeriod {
+    pub start: NaiveDate,
+    pub trough: NaiveDate,
+    // None while the equity is still below the previous peak
+    pub recovery: Option<NaiveDate>,
+    pub depth: f64,
+    pub length_days: i64,
+}
+
+// Fraction below the running peak for each point of an equity series
+pub fn drawdown_series(equity: &[(NaiveDate, Money)]) -> Vec<(NaiveDate, f64, NaiveDate, Money)> {
+    let mut series = Vec::new();
+    let mut peak: Option<(NaiveDate, Money)> = None;
+    for (date, value) in equity {
+        if peak.is_none_or(|(_, peak_value)| *value >= peak_value) {
+            peak = Some((*date, *value));
+        }
+        let (peak_date, peak_value) = peak.unwrap();
+        let drawdown = if peak_value > Decimal::ZERO { ((peak_value - *value) / peak_value).to_f64() } else { 0.0 };
+        series.push((*date, drawdown, peak_date, peak_value));
+    }
+    series
+}
+
+// Alert when the drawdown crosses a threshold, at most once per cooldown per threshold
+pub fn drawdown_alerts(equity: &[(NaiveDate, Money)], config: &DrawdownAlertConfig) -> Vec<DrawdownAlert> {
+    let mut thresholds = config.thresholds.clone();
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut last_alerts: Vec<Option<NaiveDate>> = vec![None; thresholds.len()];
+    let mut previous_drawdown = 0.0;
+    let mut alerts = Vec::new();
+
+    for ((date, drawdown, peak_date, peak_equity), (_, value)) in drawdown_series(equity).into_iter().zip(equity) {
+        for (threshold, last_alert) in thresholds.iter().zip(last_alerts.iter_mut()) {
+            let crossed = drawdown >= *threshold && previous_drawdown < *threshold;
+            let cooling_down = last_alert.is_some_and(|last| (date - last).num_days() < config.cooldown_days);
+            if crossed && !cooling_down {
+                *last_alert = Some(date);
+                alerts.push(DrawdownAlert {
+                    date,
+                    threshold: *threshold,
+                    drawdown,
+                    peak_date,
+                    peak_equity,
+                    equity: *value,
+                });
+            }
+        }
+        previous_drawdown = drawdown;
+    }
+
+    alerts
+}
+
+// Every period spent below a previous peak, with its deepest point and recovery
+pub fn drawdown_periods(equity: &[(NaiveDate, Money)]) -> Vec<DrawdownPeriod> {
+    let mut periods = Vec::new();
+    let mut current: Option<DrawdownPeriod> = None;
+
+    for (date, drawdown, peak_date, _) in drawdown_series(equity) {
+        match current.as_mut() {
+            Some(period) if drawdown <= 0.0 => {
+                period.recovery = Some(date);
+                period.length_days = (date - period.start).num_days();
+                periods.push(current.take().unwrap());
+            },
+            Some(period) => {
+                if drawdown > period.depth {
+                    period.depth = drawdown;
+                    period.trough = date;
+                }
+                period.length_days = (date - period.start).num_days();
+            },
+            None if drawdown > 0.0 => {
+                current = Some(DrawdownPeriod {
+                    start: peak_date,
+                    trough: date,
+                    recovery: None,
+                    depth: drawdown,
+                    length_days: (date - peak_date).num_days(),
+                });
+            },
+            None => {}
+        }
+    }
+
+    periods.extend(current);
+    periods
+}
+
+pub const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+
+// Returns and risk statistics of an equity series over a period. Daily returns are
+// taken on weekdays and net of external flows, which are assumed to arrive at the
+// start of the day; ratios are None when the returns don't vary.
+#[derive(Debug, Clone)]
+pub struct PerformanceReport {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub starting_equity: Money,
+    pub ending_equity: Money,
+    // Deposits less withdrawals in the period
+    pub net_flows: Money,
+    pub daily_returns: Vec<(NaiveDate, f64)>,
+    pub time_weighted_return: f64,
+    // Annualized internal rate of return of the flows and the ending equity;
+    // None when it can't be bracketed
+    pub money_weighted_return: Option<f64>,
+    pub annualized_return: f64,
+    pub annualized_volatility: f64,
+    pub sharpe_ratio: Option<f64>,
+    pub sortino_ratio: Option<f64>,
+    // Deepest fall of the time-weighted index from a peak, as a fraction
+    pub max_drawdown: f64,
+    pub max_drawdown_peak: NaiveDate,
+    pub max_drawdown_trough: NaiveDate,
+}
+
+// Return of each day after the first: (E_t - E_t-1 - F_t) / (E_t-1 + F_t)
+pub fn daily_returns(equity: &[(NaiveDate, Money)], flows: &BTreeMap<NaiveDate, Money>) -> Vec<(NaiveDate, f64)> {
+    equity.windows(2)
+        .filter_map(|pair| {
+            let ((_, previous), (date, value)) = (pair[0], pair[1]);
+            let flow = flows.get(&date).copied().unwrap_or(Decimal::ZERO);
+            let invested = previous + flow;
+            (!invested.is_zero()).then(|| (date, ((value - invested) / invested).to_f64()))
+        })
+        .collect()
+}
+
+// Annualized rate r at which the dated cash flows discount to zero, found by bisection
+pub fn money_weighted_return(cash_flows: &[(NaiveDate, f64)]) -> Option<f64> {
+    let first = cash_flows.first()?.0;
+    let present_value = |rate: f64| -> f64 {
+        cash_flows.iter().map(|(date, amount)| amount / (1.0 + rate).powf((*date - first).num_days() as f64 / 365.0)).sum()
+    };
+    let (mut low, mut high) = (-0.9999, 100.0);
+    if present_value(low).signum() == present_value(high).signum() {
+        return None;
+    }
+    for _ in 0..200 {
+        let mid = (low + high) / 2.0;
+        if present_value(mid).signum() == present_value(low).signum() {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+pub fn performance_report(equity: &[(NaiveDate, Money)], flows: &BTreeMap<NaiveDate, Money>, risk_free_rate: f64) -> Option<PerformanceReport> {
+    let equity: Vec<(NaiveDate, Money)> = equity.iter().copied().filter(|(date, _)| !matches!(date.weekday(), Weekday::Sat | Weekday::Sun)).collect();
+    let (&(start_date, starting_equity), &(end_date, ending_equity)) = (equity.first()?, equity.last()?);
+    let returns = daily_returns(&equity, flows);
+    let values: Vec<f64> = returns.iter().map(|(_, r)| *r).collect();
+
+    let mut index = 1.0;
+    let (mut peak, mut peak_date) = (1.0, start_date);
+    let (mut max_drawdown, mut max_drawdown_peak, mut max_drawdown_trough) = (0.0, start_date, start_date);
+    for (date, r) in &returns {
+        index *= 1.0 + r;
+        if index >= peak {
+            (peak, peak_date) = (index, *date);
+        } else if (peak - index) / peak > max_drawdown {
+            (max_drawdown, max_drawdown_peak, max_drawdown_trough) = ((peak - index) / peak, peak_date, *date);
+        }
+    }
+    let time_weighted_return = index - 1.0;
+
+    let periods = values.len().max(1) as f64;
+    let mean = values.iter().sum::<f64>() / periods;
+    let variance = if values.len() > 1 { values.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (periods - 1.0) } else { 0.0 };
+    let daily_risk_free = risk_free_rate / TRADING_DAYS_PER_YEAR;
+    let downside = (values.iter().map(|r| (r - daily_risk_free).min(0.0).powi(2)).sum::<f64>() / periods).sqrt();
+    let annualize = |excess: f64, deviation: f64| (deviation > 0.0).then(|| excess / deviation * TRADING_DAYS_PER_YEAR.sqrt());
+
+    let in_period = flows.range(start_date..=end_date).filter(|(date, _)| **date > start_date);
+    let mut cash_flows = vec![(start_date, -starting_equity.to_f64())];
+    cash_flows.extend(in_period.clone().map(|(date, flow)| (*date, -flow.to_f64())));
+    cash_flows.push((end_date, ending_equity.to_f64()));
+
+    Some(PerformanceReport {
+        start_date,
+        end_date,
+        starting_equity,
+        ending_equity,
+        net_flows: in_period.map(|(_, flow)| *flow).sum(),
+        daily_returns: returns,
+        time_weighted_return,
+        money_weighted_return: money_weighted_return(&cash_flows),
+        annualized_return: (1.0 + time_weighted_return).powf(TRADING_DAYS_PER_YEAR / periods) - 1.0,
+        annualized_volatility: variance.sqrt() * TRADING_DAYS_PER_YEAR.sqrt(),
+        sharpe_ratio: annualize(mean - daily_risk_free, variance.sqrt()),
+        sortino_ratio: annualize(mean - daily_risk_free, downside),
+        max_drawdown,
+        max_drawdown_peak,
+        max_drawdown_trough,
+    })
+}
+
+// How trade statistics are computed: over every matching trade, or from a fixed-size
+// uniform sample and sketches so the cost stays flat however large the book
+#[derive(Debug, Clone, Copy)]
+pub enum AnalyticsMode {
+    Exact,
+    Approximate { sample_size: usize, seed: u64 },
+}
+
+// A statistic with the half-width of its 95% confidence interval; zero when exact
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub value: f64,
+    pub margin: f64,
+}
+
+impl Estimate {
+    pub fn exact(value: f64) -> Estimate {
+        Estimate { value, margin: 0.0 }
+    }
+}
+
+impl std::fmt::Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.margin == 0.0 {
+            write!(f, "{:.2}", self.value)
+        } else {
+            write!(f, "{:.2} ± {:.2}", self.value, self.margin)
+        }
+    }
+}
+
+// Uniform sample of at most `capacity` items from a stream of unknown length
+// (Algorithm R), with a seeded xorshift so runs are repeatable
+#[derive(Debug, Clone)]
+pub struct Reservoir<T> {
+    pub capacity: usize,
+    pub seen: u64,
+    pub items: Vec<T>,
+    pub state: u64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize, seed: u64) -> Reservoir<T> {
+        Reservoir { capacity, seen: 0, items: Vec::with_capacity(capacity), state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1 }
+    }
+
+    pub fn offer(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let slot = (self.state % self.seen) as usize;
+        if slot < self.capacity {
+            self.items[slot] = item;
+        }
+    }
+}
+
+// HyperLogLog distinct counter over 2^12 registers: about 1.6% standard error in
+// 4 KB whatever the number of distinct values
+#[derive(Debug, Clone)]
+pub struct DistinctSketch {
+    pub registers: Vec<u8>,
+}
+
+impl DistinctSketch {
+    pub const PRECISION: u32 = 12;
+
+    pub fn new() -> DistinctSketch {
+        DistinctSketch { registers: vec![0; 1 << Self::PRECISION] }
+    }
+
+    pub fn insert(&mut self, value: &str) {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write(value.as_bytes());
+        let hash = hasher.finish();
+        let register = (hash >> (64 - Self::PRECISION)) as usize;
+        let rank = ((hash << Self::PRECISION) | (1 << (Self::PRECISION - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        let m = self.registers.len() as f64;
+        let harmonic: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let mut value = 0.7213 / (1.0 + 1.079 / m) * m * m / harmonic;
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        // Linear counting is more accurate while many registers are still empty
+        if value <= 2.5 * m && empty > 0 {
+            value = m * (m / empty as f64).ln();
+        }
+        Estimate { value, margin: value * 1.96 * 1.04 / m.sqrt() }
+    }
+}
+
+impl Default for DistinctSketch {
+    fn default() -> DistinctSketch {
+        DistinctSketch::new()
+    }
+}
+
+pub const STATISTIC_QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 1.0];
+
+// Counts, totals, distinct counts and quantiles of trade size and price. Sampled
+// quantiles are off by at most quantile_rank_error in rank (95% confidence).
+#[derive(Debug, Clone)]
+pub struct TradeStatistics {
+    pub mode: AnalyticsMode,
+    pub trades: usize,
+    pub sampled: usize,
+    pub total_quantity: Estimate,
+    pub total_notional: Estimate,
+    pub distinct_instruments: Estimate,
+    pub distinct_accounts: Estimate,
+    pub quantity_quantiles: Vec<(f64, f64)>,
+    pub price_quantiles: Vec<(f64, f64)>,
+    pub quantile_rank_error: f64,
+}
+
+pub fn sample_quantiles(mut values: Vec<f64>) -> Vec<(f64, f64)> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    if values.is_empty() {
+        return Vec::new();
+    }
+    STATISTIC_QUANTILES.iter().map(|q| (*q, values[((values.len() - 1) as f64 * q).round() as usize])).collect()
+}
+
+// Population total from a sample's values, with a 95% bound that includes the
+// finite population correction
+pub fn estimate_total(sample: &[f64], population: usize) -> Estimate {
+    let n = sample.len() as f64;
+    let total = population as f64;
+    if sample.is_empty() {
+        return Estimate::exact(0.0);
+    }
+    let mean = sample.iter().sum::<f64>() / n;
+    if sample.len() >= population {
+        return Estimate::exact(mean * total);
+    }
+    let variance = sample.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+    let correction = ((total - n) / (total - 1.0)).sqrt();
+    Estimate { value: mean * total, margin: 1.96 * total * (variance / n).sqrt() * correction }
+}
+
+pub fn print_trade_statistics(statistics: &TradeStatistics) {
+    let quantiles = |quantiles: &[(f64, f64)]| quantiles.iter().map(|(q, value)| format!("p{}={:.2}", q * 100.0, value)).collect::<Vec<String>>().join(" ");
+    println!("{:?}: {} trades, {} used", statistics.mode, statistics.trades, statistics.sampled);
+    println!("  Quantity {} | notional {}", statistics.total_quantity, statistics.total_notional);
+    println!("  Distinct instruments {} | accounts {}", statistics.distinct_instruments, statistics.distinct_accounts);
+    println!("  Size {} | price {} (rank error ±{:.1}%)",
+             quantiles(&statistics.quantity_quantiles), quantiles(&statistics.price_quantiles), statistics.quantile_rank_error * 100.0);
+}
+
+// Portfolio against a benchmark over the days of a PerformanceReport. Alpha is
+// Jensen's alpha, annualized; beta and tracking error come from daily returns.
+#[derive(Debug, Clone)]
+pub struct BenchmarkComparison {
+    pub benchmark: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub portfolio_return: f64,
+    pub benchmark_return: f64,
+    // Portfolio return less benchmark return, compounded over the period
+    pub cumulative_excess_return: f64,
+    pub alpha: f64,
+    pub beta: Option<f64>,
+    pub tracking_error: f64,
+    pub information_ratio: Option<f64>,
+}
+
+// `pairs` holds each day's portfolio and benchmark return
+pub fn benchmark_comparison(benchmark: &str, performance: &PerformanceReport, pairs: &[(f64, f64)], risk_free_rate: f64) -> BenchmarkComparison {
+    let n = pairs.len().max(1) as f64;
+    let mean = |values: &mut dyn Iterator<Item = f64>| values.sum::<f64>() / n;
+    let (portfolio_mean, benchmark_mean) = (mean(&mut pairs.iter().map(|(p, _)| *p)), mean(&mut pairs.iter().map(|(_, b)| *b)));
+    let sample = (n - 1.0).max(1.0);
+    let covariance = pairs.iter().map(|(p, b)| (p - portfolio_mean) * (b - benchmark_mean)).sum::<f64>() / sample;
+    let benchmark_variance = pairs.iter().map(|(_, b)| (b - benchmark_mean).powi(2)).sum::<f64>() / sample;
+    let excess_mean = portfolio_mean - benchmark_mean;
+    let tracking_deviation = (pairs.iter().map(|(p, b)| (p - b - excess_mean).powi(2)).sum::<f64>() / sample).sqrt();
+
+    let beta = (benchmark_variance > 0.0).then(|| covariance / benchmark_variance);
+    let daily_risk_free = risk_free_rate / TRADING_DAYS_PER_YEAR;
+    let alpha = (portfolio_mean - daily_risk_free - beta.unwrap_or(0.0) * (benchmark_mean - daily_risk_free)) * TRADING_DAYS_PER_YEAR;
+    let benchmark_return = pairs.iter().fold(1.0, |growth, (_, b)| growth * (1.0 + b)) - 1.0;
+    let tracking_error = tracking_deviation * TRADING_DAYS_PER_YEAR.sqrt();
+    BenchmarkComparison {
+        benchmark: benchmark.to_string(),
+        start_date: performance.start_date,
+        end_date: performance.end_date,
+        portfolio_return: performance.time_weighted_return,
+        benchmark_return,
+        cumulative_excess_return: performance.time_weighted_return - benchmark_return,
+        alpha,
+        beta,
+        tracking_error,
+        information_ratio: (tracking_error > 0.0).then(|| excess_mean * TRADING_DAYS_PER_YEAR / tracking_error),
+    }
+}
+
+pub fn print_benchmark_comparison(comparison: &BenchmarkComparison) {
+    let ratio = |ratio: Option<f64>| ratio.map_or("n/a".to_string(), |ratio| format!("{:.2}", ratio));
+    println!("\n=== Against {} {} to {} ===", comparison.benchmark, comparison.start_date, comparison.end_date);
+    println!("Portfolio {:.2}% vs benchmark {:.2}%: excess {:+.2}%",
+             comparison.portfolio_return * 100.0, comparison.benchmark_return * 100.0, comparison.cumulative_excess_return * 100.0);
+    println!("Alpha {:+.2}% annualized | beta {} | tracking error {:.2}% | information ratio {}",
+             comparison.alpha * 100.0, ratio(comparison.beta), comparison.tracking_error * 100.0, ratio(comparison.information_ratio));
+}
+
+pub fn print_performance_report(report: &PerformanceReport) {
+    let ratio = |ratio: Option<f64>| ratio.map_or("n/a".to_string(), |ratio| format!("{:.2}", ratio));
+    println!("\n=== Performance {} to {} ===", report.start_date, report.end_date);
+    println!("Equity: ${:.2} -> ${:.2}, net flows ${:.2} over {} daily returns", report.starting_equity, report.ending_equity, report.net_flows, report.daily_returns.len());
+    println!("Time-weighted return: {:.2}% ({:.2}% annualized)", report.time_weighted_return * 100.0, report.annualized_return * 100.0);
+    println!("Money-weighted return: {}", report.money_weighted_return.map_or("n/a".to_string(), |irr| format!("{:.2}% annualized", irr * 100.0)));
+    println!("Volatility: {:.2}% annualized | Sharpe: {} | Sortino: {}", report.annualized_volatility * 100.0, ratio(report.sharpe_ratio), ratio(report.sortino_ratio));
+    println!("Max drawdown: {:.2}% from {} to {}", report.max_drawdown * 100.0, report.max_drawdown_peak, report.max_drawdown_trough);
+}
+
+#[derive(Debug, Clone)]
+pub struct PnlAnomalyConfig {
+    // Number of prior daily moves the volatility is measured over
+    pub lookback_days: usize,
+    // Moves further than this many standard deviations from the mean are flagged
+    pub threshold_sigmas: f64,
+    // No flags until at least this many prior moves are available
+    pub min_history: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct PnlAnomaly {
+    pub instrument: String,
+    pub date: NaiveDate,
+    pub pnl_change: Money,
+    pub mean_change: f64,
+    pub std_dev: f64,
+    pub sigmas: f64,
+}
+
+// Day-over-day P&L moves that stand out against the instrument's own recent
+// volatility. Each move is judged against the moves before it, never itself.
+pub fn pnl_anomalies(instrument: &str, pnl_history: &[(NaiveDate, Money)], config: &PnlAnomalyConfig) -> Vec<PnlAnomaly> {
+    let changes: Vec<(NaiveDate, Money)> = pnl_history
+        .windows(2)
+        .map(|pair| (pair[1].0, pair[1].1 - pair[0].1))
+        .collect();
+
+    let mut anomalies = Vec::new();
+    for (index, (date, change)) in changes.iter().enumerate() {
+        let window = &changes[index.saturating_sub(config.lookback_days)..index];
+        if window.len() < config.min_history.max(2) {
+            continue;
+        }
+        let values: Vec<f64> = window.iter().map(|(_, change)| change.to_f64()).collect();
+        let mean_change = values.iter().sum::<f64>() / values.len() as f64;
+        let variance = values.iter().map(|value| (value - mean_change).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+        let std_dev = variance.sqrt();
+        if std_dev == 0.0 {
+            continue;
+        }
+        let sigmas = (change.to_f64() - mean_change) / std_dev;
+        if sigmas.abs() > config.threshold_sigmas {
+            anomalies.push(PnlAnomaly {
+                instrument: instrument.to_string(),
+                date: *date,
+                pnl_change: *change,
+                mean_change,
+                std_dev,
+                sigmas,
+            });
+        }
+    }
+    anomalies
+}
+
+// Trades and position of one instrument inside the concurrent repository
+#[derive(Debug)]
+pub struct InstrumentBook {
+    pub trades: BTreeMap<TradeId, Trade>,
+    pub position: TradePosition,
+}
+
+impl InstrumentBook {
+    // Replay the book's active trades in chronological order after a cancel or amend
+    pub fn rebuild_position(&mut self) {
+        let mut replay: Vec<&Trade> = self.trades.values()
+            .filter(|trade| !matches!(trade.status, TradeStatus::Cancelled))
+            .collect();
+        replay.sort_by_key(|trade| trade.chronological_key());
+        let mut position = TradePosition::new(self.position.instrument.clone());
+        for trade in replay {
+            position.update_position(trade);
+        }
+        self.position = position;
+    }
+}
+
+pub const TRADE_INDEX_SHARDS: usize = 64;
+
+// Repository for multiple writer threads. Each instrument has its own lock, so
+// trades in different instruments never wait on each other; the instrument map
+// is only write-locked the first time an instrument is seen, and the trade id
+// index is sharded by id.
+//
+// Consistency guarantees:
+// - Every add, amend and cancel changes the trade and its instrument's position
+//   under the instrument lock, so a position read sees an amend either not at
+//   all or in full, never the old trade removed without the new one applied.
+// - Writes to the same instrument are applied one at a time in lock order; for
+//   the same trade that means concurrent amends land last-writer-wins.
+// - A trade id is reserved and its trade booked under the index shard lock, so
+//   a duplicate id is always rejected and an amend/cancel that finds the id also
+//   finds the trade.
+// - get_position and trade_and_position are consistent for one instrument.
+//   get_all_positions reads instruments one after another and may mix states
+//   across instruments; snapshot gives a single point in time at the cost of
+//   briefly blocking every writer.
+// Locks are taken index shard -> instrument map -> instrument book, and never
+// more than one shard or (outside snapshot) more than one book at a time.
+#[derive(Debug)]
+pub struct ConcurrentTradeRepository {
+    pub books: RwLock<HashMap<String, Arc<Mutex<InstrumentBook>>>>,
+    // trade id -> instrument, for amend and cancel by id
+    pub trade_index: Vec<Mutex<HashMap<TradeId, String>>>,
+}
+
+impl ConcurrentTradeRepository {
+    pub fn new() -> ConcurrentTradeRepository {
+        ConcurrentTradeRepository {
+            books: RwLock::new(HashMap::new()),
+            trade_index: (0..TRADE_INDEX_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    pub fn index_shard(&self, trade_id: TradeId) -> &Mutex<HashMap<TradeId, String>> {
+        &self.trade_index[trade_id.shard(TRADE_INDEX_SHARDS)]
+    }
+
+    pub fn book(&self, instrument: &str) -> Arc<Mutex<InstrumentBook>> {
+        if let Some(book) = self.books.read().unwrap().get(instrument) {
+            return Arc::clone(book);
+        }
+        let mut books = self.books.write().unwrap();
+        Arc::clone(books.entry(instrument.to_string()).or_insert_with(|| {
+            Arc::new(Mutex::new(InstrumentBook {
+                trades: BTreeMap::new(),
+                position: TradePosition::new(instrument.to_string()),
+            }))
+        }))
+    }
+
+    pub fn instrument_of(&self, trade_id: TradeId) -> Option<String> {
+        self.index_shard(trade_id).lock().unwrap().get(&trade_id).cloned()
+    }
+
+    pub fn add_trade(&self, mut trade: Trade) -> Result<(), PositionError> {
+        validate_terms(&trade.instrument, trade.quantity, trade.price)?;
+        if !matches!(trade.status, TradeStatus::Active) && !trade.status.can_become(&TradeStatus::Active) {
+            return Err(PositionError::InvalidTransition { trade_id: trade.trade_id, from: trade.status, to: TradeStatus::Active });
+        }
+        trade.status = TradeStatus::Active;
+        let mut index = self.index_shard(trade.trade_id).lock().unwrap();
+        if index.contains_key(&trade.trade_id) {
+            return Err(PositionError::DuplicateTradeId(trade.trade_id));
+        }
+        index.insert(trade.trade_id, trade.instrument.clone());
+        let book = self.book(&trade.instrument);
+        let mut book = book.lock().unwrap();
+        book.position.update_position(&trade);
+        book.trades.insert(trade.trade_id, trade);
+        Ok(())
+    }
+
+    pub fn amend_trade(&self, trade_id: impl Into<TradeId>, new_quantity: i32, new_price: impl Into<Price>) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let new_price = new_price.into();
+        let instrument = self.instrument_of(trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        validate_terms(&instrument, new_quantity, new_price)?;
+        let book = self.book(&instrument);
+        let mut book = book.lock().unwrap();
+        let trade = book.trades.get_mut(&trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        if matches!(trade.status, TradeStatus::Cancelled) {
+            return Err(PositionError::TradeCancelled(trade_id));
+        }
+        if !trade.status.can_become(&TradeStatus::Amended) {
+            return Err(PositionError::InvalidTransition { trade_id, from: trade.status.clone(), to: TradeStatus::Amended });
+        }
+        trade.quantity = new_quantity;
+        trade.price = new_price;
+        trade.status = TradeStatus::Amended;
+        book.rebuild_position();
+        Ok(())
+    }
+
+    pub fn cancel_trade(&self, trade_id: impl Into<TradeId>) -> Result<(), PositionError> {
+        let trade_id = trade_id.into();
+        let instrument = self.instrument_of(trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        let book = self.book(&instrument);
+        let mut book = book.lock().unwrap();
+        let trade = book.trades.get_mut(&trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+        if matches!(trade.status, TradeStatus::Cancelled) {
+            return Err(PositionError::TradeCancelled(trade_id));
+        }
+        trade.status = TradeStatus::Cancelled;
+        book.rebuild_position();
+        Ok(())
+    }
+
+    pub fn get_position(&self, instrument: &str) -> Option<TradePosition> {
+        let book = Arc::clone(self.books.read().unwrap().get(instrument)?);
+        let position = book.lock().unwrap().position.clone();
+        Some(position)
+    }
+
+    pub fn get_all_positions(&self) -> BTreeMap<String, TradePosition> {
+        let books: Vec<Arc<Mutex<InstrumentBook>>> = self.books.read().unwrap().values().cloned().collect();
+        books
+            .iter()
+            .map(|book| {
+                let book = book.lock().unwrap();
+                (book.position.instrument.clone(), book.position.clone())
+            })
+            .collect()
+    }
+
+    pub fn get_trade(&self, trade_id: impl Into<TradeId>) -> Option<Trade> {
+        let trade_id = trade_id.into();
+        self.trade_and_position(trade_id).map(|(trade, _)| trade)
+    }
+
+    // A trade together with its instrument's position, read under one lock
+    pub fn trade_and_position(&self, trade_id: impl Into<TradeId>) -> Option<(Trade, TradePosition)> {
+        let trade_id = trade_id.into();
+        let instrument = self.instrument_of(trade_id)?;
+        let book = self.book(&instrument);
+        let book = book.lock().unwrap();
+        Some((book.trades.get(&trade_id)?.clone(), book.position.clone()))
+    }
+
+    // Every position as of one instant: all books are held until the copy is done
+    pub fn snapshot(&self) -> BTreeMap<String, TradePosition> {
+        let books = self.books.read().unwrap();
+        let mut instruments: Vec<&String> = books.keys().collect();
+        instruments.sort();
+        let guards: Vec<_> = instruments.iter().map(|instrument| books[*instrument].lock().unwrap()).collect();
+        guards
+            .iter()
+            .map(|book| (book.position.instrument.clone(), book.position.clone()))
+            .collect()
+    }
+}
+
+impl Default for ConcurrentTradeRepository {
+    fn default() -> ConcurrentTradeRepository {
+        ConcurrentTradeRepository::new()
+    }
+}
+
+// What-if engine: hypothetical trades and marks booked on a copy of the
+// repository, which can be inspected and then discarded or committed
+#[derive(Debug, Clone)]
+pub struct WhatIf {
+    pub sandbox: TradeRepository,
+    pub booked: Vec<Trade>,
+    pub marks: Vec<(String, Price)>,
+}
+
+impl WhatIf {
+    pub fn new(base: &TradeRepository) -> WhatIf {
+        // Hypothetical trades must never reach the real journal
+        let mut sandbox = base.clone();
+        sandbox.journal_file = None;
+        sandbox.storage = None;
+        sandbox.position_snapshots = None;
+        WhatIf {
+            sandbox,
+            booked: Vec::new(),
+            marks: Vec::new(),
+        }
+    }
+
+    pub fn book(&mut self, trade: Trade) -> Result<(), PositionError> {
+        self.sandbox.transaction(|tx| tx.add_trade(trade.clone()))?;
+        self.booked.push(trade);
+        Ok(())
+    }
+
+    pub fn mark(&mut self, instrument: &str, price: Price) -> Result<(), PositionError> {
+        self.sandbox.transaction(|tx| tx.update_price(instrument, price))?;
+        self.marks.push((instrument.to_string(), price));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.booked.is_empty() && self.marks.is_empty()
+    }
+
+    // Repository as it would look with the overlay applied
+    pub fn repository(&self) -> &TradeRepository {
+        &self.sandbox
+    }
+
+    // (instrument, quantity before, quantity after) for instruments the overlay touches
+    pub fn position_changes(&self, base: &TradeRepository) -> Vec<(String, i32, i32)> {
+        let instruments: HashSet<&str> = self.booked.iter().map(|trade| trade.instrument.as_str()).collect();
+        let mut changes: Vec<(String, i32, i32)> = instruments
+            .into_iter()
+            .map(|instrument| {
+                let before = base.get_position(instrument).map_or(0, |position| position.quantity);
+                let after = self.sandbox.get_position(instrument).map_or(0, |position| position.quantity);
+                (instrument.to_string(), before, after)
+            })
+            .collect();
+        changes.sort();
+        changes
+    }
+
+    // Apply the overlay to the base repository in one transaction
+    pub fn commit(self, base: &mut TradeRepository) -> Result<usize, PositionError> {
+        let booked = self.booked.len();
+        base.transaction(|tx| {
+            for trade in self.booked {
+                tx.add_trade(trade)?;
+            }
+            for (instrument, price) in &self.marks {
+                tx.update_price(instrument, *price)?;
+            }
+            Ok(booked)
+        })
+    }
+}
+
+pub const REPL_HELP: &str = "Commands:
+  load <file.csv>                              ingest trades into the repository
+  buy|sell <instrument> <qty> <price> [date]   book a hypothetical trade
+  mark <instrument> <price>                    set a hypothetical market price
+  positions                                    positions with the overlay applied
+  pnl                                          P&L before and after the overlay
+  trades                                       list hypothetical trades
+  commit                                       apply the overlay to the repository
+  discard                                      drop the overlay
+  help, quit";
+
+// Interactive what-if session (`rustopos repl`)
+pub fn run_repl<R: BufRead, W: Write>(mut repo: TradeRepository, input: R, mut out: W) -> std::io::Result<TradeRepository> {
+    let mut overlay = WhatIf::new(&repo);
+    writeln!(out, "rustopos what-if repl, type 'help' for commands")?;
+    write!(out, "> ")?;
+    out.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {},
+            ["help"] => writeln!(out, "{}", REPL_HELP)?,
+            ["quit"] | ["exit"] => break,
+            ["load", path] => {
+                if !overlay.is_empty() {
+                    writeln!(out, "Commit or discard the overlay before loading")?;
+                } else {
+                    match std::fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|csv| repo.ingest_from(&mut CsvSourceAdapter::new(path, &csv, 1000)).map_err(|e| e.to_string())) {
+                        Ok(summary) => writeln!(out, "Loaded {} trades, {} quarantined", summary.ingested, summary.quarantined)?,
+                        Err(e) => writeln!(out, "Load failed: {}", e)?,
+                    }
+                    overlay = WhatIf::new(&repo);
+                }
+            },
+            [side @ ("buy" | "sell"), instrument, quantity, price, rest @ ..] if rest.len() <= 1 => {
+                let side = if *side == "buy" { Side::Buy } else { Side::Sell };
+                let trade_date = match rest.first() {
+                    Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("Invalid date {}", date)),
+                    None => Ok(chrono::Local::now().date_naive()),
+                };
+                let trade = trade_date.and_then(|trade_date| {
+                    let quantity: i32 = quantity.parse().map_err(|_| format!("Invalid quantity {}", quantity))?;
+                    let price = Decimal::parse(price).map_err(|_| format!("Invalid price {}", price))?;
+                    Ok(Trade::new(overlay.repository().next_trade_id(), trade_date, instrument.to_string(), quantity, price, side))
+                });
+                match trade.and_then(|trade| {
+                    let trade_id = trade.trade_id;
+                    overlay.book(trade).map(|_| trade_id).map_err(|e| e.to_string())
+                }) {
+                    Ok(trade_id) => writeln!(out, "Booked hypothetical trade {}", trade_id)?,
+                    Err(e) => writeln!(out, "Error: {}", e)?,
+                }
+            },
+            ["mark", instrument, price] => match Decimal::parse(price).map_err(|_| format!("Invalid price {}", price)).and_then(|price| overlay.mark(instrument, price).map_err(|e| e.to_string())) {
+                Ok(()) => writeln!(out, "Marked {} at {}", instrument, price)?,
+                Err(e) => writeln!(out, "Error: {}", e)?,
+            },
+            ["positions"] => {
+                let sandbox = overlay.repository();
+                for (instrument, position) in &sandbox.positions {
+                    let market_price = sandbox.get_market_price(instrument).unwrap_or(position.average_price);
+                    writeln!(out, "{:<16} {:>10} @ {:>10.2}  unrealized {:>12.2}", instrument, position.quantity, position.average_price, position.unrealized_pnl(market_price))?;
+                }
+                for (instrument, before, after) in overlay.position_changes(&repo) {
+                    writeln!(out, "  {}: {} -> {}", instrument, before, after)?;
+                }
+            },
+            ["pnl"] => {
+                let (realized, unrealized, market_value) = repo.calculate_portfolio_pnl();
+                let (what_if_realized, what_if_unrealized, what_if_market_value) = overlay.repository().calculate_portfolio_pnl();
+                writeln!(out, "{:<14} {:>14} {:>14} {:>14}", "", "Current", "What-if", "Change")?;
+                for (label, current, what_if) in [("Market Value", market_value, what_if_market_value), ("Realized", realized, what_if_realized), ("Unrealized", unrealized, what_if_unrealized)] {
+                    writeln!(out, "{:<14} {:>14.2} {:>14.2} {:>14.2}", label, current, what_if, what_if - current)?;
+                }
+            },
+            ["trades"] => {
+                for trade in &overlay.booked {
+                    writeln!(out, "{} {} {:?} {} {} @ {:.2}", trade.trade_id, trade.trade_date, trade.side, trade.instrument, trade.quantity, trade.price)?;
+                }
+            },
+            ["commit"] => {
+                let pending = std::mem::replace(&mut overlay, WhatIf::new(&repo));
+                match pending.commit(&mut repo) {
+                    Ok(count) => writeln!(out, "Committed {} trades", count)?,
+                    Err(e) => writeln!(out, "Commit failed, repository unchanged: {}", e)?,
+                }
+                overlay = WhatIf::new(&repo);
+            },
+            ["discard"] => {
+                overlay = WhatIf::new(&repo);
+                writeln!(out, "Overlay discarded")?;
+            },
+            _ => writeln!(out, "Unknown command '{}', type 'help'", line.trim())?,
+        }
+        write!(out, "> ")?;
+        out.flush()?;
+    }
+
+    Ok(repo)
+}
+
+// Identifies the same booking in two systems whose trade ids differ: booking
+// details plus the trade's position among identical bookings, ordered by id
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TradeKey {
+    pub trade_date: NaiveDate,
+    pub account_id: String,
+    pub instrument: String,
+    pub side: &'static str,
+    pub occurrence: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeMismatch {
+    pub key: TradeKey,
+    pub trade_id_a: TradeId,
+    pub trade_id_b: TradeId,
+    pub differences: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionDifference {
+    pub instrument: String,
+    pub quantity_a: i32,
+    pub quantity_b: i32,
+    pub average_price_a: Price,
+    pub average_price_b: Price,
+    pub realized_pnl_a: Money,
+    pub realized_pnl_b: Money,
+}
+
+#[derive(Debug, Clone)]
+pub struct RepositoryDiff {
+    pub only_in_a: Vec<Trade>,
+    pub only_in_b: Vec<Trade>,
+    pub mismatches: Vec<TradeMismatch>,
+    pub position_differences: Vec<PositionDifference>,
+}
+
+impl RepositoryDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.mismatches.is_empty() && self.position_differences.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("Repositories match");
+            return;
+        }
+        for trade in &self.only_in_a {
+            println!("Only in A: trade {} {} {:?} {} {} @ {:.2}", trade.trade_id, trade.trade_date, trade.side, trade.quantity, trade.instrument, trade.price);
+        }
+        for trade in &self.only_in_b {
+            println!("Only in B: trade {} {} {:?} {} {} @ {:.2}", trade.trade_id, trade.trade_date, trade.side, trade.quantity, trade.instrument, trade.price);
+        }
+        for mismatch in &self.mismatches {
+            println!("Mismatch {} {} {}: A trade {} vs B trade {}: {}",
+                     mismatch.key.trade_date, mismatch.key.instrument, mismatch.key.account_id,
+                     mismatch.trade_id_a, mismatch.trade_id_b, mismatch.differences.join(", "));
+        }
+        for difference in &self.position_differences {
+            println!("Position {}: A {} @ {:.2} (realized {:.2}) vs B {} @ {:.2} (realized {:.2})",
+                     difference.instrument, difference.quantity_a, difference.average_price_a, difference.realized_pnl_a,
+                     difference.quantity_b, difference.average_price_b, difference.realized_pnl_b);
+        }
+    }
+}
+
+pub fn keyed_active_trades(repo: &TradeRepository) -> BTreeMap<TradeKey, &Trade> {
+    let mut trades: Vec<&Trade> = repo.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)).collect();
+    trades.sort_by_key(|trade| trade.trade_id);
+
+    let mut occurrences: HashMap<(NaiveDate, &str, &str, &'static str), usize> = HashMap::new();
+    let mut keyed = BTreeMap::new();
+    for trade in trades {
+        let side = side_name(&trade.side);
+        let occurrence = occurrences.entry((trade.trade_date, trade.account_id.as_str(), trade.instrument.as_str(), side)).or_insert(0);
+        keyed.insert(TradeKey {
+            trade_date: trade.trade_date,
+            account_id: trade.account_id.clone(),
+            instrument: trade.instrument.clone(),
+            side,
+            occurrence: *occurrence,
+        }, trade);
+        *occurrence += 1;
+    }
+    keyed
+}
+
+// Compare two repositories, e.g. a legacy system and its replacement run in
+// parallel: active trades are paired by TradeKey rather than trade id, paired
+// trades are checked for economic differences, and the resulting positions
+// are compared per instrument
+pub fn diff_repositories(a: &TradeRepository, b: &TradeRepository) -> RepositoryDiff {
+    // Average prices may differ in the last places when the same trades were booked in another order
+    let position_tolerance = Decimal::from(1e-6);
+    let trades_a = keyed_active_trades(a);
+    let trades_b = keyed_active_trades(b);
+
+    let mut diff = RepositoryDiff {
+        only_in_a: trades_a.iter().filter(|(key, _)| !trades_b.contains_key(key)).map(|(_, trade)| (*trade).clone()).collect(),
+        only_in_b: trades_b.iter().filter(|(key, _)| !trades_a.contains_key(key)).map(|(_, trade)| (*trade).clone()).collect(),
+        mismatches: Vec::new(),
+        position_differences: Vec::new(),
+    };
+
+    for (key, trade_a) in &trades_a {
+        let Some(trade_b) = trades_b.get(key) else { continue };
+        let mut differences = Vec::new();
+        if trade_a.quantity != trade_b.quantity {
+            differences.push(format!("quantity {} vs {}", trade_a.quantity, trade_b.quantity));
+        }
+        if trade_a.price != trade_b.price {
+            differences.push(format!("price {} vs {}", trade_a.price, trade_b.price));
+        }
+        if trade_a.commission != trade_b.commission {
+            differences.push(format!("commission {} vs {}", trade_a.commission, trade_b.commission));
+        }
+        if !differences.is_empty() {
+            diff.mismatches.push(TradeMismatch {
+                key: key.clone(),
+                trade_id_a: trade_a.trade_id,
+                trade_id_b: trade_b.trade_id,
+                differences,
+            });
+        }
+    }
+
+    let instruments: BTreeSet<&String> = a.positions.keys().chain(b.positions.keys()).collect();
+    for instrument in instruments {
+        let empty = TradePosition::new(instrument.clone());
+        let position_a = a.get_position(instrument).unwrap_or(&empty);
+        let position_b = b.get_position(instrument).unwrap_or(&empty);
+        if position_a.quantity != position_b.quantity
+            || (position_a.average_price - position_b.average_price).abs() > position_tolerance
+            || (position_a.realized_pnl - position_b.realized_pnl).abs() > position_tolerance
+        {
+            diff.position_differences.push(PositionDifference {
+                instrument: instrument.clone(),
+                quantity_a: position_a.quantity,
+                quantity_b: position_b.quantity,
+                average_price_a: position_a.average_price,
+                average_price_b: position_b.average_price,
+                realized_pnl_a: position_a.realized_pnl,
+                realized_pnl_b: position_b.realized_pnl,
+            });
+        }
+    }
+
+    diff
+}
+
+// What an API key may do. Each role includes everything the roles before it may do.
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    // Positions and trades
+    Viewer,
+    // Booking, amending and cancelling trades
+    Trader,
+    // Managing API keys and reading the request log
+    Admin,
+}
+
+impl Role {
+    pub fn rank(self) -> u8 {
+        match self {
+            Role::Viewer => 0,
+            Role::Trader => 1,
+            Role::Admin => 2,
+        }
+    }
+
+    pub fn includes(self, required: Role) -> bool {
+        self.rank() >= required.rank()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Trader => "trader",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// An API key as the service keeps it. The secret is only stored hashed; the full
+// token is shown once, when the key is created or rotated.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub key_id: String,
+    pub name: String,
+    pub role: Role,
+    // Accounts the key may see and trade; None for every account
+    pub accounts: Option<BTreeSet<String>>,
+    pub secret_hash: u64,
+    // Secret replaced by the last rotation, still accepted until the given time
+    pub previous_secret: Option<(u64, DateTime<Utc>)>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn covers_account(&self, account_id: &str) -> bool {
+        self.accounts.as_ref().is_none_or(|accounts| accounts.contains(account_id))
+    }
+}
+
+// API keys of a service. Tokens are "<key id>.<secret>"; secrets are random and
+// hashed with a key private to the store, so they never leave it in clear.
+#[derive(Debug, Clone)]
+pub struct ApiKeyStore {
+    pub keys: BTreeMap<String, ApiKey>,
+    pub hasher: RandomState,
+    pub next_key: u32,
+    // How long the old secret keeps working after a rotation
+    pub rotation_grace: chrono::Duration,
+}
+
+impl ApiKeyStore {
+    pub fn new() -> ApiKeyStore {
+        ApiKeyStore {
+            keys: BTreeMap::new(),
+            hasher: RandomState::new(),
+            next_key: 1,
+            rotation_grace: chrono::Duration::zero(),
+        }
+    }
+
+    pub fn with_rotation_grace(mut self, grace: chrono::Duration) -> Self {
+        self.rotation_grace = grace;
+        self
+    }
+
+    pub fn hash_secret(&self, secret: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(secret.as_bytes());
+        hasher.finish()
+    }
+
+    // 128 random bits from two freshly seeded hashers
+    pub fn new_secret(&self) -> String {
+        format!("{:016x}{:016x}", random_u64(), random_u64())
+    }
+
+    pub fn create(&mut self, name: &str, role: Role, accounts: Option<BTreeSet<String>>, expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> (String, String) {
+        let key_id = format!("key-{:04}", self.next_key);
+        self.next_key += 1;
+        let secret = self.new_secret();
+        self.keys.insert(key_id.clone(), ApiKey {
+            key_id: key_id.clone(),
+            name: name.to_string(),
+            role,
+            accounts,
+            secret_hash: self.hash_secret(&secret),
+            previous_secret: None,
+            created_at: now,
+            expires_at,
+            rotated_at: None,
+            revoked_at: None,
+        });
+        let token = format!("{}.{}", key_id, secret);
+        (key_id, token)
+    }
+
+    // Issue a new secret for the key; the old one works for the rotation grace period
+    pub fn rotate(&mut self, key_id: &str, now: DateTime<Utc>) -> Result<String, PositionError> {
+        let secret = self.new_secret();
+        let secret_hash = self.hash_secret(&secret);
+        let grace = self.rotation_grace;
+        let key = self.keys.get_mut(key_id)
+            .filter(|key| key.revoked_at.is_none())
+            .ok_or_else(|| PositionError::InvalidRecord(format!("no active API key {}", key_id)))?;
+        key.previous_secret = Some((key.secret_hash, now + grace));
+        key.secret_hash = secret_hash;
+        key.rotated_at = Some(now);
+        Ok(format!("{}.{}", key_id, secret))
+    }
+
+    pub fn revoke(&mut self, key_id: &str, now: DateTime<Utc>) -> Result<(), PositionError> {
+        let key = self.keys.get_mut(key_id)
+            .filter(|key| key.revoked_at.is_none())
+            .ok_or_else(|| PositionError::InvalidRecord(format!("no active API key {}", key_id)))?;
+        key.revoked_at = Some(now);
+        key.previous_secret = None;
+        Ok(())
+    }
+
+    pub fn authenticate(&self, token: &str, now: DateTime<Utc>) -> Result<&ApiKey, PositionError> {
+        let (key_id, secret) = token.split_once('.')
+            .ok_or_else(|| PositionError::Unauthenticated("malformed API key".to_string()))?;
+        let key = self.keys.get(key_id)
+            .ok_or_else(|| PositionError::Unauthenticated(format!("unknown API key {}", key_id)))?;
+        if key.revoked_at.is_some() {
+            return Err(PositionError::Unauthenticated(format!("API key {} is revoked", key_id)));
+        }
+        if key.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(PositionError::Unauthenticated(format!("API key {} has expired", key_id)));
+        }
+        let hash = self.hash_secret(secret);
+        let previous_valid = key.previous_secret.is_some_and(|(previous, until)| previous == hash && now < until);
+        if hash != key.secret_hash && !previous_valid {
+            return Err(PositionError::Unauthenticated(format!("wrong secret for API key {}", key_id)));
+        }
+        Ok(key)
+    }
+
+    pub fn print_keys(&self, now: DateTime<Utc>) {
+        println!("{:<10} {:<14} {:<8} {:<17} State", "Key", "Name", "Role", "Created");
+        for key in self.keys.values() {
+            let state = match (key.revoked_at, key.expires_at, key.rotated_at) {
+                (Some(revoked_at), _, _) => format!("revoked {}", revoked_at.format("%Y-%m-%d %H:%M")),
+                (None, Some(expires_at), _) if now >= expires_at => format!("expired {}", expires_at.format("%Y-%m-%d %H:%M")),
+                (None, _, Some(rotated_at)) => format!("rotated {}", rotated_at.format("%Y-%m-%d %H:%M")),
+                _ => "active".to_string(),
+            };
+            println!("{:<10} {:<14} {:<8} {:<17} {}", key.key_id, key.name, key.role.name(), key.created_at.format("%Y-%m-%d %H:%M"), state);
+        }
+    }
+}
+
+impl Default for ApiKeyStore {
+    fn default() -> ApiKeyStore {
+        ApiKeyStore::new()
+    }
+}
+
+// Settings the service can change while running, read from a JSON file such as
+// {"sanity_bands": {"AAPL": {"max_price_deviation": 0.1, "max_adv_fraction": 0.25, "action": "block"}},
+//  "fee_schedules": {"*": [{"type": "commission", "per_share": 0.005}, {"type": "tax", "bps": 10, "side": "sell"}]},
+//  "drawdown_alerts": {"thresholds": [0.05, 0.1], "cooldown_days": 5},
+//  "pnl_anomalies": {"lookback_days": 20, "threshold_sigmas": 3, "min_history": 5},
+//  "reports": [<report definition>, ...]}
+// "*" is the default for instruments without an entry of their own; a section left
+// out is empty.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceConfig {
+    pub sanity_bands: BTreeMap<String, SanityBand>,
+    pub fee_schedules: BTreeMap<String, FeeSchedule>,
+    pub drawdown_alerts: Option<DrawdownAlertConfig>,
+    pub pnl_anomalies: Option<PnlAnomalyConfig>,
+    pub reports: BTreeMap<String, ReportDefinition>,
+}
+
+impl ServiceConfig {
+    pub fn from_json(text: &str) -> Result<ServiceConfig, String> {
+        let value = parse_json(text)?;
+        let section = |key: &str| match value.get(key) {
+            Some(JsonValue::Object(entries)) => Ok(entries.clone()),
+            None | Some(JsonValue::Null) => Ok(BTreeMap::new()),
+            Some(_) => Err(format!("'{}' must be an object", key)),
+        };
+        let number = |value: &JsonValue, key: &str, context: &str| value.get(key).and_then(JsonValue::as_f64).ok_or(format!("{} needs a number '{}'", context, key));
+        let count = |value: &JsonValue, key: &str, context: &str| value.get(key).and_then(JsonValue::as_i64).filter(|count| *count >= 0).ok_or(format!("{} needs a count '{}'", context, key));
+        let mut config = ServiceConfig::default();
+
+        for (instrument, band) in section("sanity_bands")? {
+            let context = format!("Sanity band {}", instrument);
+            let action = match band.get("action").and_then(JsonValue::as_str) {
+                None | Some("flag") => SanityAction::Flag,
+                Some("block") => SanityAction::Block,
+                Some(other) => return Err(format!("{} has unknown action {}", context, other)),
+            };
+            config.sanity_bands.insert(instrument, SanityBand {
+                max_price_deviation: number(&band, "max_price_deviation", &context)?,
+                max_adv_fraction: number(&band, "max_adv_fraction", &context)?,
+                action,
+            });
+        }
+        for (instrument, rules) in section("fee_schedules")? {
+            let context = format!("Fee schedule {}", instrument);
+            let JsonValue::Array(rules) = rules else {
+                return Err(format!("{} must be an array of rules", context));
+            };
+            let mut schedule = FeeSchedule::new();
+            for rule in rules {
+                let fee_type = match rule.get("type").and_then(JsonValue::as_str) {
+                    Some("commission") => FeeType::Commission,
+                    Some("exchange_fee") => FeeType::ExchangeFee,
+                    Some("tax") => FeeType::Tax,
+                    other => return Err(format!("{} has unknown fee type {:?}", context, other)),
+                };
+                let basis = match (rule.get("per_share"), rule.get("per_trade"), rule.get("bps")) {
+                    (Some(rate), None, None) => FeeBasis::PerShare(Decimal::from_f64(rate.as_f64().ok_or(format!("{} has a non-numeric rate", context))?)?),
+                    (None, Some(amount), None) => FeeBasis::PerTrade(Decimal::from_f64(amount.as_f64().ok_or(format!("{} has a non-numeric amount", context))?)?),
+                    (None, None, Some(bps)) => FeeBasis::Bps(bps.as_f64().ok_or(format!("{} has non-numeric bps", context))?),
+                    _ => return Err(format!("{} rules need exactly one of per_share, per_trade or bps", context)),
+                };
+                let side = match rule.get("side").and_then(JsonValue::as_str) {
+                    None => None,
+                    Some("buy") => Some(Side::Buy),
+                    Some("sell") => Some(Side::Sell),
+                    Some(other) => return Err(format!("{} has unknown side {}", context, other)),
+                };
+                schedule = schedule.rule(fee_type, basis, side);
+            }
+            config.fee_schedules.insert(instrument, schedule);
+        }
+        if let Some(alerts) = value.get("drawdown_alerts").filter(|alerts| !alerts.is_null()) {
+            let Some(JsonValue::Array(thresholds)) = alerts.get("thresholds") else {
+                return Err("Drawdown alerts need an array of thresholds".to_string());
+            };
+            config.drawdown_alerts = Some(DrawdownAlertConfig {
+                thresholds: thresholds.iter().map(|threshold| threshold.as_f64().ok_or("Drawdown thresholds must be numbers".to_string())).collect::<Result<Vec<f64>, String>>()?,
+                cooldown_days: count(alerts, "cooldown_days", "Drawdown alerts")?,
+            });
+        }
+        if let Some(anomalies) = value.get("pnl_anomalies").filter(|anomalies| !anomalies.is_null()) {
+            config.pnl_anomalies = Some(PnlAnomalyConfig {
+                lookback_days: count(anomalies, "lookback_days", "P&L anomalies")? as usize,
+                threshold_sigmas: number(anomalies, "threshold_sigmas", "P&L anomalies")?,
+                min_history: count(anomalies, "min_history", "P&L anomalies")? as usize,
+            });
+        }
+        match value.get("reports") {
+            Some(JsonValue::Array(reports)) => {
+                for report in reports {
+                    let definition = ReportDefinition::from_json_value(report)?;
+                    config.reports.insert(definition.name.clone(), definition);
+                }
+            },
+            None | Some(JsonValue::Null) => {},
+            Some(_) => return Err("'reports' must be an array".to_string()),
+        }
+        Ok(config)
+    }
+}
+
+// Entries added, removed or changed between two config sections
+pub fn config_differences<V: std::fmt::Debug>(section: &str, before: &BTreeMap<String, V>, after: &BTreeMap<String, V>, changes: &mut Vec<String>) {
+    for (key, value) in after {
+        match before.get(key) {
+            None => changes.push(format!("{} {} added", section, key)),
+            Some(previous) if format!("{:?}", previous) != format!("{:?}", value) => changes.push(format!("{} {} changed", section, key)),
+            Some(_) => {},
+        }
+    }
+    for key in before.keys().filter(|key| !after.contains_key(*key)) {
+        changes.push(format!("{} {} removed", section, key));
+    }
+}
+
+// Audit entry for a config reload, whether it was applied or refused
+#[derive(Debug, Clone)]
+pub struct ConfigChange {
+    pub sequence: u64,
+    pub recorded_at: DateTime<Utc>,
+    // Key that asked for the reload, or the watcher
+    pub actor: String,
+    // e.g. "sanity band AAPL changed"; empty when nothing differed
+    pub changes: Vec<String>,
+    // None when the config was applied
+    pub error: Option<String>,
+}
+
+// Operations the service accepts
+#[derive(Debug, Clone)]
+pub enum ServiceRequest {
+    // Boxed: a trade is several times the size of every other request
+    AddTrade(Box<Trade>),
+    AmendTrade { trade_id: TradeId, quantity: i32, price: Price },
+    CancelTrade(TradeId),
+    // An account's position, or the firm-wide one when no account is given
+    Position { instrument: String, account_id: Option<String> },
+    // Every open position, and realized and unrealized P&L, of an account or the firm
+    Positions { account_id: Option<String> },
+    Pnl { account_id: Option<String> },
+    Trades(TradeFilter),
+    CreateKey { name: String, role: Role, accounts: Option<BTreeSet<String>>, expires_at: Option<DateTime<Utc>> },
+    RotateKey(String),
+    RevokeKey(String),
+    RequestLog,
+    // Re-read the watched config file
+    ReloadConfig,
+    // A report from the config, firm-wide
+    Report { name: String, as_of_date: NaiveDate },
+}
+
+impl ServiceRequest {
+    pub fn operation(&self) -> &'static str {
+        match self {
+            ServiceRequest::AddTrade(_) => "add_trade",
+            ServiceRequest::AmendTrade { .. } => "amend_trade",
+            ServiceRequest::CancelTrade(_) => "cancel_trade",
+            ServiceRequest::Position { .. } => "position",
+            ServiceRequest::Positions { .. } => "positions",
+            ServiceRequest::Pnl { .. } => "pnl",
+            ServiceRequest::Trades(_) => "trades",
+            ServiceRequest::CreateKey { .. } => "create_key",
+            ServiceRequest::RotateKey(_) => "rotate_key",
+            ServiceRequest::RevokeKey(_) => "revoke_key",
+            ServiceRequest::RequestLog => "request_log",
+            ServiceRequest::ReloadConfig => "reload_config",
+            ServiceRequest::Report { .. } => "report",
+        }
+    }
+
+    pub fn required_role(&self) -> Role {
+        match self {
+            ServiceRequest::Position { .. } | ServiceRequest::Positions { .. } | ServiceRequest::Pnl { .. } | ServiceRequest::Trades(_)
+                | ServiceRequest::Report { .. } => Role::Viewer,
+            ServiceRequest::AddTrade(_) | ServiceRequest::AmendTrade { .. } | ServiceRequest::CancelTrade(_) => Role::Trader,
+            ServiceRequest::CreateKey { .. } | ServiceRequest::RotateKey(_) | ServiceRequest::RevokeKey(_) | ServiceRequest::RequestLog
+                | ServiceRequest::ReloadConfig => Role::Admin,
+        }
+    }
+
+    // What the request acts on, for the request log
+    pub fn target(&self) -> String {
+        match self {
+            ServiceRequest::AddTrade(trade) => format!("trade {}", trade.trade_id),
+            ServiceRequest::AmendTrade { trade_id, .. } | ServiceRequest::CancelTrade(trade_id) => format!("trade {}", trade_id),
+            ServiceRequest::Position { instrument, account_id } => match account_id {
+                Some(account_id) => format!("{} in {}", instrument, account_id),
+                None => instrument.clone(),
+            },
+            ServiceRequest::Positions { account_id } | ServiceRequest::Pnl { account_id } => account_id.clone().unwrap_or_else(|| "firm".to_string()),
+            ServiceRequest::Trades(filter) => filter.instrument.clone().unwrap_or_else(|| "all trades".to_string()),
+            ServiceRequest::CreateKey { name, role, .. } => format!("{} ({})", name, role.name()),
+            ServiceRequest::RotateKey(key_id) | ServiceRequest::RevokeKey(key_id) => key_id.clone(),
+            ServiceRequest::RequestLog | ServiceRequest::ReloadConfig => String::new(),
+            ServiceRequest::Report { name, .. } => name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ServiceResponse {
+    Done,
+    Position(Option<TradePosition>),
+    Positions(Vec<TradePosition>),
+    Pnl { realized: Money, unrealized: Money, market_value: Money },
+    Trades(Vec<Trade>),
+    // A new or rotated key; the token is not shown again
+    Key { key_id: String, token: String },
+    RequestLog(Vec<RequestRecord>),
+    ConfigReloaded(ConfigChange),
+    Report(CustomReport),
+}
+
+// One request as the service received it, whether or not it was allowed
+#[derive(Debug, Clone)]
+pub struct RequestRecord {
+    pub sequence: u64,
+    pub received_at: DateTime<Utc>,
+    // None when the token did not name a known key
+    pub key_id: Option<String>,
+    pub operation: &'static str,
+    pub target: String,
+    // None when the request succeeded
+    pub error: Option<String>,
+}
+
+// Service layer in front of a repository: every request carries an API key, is
+// checked against the key's role and accounts, and is recorded in the request log
+#[derive(Debug, Clone)]
+pub struct TradeService {
+    pub repository: TradeRepository,
+    pub keys: ApiKeyStore,
+    pub request_log: Vec<RequestRecord>,
+    // Running config, the file it is reloaded from and the text last read from it
+    pub config: ServiceConfig,
+    pub config_path: Option<std::path::PathBuf>,
+    pub config_text: Option<String>,
+    pub config_log: Vec<ConfigChange>,
+}
+
+impl TradeService {
+    pub fn new(repository: TradeRepository, keys: ApiKeyStore) -> TradeService {
+        TradeService {
+            repository,
+            keys,
+            request_log: Vec::new(),
+            config: ServiceConfig::default(),
+            config_path: None,
+            config_text: None,
+            config_log: Vec::new(),
+        }
+    }
+
+    // Load the config file now and reload it whenever poll_config finds it changed
+    pub fn watch_config(&mut self, path: impl Into<std::path::PathBuf>, now: DateTime<Utc>) -> Result<ConfigChange, PositionError> {
+        self.config_path = Some(path.into());
+        self.reload_config("config-watcher", now)
+    }
+
+    // Reload if the watched file's contents changed since it was last read
+    pub fn poll_config(&mut self, now: DateTime<Utc>) -> Option<Result<ConfigChange, PositionError>> {
+        let text = std::fs::read_to_string(self.config_path.as_ref()?).ok();
+        if text.is_none() || text == self.config_text {
+            return None;
+        }
+        Some(self.reload_config("config-watcher", now))
+    }
+
+    // Read the config file and apply it to the running service. Trades, positions and
+    // keys are untouched. A config that can't be read or parsed is refused as a
+    // whole and the running one kept; either way the reload is audited.
+    pub fn reload_config(&mut self, actor: &str, now: DateTime<Utc>) -> Result<ConfigChange, PositionError> {
+        let path = self.config_path.clone().ok_or(PositionError::InvalidRecord("No config file is watched".to_string()))?;
+        let loaded = std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| {
+            let config = ServiceConfig::from_json(&text);
+            self.config_text = Some(text);
+            config
+        });
+        let (changes, error) = match loaded {
+            Ok(config) => (self.apply_config(config), None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        let change = ConfigChange {
+            sequence: self.config_log.last().map_or(1, |change| change.sequence + 1),
+            recorded_at: now,
+            actor: actor.to_string(),
+            changes,
+            error,
+        };
+        self.config_log.push(change.clone());
+        match &change.error {
+            Some(e) => Err(PositionError::InvalidRecord(format!("Config {} refused: {}", path.display(), e))),
+            None => Ok(change),
+        }
+    }
+
+    // Settings the running config made are withdrawn before the new ones are set,
+    // so an entry dropped from the file stops applying. Fee schedules only affect
+    // trades booked or amended from now on.
+    pub fn apply_config(&mut self, config: ServiceConfig) -> Vec<String> {
+        let mut changes = Vec::new();
+        config_differences("sanity band", &self.config.sanity_bands, &config.sanity_bands, &mut changes);
+        config_differences("fee schedule", &self.config.fee_schedules, &config.fee_schedules, &mut changes);
+        config_differences("report", &self.config.reports, &config.reports, &mut changes);
+        if format!("{:?}", self.config.drawdown_alerts) != format!("{:?}", config.drawdown_alerts) {
+            changes.push("drawdown alerts changed".to_string());
+        }
+        if format!("{:?}", self.config.pnl_anomalies) != format!("{:?}", config.pnl_anomalies) {
+            changes.push("P&L anomaly rules changed".to_string());
+        }
+
+        let repository = &mut self.repository;
+        for instrument in self.config.sanity_bands.keys() {
+            match instrument.as_str() {
+                "*" => repository.default_sanity_band = None,
+                instrument => {
+                    repository.sanity_bands.remove(instrument);
+                },
+            }
+        }
+        for instrument in self.config.fee_schedules.keys() {
+            match instrument.as_str() {
+                "*" => repository.default_fee_schedule = None,
+                instrument => {
+                    repository.fee_schedules.remove(instrument);
+                },
+            }
+        }
+        for (instrument, band) in &config.sanity_bands {
+            match instrument.as_str() {
+                "*" => repository.default_sanity_band = Some(band.clone()),
+                instrument => repository.set_sanity_band(instrument, band.clone()),
+            }
+        }
+        for (instrument, schedule) in &config.fee_schedules {
+            match instrument.as_str() {
+                "*" => repository.set_default_fee_schedule(schedule.clone()),
+                instrument => repository.set_fee_schedule(instrument, schedule.clone()),
+            }
+        }
+        self.config = config;
+        changes
+    }
+
+    // Key for the operator to start with, created outside the request path
+    pub fn bootstrap_admin_key(&mut self, name: &str, now: DateTime<Utc>) -> String {
+        self.keys.create(name, Role::Admin, None, None, now).1
+    }
+
+    pub fn handle(&mut self, token: &str, request: ServiceRequest, now: DateTime<Utc>) -> Result<ServiceResponse, PositionError> {
+        let key_id = token.split_once('.').map(|(key_id, _)| key_id.to_string()).filter(|key_id| self.keys.keys.contains_key(key_id));
+        let operation = request.operation();
+        let target = request.target();
+        let result = self.authorize_and_run(token, request, now);
+        self.request_log.push(RequestRecord {
+            sequence: self.request_log.last().map_or(1, |record| record.sequence + 1),
+            received_at: now,
+            key_id,
+            operation,
+            target,
+            error: result.as_ref().err().map(|error| error.to_string()),
+        });
+        result
+    }
+
+    pub fn authorize_and_run(&mut self, token: &str, request: ServiceRequest, now: DateTime<Utc>) -> Result<ServiceResponse, PositionError> {
+        let key = self.keys.authenticate(token, now)?.clone();
+        let forbidden = |reason: String| PositionError::Forbidden { key_id: key.key_id.clone(), reason };
+        if !key.role.includes(request.required_role()) {
+            return Err(forbidden(format!("{} with role {}", request.operation(), key.role.name())));
+        }
+        let check_trade_account = |repository: &TradeRepository, trade_id: TradeId| {
+            let trade = repository.trades.get(&trade_id).ok_or(PositionError::TradeNotFound(trade_id))?;
+            if !key.covers_account(&trade.account_id) {
+                return Err(forbidden(format!("use account {}", trade.account_id)));
+            }
+            Ok(())
+        };
+
+        // Trade audit entries name the key that made the change
+        let actor = std::mem::replace(&mut self.repository.actor, key.key_id.clone());
+        let result = match request {
+            ServiceRequest::AddTrade(trade) => {
+                if !key.covers_account(&trade.account_id) {
+                    Err(forbidden(format!("use account {}", trade.account_id)))
+                } else {
+                    // Sanity bands from the config apply to every booking through the service
+                    self.repository.add_trade_checked(*trade).map(|_| ServiceResponse::Done)
+                }
+            },
+            ServiceRequest::AmendTrade { trade_id, quantity, price } => check_trade_account(&self.repository, trade_id)
+                .and_then(|_| self.repository.amend_trade(trade_id, quantity, price))
+                .map(|_| ServiceResponse::Done),
+            ServiceRequest::CancelTrade(trade_id) => check_trade_account(&self.repository, trade_id)
+                .and_then(|_| self.repository.cancel_trade(trade_id))
+                .map(|_| ServiceResponse::Done),
+            ServiceRequest::Position { instrument, account_id } => match account_id {
+                Some(account_id) if key.covers_account(&account_id) => {
+                    Ok(ServiceResponse::Position(self.repository.build_account_positions(&account_id).remove(&instrument)))
+                },
+                Some(account_id) => Err(forbidden(format!("use account {}", account_id))),
+                None if key.accounts.is_none() => Ok(ServiceResponse::Position(self.repository.get_position(&instrument).cloned())),
+                None => Err(forbidden("read firm-wide positions".to_string())),
+            },
+            ServiceRequest::Positions { account_id } => match account_id {
+                Some(account_id) if key.covers_account(&account_id) => {
+                    Ok(ServiceResponse::Positions(self.repository.build_account_positions(&account_id).into_values().filter(|position| position.quantity != 0).collect()))
+                },
+                Some(account_id) => Err(forbidden(format!("use account {}", account_id))),
+                None if key.accounts.is_none() => {
+                    Ok(ServiceResponse::Positions(self.repository.positions.values().filter(|position| position.quantity != 0).cloned().collect()))
+                },
+                None => Err(forbidden("read firm-wide positions".to_string())),
+            },
+            ServiceRequest::Pnl { account_id } => {
+                let pnl = match account_id {
+                    Some(account_id) if key.covers_account(&account_id) => Ok(self.repository.calculate_account_pnl(&account_id)),
+                    Some(account_id) => Err(forbidden(format!("use account {}", account_id))),
+                    None if key.accounts.is_none() => Ok(self.repository.calculate_portfolio_pnl()),
+                    None => Err(forbidden("read firm-wide P&L".to_string())),
+                };
+                pnl.map(|(realized, unrealized, market_value)| ServiceResponse::Pnl { realized, unrealized, market_value })
+            },
+            // Trades of accounts outside the key's scope are left out rather than refused
+            ServiceRequest::Trades(filter) => Ok(ServiceResponse::Trades(
+                self.repository.filter_trades(&filter).into_iter().filter(|trade| key.covers_account(&trade.account_id)).cloned().collect(),
+            )),
+            ServiceRequest::CreateKey { name, role, accounts, expires_at } => {
+                let (key_id, token) = self.keys.create(&name, role, accounts, expires_at, now);
+                Ok(ServiceResponse::Key { key_id, token })
+            },
+            ServiceRequest::RotateKey(key_id) => self.keys.rotate(&key_id, now).map(|token| ServiceResponse::Key { key_id, token }),
+            ServiceRequest::RevokeKey(key_id) => self.keys.revoke(&key_id, now).map(|_| ServiceResponse::Done),
+            ServiceRequest::RequestLog => Ok(ServiceResponse::RequestLog(self.request_log.clone())),
+            ServiceRequest::ReloadConfig => self.reload_config(&key.key_id, now).map(ServiceResponse::ConfigReloaded),
+            ServiceRequest::Report { name, as_of_date } => match self.config.reports.get(&name) {
+                Some(_) if key.accounts.is_some() => Err(forbidden("run firm-wide reports".to_string())),
+                Some(definition) => Ok(ServiceResponse::Report(self.repository.run_report(definition, as_of_date))),
+                None => Err(PositionError::InvalidRecord(format!("No report named {}", name))),
+            },
+        };
+        self.repository.actor = actor;
+        result
+    }
+}
+
+pub fn print_request_log(records: &[RequestRecord]) {
+    println!("{:>4} {:<20} {:<9} {:<13} {:<22} Outcome", "Seq", "Received", "Key", "Operation", "Target");
+    for record in records {
+        println!("{:>4} {:<20} {:<9} {:<13} {:<22} {}",
+                 record.sequence,
+                 record.received_at.format("%Y-%m-%d %H:%M:%S"),
+                 record.key_id.as_deref().unwrap_or("-"),
+                 record.operation,
+                 record.target,
+                 record.error.as_deref().unwrap_or("ok"));
+    }
+}
+
+// HTTP front end for a TradeService (`--features server`), to run the crate as a
+// lightweight position service. Each connection gets its own thread and one
+// request; requests take turns on the shared service. The API key goes in an
+// `Authorization: Bearer <token>` header. Bodies and replies use the JSON of the
+// exports:
+//   POST   /trades        a trade as trade_to_json writes it, with status "new"
+//   PATCH  /trades/{id}   {"quantity": 120, "price": 101.5}
+//   DELETE /trades/{id}
+//   GET    /positions     ?account= for one account
+//   GET    /pnl           ?account= for one account
+//   GET    /trades        ?instrument=&side=buy|sell&from=&to= (dates as YYYY-MM-DD)
+//   GET    /stream        WebSocket of position and P&L updates, ?instrument= and ?account=
+// Errors come back as {"error": "..."}.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: BTreeMap<String, String>,
+    pub token: Option<String>,
+    // Sec-WebSocket-Key of an upgrade request
+    pub websocket_key: Option<String>,
+    pub body: String,
+}
+
+// Largest request body the server reads
+#[cfg(feature = "server")]
+pub const HTTP_MAX_BODY: usize = 1 << 20;
+
+// Longest request line or header the server reads
+#[cfg(feature = "server")]
+pub const HTTP_MAX_LINE: usize = 8 << 10;
+
+#[cfg(feature = "server")]
+pub fn read_http_line(reader: &mut impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    std::io::Read::take(reader.by_ref(), HTTP_MAX_LINE as u64 + 1).read_line(&mut line).map_err(|e| e.to_string())?;
+    if line.len() > HTTP_MAX_LINE {
+        return Err(format!("Line of over {} bytes", HTTP_MAX_LINE));
+    }
+    Ok(line)
+}
+
+#[cfg(feature = "server")]
+impl HttpRequest {
+    pub fn read(reader: &mut impl BufRead) -> Result<HttpRequest, String> {
+        let line = read_http_line(reader)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(format!("Malformed request line {:?}", line.trim_end()));
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (url_decode(name), url_decode(value))
+            })
+            .collect();
+
+        let (mut token, mut websocket_key, mut content_length) = (None, None, 0);
+        loop {
+            let header = read_http_line(reader)?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else { continue };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "authorization" => token = value.trim().strip_prefix("Bearer ").map(|token| token.trim().to_string()),
+                "sec-websocket-key" => websocket_key = Some(value.trim().to_string()),
+                "content-length" => content_length = value.trim().parse().map_err(|_| format!("Invalid Content-Length {}", value.trim()))?,
+                _ => {}
+            }
+        }
+        if content_length > HTTP_MAX_BODY {
+            return Err(format!("Body of {} bytes is over the {} byte limit", content_length, HTTP_MAX_BODY));
+        }
+        let mut body = vec![0; content_length];
+        std::io::Read::read_exact(reader, &mut body).map_err(|e| e.to_string())?;
+        Ok(HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            token,
+            websocket_key,
+            body: String::from_utf8(body).map_err(|_| "Body is not UTF-8".to_string())?,
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+pub fn url_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                },
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[cfg(feature = "server")]
+impl HttpResponse {
+    pub fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse { status, body: format!("{{\"error\":{}}}", json_string(message)) }
+    }
+
+    pub fn from_error(error: &PositionError) -> HttpResponse {
+        let status = match error {
+            PositionError::Unauthenticated(_) => 401,
+            PositionError::Forbidden { .. } => 403,
+            PositionError::TradeNotFound(_) | PositionError::InstrumentNotFound(_) | PositionError::OrderNotFound(_) => 404,
+            PositionError::DuplicateTradeId(_) | PositionError::TradeCancelled(_) | PositionError::InvalidTransition { .. } => 409,
+            _ => 400,
+        };
+        HttpResponse::error(status, &error.to_string())
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Error",
+        };
+        write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               self.status, reason, self.body.len(), self.body)?;
+        writer.flush()
+    }
+}
+
+// Turn an HTTP request into a service request, or the reply refusing it. Bodies are
+// parsed here, before the service is locked.
+#[cfg(feature = "server")]
+pub fn http_service_request(request: &HttpRequest) -> Result<ServiceRequest, HttpResponse> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let account_id = request.query.get("account").cloned();
+    let parsed = match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["trades"]) => parse_json(&request.body).and_then(|value| trade_from_json(&value)).map(|trade| ServiceRequest::AddTrade(Box::new(trade))),
+        ("PATCH", ["trades", id]) => TradeId::parse(id).and_then(|trade_id| {
+            let value = parse_json(&request.body)?;
+            let quantity = value.get("quantity").and_then(JsonValue::as_i64).ok_or("Body needs an integer 'quantity'")?;
+            let quantity = i32::try_from(quantity).map_err(|_| format!("Quantity {} is out of range", quantity))?;
+            let price = value.get("price").and_then(JsonValue::as_f64).ok_or("Body needs a numeric 'price'")?;
+            let price = Decimal::from_f64(price).map_err(|e| format!("Invalid price: {}", e))?;
+            Ok(ServiceRequest::AmendTrade { trade_id, quantity, price })
+        }),
+        ("DELETE", ["trades", id]) => TradeId::parse(id).map(ServiceRequest::CancelTrade),
+        ("GET", ["positions"]) => Ok(ServiceRequest::Positions { account_id }),
+        ("GET", ["pnl"]) => Ok(ServiceRequest::Pnl { account_id }),
+        ("GET", ["trades"]) => http_trade_filter(&request.query).map(ServiceRequest::Trades),
+        ("GET", ["stream"]) => return Err(HttpResponse::error(400, "/stream needs a WebSocket upgrade")),
+        (_, ["trades"] | ["trades", _] | ["positions"] | ["pnl"] | ["stream"]) => return Err(HttpResponse::error(405, &format!("{} is not allowed on {}", request.method, request.path))),
+        _ => return Err(HttpResponse::error(404, &format!("No route for {}", request.path))),
+    };
+    parsed.map_err(|e| HttpResponse::error(400, &e))
+}
+
+// Run a request from http_service_request and render the reply
+#[cfg(feature = "server")]
+pub fn route_http(service: &mut TradeService, token: &str, service_request: ServiceRequest, now: DateTime<Utc>) -> HttpResponse {
+    let created = matches!(service_request, ServiceRequest::AddTrade(_));
+    match service.handle(token, service_request, now) {
+        Ok(ServiceResponse::Positions(positions)) => {
+            let positions: Vec<String> = positions.iter().map(position_to_json).collect();
+            HttpResponse { status: 200, body: format!("[{}]", positions.join(",")) }
+        },
+        Ok(ServiceResponse::Pnl { realized, unrealized, market_value }) => HttpResponse {
+            status: 200,
+            body: format!("{{\"realized_pnl\":\"{}\",\"unrealized_pnl\":\"{}\",\"total_pnl\":\"{}\",\"market_value\":\"{}\"}}", realized, unrealized, realized + unrealized, market_value),
+        },
+        Ok(ServiceResponse::Trades(trades)) => {
+            let trades: Vec<String> = trades.iter().map(trade_to_json).collect();
+            HttpResponse { status: 200, body: format!("[{}]", trades.join(",")) }
+        },
+        Ok(_) => HttpResponse { status: if created { 201 } else { 200 }, body: "{\"status\":\"ok\"}".to_string() },
+        Err(e) => HttpResponse::from_error(&e),
+    }
+}
+
+#[cfg(feature = "server")]
+pub fn http_trade_filter(query: &BTreeMap<String, String>) -> Result<TradeFilter, String> {
+    let date = |name: &str| query.get(name).map(|text| NaiveDate::parse_from_str(text, "%Y-%m-%d").map_err(|_| format!("Invalid {} date {}", name, text))).transpose();
+    let mut filter = TradeFilter::new();
+    if let Some(instrument) = query.get("instrument") {
+        filter = filter.instrument(instrument.clone());
+    }
+    match query.get("side").map(String::as_str) {
+        Some("buy") => filter = filter.side(Side::Buy),
+        Some("sell") => filter = filter.side(Side::Sell),
+        Some(other) => return Err(format!("Invalid side {}", other)),
+        None => {}
+    }
+    // Either end of the range may be left open
+    filter.date_from = date("from")?;
+    filter.date_to = date("to")?;
+    Ok(filter)
+}
+
+// Connections served at once, each on its own thread; more are turned away with a 503
+#[cfg(feature = "server")]
+pub const MAX_CONNECTIONS: usize = 64;
+
+// Accepts connections until shut down; see HttpRequest for the routes
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub struct PositionServer {
+    pub address: std::net::SocketAddr,
+    pub token: CancellationToken,
+    pub worker: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "server")]
+impl PositionServer {
+    // Listen on the address, e.g. "127.0.0.1:8080", or port 0 for any free port
+    pub fn start(address: &str, service: Arc<Mutex<TradeService>>) -> Result<PositionServer, PositionError> {
+        let listener = std::net::TcpListener::bind(address).map_err(|e| PositionError::Source(format!("Cannot listen on {}: {}", address, e)))?;
+        let address = listener.local_addr().map_err(|e| PositionError::Source(e.to_string()))?;
+        // Polled, so a shutdown is seen without waiting for another connection
+        listener.set_nonblocking(true).map_err(|e| PositionError::Source(e.to_string()))?;
+        let token = CancellationToken::new();
+        let active = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let worker = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                while !token.is_cancelled() {
+                    match listener.accept() {
+                        // Only this thread adds connections, so the count cannot race past the limit.
+                        // What the client already sent is read first, or closing would reset the
+                        // connection before it sees the 503.
+                        Ok((mut stream, _)) if active.load(Ordering::Acquire) >= MAX_CONNECTIONS => {
+                            let _ = stream.set_nonblocking(false);
+                            let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+                            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                            let _ = std::io::Read::read(&mut stream, &mut [0; 8192]);
+                            let _ = HttpResponse::error(503, "Too many connections").write_to(&mut stream);
+                            let _ = stream.shutdown(std::net::Shutdown::Write);
+                        },
+                        Ok((stream, _)) => {
+                            let slot = ConnectionSlot::take(&active);
+                            let (service, token) = (Arc::clone(&service), token.clone());
+                            std::thread::spawn(move || {
+                                serve_connection(stream, &service, &token);
+                                drop(slot);
+                            });
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(10)),
+                        Err(_) => break,
+                    }
+                }
+            })
+        };
+        Ok(PositionServer { address, token, worker: Some(worker) })
+    }
+
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.address
+    }
+
+    pub fn shutdown(&mut self) {
+        self.token.cancel();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for PositionServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// One of the server's MAX_CONNECTIONS, given back when its thread ends, even by a panic
+#[cfg(feature = "server")]
+pub struct ConnectionSlot(Arc<std::sync::atomic::AtomicUsize>);
+
+#[cfg(feature = "server")]
+impl ConnectionSlot {
+    pub fn take(active: &Arc<std::sync::atomic::AtomicUsize>) -> ConnectionSlot {
+        active.fetch_add(1, Ordering::AcqRel);
+        ConnectionSlot(Arc::clone(active))
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(feature = "server")]
+pub fn serve_connection(stream: std::net::TcpStream, service: &Mutex<TradeService>, shutdown: &CancellationToken) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let Ok(mut writer) = stream.try_clone() else { return };
+    let response = match HttpRequest::read(&mut std::io::BufReader::new(stream)) {
+        Ok(request) if request.path == "/stream" && request.websocket_key.is_some() => {
+            let _ = stream_updates(&mut writer, service, &request, shutdown);
+            return;
+        },
+        // A handler that panicked may have left the repository half-updated, so
+        // nothing is served from it after that
+        Ok(request) => match http_service_request(&request) {
+            Ok(service_request) => match service.lock() {
+                Ok(mut service) => route_http(&mut service, request.token.as_deref().unwrap_or_default(), service_request, Utc::now()),
+                Err(_) => HttpResponse::error(500, "Service stopped after an internal error"),
+            },
+            Err(response) => response,
+        },
+        Err(e) => HttpResponse::error(400, &e),
+    };
+    let _ = response.write_to(&mut writer);
+}
+
+// Upgrade to a WebSocket and send each PositionUpdate as a JSON text frame until the
+// client goes away or the server shuts down. Browsers cannot set headers on a
+// WebSocket, so the key may also come as ?token=.
+#[cfg(feature = "server")]
+pub fn stream_updates(writer: &mut std::net::TcpStream, service: &Mutex<TradeService>, request: &HttpRequest, shutdown: &CancellationToken) -> std::io::Result<()> {
+    let account_id = request.query.get("account").cloned();
+    let token = request.token.as_deref().or(request.query.get("token").map(String::as_str)).unwrap_or_default();
+    let updates = {
+        let Ok(mut service) = service.lock() else {
+            return HttpResponse::error(500, "Service stopped after an internal error").write_to(writer);
+        };
+        // A stream shows what a positions query would, so it needs the same access
+        if let Err(e) = service.handle(token, ServiceRequest::Positions { account_id: account_id.clone() }, Utc::now()) {
+            return HttpResponse::from_error(&e).write_to(writer);
+        }
+        let mut filter = StreamFilter::new();
+        if let Some(instrument) = request.query.get("instrument") {
+            filter = filter.instrument(instrument);
+        }
+        if let Some(account_id) = &account_id {
+            filter = filter.account(account_id);
+        }
+        service.repository.subscribe_positions(filter)
+    };
+    let key = request.websocket_key.as_deref().unwrap_or_default();
+    write!(writer, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", websocket_accept(key))?;
+    writer.flush()?;
+    // The socket is checked between updates for a close frame or a dropped
+    // connection, so a client that leaves gives its connection slot back at once
+    writer.set_read_timeout(Some(Duration::from_millis(1)))?;
+    let (mut received, mut last_sent) = (Vec::new(), Instant::now());
+    while !shutdown.is_cancelled() {
+        match updates.recv_timeout(Duration::from_millis(100)) {
+            Ok(update) => {
+                for update in std::iter::once(update).chain(updates.try_iter()) {
+                    writer.write_all(&websocket_frame(0x1, position_update_to_json(&update).as_bytes()))?;
+                }
+                last_sent = Instant::now();
+            },
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {},
+            // Ended by the repository, e.g. for falling too far behind
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if last_sent.elapsed() >= WEBSOCKET_PING_INTERVAL {
+            writer.write_all(&websocket_frame(0x9, &[]))?;
+            last_sent = Instant::now();
+        }
+        let mut chunk = [0; 512];
+        match std::io::Read::read(writer, &mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read) => received.extend_from_slice(&chunk[..read]),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        }
+        while let Some((opcode, payload)) = take_websocket_frame(&mut received) {
+            match opcode {
+                0x8 => return writer.write_all(&websocket_frame(0x8, &[])),
+                0x9 => writer.write_all(&websocket_frame(0xA, &payload))?,
+                _ => {}
             }
         }
-        
-        println!("\n--- Portfolio Summary ---");
-        println!("Total Market Value: ${:.2}", total_market_value);
-        println!("Total Realized P&L: ${:.2}", total_realized_pnl);
-        println!("Total Unrealized P&L: ${:.2}", total_unrealized_pnl);
-        println!("Total P&L: ${:.2}", total_realized_pnl + total_unrealized_pnl);
+        if received.len() > WEBSOCKET_MAX_CLIENT_FRAME {
+            break;
+        }
+    }
+    writer.write_all(&websocket_frame(0x8, &[]))
+}
+
+// Idle time after which a stream pings its client, so one that vanished without
+// closing the connection shows up as a failed write
+#[cfg(feature = "server")]
+pub const WEBSOCKET_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// Clients of a stream only send control frames; anything longer ends the stream
+#[cfg(feature = "server")]
+pub const WEBSOCKET_MAX_CLIENT_FRAME: usize = 4 << 10;
+
+// Take the first complete frame off what a client sent, as its opcode and unmasked
+// payload (RFC 6455 section 5.2)
+#[cfg(feature = "server")]
+pub fn take_websocket_frame(buffer: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    let (opcode, second) = (buffer.first()? & 0x0f, *buffer.get(1)?);
+    let (length, mut offset) = match second & 0x7f {
+        126 => (u16::from_be_bytes(buffer.get(2..4)?.try_into().ok()?) as usize, 4),
+        127 => (usize::try_from(u64::from_be_bytes(buffer.get(2..10)?.try_into().ok()?)).ok()?, 10),
+        length => (length as usize, 2),
+    };
+    let mut mask = [0; 4];
+    if second & 0x80 != 0 {
+        mask.copy_from_slice(buffer.get(offset..offset + 4)?);
+        offset += 4;
+    }
+    let end = offset.checked_add(length)?;
+    let payload = buffer.get(offset..end)?.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    buffer.drain(..end);
+    Some((opcode, payload))
+}
+
+#[cfg(feature = "server")]
+pub fn position_update_to_json(update: &PositionUpdate) -> String {
+    let account = |account_id: &Option<String>| account_id.as_deref().map_or("null".to_string(), json_string);
+    match update {
+        PositionUpdate::Position { account_id, instrument, previous_quantity, quantity, average_price } => format!(
+            "{{\"type\":\"position\",\"account_id\":{},\"instrument\":{},\"previous_quantity\":{},\"quantity\":{},\"average_price\":\"{}\"}}",
+            account(account_id), json_string(instrument), previous_quantity, quantity, average_price,
+        ),
+        PositionUpdate::Pnl { account_id, instrument, price, realized_pnl, unrealized_pnl } => format!(
+            "{{\"type\":\"pnl\",\"account_id\":{},\"instrument\":{},\"price\":\"{}\",\"realized_pnl\":\"{}\",\"unrealized_pnl\":\"{}\"}}",
+            account(account_id), json_string(instrument), price, realized_pnl, unrealized_pnl,
+        ),
+    }
+}
+
+// Unmasked, unfragmented frame as a server sends it (RFC 6455 section 5.2)
+#[cfg(feature = "server")]
+pub fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(feature = "server")]
+pub fn websocket_accept(key: &str) -> String {
+    base64_encode(&sha1(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes()))
+}
+
+// Only the handshake hashes with it; not for anything that needs to resist collisions
+#[cfg(feature = "server")]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, bytes) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, next);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(feature = "server")]
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            encoded.push(if i <= chunk.len() { ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char } else { '=' });
+        }
+    }
+    encoded
+}
+
+// gRPC front end for a TradeService (`--features grpc`, with tonic 0.12, prost 0.13,
+// tokio and tokio-stream), generated from proto/position_service.proto by build.rs.
+// Requests go through TradeService::handle like HTTP ones, so keys, roles and the
+// request log work the same way.
+#[cfg(feature = "grpc")]
+pub mod grpc {
+    tonic::include_proto!("rustopos.v1");
+}
+
+// How often a subscription's forwarding thread looks for a client that has gone
+#[cfg(feature = "grpc")]
+pub const GRPC_STREAM_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[cfg(feature = "grpc")]
+#[derive(Debug, Clone)]
+pub struct GrpcPositionService {
+    pub service: Arc<Mutex<TradeService>>,
+}
+
+#[cfg(feature = "grpc")]
+impl GrpcPositionService {
+    // tonic::Status is what the generated handlers return, so it is passed on unboxed
+    #[allow(clippy::result_large_err)]
+    pub fn handle<T>(&self, request: &tonic::Request<T>, service_request: ServiceRequest) -> Result<ServiceResponse, tonic::Status> {
+        self.lock()?.handle(&grpc_token(request), service_request, Utc::now()).map_err(|e| grpc_status(&e))
+    }
+
+    // A handler that panicked may have left the repository half-updated, so nothing
+    // is served from it after that
+    #[allow(clippy::result_large_err)]
+    pub fn lock(&self) -> Result<std::sync::MutexGuard<'_, TradeService>, tonic::Status> {
+        self.service.lock().map_err(|_| tonic::Status::internal("Service stopped after an internal error"))
+    }
+}
+
+#[cfg(feature = "grpc")]
+pub fn grpc_token<T>(request: &tonic::Request<T>) -> String {
+    request.metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+// Same split as HttpResponse::from_error
+#[cfg(feature = "grpc")]
+pub fn grpc_status(error: &PositionError) -> tonic::Status {
+    let message = error.to_string();
+    match error {
+        PositionError::Unauthenticated(_) => tonic::Status::unauthenticated(message),
+        PositionError::Forbidden { .. } => tonic::Status::permission_denied(message),
+        PositionError::TradeNotFound(_) | PositionError::InstrumentNotFound(_) | PositionError::OrderNotFound(_) => tonic::Status::not_found(message),
+        PositionError::DuplicateTradeId(_) => tonic::Status::already_exists(message),
+        PositionError::TradeCancelled(_) | PositionError::InvalidTransition { .. } => tonic::Status::failed_precondition(message),
+        _ => tonic::Status::invalid_argument(message),
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)]
+pub fn trade_from_grpc(message: grpc::Trade) -> Result<Trade, tonic::Status> {
+    let side = match grpc::Side::try_from(message.side) {
+        Ok(grpc::Side::Buy) => Side::Buy,
+        Ok(grpc::Side::Sell) => Side::Sell,
+        _ => return Err(tonic::Status::invalid_argument(format!("Trade {} has no side", message.trade_id))),
+    };
+    let trade_date = NaiveDate::parse_from_str(&message.trade_date, "%Y-%m-%d").map_err(|_| tonic::Status::invalid_argument(format!("Invalid trade date {}", message.trade_date)))?;
+    let price = Decimal::parse(&message.price).map_err(tonic::Status::invalid_argument)?;
+    let mut trade = Trade::new(TradeId::parse(&message.trade_id).map_err(tonic::Status::invalid_argument)?, trade_date, message.instrument, message.quantity, price, side);
+    if let Some(account_id) = &message.account_id {
+        trade = trade.with_account(account_id);
+    }
+    if let Some(strategy) = &message.strategy {
+        trade = trade.with_strategy(strategy);
+    }
+    if let Some(broker) = &message.broker {
+        let commission = message.commission.as_deref().map(Decimal::parse).transpose().map_err(tonic::Status::invalid_argument)?;
+        trade = trade.with_broker(broker, commission.unwrap_or(Decimal::ZERO));
+    }
+    Ok(trade)
+}
+
+#[cfg(feature = "grpc")]
+pub fn position_to_grpc(position: &TradePosition) -> grpc::Position {
+    grpc::Position {
+        instrument: position.instrument.clone(),
+        quantity: position.quantity,
+        average_price: position.average_price.to_string(),
+        realized_pnl: position.realized_pnl.to_string(),
+        total_cost: position.total_cost.to_string(),
+    }
+}
+
+#[cfg(feature = "grpc")]
+pub fn position_update_to_grpc(update: PositionUpdate) -> grpc::PositionUpdate {
+    match update {
+        PositionUpdate::Position { account_id, instrument, previous_quantity, quantity, average_price } => grpc::PositionUpdate {
+            account_id,
+            update: Some(grpc::position_update::Update::Position(grpc::PositionChange {
+                instrument,
+                previous_quantity,
+                quantity,
+                average_price: average_price.to_string(),
+            })),
+        },
+        PositionUpdate::Pnl { account_id, instrument, price, realized_pnl, unrealized_pnl } => grpc::PositionUpdate {
+            account_id,
+            update: Some(grpc::position_update::Update::Pnl(grpc::PnlChange {
+                instrument,
+                price: price.to_string(),
+                realized_pnl: realized_pnl.to_string(),
+                unrealized_pnl: unrealized_pnl.to_string(),
+            })),
+        },
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[tonic::async_trait]
+impl grpc::position_service_server::PositionService for GrpcPositionService {
+    type SubscribePnlStream = tokio_stream::wrappers::ReceiverStream<Result<grpc::PositionUpdate, tonic::Status>>;
+
+    async fn book_trade(&self, request: tonic::Request<grpc::BookTradeRequest>) -> Result<tonic::Response<grpc::Ack>, tonic::Status> {
+        let trade = request.get_ref().trade.clone().ok_or_else(|| tonic::Status::invalid_argument("No trade to book"))?;
+        self.handle(&request, ServiceRequest::AddTrade(Box::new(trade_from_grpc(trade)?)))?;
+        Ok(tonic::Response::new(grpc::Ack {}))
+    }
+
+    async fn amend_trade(&self, request: tonic::Request<grpc::AmendTradeRequest>) -> Result<tonic::Response<grpc::Ack>, tonic::Status> {
+        let message = request.get_ref();
+        let trade_id = TradeId::parse(&message.trade_id).map_err(tonic::Status::invalid_argument)?;
+        let price = Decimal::parse(&message.price).map_err(tonic::Status::invalid_argument)?;
+        self.handle(&request, ServiceRequest::AmendTrade { trade_id, quantity: message.quantity, price })?;
+        Ok(tonic::Response::new(grpc::Ack {}))
+    }
+
+    async fn cancel_trade(&self, request: tonic::Request<grpc::CancelTradeRequest>) -> Result<tonic::Response<grpc::Ack>, tonic::Status> {
+        let trade_id = TradeId::parse(&request.get_ref().trade_id).map_err(tonic::Status::invalid_argument)?;
+        self.handle(&request, ServiceRequest::CancelTrade(trade_id))?;
+        Ok(tonic::Response::new(grpc::Ack {}))
+    }
+
+    async fn get_position(&self, request: tonic::Request<grpc::PositionRequest>) -> Result<tonic::Response<grpc::PositionReply>, tonic::Status> {
+        let message = request.get_ref();
+        let service_request = ServiceRequest::Position { instrument: message.instrument.clone(), account_id: message.account_id.clone() };
+        match self.handle(&request, service_request)? {
+            ServiceResponse::Position(position) => Ok(tonic::Response::new(grpc::PositionReply { position: position.as_ref().map(position_to_grpc) })),
+            other => Err(tonic::Status::internal(format!("Unexpected response {:?}", other))),
+        }
     }
 
-    // Print trade analysis
-    fn print_trade_analysis(&self, filter: &TradeFilter) {
-        let filtered_trades = self.filter_trades(filter);
-        
-        println!("\n=== Trade Analysis ===");
-        println!("Total trades matching filter: {}", filtered_trades.len());
-        
-        let mut total_volume = 0.0;
-        let mut buy_volume = 0.0;
-        let mut sell_volume = 0.0;
-        let mut buy_count = 0;
-        let mut sell_count = 0;
-        
-        for trade in &filtered_trades {
-            let volume = trade.quantity as f64 * trade.price;
-            total_volume += volume;
-            
-            match trade.side {
-                Side::Buy => {
-                    buy_volume += volume;
-                    buy_count += 1;
-                },
-                Side::Sell => {
-                    sell_volume += volume;
-                    sell_count += 1;
+    async fn list_positions(&self, request: tonic::Request<grpc::PositionsRequest>) -> Result<tonic::Response<grpc::PositionsReply>, tonic::Status> {
+        let account_id = request.get_ref().account_id.clone();
+        match self.handle(&request, ServiceRequest::Positions { account_id })? {
+            ServiceResponse::Positions(positions) => Ok(tonic::Response::new(grpc::PositionsReply { positions: positions.iter().map(position_to_grpc).collect() })),
+            other => Err(tonic::Status::internal(format!("Unexpected response {:?}", other))),
+        }
+    }
+
+    async fn get_pnl(&self, request: tonic::Request<grpc::PnlRequest>) -> Result<tonic::Response<grpc::PnlReply>, tonic::Status> {
+        let account_id = request.get_ref().account_id.clone();
+        match self.handle(&request, ServiceRequest::Pnl { account_id })? {
+            ServiceResponse::Pnl { realized, unrealized, market_value } => Ok(tonic::Response::new(grpc::PnlReply {
+                realized_pnl: realized.to_string(),
+                unrealized_pnl: unrealized.to_string(),
+                market_value: market_value.to_string(),
+            })),
+            other => Err(tonic::Status::internal(format!("Unexpected response {:?}", other))),
+        }
+    }
+
+    async fn subscribe_pnl(&self, request: tonic::Request<grpc::SubscribeRequest>) -> Result<tonic::Response<Self::SubscribePnlStream>, tonic::Status> {
+        let message = request.get_ref();
+        let mut filter = StreamFilter::new();
+        if let Some(instrument) = &message.instrument {
+            filter = filter.instrument(instrument);
+        }
+        if let Some(account_id) = &message.account_id {
+            filter = filter.account(account_id);
+        }
+        let updates = {
+            let mut service = self.lock()?;
+            // A subscription shows what a positions query would, so it needs the same access
+            service.handle(&grpc_token(&request), ServiceRequest::Positions { account_id: message.account_id.clone() }, Utc::now()).map_err(|e| grpc_status(&e))?;
+            service.repository.subscribe_positions(filter)
+        };
+        // The repository sends on a std channel. The forwarding thread checks between
+        // updates whether the client has gone, so a quiet instrument does not keep it.
+        let (sender, receiver) = tokio::sync::mpsc::channel(256);
+        std::thread::spawn(move || {
+            while !sender.is_closed() {
+                match updates.recv_timeout(GRPC_STREAM_POLL_INTERVAL) {
+                    Ok(update) => {
+                        if sender.blocking_send(Ok(position_update_to_grpc(update))).is_err() {
+                            break;
+                        }
+                    },
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {},
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
                 }
             }
+        });
+        Ok(tonic::Response::new(tokio_stream::wrappers::ReceiverStream::new(receiver)))
+    }
+}
+
+// A client request carrying an API key the way the server reads it
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)]
+pub fn grpc_request<T>(message: T, token: &str) -> Result<tonic::Request<T>, tonic::Status> {
+    let mut request = tonic::Request::new(message);
+    let value = format!("Bearer {}", token).parse().map_err(|_| tonic::Status::unauthenticated("Token is not printable ASCII"))?;
+    request.metadata_mut().insert("authorization", value);
+    Ok(request)
+}
+
+// Serve the gRPC API on a bound listener (port 0 picks a free one) until shutdown completes
+#[cfg(feature = "grpc")]
+pub async fn serve_grpc(listener: tokio::net::TcpListener, service: Arc<Mutex<TradeService>>, shutdown: impl std::future::Future<Output = ()>) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(grpc::position_service_server::PositionServiceServer::new(GrpcPositionService { service }))
+        .serve_with_incoming_shutdown(tokio_stream::wrappers::TcpListenerStream::new(listener), shutdown)
+        .await
+}
+
+// One instrument of the demo dataset: reference data and how its generated closes move
+#[derive(Debug, Clone)]
+pub struct DemoInstrument {
+    pub symbol: &'static str,
+    pub asset_class: AssetClass,
+    pub currency: &'static str,
+    pub sector: &'static str,
+    pub multiplier: i32,
+    pub start_price: f64,
+    // Standard deviation of the daily return
+    pub daily_volatility: f64,
+    // Largest position an account builds, in units
+    pub position_limit: i32,
+}
+
+pub const DEMO_INSTRUMENTS: [DemoInstrument; 18] = [
+    DemoInstrument { symbol: "AAPL", asset_class: AssetClass::Equity, currency: "USD", sector: "Technology", multiplier: 1, start_price: 178.0, daily_volatility: 0.018, position_limit: 20_000 },
+    DemoInstrument { symbol: "MSFT", asset_class: AssetClass::Equity, currency: "USD", sector: "Technology", multiplier: 1, start_price: 334.0, daily_volatility: 0.016, position_limit: 10_000 },
+    DemoInstrument { symbol: "NVDA", asset_class: AssetClass::Equity, currency: "USD", sector: "Technology", multiplier: 1, start_price: 300.0, daily_volatility: 0.032, position_limit: 10_000 },
+    DemoInstrument { symbol: "JPM", asset_class: AssetClass::Equity, currency: "USD", sector: "Financials", multiplier: 1, start_price: 158.0, daily_volatility: 0.015, position_limit: 15_000 },
+    DemoInstrument { symbol: "XOM", asset_class: AssetClass::Equity, currency: "USD", sector: "Energy", multiplier: 1, start_price: 61.0, daily_volatility: 0.021, position_limit: 40_000 },
+    DemoInstrument { symbol: "JNJ", asset_class: AssetClass::Equity, currency: "USD", sector: "Health Care", multiplier: 1, start_price: 171.0, daily_volatility: 0.010, position_limit: 15_000 },
+    DemoInstrument { symbol: "SPY", asset_class: AssetClass::Equity, currency: "USD", sector: "Index", multiplier: 1, start_price: 477.0, daily_volatility: 0.012, position_limit: 10_000 },
+    DemoInstrument { symbol: "SAP", asset_class: AssetClass::Equity, currency: "EUR", sector: "Technology", multiplier: 1, start_price: 125.0, daily_volatility: 0.016, position_limit: 20_000 },
+    DemoInstrument { symbol: "ASML", asset_class: AssetClass::Equity, currency: "EUR", sector: "Technology", multiplier: 1, start_price: 700.0, daily_volatility: 0.023, position_limit: 4_000 },
+    DemoInstrument { symbol: "TTE", asset_class: AssetClass::Equity, currency: "EUR", sector: "Energy", multiplier: 1, start_price: 45.0, daily_volatility: 0.017, position_limit: 50_000 },
+    DemoInstrument { symbol: "HSBA", asset_class: AssetClass::Equity, currency: "GBP", sector: "Financials", multiplier: 1, start_price: 4.5, daily_volatility: 0.014, position_limit: 400_000 },
+    DemoInstrument { symbol: "SHEL", asset_class: AssetClass::Equity, currency: "GBP", sector: "Energy", multiplier: 1, start_price: 16.5, daily_volatility: 0.016, position_limit: 100_000 },
+    DemoInstrument { symbol: "7203", asset_class: AssetClass::Equity, currency: "JPY", sector: "Consumer", multiplier: 1, start_price: 2100.0, daily_volatility: 0.014, position_limit: 500_000 },
+    DemoInstrument { symbol: "6758", asset_class: AssetClass::Equity, currency: "JPY", sector: "Consumer", multiplier: 1, start_price: 14500.0, daily_volatility: 0.019, position_limit: 60_000 },
+    DemoInstrument { symbol: "ESZ2", asset_class: AssetClass::Future, currency: "USD", sector: "Index", multiplier: 50, start_price: 4770.0, daily_volatility: 0.012, position_limit: 200 },
+    DemoInstrument { symbol: "UST10", asset_class: AssetClass::Bond, currency: "USD", sector: "Rates", multiplier: 1, start_price: 99.5, daily_volatility: 0.004, position_limit: 100_000 },
+    DemoInstrument { symbol: "BUND", asset_class: AssetClass::Bond, currency: "EUR", sector: "Rates", multiplier: 1, start_price: 101.0, daily_volatility: 0.004, position_limit: 100_000 },
+    DemoInstrument { symbol: "BTC", asset_class: AssetClass::Crypto, currency: "USD", sector: "Crypto", multiplier: 1, start_price: 46000.0, daily_volatility: 0.035, position_limit: 40 },
+];
+
+// Accounts of the demo dataset: book, strategies and the instruments each trades
+pub const DEMO_ACCOUNTS: [(&str, &str, &[&str], &[&str]); 6] = [
+    ("ACC-US", "Equities", &["momentum", "value"], &["AAPL", "MSFT", "NVDA", "JPM", "XOM", "JNJ", "SPY"]),
+    ("ACC-EU", "Equities", &["value"], &["SAP", "ASML", "TTE", "HSBA", "SHEL"]),
+    ("ACC-ASIA", "Equities", &["momentum"], &["7203", "6758"]),
+    ("ACC-MACRO", "Macro", &["index", "crypto"], &["ESZ2", "SPY", "BTC"]),
+    ("ACC-RATES", "Macro", &["carry"], &["UST10", "BUND"]),
+    ("ACC-QUANT", "Systematic", &["stat-arb"], &["AAPL", "MSFT", "JPM", "XOM", "SAP", "TTE", "SHEL", "7203"]),
+];
+
+pub const DEMO_BROKERS: [&str; 3] = ["ALPHA", "BETA", "GAMMA"];
+
+// Generated multi-asset, multi-account book for the programs in examples/: weekday
+// closes and FX rates for every day of the period, and trades near those closes
+// from accounts that build and unwind positions within their limits. The same
+// seed always gives the same data.
+#[derive(Debug, Clone)]
+pub struct DemoDataset {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub closes: Vec<(String, NaiveDate, Price)>,
+    pub fx_rates: Vec<(String, NaiveDate, f64)>,
+    pub trades: Vec<Trade>,
+}
+
+impl DemoDataset {
+    // A year of 252 trading days with about 16 trades a day is the usual size
+    pub fn generate(seed: u64, start_date: NaiveDate, trading_days: usize, trades_per_day: usize) -> DemoDataset {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        let mut uniform = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let days: Vec<NaiveDate> = start_date.iter_days()
+            .filter(|date| !matches!(date.weekday(), Weekday::Sat | Weekday::Sun))
+            .take(trading_days)
+            .collect();
+        let end_date = days.last().copied().unwrap_or(start_date);
+
+        let mut prices: Vec<f64> = DEMO_INSTRUMENTS.iter().map(|instrument| instrument.start_price).collect();
+        let mut rates = [("EUR", 1.13), ("GBP", 1.35), ("JPY", 0.0087)];
+        let mut holdings: HashMap<(&str, &str), i32> = HashMap::new();
+        let (mut closes, mut fx_rates, mut trades) = (Vec::new(), Vec::new(), Vec::new());
+        let mut next_id = 1;
+
+        for date in days {
+            // Each day's moves are roughly normal: the sum of 12 uniforms less 6
+            let mut standard = || (0..12).map(|_| uniform()).sum::<f64>() - 6.0;
+            for (price, instrument) in prices.iter_mut().zip(&DEMO_INSTRUMENTS) {
+                *price *= 1.0 + instrument.daily_volatility * standard();
+            }
+            for (currency, rate) in rates.iter_mut() {
+                *rate *= 1.0 + 0.005 * standard();
+                fx_rates.push((currency.to_string(), date, *rate));
+            }
+            for _ in 0..trades_per_day {
+                let (account_id, _, strategies, universe) = DEMO_ACCOUNTS[(uniform() * DEMO_ACCOUNTS.len() as f64) as usize];
+                let symbol = universe[(uniform() * universe.len() as f64) as usize];
+                let index = DEMO_INSTRUMENTS.iter().position(|instrument| instrument.symbol == symbol).unwrap();
+                let instrument = &DEMO_INSTRUMENTS[index];
+
+                // Buy less often the closer the account is to its limit, so positions mean-revert
+                let holding = holdings.entry((account_id, symbol)).or_insert(0);
+                let fill = (*holding as f64 / instrument.position_limit as f64).clamp(-1.0, 1.0);
+                let side = if uniform() < 0.5 - 0.4 * fill { Side::Buy } else { Side::Sell };
+                let lot = (instrument.position_limit / 100).max(1);
+                let quantity = lot * (1 + (uniform() * 10.0) as i32);
+                *holding += if matches!(side, Side::Buy) { quantity } else { -quantity };
+
+                let price = (prices[index] * (1.0 + 0.004 * (uniform() - 0.5)) * 100.0).round() / 100.0;
+                let notional = price * quantity as f64 * instrument.multiplier as f64;
+                let broker = DEMO_BROKERS[(uniform() * DEMO_BROKERS.len() as f64) as usize];
+                let executed_at = date.and_hms_opt(8, 0, 0).unwrap().and_utc() + chrono::Duration::seconds((uniform() * 30_000.0) as i64);
+                trades.push(Trade::new(next_id, date, symbol.to_string(), quantity, price, side)
+                    .with_execution_time(executed_at)
+                    .with_account(account_id)
+                    .with_strategy(strategies[(uniform() * strategies.len() as f64) as usize])
+                    .with_broker(broker, ((notional * 0.0002).max(1.0) * 100.0).round() / 100.0));
+                next_id += 1;
+            }
+            for (price, instrument) in prices.iter().zip(&DEMO_INSTRUMENTS) {
+                closes.push((instrument.symbol.to_string(), date, Decimal::from(*price).round(2, RoundingMode::HalfEven)));
+            }
         }
-        
-        println!("Buy trades: {} (Volume: ${:.2})", buy_count, buy_volume);
-        println!("Sell trades: {} (Volume: ${:.2})", sell_count, sell_volume);
-        println!("Total volume: ${:.2}", total_volume);
-        
-        if buy_count > 0 {
-            println!("Average buy volume: ${:.2}", buy_volume / buy_count as f64);
+        DemoDataset { start_date, end_date, closes, fx_rates, trades }
+    }
+
+    // Dates with closes, oldest first
+    pub fn trading_days(&self) -> Vec<NaiveDate> {
+        self.closes.iter().map(|(_, date, _)| *date).collect::<BTreeSet<NaiveDate>>().into_iter().collect()
+    }
+
+    // Instrument records, sectors, account books and FX history; no prices or trades
+    pub fn load_reference_data(&self, repo: &mut TradeRepository) {
+        for instrument in &DEMO_INSTRUMENTS {
+            repo.register_instrument(Instrument::new(instrument.symbol, instrument.asset_class, instrument.currency).multiplier(instrument.multiplier));
+            repo.set_instrument_sector(instrument.symbol, instrument.sector);
         }
-        if sell_count > 0 {
-            println!("Average sell volume: ${:.2}", sell_volume / sell_count as f64);
+        for (account_id, book, _, _) in DEMO_ACCOUNTS {
+            repo.set_account_book(account_id, book);
+        }
+        for (currency, date, rate) in &self.fx_rates {
+            repo.fx_rates.set_rate(currency, *date, *rate);
+        }
+    }
+
+    // Reference data, closes with the last one as the current mark, and every trade
+    // booked as one batch
+    pub fn load(&self, repo: &mut TradeRepository) -> Result<BatchReport, PositionError> {
+        self.load_reference_data(repo);
+        for (instrument, date, close) in &self.closes {
+            repo.record_close_price(instrument, *date, *close);
+        }
+        for instrument in &DEMO_INSTRUMENTS {
+            if let Some((_, close)) = repo.close_prices.latest(instrument.symbol) {
+                repo.update_market_price(instrument.symbol, close)?;
+            }
+        }
+        repo.add_trades_batch(self.trades.clone())
+    }
+
+    // Trades dated between the dates inclusive, as CsvSourceAdapter reads them
+    pub fn trades_csv(&self, start_date: NaiveDate, end_date: NaiveDate) -> String {
+        let mut csv = String::from("trade_id,date,instrument,side,quantity,price,account\n");
+        for trade in self.trades.iter().filter(|trade| trade.trade_date >= start_date && trade.trade_date <= end_date) {
+            let side = if matches!(trade.side, Side::Buy) { "BUY" } else { "SELL" };
+            csv.push_str(&format!("{},{},{},{},{},{},{}\n", trade.trade_id, trade.trade_date, trade.instrument, side, trade.quantity, trade.price, trade.account_id));
+        }
+        csv
+    }
+
+    // Closes as date,instrument,close rows for TimeSeriesStore::import_csv
+    pub fn closes_csv(&self) -> String {
+        let mut csv = String::from("date,instrument,close\n");
+        for (instrument, date, close) in &self.closes {
+            csv.push_str(&format!("{},{},{}\n", date, instrument, close));
         }
+        csv
     }
 }
 
-fn main() {
-    let mut repo = TradeRepository::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Add trades with different dates and types
-    repo.add_trade(Trade::new(1, NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), "AAPL".to_string(), 100, 100.0, Side::Buy));
-    repo.add_trade(Trade::new_with_type(2, NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(), "AAPL".to_string(), 50, 110.0, Side::Buy, TradeType::Limit));
-    repo.add_trade(Trade::new(3, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), "MSFT".to_string(), 200, 150.0, Side::Buy));
-    repo.add_trade(Trade::new(4, NaiveDate::from_ymd_opt(2022, 1, 4).unwrap(), "AAPL".to_string(), 20, 120.0, Side::Sell));
-    repo.add_trade(Trade::new(5, NaiveDate::from_ymd_opt(2022, 1, 5).unwrap(), "MSFT".to_string(), 50, 160.0, Side::Buy));
-    repo.add_trade(Trade::new(6, NaiveDate::from_ymd_opt(2022, 1, 6).unwrap(), "AAPL".to_string(), 30, 125.0, Side::Sell));
+    // Positions and daily table as a full replay of the active trades leaves them
+    fn replayed(repo: &TradeRepository) -> (Vec<String>, BTreeMap<String, BTreeMap<NaiveDate, TradePosition>>) {
+        let mut rebuilt = repo.clone();
+        rebuilt.rebuild_daily_positions(&QueryControl::unlimited()).unwrap();
+        let mut positions: Vec<String> = rebuilt.positions.keys().map(|instrument| {
+            let mut position = TradePosition::new(instrument.clone());
+            for trade in rebuilt.instrument_trades_chronological(instrument) {
+                position.update_position(trade);
+            }
+            format!("{:?}", position)
+        }).collect();
+        positions.sort();
+        (positions, rebuilt.daily_positions)
+    }
 
-    // Set current market prices for P&L calculations
-    repo.update_market_price("AAPL", 135.0);
-    repo.update_market_price("MSFT", 155.0);
+    fn current(repo: &TradeRepository) -> Vec<String> {
+        let mut positions: Vec<String> = repo.positions.values().map(|position| format!("{:?}", position)).collect();
+        positions.sort();
+        positions
+    }
 
-    // Print positions with P&L
-    repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
+    #[test]
+    fn amends_and_cancels_match_a_full_replay() {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+        let first = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        let mut repo = TradeRepository::new();
+        let mut latest = 0;
+        for trade_id in 1..=600 {
+            let date = first + chrono::Duration::days(if next(4) == 0 { next(5) as i64 } else { trade_id / 150 });
+            let executed_at = date.and_hms_opt(9, 0, 0).unwrap().and_utc() + chrono::Duration::seconds(next(20_000) as i64);
+            let side = if next(3) == 0 { Side::Sell } else { Side::Buy };
+            let trade = Trade::new(trade_id, date, format!("SYM{}", next(2)), 1 + next(90) as i32, 50.0 + next(5000) as f64 / 100.0, side)
+                .with_execution_time(executed_at);
+            repo.add_trade(trade).unwrap();
+            if repo.daily_position_marks.values().any(|(_, id)| *id == TradeId::from(trade_id)) {
+                latest = trade_id;
+            }
+            match next(6) {
+                0 => {
+                    let target = 1 + next(trade_id as u64) as i64;
+                    if repo.trades.get(&TradeId::from(target)).is_some_and(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+                        repo.amend_trade(target, 1 + next(90) as i32, 60.0).unwrap();
+                    }
+                },
+                1 => {
+                    let target = 1 + next(trade_id as u64) as i64;
+                    if repo.trades.get(&TradeId::from(target)).is_some_and(|trade| !matches!(trade.status, TradeStatus::Cancelled)) {
+                        repo.cancel_trade(target).unwrap();
+                    }
+                },
+                2 if repo.trades.get(&TradeId::from(latest)).is_some_and(|trade| !matches!(trade.status, TradeStatus::Cancelled)) => {
+                    if next(2) == 0 {
+                        repo.amend_trade(latest, 1 + next(90) as i32, 75.5).unwrap();
+                    } else {
+                        repo.cancel_trade(latest).unwrap();
+                    }
+                },
+                _ => {},
+            }
+            let (positions, days) = replayed(&repo);
+            assert_eq!(current(&repo), positions, "positions after trade {}", trade_id);
+            assert_eq!(format!("{:?}", repo.daily_positions), format!("{:?}", days), "daily positions after trade {}", trade_id);
+        }
+    }
 
-    // Test advanced filtering
-    println!("\n=== Advanced Trade Filtering ===");
-    
-    // Filter by instrument and side
-    let filter = TradeFilter::new()
-        .instrument("AAPL".to_string())
-        .side(Side::Buy);
-    
-    let aapl_buys = repo.filter_trades(&filter);
-    println!("AAPL Buy trades: {}", aapl_buys.len());
-    for trade in aapl_buys {
-        println!("  {:?}", trade);
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, day).unwrap()
     }
 
-    // Filter by date range and quantity
-    let filter = TradeFilter::new()
-        .date_range(
-            NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(),
-            NaiveDate::from_ymd_opt(2022, 1, 4).unwrap()
-        )
-        .quantity_range(50, 200);
-    
-    repo.print_trade_analysis(&filter);
-
-    // Portfolio P&L analysis
-    let (realized, unrealized, market_value) = repo.calculate_portfolio_pnl();
-    println!("\n=== Portfolio P&L Summary ===");
-    println!("Total Realized P&L: ${:.2}", realized);
-    println!("Total Unrealized P&L: ${:.2}", unrealized);
-    println!("Total Market Value: ${:.2}", market_value);
-    println!("Total P&L: ${:.2}", realized + unrealized);
-
-    // Top performers
-    println!("\n=== Top Performers (by Unrealized P&L) ===");
-    let top_performers = repo.get_top_performers(5, true);
-    for (instrument, realized, unrealized) in top_performers {
-        println!("{}: Realized: ${:.2}, Unrealized: ${:.2}, Total: ${:.2}", 
-            instrument, realized, unrealized, realized + unrealized);
-    }
-
-    // Test amend by date with P&L impact
-    println!("\n=== Testing Amend with P&L Impact ===");
-    println!("Before amend:");
-    repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
-    
-    match repo.amend_trade_by_date("AAPL", NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(), 75, 115.0) {
-        Ok(()) => {
-            println!("\nAfter amending AAPL trade from 2022-01-02:");
-            repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
-        },
-        Err(e) => println!("Error: {}", e),
-    }
-
-    // Multi-criteria search
-    println!("\n=== Multi-Criteria Search ===");
-    let multi_results = repo.get_trades_by_criteria(
-        vec!["AAPL".to_string(), "MSFT".to_string()],
-        Some(Side::Buy),
-        Some((
-            NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
-            NaiveDate::from_ymd_opt(2022, 1, 5).unwrap()
-        ))
-    );
-    
-    println!("Buy trades for AAPL/MSFT from Jan 1-5:");
-    for trade in multi_results {
-        println!("  {} {} {} shares @ ${:.2} on {}", 
-            trade.instrument, 
-            match trade.side { Side::Buy => "BUY", Side::Sell => "SELL" },
-            trade.quantity, 
-            trade.price, 
-            trade.trade_date
-        );
+    fn assert_position(repo: &TradeRepository, instrument: &str, quantity: i32, average_price: f64, realized_pnl: f64) {
+        let position = repo.get_position(instrument).unwrap();
+        assert_eq!(position.quantity, quantity);
+        assert_eq!(position.average_price, Decimal::from(average_price));
+        assert_eq!(position.realized_pnl, Decimal::from(realized_pnl));
     }
-} AAPL trade from 2022-01-02...");
-    
-    match repo.amend_trade_by_date("AAPL", NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(), 75, 115.0) {
-        Ok(()) => println!("Successfully amended trade"),
-        Err(e) => println!("Error: {}", e),
+
+    #[test]
+    fn cancelling_an_earlier_buy_replays_the_rest() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "AAPL".to_string(), 100, 10.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(2, day(2), "AAPL".to_string(), 100, 20.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(3, day(3), "AAPL".to_string(), 50, 30.0, Side::Sell)).unwrap();
+        assert_position(&repo, "AAPL", 150, 15.0, 750.0);
+
+        // Taking the first buy out arithmetically would leave the sell closed at 15
+        repo.cancel_trade(1).unwrap();
+        assert_position(&repo, "AAPL", 50, 20.0, 500.0);
+        repo.cancel_trade(3).unwrap();
+        assert_position(&repo, "AAPL", 100, 20.0, 0.0);
     }
 
-    // Show position after amendment
-    repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 5).unwrap());
+    #[test]
+    fn cancelling_a_sell_between_buys_restores_the_average() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "MSFT".to_string(), 100, 10.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(2, day(2), "MSFT".to_string(), 100, 30.0, Side::Sell)).unwrap();
+        repo.add_trade(Trade::new(3, day(3), "MSFT".to_string(), 100, 20.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(4, day(4), "MSFT".to_string(), 50, 40.0, Side::Sell)).unwrap();
+        assert_position(&repo, "MSFT", 50, 20.0, 3000.0);
 
-    // Show trades in date range
-    println!("\n=== Trades from 2022-01-01 to 2022-01-03 ===");
-    let trades_in_range = repo.find_trades_by_date(
-        NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
-        NaiveDate::from_ymd_opt(2022, 1, 3).unwrap()
-    );
-    
-    for trade in trades_in_range {
-        println!("{:?}", trade);
+        repo.cancel_trade(2).unwrap();
+        assert_position(&repo, "MSFT", 150, 15.0, 1250.0);
     }
 
-    // Show position history for AAPL
-    println!("\n=== AAPL Position History ===");
-    let history = repo.get_position_history(
-        "AAPL",
-        NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
-        NaiveDate::from_ymd_opt(2022, 1, 5).unwrap()
-    );
-    
-    for (date, position) in history {
-        println!("{}: {} shares @ ${:.2}", date, position.quantity, position.average_price);
+    #[test]
+    fn cancelling_out_of_order_on_one_day_follows_execution_time() {
+        let date = day(6);
+        let at = |hour: u32| date.and_hms_opt(hour, 0, 0).unwrap().and_utc();
+        let mut repo = TradeRepository::new();
+        // Booked in reverse: the sell executed last, the 10.0 buy first
+        repo.add_trade(Trade::new(3, date, "TSLA".to_string(), 100, 25.0, Side::Sell).with_execution_time(at(15))).unwrap();
+        repo.add_trade(Trade::new(2, date, "TSLA".to_string(), 100, 30.0, Side::Buy).with_execution_time(at(12))).unwrap();
+        repo.add_trade(Trade::new(1, date, "TSLA".to_string(), 100, 10.0, Side::Buy).with_execution_time(at(10))).unwrap();
+        assert_position(&repo, "TSLA", 100, 20.0, 500.0);
+
+        repo.cancel_trade(2).unwrap();
+        assert_position(&repo, "TSLA", 0, 0.0, 1500.0);
+        repo.cancel_trade(1).unwrap();
+        assert_position(&repo, "TSLA", -100, 25.0, 0.0);
+    }
+
+    #[test]
+    fn amending_an_earlier_trade_reprices_the_later_close() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "AAPL".to_string(), 100, 10.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(2, day(2), "AAPL".to_string(), 100, 20.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(3, day(3), "AAPL".to_string(), 50, 30.0, Side::Sell)).unwrap();
+
+        repo.amend_trade(1, 100, 14.0).unwrap();
+        assert_position(&repo, "AAPL", 150, 17.0, 650.0);
+    }
+
+    #[test]
+    fn decimal_division_and_conversion_refuse_what_has_no_value() {
+        assert_eq!(Decimal::from(1).checked_div(Decimal::ZERO), None);
+        assert_eq!(Decimal::from(1).checked_div(Decimal::from(4)), Some(Decimal::from(0.25)));
+        assert!(Decimal::from_f64(f64::NAN).is_err());
+        assert!(Decimal::from_f64(f64::INFINITY).is_err());
+        assert_eq!(Decimal::from_f64(0.1), Ok(Decimal::parse("0.1").unwrap()));
+    }
+
+    // Adapter harness: ingest everything into an empty repository and check the
+    // expected number of trades were booked and quarantined, with every polled
+    // record accounted for
+    fn verify_adapter<A: SourceAdapter>(mut adapter: A, expected_ingested: usize, expected_quarantined: usize) -> IngestSummary {
+        let mut repo = TradeRepository::new();
+        let summary = repo.ingest_from(&mut adapter).unwrap();
+        assert_eq!(summary.ingested, expected_ingested, "{}: trades ingested", summary.source);
+        assert_eq!(repo.trades.len(), expected_ingested, "{}: trades booked", summary.source);
+        assert_eq!(summary.quarantined, expected_quarantined, "{}: records quarantined", summary.source);
+        assert_eq!(repo.quarantine.len(), expected_quarantined, "{}: quarantine entries", summary.source);
+        assert_eq!(summary.polled, summary.ingested + summary.quarantined + summary.duplicates, "{}: records accounted for", summary.source);
+        summary
+    }
+
+    #[test]
+    fn csv_adapter_books_good_records_and_quarantines_bad_ones() {
+        let feed = "trade_id,date,instrument,side,quantity,price,account\n\
+            30,2022-02-07,AAPL,BUY,40,140.0,\n\
+            31,2022-02-07,MSFT,SELL,abc,160.0,\n\
+            32,2022-02-07,AAPL,HOLD,10,140.0,\n\
+            1,2022-02-07,AAPL,BUY,10,140.0,\n\
+            33,2022-02-08,SAP,BUY,20,126.0,EU-DESK\n";
+        verify_adapter(CsvSourceAdapter::new("csv", feed, 2), 3, 2);
+    }
+
+    #[test]
+    fn sequenced_csv_adapter_reorders_and_skips_resends() {
+        let feed = "seq,trade_id,date,instrument,side,quantity,price\n\
+            1,40,2022-02-07,AAPL,BUY,40,140.0\n\
+            3,42,2022-02-07,AAPL,SELL,10,141.0\n\
+            2,41,2022-02-07,AAPL,BUY,20,140.5\n\
+            2,41,2022-02-07,AAPL,BUY,20,140.5\n";
+        let summary = verify_adapter(CsvSourceAdapter::new("csv-sequenced", feed, 1).sequenced(), 3, 0);
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.gaps_opened, 0);
+    }
+
+    #[test]
+    fn failed_transaction_restores_touched_trades_and_positions() {
+        let mut repo = TradeRepository::new();
+        repo.add_trade(Trade::new(1, day(1), "AAPL".to_string(), 100, 10.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(2, day(2), "AAPL".to_string(), 100, 20.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(3, day(3), "MSFT".to_string(), 50, 30.0, Side::Buy)).unwrap();
+        repo.tier_storage(day(2), 0);
+        let state = |repo: &TradeRepository| {
+            let mut trades: Vec<String> = repo.trades.values().map(|trade| format!("{:?}", trade)).collect();
+            trades.sort();
+            (trades, current(repo), format!("{:?}", repo.daily_positions), repo.audit_log.len(), repo.journal.len(), repo.status_log.len())
+        };
+        let before = state(&repo);
+
+        let result = repo.transaction(|tx| {
+            tx.cancel(1)?;
+            tx.amend(3, 80, 31.0)?;
+            tx.add_trade(Trade::new(4, day(3), "IBM".to_string(), 10, 120.0, Side::Buy))?;
+            tx.repo.transaction(|inner| inner.add_trade(Trade::new(5, day(4), "AAPL".to_string(), 50, 25.0, Side::Sell)))?;
+            tx.cancel(99)
+        });
+        assert!(matches!(result, Err(PositionError::TradeNotFound(_))));
+        assert_eq!(state(&repo), before);
+        assert!(!repo.trades.is_hot(&TradeId::from(1)));
+        assert_eq!(current(&repo), replayed(&repo).0);
+
+        // The restored folds carry on as if the transaction never ran
+        repo.cancel_trade(2).unwrap();
+        repo.amend_trade(1, 100, 12.0).unwrap();
+        assert_position(&repo, "AAPL", 100, 12.0, 0.0);
+        assert_eq!(current(&repo), replayed(&repo).0);
+        assert_eq!(format!("{:?}", repo.daily_positions), format!("{:?}", replayed(&repo).1));
+    }
+
+    #[test]
+    fn corporate_actions_restate_history_or_change_nothing() {
+        let book = || {
+            let mut repo = TradeRepository::new();
+            repo.add_trade(Trade::new(1, day(1), "TSLA".to_string(), 100, 600.0, Side::Buy)).unwrap();
+            repo.add_trade(Trade::new(2, day(2), "TSLA".to_string(), 50, 660.0, Side::Sell)).unwrap();
+            repo.add_trade(Trade::new(3, day(3), "TSLA".to_string(), 30, 630.0, Side::Buy)).unwrap();
+            repo.record_close_price("TSLA", day(3), 640.0);
+            repo
+        };
+        let closes = |repo: &TradeRepository, instrument: &str| -> Vec<(NaiveDate, Price)> { repo.close_prices.range(instrument, day(1), day(31)).collect() };
+
+        // Storage that has never seen trade 3 refuses its restatement at commit
+        let mut refused = book();
+        let mut storage_of_two = MemoryTradeStore::default();
+        for trade_id in [1, 2] {
+            let trade = refused.trades.get(&TradeId::from(trade_id)).unwrap().clone();
+            storage_of_two.trades.insert(trade.trade_id, trade);
+        }
+        refused.attach_storage(Arc::new(Mutex::new(storage_of_two)));
+        let trades_before: Vec<String> = refused.trades.values().map(|trade| format!("{:?}", trade)).collect();
+        let positions_before = current(&refused);
+        let result = refused.apply_corporate_action(CorporateAction::split("TSLA", day(10), 4, 1, true));
+        assert!(matches!(result, Err(PositionError::TradeNotFound(_))));
+        assert_eq!(refused.trades.values().map(|trade| format!("{:?}", trade)).collect::<Vec<String>>(), trades_before);
+        assert_eq!(current(&refused), positions_before);
+        assert_eq!(closes(&refused, "TSLA"), vec![(day(3), Decimal::from(640.0))]);
+        assert!(refused.corporate_actions.is_empty());
+
+        let mut split = book();
+        let result = split.apply_corporate_action(CorporateAction::split("TSLA", day(10), 4, 1, true)).unwrap();
+        assert_eq!(result.restated_trades, vec![TradeId::from(1), TradeId::from(2), TradeId::from(3)]);
+        assert_position(&split, "TSLA", 320, 152.8125, 3000.0);
+        assert_eq!(closes(&split, "TSLA"), vec![(day(3), Decimal::from(160.0))]);
+        assert_eq!(current(&split), replayed(&split).0);
+        assert_eq!(format!("{:?}", split.daily_positions), format!("{:?}", replayed(&split).1));
+
+        // A rename carries cancelled trades along and leaves nothing under the old symbol
+        let mut renamed = book();
+        renamed.cancel_trade(2).unwrap();
+        renamed.apply_corporate_action(CorporateAction::symbol_change("TSLA", day(10), "TSLQ")).unwrap();
+        assert!(renamed.get_position("TSLA").is_none());
+        assert_eq!(renamed.trades.instrument_values("TSLQ").count(), 3);
+        assert_eq!(renamed.get_position("TSLQ").map(|position| position.quantity), Some(130));
+        assert_eq!(closes(&renamed, "TSLQ"), vec![(day(3), Decimal::from(640.0))]);
+        assert_eq!(current(&renamed), replayed(&renamed).0);
+    }
+
+    #[test]
+    fn concurrent_writers_end_where_one_writer_would() {
+        let concurrent = ConcurrentTradeRepository::new();
+        let trades: Vec<Trade> = (0..2000i64).map(|i| {
+            let side = if i % 3 == 2 { Side::Sell } else { Side::Buy };
+            Trade::new(i, day(1 + (i % 20) as u32), ["AAPL", "MSFT"][(i % 2) as usize].to_string(), 10, 100.0 + (i % 7) as f64, side)
+        }).collect();
+        // Four writers race over the same ids; each id is booked exactly once
+        let duplicates: usize = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..4).map(|_| scope.spawn(|| {
+                trades.iter().filter(|trade| matches!(concurrent.add_trade((*trade).clone()), Err(PositionError::DuplicateTradeId(_)))).count()
+            })).collect();
+            writers.into_iter().map(|writer| writer.join().unwrap()).sum()
+        });
+        assert_eq!(duplicates, 3 * trades.len());
+
+        concurrent.cancel_trade(0).unwrap();
+        concurrent.amend_trade(1, 20, 90.0).unwrap();
+        assert!(matches!(concurrent.cancel_trade(0), Err(PositionError::TradeCancelled(_))));
+        assert!(matches!(concurrent.amend_trade(0, 20, 90.0), Err(PositionError::TradeCancelled(_))));
+        assert!(matches!(concurrent.amend_trade(5000, 20, 90.0), Err(PositionError::TradeNotFound(_))));
+        assert!(concurrent.amend_trade(1, 0, 90.0).is_err());
+        assert!(matches!(concurrent.get_trade(0).map(|trade| trade.status), Some(TradeStatus::Cancelled)));
+        let (trade, position) = concurrent.trade_and_position(1).unwrap();
+        assert_eq!((trade.quantity, trade.price), (20, Decimal::from(90.0)));
+        assert_eq!(concurrent.get_position("MSFT").map(|position| position.quantity), Some(position.quantity));
+        assert!(concurrent.get_trade(5000).is_none());
+
+        let mut repo = TradeRepository::new();
+        for trade in &trades {
+            repo.add_trade(trade.clone()).unwrap();
+        }
+        repo.cancel_trade(0).unwrap();
+        repo.amend_trade(1, 20, 90.0).unwrap();
+        let snapshot = concurrent.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        for (instrument, position) in snapshot {
+            let expected = repo.get_position(&instrument).unwrap();
+            assert_eq!((position.quantity, position.average_price, position.realized_pnl), (expected.quantity, expected.average_price, expected.realized_pnl), "{}", instrument);
+        }
+    }
+
+    #[test]
+    fn order_manager_works_orders_against_the_market_and_the_book() {
+        let mut repo = TradeRepository::new();
+        let mut oms = OrderManager::new(day(1));
+        assert!(matches!(oms.submit(&mut repo, Order::market("AAPL", Side::Buy, 100)), Err(PositionError::OrderRejected(_))));
+        assert!(matches!(oms.submit(&mut repo, Order::new("AAPL", Side::Buy, 100, TradeType::Limit)), Err(PositionError::OrderRejected(_))));
+        assert!(matches!(oms.submit(&mut repo, Order::limit("AAPL", Side::Buy, 0, 150.0)), Err(PositionError::InvalidQuantity(0))));
+
+        repo.update_market_price("AAPL", 150.0).unwrap();
+        let market = oms.submit(&mut repo, Order::market("AAPL", Side::Buy, 100)).unwrap();
+        let limit = oms.submit(&mut repo, Order::limit("AAPL", Side::Buy, 100, 145.0).time_in_force(TimeInForce::GoodTillCancel)).unwrap();
+        let stop = oms.submit(&mut repo, Order::stop("AAPL", Side::Sell, 50, 140.0).time_in_force(TimeInForce::GoodTillCancel)).unwrap();
+        let day_order = oms.submit(&mut repo, Order::limit("AAPL", Side::Buy, 10, 100.0)).unwrap();
+        assert!(matches!(oms.get_order(market).map(|order| order.status), Some(OrderStatus::Filled)));
+        assert_eq!(oms.working_orders("AAPL").len(), 3);
+
+        repo.update_market_price("AAPL", 144.0).unwrap();
+        assert_eq!(oms.on_market_update(&mut repo, "AAPL").unwrap().len(), 1);
+        repo.update_market_price("AAPL", 139.0).unwrap();
+        assert_eq!(oms.on_market_update(&mut repo, "AAPL").unwrap().len(), 1);
+        assert!(matches!(oms.get_order(limit).map(|order| order.status), Some(OrderStatus::Filled)));
+        assert!(matches!(oms.get_order(stop).map(|order| order.status), Some(OrderStatus::Filled)));
+        assert_eq!(repo.get_position("AAPL").map(|position| position.quantity), Some(150));
+        assert_eq!(oms.end_of_day(day(2)), vec![day_order]);
+        assert!(matches!(oms.cancel(day_order), Err(PositionError::OrderRejected(_))));
+        assert!(matches!(oms.fill(&mut repo, 99, 10, 1.0), Err(PositionError::OrderNotFound(99))));
+
+        // Book levels are taken up to their displayed size; a fill-or-kill larger than the book books nothing
+        repo.ingest_book_snapshot(BookSnapshot::new("MSFT", start_of_day(day(2))).bid(299.5, 100).ask(300.0, 100).ask(301.0, 50)).unwrap();
+        let killed = oms.submit(&mut repo, Order::market("MSFT", Side::Buy, 200).time_in_force(TimeInForce::FillOrKill)).unwrap();
+        assert!(matches!(oms.get_order(killed).map(|order| order.status), Some(OrderStatus::Cancelled)));
+        assert!(repo.get_position("MSFT").is_none());
+        let swept = oms.submit(&mut repo, Order::limit("MSFT", Side::Buy, 200, 301.0).time_in_force(TimeInForce::ImmediateOrCancel)).unwrap();
+        let swept = oms.get_order(swept).unwrap();
+        assert_eq!((swept.filled_quantity, swept.executions.len()), (150, 2));
+        assert!(matches!(swept.status, OrderStatus::Cancelled));
+        let working = oms.submit(&mut repo, Order::limit("MSFT", Side::Buy, 30, 290.0).time_in_force(TimeInForce::GoodTillCancel)).unwrap();
+        assert!(matches!(oms.fill(&mut repo, working, 31, 290.0), Err(PositionError::InvalidQuantity(31))));
+        oms.fill(&mut repo, working, 30, 290.0).unwrap();
+        assert_eq!(repo.get_position("MSFT").map(|position| position.quantity), Some(180));
+    }
+
+    #[test]
+    fn matching_engine_fills_by_price_then_time_at_the_resting_price() {
+        let mut repo = TradeRepository::new();
+        let mut engine = MatchingEngine::new(day(1));
+        assert!(matches!(engine.submit(&mut repo, Order::market("IBM", Side::Buy, 10)), Err(PositionError::OrderRejected(_))));
+        assert!(matches!(engine.submit(&mut repo, Order::stop("IBM", Side::Sell, 10, 120.0)), Err(PositionError::OrderRejected(_))));
+
+        let first = engine.submit(&mut repo, Order::limit("IBM", Side::Sell, 100, 125.0).with_account("MM-A")).unwrap();
+        let second = engine.submit(&mut repo, Order::limit("IBM", Side::Sell, 100, 125.0).with_account("MM-B")).unwrap();
+        let higher = engine.submit(&mut repo, Order::limit("IBM", Side::Sell, 200, 125.5).with_account("MM-A").time_in_force(TimeInForce::GoodTillCancel)).unwrap();
+        engine.submit(&mut repo, Order::limit("IBM", Side::Buy, 150, 124.5).with_account("MM-B")).unwrap();
+        assert_eq!((engine.best_bid("IBM"), engine.best_ask("IBM")), (Some(Decimal::from(124.5)), Some(Decimal::from(125.0))));
+
+        let sweep = engine.submit(&mut repo, Order::limit("IBM", Side::Buy, 250, 125.5).with_account("STRAT")).unwrap();
+        let fills: Vec<(OrderId, Price, i32)> = engine.fills_for(sweep).iter().map(|fill| (fill.sell_order, fill.price, fill.quantity)).collect();
+        assert_eq!(fills, vec![(first, Decimal::from(125.0), 100), (second, Decimal::from(125.0), 100), (higher, Decimal::from(125.5), 50)]);
+        assert_eq!(engine.get_order(sweep).and_then(Order::average_fill_price), Some(Decimal::from(125.1)));
+        assert_eq!(repo.get_market_price("IBM"), Some(Decimal::from(125.5)));
+
+        let killed = engine.submit(&mut repo, Order::market("IBM", Side::Buy, 500).with_account("STRAT").time_in_force(TimeInForce::FillOrKill)).unwrap();
+        assert!(matches!(engine.get_order(killed).map(|order| order.status), Some(OrderStatus::Cancelled)));
+        assert!(engine.fills_for(killed).is_empty());
+        engine.submit(&mut repo, Order::market("IBM", Side::Sell, 60).with_account("STRAT")).unwrap();
+        assert_eq!(engine.book_snapshot("IBM", start_of_day(day(1))).bids.first().map(|level| (level.price, level.size)), Some((Decimal::from(124.5), 90)));
+
+        // Both sides are booked, each under its own account
+        assert_eq!(repo.build_account_positions("STRAT").get("IBM").map(|position| position.quantity), Some(190));
+        assert_eq!(repo.build_account_positions("MM-A").get("IBM").map(|position| position.quantity), Some(-150));
+        assert_eq!(repo.get_position("IBM").map(|position| position.quantity), Some(0));
+        assert_eq!(engine.end_of_day(day(2)).len(), 1);
+        assert_eq!(engine.best_bid("IBM"), None);
+        assert_eq!(engine.best_ask("IBM"), Some(Decimal::from(125.5)));
+    }
+
+    #[test]
+    fn tax_lots_close_under_each_cost_basis_method() {
+        let mut repo = TradeRepository::new();
+        for (trade_id, date, quantity, price, side) in [(80, 1, 100, 150.0, Side::Buy), (81, 2, 100, 170.0, Side::Buy), (82, 3, 100, 160.0, Side::Buy), (83, 10, 150, 180.0, Side::Sell)] {
+            repo.add_trade(Trade::new(trade_id, day(date), "AAPL".to_string(), quantity, price, side)).unwrap();
+        }
+        repo.select_lots(83, &[TradeId::from(82), TradeId::from(80)]).unwrap();
+        assert!(matches!(repo.select_lots(83, &[TradeId::from(99)]), Err(PositionError::TradeNotFound(_))));
+        let lots = |method: CostBasisMethod| {
+            let position = repo.replay_lots("AAPL", method);
+            let open: Vec<(i64, i32)> = position.lots.iter().map(|lot| (lot.trade_id.as_i64().unwrap(), lot.quantity)).collect();
+            (position.realized_pnl, open)
+        };
+        assert_eq!(lots(CostBasisMethod::Fifo), (Decimal::from(3500), vec![(81, 50), (82, 100)]));
+        assert_eq!(lots(CostBasisMethod::Lifo), (Decimal::from(2500), vec![(80, 100), (81, 50)]));
+        assert_eq!(lots(CostBasisMethod::Hifo), (Decimal::from(2000), vec![(80, 100), (82, 50)]));
+        assert_eq!(lots(CostBasisMethod::SpecificLot), (Decimal::from(3500), vec![(80, 50), (81, 100)]));
+        assert_eq!(repo.replay_lots("AAPL", CostBasisMethod::AverageCost).realized_pnl, Decimal::from(3000));
+
+        repo.set_cost_basis_method("AAPL", CostBasisMethod::Hifo);
+        let closures = repo.realized_lots(day(1), day(31));
+        assert_eq!(closures.iter().map(|(_, closure)| (closure.lot_trade_id.as_i64().unwrap(), closure.quantity, closure.holding_days())).collect::<Vec<_>>(), vec![(81, 100, 8), (82, 50, 7)]);
+        assert_eq!(repo.tax_lots("AAPL").unwrap().unrealized_pnl(Decimal::from(170)), Decimal::from(2500));
+
+        // Cancelling the sale reopens every lot
+        repo.cancel_trade(83).unwrap();
+        assert_eq!(repo.tax_lots("AAPL").unwrap().quantity(), 300);
+        assert!(repo.realized_lots(day(1), day(31)).is_empty());
+        assert!(repo.tax_lots("MSFT").is_none());
+    }
+
+    #[test]
+    fn fx_store_fallbacks_and_cross_rates() {
+        let mut store = FxRateStore::new("USD", FxFallback::PreviousBusinessDay);
+        store.set_rate("EUR", day(1), 1.1);
+        store.set_rate("GBP", day(1), 1.25);
+        store.set_rate("EUR", day(3), 1.3);
+        assert_eq!(store.rate_on("EUR", day(2)), Ok(1.1));
+        assert_eq!(store.rate_on("EUR", day(9)), Ok(1.3));
+        assert_eq!(store.rate_on("USD", day(9)), Ok(1.0));
+        assert!(store.rate_on("EUR", NaiveDate::from_ymd_opt(2024, 4, 30).unwrap()).is_err());
+        // Both legs through the firm currency
+        assert_eq!(store.rates_on(day(1)).convert(Decimal::from(125), "GBP", "EUR").map(|amount| amount.round(4, RoundingMode::HalfUp)), Ok(Decimal::from(142.0455)));
+        assert!(store.rates_on(day(1)).convert(Decimal::from(1), "JPY", "USD").is_err());
+
+        store.fallback = FxFallback::Fail;
+        assert!(store.rate_on("EUR", day(2)).is_err());
+        assert_eq!(store.rates_on(day(2)).convert(Decimal::from(1), "USD", "USD"), Ok(Decimal::from(1)));
+        assert!(store.rates_on(day(2)).convert(Decimal::from(1), "EUR", "USD").is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_keeps_what_was_committed_and_nothing_rolled_back() {
+        let sqlite: SharedTradeStorage = Arc::new(Mutex::new(SqliteTradeStore::open_in_memory().unwrap()));
+        let mut repo = TradeRepository::new();
+        repo.attach_storage(Arc::clone(&sqlite));
+        let mut booked = Trade::new(1, day(1), "T".to_string(), 1000, 16.5, Side::Buy).with_account("EQ1");
+        booked.strategy = Some("carry".to_string());
+        booked.commission = Decimal::from(1.25);
+        repo.add_trade(booked).unwrap();
+        repo.cancel_and_rebook(1, Trade::new(2, day(1), "T".to_string(), 1200, 16.5, Side::Buy).with_account("EQ1")).unwrap();
+        repo.add_trade(Trade::new(3, day(2), "T".to_string(), 200, 17.0, Side::Sell)).unwrap();
+        repo.amend_trade(3, 300, 17.25).unwrap();
+        let failed = repo.transaction(|tx| {
+            tx.add_trade(Trade::new(4, day(2), "T".to_string(), 10, 16.6, Side::Sell))?;
+            tx.add_trade(Trade::new(1, day(2), "T".to_string(), 10, 16.6, Side::Sell))
+        });
+        assert!(matches!(failed, Err(PositionError::DuplicateTradeId(_))));
+
+        let stored = lock_storage(&sqlite).unwrap().query(&TradeFilter::new()).unwrap();
+        assert_eq!(stored.iter().map(|trade| (trade.trade_id.as_i64().unwrap(), trade_status_name(&trade.status))).collect::<Vec<_>>(),
+                   vec![(1, "cancelled"), (2, "active"), (3, "amended")]);
+        assert_eq!(format!("{:?}", stored[0].strategy), format!("{:?}", repo.trades.get(&TradeId::from(1)).unwrap().strategy));
+        assert_eq!(stored[0].commission, Decimal::from(1.25));
+        assert_eq!(stored[1].replaces, Some(TradeId::from(1)));
+        assert_eq!(lock_storage(&sqlite).unwrap().query(&TradeFilter::new().side(Side::Sell)).unwrap().len(), 1);
+
+        let reloaded = TradeRepository::load_from_storage(sqlite).unwrap();
+        assert_eq!(current(&reloaded), current(&repo));
+        assert_eq!(reloaded.trades.len(), 3);
+    }
+
+    #[test]
+    fn cold_segments_decode_every_field_as_sealed() {
+        let mut trades: Vec<Trade> = (0..2500i32).map(|i| {
+            let trade = Trade::new(i * 3 - 40, day(1 + (i % 28) as u32), format!("SYM{}", i % 7), 10 + i, 100.0 + i as f64 / 8.0, if i % 2 == 0 { Side::Buy } else { Side::Sell });
+            trade.with_execution_time(day(1).and_hms_nano_opt(9, 30, 0, i as u32 * 1000).unwrap().and_utc())
+        }).collect();
+        trades[7].strategy = Some("pairs".to_string());
+        trades[7].exec_id = Some("X-7".to_string());
+        trades[7].order_id = Some(u64::MAX);
+        trades[8].replaces = Some(TradeId::from_uuid(u128::MAX / 3));
+        trades[8].received_at = Some(day(2).and_hms_opt(1, 2, 3).unwrap().and_utc());
+        trades[9].status = TradeStatus::Cancelled;
+        trades[9].trade_type = TradeType::Stop;
+        trades[9].commission = Decimal::from(-1.5);
+        trades[9].arrival_price = Some(Decimal::from(99.125));
+
+        let segment = ColdSegment::seal(trades.clone()).unwrap();
+        assert_eq!(segment.blocks.len(), 3);
+        let decoded: Vec<String> = segment.trades().map(|trade| format!("{:?}", trade)).collect();
+        trades.sort_by_key(|trade| trade.trade_id);
+        assert_eq!(decoded, trades.iter().map(|trade| format!("{:?}", trade)).collect::<Vec<_>>());
+        assert_eq!(format!("{:?}", segment.get(trades[2000].trade_id)), format!("{:?}", Some(&trades[2000])));
+        assert!(segment.get(TradeId::from(-39)).is_none());
+        assert!(segment.encoded_bytes() * 10 < trades.len() * std::mem::size_of::<Trade>());
+    }
+
+    #[test]
+    fn writes_keep_decoded_cold_blocks_they_do_not_touch() {
+        let mut store = TradeStore::new();
+        let trades: Vec<Trade> = (0..2500i64).map(|i| Trade::new(i, day(1 + (i % 20) as u32), "AAPL".to_string(), 10, 100.0, Side::Buy)).collect();
+        store.insert_new("AAPL", &trades);
+        store.seal_before(day(28));
+        let decoded = |store: &TradeStore| -> Vec<bool> { store.cold[0].blocks.iter().map(|block| block.decoded.get().is_some()).collect() };
+        assert_eq!(store.values().count(), 2500);
+        assert_eq!(decoded(&store), vec![true, true, true]);
+
+        store.insert_new("MSFT", &[Trade::new(5000, day(28), "MSFT".to_string(), 10, 50.0, Side::Buy)]);
+        store.insert(TradeId::from(5000), Trade::new(5000, day(28), "MSFT".to_string(), 20, 50.0, Side::Buy));
+        assert_eq!(decoded(&store), vec![true, true, true]);
+        store.get_mut(&TradeId::from(1500)).unwrap().quantity = 30;
+        assert_eq!(decoded(&store), vec![true, false, true]);
+        store.insert(TradeId::from(2400), Trade::new(2400, day(1), "AAPL".to_string(), 40, 100.0, Side::Buy));
+        assert_eq!(decoded(&store), vec![true, false, false]);
+        assert_eq!(store.get(&TradeId::from(1500)).map(|trade| trade.quantity), Some(30));
+        assert_eq!(store.get(&TradeId::from(2400)).map(|trade| trade.quantity), Some(40));
+        assert_eq!(store.len(), 2501);
+
+        store.release_decoded();
+        assert_eq!(decoded(&store), vec![false, false, false]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn futures_fees_and_lots_scale_by_the_multiplier_once() {
+        let mut repo = TradeRepository::new();
+        repo.register_instrument(Instrument::new("ESZ2", AssetClass::Future, "USD").multiplier(50));
+        repo.add_trade(Trade::new(1, day(1), "ESZ2".to_string(), 1, 4000.0, Side::Buy).with_broker("GS", 2.5)).unwrap();
+        repo.add_trade(Trade::new(2, day(2), "ESZ2".to_string(), 1, 4000.0, Side::Sell)).unwrap();
+        assert_eq!(repo.get_position("ESZ2").unwrap().realized_pnl, Decimal::from(-2.5));
+
+        repo.add_trade(Trade::new(3, day(3), "ESZ2".to_string(), 2, 4000.0, Side::Buy)).unwrap();
+        repo.add_trade(Trade::new(4, day(4), "ESZ2".to_string(), 1, 4010.0, Side::Sell)).unwrap();
+        for method in [CostBasisMethod::Fifo, CostBasisMethod::AverageCost] {
+            let lots = repo.replay_lots("ESZ2", method);
+            assert_eq!(lots.realized_pnl, Decimal::from(497.5), "{:?}", method);
+            assert_eq!(lots.cost_basis(), Decimal::from(200_000), "{:?}", method);
+            assert_eq!(lots.average_price(), Decimal::from(4000), "{:?}", method);
+            assert_eq!(lots.unrealized_pnl(Decimal::from(4001)), Decimal::from(50), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn non_finite_numbers_from_outside_are_refused() {
+        let input = "buy AAPL 10 nan 2024-05-01\nmark AAPL inf\nbuy AAPL 10 100 2024-05-01\nmark AAPL 1e999\ncommit\n";
+        let mut out = Vec::new();
+        let repo = run_repl(TradeRepository::new(), input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Error: Invalid price nan"), "{}", out);
+        assert!(out.contains("Error: Invalid price inf"), "{}", out);
+        assert!(out.contains("Committed 1 trades"), "{}", out);
+        assert_position(&repo, "AAPL", 10, 100.0, 0.0);
+
+        let rates = FxRates::new("USD").rate("EUR", 1.1).rate("XXX", 0.0);
+        assert_eq!(rates.convert(Decimal::from(10), "EUR", "USD"), Ok(Decimal::from(11)));
+        assert!(rates.convert(Decimal::from(10), "EUR", "XXX").is_err());
+
+        let feed = "trade_id,date,instrument,side,quantity,price\n1,2022-02-07,AAPL,BUY,40,NaN\n2,2022-02-07,AAPL,BUY,40,inf\n3,2022-02-07,AAPL,BUY,40,140.0\n";
+        verify_adapter(CsvSourceAdapter::new("csv", feed, 10), 1, 2);
+    }
+
+    #[test]
+    fn fx_store_refuses_rates_it_cannot_convert_with() {
+        let mut store = FxRateStore::new("USD", FxFallback::Interpolate);
+        for rate in ["nan", "inf", "0", "-1.1", "abc"] {
+            assert!(store.import_csv(&format!("date,currency,rate\n2024-05-01,EUR,1.1\n2024-05-02,EUR,{}\n", rate)).is_err(), "{}", rate);
+        }
+        assert!(store.rates.series("EUR").is_none(), "nothing imported from a bad file");
+
+        assert_eq!(store.import_csv("date,currency,rate\n2024-05-01,EUR,1.1\n2024-05-03,EUR,1.3\n"), Ok(2));
+        assert!((store.rate_on("EUR", day(2)).unwrap() - 1.2).abs() < 1e-9);
+        assert_eq!(store.rates_on(day(3)).convert(Decimal::from(100), "EUR", "USD"), Ok(Decimal::from(130)));
+        assert!(store.rate_on("EUR", day(4)).is_err());
+        assert!(store.rate_on("GBP", day(1)).is_err());
+
+        let mut repo = TradeRepository::new();
+        assert!(repo.import_borrow_rates_csv("date,instrument,rate\n2024-05-01,GME,nan\n").is_err());
+        assert!(repo.import_borrow_rates_csv("date,instrument,rate\n2024-05-01,GME,-0.01\n").is_err());
+        assert_eq!(repo.import_borrow_rates_csv("date,instrument,rate\n2024-05-01,GME,0.35\n2024-05-01,MSFT,0\n").unwrap(), 2);
+        assert_eq!(repo.borrow_rate_on("GME", day(9)), Some(0.35));
+    }
+
+    #[test]
+    fn batch_bookings_reach_stream_subscribers_once_per_instrument() {
+        let mut repo = TradeRepository::new();
+        repo.update_market_price("AAPL", 12.0).unwrap();
+        let updates = repo.subscribe_positions(StreamFilter::new());
+        let report = repo.add_trades_batch(vec![
+            Trade::new(1, day(1), "AAPL".to_string(), 100, 10.0, Side::Buy),
+            Trade::new(2, day(1), "MSFT".to_string(), 50, 30.0, Side::Buy),
+            Trade::new(3, day(2), "AAPL".to_string(), 100, 20.0, Side::Buy),
+        ]).unwrap();
+        assert_eq!(report.accepted, 3);
+
+        let received: Vec<String> = updates.try_iter().map(|update| match update {
+            PositionUpdate::Position { instrument, previous_quantity, quantity, .. } => format!("{} {} -> {}", instrument, previous_quantity, quantity),
+            PositionUpdate::Pnl { instrument, unrealized_pnl, .. } => format!("{} unrealized {}", instrument, unrealized_pnl),
+        }).collect();
+        assert_eq!(received, ["AAPL 0 -> 200", "AAPL unrealized -600", "MSFT 0 -> 50"]);
+    }
+
+    #[test]
+    fn a_stream_that_falls_behind_is_ended() {
+        let mut repo = TradeRepository::new();
+        let updates = repo.subscribe_positions(StreamFilter::new().instrument("AAPL"));
+        for trade_id in 1..=POSITION_STREAM_BUFFER as i64 + 1 {
+            repo.add_trade(Trade::new(trade_id, day(1), "AAPL".to_string(), 1, 10.0, Side::Buy)).unwrap();
+        }
+        assert!(repo.position_streams.is_empty());
+        assert_eq!(updates.try_iter().count(), POSITION_STREAM_BUFFER);
+        assert!(matches!(updates.try_recv(), Err(std::sync::mpsc::TryRecvError::Disconnected)));
+    }
+
+    #[test]
+    fn api_keys_are_scoped_rotated_revoked_and_every_request_logged() {
+        let opened = day(1).and_hms_opt(9, 0, 0).unwrap().and_utc();
+        let at = |minutes: i64| opened + chrono::Duration::minutes(minutes);
+        let mut service = TradeService::new(TradeRepository::new(), ApiKeyStore::new().with_rotation_grace(chrono::Duration::minutes(5)));
+        let admin = service.bootstrap_admin_key("operator", opened);
+        let mut issue = |name: &str, role: Role, accounts: Option<&str>, expires_at: Option<DateTime<Utc>>| {
+            let request = ServiceRequest::CreateKey { name: name.to_string(), role, accounts: accounts.map(|account| BTreeSet::from([account.to_string()])), expires_at };
+            match service.handle(&admin, request, opened) {
+                Ok(ServiceResponse::Key { key_id, token }) => (key_id, token),
+                other => panic!("expected a key, got {:?}", other),
+            }
+        };
+        let (desk_id, desk) = issue("equity desk", Role::Trader, Some("EQ1"), None);
+        let (viewer_id, viewer) = issue("risk", Role::Viewer, None, None);
+        let (_, temporary) = issue("contractor", Role::Viewer, None, Some(at(30)));
+        let book = |trade_id: i32, account: &str| ServiceRequest::AddTrade(Box::new(Trade::new(trade_id, day(1), "NVDA".to_string(), 100, 140.0, Side::Buy).with_account(account)));
+        let firm_position = || ServiceRequest::Position { instrument: "NVDA".to_string(), account_id: None };
+        let refusal = |result: Result<ServiceResponse, PositionError>| match result {
+            Err(PositionError::Unauthenticated(_)) => "unauthenticated",
+            Err(PositionError::Forbidden { .. }) => "forbidden",
+            Err(_) => "other",
+            Ok(_) => "ok",
+        };
+
+        assert_eq!(refusal(service.handle(&desk, book(1, "EQ1"), at(1))), "ok");
+        assert_eq!(refusal(service.handle(&desk, book(2, "EQ2"), at(2))), "forbidden");
+        assert_eq!(refusal(service.handle(&viewer, book(3, "EQ1"), at(3))), "forbidden");
+        assert_eq!(refusal(service.handle(&desk, firm_position(), at(4))), "forbidden");
+        assert!(matches!(service.handle(&viewer, firm_position(), at(4)), Ok(ServiceResponse::Position(Some(position))) if position.quantity == 100));
+        assert_eq!(refusal(service.handle(&desk, ServiceRequest::CreateKey { name: "mine".to_string(), role: Role::Admin, accounts: None, expires_at: None }, at(5))), "forbidden");
+        assert_eq!(refusal(service.handle(&format!("{}.0000", desk_id), firm_position(), at(5))), "unauthenticated");
+        assert_eq!(refusal(service.handle("no-dot", firm_position(), at(5))), "unauthenticated");
+        assert_eq!(refusal(service.handle("key-9999.0000", firm_position(), at(5))), "unauthenticated");
+        assert_eq!(refusal(service.handle(&temporary, firm_position(), at(29))), "ok");
+        assert_eq!(refusal(service.handle(&temporary, firm_position(), at(30))), "unauthenticated");
+
+        // The old secret works through the grace period only
+        let rotated = match service.handle(&admin, ServiceRequest::RotateKey(desk_id.clone()), at(10)) {
+            Ok(ServiceResponse::Key { token, .. }) => token,
+            other => panic!("expected a key, got {:?}", other),
+        };
+        assert_eq!(refusal(service.handle(&desk, ServiceRequest::Trades(TradeFilter::new()), at(14))), "ok");
+        assert_eq!(refusal(service.handle(&desk, ServiceRequest::Trades(TradeFilter::new()), at(15))), "unauthenticated");
+        assert_eq!(refusal(service.handle(&rotated, ServiceRequest::AmendTrade { trade_id: TradeId::from(1), quantity: 150, price: Decimal::from(139.5) }, at(16))), "ok");
+        assert_eq!(service.repository.audit_log.last().map(|entry| entry.actor.clone()), Some(desk_id.clone()));
+
+        service.handle(&admin, ServiceRequest::RevokeKey(viewer_id.clone()), at(20)).unwrap();
+        assert_eq!(refusal(service.handle(&viewer, firm_position(), at(21))), "unauthenticated");
+        assert!(service.handle(&admin, ServiceRequest::RevokeKey(viewer_id.clone()), at(22)).is_err());
+        assert!(service.handle(&admin, ServiceRequest::RotateKey(viewer_id.clone()), at(22)).is_err());
+        assert_eq!(service.keys.keys[&viewer_id].revoked_at, Some(at(20)));
+        assert_eq!((service.keys.keys[&desk_id].name.as_str(), service.keys.keys[&desk_id].created_at), ("equity desk", opened));
+
+        // Every request is recorded, refused ones and unknown keys included
+        let log = match service.handle(&admin, ServiceRequest::RequestLog, at(25)) {
+            Ok(ServiceResponse::RequestLog(records)) => records,
+            other => panic!("expected the request log, got {:?}", other),
+        };
+        assert_eq!(log.len(), 22);
+        assert!(log.windows(2).all(|pair| pair[1].sequence == pair[0].sequence + 1));
+        assert_eq!(log.iter().filter(|record| record.error.is_some()).count(), 12);
+        assert!(log.iter().filter(|record| record.key_id.is_none()).all(|record| record.error.is_some()));
+        assert_eq!(log.iter().filter(|record| record.key_id.as_deref() == Some(desk_id.as_str())).count(), 8);
+        assert_eq!(service.request_log.len(), 23);
+    }
+
+    #[test]
+    fn json_parsing_accepts_what_the_exports_write_and_refuses_the_rest() {
+        let parsed = parse_json(r#" {"a": [1, -2.5e3, true, null], "b": {"c": "line\nbreak \"quoted\" \u00e9\/"}, "d": []} "#).unwrap();
+        assert_eq!(parsed.get("a").map(|a| format!("{:?}", a)), Some("Array([Number(1.0), Number(-2500.0), Bool(true), Null])".to_string()));
+        assert_eq!(parsed.get("b").and_then(|b| b.get("c")).and_then(JsonValue::as_str), Some("line\nbreak \"quoted\" \u{e9}/"));
+        assert_eq!(parse_json(&json_string("tab\t \u{1} ü")).unwrap().as_str(), Some("tab\t \u{1} ü"));
+
+        for bad in ["", "{", "[1,]", "[1 2]", "{\"a\" 1}", "{a: 1}", "nul", "tru", "\"open", "\"\\x\"", "\"\\u12\"", "\"\\u12g4\"", "1 2", "--1", "[\"a\"]]"] {
+            assert!(parse_json(bad).is_err(), "{}", bad);
+        }
+        // Nesting is bounded rather than recursing until the stack runs out
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_json(&nested(JSON_MAX_DEPTH)).is_ok());
+        assert!(parse_json(&nested(JSON_MAX_DEPTH + 1)).is_err());
+        assert!(parse_json(&"[".repeat(1 << 20)).is_err());
+        assert!(parse_json(&"{\"a\":".repeat(100_000)).is_err());
+
+        let mut trade = Trade::new(7, day(3), "AAPL".to_string(), 100, 189.25, Side::Sell).with_account("EQ1").with_broker("GS", Decimal::from(1.5));
+        trade.strategy = Some("pairs \"A\"".to_string());
+        let round_trip = trade_from_json(&parse_json(&trade_to_json(&trade)).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", round_trip), format!("{:?}", trade));
+        assert!(trade_from_json(&parse_json(r#"{"trade_id": 1}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn fix_execution_reports_book_correct_and_bust_and_damage_is_refused() {
+        let report = |exec_type: &str, exec_id: &str, exec_ref_id: Option<&str>, last_qty: &str, last_px: &str| {
+            let mut message = FixMessage::new("8")
+                .field(37, 7001)
+                .field(17, exec_id)
+                .field(150, exec_type)
+                .field(55, "AAPL")
+                .field(54, 1)
+                .field(32, last_qty)
+                .field(31, last_px)
+                .field(14, last_qty)
+                .field(151, 0)
+                .field(60, "20240501-14:30:00.250");
+            if let Some(exec_ref_id) = exec_ref_id {
+                message = message.field(19, exec_ref_id);
+            }
+            message.encode()
+        };
+        let mut repo = TradeRepository::new();
+        let booked = match repo.apply_fix_execution(&report("F", "E1", None, "100", "150.25")).unwrap() {
+            FixOutcome::Booked(trade_id) => trade_id,
+            other => panic!("expected a booking, got {:?}", other),
+        };
+        assert_eq!(repo.trades.get(&booked).map(|trade| (trade.quantity, trade.price, trade.trade_date)), Some((100, Decimal::from(150.25), day(1))));
+        assert!(matches!(repo.apply_fix_execution(&report("G", "E2", Some("E1"), "80", "150.5")), Ok(FixOutcome::Corrected(trade_id)) if trade_id == booked));
+        assert_position(&repo, "AAPL", 80, 150.5, 0.0);
+        assert!(matches!(repo.apply_fix_execution(&report("0", "E3", None, "0", "0")), Ok(FixOutcome::Ignored { .. })));
+        assert!(matches!(repo.apply_fix_execution(&report("H", "E4", Some("E1"), "80", "150.5")), Ok(FixOutcome::Cancelled(trade_id)) if trade_id == booked));
+        assert!(repo.apply_fix_execution(&report("H", "E5", Some("E1"), "80", "150.5")).is_err());
+
+        // Pipes stand in for SOH, as in logs
+        let piped = report("F", "E6", None, "10", "151").replace(FIX_SOH, "|");
+        assert!(matches!(FixMessage::parse(&piped).map(|message| message.get(17).map(str::to_string)), Ok(Some(id)) if id == "E6"));
+        let good = report("F", "E7", None, "10", "151");
+        let damaged = [
+            good.replace("55=AAPL", "55=AAPX"),
+            good.replace("9=", "9=1"),
+            good.replacen("8=FIX.4.4", "35=8", 1),
+            good.replace("35=8", "35=D"),
+            "garbage".to_string(),
+            "8=FIX.4.4|x=1|".to_string(),
+            String::new(),
+            report("F", "E8", None, "1e20", "151"),
+            report("F", "E9", None, "10.5", "151"),
+            report("F", "E10", None, "10", "nan"),
+            report("F", "E11", None, "10", "1e999"),
+            report("F", "E12", None, "10", "151").replace("20240501-14:30:00.250", "yesterday"),
+        ];
+        for message in &damaged {
+            assert!(repo.apply_fix_execution(message).is_err(), "{}", message.replace(FIX_SOH, "|"));
+        }
+        assert_eq!(repo.trades.len(), 1);
+    }
+
+    #[cfg(feature = "server")]
+    fn http_request(method: &str, path: &str, body: &str) -> Result<HttpRequest, String> {
+        HttpRequest::read(&mut format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).as_bytes())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn http_bodies_with_unrepresentable_numbers_are_refused_before_locking() {
+        let status = |method: &str, path: &str, body: &str| http_service_request(&http_request(method, path, body).unwrap()).err().map(|response| response.status);
+        assert_eq!(status("PATCH", "/trades/1", r#"{"quantity":100,"price":101.5}"#), None);
+        assert_eq!(status("PATCH", "/trades/1", r#"{"quantity":1,"price":1e999}"#), Some(400));
+        assert_eq!(status("PATCH", "/trades/1", r#"{"quantity":4294967396,"price":1}"#), Some(400));
+
+        let trade = trade_to_json(&Trade::new(1, day(1), "AAPL".to_string(), 100, 10.0, Side::Buy).with_broker("GS", Decimal::from(1)));
+        assert_eq!(status("POST", "/trades", &trade), None);
+        for (field, bad) in [("\"price\":10", "\"price\":1e999"), ("\"commission\":1", "\"commission\":-1e999"), ("\"quantity\":100", "\"quantity\":4294967396")] {
+            assert!(trade.contains(field), "{} in {}", field, trade);
+            assert_eq!(status("POST", "/trades", &trade.replace(field, bad)), Some(400), "{}", bad);
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn http_lines_over_the_limit_are_refused() {
+        assert!(http_request("GET", &format!("/{}", "a".repeat(HTTP_MAX_LINE)), "").is_err());
+        let header = format!("GET /positions HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(HTTP_MAX_LINE));
+        assert!(HttpRequest::read(&mut header.as_bytes()).is_err());
+        assert!(http_request("GET", "/positions", "").is_ok());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn websocket_client_frames_are_unmasked_once_complete() {
+        // A masked "Hello" text frame then a masked close frame, as in RFC 6455 section 5.7
+        let mut received = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0x88, 0x80];
+        assert_eq!(take_websocket_frame(&mut received), Some((0x1, b"Hello".to_vec())));
+        assert_eq!(take_websocket_frame(&mut received), None);
+        received.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(take_websocket_frame(&mut received), Some((0x8, Vec::new())));
+        assert!(received.is_empty());
+        assert_eq!(take_websocket_frame(&mut vec![0x81, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn websocket_frames_round_trip_at_every_length_encoding() {
+        for length in [0, 125, 126, 65535, 65536, 70000] {
+            let payload: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+            let mut frame = websocket_frame(0x1, &payload);
+            assert_eq!(frame[0], 0x81);
+            let partial = frame.len() - 1;
+            assert_eq!(take_websocket_frame(&mut frame[..partial].to_vec()), None, "{}", length);
+            assert_eq!(take_websocket_frame(&mut frame), Some((0x1, payload)), "{}", length);
+            assert!(frame.is_empty());
+        }
+        assert_eq!(take_websocket_frame(&mut vec![0x81]), None);
+        assert_eq!(take_websocket_frame(&mut vec![0x81, 0xfe, 0x00]), None);
+        // RFC 6455 section 1.3
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn http_routes_answer_with_the_status_of_what_the_service_did() {
+        let mut service = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+        let admin = service.bootstrap_admin_key("operator", Utc::now());
+        let (_, viewer) = service.keys.create("risk", Role::Viewer, None, None, Utc::now());
+        let mut call = |token: &str, method: &str, path: &str, body: &str| -> (u16, String) {
+            let response = match http_request(method, path, body).map_err(|e| HttpResponse::error(400, &e)).and_then(|request| http_service_request(&request)) {
+                Ok(service_request) => route_http(&mut service, token, service_request, Utc::now()),
+                Err(response) => response,
+            };
+            (response.status, response.body)
+        };
+        let trade = trade_to_json(&Trade::new(220, day(1), "AMD".to_string(), 300, 118.0, Side::Buy).with_account("EQ1"));
+
+        assert_eq!(call(&admin, "POST", "/trades", &trade).0, 201);
+        assert_eq!(call(&admin, "POST", "/trades", &trade).0, 409);
+        assert_eq!(call(&viewer, "POST", "/trades", &trade.replace("220", "221")).0, 403);
+        assert_eq!(call("key-0001.wrong", "GET", "/positions", "").0, 401);
+        assert_eq!(call(&admin, "POST", "/trades", "{\"trade_id\": 221}").0, 400);
+        assert_eq!(call(&admin, "PATCH", "/trades/220", "{\"quantity\": 250, \"price\": 117.5}").0, 200);
+        assert_eq!(call(&admin, "PATCH", "/trades/999", "{\"quantity\": 250, \"price\": 117.5}").0, 404);
+        assert_eq!(call(&admin, "PATCH", "/trades/abc", "{\"quantity\": 250, \"price\": 117.5}").0, 400);
+        let (status, positions) = call(&viewer, "GET", "/positions?account=EQ1", "");
+        assert_eq!(status, 200);
+        assert!(positions.contains("\"quantity\":250"), "{}", positions);
+        assert_eq!(call(&viewer, "GET", "/pnl", "").0, 200);
+        let (status, trades) = call(&viewer, "GET", "/trades?instrument=AMD&side=buy", "");
+        assert_eq!((status, trades.matches("\"trade_id\"").count()), (200, 1));
+        assert_eq!(call(&viewer, "GET", "/trades?side=sideways", "").0, 400);
+        assert_eq!(call(&admin, "DELETE", "/trades/220", "").0, 200);
+        assert_eq!(call(&admin, "DELETE", "/trades/220", "").0, 409);
+        assert_eq!(call(&admin, "PUT", "/positions", "").0, 405);
+        assert_eq!(call(&admin, "GET", "/stream", "").0, 400);
+        assert_eq!(call(&admin, "GET", "/nowhere", "").0, 404);
+        assert_eq!(call(&admin, "POST", "/trades", &"[".repeat(100_000)).0, 400);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn streams_give_their_connection_back_when_the_client_leaves() {
+        let mut service = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+        let token = service.bootstrap_admin_key("operator", Utc::now());
+        let mut server = PositionServer::start("127.0.0.1:0", Arc::new(Mutex::new(service))).unwrap();
+        let connect = |request: &str| {
+            let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "{}", request).unwrap();
+            let mut reply = [0; 12];
+            std::io::Read::read_exact(&mut stream, &mut reply).unwrap();
+            (stream, String::from_utf8_lossy(&reply).into_owned())
+        };
+        let upgrade = format!("GET /stream HTTP/1.1\r\nAuthorization: Bearer {}\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", token);
+        let positions = format!("GET /positions HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", token);
+
+        let streams: Vec<_> = (0..MAX_CONNECTIONS).map(|_| connect(&upgrade)).collect();
+        assert!(streams.iter().all(|(_, reply)| reply == "HTTP/1.1 101"));
+        assert_eq!(connect(&positions).1, "HTTP/1.1 503");
+
+        // Half the clients close properly, the rest just drop the connection
+        for (i, (mut stream, _)) in streams.into_iter().enumerate() {
+            if i % 2 == 0 {
+                stream.write_all(&[0x88, 0x80, 1, 2, 3, 4]).unwrap();
+            }
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while connect(&positions).1 != "HTTP/1.1 200" {
+            assert!(Instant::now() < deadline, "connection slots never came back");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        server.shutdown();
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn grpc_refuses_a_poisoned_service_and_drops_abandoned_subscriptions() {
+        use grpc::position_service_server::PositionService;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let mut service = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+        let token = service.bootstrap_admin_key("operator", Utc::now());
+        let grpc_service = GrpcPositionService { service: Arc::new(Mutex::new(service)) };
+        let subscription = runtime.block_on(grpc_service.subscribe_pnl(grpc_request(grpc::SubscribeRequest::default(), &token).unwrap())).unwrap();
+        drop(subscription);
+        // Nothing is booked, so only the poll can notice the client has gone
+        std::thread::sleep(GRPC_STREAM_POLL_INTERVAL * 4);
+        let update = PositionUpdate::Position { account_id: None, instrument: "AAPL".to_string(), previous_quantity: 0, quantity: 100, average_price: Decimal::from(10.0) };
+        let sent = grpc_service.lock().unwrap().repository.position_streams[0].sender.try_send(update);
+        assert!(matches!(sent, Err(std::sync::mpsc::TrySendError::Disconnected(_))));
+
+        let shared = Arc::clone(&grpc_service.service);
+        let _ = std::thread::spawn(move || {
+            let _held = shared.lock().unwrap();
+            panic!("handler panicked");
+        }).join();
+        let pnl = grpc_service.handle(&grpc_request((), &token).unwrap(), ServiceRequest::Pnl { account_id: None });
+        assert_eq!(pnl.unwrap_err().code(), tonic::Code::Internal);
+        let subscription = runtime.block_on(grpc_service.subscribe_pnl(grpc_request(grpc::SubscribeRequest::default(), &token).unwrap()));
+        assert_eq!(subscription.err().map(|status| status.code()), Some(tonic::Code::Internal));
+    }
+}
@@ -0,0 +1,57 @@
+// Import and end of day over the demo dataset.
+// Closes arrive as a CSV price file and trades through the CSV feed adapter, one
+// trading day at a time. After each day the marks move to the day's close, the
+// session-aware end of day records them, and the day's P&L is explained; the
+// explains are rolled up by month.
+// Usage: cargo run --release --example import_and_eod -- [seed]
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate};
+use rustopos::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let seed: u64 = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(7);
+    let dataset = DemoDataset::generate(seed, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), 252, 16);
+    let mut repo = TradeRepository::new();
+    dataset.load_reference_data(&mut repo);
+
+    // The vendor's price file is loaded into a store of its own, so the repository
+    // only learns each close at that day's end of day
+    let mut vendor_closes: TimeSeriesStore<Price> = TimeSeriesStore::new();
+    let imported = vendor_closes.import_csv(&dataset.closes_csv(), "date,instrument,close", Decimal::parse)?;
+    println!("Imported {} closes for {} instruments", imported, vendor_closes.names().count());
+
+    let mut months: BTreeMap<(i32, u32), (usize, PnlExplainLine)> = BTreeMap::new();
+    let mut quarantined = 0;
+    for (day, date) in dataset.trading_days().into_iter().enumerate() {
+        let summary = repo.ingest_from(&mut CsvSourceAdapter::new("demo-feed", &dataset.trades_csv(date, date), 100))?;
+        quarantined += summary.quarantined;
+        for instrument in repo.positions.keys().cloned().collect::<Vec<String>>() {
+            if let Some(close) = vendor_closes.get(&instrument, date) {
+                repo.update_market_price(&instrument, close)?;
+            }
+        }
+        let eod = repo.run_end_of_day(date, date.and_hms_opt(23, 0, 0).unwrap().and_utc());
+        let missing: Vec<&str> = eod.iter().filter(|close| !matches!(close.status, EodCloseStatus::Recorded)).map(|close| close.instrument.as_str()).collect();
+        if !missing.is_empty() {
+            println!("{}: no close recorded for {}", date, missing.join(", "));
+        }
+        // The first day has no prior close to explain against
+        if day == 0 {
+            continue;
+        }
+        let explain = repo.pnl_explain(date)?;
+        let (trades, month) = months.entry((date.year(), date.month())).or_insert_with(|| (0, PnlExplainLine::default()));
+        *trades += summary.ingested;
+        month.add(&explain.total());
+    }
+
+    println!("\n{:<8} {:>7} {:>14} {:>14} {:>14} {:>12} {:>12} {:>12}", "Month", "Trades", "P&L Change", "New Trades", "Price Move", "Fees", "FX", "Other");
+    for ((year, month), (trades, line)) in &months {
+        println!("{}-{:02} {:>7} {:>14.2} {:>14.2} {:>14.2} {:>12.2} {:>12.2} {:>12.2}",
+                 year, month, trades, line.change(), line.new_trades, line.price_move, line.fees, line.fx, line.unexplained);
+    }
+    println!("Quarantined records: {}", quarantined);
+    repo.print_pnl_rollup(dataset.end_date);
+    Ok(())
+}
@@ -0,0 +1,42 @@
+// Reports over a year of the demo dataset.
+// The whole year is booked as one batch, then the book is reported the ways a desk
+// head, risk and the COO would look at it.
+// Usage: cargo run --release --example reports -- [seed]
+use std::time::Instant;
+
+use chrono::NaiveDate;
+use rustopos::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let seed: u64 = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(7);
+    let dataset = DemoDataset::generate(seed, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), 252, 16);
+    let mut repo = TradeRepository::new();
+    let started = Instant::now();
+    let booked = dataset.load(&mut repo)?;
+    println!("Booked {} trades ({} rejected) in {:.1?}", booked.accepted, booked.rejected, started.elapsed());
+    let as_of = dataset.end_date;
+
+    println!("\n=== By Asset Class and Currency ===");
+    print_aggregate_node(&repo.aggregate_as_of(as_of, &[GroupBy::AssetClass, GroupBy::Currency]), 0);
+    repo.print_pnl_rollup(as_of);
+    println!("\n=== Sector Contributions ===");
+    for sector in repo.pnl_contribution(as_of, Dimension::Sector).children {
+        println!("{:<12} weight {:>6.1}% | P&L {:>14.2} ({:+.1}% of total)", sector.name, sector.weight * 100.0, sector.total_pnl, sector.pnl_share * 100.0);
+    }
+    repo.print_broker_cost_report();
+    print_var_report(&repo.value_at_risk(as_of, &VarConfig { confidence_levels: vec![0.95, 0.99, 0.995], lookback_days: 250 })?);
+
+    // SPY's closes double as the benchmark series
+    for (instrument, date, close) in dataset.closes.iter().filter(|(instrument, _, _)| instrument == "SPY") {
+        repo.set_benchmark_price(instrument, *date, *close);
+    }
+    repo.set_risk_free_rate(0.02);
+    print_performance_report(&repo.performance(50_000_000.0, dataset.start_date, as_of)?);
+    print_benchmark_comparison(&repo.benchmark_comparison("SPY", 50_000_000.0, dataset.start_date, as_of)?);
+
+    println!("\n=== Trade Statistics ===");
+    for mode in [AnalyticsMode::Exact, AnalyticsMode::Approximate { sample_size: 500, seed }] {
+        print_trade_statistics(&repo.trade_statistics(&TradeFilter::new(), mode));
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+// The service layer over the demo dataset, served over HTTP (`--features server`).
+// The first quarter is booked directly; the next week's flow then comes through
+// the HTTP server, each account's trades posted with that desk's key. A firm-wide
+// viewer reads positions and P&L, a desk trying another desk's account is refused,
+// and the admin reads the request log.
+// Usage: cargo run --example service --features server -- [seed]
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDate;
+use rustopos::*;
+
+// One request on its own connection; the status line and the body
+fn send(address: std::net::SocketAddr, token: &str, method: &str, target: &str, body: &str) -> std::io::Result<(String, String)> {
+    let mut stream = std::net::TcpStream::connect(address)?;
+    write!(stream, "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}", method, target, token, body.len(), body)?;
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response)?;
+    let status = response.lines().next().unwrap_or_default().to_string();
+    Ok((status, response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string()))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let seed: u64 = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(7);
+    let dataset = DemoDataset::generate(seed, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), 67, 16);
+    let days = dataset.trading_days();
+    let (quarter_end, week_end) = (days[61], days[66]);
+
+    let mut repo = TradeRepository::new();
+    dataset.load_reference_data(&mut repo);
+    for (instrument, date, close) in &dataset.closes {
+        repo.record_close_price(instrument, *date, *close);
+    }
+    let quarter: Vec<Trade> = dataset.trades.iter().filter(|trade| trade.trade_date <= quarter_end).cloned().collect();
+    repo.add_trades_batch(quarter)?;
+
+    let opened = quarter_end.and_hms_opt(22, 0, 0).unwrap().and_utc();
+    let mut service = TradeService::new(repo, ApiKeyStore::new());
+    let admin = service.bootstrap_admin_key("operator", opened);
+    let mut desk_keys = BTreeMap::new();
+    for (account_id, _, _, _) in DEMO_ACCOUNTS {
+        let request = ServiceRequest::CreateKey {
+            name: format!("{} desk", account_id),
+            role: Role::Trader,
+            accounts: Some(BTreeSet::from([account_id.to_string()])),
+            expires_at: None,
+        };
+        if let ServiceResponse::Key { token, .. } = service.handle(&admin, request, opened)? {
+            desk_keys.insert(account_id.to_string(), token);
+        }
+    }
+    let viewer = match service.handle(&admin, ServiceRequest::CreateKey { name: "risk".to_string(), role: Role::Viewer, accounts: None, expires_at: None }, opened)? {
+        ServiceResponse::Key { token, .. } => token,
+        other => return Err(format!("expected a key, got {:?}", other).into()),
+    };
+
+    let service = Arc::new(Mutex::new(service));
+    let mut server = PositionServer::start("127.0.0.1:0", Arc::clone(&service))?;
+    let address = server.local_addr();
+    println!("Listening on {}", address);
+
+    let mut refused = 0;
+    for trade in dataset.trades.iter().filter(|trade| trade.trade_date > quarter_end && trade.trade_date <= week_end) {
+        let (status, _) = send(address, &desk_keys[&trade.account_id], "POST", "/trades", &trade_to_json(trade))?;
+        if !status.contains(" 201 ") {
+            refused += 1;
+        }
+    }
+    // The US desk tries to book into the macro account
+    let stray = dataset.trades.iter().find(|trade| trade.account_id == "ACC-MACRO").unwrap().clone();
+    let stray = Trade { trade_id: TradeId::from(1_000_000), trade_date: week_end, ..stray };
+    let (status, body) = send(address, &desk_keys["ACC-US"], "POST", "/trades", &trade_to_json(&stray))?;
+    println!("Stray booking: {} {}", status, body);
+    let (status, body) = send(address, &viewer, "GET", "/pnl", "")?;
+    println!("Book P&L: {} {}", status, body);
+    let (status, body) = send(address, &viewer, "GET", "/positions?account=ACC-US", "")?;
+    println!("ACC-US positions: {} ({} bytes)", status, body.len());
+    server.shutdown();
+
+    let mut service = service.lock().map_err(|_| "service lock poisoned")?;
+
+    let closed = week_end.and_hms_opt(22, 0, 0).unwrap().and_utc();
+    for instrument in ["AAPL", "SAP", "ESZ2", "BTC"] {
+        if let ServiceResponse::Position(Some(position)) = service.handle(&viewer, ServiceRequest::Position { instrument: instrument.to_string(), account_id: None }, closed)? {
+            println!("{}: {} units at {:.2}, realized {:.2}", instrument, position.quantity, position.average_price, position.realized_pnl);
+        }
+    }
+    if let ServiceResponse::RequestLog(records) = service.handle(&admin, ServiceRequest::RequestLog, closed)? {
+        let failed = records.iter().filter(|record| record.error.is_some()).count();
+        println!("\n{} requests, {} refused ({} of the week's trades)", records.len(), failed, refused);
+        print_request_log(&records[records.len().saturating_sub(10)..]);
+    }
+    Ok(())
+}
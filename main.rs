@@ -0,0 +1,2124 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use chrono::{NaiveDate, NaiveTime, Utc};
+use rustopos::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().nth(1).as_deref() == Some("repl") {
+        let stdin = std::io::stdin();
+        run_repl(TradeRepository::new(), stdin.lock(), std::io::stdout())?;
+        return Ok(());
+    }
+
+    let mut repo = TradeRepository::new();
+
+    // Add trades with different dates and types
+    repo.add_trade(Trade::new(1, NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), "AAPL".to_string(), 100, 100.0, Side::Buy))?;
+    repo.add_trade(Trade::new_with_type(2, NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(), "AAPL".to_string(), 50, 110.0, Side::Buy, TradeType::Limit))?;
+    repo.add_trade(Trade::new(3, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), "MSFT".to_string(), 200, 150.0, Side::Buy))?;
+    repo.add_trade(Trade::new(4, NaiveDate::from_ymd_opt(2022, 1, 4).unwrap(), "AAPL".to_string(), 20, 120.0, Side::Sell))?;
+    repo.add_trade(Trade::new(5, NaiveDate::from_ymd_opt(2022, 1, 5).unwrap(), "MSFT".to_string(), 50, 160.0, Side::Buy))?;
+    repo.add_trade(Trade::new(6, NaiveDate::from_ymd_opt(2022, 1, 6).unwrap(), "AAPL".to_string(), 30, 125.0, Side::Sell))?;
+
+    // Set current market prices for P&L calculations
+    repo.update_market_price("AAPL", 135.0)?;
+    repo.update_market_price("MSFT", 155.0)?;
+
+    // Print positions with P&L
+    repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
+
+    // Test advanced filtering
+    println!("\n=== Advanced Trade Filtering ===");
+    
+    // Filter by instrument and side
+    let filter = TradeFilter::new()
+        .instrument("AAPL".to_string())
+        .side(Side::Buy);
+    
+    let aapl_buys = repo.filter_trades(&filter);
+    println!("AAPL Buy trades: {}", aapl_buys.len());
+    for trade in aapl_buys {
+        println!("  {:?}", trade);
+    }
+
+    // Filter by date range and quantity
+    let filter = TradeFilter::new()
+        .date_range(
+            NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(),
+            NaiveDate::from_ymd_opt(2022, 1, 4).unwrap()
+        )
+        .quantity_range(50, 200);
+    
+    repo.print_trade_analysis(&filter);
+
+    // Filter by order type and price
+    let filter = TradeFilter::new()
+        .trade_type(TradeType::Market)
+        .price_range(100.0, 200.0);
+    println!("Market trades priced 100-200: {}", repo.filter_trades(&filter).len());
+
+    // Portfolio P&L analysis
+    let (realized, unrealized, market_value) = repo.calculate_portfolio_pnl();
+    println!("\n=== Portfolio P&L Summary ===");
+    println!("Total Realized P&L: ${:.2}", realized);
+    println!("Total Unrealized P&L: ${:.2}", unrealized);
+    println!("Total Market Value: ${:.2}", market_value);
+    println!("Total P&L: ${:.2}", realized + unrealized);
+
+    // Top performers
+    println!("\n=== Top Performers (by Unrealized P&L) ===");
+    let top_performers = repo.get_top_performers(5, true);
+    for (instrument, realized, unrealized) in top_performers {
+        println!("{}: Realized: ${:.2}, Unrealized: ${:.2}, Total: ${:.2}", 
+            instrument, realized, unrealized, realized + unrealized);
+    }
+
+    // Test amend by date with P&L impact
+    println!("\n=== Testing Amend with P&L Impact ===");
+    println!("Before amend:");
+    repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
+    
+    match repo.amend_trade_by_date("AAPL", NaiveDate::from_ymd_opt(2022, 1, 2).unwrap(), 75, 115.0) {
+        Ok(()) => {
+            println!("\nAfter amending AAPL trade from 2022-01-02:");
+            repo.print_position_summary_as_of(NaiveDate::from_ymd_opt(2022, 1, 6).unwrap());
+        },
+        Err(e) => println!("Error: {}", e),
+    }
+
+    // Multi-criteria search
+    println!("\n=== Multi-Criteria Search ===");
+    let multi_results = repo.get_trades_by_criteria(
+        vec!["AAPL".to_string(), "MSFT".to_string()],
+        Some(Side::Buy),
+        Some((
+            NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
+            NaiveDate::from_ymd_opt(2022, 1, 5).unwrap()
+        ))
+    );
+    
+    println!("Buy trades for AAPL/MSFT from Jan 1-5:");
+    for trade in multi_results {
+        println!("  {} {} {} shares @ ${:.2} on {}", 
+            trade.instrument, 
+            match trade.side { Side::Buy => "BUY", Side::Sell => "SELL" },
+            trade.quantity, 
+            trade.price, 
+            trade.trade_date
+        );
+    }
+
+    // Backdated booking updates every later day in the daily position table
+    println!("\n=== Backdated Booking ===");
+    repo.add_trade(Trade::new(7, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), "AAPL".to_string(), 25, 105.0, Side::Buy))?;
+    let history = repo.get_position_history(
+        "AAPL",
+        NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
+        NaiveDate::from_ymd_opt(2022, 1, 7).unwrap()
+    );
+
+    for (date, position) in history {
+        println!("{}: {} shares @ ${:.2} | Realized P&L: ${:.2}", date, position.quantity, position.average_price, position.realized_pnl);
+    }
+
+    // Portfolio state through the framework-facing trait
+    println!("\n=== PositionProvider View ===");
+    print_portfolio_state(&repo, NaiveDate::from_ymd_opt(2022, 1, 4).unwrap());
+
+    // A failing step rolls back every change made earlier in the transaction
+    println!("\n=== Transactions ===");
+    let result = repo.transaction(|tx| {
+        tx.add_trade(Trade::new(8, NaiveDate::from_ymd_opt(2022, 1, 7).unwrap(), "MSFT".to_string(), 100, 158.0, Side::Sell))?;
+        tx.update_price("MSFT", 158.0)?;
+        println!("MSFT inside transaction: {} shares", tx.get_position("MSFT").unwrap().quantity);
+        tx.amend(8, 120, 158.0)?;
+        println!("Trade 8 amended inside transaction to {} shares", tx.active_trade(8)?.quantity);
+        tx.cancel(99)
+    });
+    println!("Transaction result: {:?}", result);
+    println!("MSFT after rollback: {} shares, mark {:?}", repo.get_position("MSFT").unwrap().quantity, repo.get_market_price("MSFT"));
+
+    let result = repo.transaction(|tx| {
+        tx.add_trade(Trade::new(8, NaiveDate::from_ymd_opt(2022, 1, 7).unwrap(), "MSFT".to_string(), 100, 158.0, Side::Sell))?;
+        tx.update_price("MSFT", 158.0)
+    });
+    println!("Transaction result: {:?}", result);
+    println!("MSFT after commit: {} shares, mark {:?}", repo.get_position("MSFT").unwrap().quantity, repo.get_market_price("MSFT"));
+
+    // Futures expiry with a roll into the next contract
+    println!("\n=== Contract Expiry ===");
+    repo.register_contract(DerivativeContract::new("ESH2".to_string(), "ES".to_string(), ContractKind::Future, NaiveDate::from_ymd_opt(2022, 3, 18).unwrap(), RollRule::RollInto("ESM2".to_string())));
+    repo.register_contract(DerivativeContract::new("ESM2".to_string(), "ES".to_string(), ContractKind::Future, NaiveDate::from_ymd_opt(2022, 6, 17).unwrap(), RollRule::Close));
+    repo.add_trade(Trade::new(9, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "ESH2".to_string(), 10, 4500.0, Side::Buy))?;
+    repo.update_market_price("ESM2", 4410.0)?;
+
+    let settlement_prices = HashMap::from([("ESH2".to_string(), Decimal::from(4400))]);
+    for event in repo.process_expiries(NaiveDate::from_ymd_opt(2022, 3, 18).unwrap(), &settlement_prices) {
+        println!("  {}", event);
+    }
+    println!("ESH2: {} contracts, ESM2: {} contracts", repo.get_position("ESH2").unwrap().quantity, repo.get_position("ESM2").unwrap().quantity);
+
+    // Continuous ES series and P&L across the roll
+    println!("\n=== Continuous Futures Series ===");
+    for (date, esh2, esm2) in [((3, 16), 4450.0, 4460.0), ((3, 17), 4420.0, 4430.0), ((3, 18), 4400.0, 4410.0), ((3, 21), 4425.0, 4425.0), ((3, 22), 4440.0, 4440.0)] {
+        let date = NaiveDate::from_ymd_opt(2022, date.0, date.1).unwrap();
+        if date <= NaiveDate::from_ymd_opt(2022, 3, 18).unwrap() {
+            repo.record_close_price("ESH2", date, esh2);
+        }
+        repo.record_close_price("ESM2", date, esm2);
+    }
+    for point in repo.continuous_series("ES") {
+        println!("  {} {} raw ${:.2} adjusted ${:.2}", point.date, point.contract, point.raw_price, point.adjusted_price);
+    }
+    for (date, pnl) in repo.root_pnl_history("ES", NaiveDate::from_ymd_opt(2022, 3, 16).unwrap(), NaiveDate::from_ymd_opt(2022, 3, 22).unwrap()) {
+        println!("  {} ES P&L: ${:.2}", date, pnl);
+    }
+
+    // Long KO / short PEP pair, then the short leg is partially covered
+    repo.add_trade(Trade::new(20, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "KO".to_string(), 300, 60.0, Side::Buy))?;
+    repo.add_trade(Trade::new(21, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "PEP".to_string(), 100, 170.0, Side::Sell))?;
+    if let Err(e) = repo.link_pair("KO/PEP", "KO", "PEP") {
+        println!("Error: {}", e);
+    }
+    repo.add_trade(Trade::new(22, NaiveDate::from_ymd_opt(2022, 2, 10).unwrap(), "PEP".to_string(), 20, 165.0, Side::Buy))?;
+    repo.update_market_price("KO", 61.5)?;
+    repo.update_market_price("PEP", 166.0)?;
+    repo.print_pair_reports();
+
+    // A EUR-based account trading a EUR listing, consolidated into USD
+    repo.set_account_currency("EU-DESK", "EUR");
+    repo.set_instrument_currency("SAP", "EUR");
+    repo.add_trade(Trade::new(23, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "SAP".to_string(), 100, 120.0, Side::Buy).with_account("EU-DESK"))?;
+    repo.update_market_price("SAP", 125.0)?;
+    let opening_rates = FxRates::new("USD").rate("EUR", 1.10);
+    let closing_rates = FxRates::new("USD").rate("EUR", 1.12);
+    repo.print_firm_currency_report(&closing_rates, &opening_rates);
+
+    // Historical FX rates imported from CSV and used for firm-currency P&L history
+    println!("\n=== Historical FX Rates ===");
+    let csv = "date,currency,rate\n2022-02-01,EUR,1.10\n2022-02-04,EUR,1.13\n";
+    match repo.fx_rates.import_csv(csv) {
+        Ok(count) => println!("Imported {} FX rates", count),
+        Err(e) => println!("Error: {}", e),
+    }
+    repo.fx_rates.fallback = FxFallback::Interpolate;
+    repo.record_close_price("SAP", NaiveDate::from_ymd_opt(2022, 2, 3).unwrap(), 123.0);
+    match repo.firm_pnl_history(NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 2, 4).unwrap()) {
+        Ok(history) => {
+            for (date, pnl) in history {
+                println!("  {} Firm P&L: ${:.2}", date, pnl);
+            }
+        },
+        Err(e) => println!("Error: {}", e),
+    }
+    match repo.firm_currency_report_as_of(NaiveDate::from_ymd_opt(2022, 2, 4).unwrap()) {
+        Ok(report) => println!("Translation difference on 2022-02-04: ${:.2}", report.total_translation_difference),
+        Err(e) => println!("Error: {}", e),
+    }
+    // Desks that must not estimate a rate refuse the conversion instead
+    repo.fx_rates.fallback = FxFallback::Fail;
+    match repo.fx_rates.rate_on("EUR", NaiveDate::from_ymd_opt(2022, 2, 2).unwrap()) {
+        Ok(rate) => println!("EUR on 2022-02-02: {}", rate),
+        Err(e) => println!("Without a fallback: {}", e),
+    }
+    repo.fx_rates.fallback = FxFallback::Interpolate;
+
+    // OTC-style trades with two subsidiaries of the same bank
+    repo.register_counterparty(Counterparty::new("BIGBANK", "Big Bank Holdings", None));
+    repo.register_counterparty(Counterparty::new("BIGBANK-LDN", "Big Bank London", Some("BIGBANK")));
+    repo.register_counterparty(Counterparty::new("BIGBANK-NY", "Big Bank New York", Some("BIGBANK")));
+    repo.add_trade(Trade::new(24, NaiveDate::from_ymd_opt(2022, 2, 2).unwrap(), "IBM".to_string(), 500, 130.0, Side::Buy).with_counterparty("BIGBANK-LDN"))?;
+    repo.add_trade(Trade::new(25, NaiveDate::from_ymd_opt(2022, 2, 3).unwrap(), "IBM".to_string(), 300, 131.0, Side::Sell).with_counterparty("BIGBANK-NY"))?;
+    repo.add_trade(Trade::new(26, NaiveDate::from_ymd_opt(2022, 2, 3).unwrap(), "IBM".to_string(), 100, 131.0, Side::Buy).with_counterparty("OTHER"))?;
+    repo.update_market_price("IBM", 132.0)?;
+    repo.print_counterparty_exposure_report();
+
+    // Counterparty confirmations for the IBM trades
+    repo.import_confirmations(vec![
+        Confirmation { confirm_id: "LDN-001".to_string(), counterparty: "BIGBANK-LDN".to_string(), trade_date: NaiveDate::from_ymd_opt(2022, 2, 2).unwrap(), instrument: "IBM".to_string(), quantity: 500, price: Decimal::from(130.001), side: Side::Buy, received_date: NaiveDate::from_ymd_opt(2022, 2, 3).unwrap() },
+        Confirmation { confirm_id: "NY-001".to_string(), counterparty: "BIGBANK-NY".to_string(), trade_date: NaiveDate::from_ymd_opt(2022, 2, 3).unwrap(), instrument: "IBM".to_string(), quantity: 250, price: Decimal::from(131), side: Side::Sell, received_date: NaiveDate::from_ymd_opt(2022, 2, 4).unwrap() },
+        Confirmation { confirm_id: "NY-002".to_string(), counterparty: "BIGBANK-NY".to_string(), trade_date: NaiveDate::from_ymd_opt(2022, 2, 3).unwrap(), instrument: "MSFT".to_string(), quantity: 10, price: Decimal::from(150), side: Side::Buy, received_date: NaiveDate::from_ymd_opt(2022, 2, 4).unwrap() },
+    ]);
+    repo.print_confirmation_report(NaiveDate::from_ymd_opt(2022, 2, 8).unwrap(), &MatchTolerance { price: Decimal::from(0.01), quantity: 0, trade_date_days: 0 });
+
+    // Feed ingestion through the example CSV adapter, with bad records quarantined
+    println!("\n=== Feed Ingestion ===");
+    let feed = "trade_id,date,instrument,side,quantity,price,account\n\
+        30,2022-02-07,AAPL,BUY,40,140.0,\n\
+        31,2022-02-07,MSFT,SELL,abc,160.0,\n\
+        32,2022-02-07,AAPL,HOLD,10,140.0,\n\
+        1,2022-02-07,AAPL,BUY,10,140.0,\n\
+        33,2022-02-08,SAP,BUY,20,126.0,EU-DESK\n";
+    // Give up on the feed after three reconnects in one run
+    repo.set_feed_reconnect_limit(3);
+    match repo.ingest_from(&mut CsvSourceAdapter::new("csv-demo", feed, 2)) {
+        Ok(summary) => println!("{:?}", summary),
+        Err(e) => println!("Error: {}", e),
+    }
+    for record in &repo.quarantine {
+        println!("  Quarantined from {}: {} ({})", record.source, record.record, record.reason);
+    }
+
+    // Drawdowns on a sample equity curve
+    println!("\n=== Drawdown Alerts ===");
+    let equity: Vec<(NaiveDate, Money)> = [100, 104, 98, 93, 96, 92, 99, 105, 103]
+        .iter()
+        .enumerate()
+        .map(|(day, value)| (NaiveDate::from_ymd_opt(2022, 3, 1).unwrap() + chrono::Duration::days(day as i64), Decimal::from(*value)))
+        .collect();
+    let config = DrawdownAlertConfig { thresholds: vec![0.05, 0.10], cooldown_days: 3 };
+    for alert in drawdown_alerts(&equity, &config) {
+        println!("  {} drawdown {:.1}% crossed {:.0}% (peak ${:.2} on {}, equity ${:.2})",
+            alert.date, alert.drawdown * 100.0, alert.threshold * 100.0, alert.peak_equity, alert.peak_date, alert.equity);
+    }
+    for period in drawdown_periods(&equity) {
+        println!("  Drawdown from {} to trough {} ({:.1}%), recovered {:?}, {} days", period.start, period.trough, period.depth * 100.0, period.recovery, period.length_days);
+    }
+    match repo.equity_curve(1_000_000.0, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 2, 4).unwrap()) {
+        Ok(curve) => println!("  Book drawdown periods: {}", drawdown_periods(&curve).len()),
+        Err(e) => println!("Error: {}", e),
+    }
+
+    // A fund seeded with cash that adds capital halfway through a volatile fortnight
+    let mut fund = TradeRepository::new();
+    let fund_day = |day: u32| NaiveDate::from_ymd_opt(2022, 4, day).unwrap();
+    fund.set_risk_free_rate(0.02);
+    fund.deposit_cash("FUND", "USD", 100_000, fund_day(4))?;
+    fund.add_trade(Trade::new(fund.next_trade_id(), fund_day(4), "SPY".to_string(), 200, 450.0, Side::Buy).with_account("FUND"))?;
+    fund.deposit_cash("FUND", "USD", 50_000, fund_day(11))?;
+    fund.add_trade(Trade::new(fund.next_trade_id(), fund_day(11), "SPY".to_string(), 100, 440.0, Side::Buy).with_account("FUND"))?;
+    for (day, close) in [(4, 452.0), (5, 447.5), (6, 441.0), (7, 444.0), (8, 438.5), (11, 440.0), (12, 446.0), (13, 451.5), (14, 449.0), (15, 455.0)] {
+        fund.record_close_price("SPY", fund_day(day), close);
+    }
+    match fund.performance(0.0, fund_day(4), fund_day(15)) {
+        Ok(report) => print_performance_report(&report),
+        Err(e) => println!("Error: {}", e),
+    }
+    fund.import_benchmark_csv("date,benchmark,price\n2022-04-04,SPX,4582.6\n2022-04-05,SPX,4525.1\n2022-04-06,SPX,4481.2\n\
+        2022-04-07,SPX,4500.2\n2022-04-08,SPX,4488.3\n2022-04-11,SPX,4412.5\n2022-04-12,SPX,4397.5\n2022-04-13,SPX,4446.6\n2022-04-14,SPX,4392.6")?;
+    fund.set_benchmark_price("SPX", fund_day(15), 4392.6);
+    match fund.benchmark_comparison("SPX", 0.0, fund_day(4), fund_day(15)) {
+        Ok(comparison) => print_benchmark_comparison(&comparison),
+        Err(e) => println!("Error: {}", e),
+    }
+
+    // Broker commissions and slippage against arrival prices
+    repo.add_trade(Trade::new(40, NaiveDate::from_ymd_opt(2022, 3, 1).unwrap(), "NVDA".to_string(), 200, 240.5, Side::Buy).with_broker("ALPHA", 8.0).with_arrival_price(240.0))?;
+    repo.add_trade(Trade::new(41, NaiveDate::from_ymd_opt(2022, 3, 2).unwrap(), "NVDA".to_string(), 100, 245.0, Side::Sell).with_broker("ALPHA", 4.0).with_arrival_price(245.2))?;
+    repo.add_trade(Trade::new(42, NaiveDate::from_ymd_opt(2022, 3, 2).unwrap(), "NVDA".to_string(), 100, 244.8, Side::Buy).with_broker("BETA", 2.5))?;
+    repo.print_broker_cost_report();
+
+    // Same history under average cost, FIFO and LIFO
+    repo.print_cost_basis_comparison(&[CostBasisMethod::AverageCost, CostBasisMethod::Fifo, CostBasisMethod::Lifo]);
+
+    // Older trades move to a cold segment; queries and amends still see them
+    println!("\n=== Storage Tiering ===");
+    let sealed = repo.tier_storage(NaiveDate::from_ymd_opt(2022, 3, 1).unwrap(), 30);
+    println!("Sealed {} trades; {} hot, {} cold segment(s) in {} bytes, {} total", sealed, repo.trades.hot.len(), repo.trades.cold.len(), repo.trades.cold_bytes(), repo.trades.len());
+    repo.amend_trade(3, 210, 150.0)?;
+    println!("Amended cold trade 3: {:?} shares, {} hot", repo.trades.get(&TradeId::from(3)).map(|trade| trade.quantity), repo.trades.hot.len());
+    println!("Trades from 2022-01-01 to 2022-01-03: {}", repo.find_trades_by_date(NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 1, 3).unwrap()).len());
+
+    // Structured summary grouped by sector and by account
+    repo.set_instrument_sector("AAPL", "Technology");
+    repo.set_instrument_sector("MSFT", "Technology");
+    repo.set_instrument_sector("KO", "Consumer Staples");
+    repo.set_instrument_sector("PEP", "Consumer Staples");
+    repo.print_position_summary(NaiveDate::from_ymd_opt(2022, 3, 31).unwrap(), Some(Dimension::Sector));
+    let summary = repo.position_summary(NaiveDate::from_ymd_opt(2022, 3, 31).unwrap(), Some(Dimension::Account));
+    for totals in &summary.group_totals {
+        println!("Account {}: Value ${:.2}, P&L ${:.2}", totals.group, totals.market_value, totals.total_pnl());
+    }
+    for totals in &repo.position_summary(NaiveDate::from_ymd_opt(2022, 3, 31).unwrap(), Some(Dimension::Currency)).group_totals {
+        println!("Quoted in {}: Value {:.2}, P&L {:.2}", totals.group, totals.market_value, totals.total_pnl());
+    }
+    let contribution = repo.pnl_contribution(NaiveDate::from_ymd_opt(2022, 3, 31).unwrap(), Dimension::Sector);
+    for sector in &contribution.children {
+        let leaves: Vec<String> = sector.children.iter().map(|leaf| format!("{} {:.1}%/{:.1}%", leaf.name, leaf.weight * 100.0, leaf.pnl_share * 100.0)).collect();
+        println!("Treemap {}: weight {:.1}%, P&L share {:.1}% ({})", sector.name, sector.weight * 100.0, sector.pnl_share * 100.0, leaves.join(", "));
+    }
+    println!("Treemap JSON: {}", contribution.to_json());
+
+    // Correct a mis-booked price by cancelling and rebooking
+    println!("\n=== Cancel and Rebook ===");
+    let corrected = Trade::new(43, NaiveDate::from_ymd_opt(2022, 3, 2).unwrap(), "NVDA".to_string(), 100, 244.6, Side::Buy).with_broker("BETA", 2.5);
+    match repo.cancel_and_rebook(42, corrected) {
+        Ok(()) => println!("Trade 42 replaced by {:?}; trade 43 replaces {:?}", repo.trades.get(&TradeId::from(42)).unwrap().replaced_by, repo.trades.get(&TradeId::from(43)).unwrap().replaces),
+        Err(e) => println!("Error: {}", e),
+    }
+    if let Err(e) = repo.cancel_and_rebook(42, Trade::new(44, NaiveDate::from_ymd_opt(2022, 3, 2).unwrap(), "NVDA".to_string(), 100, 244.6, Side::Buy)) {
+        println!("Second rebook rejected: {}", e);
+    }
+    if let Some(event) = repo.event_log.last() {
+        println!("Last event: {}", event);
+    }
+
+    // Fat-finger checks: a price typo is blocked, then booked with an override
+    println!("\n=== Sanity Checks ===");
+    repo.set_average_daily_volume("IBM", 5_000_000.0);
+    repo.set_sanity_band("IBM", SanityBand { max_price_deviation: 0.10, max_adv_fraction: 0.05, action: SanityAction::Block });
+    repo.default_sanity_band = Some(SanityBand { max_price_deviation: 0.20, max_adv_fraction: 0.10, action: SanityAction::Flag });
+    let typo = Trade::new(45, NaiveDate::from_ymd_opt(2022, 3, 3).unwrap(), "IBM".to_string(), 100, 1320.0, Side::Buy);
+    match repo.add_trade_checked(typo.clone()) {
+        Ok(breaches) => println!("Booked with {} flag(s)", breaches.len()),
+        Err(e) => println!("{}", e),
+    }
+    if let Err(e) = repo.add_trade_with_override(typo, "Block trade agreed at premium, confirmed by phone", "head-of-desk") {
+        println!("Error: {}", e);
+    }
+    for approval in &repo.sanity_overrides {
+        println!("Trade {} booked over {} breach(es), approved by {}: {}", approval.trade_id, approval.breaches.len(), approval.approved_by, approval.justification);
+        for breach in &approval.breaches {
+            println!("  {}", breach);
+        }
+    }
+    match repo.add_trade_checked(Trade::new(46, NaiveDate::from_ymd_opt(2022, 3, 3).unwrap(), "KO".to_string(), 100, 80.0, Side::Buy)) {
+        Ok(breaches) => println!("KO booked with {} flag(s)", breaches.len()),
+        Err(e) => println!("{}", e),
+    }
+
+    // Heavy queries stop when cancelled or past their timeout
+    println!("\n=== Query Cancellation ===");
+    let token = CancellationToken::new();
+    token.cancel();
+    let cancelled = repo.firm_pnl_history_controlled(NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 12, 31).unwrap(), &QueryControl::new(token));
+    println!("Cancelled P&L history: {:?}", cancelled.err());
+    repo.default_query_timeout = Some(Duration::from_secs(0));
+    let timed_out = repo.rebuild_daily_positions(&repo.query_control(CancellationToken::new()));
+    println!("Rebuild with zero timeout: {:?}", timed_out.err());
+    repo.default_query_timeout = Some(Duration::from_secs(30));
+    let rebuilt = repo.rebuild_daily_positions(&repo.query_control(CancellationToken::new()));
+    println!("Rebuild with 30s timeout: {:?}", rebuilt);
+    let history = repo.get_position_history_controlled("AAPL", NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 3, 31).unwrap(), &repo.query_control(CancellationToken::new()));
+    println!("AAPL history days: {:?}", history.map(|history| history.len()));
+
+    // Writers on separate threads, one instrument each
+    println!("\n=== Concurrent Repository ===");
+    let concurrent = ConcurrentTradeRepository::new();
+    std::thread::scope(|scope| {
+        let writers: Vec<_> = ["AAPL", "MSFT", "IBM", "KO"].iter().enumerate().map(|(thread, instrument)| {
+            let concurrent = &concurrent;
+            scope.spawn(move || {
+                for i in 0..1000 {
+                    let trade_id = (thread * 1000 + i) as i32;
+                    concurrent.add_trade(Trade::new(trade_id, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), instrument.to_string(), 10, 100.0 + i as f64 / 100.0, Side::Buy))?;
+                }
+                Ok::<(), PositionError>(())
+            })
+        }).collect();
+        writers.into_iter().try_for_each(|writer| writer.join().expect("writer thread panicked"))
+    })?;
+    concurrent.cancel_trade(0)?;
+    concurrent.amend_trade(1000, 20, 100.0)?;
+    for (instrument, position) in concurrent.get_all_positions() {
+        println!("{}: {} shares @ ${:.2}", instrument, position.quantity, position.average_price);
+    }
+
+    // Amends racing a reader: every read must show whole amends only
+    let (reads, consistent) = std::thread::scope(|scope| {
+        let concurrent = &concurrent;
+        let amender = scope.spawn(move || {
+            (3000..4000).try_for_each(|trade_id| concurrent.amend_trade(trade_id, 20, 100.0))
+        });
+        let reader = scope.spawn(move || {
+            let mut reads = 0;
+            let mut consistent = true;
+            let mut last_quantity = 0;
+            while !amender.is_finished() {
+                let quantity = concurrent.snapshot()["KO"].quantity;
+                consistent &= quantity % 10 == 0 && quantity >= last_quantity && (10000..=20000).contains(&quantity);
+                last_quantity = quantity;
+                reads += 1;
+            }
+            amender.join().expect("amend thread panicked").map(|_| (reads, consistent))
+        });
+        reader.join().expect("reader thread panicked")
+    })?;
+    let (trade, ko) = concurrent.trade_and_position(3999).unwrap();
+    println!("Reader took {} snapshots during 1000 amends, all consistent: {}; trade 3999 now {} shares, KO {} shares",
+             reads, consistent, trade.quantity, ko.quantity);
+
+    println!("\n=== Greeks by Underlying ===");
+    let mut options_repo = TradeRepository::new();
+    let trade_date = NaiveDate::from_ymd_opt(2022, 1, 3).unwrap();
+    let expiry = NaiveDate::from_ymd_opt(2022, 3, 18).unwrap();
+    options_repo.register_contract(DerivativeContract::new("AAPL220318C180".to_string(), "AAPL".to_string(), ContractKind::Option, expiry, RollRule::Close)
+        .with_option_terms(OptionRight::Call, 180.0, 100.0));
+    options_repo.register_contract(DerivativeContract::new("AAPL220318P170".to_string(), "AAPL".to_string(), ContractKind::Option, expiry, RollRule::Close)
+        .with_option_terms(OptionRight::Put, 170.0, 100.0));
+    options_repo.add_trade(Trade::new(50, trade_date, "AAPL".to_string(), 1000, 175.0, Side::Buy))?;
+    options_repo.add_trade(Trade::new(51, trade_date, "AAPL220318C180".to_string(), 10, 5.0, Side::Sell))?;
+    options_repo.add_trade(Trade::new(52, trade_date, "AAPL220318P170".to_string(), 5, 4.0, Side::Buy))?;
+    options_repo.add_trade(Trade::new(53, trade_date, "MSFT".to_string(), 100, 330.0, Side::Buy))?;
+    options_repo.update_market_price("AAPL", 176.0)?;
+    options_repo.update_market_price("AAPL220318C180", 5.5)?;
+    options_repo.update_market_price("AAPL220318P170", 3.8)?;
+    options_repo.set_implied_volatility("AAPL220318C180", 0.28);
+    options_repo.set_implied_volatility("AAPL220318P170", 0.30);
+    options_repo.set_risk_free_rate(0.01);
+    options_repo.print_risk_report(trade_date);
+
+    println!("\n=== Option Strategies ===");
+    let mut strategy_repo = options_repo.clone();
+    for (strike, right, mark, volatility) in [(190.0, OptionRight::Call, 2.4, 0.27), (170.0, OptionRight::Call, 9.1, 0.29)] {
+        let instrument = format!("AAPL220318{}{}", if matches!(right, OptionRight::Call) { "C" } else { "P" }, strike);
+        strategy_repo.register_contract(DerivativeContract::new(instrument.clone(), "AAPL".to_string(), ContractKind::Option, expiry, RollRule::Close)
+            .with_option_terms(right, strike, 100.0));
+        strategy_repo.update_market_price(&instrument, mark)?;
+        strategy_repo.set_implied_volatility(&instrument, volatility);
+    }
+    // Long 180 calls against short 190s make a vertical, the 170 calls pair with
+    // the 170 puts as a straddle, and the leftover short 190s are covered by shares
+    strategy_repo.add_trade(Trade::new(54, trade_date, "AAPL220318C180".to_string(), 14, 5.2, Side::Buy))?;
+    strategy_repo.add_trade(Trade::new(55, trade_date, "AAPL220318C190".to_string(), 6, 2.5, Side::Sell))?;
+    strategy_repo.add_trade(Trade::new(56, trade_date, "AAPL220318C170".to_string(), 5, 9.0, Side::Buy))?;
+    strategy_repo.print_option_strategies(trade_date);
+
+    println!("\n=== What-if REPL ===");
+    let script = "buy MSFT 200 335.0 2022-01-04\nmark MSFT 340\npnl\ncommit\npositions\nquit\n";
+    match run_repl(options_repo, script.as_bytes(), std::io::stdout()) {
+        Ok(repo) => println!("\nMSFT after commit: {:?}", repo.get_position("MSFT").map(|position| position.quantity)),
+        Err(e) => println!("repl error: {}", e),
+    }
+
+    println!("\n=== Audit Log Export ===");
+    let mut ndjson = Vec::new();
+    match repo.export_audit_ndjson(&mut ndjson) {
+        Ok(count) => println!("Exported {} audit entries", count),
+        Err(e) => println!("Export failed: {}", e),
+    }
+    let ndjson = String::from_utf8(ndjson).unwrap_or_default();
+    if let Some(line) = ndjson.lines().find(|line| line.contains("\"action\":\"amend\"")) {
+        println!("{}", line);
+    }
+    let mut replica = TradeRepository::new();
+    match replica.replay_audit_ndjson(&ndjson) {
+        Ok(count) => {
+            let matching = repo.get_all_positions().iter().all(|(instrument, position)| {
+                replica.get_position(instrument).is_some_and(|copy| copy.quantity == position.quantity && copy.realized_pnl == position.realized_pnl)
+            });
+            println!("Replayed {} entries into a new repository, positions match: {}", count, matching);
+        },
+        Err(e) => println!("Replay failed: {}", e),
+    }
+    if let Err(e) = replica.replay_audit_ndjson("{\"schema_version\":99}") {
+        println!("Rejected: {}", e);
+    }
+
+    // The same log replayed at 10x with a live P&L line after every cancel
+    println!("\n=== Replay ===");
+    let mut replayed = TradeRepository::new();
+    let stats = Replayer::from_ndjson(&ndjson)?
+        .speed(ReplaySpeed::Accelerated(10.0))
+        .max_pause(Duration::from_millis(50))
+        .run(&mut replayed, |live, entry| {
+            if matches!(entry.action, AuditAction::Cancel) {
+                let (realized, _, _) = live.calculate_portfolio_pnl();
+                println!("  #{} cancel {} by {}: realized ${:.2} across {} trades", entry.sequence, entry.trade_id, entry.actor, realized, live.trades.len());
+            }
+        })?;
+    println!("Replayed {} events ({:?} of history in {:?}), matches source: {}",
+             stats.events, stats.history, stats.elapsed, diff_repositories(&repo, &replayed).is_empty());
+    // At the pace it was recorded, still with overnight gaps capped
+    let mut paced = TradeRepository::new();
+    let stats = Replayer::from_ndjson(&ndjson)?
+        .speed(ReplaySpeed::RealTime)
+        .max_pause(Duration::from_millis(50))
+        .run(&mut paced, |_, _| {})?;
+    println!("Replayed {} events in real time in {:?}", stats.events, stats.elapsed);
+
+    println!("\n=== Constrained Rebalance ===");
+    let mut rebalance_repo = TradeRepository::new();
+    let rebalance_date = NaiveDate::from_ymd_opt(2022, 3, 1).unwrap();
+    rebalance_repo.add_trade(Trade::new(60, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "AAPL".to_string(), 400, 160.0, Side::Buy))?;
+    rebalance_repo.add_trade(Trade::new(61, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "MSFT".to_string(), 100, 300.0, Side::Buy))?;
+    rebalance_repo.add_trade(Trade::new(62, NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), "KO".to_string(), 500, 60.0, Side::Buy))?;
+    for (instrument, price) in [("AAPL", 165.0), ("MSFT", 295.0), ("KO", 61.0), ("NVDA", 240.0)] {
+        rebalance_repo.update_market_price(instrument, price)?;
+    }
+    let targets: BTreeMap<String, f64> = [("AAPL", 0.30), ("MSFT", 0.20), ("NVDA", 0.40), ("TSLA", 0.10)]
+        .iter()
+        .map(|(instrument, weight)| (instrument.to_string(), *weight))
+        .collect();
+    let constraints = RebalanceConstraints::new()
+        .max_turnover(0.40)
+        .max_trade_notional(25000.0)
+        .max_trade_notional_for("NVDA", 30000.0)
+        .do_not_trade("KO");
+    let plan = rebalance_repo.generate_rebalance(&targets, rebalance_date, &constraints);
+    rebalance_repo.print_rebalance_plan(&plan);
+
+    println!("\n=== Repository Diff ===");
+    let mut legacy = rebalance_repo.clone();
+    let mut migrated = TradeRepository::new();
+    // The new system numbers trades differently
+    for (trade_id, trade) in legacy.trades.iter() {
+        migrated.add_trade(Trade { trade_id: TradeId::from(trade_id.as_i64().unwrap() + 1000), ..trade.clone() })?;
+    }
+    legacy.add_trade(Trade::new(63, rebalance_date, "NVDA".to_string(), 50, 240.0, Side::Buy))?;
+    migrated.amend_trade(1061, 100, 301.0)?;
+    migrated.add_trade(Trade::new(1064, rebalance_date, "KO".to_string(), 100, 61.0, Side::Sell))?;
+    diff_repositories(&legacy, &migrated).print();
+
+    println!("\n=== Intraday P&L Since Open ===");
+    let previous_close = rebalance_date.pred_opt().unwrap();
+    for (instrument, close) in [("AAPL", 162.0), ("MSFT", 298.0), ("KO", 60.5)] {
+        rebalance_repo.record_close_price(instrument, previous_close, close);
+    }
+    rebalance_repo.add_trade(Trade::new(64, rebalance_date, "AAPL".to_string(), 100, 166.0, Side::Sell))?;
+    rebalance_repo.add_trade(Trade::new(65, rebalance_date, "NVDA".to_string(), 20, 238.0, Side::Buy))?;
+    rebalance_repo.print_intraday_pnl(rebalance_date);
+    if let Some(aapl) = rebalance_repo.get_position("AAPL") {
+        println!("AAPL cost basis unchanged: {} @ {:.2}, realized {:.2}", aapl.quantity, aapl.average_price, aapl.realized_pnl);
+    }
+
+
+    // Mutations report what went wrong instead of silently doing nothing
+    println!("\n=== Typed Errors ===");
+    for result in [rebalance_repo.amend_trade(999, 10, 1.0), rebalance_repo.cancel_trade(64), rebalance_repo.cancel_trade(64), rebalance_repo.add_trade(Trade::new(60, rebalance_date, "AAPL".to_string(), 0, 1.0, Side::Buy))] {
+        match result {
+            Ok(()) => println!("ok"),
+            Err(PositionError::TradeCancelled(trade_id)) => println!("trade {} was cancelled earlier", trade_id),
+            Err(e) => println!("{} ({:?})", e, e),
+        }
+    }
+
+    println!("\n=== Participation ===");
+    let adv_csv = "date,instrument,adv\n2022-02-01,AAPL,90000\n2022-03-01,AAPL,1500\n2022-02-01,MSFT,30000\n";
+    println!("Imported {} ADV rows", rebalance_repo.import_adv_csv(adv_csv)?);
+    rebalance_repo.add_trade(Trade::new(66, rebalance_date, "AAPL".to_string(), 120, 165.5, Side::Sell))?;
+    rebalance_repo.print_participation_report(NaiveDate::from_ymd_opt(2022, 2, 1).unwrap(), rebalance_date, Some(0.05));
+    if let Err(e) = rebalance_repo.import_adv_csv("2022-03-01,AAPL,lots") {
+        println!("Rejected: {}", e);
+    }
+
+    // Every dated metric shares the same store: last known value on or before a date
+    println!("\n=== Time Series ===");
+    let february = NaiveDate::from_ymd_opt(2022, 2, 15).unwrap();
+    rebalance_repo.set_average_daily_volume_on("MSFT", rebalance_date, 28000.0);
+    rebalance_repo.set_beta_on("AAPL", rebalance_date, 1.25);
+    rebalance_repo.set_borrow_rate_on("MSFT", rebalance_date, 0.0025);
+    let borrow_csv = "date,instrument,rate\n2022-02-01,AAPL,0.003\n2022-03-01,AAPL,0.0045\n";
+    println!("Imported {} borrow rates", rebalance_repo.import_borrow_rates_csv(borrow_csv)?);
+    println!("AAPL borrow on {}: {:?}, latest MSFT ADV: {:?}, AAPL beta now: {:?}",
+             february, rebalance_repo.borrow_rate_on("AAPL", february), rebalance_repo.adv_history.latest("MSFT"), rebalance_repo.betas.value_on("AAPL", rebalance_date));
+    let aapl_adv: Vec<String> = rebalance_repo.adv_history.range("AAPL", NaiveDate::MIN, rebalance_date).map(|(date, adv)| format!("{} {}", date, adv)).collect();
+    println!("AAPL ADV history: {}", aapl_adv.join(", "));
+
+    // Thousands of small fills no longer drift: prices and P&L are fixed-point
+    println!("\n=== Decimal Money ===");
+    let mut decimal_repo = TradeRepository::new();
+    decimal_repo.set_rounding_policy("EURUSD", RoundingPolicy::new(5, 2, RoundingMode::HalfEven));
+    let mut float_realized = 0.0;
+    for i in 0..2000 {
+        let (price, side) = if i % 2 == 0 { (1.1, Side::Buy) } else { (1.2, Side::Sell) };
+        decimal_repo.add_trade(Trade::new(70 + i, rebalance_date, "XYZ".to_string(), 1, price, side))?;
+        if i % 2 == 1 {
+            float_realized += 1.2 - 1.1;
+        }
+    }
+    let xyz = decimal_repo.get_position("XYZ").unwrap();
+    println!("Realized after 1000 round trips: {} (f64 sum: {})", xyz.realized_pnl, float_realized);
+    decimal_repo.add_trade(Trade::new(3000, rebalance_date, "EURUSD".to_string(), 100000, 1.0842349, Side::Buy))?;
+    println!("EURUSD booked at {} under a 5-place price policy", decimal_repo.get_position("EURUSD").unwrap().average_price);
+    decimal_repo.set_default_rounding_policy(RoundingPolicy::new(4, 2, RoundingMode::HalfUp));
+    decimal_repo.set_rounding_policy("USDJPY", RoundingPolicy::new(2, 0, RoundingMode::Down));
+    decimal_repo.add_trade(Trade::new(3001, rebalance_date, "GBPUSD".to_string(), 100000, 1.27345, Side::Buy))?;
+    decimal_repo.add_trade(Trade::new(3002, rebalance_date, "USDJPY".to_string(), 100000, 135.679, Side::Buy))?;
+    println!("GBPUSD rounded half up to {}, USDJPY truncated to {}",
+             decimal_repo.get_position("GBPUSD").unwrap().average_price, decimal_repo.get_position("USDJPY").unwrap().average_price);
+
+    // Tax lots: each buy opens a lot, sells consume lots under the instrument's method
+    println!("\n=== Tax Lots ===");
+    let mut lots_repo = TradeRepository::new();
+    for (trade_id, day, quantity, price, side) in [(80, 1, 100, 150.0, Side::Buy), (81, 2, 100, 170.0, Side::Buy), (82, 3, 100, 160.0, Side::Buy), (83, 10, 150, 180.0, Side::Sell)] {
+        lots_repo.add_trade(Trade::new(trade_id, NaiveDate::from_ymd_opt(2022, 4, day).unwrap(), "AAPL".to_string(), quantity, price, side))?;
+    }
+    lots_repo.update_market_price("AAPL", 175.0)?;
+    lots_repo.print_tax_lots("AAPL");
+    lots_repo.set_cost_basis_method("AAPL", CostBasisMethod::SpecificLot);
+    lots_repo.select_lots(83, &[TradeId::from(82), TradeId::from(80)])?;
+    lots_repo.print_tax_lots("AAPL");
+    lots_repo.print_cost_basis_comparison(&[CostBasisMethod::Fifo, CostBasisMethod::Lifo, CostBasisMethod::Hifo, CostBasisMethod::AverageCost, CostBasisMethod::SpecificLot]);
+    let realized: Vec<(String, LotClosure)> = lots_repo.realized_lots(NaiveDate::from_ymd_opt(2022, 4, 1).unwrap(), NaiveDate::from_ymd_opt(2022, 4, 30).unwrap());
+    println!("April lot closures: {}, realized ${:.2}", realized.len(), realized.iter().map(|(_, closure)| closure.realized_pnl).sum::<Money>());
+    // Instruments without a method of their own use the book's default
+    lots_repo.set_default_cost_basis_method(CostBasisMethod::Hifo);
+    for (trade_id, day, quantity, price, side) in [(84, 4, 100, 280.0, Side::Buy), (85, 5, 100, 300.0, Side::Buy), (86, 11, 100, 310.0, Side::Sell)] {
+        lots_repo.add_trade(Trade::new(trade_id, NaiveDate::from_ymd_opt(2022, 4, day).unwrap(), "MSFT".to_string(), quantity, price, side))?;
+    }
+    lots_repo.print_tax_lots("MSFT");
+
+    // Sequenced feed: 3 and 4 arrive swapped, 6 is late, 10 and 11 never come
+    println!("\n=== Feed Sequence Gaps ===");
+    let mut sequenced_repo = TradeRepository::new();
+    sequenced_repo.set_reorder_window(2);
+    let feed = "seq,trade_id,date,instrument,side,quantity,price\n\
+        1,90,2022-04-01,AAPL,BUY,10,150.0\n\
+        2,91,2022-04-01,AAPL,BUY,10,151.0\n\
+        4,93,2022-04-01,MSFT,BUY,5,300.0\n\
+        3,92,2022-04-01,AAPL,SELL,5,152.0\n\
+        5,94,2022-04-01,MSFT,BUY,5,301.0\n\
+        7,96,2022-04-01,KO,BUY,100,60.0\n\
+        8,97,2022-04-01,KO,BUY,100,60.5\n\
+        9,98,2022-04-01,KO,SELL,50,61.0\n\
+        6,95,2022-04-01,MSFT,SELL,5,302.0\n\
+        2,91,2022-04-01,AAPL,BUY,10,151.0\n\
+        12,99,2022-04-01,KO,SELL,50,61.5\n";
+    let summary = sequenced_repo.ingest_from(&mut CsvSourceAdapter::new("seq-feed", feed, 3).sequenced())?;
+    println!("Polled {}, ingested {}, out of order {}, duplicates {}, gaps opened {}",
+             summary.polled, summary.ingested, summary.out_of_order, summary.duplicates, summary.gaps_opened);
+    sequenced_repo.print_sequence_alerts();
+
+    // Retention run as of 2030: cancelled 2022 trades go, 2026 audit actors are redacted
+    println!("\n=== Retention ===");
+    let mut retained = repo.clone();
+    retained.tier_storage(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap(), 0);
+    let before = retained.calculate_portfolio_pnl();
+    let policy = RetentionPolicy::new().purge_cancelled_trades_after(5).purge_trader_identities_after(3);
+    let report = retained.run_retention(&policy, NaiveDate::from_ymd_opt(2030, 1, 1).unwrap())?;
+    println!("As of {}: purged {} cancelled trades ({} segments rewritten, {} audit entries), redacted {} identities",
+             report.as_of_date, report.cancelled_trades_purged, report.segments_rewritten, report.audit_entries_purged, report.identities_redacted);
+    println!("Trades {} -> {}, P&L unchanged: {}", repo.trades.len(), retained.trades.len(), before == retained.calculate_portfolio_pnl());
+
+    // Fees from a schedule land in cost basis and realized P&L
+    println!("\n=== Fees ===");
+    let mut fee_repo = TradeRepository::new();
+    fee_repo.set_default_fee_schedule(FeeSchedule::new()
+        .commission(FeeBasis::PerShare(Decimal::from(0.005)))
+        .commission(FeeBasis::PerTrade(Decimal::from(1)))
+        .exchange_fee(FeeBasis::Bps(0.3))
+        .tax_on(Side::Sell, FeeBasis::Bps(0.278)));
+    fee_repo.add_trade(Trade::new(100, NaiveDate::from_ymd_opt(2022, 4, 1).unwrap(), "AAPL".to_string(), 1000, 150.0, Side::Buy))?;
+    fee_repo.add_trade(Trade::new(101, NaiveDate::from_ymd_opt(2022, 4, 5).unwrap(), "AAPL".to_string(), 400, 160.0, Side::Sell))?;
+    fee_repo.update_market_price("AAPL", 158.0)?;
+    for trade_id in [100, 101] {
+        let trade = fee_repo.trades.get(&TradeId::from(trade_id)).unwrap();
+        println!("Trade {}: commission ${:.2}, exchange fee ${:.2}, tax ${:.2}, net price {:.4}", trade_id, trade.commission, trade.exchange_fee, trade.tax, trade.net_price());
+    }
+    let aapl = fee_repo.get_position("AAPL").unwrap();
+    let (realized, unrealized, _) = fee_repo.calculate_portfolio_pnl();
+    println!("AAPL {} @ {:.4} (fees in cost basis), realized ${:.2}, unrealized ${:.2}", aapl.quantity, aapl.average_price, realized, unrealized);
+    fee_repo.amend_trade(100, 1200, 150.0)?;
+    let amended = fee_repo.trades.get(&TradeId::from(100)).unwrap();
+    println!("After amending trade 100 to 1200 shares: commission ${:.2}, exchange fee ${:.2}", amended.commission, amended.exchange_fee);
+    // Stamp duty on both sides of the trade, as in Hong Kong
+    fee_repo.set_fee_schedule("0700.HK", FeeSchedule::new().commission(FeeBasis::Bps(3.0)).tax(FeeBasis::Bps(13.0)));
+    fee_repo.add_trade(Trade::new(102, NaiveDate::from_ymd_opt(2022, 4, 5).unwrap(), "0700.HK".to_string(), 500, 310.0, Side::Buy))?;
+    let hk = fee_repo.trades.get(&TradeId::from(102)).unwrap();
+    println!("Trade 102: commission ${:.2}, stamp duty ${:.2}", hk.commission, hk.tax);
+
+    // Booked out of order: the 15:30 sell has the lowest id, the 11:00 buy arrives last
+    println!("\n=== Intraday Ordering ===");
+    let mut intraday_repo = TradeRepository::new();
+    let session = NaiveDate::from_ymd_opt(2022, 4, 11).unwrap();
+    let at = |hour: u32, minute: u32| session.and_hms_opt(hour, minute, 0).unwrap().and_utc();
+    intraday_repo.add_trade(Trade::new(112, session, "AMD".to_string(), 100, 100.0, Side::Buy).with_execution_time(at(9, 45)))?;
+    intraday_repo.add_trade(Trade::new(110, session, "AMD".to_string(), 150, 104.0, Side::Sell).with_execution_time(at(15, 30)))?;
+    intraday_repo.add_trade(Trade::new(111, session, "AMD".to_string(), 100, 98.0, Side::Buy).with_execution_time(at(11, 0)))?;
+    for as_of in [at(10, 0), at(12, 0), at(16, 0)] {
+        if let Some(amd) = intraday_repo.build_position_map_as_of(as_of).get("AMD") {
+            println!("AMD as of {}: {} @ {:.2}, realized ${:.2}", as_of.format("%H:%M"), amd.quantity, amd.average_price, amd.realized_pnl);
+        }
+    }
+    let close = intraday_repo.build_position_map_as_of_date(session);
+    println!("End of day matches 16:00: {}", close["AMD"].realized_pnl == intraday_repo.build_position_map_as_of(at(16, 0))["AMD"].realized_pnl);
+
+    // Six weeks of quiet closes with one outsized move in XOM
+    let mut anomaly_repo = TradeRepository::new();
+    let first_day = NaiveDate::from_ymd_opt(2022, 5, 2).unwrap();
+    anomaly_repo.add_trade(Trade::new(120, first_day, "XOM".to_string(), 500, 85.0, Side::Buy))?;
+    anomaly_repo.add_trade(Trade::new(121, first_day, "CVX".to_string(), 200, 160.0, Side::Buy))?;
+    for day in 0..42 {
+        let date = first_day + chrono::Duration::days(day);
+        let wiggle = ((day * 7) % 5) as f64 * 0.2 - 0.4;
+        let xom_jump = if day >= 31 { 6.0 } else { 0.0 };
+        anomaly_repo.record_close_price("XOM", date, 85.0 + wiggle + xom_jump);
+        anomaly_repo.record_close_price("CVX", date, 160.0 - wiggle);
+    }
+    let config = PnlAnomalyConfig { lookback_days: 20, threshold_sigmas: 3.0, min_history: 10 };
+    anomaly_repo.print_pnl_exception_report(first_day, first_day + chrono::Duration::days(41), &config);
+
+    // A US and a Tokyo listing closing at different times, with a US half day
+    println!("\n=== Trading Sessions ===");
+    let mut global = TradeRepository::new();
+    let half_day = NaiveDate::from_ymd_opt(2022, 11, 25).unwrap();
+    let hm = |hour: u32, minute: u32| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+    global.add_session_calendar(SessionCalendar::new("XNYS", -5 * 60, hm(9, 30), hm(16, 0)).half_day(half_day, hm(13, 0)));
+    global.add_session_calendar(SessionCalendar::new("XTKS", 9 * 60, hm(9, 0), hm(15, 0)).holiday(half_day + chrono::Duration::days(3)));
+    for (instrument, calendar) in [("IBM", "XNYS"), ("BABA", "XNYS"), ("7203.T", "XTKS")] {
+        global.set_instrument_session(instrument, calendar);
+    }
+    let utc = |hour: u32, minute: u32| half_day.and_hms_opt(hour, minute, 0).unwrap().and_utc();
+    // 11:00 and 14:30 New York; 12:00 and 16:00 Tokyo
+    global.add_trade(Trade::new(190, half_day, "IBM".to_string(), 100, 147.0, Side::Buy).with_execution_time(utc(16, 0)))?;
+    global.add_trade(Trade::new(191, half_day, "IBM".to_string(), 50, 148.0, Side::Buy).with_execution_time(utc(19, 30)))?;
+    global.add_trade(Trade::new(192, half_day, "7203.T".to_string(), 300, 2100.0, Side::Buy).with_execution_time(utc(3, 0)))?;
+    global.add_trade(Trade::new(193, half_day, "7203.T".to_string(), 100, 2110.0, Side::Sell).with_execution_time(utc(7, 0)))?;
+    global.add_trade(Trade::new(194, half_day, "BABA".to_string(), 80, 80.0, Side::Buy).with_execution_time(utc(15, 0)))?;
+    for (instrument, position) in global.positions_at_close(half_day) {
+        println!("{} at close: {} shares", instrument, position.quantity);
+    }
+    let tokyo_holiday = half_day + chrono::Duration::days(3);
+    println!("7203.T at close on the {} holiday: {} shares", tokyo_holiday, global.positions_at_close(tokyo_holiday)["7203.T"].quantity);
+    global.set_mark_method("IBM", MarkMethod::Mid);
+    global.set_mark_method("BABA", MarkMethod::Mid);
+    global.ingest_book_snapshot(BookSnapshot::new("IBM", utc(17, 55)).bid(147.9, 300).ask(148.1, 300))?;
+    global.ingest_book_snapshot(BookSnapshot::new("BABA", utc(20, 30) - chrono::Duration::days(1)).bid(79.9, 500).ask(80.1, 500))?;
+    global.update_market_price("7203.T", 2105.0)?;
+    for close in global.run_end_of_day(half_day, utc(21, 0)) {
+        let cutoff = close.cutoff.map_or("-".to_string(), |cutoff| cutoff.format("%H:%M UTC").to_string());
+        let status = match &close.status {
+            EodCloseStatus::StaleMark { captured_at } => format!("StaleMark captured {}", captured_at.format("%Y-%m-%d %H:%M UTC")),
+            other => format!("{:?}", other),
+        };
+        println!("{} close {}: {} at {:?}", close.instrument, cutoff, status, close.price);
+    }
+
+    println!("\n=== Cash Sweeps ===");
+    let mut cash_repo = TradeRepository::new();
+    let july = |day: u32| NaiveDate::from_ymd_opt(2022, 7, day).unwrap();
+    cash_repo.add_sweep_rule(SweepRule {
+        account_id: "ACC-CASH".to_string(),
+        currency: "USD".to_string(),
+        mmf_instrument: "SWEEP.USD".to_string(),
+        target_balance: 10000,
+        annual_yield: 0.045,
+    })?;
+    cash_repo.book_cash("ACC-CASH", "USD", 250000, july(1))?;
+    cash_repo.book_cash("ACC-CASH", "USD", -60000, july(15))?;
+    for day in 1..=31 {
+        for event in cash_repo.run_sweeps(july(day))? {
+            let direction = if event.amount > 0 { "invested in" } else { "redeemed from" };
+            println!("{} {}: {} USD {} {} (trades {} and {})", event.date, event.account_id, event.amount.abs(), direction, event.mmf_instrument, event.cash_trade_id, event.fund_trade_id);
+        }
+    }
+    println!("Cash at month end: {} USD", cash_repo.cash_balance("ACC-CASH", "USD", july(31)));
+    let (_, _, market_value) = cash_repo.calculate_portfolio_pnl();
+    println!("Market value including cash and fund units: ${:.2}", market_value);
+    cash_repo.print_income_report(july(1), july(31));
+
+    // UK stamp duty and the French FTT on purchases; French dividends withheld at source
+    println!("\n=== Transaction Taxes and Withholding ===");
+    let mut taxed = TradeRepository::new();
+    taxed.add_tax_market(TaxMarket::new("UK").transaction_tax("Stamp duty", Some(Side::Buy), FeeBasis::Bps(50.0)));
+    taxed.add_tax_market(TaxMarket::new("FR").transaction_tax("FTT", Some(Side::Buy), FeeBasis::Bps(30.0)).dividend_withholding(0.25));
+    taxed.set_instrument_market("VOD.L", "UK");
+    taxed.set_instrument_market("TTE.PA", "FR");
+    taxed.set_fee_schedule("VOD.L", FeeSchedule::new().commission(FeeBasis::Bps(5.0)));
+    taxed.add_trade(Trade::new(195, july(4), "VOD.L".to_string(), 20000, 1.25, Side::Buy).with_account("ACC-EU"))?;
+    taxed.add_trade(Trade::new(196, july(6), "VOD.L".to_string(), 5000, 1.28, Side::Sell).with_account("ACC-EU"))?;
+    taxed.add_trade(Trade::new(197, july(5), "TTE.PA".to_string(), 400, 50.0, Side::Buy).with_account("ACC-EU"))?;
+    taxed.add_trade(Trade::new(198, july(5), "TTE.PA".to_string(), 100, 50.2, Side::Sell).with_account("ACC-HEDGE"))?;
+    for payment in taxed.record_dividend("TTE.PA", july(20), july(25), 0.74)? {
+        println!("{} TTE.PA dividend (ex {}, paid {}) on {} shares: gross {:.2}, withheld {:.2}, net {:.2} EUR",
+                 payment.account_id, payment.ex_date, payment.pay_date, payment.quantity, payment.gross, payment.withheld, payment.net);
+    }
+    taxed.print_fee_tax_report(july(1), july(31));
+    taxed.print_income_report(july(1), july(31));
+
+    // Book through a journal file, "crash", and recover from the file alone
+    println!("\n=== Trade Journal ===");
+    let journal_path = std::env::temp_dir().join("rustopos_trade_journal.ndjson").to_string_lossy().into_owned();
+    let _ = std::fs::remove_file(&journal_path);
+    let mut journaled = TradeRepository::new();
+    journaled.attach_journal(TradeJournal::open(&journal_path)?);
+    let day = NaiveDate::from_ymd_opt(2022, 8, 1).unwrap();
+    journaled.add_trade(Trade::new(130, day, "PEP".to_string(), 300, 170.0, Side::Buy).with_broker("BRK1", 4.5))?;
+    journaled.add_trade(Trade::new(131, day, "PEP".to_string(), 100, 172.0, Side::Sell))?;
+    journaled.amend_trade(130, 400, 169.5)?;
+    journaled.cancel_and_rebook(131, Trade::new(132, day, "PEP".to_string(), 120, 172.0, Side::Sell))?;
+    let rejected = journaled.transaction(|tx| {
+        tx.add_trade(Trade::new(133, day, "PEP".to_string(), 50, 171.0, Side::Buy))?;
+        tx.cancel(999)
+    });
+    println!("Rolled back transaction: {} (not journaled)", rejected.unwrap_err());
+    let events_in_memory = journaled.journal.len();
+    drop(journaled.journal_file.take());
+
+    let recovered = TradeRepository::replay(&TradeJournal::load(&journal_path)?)?;
+    println!("Recovered {} events from {} (in memory: {})", recovered.journal.len(), journal_path, events_in_memory);
+    let pep = recovered.get_position("PEP").unwrap();
+    println!("PEP after recovery: {} @ {:.4}, realized ${:.2}; matches original: {}",
+             pep.quantity, pep.average_price, pep.realized_pnl, diff_repositories(&journaled, &recovered).is_empty());
+
+    // A write torn by the crash is dropped on load
+    std::fs::OpenOptions::new().append(true).open(&journal_path)?.write_all(b"{\"event\":\"added\",\"tra")?;
+    println!("Events after a torn final write: {}", TradeJournal::load(&journal_path)?.len());
+    let _ = std::fs::remove_file(&journal_path);
+
+    // A year of day-trading round trips in two accounts, plus a position still open
+    let mut busy = TradeRepository::new();
+    let year_start = NaiveDate::from_ymd_opt(2021, 1, 4).unwrap();
+    let mut busy_id = 30_000;
+    for day in 0..250 {
+        let date = year_start + chrono::Duration::days(day);
+        for (account_id, strategy) in [("ACC-DAY1", "scalp"), ("ACC-DAY2", "momentum")] {
+            let open_price = 40.0 + (day % 9) as f64 * 0.5;
+            let close_price = open_price + if day % 4 == 0 { -0.3 } else { 0.2 };
+            busy.add_trade(Trade::new(busy_id, date, "F".to_string(), 500, open_price, Side::Buy).with_account(account_id).with_strategy(strategy))?;
+            busy.add_trade(Trade::new(busy_id + 1, date, "F".to_string(), 500, close_price, Side::Sell).with_account(account_id).with_strategy(strategy))?;
+            busy_id += 2;
+        }
+    }
+    busy.add_trade(Trade::new(busy_id, year_start + chrono::Duration::days(300), "F".to_string(), 800, 44.0, Side::Buy).with_account("ACC-DAY1"))?;
+    let realized_before = busy.get_position("F").unwrap().realized_pnl;
+    let rollup_before = busy.pnl_rollup(year_start + chrono::Duration::days(400))?;
+    let trades_before = busy.trades.len();
+    let compression = busy.compress_before(year_start + chrono::Duration::days(365))?;
+    print_compression_report(&compression);
+    let f = busy.get_position("F").unwrap();
+    let rollup_after = busy.pnl_rollup(year_start + chrono::Duration::days(400))?;
+    println!("Trades {} -> {}; F {} shares, realized ${:.2} (was ${:.2}); ACC-DAY2 realized ${:.2} (was ${:.2})",
+             trades_before, busy.trades.len(), f.quantity, f.realized_pnl, realized_before,
+             rollup_after.drill_down(&["Unassigned", "ACC-DAY2"]).map_or(Decimal::ZERO, |node| node.realized_pnl),
+             rollup_before.drill_down(&["Unassigned", "ACC-DAY2"]).map_or(Decimal::ZERO, |node| node.realized_pnl));
+    let traced = busy.compression_of(30_001).map(|compression| compression.synthetic_trades.iter().map(|trade| trade.trade_id).collect::<Vec<_>>());
+    println!("Trade 30001 was compressed into {:?}; audit entries kept: {}", traced, busy.audit_log.iter().filter(|entry| entry.trade_id == TradeId::from(30_001)).count());
+    let replayed = TradeRepository::replay(&busy.journal)?;
+    println!("Journal replay matches: {}", diff_repositories(&busy, &replayed).is_empty());
+
+    // SAP was booked as a USD line without its exchange's fees; the master is fixed afterwards
+    let mut master_repo = TradeRepository::new();
+    let booked = NaiveDate::from_ymd_opt(2022, 8, 8).unwrap();
+    master_repo.add_trade(Trade::new(140, booked, "SAP".to_string(), 200, 95.0, Side::Buy))?;
+    master_repo.add_trade(Trade::new(141, booked + chrono::Duration::days(1), "SAP".to_string(), 50, 99.0, Side::Sell))?;
+    master_repo.add_trade(Trade::new(142, booked + chrono::Duration::days(2), "SAP".to_string(), 80, 97.0, Side::Sell))?;
+    master_repo.cancel_trade(142)?;
+    master_repo.record_close_price("SAP", booked + chrono::Duration::days(3), 98.0);
+    master_repo.set_instrument_currency("SAP", "EUR");
+    master_repo.fx_rates.set_rate("EUR", booked, 1.02);
+    master_repo.set_fee_schedule("SAP", FeeSchedule::new().exchange_fee(FeeBasis::Bps(1.0)));
+    let restatement = master_repo.recompute_instrument("SAP", booked + chrono::Duration::days(3))?;
+    print_restatement(&restatement);
+
+    // The vendor corrects the SAP close and commissions are added to the schedule;
+    // the whole week is restated for sign-off
+    master_repo.record_close_price("SAP", booked + chrono::Duration::days(3), 97.5);
+    master_repo.set_fee_schedule("SAP", FeeSchedule::new().exchange_fee(FeeBasis::Bps(1.0)).commission(FeeBasis::Bps(5.0)));
+    let range_restatement = master_repo.restate(booked, booked + chrono::Duration::days(4), "SAP close correction and commission schedule")?;
+    print_range_restatement(&range_restatement);
+    println!("Restatements on record: {}", master_repo.restatements.len());
+
+    let mut desk = TradeRepository::new();
+    let desk_date = NaiveDate::from_ymd_opt(2022, 8, 15).unwrap();
+    desk.set_account_book("ACC-EQ1", "Equities");
+    desk.set_account_book("ACC-EQ2", "Equities");
+    desk.set_account_book("ACC-MACRO", "Macro");
+    desk.set_instrument_currency("BMW", "EUR");
+    desk.fx_rates.set_rate("EUR", desk_date, 1.01);
+    desk.add_trade(Trade::new(150, desk_date, "AAPL".to_string(), 100, 170.0, Side::Buy).with_account("ACC-EQ1").with_strategy("momentum"))?;
+    desk.add_trade(Trade::new(151, desk_date, "NVDA".to_string(), 50, 180.0, Side::Buy).with_account("ACC-EQ1").with_strategy("momentum"))?;
+    desk.add_trade(Trade::new(152, desk_date, "KO".to_string(), 300, 64.0, Side::Buy).with_account("ACC-EQ1").with_strategy("pairs"))?;
+    desk.add_trade(Trade::new(153, desk_date, "PEP".to_string(), 110, 178.0, Side::Sell).with_account("ACC-EQ1").with_strategy("pairs"))?;
+    desk.add_trade(Trade::new(154, desk_date, "MSFT".to_string(), 40, 290.0, Side::Buy).with_account("ACC-EQ2"))?;
+    desk.add_trade(Trade::new(155, desk_date, "BMW".to_string(), 200, 80.0, Side::Buy).with_account("ACC-MACRO").with_strategy("europe"))?;
+    desk.add_trade(Trade::new(156, desk_date, "BMW".to_string(), 50, 83.0, Side::Sell).with_account("ACC-MACRO").with_strategy("europe"))?;
+    for (instrument, price) in [("AAPL", 173.0), ("NVDA", 176.0), ("KO", 65.0), ("PEP", 176.5), ("MSFT", 292.0), ("BMW", 82.0)] {
+        desk.update_market_price(instrument, price)?;
+    }
+    desk.print_pnl_rollup(desk_date);
+    if let Some(pairs) = desk.pnl_rollup(desk_date)?.drill_down(&["Equities", "ACC-EQ1", "pairs"]) {
+        println!("Drill-down Equities/ACC-EQ1/pairs: {} instruments, P&L ${:.2}, net ${:.2}", pairs.children.len(), pairs.total_pnl(), pairs.net_exposure());
+    }
+
+    // Yesterday's book carried into today: a new trade, a move in the closes, EUR
+    // strengthening and yesterday's MSFT fill amended this morning
+    let mut explained = TradeRepository::new();
+    let today = Utc::now().date_naive();
+    let yesterday = today - chrono::Duration::days(1);
+    explained.set_instrument_currency("BMW", "EUR");
+    explained.fx_rates.set_rate("EUR", yesterday, 1.01);
+    explained.fx_rates.set_rate("EUR", today, 1.03);
+    explained.set_fee_schedule("AAPL", FeeSchedule::new().commission(FeeBasis::Bps(2.0)));
+    explained.add_trade(Trade::new(1, yesterday, "AAPL".to_string(), 100, 170.0, Side::Buy))?;
+    explained.add_trade(Trade::new(2, yesterday, "MSFT".to_string(), 40, 290.0, Side::Buy))?;
+    explained.add_trade(Trade::new(3, yesterday, "BMW".to_string(), 200, 80.0, Side::Buy))?;
+    for (instrument, prior, close) in [("AAPL", 171.0, 173.5), ("MSFT", 292.0, 288.0), ("BMW", 81.0, 81.5)] {
+        explained.record_close_price(instrument, yesterday, prior);
+        explained.record_close_price(instrument, today, close);
+    }
+    explained.add_trade(Trade::new(4, today, "AAPL".to_string(), 50, 172.0, Side::Sell))?;
+    explained.amend_trade(2, 40, 289.5)?;
+    print_pnl_explain(&explained.pnl_explain(today)?);
+
+    println!("\n=== Book Hierarchy ===");
+    let mut books = desk.clone();
+    books.add_trade(Trade::new(157, desk_date, "AAPL".to_string(), 60, 172.0, Side::Sell).with_account("ACC-EQ2"))?;
+    books.add_trade(Trade::new(158, desk_date, "AAPL".to_string(), 30, 171.0, Side::Sell).with_account("ACC-MACRO"))?;
+    for (book, accounts) in books.books() {
+        let positions = books.build_book_positions_as_of(&book, desk_date);
+        let open: Vec<String> = positions.values().filter(|position| position.quantity != 0).map(|position| format!("{} {}", position.instrument, position.quantity)).collect();
+        println!("{} ({}): {}", book, accounts.join(", "), open.join(", "));
+    }
+    books.print_position_breakdown("AAPL", desk_date);
+
+    println!("\n=== Aggregation ===");
+    for (instrument, region) in [("AAPL", "Americas"), ("NVDA", "Americas"), ("KO", "Americas"), ("PEP", "Americas"), ("MSFT", "Americas"), ("BMW", "EMEA")] {
+        books.set_instrument_tag(instrument, "region", region);
+    }
+    print_aggregate_node(&books.aggregate(&[GroupBy::Tag("region".to_string()), GroupBy::Currency, GroupBy::Instrument]), 0);
+    books.register_instrument(Instrument::new("BMW", AssetClass::Equity, "EUR"));
+    books.set_instrument_sector("BMW", "Consumer Discretionary");
+    print_aggregate_node(&books.aggregate(&[GroupBy::AssetClass, GroupBy::Sector]), 0);
+    let by_book = books.aggregate_as_of(desk_date, &[GroupBy::Book, GroupBy::Account, GroupBy::Instrument]);
+    if let Some(aapl) = by_book.drill_down(&["Equities", "ACC-EQ2", "AAPL"]) {
+        println!("Equities/ACC-EQ2/AAPL: Value ${:.2} ({:.1}% of gross), P&L ${:.2}", aapl.market_value, aapl.weight * 100.0, aapl.total_pnl());
+    }
+
+    println!("\n=== Internal Crosses ===");
+    let mut crossing = TradeRepository::new();
+    crossing.set_account_book("ACC-EQ1", "Equities");
+    crossing.set_account_book("ACC-MACRO", "Macro");
+    let crossed_at = desk_date.and_hms_opt(14, 30, 0).unwrap().and_utc();
+    crossing.add_trade(Trade::new(1, desk_date, "MSFT".to_string(), 300, 410.0, Side::Buy).with_account("ACC-EQ1").with_execution_time(crossed_at).with_counterparty("GS").with_broker("GS", 15.0))?;
+    crossing.add_trade(Trade::new(2, desk_date, "MSFT".to_string(), 200, 410.0, Side::Sell).with_account("ACC-MACRO").with_execution_time(crossed_at).with_counterparty("MS").with_broker("MS", 10.0))?;
+    crossing.add_trade(Trade::new(3, desk_date, "MSFT".to_string(), 100, 411.0, Side::Sell).with_account("ACC-MACRO").with_execution_time(crossed_at).with_counterparty("MS").with_broker("MS", 5.0))?;
+    crossing.update_market_price("MSFT", 412.0)?;
+    let flagged = crossing.flag_internal_crosses(desk_date, desk_date);
+    println!("Flagged {} new, {} on a second run", flagged.len(), crossing.flag_internal_crosses(desk_date, desk_date).len());
+    crossing.print_internal_cross_flags();
+    crossing.print_broker_cost_report();
+    crossing.set_net_internal_crosses(true);
+    crossing.print_broker_cost_report();
+    crossing.print_counterparty_exposure_report();
+
+    // Report definitions are plain data, e.g. kept in files next to the ops runbooks
+    println!("\n=== Custom Reports ===");
+    for (instrument, sector) in [("AAPL", "Technology"), ("NVDA", "Technology"), ("MSFT", "Technology"), ("KO", "Consumer Staples"), ("PEP", "Consumer Staples"), ("BMW", "Consumer Discretionary")] {
+        desk.set_instrument_sector(instrument, sector);
+    }
+    desk.add_trade(Trade::new(157, desk_date, "MSFT".to_string(), 90, 291.0, Side::Sell).with_account("ACC-MACRO"))?;
+    let definitions = [
+        r#"{"name": "Short positions over $10k by sector",
+            "columns": ["instrument", "account", "quantity", "market_value", "unrealized_pnl"],
+            "filters": [{"field": "side", "op": "=", "value": "short"}, {"field": "exposure", "op": ">", "value": 10000}],
+            "group_by": "sector", "sort": [{"field": "exposure", "descending": true}]}"#,
+        r#"{"name": "P&L by book", "columns": ["account", "instrument", "total_pnl"],
+            "group_by": "book", "sort": [{"field": "total_pnl", "descending": true}], "format": "csv"}"#,
+        r#"{"name": "Bad report", "columns": ["instrument", "delta"]}"#,
+    ];
+    for definition in definitions {
+        match ReportDefinition::from_json(definition) {
+            Ok(definition) => print!("{}", desk.run_report(&definition, desk_date).render()),
+            Err(e) => println!("Rejected report definition: {}", e),
+        }
+    }
+
+    // A thinly traded name: the last print is stale against a wide, lopsided book
+    println!("\n=== Book Marks ===");
+    let mut thin = TradeRepository::new();
+    thin.add_trade(Trade::new(160, desk_date, "ILLQ".to_string(), 5000, 10.0, Side::Buy))?;
+    thin.update_market_price("ILLQ", 12.0)?;
+    let book = BookSnapshot::new("ILLQ", desk_date.and_hms_opt(15, 59, 0).unwrap().and_utc())
+        .bid(10.50, 200).bid(10.40, 1500).bid(10.20, 4000)
+        .ask(10.90, 900).ask(11.20, 300).ask(11.60, 200);
+    for method in [MarkMethod::LastTrade, MarkMethod::Mid, MarkMethod::Microprice, MarkMethod::DepthWeighted { levels: 3, decay: 0.5 }] {
+        thin.set_mark_method("ILLQ", method);
+        thin.ingest_book_snapshot(book.clone())?;
+        let mark = thin.get_market_price("ILLQ").unwrap();
+        println!("{:?}: mark {:.4}, unrealized ${:.2}", method, mark, thin.get_position("ILLQ").unwrap().unrealized_pnl(mark));
+    }
+    // Instruments without a method of their own follow the book's default
+    thin.set_default_mark_method(MarkMethod::Microprice);
+    thin.ingest_book_snapshot(BookSnapshot::new("OTHR", desk_date.and_hms_opt(15, 59, 0).unwrap().and_utc()).bid(20.0, 100).ask(20.2, 300))?;
+    println!("OTHR marked at {:.4} by the default method", thin.get_market_price("OTHR").unwrap());
+    let crossed = BookSnapshot::new("ILLQ", desk_date.and_hms_opt(16, 0, 0).unwrap().and_utc()).bid(11.0, 100).ask(10.9, 100);
+    println!("Crossed book: {}", thin.ingest_book_snapshot(crossed).unwrap_err());
+
+    // A trader's mark for today, trumped by risk's higher-priority mark; both lapse
+    // and the book mark takes over again
+    let now = Utc::now();
+    thin.override_mark(MarkOverride::for_today("ILLQ", 10.25, "trader-jl").reason("Block bid from broker"))?;
+    thin.override_mark(MarkOverride::new("ILLQ", 10.10, "risk-mgmt", now + chrono::Duration::hours(4)).priority(10).reason("Liquidity haircut"))?;
+    thin.print_mark_overrides(now);
+    println!("ILLQ marked at {:?}", thin.get_market_price("ILLQ"));
+    for expired in thin.expire_mark_overrides(now + chrono::Duration::days(2)) {
+        println!("Expired: {} {:.2} set by {}, reverting to the feed", expired.instrument, expired.price, expired.owner);
+    }
+    thin.clear_mark_override("ILLQ", "trader-jl");
+    println!("ILLQ marked at {:?} after expiry", thin.get_market_price("ILLQ"));
+
+    // Trades written through to storage survive a restart
+    println!("\n=== Trade Storage ===");
+    let storage: SharedTradeStorage = Arc::new(Mutex::new(MemoryTradeStore::default()));
+    let mut stored = TradeRepository::new();
+    stored.attach_storage(Arc::clone(&storage));
+    stored.add_trade(Trade::new(170, desk_date, "T".to_string(), 1000, 16.5, Side::Buy).with_account("ACC-EQ1"))?;
+    stored.add_trade(Trade::new(171, desk_date, "T".to_string(), 400, 16.9, Side::Sell).with_account("ACC-EQ1"))?;
+    stored.add_trade(Trade::new(172, desk_date, "VZ".to_string(), 300, 41.0, Side::Buy).with_account("ACC-EQ2"))?;
+    stored.amend_trade(172, 350, 40.8)?;
+    stored.cancel_trade(171)?;
+    drop(stored);
+    let restarted = TradeRepository::load_from_storage(Arc::clone(&storage))?;
+    println!("Reloaded {} trades; T position {} shares, VZ {} @ {:.2}", restarted.trades.len(),
+             restarted.get_position("T").unwrap().quantity, restarted.get_position("VZ").unwrap().quantity, restarted.get_position("VZ").unwrap().average_price);
+    let sells = storage.lock().unwrap().query(&TradeFilter::new().side(Side::Sell))?;
+    println!("Stored sells: {:?}", sells.iter().map(|trade| (trade.trade_id, trade_status_name(&trade.status))).collect::<Vec<_>>());
+
+    #[cfg(feature = "sqlite")]
+    {
+        let sqlite: SharedTradeStorage = Arc::new(Mutex::new(SqliteTradeStore::open_in_memory()?));
+        let mut repo_on_sqlite = TradeRepository::new();
+        repo_on_sqlite.attach_storage(Arc::clone(&sqlite));
+        repo_on_sqlite.add_trade(Trade::new(180, desk_date, "T".to_string(), 1000, 16.5, Side::Buy))?;
+        repo_on_sqlite.cancel_and_rebook(180, Trade::new(181, desk_date, "T".to_string(), 1200, 16.5, Side::Buy))?;
+        let failed = repo_on_sqlite.transaction(|tx| {
+            tx.add_trade(Trade::new(182, desk_date, "T".to_string(), 10, 16.6, Side::Sell))?;
+            tx.add_trade(Trade::new(180, desk_date, "T".to_string(), 10, 16.6, Side::Sell))
+        });
+        println!("SQLite transaction rolled back: {}", failed.is_err());
+        let reloaded = TradeRepository::load_from_storage(sqlite)?;
+        println!("SQLite reload: {} trades, T position {}", reloaded.trades.len(), reloaded.get_position("T").unwrap().quantity);
+
+        // A database file outlives the connection that wrote it
+        let database_path = std::env::temp_dir().join("rustopos_trades.sqlite").to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&database_path);
+        let mut writer = TradeRepository::new();
+        writer.attach_storage(Arc::new(Mutex::new(SqliteTradeStore::open(&database_path)?)));
+        writer.add_trade(Trade::new(185, desk_date, "VZ".to_string(), 500, 41.2, Side::Buy))?;
+        writer.add_trade(Trade::new(186, desk_date, "VZ".to_string(), 200, 41.5, Side::Sell))?;
+        drop(writer);
+        let reopened = TradeRepository::load_from_storage(Arc::new(Mutex::new(SqliteTradeStore::open(&database_path)?)))?;
+        println!("SQLite file reopened: {} trades, VZ position {}", reopened.trades.len(), reopened.get_position("VZ").unwrap().quantity);
+    }
+
+    // Flaky storage and feed (`--features chaos`); rust_soaktester.rs runs this at length
+    #[cfg(feature = "chaos")]
+    {
+        let journal_path = std::env::temp_dir().join("rustopos_chaos_journal.ndjson").to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&journal_path);
+        let stored: SharedTradeStorage = Arc::new(Mutex::new(MemoryTradeStore::default()));
+        let mut flaky = TradeRepository::new();
+        flaky.attach_journal(TradeJournal::open(&journal_path)?);
+        flaky.attach_storage(Arc::new(Mutex::new(ChaosStorage::new(Arc::clone(&stored), 7, 0.3))));
+        flaky.set_feed_reconnect_limit(50);
+        let feed: String = (1..=40).map(|i| format!("{},{},2022-04-04,SYM{},{},{},{}.5\n", i, 500 + i, i % 3, if i % 4 == 0 { "SELL" } else { "BUY" }, 10 * i, 100 + i)).collect();
+        let mut adapter = ChaosAdapter::new(CsvSourceAdapter::new("flaky-feed", &feed, 8).sequenced(), 1).disconnects(0.2, 0.3).garbles(0.1, 0.1);
+        let summary = flaky.ingest_from(&mut adapter)?;
+        println!("Chaos feed: ingested {}, duplicates {}, reconnects {}, faults {:?}, storage backlog {}",
+                 summary.ingested, summary.duplicates, summary.reconnects, adapter.faults, flaky.storage_backlog.len());
+        while flaky.flush_storage().is_err() {}
+        let replayed = TradeRepository::replay(&TradeJournal::load(&journal_path)?)?;
+        let reloaded = TradeRepository::load_from_storage(stored)?;
+        let quantities = |repo: &TradeRepository| repo.positions.values().map(|position| (position.instrument.clone(), position.quantity)).collect::<Vec<_>>();
+        println!("Live, journal replay and storage reload agree: {}", quantities(&flaky) == quantities(&replayed) && quantities(&flaky) == quantities(&reloaded));
+    }
+
+    // Closing positions are snapshotted weekly; as-of queries start from the nearest one
+    println!("\n=== Position Snapshots ===");
+    let snapshot_dir = std::env::temp_dir().join("rustopos_position_snapshots").to_string_lossy().into_owned();
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+    let mut snapshotted = TradeRepository::new();
+    snapshotted.attach_position_snapshots(PositionSnapshotStore::open(&snapshot_dir)?.interval_days(7));
+    let first_day = NaiveDate::from_ymd_opt(2022, 9, 1).unwrap();
+    let mut written = 0;
+    for day in 0..30 {
+        let date = first_day + chrono::Duration::days(day);
+        let side = if day % 3 == 2 { Side::Sell } else { Side::Buy };
+        snapshotted.add_trade(Trade::new(200 + day as i32, date, "KO".to_string(), 100 + day as i32 * 10, 60.0 + day as f64 * 0.25, side))?;
+        snapshotted.add_trade(Trade::new(300 + day as i32, date, "MCD".to_string(), 20, 250.0 - day as f64, Side::Buy))?;
+        if snapshotted.snapshot_end_of_day(date)? {
+            written += 1;
+        }
+    }
+    let as_of = first_day + chrono::Duration::days(25);
+    let from_snapshot = snapshotted.positions_from_snapshots(as_of)?;
+    let ko = &from_snapshot["KO"];
+    println!("{} snapshots written; KO as of {}: {} @ {:.4}, realized ${:.2}", written, as_of, ko.quantity, ko.average_price, ko.realized_pnl);
+    let agrees = |repo: &TradeRepository| -> Result<bool, PositionError> {
+        let expected = repo.build_position_map_as_of_date(as_of);
+        let actual = repo.positions_from_snapshots(as_of)?;
+        Ok(expected.len() == actual.len() && expected.iter().all(|(instrument, position)| {
+            let other = &actual[instrument];
+            other.quantity == position.quantity && other.average_price == position.average_price && other.realized_pnl == position.realized_pnl
+        }))
+    };
+    println!("Matches full replay: {}", agrees(&snapshotted)?);
+    // Amending a trade from the second week makes the later snapshots stale
+    snapshotted.amend_trade(209, 500, 61.0)?;
+    let remaining: Vec<String> = snapshotted.position_snapshots.as_ref().unwrap().dates.iter().map(|date| date.to_string()).collect();
+    println!("After amending trade 209: snapshots {:?}; matches full replay: {}", remaining, agrees(&snapshotted)?);
+    let reopened = PositionSnapshotStore::open(&snapshot_dir)?;
+    println!("Snapshots found on reopening the directory: {}", reopened.dates.len());
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+
+    // Back-dated amends and history queries only touch the amended instrument's trades
+    println!("\n=== Instrument Trade Index ===");
+    let mut indexed = TradeRepository::new();
+    let index_start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap();
+    for i in 0..20000 {
+        let date = index_start + chrono::Duration::days((i / 40) as i64);
+        let side = if i % 5 == 4 { Side::Sell } else { Side::Buy };
+        indexed.add_trade(Trade::new(10_000 + i, date, format!("IDX{}", i % 40), 10, 50.0 + (i % 7) as f64, side))?;
+    }
+    let started = Instant::now();
+    for i in 0..200 {
+        indexed.amend_trade(10_000 + i * 40, 12, 51.0)?;
+    }
+    let amend_time = started.elapsed();
+    let started = Instant::now();
+    let history = indexed.get_position_history("IDX0", index_start, index_start + chrono::Duration::days(499));
+    println!("200 back-dated amends in {:.1?}; {} days of IDX0 history in {:.1?}, closing at {} shares",
+             amend_time, history.len(), started.elapsed(), history.last().map_or(0, |(_, position)| position.quantity));
+
+    // Dashboard figures over the whole index book: exact, then from a 2,000-trade sample
+    println!("\n=== Approximate Analytics ===");
+    for mode in [AnalyticsMode::Exact, AnalyticsMode::Approximate { sample_size: 2000, seed: 42 }] {
+        let started = Instant::now();
+        let statistics = indexed.trade_statistics(&TradeFilter::new().side(Side::Buy), mode);
+        print_trade_statistics(&statistics);
+        println!("  in {:.1?}", started.elapsed());
+    }
+
+    // The same day of fills booked one by one and as a single batch
+    println!("\n=== Batch Ingestion ===");
+    let fill_day = NaiveDate::from_ymd_opt(2022, 10, 3).unwrap();
+    let fills: Vec<Trade> = (0..20000)
+        .map(|i| {
+            let side = if i % 3 == 2 { Side::Sell } else { Side::Buy };
+            let executed_at = fill_day.and_hms_opt(9, 30, 0).unwrap().and_utc() + chrono::Duration::milliseconds(i as i64 * 977 % 20_000_000);
+            Trade::new(50_000 + i, fill_day, format!("FILL{}", i % 20), 10 + i % 7, 25.0 + (i % 11) as f64 * 0.1, side).with_execution_time(executed_at)
+        })
+        .collect();
+    let mut one_by_one = TradeRepository::new();
+    let started = Instant::now();
+    for fill in fills.iter().cloned() {
+        one_by_one.add_trade(fill)?;
+    }
+    let single_time = started.elapsed();
+    let mut batched = TradeRepository::new();
+    let mut batch = fills.clone();
+    batch.push(Trade::new(50_000, fill_day, "FILL0".to_string(), 10, 25.0, Side::Buy));
+    batch.push(Trade::new(70_000, fill_day, "FILL0".to_string(), 0, 25.0, Side::Buy));
+    let started = Instant::now();
+    let report = batched.add_trades_batch(batch)?;
+    let batch_time = started.elapsed();
+    println!("{} trades one by one in {:.1?}; batch of {} in {:.1?}: {} accepted, {} rejected",
+             fills.len(), single_time, report.results.len(), batch_time, report.accepted, report.rejected);
+    for (trade_id, result) in report.results.iter().filter(|(_, result)| result.is_err()) {
+        println!("Rejected trade {}: {}", trade_id, result.as_ref().unwrap_err());
+    }
+    println!("Same positions: {}", diff_repositories(&one_by_one, &batched).is_empty());
+
+    // Requests through the service layer with keys scoped by role and account
+    println!("\n=== API Keys and Request Audit ===");
+    let opened = NaiveDate::from_ymd_opt(2022, 11, 1).unwrap().and_hms_opt(9, 0, 0).unwrap().and_utc();
+    let mut service = TradeService::new(TradeRepository::new(), ApiKeyStore::new().with_rotation_grace(chrono::Duration::minutes(5)));
+    let admin = service.bootstrap_admin_key("operator", opened);
+    let create = |name: &str, role: Role, accounts: &[&str]| ServiceRequest::CreateKey {
+        name: name.to_string(),
+        role,
+        accounts: if accounts.is_empty() { None } else { Some(accounts.iter().map(|account| account.to_string()).collect()) },
+        expires_at: None,
+    };
+    let token = |response: ServiceResponse| match response {
+        ServiceResponse::Key { token, .. } => token,
+        other => panic!("expected a key, got {:?}", other),
+    };
+    let desk = token(service.handle(&admin, create("equity desk", Role::Trader, &["EQ1"]), opened)?);
+    let viewer = token(service.handle(&admin, create("risk viewer", Role::Viewer, &[]), opened)?);
+    let at = |minutes: i64| opened + chrono::Duration::minutes(minutes);
+    let desk_trade = |trade_id: i32, account: &str| ServiceRequest::AddTrade(Box::new(
+        Trade::new(trade_id, opened.date_naive(), "NVDA".to_string(), 100, 140.0, Side::Buy).with_account(account)));
+    let requests = vec![
+        (desk.clone(), desk_trade(210, "EQ1"), at(1)),
+        (desk.clone(), desk_trade(211, "EQ2"), at(2)),
+        (viewer.clone(), desk_trade(212, "EQ1"), at(3)),
+        (viewer.clone(), ServiceRequest::Position { instrument: "NVDA".to_string(), account_id: None }, at(4)),
+        (desk.clone(), ServiceRequest::Position { instrument: "NVDA".to_string(), account_id: None }, at(5)),
+        (desk.clone(), ServiceRequest::AmendTrade { trade_id: TradeId::from(210), quantity: 150, price: Price::from(139.5) }, at(6)),
+        ("key-0009.0000".to_string(), ServiceRequest::CancelTrade(TradeId::from(210)), at(7)),
+    ];
+    for (token, request, received_at) in requests {
+        if let Err(error) = service.handle(&token, request, received_at) {
+            println!("Refused: {}", error);
+        }
+    }
+    let rotated = token(service.handle(&admin, ServiceRequest::RotateKey("key-0002".to_string()), at(10))?);
+    println!("Old desk key during grace: {}", service.handle(&desk, ServiceRequest::Trades(TradeFilter::new()), at(12)).is_ok());
+    println!("Old desk key after grace: {}", service.handle(&desk, ServiceRequest::Trades(TradeFilter::new()), at(16)).is_ok());
+    if let ServiceResponse::Trades(trades) = service.handle(&rotated, ServiceRequest::Trades(TradeFilter::new()), at(17))? {
+        println!("Desk sees {} trade(s); trade 210 amended by {}", trades.len(), service.repository.audit_log.last().map_or("-", |entry| entry.actor.as_str()));
+    }
+    if let ServiceResponse::Positions(positions) = service.handle(&rotated, ServiceRequest::Positions { account_id: Some("EQ1".to_string()) }, at(18))? {
+        for position in &positions {
+            println!("EQ1 {}: {} @ {:.2}", position.instrument, position.quantity, position.average_price);
+        }
+    }
+    if let Err(error) = service.handle(&rotated, ServiceRequest::Positions { account_id: None }, at(18)) {
+        println!("Refused: {}", error);
+    }
+    if let ServiceResponse::Pnl { realized, unrealized, market_value } = service.handle(&viewer, ServiceRequest::Pnl { account_id: None }, at(19))? {
+        println!("Book P&L: realized {:.2}, unrealized {:.2}, market value {:.2}", realized, unrealized, market_value);
+    }
+    service.handle(&admin, ServiceRequest::RevokeKey("key-0003".to_string()), at(20))?;
+    if let Err(error) = service.handle(&viewer, ServiceRequest::Trades(TradeFilter::new()), at(21)) {
+        println!("Refused: {}", error);
+    }
+    if let ServiceResponse::RequestLog(records) = service.handle(&admin, ServiceRequest::RequestLog, at(25))? {
+        print_request_log(&records);
+    }
+    service.keys.print_keys(at(25));
+
+    // The same requests over HTTP (`--features server`)
+    #[cfg(feature = "server")]
+    {
+        let mut http_service = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+        let http_admin = http_service.bootstrap_admin_key("operator", Utc::now());
+        let mut server = PositionServer::start("127.0.0.1:0", Arc::new(Mutex::new(http_service)))?;
+        let send = |method: &str, target: &str, body: &str| -> std::io::Result<String> {
+            let mut stream = std::net::TcpStream::connect(server.local_addr())?;
+            write!(stream, "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}", method, target, http_admin, body.len(), body)?;
+            let mut response = String::new();
+            std::io::Read::read_to_string(&mut stream, &mut response)?;
+            Ok(response.lines().next().unwrap_or_default().to_string() + " " + response.split("\r\n\r\n").nth(1).unwrap_or_default())
+        };
+        let mut updates = std::io::BufReader::new(std::net::TcpStream::connect(server.local_addr())?);
+        write!(updates.get_mut(), "GET /stream?account=EQ1 HTTP/1.1\r\nAuthorization: Bearer {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", http_admin)?;
+        let mut handshake = Vec::new();
+        loop {
+            let mut line = String::new();
+            std::io::BufRead::read_line(&mut updates, &mut line)?;
+            if line.trim_end().is_empty() {
+                break;
+            }
+            handshake.push(line.trim_end().to_string());
+        }
+        println!("Stream handshake: {}", handshake.join("; "));
+        let booked = trade_to_json(&Trade::new(220, opened.date_naive(), "AMD".to_string(), 300, 118.0, Side::Buy).with_account("EQ1"));
+        let calls = [
+            ("POST", "/trades", booked.as_str()),
+            ("POST", "/trades", "{\"trade_id\": 221}"),
+            ("PATCH", "/trades/220", "{\"quantity\": 250, \"price\": 117.5}"),
+            ("GET", "/positions?account=EQ1", ""),
+            ("GET", "/pnl", ""),
+            ("GET", "/trades?instrument=AMD&side=buy", ""),
+            ("DELETE", "/trades/220", ""),
+            ("DELETE", "/trades/220", ""),
+            ("PUT", "/positions", ""),
+        ];
+        for (method, target, body) in calls {
+            match send(method, target, body) {
+                Ok(reply) => println!("{} {} -> {}", method, target, reply),
+                Err(e) => println!("{} {} failed: {}", method, target, e),
+            }
+        }
+        // Frames from the server are unmasked; stop once none arrive for a while
+        updates.get_ref().set_read_timeout(Some(Duration::from_millis(200)))?;
+        let mut header = [0u8; 2];
+        while std::io::Read::read_exact(&mut updates, &mut header).is_ok() {
+            let mut length = (header[1] & 0x7f) as usize;
+            if length == 126 {
+                let mut extended = [0u8; 2];
+                std::io::Read::read_exact(&mut updates, &mut extended)?;
+                length = u16::from_be_bytes(extended) as usize;
+            }
+            let mut payload = vec![0; length];
+            std::io::Read::read_exact(&mut updates, &mut payload)?;
+            println!("Streamed: {}", String::from_utf8_lossy(&payload));
+        }
+        server.shutdown();
+    }
+
+    // And over gRPC (`--features grpc`), with a P&L subscription open throughout
+    #[cfg(feature = "grpc")]
+    {
+        let mut grpc_service = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+        let grpc_admin = grpc_service.bootstrap_admin_key("operator", Utc::now());
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let address = listener.local_addr()?;
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let shared = Arc::new(Mutex::new(grpc_service));
+            let server = tokio::spawn(serve_grpc(listener, Arc::clone(&shared), async move { let _ = stopped.await; }));
+            let mut client = grpc::position_service_client::PositionServiceClient::connect(format!("http://{}", address)).await?;
+            let mut updates = client.subscribe_pnl(grpc_request(grpc::SubscribeRequest { instrument: Some("AMD".to_string()), account_id: None }, &grpc_admin)?).await?.into_inner();
+            let trade = grpc::Trade {
+                trade_id: "230".to_string(),
+                trade_date: opened.date_naive().format("%Y-%m-%d").to_string(),
+                instrument: "AMD".to_string(),
+                quantity: 300,
+                price: "118.00".to_string(),
+                side: grpc::Side::Buy as i32,
+                account_id: Some("EQ1".to_string()),
+                strategy: None,
+                broker: Some("ALPHA".to_string()),
+                commission: Some("3.00".to_string()),
+            };
+            client.book_trade(grpc_request(grpc::BookTradeRequest { trade: Some(trade) }, &grpc_admin)?).await?;
+            client.amend_trade(grpc_request(grpc::AmendTradeRequest { trade_id: "230".to_string(), quantity: 250, price: "117.50".to_string() }, &grpc_admin)?).await?;
+            shared.lock().unwrap().repository.update_market_price("AMD", 119.25)?;
+            let positions = client.list_positions(grpc_request(grpc::PositionsRequest { account_id: Some("EQ1".to_string()) }, &grpc_admin)?).await?.into_inner().positions;
+            println!("gRPC positions: {:?}", positions.iter().map(|position| (position.instrument.as_str(), position.quantity)).collect::<Vec<_>>());
+            let pnl = client.get_pnl(grpc_request(grpc::PnlRequest { account_id: None }, &grpc_admin)?).await?.into_inner();
+            println!("gRPC P&L: realized {}, unrealized {}, market value {}", pnl.realized_pnl, pnl.unrealized_pnl, pnl.market_value);
+            let position = client.get_position(grpc_request(grpc::PositionRequest { instrument: "AMD".to_string(), account_id: None }, &grpc_admin)?).await?.into_inner().position;
+            println!("gRPC AMD position: {:?}", position.map(|position| (position.quantity, position.average_price)));
+            if let Err(status) = client.cancel_trade(grpc_request(grpc::CancelTradeRequest { trade_id: "999".to_string() }, &grpc_admin)?).await {
+                println!("gRPC refused: {:?} {}", status.code(), status.message());
+            }
+            while let Ok(Some(update)) = tokio::time::timeout(Duration::from_millis(200), updates.message()).await.unwrap_or(Ok(None)) {
+                match update.update {
+                    Some(grpc::position_update::Update::Position(change)) => println!("gRPC stream {}: {} -> {}", change.instrument, change.previous_quantity, change.quantity),
+                    Some(grpc::position_update::Update::Pnl(change)) => println!("gRPC stream {} P&L: realized {}, unrealized {}", change.instrument, change.realized_pnl, change.unrealized_pnl),
+                    None => {}
+                }
+            }
+            // A graceful shutdown waits for open connections, the subscription among them
+            drop(updates);
+            drop(client);
+            let _ = stop.send(());
+            server.await??;
+            Ok::<(), Box<dyn std::error::Error>>(())
+        })?;
+    }
+
+
+    // A venue whose clock runs 1.5s ahead of ours, and a slow but correct one
+    println!("\n=== Source and Receive Timestamps ===");
+    let mut stamped = TradeRepository::new();
+    let open_time = NaiveDate::from_ymd_opt(2022, 11, 2).unwrap().and_hms_opt(14, 30, 0).unwrap().and_utc();
+    let ms = |offset: i64| open_time + chrono::Duration::milliseconds(offset);
+    let fill = |trade_id: i32, quantity: i32, price: f64, side: Side, executed: i64| {
+        Trade::new(trade_id, open_time.date_naive(), "AMD".to_string(), quantity, price, side)
+            .with_execution_time(ms(executed))
+            .with_source_time(ms(executed))
+    };
+    stamped.ingest_trade("VENUE-SLOW", fill(220, 200, 100.0, Side::Buy, 0), ms(400))?;
+    stamped.ingest_trade("VENUE-SKEW", fill(221, 100, 101.0, Side::Buy, 2_000), ms(600))?;
+    stamped.ingest_trade("VENUE-SLOW", fill(222, 150, 102.0, Side::Sell, 2_500), ms(3_300))?;
+    stamped.ingest_price("VENUE-SKEW", "AMD", 101.5, ms(2_100), ms(700))?;
+    stamped.ingest_price("VENUE-SLOW", "AMD", 101.2, ms(1_000), ms(1_350))?;
+    println!("AMD mark after the late 101.2 tick: {} from {}", stamped.get_market_price("AMD").unwrap(), stamped.mark_source("AMD").unwrap());
+    stamped.ingest_price("VENUE-SLOW", "AMD", 103.0, ms(3_000), ms(3_600))?;
+    stamped.print_latency_report();
+    stamped.print_pnl_as_of(ms(2_800), TimestampDomain::Source);
+    stamped.print_pnl_as_of(ms(2_800), TimestampDomain::Receive);
+
+    // The same book under three id schemes; ids round-trip through the journal
+    println!("\n=== Trade Id Schemes ===");
+    let epoch = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let mut snowflakes = SnowflakeIds::new(37, epoch);
+    let stamp = NaiveDate::from_ymd_opt(2022, 11, 3).unwrap().and_hms_opt(15, 0, 0).unwrap().and_utc();
+    let flakes = [snowflakes.id_at(stamp), snowflakes.id_at(stamp), snowflakes.id_at(stamp - chrono::Duration::seconds(2))];
+    println!("Snowflakes {:?}, increasing after a clock step back: {}", flakes, flakes.windows(2).all(|pair| pair[0] < pair[1]));
+    println!("Snowflake {} decodes to {:?}", flakes[0], snowflakes.decode(flakes[0]));
+    let schemes: Vec<(&str, SharedIdGenerator)> = vec![
+        ("sequential", Arc::new(Mutex::new(SequentialIds::starting_at(5_000_000_000)))),
+        ("snowflake", Arc::new(Mutex::new(SnowflakeIds::new(37, epoch)))),
+        ("uuid v7", Arc::new(Mutex::new(Uuid7Ids::new()))),
+    ];
+    for (scheme, generator) in schemes {
+        let mut ids_repo = TradeRepository::new();
+        ids_repo.set_id_generator(generator);
+        let mut booked = Vec::new();
+        for (quantity, side) in [(300, Side::Buy), (100, Side::Sell), (50, Side::Buy)] {
+            let trade_id = ids_repo.next_trade_id();
+            ids_repo.add_trade(Trade::new(trade_id, stamp.date_naive(), "ORCL".to_string(), quantity, 120.0, side))?;
+            booked.push(trade_id);
+        }
+        ids_repo.cancel_trade(booked[1])?;
+        let replayed = TradeRepository::replay(&ids_repo.journal)?;
+        let round_trip = booked.iter().all(|id| TradeId::parse(&id.to_string()).is_ok_and(|parsed| parsed == *id));
+        println!("{:<10} first id {:<36} ordered {} parses back {} replay ORCL {} shares",
+                 scheme, booked[0].to_string(), booked.windows(2).all(|pair| pair[0] < pair[1]), round_trip,
+                 replayed.get_position("ORCL").map_or(0, |position| position.quantity));
+    }
+
+    // Cancelling or amending an older trade replays the trades that remain, so the
+    // position matches one booked from scratch without the change
+    println!("\n=== Out-of-Order Cancels and Amends ===");
+    let day = |d| NaiveDate::from_ymd_opt(2022, 6, d).unwrap();
+    let history = [
+        Trade::new(301, day(1), "NFLX".to_string(), 100, 100.0, Side::Buy),
+        Trade::new(302, day(2), "NFLX".to_string(), 100, 120.0, Side::Buy),
+        Trade::new(303, day(3), "NFLX".to_string(), 150, 130.0, Side::Sell),
+        Trade::new(304, day(4), "NFLX".to_string(), 100, 90.0, Side::Sell),
+        Trade::new(305, day(5), "NFLX".to_string(), 80, 95.0, Side::Buy),
+    ];
+    let expected = |trades: Vec<Trade>| -> Result<TradePosition, PositionError> {
+        let mut fresh = TradeRepository::new();
+        for trade in trades {
+            fresh.add_trade(trade)?;
+        }
+        Ok(fresh.get_position("NFLX").unwrap().clone())
+    };
+    let same = |a: &TradePosition, b: &TradePosition| a.quantity == b.quantity && a.average_price == b.average_price && a.realized_pnl == b.realized_pnl;
+    let mut reordered = TradeRepository::new();
+    let concurrent_book = ConcurrentTradeRepository::new();
+    for trade in &history {
+        reordered.add_trade(trade.clone())?;
+        concurrent_book.add_trade(trade.clone())?;
+    }
+    let mut remaining = history.to_vec();
+    for (label, trade_id) in [("cancel 302 (second buy)", 302), ("cancel 301 (first buy)", 301)] {
+        reordered.cancel_trade(trade_id)?;
+        concurrent_book.cancel_trade(trade_id)?;
+        remaining.retain(|trade| trade.trade_id != TradeId::from(trade_id));
+        let position = reordered.get_position("NFLX").unwrap();
+        let rebuilt = expected(remaining.clone())?;
+        println!("{:<26} {} @ {:.2}, realized {:.2}; matches replay: {}, concurrent: {}",
+                 label, position.quantity, position.average_price, position.realized_pnl, same(position, &rebuilt),
+                 same(&concurrent_book.get_position("NFLX").unwrap(), &rebuilt));
+    }
+    reordered.amend_trade(303, 60, 125.0)?;
+    concurrent_book.amend_trade(303, 60, 125.0)?;
+    remaining.iter_mut().filter(|trade| trade.trade_id == TradeId::from(303)).for_each(|trade| {
+        trade.quantity = 60;
+        trade.price = Decimal::from(125);
+    });
+    let position = reordered.get_position("NFLX").unwrap();
+    let rebuilt = expected(remaining.clone())?;
+    println!("{:<26} {} @ {:.2}, realized {:.2}; matches replay: {}, concurrent: {}",
+             "amend 303 to 60 @ 125", position.quantity, position.average_price, position.realized_pnl, same(position, &rebuilt),
+             same(&concurrent_book.get_position("NFLX").unwrap(), &rebuilt));
+    let last_day = reordered.get_position_history("NFLX", day(5), day(5));
+    println!("Daily table on {} agrees: {}", day(5), last_day.first().is_some_and(|(_, closing)| same(closing, position)));
+
+    // Exposures go out as CSV for an external optimizer; its weights come back as trades
+    println!("\n=== Optimizer Export and Import ===");
+    let mut optimized = TradeRepository::new();
+    let optimizer_date = NaiveDate::from_ymd_opt(2022, 7, 1).unwrap();
+    for (trade_id, instrument, quantity, price, sector, beta) in [
+        (401, "AAPL", 400, 160.0, "Technology", 1.2),
+        (402, "MSFT", 100, 300.0, "Technology", 0.9),
+        (403, "KO", 500, 60.0, "Staples", 0.6),
+        (404, "XOM", 200, 80.0, "Energy", 1.1),
+    ] {
+        optimized.add_trade(Trade::new(trade_id, optimizer_date.pred_opt().unwrap(), instrument.to_string(), quantity, price, Side::Buy))?;
+        optimized.update_market_price(instrument, price)?;
+        optimized.set_instrument_sector(instrument, sector);
+        optimized.set_beta(instrument, beta);
+    }
+    let optimizer_limits = RebalanceConstraints::new()
+        .max_turnover(0.5)
+        .max_trade_notional_for("MSFT", 10000.0)
+        .do_not_trade("KO");
+    let export = optimized.optimizer_export(optimizer_date, &optimizer_limits);
+    print!("{}", export.exposures_csv());
+    print!("{}", export.constraints_csv());
+    let weights = "instrument,weight\nAAPL,0.40\nMSFT,0.28\nKO,0.2143\nXOM,0.1057\n";
+    let plan = optimized.import_optimizer_weights(weights, optimizer_date, &optimizer_limits)?;
+    optimized.print_rebalance_plan(&plan);
+    for bad in ["AAPL,0.5\nAAPL,0.5", "AAPL,half"] {
+        if let Err(e) = optimized.import_optimizer_weights(bad, optimizer_date, &optimizer_limits) {
+            println!("Rejected: {}", e);
+        }
+    }
+
+    // Amend by date refuses to guess between trades; richer amendments move trades
+    // between instruments and dates, and each trade keeps its amendment history
+    println!("\n=== Amend Requests and History ===");
+    let mut amending = TradeRepository::new();
+    let amend_day = NaiveDate::from_ymd_opt(2022, 8, 1).unwrap();
+    amending.add_trade(Trade::new(501, amend_day, "AMZN".to_string(), 100, 130.0, Side::Buy))?;
+    amending.add_trade(Trade::new(502, amend_day, "AMZN".to_string(), 40, 131.0, Side::Buy))?;
+    amending.add_trade(Trade::new(503, amend_day.succ_opt().unwrap(), "GOOG".to_string(), 60, 110.0, Side::Buy))?;
+    match amending.amend_trade_by_date("AMZN", amend_day, 50, 129.0) {
+        Ok(()) => println!("Amended by date"),
+        Err(e) => println!("Rejected: {}", e),
+    }
+    amending.cancel_trade(502)?;
+    amending.amend_trade_by_date("AMZN", amend_day, 120, 129.5)?;
+    // Booked against the wrong ticker and day, and as a buy instead of a sell
+    amending.amend(503, AmendRequest::new().instrument("GOOGL").trade_date(amend_day).side(Side::Sell))?;
+    amending.amend(503, AmendRequest::new().quantity(45))?;
+    for instrument in ["AMZN", "GOOG", "GOOGL"] {
+        let position = amending.get_position(instrument).unwrap();
+        println!("{}: {} @ {:.2}", instrument, position.quantity, position.average_price);
+    }
+    amending.print_amendment_history(501);
+    amending.print_amendment_history(503);
+    if let Err(e) = amending.amend(503, AmendRequest::new().quantity(-5)) {
+        println!("Rejected: {}", e);
+    }
+
+    // Who traded what with whom, weekly, plus the data for a sankey diagram
+    println!("\n=== Trade Flows ===");
+    let mut flows = TradeRepository::new();
+    let flow_day = |d| NaiveDate::from_ymd_opt(2022, 9, d).unwrap();
+    for (trade_id, d, account, counterparty, instrument, quantity, price, side) in [
+        (601, 5, "ACC-A", Some("GS"), "AAPL", 100, 150.0, Side::Buy),
+        (602, 6, "ACC-A", Some("GS"), "AAPL", 40, 152.0, Side::Sell),
+        (603, 6, "ACC-B", Some("MS"), "AAPL", 80, 151.0, Side::Sell),
+        (604, 7, "ACC-B", Some("GS"), "MSFT", 50, 250.0, Side::Buy),
+        (605, 13, "ACC-A", None, "MSFT", 30, 255.0, Side::Buy),
+        (606, 14, "ACC-B", Some("MS"), "MSFT", 30, 256.0, Side::Sell),
+    ] {
+        let trade = Trade::new(trade_id, flow_day(d), instrument.to_string(), quantity, price, side).with_account(account);
+        flows.add_trade(match counterparty {
+            Some(counterparty) => trade.with_counterparty(counterparty),
+            None => trade,
+        })?;
+    }
+    flows.print_trade_flows(flow_day(1), flow_day(30), FlowPeriod::Week);
+    let monthly = flows.trade_flows(flow_day(1), flow_day(30), FlowPeriod::Month);
+    println!("{}", monthly.sankey_json());
+    // The weekly buyers and sellers above were never in the market on the same day
+    let daily = flows.trade_flows(flow_day(1), flow_day(30), FlowPeriod::Day);
+    println!("Same-day crossing opportunities: {}", daily.crossing_opportunities().len());
+
+    // A background thread snapshots risk while the book trades; the last few are
+    // kept in memory and all of them on disk for a post-mortem
+    println!("\n=== Intraday Risk Snapshots ===");
+    let risk_path = std::env::temp_dir().join("rustopos_risk_snapshots.ndjson").to_string_lossy().into_owned();
+    let _ = std::fs::remove_file(&risk_path);
+    let live = Arc::new(RwLock::new(TradeRepository::new()));
+    {
+        let mut book = live.write().unwrap();
+        book.set_implied_volatility("AAPL", 0.30);
+        book.set_implied_volatility("TSLA", 0.60);
+    }
+    let history = RiskSnapshotHistory::new(5).persist_to(&risk_path)?;
+    let snapshotter = RiskSnapshotter::start(Arc::clone(&live), Duration::from_millis(20), history);
+    let risk_day = NaiveDate::from_ymd_opt(2022, 10, 3).unwrap();
+    for step in 0..10 {
+        {
+            let mut book = live.write().unwrap();
+            book.add_trade(Trade::new(700 + step, risk_day, "AAPL".to_string(), 100, 140.0, Side::Buy))?;
+            if step % 3 == 0 {
+                book.add_trade(Trade::new(800 + step, risk_day, "TSLA".to_string(), 50, 220.0, Side::Sell))?;
+            }
+            book.update_market_price("AAPL", 140.0 - step as f64)?;
+        }
+        std::thread::sleep(Duration::from_millis(15));
+    }
+    std::thread::sleep(Duration::from_millis(30));
+    println!("Snapshots while running: {}", snapshotter.snapshots().len());
+    let history = snapshotter.stop();
+    let persisted = RiskSnapshotHistory::load(&risk_path)?;
+    println!("Kept in memory: {} (capacity {}); at least as many on disk: {}, write failures: {}",
+             history.snapshots.len(), history.capacity, persisted.len() >= history.snapshots.len(), history.persist_failures);
+    let first_taken = persisted.first().map(|snapshot| snapshot.taken_at).unwrap_or_else(Utc::now);
+    println!("Kept snapshots all after the first persisted one: {}", history.between(first_taken, Utc::now()).len() == history.snapshots.len());
+    let latest = live.read().unwrap().risk_snapshot(Utc::now());
+    println!("Now: long ${:.2}, short ${:.2}, net ${:.2}, gross ${:.2}, unrealized ${:.2}, VaR(99%, 1d) ${:.2}",
+             latest.long_exposure, latest.short_exposure, latest.net_exposure(), latest.gross_exposure(), latest.unrealized_pnl, latest.var_99);
+    let round_trip = RiskSnapshot::from_json(&latest.to_json()).map_err(PositionError::InvalidRecord)?;
+    println!("Snapshot reads back exactly: {}", round_trip.var_99 == latest.var_99 && round_trip.positions == latest.positions);
+
+    // Orders work against the market and book their executions as trades
+    println!("\n=== Market Data Feeds ===");
+    let mut streamed = TradeRepository::new();
+    streamed.add_trade(Trade::new(1, risk_day, "AAPL".to_string(), 100, 140.0, Side::Buy))?;
+    streamed.add_trade(Trade::new(2, risk_day, "TSLA".to_string(), 50, 220.0, Side::Sell))?;
+    let opened = risk_day.and_hms_opt(9, 30, 0).unwrap().and_utc();
+    let mut simulated = SimulatedFeed::new("SIM", 7, 0.002, opened, chrono::Duration::seconds(1))
+        .start_price("AAPL", 140.0)
+        .start_price("TSLA", 220.0)
+        .start_price("NVDA", 120.0)
+        .limit(20);
+    simulated.subscribe("AAPL");
+    simulated.subscribe("TSLA");
+    let stream = QuoteStream::start(simulated, Duration::from_millis(2));
+    let mut applied = 0;
+    while let Some(quote) = stream.next_quote(Duration::from_millis(200)) {
+        streamed.ingest_price("SIM", &quote.instrument, quote.price, quote.source_time, Utc::now())?;
+        applied += 1 + streamed.apply_quotes(&stream)?;
+        if applied >= 10 {
+            stream.unsubscribe("TSLA");
+            stream.subscribe("NVDA");
+        }
+    }
+    stream.stop();
+    let (_, unrealized, _) = streamed.calculate_portfolio_pnl();
+    println!("Applied {} simulated quotes: AAPL {:?}, TSLA {:?}, NVDA {:?}, unrealized ${:.2}",
+             applied, streamed.get_market_price("AAPL"), streamed.get_market_price("TSLA"), streamed.get_market_price("NVDA"), unrealized);
+    let recorded = "time,instrument,price\n2022-10-03T13:30:00Z,AAPL,141.10\n2022-10-03T13:30:00Z,MSFT,240.00\n2022-10-03T13:30:01Z,AAPL,141.25\n";
+    let mut replay = CsvReplayFeed::new("REPLAY", recorded).map_err(PositionError::InvalidRecord)?;
+    replay.subscribe("AAPL");
+    let stream = QuoteStream::start(replay, Duration::from_millis(1));
+    std::thread::sleep(Duration::from_millis(20));
+    println!("Replayed {} quotes, AAPL now {:?}", streamed.apply_quotes(&stream)?, streamed.get_market_price("AAPL"));
+
+    println!("\n=== P&L Notifications ===");
+    let aapl_alerts = streamed.subscribe_pnl(PnlWatch::instrument("AAPL").threshold(0.0).threshold(250.0));
+    let book_alerts = streamed.subscribe_pnl(PnlWatch::all().change_fraction(0.5));
+    for price in [142.0, 144.0, 139.5, 138.0] {
+        streamed.update_market_price("AAPL", price)?;
+    }
+    streamed.update_market_price("TSLA", 200.0)?;
+    streamed.add_trade(Trade::new(3, risk_day, "AAPL".to_string(), 100, 138.0, Side::Buy))?;
+    for notification in aapl_alerts.try_iter().chain(book_alerts.try_iter()) {
+        let trigger = match notification.trigger {
+            PnlTrigger::Crossed { threshold, upward } => format!("crossed {:.2} {}", threshold, if upward { "upward" } else { "downward" }),
+            PnlTrigger::Changed { fraction } => format!("moved {:+.1}%", fraction * 100.0),
+        };
+        println!("{} {}: unrealized {:.2} -> {:.2} ({})", notification.raised_at.format("%H:%M:%S"), notification.instrument,
+                 notification.previous_unrealized_pnl, notification.unrealized_pnl, trigger);
+    }
+    drop(book_alerts);
+    streamed.update_market_price("TSLA", 150.0)?;
+    println!("Subscriptions after dropping a receiver and the next price: {}", streamed.pnl_subscriptions.len());
+
+    println!("\n=== Position Streams ===");
+    let aapl_stream = streamed.subscribe_positions(StreamFilter::new().instrument("AAPL"));
+    let desk_stream = streamed.subscribe_positions(StreamFilter::new().account("DESK-B"));
+    streamed.add_trade(Trade::new(4, risk_day, "TSLA".to_string(), 20, 151.0, Side::Buy).with_account("DESK-B"))?;
+    streamed.add_trade(Trade::new(5, risk_day, "AAPL".to_string(), 40, 139.0, Side::Sell).with_account("DESK-B"))?;
+    streamed.update_market_price("AAPL", 137.25)?;
+    streamed.update_market_price("NVDA", 121.0)?;
+    streamed.amend_trade(5, 60, 139.0)?;
+    streamed.cancel_trade(4)?;
+    for (name, receiver) in [("AAPL", &aapl_stream), ("DESK-B", &desk_stream)] {
+        for update in receiver.try_iter() {
+            match update {
+                PositionUpdate::Position { account_id, instrument, previous_quantity, quantity, average_price } => {
+                    println!("{:<7} {} {}: {} -> {} @ {:.2}", name, account_id.as_deref().unwrap_or("firm"), instrument, previous_quantity, quantity, average_price)
+                },
+                PositionUpdate::Pnl { account_id, instrument, price, realized_pnl, unrealized_pnl } => {
+                    println!("{:<7} {} {} at {:.2}: realized {:.2}, unrealized {:.2}", name, account_id.as_deref().unwrap_or("firm"), instrument, price, realized_pnl, unrealized_pnl)
+                },
+            }
+        }
+    }
+    drop(aapl_stream);
+    streamed.update_market_price("AAPL", 137.5)?;
+    println!("Streams after dropping one: {}; last DESK-B update for {}", streamed.position_streams.len(),
+             desk_stream.try_iter().last().map_or("-".to_string(), |update| update.instrument().to_string()));
+
+    println!("\n=== Order Management ===");
+    let mut ordered = TradeRepository::new();
+    let first_session = NaiveDate::from_ymd_opt(2022, 11, 1).unwrap();
+    let mut oms = OrderManager::new(first_session);
+    ordered.update_market_price("AAPL", 150.0)?;
+    oms.submit(&mut ordered, Order::market("AAPL", Side::Buy, 100))?;
+    let resting = oms.submit(&mut ordered, Order::limit("AAPL", Side::Buy, 100, 145.0).time_in_force(TimeInForce::GoodTillCancel))?;
+    let stop = oms.submit(&mut ordered, Order::stop("AAPL", Side::Sell, 50, 140.0).time_in_force(TimeInForce::GoodTillCancel))?;
+    println!("Working AAPL orders at 150: {}", oms.working_orders("AAPL").len());
+    for price in [144.0, 139.0] {
+        ordered.update_market_price("AAPL", price)?;
+        println!("AAPL at {}: executions {:?}", price, oms.on_market_update(&mut ordered, "AAPL")?);
+    }
+    println!("Limit {:?}, stop {:?}", oms.get_order(resting).map(|order| order.status), oms.get_order(stop).map(|order| order.status));
+
+    let book = BookSnapshot::new("MSFT", Utc::now()).bid(299.5, 100).ask(300.0, 100).ask(301.0, 50);
+    ordered.ingest_book_snapshot(book)?;
+    oms.submit(&mut ordered, Order::market("MSFT", Side::Buy, 200).time_in_force(TimeInForce::FillOrKill))?;
+    oms.submit(&mut ordered, Order::limit("MSFT", Side::Buy, 120, 300.5).time_in_force(TimeInForce::ImmediateOrCancel))?;
+    let sweep = oms.submit(&mut ordered, Order::market("MSFT", Side::Buy, 130).with_account("ACC-OMS"))?;
+    let working = oms.submit(&mut ordered, Order::limit("MSFT", Side::Sell, 80, 305.0).time_in_force(TimeInForce::GoodTillCancel))?;
+    let day_order = oms.submit(&mut ordered, Order::limit("MSFT", Side::Buy, 10, 290.0))?;
+    oms.fill(&mut ordered, working, 30, 305.2)?;
+    println!("Expired at end of day: {:?}", oms.end_of_day(first_session.succ_opt().unwrap()));
+    oms.print_orders();
+    println!("Sweep order average price: {:?}", oms.get_order(sweep).and_then(Order::average_fill_price));
+    for instrument in ["AAPL", "MSFT"] {
+        let position = ordered.get_position(instrument).unwrap();
+        println!("{}: {} @ {:.2}", instrument, position.quantity, position.average_price);
+    }
+    for result in [oms.cancel(day_order), oms.cancel(99), oms.fill(&mut ordered, working, 60, 305.0).map(|_| ()), oms.submit(&mut ordered, Order::market("NFLX", Side::Buy, 5)).map(|_| ())] {
+        if let Err(e) = result {
+            println!("Rejected: {}", e);
+        }
+    }
+
+    println!("\n=== Risk Limits ===");
+    let mut limited = TradeRepository::new();
+    let limit_date = NaiveDate::from_ymd_opt(2022, 11, 2).unwrap();
+    limited.update_market_price("AAPL", 150.0)?;
+    limited.update_market_price("MSFT", 300.0)?;
+    limited.set_risk_limits(RiskLimits::new(SanityAction::Flag).max_position("AAPL", 500).max_order_notional(60000.0).max_gross_exposure(150000.0));
+    limited.add_trade(Trade::new(1, limit_date, "AAPL".to_string(), 400, 150.0, Side::Buy))?;
+    limited.add_trade(Trade::new(2, limit_date, "AAPL".to_string(), 200, 150.5, Side::Buy))?;
+    limited.add_trade(Trade::new(3, limit_date, "MSFT".to_string(), 250, 300.0, Side::Buy))?;
+    limited.print_limit_flags();
+    limited.set_risk_limits(RiskLimits::new(SanityAction::Block).max_position("AAPL", 500).max_net_exposure(250000.0).restrict("GME"));
+    println!("Selling 1200 AAPL would breach: {:?}", limited.check_risk_limits("AAPL", &Side::Sell, 1200, Decimal::from(150.0)));
+    let mut limit_oms = OrderManager::new(limit_date);
+    for order in [Order::market("MSFT", Side::Buy, 100), Order::limit("GME", Side::Buy, 10, 25.0), Order::market("AAPL", Side::Buy, 100)] {
+        match limit_oms.submit(&mut limited, order) {
+            Ok(order_id) => println!("Order {} accepted", order_id),
+            Err(e) => println!("Rejected: {}", e),
+        }
+    }
+
+    println!("\n=== Matching Engine ===");
+    let mut simulated = TradeRepository::new();
+    let session = NaiveDate::from_ymd_opt(2022, 3, 1).unwrap();
+    let mut engine = MatchingEngine::new(session);
+    // Two makers quote the same ask; the earlier one trades first
+    engine.submit(&mut simulated, Order::limit("IBM", Side::Sell, 100, 125.0).with_account("MM-A"))?;
+    engine.submit(&mut simulated, Order::limit("IBM", Side::Sell, 100, 125.0).with_account("MM-B"))?;
+    engine.submit(&mut simulated, Order::limit("IBM", Side::Sell, 200, 125.5).with_account("MM-A").time_in_force(TimeInForce::GoodTillCancel))?;
+    engine.submit(&mut simulated, Order::limit("IBM", Side::Buy, 150, 124.5).with_account("MM-B"))?;
+    engine.print_book("IBM");
+    let sweep = engine.submit(&mut simulated, Order::limit("IBM", Side::Buy, 250, 125.5).with_account("STRAT"))?;
+    let killed = engine.submit(&mut simulated, Order::market("IBM", Side::Buy, 500).with_account("STRAT").time_in_force(TimeInForce::FillOrKill))?;
+    let hit = engine.submit(&mut simulated, Order::market("IBM", Side::Sell, 60).with_account("STRAT"))?;
+    let resting = engine.submit(&mut simulated, Order::limit("IBM", Side::Sell, 50, 126.0).with_account("STRAT"))?;
+    engine.print_fills();
+    engine.print_book("IBM");
+    println!("Sweep average {:?}, kill {:?}, hit {:?}, resting {:?}",
+             engine.get_order(sweep).and_then(Order::average_fill_price), engine.get_order(killed).map(|order| order.status),
+             engine.get_order(hit).map(|order| order.status), engine.get_order(resting).map(|order| order.status));
+    println!("IBM marked at {:?}, fills of the sweep: {}", simulated.get_market_price("IBM"), engine.fills_for(sweep).len());
+    for account in ["STRAT", "MM-A", "MM-B"] {
+        let positions = simulated.build_account_positions(account);
+        let ibm = positions.get("IBM").map_or(0, |position| position.quantity);
+        println!("{:<5} IBM position {:>5}", account, ibm);
+    }
+    engine.cancel(resting)?;
+    println!("Resting offer {:?} after cancel, cancelling again: {}", engine.get_order(resting).map(|order| order.status), engine.cancel(resting).unwrap_err());
+    println!("Expired at end of day: {:?}", engine.end_of_day(session.succ_opt().unwrap()));
+    println!("Best bid {:?}, best ask {:?}", engine.best_bid("IBM"), engine.best_ask("IBM"));
+
+    // Only the strategy's executions are booked; the makers are simulated
+    let mut strategy_only = TradeRepository::new();
+    let mut engine = MatchingEngine::new(session).book_only(&["STRAT"]);
+    engine.submit(&mut strategy_only, Order::limit("IBM", Side::Sell, 100, 125.0).with_account("MM-A"))?;
+    engine.submit(&mut strategy_only, Order::market("IBM", Side::Buy, 40).with_account("STRAT"))?;
+    println!("Strategy-only repository: {} trade(s), IBM {:?}", strategy_only.trades.len(), strategy_only.get_position("IBM").map(|position| position.quantity));
+    for result in [engine.submit(&mut strategy_only, Order::stop("IBM", Side::Sell, 10, 120.0)).map(|_| ()), engine.submit(&mut strategy_only, Order::market("IBM", Side::Sell, 10)).map(|_| ())] {
+        if let Err(e) = result {
+            println!("Rejected: {}", e);
+        }
+    }
+
+    println!("\n=== Execution Reports ===");
+    let mut executions = TradeRepository::new();
+    let report = |exec_id: &str, last_qty: i32, last_price: f64, cum_qty: i32, minute: u32| ExecutionReport {
+        order_id: 7001,
+        exec_id: exec_id.to_string(),
+        instrument: "ORCL".to_string(),
+        side: Side::Buy,
+        order_type: TradeType::Limit,
+        account_id: "ACC-EXE".to_string(),
+        counterparty: Some("BROKER-X".to_string()),
+        last_qty,
+        last_price: Decimal::from(last_price),
+        cum_qty,
+        leaves_qty: 1000 - cum_qty,
+        transact_time: NaiveDate::from_ymd_opt(2022, 3, 2).unwrap().and_hms_opt(14, minute, 0).unwrap().and_utc(),
+    };
+    for fill in [report("E1", 300, 88.10, 300, 30), report("E2", 450, 88.25, 750, 41), report("E2", 450, 88.25, 750, 41), report("E4", 100, 88.30, 950, 52), report("E3", 200, 88.20, 950, 48)] {
+        match executions.book_execution(fill) {
+            Ok(trade_id) => println!("Booked trade {}", trade_id),
+            Err(e) => println!("Rejected: {}", e),
+        }
+    }
+    executions.print_order_fills();
+    if let Some(view) = executions.order_fills(7001) {
+        println!("Order 7001: {} of {} filled at {:.4} between {} and {}", view.cum_qty, view.cum_qty + view.leaves_qty.unwrap_or(0),
+                 view.average_price, view.first_fill.format("%H:%M"), view.last_fill.format("%H:%M"));
+    }
+    println!("Position ORCL: {:?}", executions.get_position("ORCL").map(|position| (position.quantity, position.average_price)));
+    println!("Order manager fills (linked by order id):");
+    ordered.print_order_fills();
+
+    println!("\n=== FIX Execution Reports ===");
+    let mut session = TradeRepository::new();
+    let fix_report = |exec_id: &str, exec_type: &str, last_qty: i32, last_price: f64, cum_qty: i32| FixMessage::new("8")
+        .field(49, "BROKER-X").field(56, "DESK").field(37, 8001).field(17, exec_id).field(150, exec_type).field(39, if cum_qty == 500 { 2 } else { 1 })
+        .field(1, "ACC-FIX").field(55, "ORCL").field(54, 1).field(40, 2).field(32, last_qty).field(31, last_price)
+        .field(14, cum_qty).field(151, 500 - cum_qty).field(60, "20221103-14:30:05.250");
+    let inbound = vec![
+        fix_report("X1", "0", 0, 0.0, 0).encode(),
+        fix_report("X2", "F", 200, 89.10, 200).encode(),
+        fix_report("X3", "F", 300, 89.20, 500).encode(),
+        fix_report("X4", "G", 250, 89.15, 450).field(19, "X3").encode(),
+        fix_report("X5", "H", 200, 89.10, 250).field(19, "X2").encode(),
+        "8=FIX.4.4|9=5|35=8|10=000|".to_string(),
+    ];
+    for raw in &inbound {
+        match session.apply_fix_execution(raw) {
+            Ok(FixOutcome::Booked(trade_id)) => println!("Booked trade {}", trade_id),
+            Ok(FixOutcome::Cancelled(trade_id)) => println!("Cancelled trade {}", trade_id),
+            Ok(FixOutcome::Corrected(trade_id)) => println!("Corrected trade {}", trade_id),
+            Ok(FixOutcome::Ignored { exec_type }) => println!("Nothing to book for ExecType {}", exec_type),
+            Err(e) => println!("Rejected: {}", e),
+        }
+    }
+    println!("Position ORCL: {:?}", session.get_position("ORCL").map(|position| (position.quantity, position.average_price)));
+    let mut drop_copy = FixDropCopy::new("POSMGR", "MIDOFFICE").starting_at(41);
+    for message in drop_copy.encode_all(&session, &session.journal) {
+        println!("{}", message.replace(FIX_SOH, "|"));
+    }
+
+    println!("\n=== Trade Lifecycle ===");
+    let mut lifecycle = TradeRepository::new();
+    let day = NaiveDate::from_ymd_opt(2022, 3, 3).unwrap();
+    lifecycle.set_actor("trader");
+    lifecycle.submit_for_approval(Trade::new(1, day, "AMZN".to_string(), 20, 2900.0, Side::Buy))?;
+    lifecycle.submit_for_approval(Trade::new(2, day, "AMZN".to_string(), 500, 2950.0, Side::Buy))?;
+    println!("Held: trade 1 {:?}, AMZN position {:?}", lifecycle.held_trade(1).map(|trade| &trade.status), lifecycle.get_position("AMZN").map(|position| position.quantity));
+    lifecycle.approve_trade(1, "checker")?;
+    lifecycle.reject_trade(2, "checker", "over the desk limit")?;
+    lifecycle.amend_trade(1, 25, 2900.0)?;
+    lifecycle.amend_trade(1, 25, 2895.0)?;
+    lifecycle.cancel_trade(1)?;
+    lifecycle.add_trade(Trade::new(3, day, "AMZN".to_string(), 10, 2910.0, Side::Buy))?;
+    for id in [1, 2, 3] {
+        lifecycle.print_status_history(id);
+    }
+    let restored_cancelled = Trade { status: TradeStatus::Cancelled, ..Trade::new(4, day, "AMZN".to_string(), 5, 2900.0, Side::Sell) };
+    for result in [lifecycle.amend_trade(1, 30, 2900.0), lifecycle.approve_trade(2, "checker"), lifecycle.reject_trade(3, "checker", "late"), lifecycle.add_trade(restored_cancelled)] {
+        if let Err(e) = result {
+            println!("Rejected: {}", e);
+        }
+    }
+    println!("AMZN position: {:?}", lifecycle.get_position("AMZN").map(|position| position.quantity));
+
+    println!("\n=== Instrument Master ===");
+    let mut mastered = TradeRepository::new();
+    let day = NaiveDate::from_ymd_opt(2022, 3, 4).unwrap();
+    mastered.register_instrument(Instrument::new("ESM2", AssetClass::Future, "USD").multiplier(50).tick_size(0.25));
+    mastered.register_instrument(Instrument::new("7203.T", AssetClass::Equity, "JPY").tick_size(1).lot_size(100));
+    mastered.register_instrument(Instrument::new("EURUSD", AssetClass::Fx, "USD").tick_size(0.00001).lot_size(1000));
+    mastered.add_trade(Trade::new(1, day, "ESM2".to_string(), 2, 4500.25, Side::Buy))?;
+    mastered.add_trade(Trade::new(2, day, "ESM2".to_string(), 1, 4510.0, Side::Sell))?;
+    mastered.add_trade(Trade::new(3, day, "7203.T".to_string(), 300, 2105.0, Side::Buy))?;
+    mastered.update_market_price("ESM2", 4520.0)?;
+    let future = mastered.get_position("ESM2").unwrap();
+    println!("ESM2: {} contract(s) @ {} x {}, market value {}, realized {}, unrealized {}",
+             future.quantity, future.average_price, future.multiplier, future.market_value(Decimal::from(4520.0)), future.realized_pnl, future.unrealized_pnl(Decimal::from(4520.0)));
+    println!("ESM2 trade 1 notional {}, 7203.T quoted in {}", mastered.trades.get(&TradeId::from(1)).map_or(Decimal::ZERO, Trade::notional), mastered.instrument_currency("7203.T"));
+    if let Some(record) = mastered.instrument("EURUSD") {
+        println!("EURUSD: {:?} quoted in {}, tick {}, lot {}", record.asset_class, record.currency, record.tick_size, record.lot_size);
+    }
+    mastered.require_registered_instruments(true);
+    for trade in [Trade::new(4, day, "ESM2".to_string(), 1, 4500.1, Side::Buy), Trade::new(5, day, "7203.T".to_string(), 150, 2100.0, Side::Sell), Trade::new(6, day, "XYZ".to_string(), 10, 5.0, Side::Buy)] {
+        if let Err(e) = mastered.add_trade(trade) {
+            println!("Rejected: {}", e);
+        }
+    }
+    if let Err(e) = mastered.amend_trade(3, 250, 2105.0) {
+        println!("Rejected: {}", e);
+    }
+
+    println!("\n=== Option Expiry ===");
+    let mut expiring = TradeRepository::new();
+    let listed = NaiveDate::from_ymd_opt(2022, 2, 14).unwrap();
+    let expiry = NaiveDate::from_ymd_opt(2022, 3, 18).unwrap();
+    for (instrument, right, strike, roll_rule) in [("MSFT220318C300", OptionRight::Call, 300.0, RollRule::Exercise), ("MSFT220318P280", OptionRight::Put, 280.0, RollRule::Close), ("MSFT220318C320", OptionRight::Call, 320.0, RollRule::Exercise)] {
+        expiring.register_option(DerivativeContract::new(instrument.to_string(), "MSFT".to_string(), ContractKind::Option, expiry, roll_rule)
+            .with_option_terms(right, strike, 100.0))?;
+    }
+    expiring.add_trade(Trade::new(1, listed, "MSFT220318C300".to_string(), 2, 5.0, Side::Buy))?;
+    expiring.add_trade(Trade::new(2, listed, "MSFT220318P280".to_string(), 1, 4.0, Side::Sell))?;
+    expiring.add_trade(Trade::new(3, listed, "MSFT220318C320".to_string(), 3, 1.2, Side::Buy))?;
+    let settlement_prices = HashMap::from([("MSFT".to_string(), Decimal::from(310))]);
+    for event in expiring.process_expiries(expiry, &settlement_prices) {
+        println!("  {}", event);
+    }
+    for instrument in ["MSFT220318C300", "MSFT220318P280", "MSFT220318C320", "MSFT"] {
+        let position = expiring.get_position(instrument).unwrap();
+        println!("{}: {} @ {}, realized {}", instrument, position.quantity, position.average_price, position.realized_pnl);
+    }
+
+    println!("\n=== Futures Daily Settlement ===");
+    let mut futures = TradeRepository::new();
+    let holiday = NaiveDate::from_ymd_opt(2022, 3, 9).unwrap();
+    futures.register_instrument(Instrument::new("ESM2", AssetClass::Future, "USD").multiplier(50).tick_size(0.25));
+    futures.set_settlement_calendar("ESM2", SettlementCalendar::new("CME").holiday(holiday));
+    let days: Vec<NaiveDate> = (7..=10).map(|day| NaiveDate::from_ymd_opt(2022, 3, day).unwrap()).collect();
+    futures.add_trade(Trade::new(1, days[0], "ESM2".to_string(), 2, 4300.0, Side::Buy))?;
+    for (day, trade, settlement_price) in [(days[0], None, 4310.0), (days[1], Some((2, 4320.0, Side::Buy)), 4290.0), (days[2], None, 4295.0), (days[3], Some((4, 4300.0, Side::Sell)), 4305.0)] {
+        if let Some((quantity, price, side)) = trade {
+            futures.add_trade(Trade::new(futures.next_trade_id(), day, "ESM2".to_string(), quantity, price, side))?;
+        }
+        futures.settle_futures(day, &HashMap::from([("ESM2".to_string(), Decimal::from(settlement_price))]));
+    }
+    futures.print_variation_margin(days[0], days[3]);
+    println!("{} settlement days: {:?}", futures.settlement_calendars["ESM2"].name, futures.settlement_calendars["ESM2"].settlement_days(days[0], days[3]));
+    let position = futures.get_position("ESM2").unwrap();
+    println!("ESM2 after settlement: {} @ {}, realized {}", position.quantity, position.average_price, position.realized_pnl);
+    futures.cancel_trade(3)?;
+    let position = futures.get_position("ESM2").unwrap();
+    println!("ESM2 with the closing sale cancelled: {} @ {}, realized {}, unrealized at 4300 {}",
+             position.quantity, position.average_price, position.realized_pnl, position.unrealized_pnl(Decimal::from(4300)));
+
+    println!("\n=== Anonymized Copy ===");
+    let mut anonymizer = Anonymizer::new(0.37, 3);
+    let shared = anonymizer.anonymize(&repo)?;
+    let active = |repo: &TradeRepository| repo.trades.values().filter(|trade| !matches!(trade.status, TradeStatus::Cancelled)).count();
+    println!("Original: {} trades ({} active), {} positions; copy: {} trades ({} active), {} positions",
+             repo.trades.len(), active(&repo), repo.positions.len(), shared.trades.len(), active(&shared), shared.positions.len());
+    if let Some(original) = repo.get_position("AAPL") {
+        let name = anonymizer.pseudonym("INST", "AAPL");
+        let copied = shared.get_position(&name).unwrap();
+        println!("AAPL -> {}: {} @ {:.4} realized {:.2} -> {} @ {:.4} realized {:.2}",
+                 name, original.quantity, original.average_price, original.realized_pnl, copied.quantity, copied.average_price, copied.realized_pnl);
+        println!("{} maps back to {:?}", name, anonymizer.original_name(&name));
+    }
+
+    println!("\n=== Corporate Actions ===");
+    let mut actions = TradeRepository::new();
+    let bought = NaiveDate::from_ymd_opt(2022, 8, 1).unwrap();
+    let split_date = NaiveDate::from_ymd_opt(2022, 8, 25).unwrap();
+    for (instrument, account) in [("TSLA", "ACC001"), ("TSLA", "ACC002"), ("NVDA", "ACC001")] {
+        actions.add_trade(Trade::new(actions.next_trade_id(), bought, instrument.to_string(), 10, 900.0, Side::Buy).with_account(account))?;
+    }
+    actions.add_trade(Trade::new(actions.next_trade_id(), bought, "TSLA".to_string(), 4, 950.0, Side::Sell).with_account("ACC002"))?;
+    actions.record_close_price("TSLA", split_date.pred_opt().unwrap(), 891.0);
+    actions.update_market_price("TSLA", 891.0)?;
+    actions.update_market_price("NVDA", 891.0)?;
+    actions.apply_corporate_action(CorporateAction::split("TSLA", split_date, 4, 1, true))?;
+    actions.apply_corporate_action(CorporateAction::split("NVDA", split_date, 4, 1, false))?;
+    for instrument in ["TSLA", "NVDA"] {
+        let position = actions.get_position(instrument).unwrap();
+        println!("{} after the split: {} @ {}, realized {}, market {:?}", instrument, position.quantity, position.average_price, position.realized_pnl, actions.get_market_price(instrument));
+    }
+    if let Err(e) = actions.apply_corporate_action(CorporateAction::split("TSLA", split_date, 2, 3, true)) {
+        println!("Rejected: {}", e);
+    }
+    let ex_date = NaiveDate::from_ymd_opt(2022, 9, 1).unwrap();
+    let pay_date = NaiveDate::from_ymd_opt(2022, 9, 15).unwrap();
+    actions.apply_corporate_action(CorporateAction::cash_dividend("TSLA", ex_date, pay_date, 0.25, DividendCredit::RealizedPnl))?;
+    actions.apply_corporate_action(CorporateAction::cash_dividend("NVDA", ex_date, pay_date, 0.75, DividendCredit::Cash))?;
+    println!("TSLA realized with the dividend: {}; ACC001 USD dividends received: {}",
+             actions.get_position("TSLA").unwrap().realized_pnl,
+             actions.cash_ledger(Some("ACC001"), pay_date)["USD"].movements.iter().filter(|movement| matches!(movement.kind, CashMovementKind::Dividend(_))).map(|movement| movement.amount).sum::<Money>());
+    let renamed = NaiveDate::from_ymd_opt(2022, 9, 20).unwrap();
+    actions.apply_corporate_action(CorporateAction::symbol_change("TSLA", renamed, "TSLQ"))?;
+    actions.add_trade(Trade::new(actions.next_trade_id(), renamed, "TSLA".to_string(), 20, 220.0, Side::Buy))?;
+    let position = actions.get_position("TSLQ").unwrap();
+    println!("TSLQ: {} @ {}, realized {}; TSLA position left: {}, market {:?}",
+             position.quantity, position.average_price, position.realized_pnl, actions.get_position("TSLA").is_some(), actions.get_market_price("TSLQ"));
+    actions.print_corporate_actions();
+    let replayed = TradeRepository::replay(&actions.journal)?;
+    println!("Journal replay: TSLQ {:?}", replayed.get_position("TSLQ").map(|position| (position.quantity, position.average_price)));
+
+    println!("\n=== Cash Ledger ===");
+    let mut ledger = TradeRepository::new();
+    let day = |day: u32| NaiveDate::from_ymd_opt(2022, 10, day).unwrap();
+    ledger.deposit_cash("ACC001", "USD", 100000, day(3))?;
+    ledger.add_trade(Trade::new(ledger.next_trade_id(), day(4), "AAPL".to_string(), 200, 140.0, Side::Buy).with_account("ACC001").with_broker("BRK1", 10.0))?;
+    ledger.add_trade(Trade::new(ledger.next_trade_id(), day(6), "AAPL".to_string(), 50, 146.0, Side::Sell).with_account("ACC001").with_broker("BRK1", 5.0))?;
+    ledger.record_dividend("AAPL", day(7), day(13), 0.23)?;
+    ledger.withdraw_cash("ACC001", "USD", 5000, day(10))?;
+    ledger.submit_for_approval(Trade::new(ledger.next_trade_id(), day(14), "MSFT".to_string(), 100, 230.0, Side::Buy).with_account("ACC001"))?;
+    ledger.record_close_price("AAPL", day(14), 138.0);
+    ledger.print_cash_ledger(Some("ACC001"), day(14));
+    println!("Buying power with the MSFT buy pending: {:.2}", ledger.buying_power("ACC001", "USD", day(14)));
+    if let Err(e) = ledger.withdraw_cash("ACC001", "USD", 0, day(14)) {
+        println!("Rejected: {}", e);
+    }
+    let equity = ledger.portfolio_equity(Some("ACC001"), day(14)).map_err(PositionError::InvalidRecord)?;
+    println!("Equity on {}: cash {:?} + market value {:?} = {} {:.2}", equity.as_of_date, equity.cash, equity.market_value, equity.firm_currency, equity.total);
+
+    println!("\n=== Cash Reconciliation ===");
+    ledger.record_interest("ACC001", "USD", day(14), 12.5);
+    let aapl_buy = ledger.trades.values().find(|trade| trade.instrument == "AAPL" && matches!(trade.side, Side::Buy)).map(|trade| trade.trade_id).unwrap();
+    let statement = format!("activity_id,date,account,currency,type,amount,reference
+BRK-1,2022-10-03,ACC001,USD,deposit,100000
+BRK-2,2022-10-06,ACC001,USD,trade,-28000,{aapl_buy}
+BRK-3,2022-10-06,ACC001,USD,fee,-12,{aapl_buy}
+BRK-4,2022-10-13,ACC001,USD,dividend,29.33,AAPL
+BRK-5,2022-10-10,ACC001,USD,withdrawal,-5000
+BRK-6,2022-10-14,ACC001,USD,fee,-25
+");
+    println!("Imported {} broker cash lines", ledger.import_broker_cash_csv(&statement)?);
+    ledger.print_cash_reconciliation("ACC001", day(20), &CashMatchTolerance { amount: Decimal::from(0.01), days: 2 });
+
+    println!("\n=== Live Config Reload ===");
+    let started = NaiveDate::from_ymd_opt(2022, 11, 7).unwrap().and_hms_opt(9, 0, 0).unwrap().and_utc();
+    let config_path = std::env::temp_dir().join("rustopos_service_config.json");
+    std::fs::write(&config_path, r#"{"fee_schedules": {"*": [{"type": "commission", "per_share": 0.01}]},
+        "reports": [{"name": "positions", "columns": ["instrument", "quantity", "market_value"]}]}"#)?;
+    let mut configured = TradeService::new(TradeRepository::new(), ApiKeyStore::new());
+    let operator = configured.bootstrap_admin_key("operator", started);
+    configured.watch_config(&config_path, started)?;
+    configured.repository.update_market_price("NVDA", 140.0)?;
+    let nvda = |trade_id: i32, price: f64| ServiceRequest::AddTrade(Box::new(Trade::new(trade_id, started.date_naive(), "NVDA".to_string(), 100, price, Side::Buy)));
+    configured.handle(&operator, nvda(1, 140.0), started)?;
+    println!("Polled with the file unchanged: {}", configured.poll_config(started).is_some());
+    let report = ServiceRequest::Report { name: "positions".to_string(), as_of_date: started.date_naive() };
+    if let ServiceResponse::Report(report) = configured.handle(&operator, report.clone(), started)? {
+        print!("{}", report.render());
+    }
+    std::fs::write(&config_path, r#"{"sanity_bands": {"NVDA": {"max_price_deviation": 0.05, "max_adv_fraction": 0.25, "action": "block"}},
+        "fee_schedules": {"*": [{"type": "commission", "per_trade": 5}], "NVDA": [{"type": "commission", "per_share": 0.02}]},
+        "drawdown_alerts": {"thresholds": [0.05], "cooldown_days": 3}}"#)?;
+    if let Some(reloaded) = configured.poll_config(started + chrono::Duration::minutes(5)) {
+        println!("Reloaded: {:?}", reloaded?.changes);
+    }
+    configured.handle(&operator, nvda(2, 141.0), started + chrono::Duration::minutes(6))?;
+    if let Err(e) = configured.handle(&operator, nvda(3, 150.0), started + chrono::Duration::minutes(7)) {
+        println!("Refused under the new band: {}", e);
+    }
+    std::fs::write(&config_path, r#"{"sanity_bands": {"NVDA": {"max_price_deviation": "wide"}}}"#)?;
+    if let Some(Err(e)) = configured.poll_config(started + chrono::Duration::minutes(10)) {
+        println!("{}", e);
+    }
+    std::fs::write(&config_path, r#"{"fee_schedules": {"*": [{"type": "commission", "per_trade": 5}]}}"#)?;
+    if let ServiceResponse::ConfigReloaded(change) = configured.handle(&operator, ServiceRequest::ReloadConfig, started + chrono::Duration::minutes(12))? {
+        println!("Reloaded by {}: {:?}", change.actor, change.changes);
+    }
+    if let Err(e) = configured.handle(&operator, report, started + chrono::Duration::minutes(13)) {
+        println!("Refused: {}", e);
+    }
+    for trade_id in [1, 2] {
+        println!("Trade {} commission: {}", trade_id, configured.repository.trades.get(&TradeId::from(trade_id)).unwrap().commission);
+    }
+    println!("NVDA position kept across reloads: {}", configured.repository.get_position("NVDA").map_or(0, |position| position.quantity));
+    for change in &configured.config_log {
+        println!("  #{} {} by {}: {}", change.sequence, change.recorded_at.format("%H:%M"), change.actor,
+                 change.error.clone().unwrap_or_else(|| format!("{} change(s)", change.changes.len())));
+    }
+    let _ = std::fs::remove_file(&config_path);
+
+    println!("\n=== Settlement Ladder ===");
+    let mut settling = TradeRepository::new();
+    let thursday = NaiveDate::from_ymd_opt(2022, 3, 10).unwrap();
+    let friday = NaiveDate::from_ymd_opt(2022, 3, 11).unwrap();
+    settling.register_instrument(Instrument::new("AAPL", AssetClass::Equity, "USD"));
+    settling.register_instrument(Instrument::new("SAP", AssetClass::Equity, "EUR"));
+    settling.register_instrument(Instrument::new("UST10Y", AssetClass::Bond, "USD").settlement_days(1));
+    settling.register_instrument(Instrument::new("ESM2", AssetClass::Future, "USD").multiplier(50));
+    settling.set_account_custodian("US-FUND", "BNY");
+    settling.set_account_custodian("EU-FUND", "EUROCLEAR");
+    settling.add_trade(Trade::new(1, thursday, "AAPL".to_string(), 500, 160.0, Side::Buy).with_account("US-FUND").with_fees(5.0, 0.0))?;
+    settling.add_trade(Trade::new(2, friday, "AAPL".to_string(), 200, 162.5, Side::Sell).with_account("US-FUND"))?;
+    settling.add_trade(Trade::new(3, friday, "UST10Y".to_string(), 1000, 98.5, Side::Buy).with_account("US-FUND"))?;
+    settling.add_trade(Trade::new(4, thursday, "SAP".to_string(), 300, 110.0, Side::Sell).with_account("EU-FUND"))?;
+    settling.add_trade(Trade::new(5, friday, "SAP".to_string(), 100, 109.0, Side::Buy).with_account("EU-FUND"))?;
+    settling.add_trade(Trade::new(6, friday, "ESM2".to_string(), 2, 4500.0, Side::Buy).with_account("US-FUND"))?;
+    settling.add_trade(Trade::new(7, friday, "MSFT".to_string(), 50, 280.0, Side::Buy))?;
+    for trade_id in [1, 3, 4] {
+        if let Some(trade) = settling.trades.get(&TradeId::from(trade_id)) {
+            println!("Trade {} ({} on {}) settles {}", trade_id, trade.instrument, trade.trade_date, settling.settlement_date(trade));
+        }
+    }
+    settling.print_settlement_ladder(friday, 3);
+
+    // A month of the generated dataset the examples/ programs run on, booked as a
+    // batch and again through the CSV feed adapter
+    println!("\n=== Demo Dataset ===");
+    let dataset = DemoDataset::generate(7, NaiveDate::from_ymd_opt(2022, 1, 3).unwrap(), 21, 16);
+    let mut demo = TradeRepository::new();
+    let booked = dataset.load(&mut demo)?;
+    println!("{} to {}: {} trades booked, {} rejected, {} closes across {} instruments and {} trading days",
+             dataset.start_date, dataset.end_date, booked.accepted, booked.rejected, dataset.closes.len(), DEMO_INSTRUMENTS.len(), dataset.trading_days().len());
+    let mut fed = TradeRepository::new();
+    dataset.load_reference_data(&mut fed);
+    fed.close_prices.import_csv(&dataset.closes_csv(), "date,instrument,close", Decimal::parse)?;
+    let summary = fed.ingest_from(&mut CsvSourceAdapter::new("demo-csv", &dataset.trades_csv(dataset.start_date, dataset.end_date), 500))?;
+    let same = demo.positions.iter().all(|(instrument, position)| fed.get_position(instrument).is_some_and(|fed| fed.quantity == position.quantity));
+    println!("CSV feed ingested {} trades; positions agree: {}", summary.ingested, same);
+    print_var_report(&demo.value_at_risk(dataset.end_date, &VarConfig { confidence_levels: vec![0.95, 0.99], lookback_days: 20 })?);
+    demo.set_duration("UST10", 8.6);
+    demo.set_duration("BUND", 8.1);
+    let scenarios = [
+        StressScenario::new("Equities -10%").asset_class(AssetClass::Equity, -0.10),
+        StressScenario::new("AAPL +5%, rates +50bp").instrument("AAPL", 0.05).rates(50.0),
+        StressScenario::new("Tech selloff, EUR -5%").sector("Technology", -0.15).currency("EUR", -0.05),
+    ];
+    let stress = demo.stress_test(dataset.end_date, &scenarios)?;
+    print_stress_report(&stress, 3);
+    println!("Stress CSV rows: {}", stress.to_csv().lines().count() - 1);
+    print_exposure_report(&demo.exposure_report(dataset.end_date, 5, Some(GroupBy::Sector))?);
+    for (account_id, _, _, _) in DEMO_ACCOUNTS {
+        demo.deposit_cash(account_id, "USD", if account_id == "ACC-MACRO" { 250_000 } else { 5_000_000 }, dataset.start_date)?;
+    }
+    demo.set_margin_rules(MarginRules::reg_t()
+        .asset_class(AssetClass::Future, MarginRule::PerContract { initial: Decimal::from(12_000), maintenance: Decimal::from(11_000) })
+        .asset_class(AssetClass::Crypto, MarginRule::Percentage { initial: 1.0, maintenance: 0.5 })
+        .instrument("UST10", MarginRule::Percentage { initial: 0.02, maintenance: 0.02 }));
+    print_margin_report(&demo.margin_report(dataset.end_date)?);
+    Ok(())
+}
@@ -0,0 +1,150 @@
+// Position arithmetic shared by the position engines. A fill either extends the
+// open position (re-averaging its price), or reduces it: the overlapping units are
+// closed at the position's average price, and any remainder flips the position
+// and opens it at the fill price.
+
+// Numbers a position engine keeps prices and P&L in
+pub trait PositionNumber: Copy + std::ops::Add<Output = Self> + std::ops::Sub<Output = Self> {
+    fn zero() -> Self;
+    fn times(self, quantity: i32) -> Self;
+    fn per(self, quantity: i32) -> Self;
+}
+
+impl PositionNumber for f64 {
+    fn zero() -> f64 {
+        0.0
+    }
+
+    fn times(self, quantity: i32) -> f64 {
+        self * quantity as f64
+    }
+
+    fn per(self, quantity: i32) -> f64 {
+        self / quantity as f64
+    }
+}
+
+// Apply a fill of `fill_quantity` units (positive buys, negative sells) at `price`
+// to a position of `quantity` units at `average_price`. Returns the realized P&L.
+pub fn apply_fill<P: PositionNumber>(quantity: &mut i32, average_price: &mut P, fill_quantity: i32, price: P) -> P {
+    if fill_quantity == 0 {
+        return P::zero();
+    }
+    if *quantity == 0 || quantity.signum() == fill_quantity.signum() {
+        // Opening, or adding in the same direction
+        if *quantity == 0 {
+            *average_price = price;
+        } else {
+            let held = average_price.times(quantity.abs()) + price.times(fill_quantity.abs());
+            *average_price = held.per((*quantity + fill_quantity).abs());
+        }
+        *quantity += fill_quantity;
+        return P::zero();
+    }
+
+    // Reducing: close what overlaps, then open the rest on the other side
+    let closed = fill_quantity.abs().min(quantity.abs());
+    let realized = if *quantity > 0 {
+        (price - *average_price).times(closed)
+    } else {
+        (*average_price - price).times(closed)
+    };
+    *quantity += fill_quantity.signum() * closed;
+    let remaining = fill_quantity.abs() - closed;
+    if remaining > 0 {
+        *quantity = fill_quantity.signum() * remaining;
+        *average_price = price;
+    } else if *quantity == 0 {
+        *average_price = P::zero();
+    }
+    realized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Debug;
+
+    // Fixed-point millionths, standing in for an engine's exact decimal type so these
+    // tests build in whichever crate includes the module
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Fixed(i64);
+
+    impl std::ops::Add for Fixed {
+        type Output = Fixed;
+        fn add(self, other: Fixed) -> Fixed {
+            Fixed(self.0 + other.0)
+        }
+    }
+
+    impl std::ops::Sub for Fixed {
+        type Output = Fixed;
+        fn sub(self, other: Fixed) -> Fixed {
+            Fixed(self.0 - other.0)
+        }
+    }
+
+    impl PositionNumber for Fixed {
+        fn zero() -> Fixed {
+            Fixed(0)
+        }
+
+        fn times(self, quantity: i32) -> Fixed {
+            Fixed(self.0 * quantity as i64)
+        }
+
+        fn per(self, quantity: i32) -> Fixed {
+            Fixed(self.0 / quantity as i64)
+        }
+    }
+
+    fn fixed(value: f64) -> Fixed {
+        Fixed((value * 1e6).round() as i64)
+    }
+
+    // Every case runs in floating point and in fixed point
+    fn flat_to_short<P: PositionNumber + PartialEq + Debug>(number: fn(f64) -> P) {
+        let (mut quantity, mut average_price) = (0, P::zero());
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, -100, number(50.0)), P::zero());
+        assert_eq!((quantity, average_price), (-100, number(50.0)));
+        // Adding to the short re-averages it
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, -100, number(40.0)), P::zero());
+        assert_eq!((quantity, average_price), (-200, number(45.0)));
+    }
+
+    fn partial_cover<P: PositionNumber + PartialEq + Debug>(number: fn(f64) -> P) {
+        let (mut quantity, mut average_price) = (-100, number(50.0));
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, 40, number(45.0)), number(200.0));
+        assert_eq!((quantity, average_price), (-60, number(50.0)));
+        // A cover above the short price loses
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, 60, number(52.5)), number(-150.0));
+        assert_eq!((quantity, average_price), (0, P::zero()));
+    }
+
+    fn short_to_long<P: PositionNumber + PartialEq + Debug>(number: fn(f64) -> P) {
+        let (mut quantity, mut average_price) = (-100, number(50.0));
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, 150, number(40.0)), number(1000.0));
+        assert_eq!((quantity, average_price), (50, number(40.0)));
+        // And back through flat to short, closing the long at a loss
+        assert_eq!(apply_fill(&mut quantity, &mut average_price, -80, number(38.0)), number(-100.0));
+        assert_eq!((quantity, average_price), (-30, number(38.0)));
+    }
+
+    #[test]
+    fn flat_to_short_opens_at_the_fill_price() {
+        flat_to_short(std::convert::identity);
+        flat_to_short(fixed);
+    }
+
+    #[test]
+    fn partial_cover_keeps_the_short_average() {
+        partial_cover(std::convert::identity);
+        partial_cover(fixed);
+    }
+
+    #[test]
+    fn short_to_long_realizes_the_overlap_and_opens_the_rest() {
+        short_to_long(std::convert::identity);
+        short_to_long(fixed);
+    }
+}
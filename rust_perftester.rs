@@ -1,30 +1,166 @@
-use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
 
-fn main() {
+use chrono::NaiveDate;
+use rustopos::*;
+
+const THREADS: usize = 8;
+const TRADES_PER_THREAD: usize = 125000;
+
+fn main() -> Result<(), PositionError> {
+    let today = chrono::Local::now().date_naive();
     let mut repo = TradeRepository::new();
     let start = Instant::now();
 
     // Add 1 million trades
     for i in 0..1000000 {
-        repo.add_trade(Trade::new(i as i32, chrono::Local::today().naive_local(), "AAPL".to_string(), 100, 100.0, Side::Buy));
+        repo.add_trade(Trade::new(i as i64, today, "AAPL".to_string(), 100, 100.0, Side::Buy))?;
     }
 
     let duration = start.elapsed();
     println!("Rust - Add trades: {} ms", duration.as_millis());
 
+    // Same 1 million trades booked as a single batch
+    let batch: Vec<Trade> = (0..1000000).map(|i| Trade::new(i as i64, today, "AAPL".to_string(), 100, 100.0, Side::Buy)).collect();
+    let mut batched = TradeRepository::new();
     let start = Instant::now();
-    // Amend trades
+    let report = batched.add_trades_batch(batch)?;
+    let duration = start.elapsed();
+    println!("Rust - Add trades, batched ({} accepted): {} ms", report.accepted, duration.as_millis());
+    drop(batched);
+
+    // Filtered queries over 1M trades spread across 100 instruments and 1000 days:
+    // a full scan against the index the query planner picks
+    let mut spread = TradeRepository::new();
+    let first_date = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
     for i in 0..1000000 {
-        repo.amend_trade(i as i32, 150, 120.0);
+        let date = first_date + chrono::Duration::days((i / 1000) as i64);
+        spread.add_trade(Trade::new(i as i64, date, format!("SYM{}", i % 100), 100, 100.0, Side::Buy))?;
+    }
+    for i in 0..1000 {
+        spread.cancel_trade(i * 997 % 1000000)?;
+    }
+    let queries = [
+        ("instrument + month", TradeFilter::new().instrument("SYM7".to_string()).date_range(first_date + chrono::Duration::days(300), first_date + chrono::Duration::days(330))),
+        ("single day", TradeFilter::new().date_range(first_date + chrono::Duration::days(500), first_date + chrono::Duration::days(500))),
+        ("cancelled", TradeFilter { status: Some(TradeStatus::Cancelled), ..TradeFilter::new() }),
+    ];
+    for (name, filter) in &queries {
+        let start = Instant::now();
+        let mut scanned = 0;
+        for _ in 0..10 {
+            scanned = spread.trades.values().filter(|trade| trade.matches_filter(filter)).count();
+        }
+        let scan_duration = start.elapsed();
+        let start = Instant::now();
+        let mut found = 0;
+        for _ in 0..10 {
+            found = spread.filter_trades(filter).len();
+        }
+        let index_duration = start.elapsed();
+        let (plan, _) = spread.trades.plan(filter);
+        println!("Rust - Filter {} ({} trades), 10 runs: full scan {} ms, {:?} {} ms (same result: {})",
+                 name, found, scan_duration.as_millis(), plan, index_duration.as_millis(), scanned == found);
+    }
+    drop(spread);
+
+    // Amends and cancels replay the trades after the one changed, so these run over
+    // the last 10000 trades of the book, oldest first
+    let start = Instant::now();
+    // Amend trades
+    for i in 990000..1000000 {
+        repo.amend_trade(i as i64, 150, 120.0)?;
     }
     let duration = start.elapsed();
-    println!("Rust - Amend trades: {} ms", duration.as_millis());
+    println!("Rust - Amend trades (last 10000): {} ms", duration.as_millis());
 
     let start = Instant::now();
     // Cancel trades
-    for i in 0..1000000 {
-        repo.cancel_trade(i as i32);
+    for i in 990000..1000000 {
+        repo.cancel_trade(i as i64)?;
     }
     let duration = start.elapsed();
-    println!("Rust - Cancel trades: {} ms", duration.as_millis());
+    println!("Rust - Cancel trades (last 10000): {} ms", duration.as_millis());
+
+    // Amending or cancelling the newest trade must not cost more as the book grows:
+    // the same 10000 amends and 10000 newest-first cancels on a small and a large book
+    let mut small = TradeRepository::new();
+    for i in 0..10000 {
+        small.add_trade(Trade::new(i, today, "AAPL".to_string(), 100, 100.0, Side::Buy))?;
+    }
+    let mut per_op = Vec::new();
+    for (book, newest) in [(&mut small, 9999), (&mut repo, 989999)] {
+        let start = Instant::now();
+        for _ in 0..10000 {
+            book.amend_trade(newest, 150, 120.0)?;
+        }
+        for i in 0..10000 {
+            book.cancel_trade(newest - i)?;
+        }
+        let duration = start.elapsed();
+        per_op.push(duration.as_secs_f64() / 20000.0);
+        println!("Rust - Amend and cancel newest trades, {} trade book: {} ms", book.trades.len(), duration.as_millis());
+    }
+    let growth = per_op[1] / per_op[0];
+    println!("Rust - Newest-trade amend/cancel, 1M vs 10k book: {:.1}x per operation (flat: {})", growth, growth < 10.0);
+    drop(small);
+    drop(repo);
+
+    // Multi-threaded adds, one instrument per thread, behind a single global lock
+    let symbols: Vec<String> = (0..THREADS).map(|t| format!("SYM{}", t)).collect();
+    let global = Mutex::new(TradeRepository::new());
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for (t, symbol) in symbols.iter().enumerate() {
+            let global = &global;
+            scope.spawn(move || {
+                for i in 0..TRADES_PER_THREAD {
+                    let trade_id = (t * TRADES_PER_THREAD + i) as i64;
+                    let trade = Trade::new(trade_id, today, symbol.clone(), 100, 100.0, Side::Buy);
+                    global.lock().unwrap().add_trade(trade).expect("benchmark trade rejected");
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    println!("Rust - Add trades, {} threads, global lock: {} ms", THREADS, duration.as_millis());
+    drop(global);
+
+    // Same workload with per-instrument locks; threads never share a lock
+    let concurrent = ConcurrentTradeRepository::new();
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for (t, symbol) in symbols.iter().enumerate() {
+            let concurrent = &concurrent;
+            scope.spawn(move || {
+                for i in 0..TRADES_PER_THREAD {
+                    let trade_id = (t * TRADES_PER_THREAD + i) as i64;
+                    concurrent.add_trade(Trade::new(trade_id, today, symbol.clone(), 100, 100.0, Side::Buy)).expect("benchmark trade rejected");
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    println!("Rust - Add trades, {} threads, per-instrument locks: {} ms", THREADS, duration.as_millis());
+    drop(concurrent);
+
+    // Contended case: every thread writes the same instrument
+    let contended = ConcurrentTradeRepository::new();
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            let contended = &contended;
+            scope.spawn(move || {
+                for i in 0..TRADES_PER_THREAD {
+                    let trade_id = (t * TRADES_PER_THREAD + i) as i64;
+                    contended.add_trade(Trade::new(trade_id, today, "AAPL".to_string(), 100, 100.0, Side::Buy)).expect("benchmark trade rejected");
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    println!("Rust - Add trades, {} threads, single instrument: {} ms", THREADS, duration.as_millis());
+
+    Ok(())
 }
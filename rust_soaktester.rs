@@ -0,0 +1,113 @@
+// Soak test for the fault-injection mode (`--features chaos`).
+// Each round sends a random sequenced or unsequenced feed through a connection that
+// drops, refuses reconnects, resends and reorders, into a journaled repository whose
+// storage fails writes; then cancels some trades. The live positions must match a
+// clean booking of the same trades, a replay of the journal and a reload of storage.
+// Usage: cargo run --release --example soak --features chaos -- [seed] [rounds]
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDate;
+use rustopos::*;
+
+const TRADES_PER_ROUND: usize = 2000;
+const INSTRUMENTS: usize = 12;
+
+type PositionKey = BTreeMap<String, (i32, Price, Money)>;
+
+fn positions_of(repo: &TradeRepository) -> PositionKey {
+    repo.positions
+        .values()
+        .map(|position| (position.instrument.clone(), (position.quantity, position.average_price, position.realized_pnl)))
+        .collect()
+}
+
+fn main() -> Result<(), PositionError> {
+    let mut args = std::env::args().skip(1);
+    let seed: u64 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(1);
+    let rounds: u64 = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(20);
+    let first_date = NaiveDate::from_ymd_opt(2022, 1, 3).unwrap();
+    let journal_path = std::env::temp_dir().join(format!("rustopos_soak_{}.ndjson", seed)).to_string_lossy().into_owned();
+    let mut failures = 0;
+
+    for round in 0..rounds {
+        let round_seed = seed.wrapping_mul(1_000_003).wrapping_add(round);
+        let mut rng = ChaosRng::new(round_seed);
+        let sequenced = round % 2 == 0;
+
+        // The trades of the round, in trade id order, and the feed that carries them
+        let first_id = (round as usize * TRADES_PER_ROUND) as i64 + 1;
+        let mut trades = Vec::new();
+        let mut feed = String::new();
+        for i in 0..TRADES_PER_ROUND {
+            let trade_id = first_id + i as i64;
+            let date = first_date + chrono::Duration::days((i / 200) as i64);
+            let instrument = format!("SYM{}", rng.below(INSTRUMENTS));
+            let side = if rng.chance(0.5) { "BUY" } else { "SELL" };
+            let quantity = 1 + rng.below(500) as i32;
+            let price = format!("{}.{:02}", 50 + rng.below(100), rng.below(100));
+            if sequenced {
+                feed.push_str(&format!("{},", i + 1));
+            }
+            feed.push_str(&format!("{},{},{},{},{},{}\n", trade_id, date, instrument, side, quantity, price));
+            let side = if side == "BUY" { Side::Buy } else { Side::Sell };
+            trades.push(Trade::new(trade_id, date, instrument, quantity, price.parse::<f64>().unwrap(), side));
+        }
+        let cancelled: Vec<i64> = (0..TRADES_PER_ROUND / 50).map(|_| first_id + rng.below(TRADES_PER_ROUND) as i64).collect();
+
+        // Through the faults
+        let _ = std::fs::remove_file(&journal_path);
+        let stored: SharedTradeStorage = Arc::new(Mutex::new(MemoryTradeStore::default()));
+        let storage = Arc::new(Mutex::new(ChaosStorage::new(Arc::clone(&stored), round_seed ^ 0x5eed, 0.05)));
+        let mut live = TradeRepository::new();
+        live.attach_journal(TradeJournal::open(&journal_path).map_err(|e| PositionError::Journal(e.to_string()))?);
+        live.attach_storage(storage.clone());
+        live.set_reorder_window(64);
+        live.set_feed_reconnect_limit(10_000);
+        let csv = CsvSourceAdapter::new("soak-feed", &feed, 100);
+        let csv = if sequenced { csv.sequenced() } else { csv };
+        let mut adapter = ChaosAdapter::new(csv, round_seed).disconnects(0.05, 0.2).garbles(0.02, 0.02);
+        let summary = live.ingest_from(&mut adapter)?;
+        for trade_id in &cancelled {
+            let _ = live.cancel_trade(*trade_id);
+        }
+        let mut flushes = 0;
+        while live.flush_storage().is_err() && flushes < 100 {
+            flushes += 1;
+        }
+
+        // Without them
+        let mut reference = TradeRepository::new();
+        for trade in trades {
+            reference.add_trade(trade)?;
+        }
+        for trade_id in &cancelled {
+            let _ = reference.cancel_trade(*trade_id);
+        }
+        let replayed = TradeRepository::replay(&TradeJournal::load(&journal_path)?)?;
+        let reloaded = TradeRepository::load_from_storage(stored)?;
+
+        let expected = positions_of(&reference);
+        let checks = [
+            ("live", positions_of(&live) == expected),
+            ("journal replay", positions_of(&replayed) == expected),
+            ("storage reload", positions_of(&reloaded) == expected),
+            ("nothing quarantined", live.quarantine.is_empty()),
+            ("storage caught up", live.storage_backlog.is_empty()),
+        ];
+        let failed: Vec<&str> = checks.iter().filter(|(_, ok)| !ok).map(|(name, _)| *name).collect();
+        println!("Round {:>3} ({}): ingested {}, duplicates {}, reconnects {}, {:?}, storage failures {} -> {}",
+                 round, if sequenced { "sequenced" } else { "unsequenced" }, summary.ingested, summary.duplicates, summary.reconnects,
+                 adapter.faults, storage.lock().unwrap().failures, if failed.is_empty() { "ok".to_string() } else { format!("FAILED {}", failed.join(", ")) });
+        if !failed.is_empty() {
+            failures += 1;
+        }
+    }
+    let _ = std::fs::remove_file(&journal_path);
+
+    println!("Soak seed {}: {} of {} rounds failed", seed, failures, rounds);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}